@@ -8,6 +8,12 @@ nvm install v12
 nvm use v12
 cargo clippy --locked --all-targets --all-features -- --deny warnings
 
+# Check the shs1 vectors with an independent implementation and run both crypto backends
+# against them.
+node ssb-box-stream/scripts/check-shs1-vectors.js
+cargo test -p ssb-box-stream --lib conformance
+cargo test -p ssb-box-stream --no-default-features --features sodium --lib conformance
+
 (
   cd ssb
   DETACH=true ./tests/ssb-server.sh
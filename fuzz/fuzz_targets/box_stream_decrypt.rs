@@ -0,0 +1,68 @@
+//! Encrypts the payloads from the input, corrupts the cipher text and decrypts it again.
+//!
+//! Since random bytes almost never pass authentication, the cipher text is derived from a valid
+//! stream so that the fuzzer reaches the body decryption and the handling of truncated streams.
+#![no_main]
+use futures::prelude::*;
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Debug, arbitrary::Arbitrary)]
+struct Input {
+    payloads: Vec<Vec<u8>>,
+    close: bool,
+    truncate: Option<u16>,
+    flip: Option<(u16, u8)>,
+}
+
+fn params() -> ssb_box_stream::CipherParams {
+    ssb_box_stream::CipherParams::from_bytes(&[1u8; 32], &[2u8; 24])
+}
+
+fuzz_target!(|input: Input| {
+    futures::executor::block_on(async {
+        let mut cipher_text = Vec::new();
+        let mut encrypt = ssb_box_stream::Encrypt::new(&mut cipher_text, params());
+        for payload in &input.payloads {
+            encrypt.feed(payload.clone()).await.unwrap();
+        }
+        if input.close {
+            encrypt.close().await.unwrap();
+        } else {
+            encrypt.flush().await.unwrap();
+        }
+
+        let mut corrupted = false;
+        if let Some(len) = input.truncate {
+            let len = len as usize;
+            corrupted = len < cipher_text.len();
+            cipher_text.truncate(len);
+        }
+        if let Some((index, mask)) = input.flip {
+            if let Some(byte) = cipher_text.get_mut(index as usize) {
+                *byte ^= mask;
+                corrupted |= mask != 0;
+            }
+        }
+
+        let mut decrypt = ssb_box_stream::Decrypt::new(&cipher_text[..], params());
+        let mut plain_text = Vec::new();
+        let mut failed = false;
+        loop {
+            match decrypt.next().await {
+                Some(Ok(data)) => plain_text.extend(data),
+                Some(Err(_)) => {
+                    failed = true;
+                    break;
+                }
+                None => break,
+            }
+        }
+
+        let expected = input.payloads.concat();
+        if !corrupted {
+            assert_eq!(plain_text, expected);
+            assert_eq!(failed, !input.close);
+        }
+        assert!(expected.starts_with(&plain_text));
+    })
+});
@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use ssb::rpc::base::packet::Header;
+
+fuzz_target!(|data: [u8; Header::SIZE]| {
+    if let Ok(Some(header)) = Header::parse(data) {
+        assert_eq!(Header::parse(header.build()), Ok(Some(header)));
+    }
+});
@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use ssb::multi_address::MultiAddress;
+
+fuzz_target!(|data: &str| {
+    let _ = data.parse::<MultiAddress>();
+});
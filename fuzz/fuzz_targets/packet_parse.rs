@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use ssb::rpc::base::packet::{Header, Packet};
+
+fuzz_target!(|input: ([u8; Header::SIZE], Vec<u8>)| {
+    let (header, body) = input;
+    if let Ok(Some(header)) = Header::parse(header) {
+        if let Ok(packet) = Packet::parse(header, body) {
+            let _ = packet.build();
+        }
+    }
+});
@@ -0,0 +1,73 @@
+use core::convert::TryInto as _;
+
+use crate::TAG_SIZE;
+
+/// Size of the plain text header
+pub const HEADER_SIZE: usize = 2 + TAG_SIZE;
+
+/// The plain text header that marks the end of the packet stream.
+pub const GOODBYE_HEADER: [u8; HEADER_SIZE] = [0u8; HEADER_SIZE];
+
+/// Plain text header of a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// Length of the packet body
+    pub body_len: u16,
+    /// Authentication tag of the encrypted body
+    pub body_tag: [u8; TAG_SIZE],
+}
+
+impl Header {
+    /// Encode the header as the body length in big endian followed by the body tag.
+    pub fn build(&self) -> [u8; HEADER_SIZE] {
+        let mut data = [0u8; HEADER_SIZE];
+        data[..2].copy_from_slice(&self.body_len.to_be_bytes());
+        data[2..].copy_from_slice(&self.body_tag);
+        data
+    }
+
+    /// Parse a header. Returns `None` for [GOODBYE_HEADER].
+    ///
+    /// The header is compared with [GOODBYE_HEADER] in constant time.
+    pub fn parse(data: &[u8; HEADER_SIZE]) -> Option<Self> {
+        if bool::from(subtle::ConstantTimeEq::ct_eq(
+            &data[..],
+            &GOODBYE_HEADER[..],
+        )) {
+            return None;
+        }
+        Some(Header {
+            body_len: u16::from_be_bytes(data[..2].try_into().unwrap()),
+            body_tag: data[2..].try_into().unwrap(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn parse_goodbye() {
+        assert_eq!(Header::parse(&GOODBYE_HEADER), None);
+    }
+
+    #[test]
+    fn build() {
+        let header = Header {
+            body_len: 0x0102,
+            body_tag: [3u8; TAG_SIZE],
+        };
+        let data = header.build();
+        assert_eq!(&data[..2], &[1, 2]);
+        assert_eq!(&data[2..], &[3u8; TAG_SIZE]);
+    }
+
+    #[test_strategy::proptest]
+    fn build_parse_roundtrip(body_len: u16, body_tag: [u8; TAG_SIZE]) {
+        prop_assume!(body_len != 0 || body_tag != [0u8; TAG_SIZE]);
+        let header = Header { body_len, body_tag };
+        prop_assert_eq!(Header::parse(&header.build()), Some(header));
+    }
+}
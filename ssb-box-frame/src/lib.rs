@@ -0,0 +1,90 @@
+//! Encryption and decryption of Scuttlebutt box stream packets.
+//!
+//! A box stream is a sequence of packets. Each packet consists of a boxed header followed by the
+//! encrypted body. The plain text header contains the length of the body and its authentication
+//! tag. A header of zeros marks the end of the stream.
+//!
+//! The crate is `#![no_std]` and only requires `alloc`. It does not perform IO: [Params] seals
+//! packets into a buffer and opens headers and bodies that were read from any source. The
+//! secret box primitive is supplied by an implementation of [SecretBox].
+//!
+//! ```
+//! # #[cfg(feature = "rust-crypto")] {
+//! # use core::convert::TryInto as _;
+//! # use ssb_box_frame::*;
+//! use crypto_secretbox::{KeyInit as _, XSalsa20Poly1305};
+//!
+//! let key = XSalsa20Poly1305::new(&[1u8; 32].into());
+//! let mut encrypt = Params::new(key.clone(), [2u8; NONCE_SIZE]);
+//! let mut decrypt = Params::new(key, [2u8; NONCE_SIZE]);
+//!
+//! let mut packets = bytes::BytesMut::new();
+//! encrypt.encrypt(&mut packets, b"hello");
+//!
+//! let (boxed_header, body) = packets.split_at_mut(BOXED_HEADER_SIZE);
+//! let header = decrypt
+//!     .decrypt_header((&*boxed_header).try_into().unwrap())
+//!     .unwrap()
+//!     .unwrap();
+//! decrypt.decrypt_body(&header.body_tag, body).unwrap();
+//! assert_eq!(body, b"hello");
+//! # }
+//! ```
+//!
+//! # Features
+//!
+//! * `std` (default): Implements `std::error::Error` for the error types.
+//! * `rust-crypto`: Implements [SecretBox] for `crypto_secretbox::XSalsa20Poly1305`.
+// Tests use `std` for the proptest macros.
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+#[cfg(all(feature = "std", not(test)))]
+extern crate std;
+
+mod header;
+mod params;
+#[cfg(any(test, feature = "rust-crypto"))]
+mod rust_crypto;
+
+pub use header::{Header, GOODBYE_HEADER, HEADER_SIZE};
+pub use params::{Params, BOXED_HEADER_SIZE, MAX_BODY_SIZE};
+
+/// Size of a secret box key
+pub const KEY_SIZE: usize = 32;
+
+/// Size of a secret box nonce
+pub const NONCE_SIZE: usize = 24;
+
+/// Size of a secret box authentication tag
+pub const TAG_SIZE: usize = 16;
+
+/// XSalsa20-Poly1305 authenticated encryption with detached tags, compatible with
+/// `crypto_secretbox_detached` from libsodium.
+///
+/// Implementations hold the key.
+pub trait SecretBox {
+    /// Encrypt `data` in place and return the authentication tag.
+    fn seal_detached(&self, data: &mut [u8], nonce: &[u8; NONCE_SIZE]) -> [u8; TAG_SIZE];
+
+    /// Authenticate and decrypt `data` in place.
+    fn open_detached(
+        &self,
+        data: &mut [u8],
+        tag: &[u8; TAG_SIZE],
+        nonce: &[u8; NONCE_SIZE],
+    ) -> Result<(), AuthError>;
+}
+
+/// A packet header or body could not be authenticated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthError;
+
+impl core::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Failed to authenticate packet")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for AuthError {}
@@ -0,0 +1,257 @@
+use bytes::BytesMut;
+
+use crate::{AuthError, Header, SecretBox, GOODBYE_HEADER, HEADER_SIZE, NONCE_SIZE, TAG_SIZE};
+
+/// Size of the encrypted and authenticated header
+pub const BOXED_HEADER_SIZE: usize = TAG_SIZE + HEADER_SIZE;
+
+/// Maximum size of the body of a packet
+pub const MAX_BODY_SIZE: u16 = 4 * 1024;
+
+/// Parameters for encrypting or decrypting a sequence of packets
+///
+/// The parameters consist of the secret box key and the nonce of the next packet. Each packet
+/// uses two nonces, one for the header and one for the body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Params<Key> {
+    key: Key,
+    nonce: [u8; NONCE_SIZE],
+}
+
+impl<Key> Params<Key> {
+    /// Create parameters from a key and the nonce of the first packet.
+    pub fn new(key: Key, nonce: [u8; NONCE_SIZE]) -> Self {
+        Self { key, nonce }
+    }
+
+    /// The key that seals and opens the packets
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    /// The nonce of the next packet
+    pub fn nonce(&self) -> &[u8; NONCE_SIZE] {
+        &self.nonce
+    }
+}
+
+impl<Key: SecretBox> Params<Key> {
+    /// Encrypt `data` and append the encrypted packets to `dst`.
+    pub fn encrypt(&mut self, dst: &mut BytesMut, data: &[u8]) {
+        self.encrypt_vectored(dst, &[data]);
+    }
+
+    /// Encrypt the concatenation of `slices` and append the encrypted packets to `dst`.
+    ///
+    /// The data is split into packets of at most [MAX_BODY_SIZE] regardless of the slice
+    /// boundaries so that many small slices result in few packets. Each packet is sealed in place
+    /// in `dst` without allocating.
+    pub fn encrypt_vectored(
+        &mut self,
+        dst: &mut BytesMut,
+        slices: &[impl core::ops::Deref<Target = [u8]>],
+    ) {
+        let max_body_size = MAX_BODY_SIZE as usize;
+        let total_len = slices.iter().map(|slice| slice.len()).sum::<usize>();
+        let packet_count = total_len.div_ceil(max_body_size);
+        dst.reserve(total_len + packet_count * BOXED_HEADER_SIZE);
+
+        let mut slices = slices.iter().map(|slice| &slice[..]);
+        let mut current: &[u8] = &[];
+        let mut remaining = total_len;
+        while remaining > 0 {
+            let packet_start = dst.len();
+            dst.resize(packet_start + BOXED_HEADER_SIZE, 0);
+            let mut body_len = 0;
+            while body_len < max_body_size && remaining > 0 {
+                if current.is_empty() {
+                    current = slices.next().unwrap();
+                    continue;
+                }
+                let len = core::cmp::min(current.len(), max_body_size - body_len);
+                dst.extend_from_slice(&current[..len]);
+                current = &current[len..];
+                body_len += len;
+                remaining -= len;
+            }
+            self.seal_packet(&mut dst[packet_start..]);
+        }
+    }
+
+    /// Seal a packet in place. `packet` consists of space for the boxed header followed by the
+    /// plain text body.
+    fn seal_packet(&mut self, packet: &mut [u8]) {
+        let (boxed_header, body) = packet.split_at_mut(BOXED_HEADER_SIZE);
+        assert!(body.len() <= MAX_BODY_SIZE as usize);
+
+        let header_nonce = self.next_nonce();
+        let body_nonce = self.next_nonce();
+        let header = Header {
+            body_len: body.len() as u16,
+            body_tag: self.key.seal_detached(body, &body_nonce),
+        };
+        self.seal_header(boxed_header, &header.build(), &header_nonce);
+    }
+
+    /// Seal the header that marks the end of the stream.
+    pub fn goodbye(&mut self) -> [u8; BOXED_HEADER_SIZE] {
+        let mut boxed_header = [0u8; BOXED_HEADER_SIZE];
+        let header_nonce = self.nonce;
+        self.seal_header(&mut boxed_header, &GOODBYE_HEADER, &header_nonce);
+        boxed_header
+    }
+
+    fn seal_header(
+        &self,
+        boxed_header: &mut [u8],
+        header: &[u8; HEADER_SIZE],
+        nonce: &[u8; NONCE_SIZE],
+    ) {
+        let (tag, data) = boxed_header.split_at_mut(TAG_SIZE);
+        data.copy_from_slice(header);
+        tag.copy_from_slice(&self.key.seal_detached(data, nonce));
+    }
+
+    /// Decrypt and authenticate a packet header. Returns `None` if the header marks the end of
+    /// the stream.
+    pub fn decrypt_header(
+        &mut self,
+        boxed_header: &[u8; BOXED_HEADER_SIZE],
+    ) -> Result<Option<Header>, AuthError> {
+        let mut tag = [0u8; TAG_SIZE];
+        tag.copy_from_slice(&boxed_header[..TAG_SIZE]);
+        let mut header = [0u8; HEADER_SIZE];
+        header.copy_from_slice(&boxed_header[TAG_SIZE..]);
+        self.key.open_detached(&mut header, &tag, &self.nonce)?;
+        let header = Header::parse(&header);
+        if header.is_some() {
+            self.next_nonce();
+        }
+        Ok(header)
+    }
+
+    /// Decrypt and authenticate a packet body in place. `body_tag` is taken from the header
+    /// of the packet.
+    pub fn decrypt_body(
+        &mut self,
+        body_tag: &[u8; TAG_SIZE],
+        body: &mut [u8],
+    ) -> Result<(), AuthError> {
+        self.key.open_detached(body, body_tag, &self.nonce)?;
+        self.next_nonce();
+        Ok(())
+    }
+
+    /// Return the current nonce and increment it.
+    fn next_nonce(&mut self) -> [u8; NONCE_SIZE] {
+        let nonce = self.nonce;
+        increment_be(&mut self.nonce);
+        nonce
+    }
+}
+
+/// Interpret the buffer as a big endian unsigned integer and increment it by one. Overflows when
+/// all bits are 1.
+fn increment_be(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut().rev() {
+        if *byte == u8::MAX {
+            *byte = 0
+        } else {
+            *byte += 1;
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::convert::TryInto as _;
+    use crypto_secretbox::{KeyInit as _, XSalsa20Poly1305};
+    use proptest::prelude::*;
+
+    fn params() -> Params<XSalsa20Poly1305> {
+        Params::new(XSalsa20Poly1305::new(&[1u8; 32].into()), [2u8; NONCE_SIZE])
+    }
+
+    fn decrypt_all(params: &mut Params<XSalsa20Poly1305>, mut cipher_text: &mut [u8]) -> Vec<u8> {
+        let mut plain_text = Vec::new();
+        loop {
+            let (boxed_header, rest) = cipher_text.split_at_mut(BOXED_HEADER_SIZE);
+            let header = match params.decrypt_header((&*boxed_header).try_into().unwrap()) {
+                Ok(Some(header)) => header,
+                Ok(None) => return plain_text,
+                Err(AuthError) => panic!("Failed to decrypt header"),
+            };
+            let (body, rest) = rest.split_at_mut(header.body_len as usize);
+            params.decrypt_body(&header.body_tag, body).unwrap();
+            plain_text.extend_from_slice(body);
+            cipher_text = rest;
+        }
+    }
+
+    #[test]
+    fn increment_be_u64() {
+        fn test(value: u64) {
+            let mut bytes = value.to_be_bytes();
+            increment_be(&mut bytes);
+            assert_eq!(value + 1, u64::from_be_bytes(bytes));
+        }
+
+        test(0);
+        test(1);
+        test(u64::MAX - 1);
+
+        let mut max_bytes = u64::MAX.to_be_bytes();
+        increment_be(&mut max_bytes);
+        assert_eq!(0, u64::from_be_bytes(max_bytes))
+    }
+
+    #[test]
+    fn encrypt_vectored() {
+        let mut encrypt = params();
+        let mut decrypt = params();
+        let data = (0..5000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let slices = data.chunks(7).collect::<Vec<_>>();
+        let mut cipher_text = BytesMut::new();
+        encrypt.encrypt_vectored(&mut cipher_text, &slices);
+        assert_eq!(cipher_text.len(), data.len() + 2 * BOXED_HEADER_SIZE);
+
+        cipher_text.extend_from_slice(&encrypt.goodbye());
+        assert_eq!(decrypt_all(&mut decrypt, &mut cipher_text), data);
+        assert_eq!(encrypt.nonce(), decrypt.nonce());
+    }
+
+    #[test]
+    fn tampered_body() {
+        let mut encrypt = params();
+        let mut decrypt = params();
+        let mut cipher_text = BytesMut::new();
+        encrypt.encrypt(&mut cipher_text, b"hello");
+        cipher_text[BOXED_HEADER_SIZE] ^= 1;
+
+        let (boxed_header, body) = cipher_text.split_at_mut(BOXED_HEADER_SIZE);
+        let header = decrypt
+            .decrypt_header((&*boxed_header).try_into().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(decrypt.decrypt_body(&header.body_tag, body), Err(AuthError));
+    }
+
+    #[test_strategy::proptest]
+    fn encrypt_roundtrip(payloads: Vec<Vec<u8>>) {
+        let mut encrypt = params();
+        let mut decrypt = params();
+
+        let mut cipher_text = BytesMut::new();
+        for payload in &payloads {
+            encrypt.encrypt(&mut cipher_text, payload);
+        }
+        cipher_text.extend_from_slice(&encrypt.goodbye());
+
+        prop_assert_eq!(
+            decrypt_all(&mut decrypt, &mut cipher_text),
+            payloads.concat()
+        );
+    }
+}
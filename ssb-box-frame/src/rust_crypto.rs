@@ -0,0 +1,22 @@
+use crypto_secretbox::aead::AeadInPlace as _;
+use crypto_secretbox::XSalsa20Poly1305;
+
+use crate::{AuthError, SecretBox, NONCE_SIZE, TAG_SIZE};
+
+impl SecretBox for XSalsa20Poly1305 {
+    fn seal_detached(&self, data: &mut [u8], nonce: &[u8; NONCE_SIZE]) -> [u8; TAG_SIZE] {
+        self.encrypt_in_place_detached(nonce.into(), &[], data)
+            .expect("Message too large")
+            .into()
+    }
+
+    fn open_detached(
+        &self,
+        data: &mut [u8],
+        tag: &[u8; TAG_SIZE],
+        nonce: &[u8; NONCE_SIZE],
+    ) -> Result<(), AuthError> {
+        self.decrypt_in_place_detached(nonce.into(), &[], data, tag.into())
+            .map_err(|_| AuthError)
+    }
+}
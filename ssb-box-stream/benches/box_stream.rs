@@ -0,0 +1,46 @@
+//! Measures encryption and decryption of box streams without network IO.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::prelude::*;
+
+const PAYLOAD_SIZES: [usize; 4] = [64, 1024, 4096, 64 * 1024];
+const STREAM_SIZE_BYTES: usize = 256 * 1024;
+
+fn params() -> ssb_box_stream::CipherParams {
+    ssb_box_stream::CipherParams::from_bytes(&[1u8; 32], &[2u8; 24])
+}
+
+/// Encrypt `STREAM_SIZE_BYTES` split into payloads of `payload_size` and return the cipher text.
+fn encrypt(payload_size: usize) -> Vec<u8> {
+    let mut cipher_text = Vec::new();
+    let mut encrypt = ssb_box_stream::Encrypt::new(&mut cipher_text, params());
+    let payload = vec![7u8; payload_size];
+    async_std::task::block_on(async {
+        for _ in 0..STREAM_SIZE_BYTES / payload_size {
+            encrypt.feed(payload.clone()).await.unwrap();
+        }
+        encrypt.close().await.unwrap();
+    });
+    cipher_text
+}
+
+fn box_stream(c: &mut Criterion) {
+    let mut group = c.benchmark_group("box_stream");
+    group.throughput(Throughput::Bytes(STREAM_SIZE_BYTES as u64));
+    for payload_size in PAYLOAD_SIZES.iter().copied() {
+        group.bench_function(BenchmarkId::new("encrypt", payload_size), |b| {
+            b.iter(|| encrypt(payload_size))
+        });
+
+        let cipher_text = encrypt(payload_size);
+        group.bench_function(BenchmarkId::new("decrypt", payload_size), |b| {
+            b.iter(|| {
+                let decrypt = ssb_box_stream::Decrypt::new(&cipher_text[..], params());
+                async_std::task::block_on(decrypt.try_for_each(|_| future::ok(()))).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, box_stream);
+criterion_main!(benches);
@@ -0,0 +1,67 @@
+//! Measures the latency of the handshake without network IO, with and without a shared
+//! [HandshakeCache].
+use criterion::{criterion_group, criterion_main, Criterion};
+use ssb_box_stream::{
+    HandshakeCache, HandshakeInput, HandshakeMachine, HandshakeOutput, NetworkKey, SecretKey,
+};
+
+/// Run the handshake between `client` and `server` to completion.
+fn run(mut client: HandshakeMachine, mut server: HandshakeMachine) {
+    let mut client_output = client.advance(HandshakeInput::Start).unwrap();
+    let mut server_output = server.advance(HandshakeInput::Start).unwrap();
+    loop {
+        match (client_output, server_output) {
+            (HandshakeOutput::Send(data), HandshakeOutput::Receive(_)) => {
+                client_output = client.advance(HandshakeInput::Sent).unwrap();
+                server_output = server.advance(HandshakeInput::Received(&data)).unwrap();
+            }
+            (HandshakeOutput::Receive(_), HandshakeOutput::Send(data)) => {
+                server_output = server.advance(HandshakeInput::Sent).unwrap();
+                client_output = client.advance(HandshakeInput::Received(&data)).unwrap();
+            }
+            (client, HandshakeOutput::Authorize(_)) => {
+                client_output = client;
+                server_output = server.advance(HandshakeInput::Authorized(true)).unwrap();
+            }
+            (HandshakeOutput::Done { .. }, HandshakeOutput::Done { .. }) => return,
+            outputs => panic!("Unexpected outputs {:?}", outputs),
+        }
+    }
+}
+
+fn handshake(c: &mut Criterion) {
+    let server_identity = SecretKey::generate();
+    let client_identity = SecretKey::generate();
+    let machines = |cache: Option<&HandshakeCache>| {
+        let mut client = HandshakeMachine::client(
+            &NetworkKey::MAIN_NET,
+            &server_identity.public_key(),
+            &client_identity,
+        );
+        let mut server = HandshakeMachine::server(&NetworkKey::MAIN_NET, &server_identity);
+        if let Some(cache) = cache {
+            client = client.with_cache(cache.clone());
+            server = server.with_cache(cache.clone());
+        }
+        (client, server)
+    };
+
+    let mut group = c.benchmark_group("handshake");
+    group.bench_function("uncached", |b| {
+        b.iter(|| {
+            let (client, server) = machines(None);
+            run(client, server)
+        })
+    });
+    let cache = HandshakeCache::new();
+    group.bench_function("cached", |b| {
+        b.iter(|| {
+            let (client, server) = machines(Some(&cache));
+            run(client, server)
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, handshake);
+criterion_main!(benches);
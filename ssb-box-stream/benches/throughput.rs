@@ -0,0 +1,83 @@
+//! Measures how fast data can be sent through an encrypted connection over a Unix socket.
+//!
+//! Each iteration sends a batch of messages and waits until the peer has received them. The
+//! `feed` variant lets [ssb_box_stream::Encrypt] coalesce messages into few writes while the
+//! `send` variant flushes after every message.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::prelude::*;
+
+const NETWORK_KEY: ssb_box_stream::NetworkKey = ssb_box_stream::NetworkKey([1u8; 32]);
+const BATCH_SIZE_BYTES: usize = 256 * 1024;
+
+type Sender = ssb_box_stream::Encrypt<futures::io::WriteHalf<async_std::os::unix::net::UnixStream>>;
+type Receiver =
+    ssb_box_stream::Decrypt<futures::io::ReadHalf<async_std::os::unix::net::UnixStream>>;
+
+async fn connect() -> (Sender, Receiver) {
+    let server_identity = ssb_box_stream::SecretKey::generate();
+    let client_identity = ssb_box_stream::SecretKey::generate();
+    let server = ssb_box_stream::Server::new(
+        &NETWORK_KEY,
+        &server_identity.public_key(),
+        &server_identity,
+    );
+    let client = ssb_box_stream::Client::new(
+        &NETWORK_KEY,
+        &server_identity.public_key(),
+        &client_identity.public_key(),
+        &client_identity,
+    );
+    let (client_stream, server_stream) = async_std::os::unix::net::UnixStream::pair().unwrap();
+    let (client, server) =
+        futures::join!(client.connect(client_stream), server.accept(server_stream));
+    let (sender, _) = client.unwrap();
+    let (_, receiver, _) = server.unwrap();
+    (sender, receiver)
+}
+
+async fn receive(receiver: &mut Receiver, mut len: usize) {
+    while len > 0 {
+        len -= receiver.try_next().await.unwrap().unwrap().len();
+    }
+}
+
+fn throughput(c: &mut Criterion) {
+    let (mut sender, mut receiver) = async_std::task::block_on(connect());
+    let mut group = c.benchmark_group("throughput");
+    group.throughput(Throughput::Bytes(BATCH_SIZE_BYTES as u64));
+    for message_size in [64usize, 1024, 4096, 64 * 1024].iter().copied() {
+        let messages = BATCH_SIZE_BYTES / message_size;
+        let message = vec![7u8; message_size];
+
+        group.bench_function(BenchmarkId::new("feed", message_size), |b| {
+            b.iter(|| {
+                async_std::task::block_on(async {
+                    let send = async {
+                        for _ in 0..messages {
+                            sender.feed(message.clone()).await.unwrap();
+                        }
+                        sender.flush().await.unwrap();
+                    };
+                    futures::join!(send, receive(&mut receiver, BATCH_SIZE_BYTES));
+                })
+            })
+        });
+
+        group.bench_function(BenchmarkId::new("send", message_size), |b| {
+            b.iter(|| {
+                async_std::task::block_on(async {
+                    let send = async {
+                        for _ in 0..messages {
+                            sender.send(message.clone()).await.unwrap();
+                        }
+                    };
+                    futures::join!(send, receive(&mut receiver, BATCH_SIZE_BYTES));
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
@@ -2,27 +2,23 @@ use futures::prelude::*;
 
 const SERVER_IDENTITY_SEED: [u8; 32] = [5u8; 32];
 const SOCKET_ADDR: &str = "localhost:5555";
-const NETWORK_IDENTIFIER: [u8; 32] = [1u8; 32];
+const NETWORK_KEY: ssb_box_stream::NetworkKey = ssb_box_stream::NetworkKey([1u8; 32]);
 
 #[async_std::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let server_identity_seed =
-        sodiumoxide::crypto::sign::ed25519::Seed::from_slice(&SERVER_IDENTITY_SEED).unwrap();
-    let server_identity_pk = sodiumoxide::crypto::sign::keypair_from_seed(&server_identity_seed).0;
+    let server_identity_pk =
+        ssb_box_stream::SecretKey::from_seed(&SERVER_IDENTITY_SEED).public_key();
 
-    let client_identity = sodiumoxide::crypto::sign::gen_keypair();
-    println!("Client identity {:?}", client_identity.0);
+    let client_identity = ssb_box_stream::SecretKey::generate();
+    println!("Client identity {:?}", client_identity.public_key());
 
-    let stream = async_std::net::TcpStream::connect(SOCKET_ADDR).await?;
-
-    let client = ssb_box_stream::Client::new(
-        &NETWORK_IDENTIFIER,
+    let (mut sender, mut receiver) = ssb_box_stream::connect_tcp(
+        SOCKET_ADDR,
+        &NETWORK_KEY,
         &server_identity_pk,
-        &client_identity.0,
-        &client_identity.1,
-    );
-
-    let (mut sender, mut receiver) = client.connect(stream).await?;
+        &client_identity,
+    )
+    .await?;
     println!("Connected to server");
 
     let receive_task = async_std::task::spawn(async move {
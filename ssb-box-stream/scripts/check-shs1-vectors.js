@@ -0,0 +1,318 @@
+// Recompute the shs1 test vectors in `src/testing.rs` from their keys and check every message
+// and the box stream parameters.
+//
+// This is an implementation of the handshake that is independent of the crate and of both of
+// its cryptographic backends. It follows the Scuttlebutt Protocol Guide:
+// https://ssbc.github.io/scuttlebutt-protocol-guide/#handshake
+//
+// Hashes, HMAC and Ed25519 signatures are provided by Node’s `crypto` module. Curve25519 and
+// the XSalsa20-Poly1305 secret box are implemented below.
+//
+// Usage: node scripts/check-shs1-vectors.js
+const crypto = require("crypto");
+const fs = require("fs");
+const path = require("path");
+
+const P = 2n ** 255n - 19n;
+
+function mod(a) {
+  const r = a % P;
+  return r < 0n ? r + P : r;
+}
+
+function pow(base, exp) {
+  let result = 1n;
+  base = mod(base);
+  while (exp > 0n) {
+    if (exp & 1n) result = mod(result * base);
+    base = mod(base * base);
+    exp >>= 1n;
+  }
+  return result;
+}
+
+function inv(a) {
+  return pow(a, P - 2n);
+}
+
+function bytesToInt(bytes) {
+  let result = 0n;
+  for (let i = bytes.length - 1; i >= 0; i--) {
+    result = (result << 8n) | BigInt(bytes[i]);
+  }
+  return result;
+}
+
+function intToBytes(value, length) {
+  const bytes = Buffer.alloc(length);
+  for (let i = 0; i < length; i++) {
+    bytes[i] = Number(value & 0xffn);
+    value >>= 8n;
+  }
+  return bytes;
+}
+
+// X25519 scalar multiplication (RFC 7748)
+function scalarmult(scalar, point) {
+  const k = Buffer.from(scalar);
+  k[0] &= 248;
+  k[31] &= 127;
+  k[31] |= 64;
+  const n = bytesToInt(k);
+  const u = bytesToInt(point) & ((1n << 255n) - 1n);
+
+  let [x2, z2, x3, z3] = [1n, 0n, u, 1n];
+  let swap = 0n;
+  for (let t = 254n; t >= 0n; t--) {
+    const bit = (n >> t) & 1n;
+    if (swap ^ bit) [x2, x3, z2, z3] = [x3, x2, z3, z2];
+    swap = bit;
+    const a = mod(x2 + z2);
+    const aa = mod(a * a);
+    const b = mod(x2 - z2);
+    const bb = mod(b * b);
+    const e = mod(aa - bb);
+    const c = mod(x3 + z3);
+    const d = mod(x3 - z3);
+    const da = mod(d * a);
+    const cb = mod(c * b);
+    x3 = mod((da + cb) ** 2n);
+    z3 = mod(u * (da - cb) ** 2n);
+    x2 = mod(aa * bb);
+    z2 = mod(e * (aa + 121665n * e));
+  }
+  if (swap) [x2, z2] = [x3, z3];
+  return intToBytes(mod(x2 * inv(z2)), 32);
+}
+
+function scalarmultBase(scalar) {
+  return scalarmult(scalar, intToBytes(9n, 32));
+}
+
+const PKCS8_ED25519_PREFIX = Buffer.from(
+  "302e020100300506032b657004220420",
+  "hex",
+);
+
+// Ed25519 keys from a seed
+function signKeypair(seed) {
+  const privateKey = crypto.createPrivateKey({
+    key: Buffer.concat([PKCS8_ED25519_PREFIX, seed]),
+    format: "der",
+    type: "pkcs8",
+  });
+  const publicKey = crypto
+    .createPublicKey(privateKey)
+    .export({ format: "der", type: "spki" })
+    .slice(-32);
+  return { seed, privateKey, publicKey };
+}
+
+function sign(keypair, data) {
+  return crypto.sign(null, data, keypair.privateKey);
+}
+
+// Convert an Ed25519 public key to a Curve25519 public key: u = (1 + y) / (1 - y)
+function signToBoxPk(publicKey) {
+  const y = bytesToInt(publicKey) & ((1n << 255n) - 1n);
+  return intToBytes(mod((1n + y) * inv(1n - y)), 32);
+}
+
+// Convert an Ed25519 seed to a Curve25519 secret key
+function signToBoxSk(keypair) {
+  const hash = crypto.createHash("sha512").update(keypair.seed).digest();
+  const sk = hash.slice(0, 32);
+  sk[0] &= 248;
+  sk[31] &= 127;
+  sk[31] |= 64;
+  return sk;
+}
+
+function sha256(...data) {
+  return crypto.createHash("sha256").update(Buffer.concat(data)).digest();
+}
+
+// `crypto_auth`: HMAC-SHA-512 truncated to 32 bytes
+function auth(key, data) {
+  return crypto.createHmac("sha512", key).update(data).digest().slice(0, 32);
+}
+
+function rotl(value, shift) {
+  return ((value << shift) | (value >>> (32 - shift))) >>> 0;
+}
+
+function salsaRounds(state) {
+  const x = state.slice();
+  const qr = (a, b, c, d) => {
+    x[b] ^= rotl((x[a] + x[d]) >>> 0, 7);
+    x[c] ^= rotl((x[b] + x[a]) >>> 0, 9);
+    x[d] ^= rotl((x[c] + x[b]) >>> 0, 13);
+    x[a] ^= rotl((x[d] + x[c]) >>> 0, 18);
+  };
+  for (let i = 0; i < 10; i++) {
+    qr(0, 4, 8, 12);
+    qr(5, 9, 13, 1);
+    qr(10, 14, 2, 6);
+    qr(15, 3, 7, 11);
+    qr(0, 1, 2, 3);
+    qr(5, 6, 7, 4);
+    qr(10, 11, 8, 9);
+    qr(15, 12, 13, 14);
+  }
+  return x.map((value) => value >>> 0);
+}
+
+function salsaState(key, input) {
+  const words = (buffer, count) =>
+    Array.from({ length: count }, (_, i) => buffer.readUInt32LE(4 * i));
+  const k = words(key, 8);
+  const n = words(input, 4);
+  const c = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+  return [c[0], ...k.slice(0, 4), c[1], ...n, c[2], ...k.slice(4), c[3]];
+}
+
+function hsalsa20(key, nonce) {
+  const x = salsaRounds(salsaState(key, nonce));
+  const out = Buffer.alloc(32);
+  [0, 5, 10, 15, 6, 7, 8, 9].forEach((index, i) =>
+    out.writeUInt32LE(x[index], 4 * i),
+  );
+  return out;
+}
+
+function salsa20Stream(key, nonce, length) {
+  const out = Buffer.alloc(length);
+  for (let block = 0; block * 64 < length; block++) {
+    const input = Buffer.alloc(16);
+    nonce.copy(input, 0);
+    input.writeUInt32LE(block, 8);
+    const state = salsaState(key, input);
+    const x = salsaRounds(state);
+    const bytes = Buffer.alloc(64);
+    x.forEach((value, i) =>
+      bytes.writeUInt32LE((value + state[i]) >>> 0, 4 * i),
+    );
+    bytes.copy(out, block * 64, 0, Math.min(64, length - block * 64));
+  }
+  return out;
+}
+
+function poly1305(key, message) {
+  const r = bytesToInt(key.slice(0, 16)) & 0x0ffffffc0ffffffc0ffffffc0fffffffn;
+  const s = bytesToInt(key.slice(16));
+  const p = 2n ** 130n - 5n;
+  let acc = 0n;
+  for (let i = 0; i < message.length; i += 16) {
+    const chunk = message.slice(i, i + 16);
+    const n = bytesToInt(Buffer.concat([chunk, Buffer.from([1])]));
+    acc = ((acc + n) * r) % p;
+  }
+  return intToBytes((acc + s) & ((1n << 128n) - 1n), 16);
+}
+
+// `crypto_secretbox_easy`: the authentication tag followed by the cipher text
+function secretbox(message, nonce, key) {
+  const subkey = hsalsa20(key, nonce.slice(0, 16));
+  const stream = salsa20Stream(subkey, nonce.slice(16), 32 + message.length);
+  const cipherText = Buffer.from(
+    message.map((byte, i) => byte ^ stream[32 + i]),
+  );
+  return Buffer.concat([poly1305(stream.slice(0, 32), cipherText), cipherText]);
+}
+
+function handshake(vector) {
+  const hex = (name) => Buffer.from(vector[name], "hex");
+  const networkKey = hex("network_key");
+  const client = signKeypair(hex("client_seed"));
+  const server = signKeypair(hex("server_seed"));
+  const clientSessionSk = hex("client_session_sk");
+  const clientSessionPk = scalarmultBase(clientSessionSk);
+  const serverSessionSk = hex("server_session_sk");
+  const serverSessionPk = scalarmultBase(serverSessionSk);
+  const zeroNonce = Buffer.alloc(24);
+
+  const clientHelloMac = auth(networkKey, clientSessionPk);
+  const serverHelloMac = auth(networkKey, serverSessionPk);
+
+  const ab = scalarmult(clientSessionSk, serverSessionPk);
+  const aB = scalarmult(clientSessionSk, signToBoxPk(server.publicKey));
+  const Ab = scalarmult(signToBoxSk(client), serverSessionPk);
+  if (!ab.equals(scalarmult(serverSessionSk, clientSessionPk))) {
+    throw new Error("Shared secret ab differs");
+  }
+  if (!aB.equals(scalarmult(signToBoxSk(server), clientSessionPk))) {
+    throw new Error("Shared secret aB differs");
+  }
+  if (!Ab.equals(scalarmult(serverSessionSk, signToBoxPk(client.publicKey)))) {
+    throw new Error("Shared secret Ab differs");
+  }
+
+  const signatureA = sign(
+    client,
+    Buffer.concat([networkKey, server.publicKey, sha256(ab)]),
+  );
+  const clientAuthenticate = secretbox(
+    Buffer.concat([signatureA, client.publicKey]),
+    zeroNonce,
+    sha256(networkKey, ab, aB),
+  );
+  const signatureB = sign(
+    server,
+    Buffer.concat([networkKey, signatureA, client.publicKey, sha256(ab)]),
+  );
+  const serverAccept = secretbox(
+    signatureB,
+    zeroNonce,
+    sha256(networkKey, ab, aB, Ab),
+  );
+  const secret = sha256(sha256(networkKey, ab, aB, Ab));
+
+  return {
+    client_hello: Buffer.concat([clientHelloMac, clientSessionPk]),
+    server_hello: Buffer.concat([serverHelloMac, serverSessionPk]),
+    client_authenticate: clientAuthenticate,
+    server_accept: serverAccept,
+    client_to_server_key: sha256(secret, server.publicKey),
+    client_to_server_nonce: serverHelloMac.slice(0, 24),
+    server_to_client_key: sha256(secret, client.publicKey),
+    server_to_client_nonce: clientHelloMac.slice(0, 24),
+  };
+}
+
+function readVectors() {
+  const source = fs.readFileSync(
+    path.join(__dirname, "..", "src", "testing.rs"),
+    "utf8",
+  );
+  const vectors = [];
+  const pattern = /Shs1Vector \{\n(\s+name: "[^}]*)\}/g;
+  for (const [, body] of source.matchAll(pattern)) {
+    const vector = {};
+    for (const [, name, value] of body.matchAll(/(\w+): "([^"]*)"/g)) {
+      vector[name] = value;
+    }
+    vectors.push(vector);
+  }
+  return vectors;
+}
+
+const vectors = readVectors();
+if (vectors.length === 0) {
+  throw new Error("No vectors found");
+}
+
+let failed = false;
+for (const vector of vectors) {
+  const expected = handshake(vector);
+  for (const [name, value] of Object.entries(expected)) {
+    if (value.toString("hex") !== vector[name]) {
+      console.error("Vector `%s`: unexpected %s", vector.name, name);
+      console.error("  expected %s", value.toString("hex"));
+      failed = true;
+    }
+  }
+}
+if (failed) {
+  process.exit(1);
+}
+console.log("%d shs1 vectors match", vectors.length);
@@ -0,0 +1,54 @@
+//! Constant-time comparisons.
+//!
+//! Comparing secret data like authentication tags, decrypted headers or keys with `==` may
+//! return as soon as the first byte differs, which leaks the position of the difference through
+//! timing. The functions in this module always inspect every byte. The length of the inputs is
+//! not treated as secret.
+use subtle::ConstantTimeEq as _;
+
+/// Returns `true` if `a` and `b` are equal.
+///
+/// The time only depends on the lengths of `a` and `b`.
+pub fn eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// Returns `true` if all bytes of `data` are zero.
+///
+/// The time only depends on the length of `data`.
+pub fn is_zero(data: &[u8]) -> bool {
+    data.iter()
+        .fold(0u8, |acc, byte| acc | byte)
+        .ct_eq(&0)
+        .into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn eq_and_is_zero() {
+        assert!(eq(b"", b""));
+        assert!(eq(&[1, 2, 3], &[1, 2, 3]));
+        assert!(!eq(&[1, 2, 3], &[1, 2, 4]));
+        assert!(!eq(&[0, 2, 3], &[1, 2, 3]));
+        assert!(!eq(&[1, 2, 3], &[1, 2]));
+
+        assert!(is_zero(&[]));
+        assert!(is_zero(&[0u8; 32]));
+        assert!(!is_zero(&[0, 0, 0, 0x80]));
+    }
+
+    #[test_strategy::proptest]
+    fn eq_like_slice_eq(a: Vec<u8>, b: Vec<u8>) {
+        prop_assert_eq!(eq(&a, &b), a == b);
+        prop_assert!(eq(&a, &a));
+    }
+
+    #[test_strategy::proptest]
+    fn is_zero_like_all_zero(data: Vec<u8>) {
+        prop_assert_eq!(is_zero(&data), data.iter().all(|byte| *byte == 0));
+    }
+}
@@ -0,0 +1,122 @@
+//! Cryptographic primitives used by the handshake and box stream.
+//!
+//! The primitives are provided by one of two backends that expose the same interface. The
+//! backend is selected with cargo features:
+//!
+//! * `rust-crypto` (default) uses pure-Rust implementations from [ed25519-dalek],
+//!   [x25519-dalek] and [crypto_secretbox] and does not require a C toolchain.
+//! * `sodium` uses [sodiumoxide] and libsodium. If both features are enabled this backend
+//!   takes precedence.
+//!
+//! The public API uses the backend independent key types from [crate::keys].
+//!
+//! Secret key types of both backends overwrite their bytes with zeros when they are dropped.
+//! Intermediate values that contain secrets, like the input to the key derivation of the
+//! handshake, are wrapped in [zeroize::Zeroizing].
+//!
+//! [ed25519-dalek]: https://docs.rs/ed25519-dalek
+//! [x25519-dalek]: https://docs.rs/x25519-dalek
+//! [crypto_secretbox]: https://docs.rs/crypto_secretbox
+//! [sodiumoxide]: https://docs.rs/sodiumoxide
+
+pub mod ct;
+
+#[cfg(not(any(feature = "rust-crypto", feature = "sodium")))]
+compile_error!("Either the `rust-crypto` or the `sodium` feature must be enabled");
+
+#[cfg(all(feature = "rust-crypto", not(feature = "sodium")))]
+mod rust;
+#[cfg(all(feature = "rust-crypto", not(feature = "sodium")))]
+pub use rust::*;
+
+#[cfg(feature = "sodium")]
+mod sodium;
+#[cfg(feature = "sodium")]
+pub use sodium::*;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(data: impl AsRef<[u8]>) -> String {
+        data.as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    // Expected values were computed with libsodium so that both backends are checked against
+    // the same reference.
+
+    #[test]
+    fn secretbox() {
+        let key = secretbox::key_from_array(&[1u8; 32]);
+        let nonce = secretbox::Nonce::from_slice(&[2u8; secretbox::NONCEBYTES]).unwrap();
+        let sealed = secretbox::seal(b"hello", &nonce, &key);
+        assert_eq!(hex(&sealed), SECRETBOX_SEALED);
+        assert_eq!(
+            secretbox::open(&sealed, &nonce, &key),
+            Ok(b"hello".to_vec())
+        );
+
+        let mut data = b"hello".to_vec();
+        let tag = secretbox::seal_detached(&mut data, &nonce, &key);
+        assert_eq!(hex(tag), SECRETBOX_SEALED[..32]);
+        assert_eq!(hex(&data), SECRETBOX_SEALED[32..]);
+        assert_eq!(
+            secretbox::open_detached(&mut data, &tag, &nonce, &key),
+            Ok(())
+        );
+        assert_eq!(data, b"hello");
+
+        let mut tampered = sealed;
+        tampered[20] ^= 1;
+        assert_eq!(secretbox::open(&tampered, &nonce, &key), Err(()));
+    }
+
+    #[test]
+    fn auth() {
+        let key = auth::key_from_array(&[3u8; 32]);
+        let tag = auth::authenticate(b"hello", &key);
+        assert_eq!(hex(tag), AUTH_TAG);
+        assert!(auth::verify(&tag, b"hello", &key));
+        assert!(!auth::verify(&tag, b"hellO", &key));
+    }
+
+    #[test]
+    fn sign_and_convert() {
+        let seed = sign::Seed::from_slice(&[4u8; 32]).unwrap();
+        let (public_key, secret_key) = sign::keypair_from_seed(&seed);
+        assert_eq!(hex(public_key), SIGN_PK);
+        let signature = sign::sign_detached(b"hello", &secret_key);
+        assert_eq!(hex(signature), SIGNATURE);
+        assert!(sign::verify_detached(&signature, b"hello", &public_key));
+        assert!(!sign::verify_detached(&signature, b"hellO", &public_key));
+
+        let box_pk = sign_to_box_pk(&public_key).unwrap();
+        let box_sk = sign_to_box_sk(&secret_key).unwrap();
+        assert_eq!(hex(box_pk), BOX_PK);
+        assert_eq!(hex(&box_sk), BOX_SK);
+
+        let (other_pk, other_sk) = box_::gen_keypair();
+        assert_eq!(
+            share_key(&box_pk, &other_sk).unwrap(),
+            share_key(&other_pk, &box_sk).unwrap()
+        );
+        let zero = box_::PublicKey::from_slice(&[0u8; 32]).unwrap();
+        assert!(share_key(&zero, &box_sk).is_none());
+    }
+
+    #[test]
+    fn hash_sha256() {
+        assert_eq!(hex(hash(b"hello")), HASH);
+    }
+
+    const SECRETBOX_SEALED: &str = "691178a0a6e933cf40579c1446303bc59500b29111";
+    const AUTH_TAG: &str = "858ff94f3623e5a0de368e91cd64ab3eb068b842dbeb6c04f4b90e6713ec0121";
+    const SIGN_PK: &str = "ca93ac1705187071d67b83c7ff0efe8108e8ec4530575d7726879333dbdabe7c";
+    const SIGNATURE: &str = "d791eb255e5323a7e4927a95e90a82ff65d44d78c898234a1e382c9e630e404482b2d1ed9c2cf51a7e0b07bf881b00027daf709770a8fa7ec55416553c169c07";
+    const BOX_PK: &str = "edd03cade80d29de6ea313a74ab369f4732ecb36649066b78b5b2dd664cb0417";
+    const BOX_SK: &str = "483e3c145d7e680a16676925fc045183d2f510cb2f660a1fc517c73762185d43";
+    const HASH: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+}
@@ -0,0 +1,299 @@
+//! Pure-Rust backend with the same interface as the `sodiumoxide` backend.
+//!
+//! Keys and other values are plain byte arrays wrapped in newtypes that mirror the
+//! corresponding `sodiumoxide` types.
+use sha2::Digest as _;
+
+/// Defines a newtype around a byte array with the constructors and accessors of the equally
+/// named `sodiumoxide` type. Like in `sodiumoxide`, secret types are not `Copy`, are compared in
+/// constant time, their `Debug` output does not show the content and their bytes are overwritten
+/// with zeros when dropped.
+macro_rules! byte_array_type {
+    ($(#[$meta:meta])* $name:ident, $size:expr) => {
+        byte_array_type!(
+            @define $(#[$meta])* #[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+            $name, $size
+        );
+
+        impl std::fmt::Debug for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "{}({:?})", stringify!($name), &self.0[..])
+            }
+        }
+    };
+    ($(#[$meta:meta])* secret $name:ident, $size:expr) => {
+        byte_array_type!(@define $(#[$meta])* #[derive(Clone, Eq)] $name, $size);
+
+        impl PartialEq for $name {
+            fn eq(&self, other: &Self) -> bool {
+                crate::crypto::ct::eq(&self.0, &other.0)
+            }
+        }
+
+        impl std::fmt::Debug for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "{}(****)", stringify!($name))
+            }
+        }
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                zeroize::Zeroize::zeroize(&mut self.0);
+            }
+        }
+
+        impl zeroize::ZeroizeOnDrop for $name {}
+    };
+    (@define $(#[$meta:meta])* $name:ident, $size:expr) => {
+        $(#[$meta])*
+        pub struct $name(pub [u8; $size]);
+
+        impl $name {
+            #[allow(dead_code)]
+            pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+                <[u8; $size] as std::convert::TryFrom<&[u8]>>::try_from(bytes)
+                    .ok()
+                    .map(Self)
+            }
+        }
+
+        impl AsRef<[u8]> for $name {
+            fn as_ref(&self) -> &[u8] {
+                &self.0
+            }
+        }
+    };
+}
+
+/// Fill an array with random bytes from the operating system.
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes).expect("Failed to obtain random bytes");
+    bytes
+}
+
+/// Does nothing. Exists for compatibility with [sodiumoxide::init].
+///
+/// [sodiumoxide::init]: https://docs.rs/sodiumoxide/latest/sodiumoxide/fn.init.html
+#[cfg(test)]
+#[allow(clippy::result_unit_err)]
+pub fn init() -> Result<(), ()> {
+    Ok(())
+}
+
+/// HMAC-SHA-512-256 message authentication, compatible with `crypto_auth` from libsodium.
+pub mod auth {
+    use hmac::Mac as _;
+
+    pub const KEYBYTES: usize = 32;
+    pub const TAGBYTES: usize = 32;
+
+    type HmacSha512 = hmac::Hmac<sha2::Sha512>;
+
+    byte_array_type!(secret Key, KEYBYTES);
+    byte_array_type!(
+        /// Authentication tag
+        Tag,
+        TAGBYTES
+    );
+
+    pub fn key_from_array(bytes: &[u8; 32]) -> Key {
+        Key(*bytes)
+    }
+
+    fn mac(data: &[u8], key: &Key) -> HmacSha512 {
+        let mut mac = HmacSha512::new_from_slice(&key.0).unwrap();
+        mac.update(data);
+        mac
+    }
+
+    pub fn authenticate(data: &[u8], key: &Key) -> Tag {
+        let output = mac(data, key).finalize().into_bytes();
+        Tag::from_slice(&output[..TAGBYTES]).unwrap()
+    }
+
+    /// Verify the tag in constant time.
+    pub fn verify(tag: &Tag, data: &[u8], key: &Key) -> bool {
+        mac(data, key).verify_truncated_left(&tag.0).is_ok()
+    }
+}
+
+/// XSalsa20-Poly1305 authenticated encryption, compatible with `crypto_secretbox` from
+/// libsodium.
+pub mod secretbox {
+    use crypto_secretbox::aead::{AeadInPlace as _, KeyInit as _};
+    use crypto_secretbox::XSalsa20Poly1305;
+
+    pub const KEYBYTES: usize = 32;
+    pub const NONCEBYTES: usize = 24;
+    pub const MACBYTES: usize = 16;
+
+    byte_array_type!(secret Key, KEYBYTES);
+    byte_array_type!(Nonce, NONCEBYTES);
+    byte_array_type!(
+        /// Authentication tag of an encrypted message
+        Tag,
+        MACBYTES
+    );
+
+    pub fn key_from_array(bytes: &[u8; 32]) -> Key {
+        Key(*bytes)
+    }
+
+    #[cfg(test)]
+    pub fn gen_key() -> Key {
+        Key(super::random_bytes())
+    }
+
+    #[cfg(test)]
+    pub fn gen_nonce() -> Nonce {
+        Nonce(super::random_bytes())
+    }
+
+    fn cipher(key: &Key) -> XSalsa20Poly1305 {
+        XSalsa20Poly1305::new(&key.0.into())
+    }
+
+    /// Encrypt `data` in place and return the authentication tag.
+    pub fn seal_detached(data: &mut [u8], nonce: &Nonce, key: &Key) -> Tag {
+        let tag = cipher(key)
+            .encrypt_in_place_detached(&nonce.0.into(), &[], data)
+            .expect("Message too large");
+        Tag(tag.into())
+    }
+
+    /// Encrypt `data` and return the authentication tag followed by the cipher text.
+    pub fn seal(data: &[u8], nonce: &Nonce, key: &Key) -> Vec<u8> {
+        let mut cipher_text = data.to_vec();
+        let tag = seal_detached(&mut cipher_text, nonce, key);
+        [&tag.0[..], &cipher_text].concat()
+    }
+
+    /// Authenticate and decrypt `data` in place.
+    #[allow(clippy::result_unit_err)]
+    pub fn open_detached(data: &mut [u8], tag: &Tag, nonce: &Nonce, key: &Key) -> Result<(), ()> {
+        cipher(key)
+            .decrypt_in_place_detached(&nonce.0.into(), &[], data, &tag.0.into())
+            .map_err(|_| ())
+    }
+
+    /// Authenticate and decrypt a message created by [seal].
+    #[allow(clippy::result_unit_err)]
+    pub fn open(data: &[u8], nonce: &Nonce, key: &Key) -> Result<Vec<u8>, ()> {
+        if data.len() < MACBYTES {
+            return Err(());
+        }
+        let (tag, cipher_text) = data.split_at(MACBYTES);
+        let tag = Tag::from_slice(tag).unwrap();
+        let mut plain_text = cipher_text.to_vec();
+        open_detached(&mut plain_text, &tag, nonce, key)?;
+        Ok(plain_text)
+    }
+}
+
+/// Ed25519 signatures, compatible with `crypto_sign` from libsodium.
+pub mod sign {
+    use ed25519_dalek::{Signer as _, Verifier as _};
+
+    pub const PUBLICKEYBYTES: usize = 32;
+    pub const SECRETKEYBYTES: usize = 64;
+    pub const SEEDBYTES: usize = 32;
+    pub const SIGNATUREBYTES: usize = 64;
+
+    byte_array_type!(PublicKey, PUBLICKEYBYTES);
+    byte_array_type!(
+        /// Seed followed by the public key like a libsodium secret key
+        secret SecretKey,
+        SECRETKEYBYTES
+    );
+    byte_array_type!(secret Seed, SEEDBYTES);
+    byte_array_type!(Signature, SIGNATUREBYTES);
+
+    pub fn keypair_from_seed(seed: &Seed) -> (PublicKey, SecretKey) {
+        let signing_key = ed25519_dalek::SigningKey::from_bytes(&seed.0);
+        (
+            PublicKey(signing_key.verifying_key().to_bytes()),
+            SecretKey(signing_key.to_keypair_bytes()),
+        )
+    }
+
+    pub fn gen_keypair() -> (PublicKey, SecretKey) {
+        keypair_from_seed(&Seed(super::random_bytes()))
+    }
+
+    impl SecretKey {
+        pub(super) fn signing_key(&self) -> ed25519_dalek::SigningKey {
+            ed25519_dalek::SigningKey::from_bytes(
+                &Seed::from_slice(&self.0[..SEEDBYTES]).unwrap().0,
+            )
+        }
+    }
+
+    pub fn sign_detached(data: &[u8], secret_key: &SecretKey) -> Signature {
+        Signature(secret_key.signing_key().sign(data).to_bytes())
+    }
+
+    pub fn verify_detached(signature: &Signature, data: &[u8], public_key: &PublicKey) -> bool {
+        let signature = ed25519_dalek::Signature::from_bytes(&signature.0);
+        ed25519_dalek::VerifyingKey::from_bytes(&public_key.0)
+            .map(|public_key| public_key.verify(data, &signature).is_ok())
+            .unwrap_or(false)
+    }
+}
+
+/// Curve25519 key exchange keys.
+pub mod box_ {
+    pub const PUBLICKEYBYTES: usize = 32;
+    pub const SECRETKEYBYTES: usize = 32;
+
+    byte_array_type!(PublicKey, PUBLICKEYBYTES);
+    byte_array_type!(secret SecretKey, SECRETKEYBYTES);
+
+    pub fn gen_keypair() -> (PublicKey, SecretKey) {
+        let secret_key = SecretKey(super::random_bytes());
+        (secret_key.public_key(), secret_key)
+    }
+
+    impl SecretKey {
+        pub fn public_key(&self) -> PublicKey {
+            PublicKey(x25519_dalek::x25519(
+                self.0,
+                x25519_dalek::X25519_BASEPOINT_BYTES,
+            ))
+        }
+    }
+}
+
+/// Compute the shared secret of two curve25519 keys.
+///
+/// Returns `None` if `public_key` has low order and the result would be all zeros.
+pub fn share_key(
+    public_key: &box_::PublicKey,
+    secret_key: &box_::SecretKey,
+) -> Option<box_::SecretKey> {
+    let shared = zeroize::Zeroizing::new(x25519_dalek::x25519(secret_key.0, public_key.0));
+    if super::ct::is_zero(&shared[..]) {
+        None
+    } else {
+        Some(box_::SecretKey(*shared))
+    }
+}
+
+/// SHA-256 hash of `data`.
+pub fn hash(data: impl AsRef<[u8]>) -> [u8; 32] {
+    sha2::Sha256::digest(data.as_ref()).into()
+}
+
+/// Convert a sign key to an exchange key.
+pub fn sign_to_box_pk(public_key: &sign::PublicKey) -> Option<box_::PublicKey> {
+    let public_key = ed25519_dalek::VerifyingKey::from_bytes(&public_key.0).ok()?;
+    Some(box_::PublicKey(public_key.to_montgomery().to_bytes()))
+}
+
+pub fn sign_to_box_sk(secret_key: &sign::SecretKey) -> Option<box_::SecretKey> {
+    let mut scalar = zeroize::Zeroizing::new(secret_key.signing_key().to_scalar_bytes());
+    scalar[0] &= 248;
+    scalar[31] &= 127;
+    scalar[31] |= 64;
+    Some(box_::SecretKey(*scalar))
+}
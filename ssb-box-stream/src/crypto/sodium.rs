@@ -1,10 +1,10 @@
-//! Facade for [sodiumoxide::crypto].
-//!
-//! Every submodule re-exports items from the corresponding [sodiumoxide::crypto] module.
+//! Backend that re-exports items from [sodiumoxide::crypto].
 use sodiumoxide::crypto::{hash::sha256, scalarmult::curve25519};
 use std::convert::TryFrom;
 
 pub use sodiumoxide::crypto::box_;
+#[cfg(test)]
+pub use sodiumoxide::init;
 
 pub mod auth {
     pub use sodiumoxide::crypto::auth::*;
@@ -5,12 +5,17 @@ use std::task::{Context, Poll};
 use crate::utils::ReadBuffer;
 
 /// A [Stream] of `Vec<u8>` that decrypts and authenticates data from the underlying `Reader`.
+///
+/// The stream ends without an error when the remote sends the goodbye packet. If the underlying
+/// reader ends before that, the stream yields an [std::io::ErrorKind::UnexpectedEof] error. Use
+/// [Decrypt::terminated] to find out how the stream ended.
 #[pin_project::pin_project]
 pub struct Decrypt<Reader: AsyncRead> {
     #[pin]
     reader: Reader,
     params: crate::cipher::Params,
     state: DecryptState,
+    bytes_read: crate::ByteCounter,
 }
 
 impl<Reader: AsyncRead> Decrypt<Reader> {
@@ -19,10 +24,37 @@ impl<Reader: AsyncRead> Decrypt<Reader> {
             reader,
             params,
             state: DecryptState::init(),
+            bytes_read: crate::ByteCounter::default(),
+        }
+    }
+
+    /// Number of bytes of complete packets read from the underlying reader, including packet
+    /// headers and the goodbye packet.
+    pub fn bytes_read(&self) -> &crate::ByteCounter {
+        &self.bytes_read
+    }
+
+    /// Returns how the stream ended or `None` if it has not ended yet.
+    pub fn terminated(&self) -> Option<Terminated> {
+        match self.state {
+            DecryptState::Terminated(terminated) => Some(terminated),
+            _ => None,
         }
     }
 }
 
+/// Describes how a [Decrypt] stream ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terminated {
+    /// The remote sent the goodbye packet and closed the stream properly.
+    Goodbye,
+    /// The underlying reader ended before the goodbye packet was received, for example because
+    /// the connection was dropped.
+    Eof,
+    /// Reading from the underlying reader or decrypting data failed.
+    Error,
+}
+
 /// Error when decrypting and authenticating data.
 #[derive(thiserror::Error, Debug)]
 pub enum DecryptError {
@@ -44,12 +76,12 @@ pub enum DecryptError {
 }
 
 enum DecryptState {
-    Closed,
+    Terminated(Terminated),
     ReadingHeader {
         buffer: ReadBuffer,
     },
     ReadingBody {
-        auth_tag: sodiumoxide::crypto::secretbox::Tag,
+        auth_tag: [u8; ssb_box_frame::TAG_SIZE],
         buffer: ReadBuffer,
     },
 }
@@ -67,9 +99,14 @@ impl<Reader: AsyncRead> Stream for Decrypt<Reader> {
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         let result = futures::ready!(self.as_mut().poll_next_inner(cx));
-        match result {
-            Some(Err(_)) | None => *self.project().state = DecryptState::Closed,
-            _ => (),
+        if let Some(Err(error)) = &result {
+            let terminated = match error {
+                DecryptError::Io(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    Terminated::Eof
+                }
+                _ => Terminated::Error,
+            };
+            *self.project().state = DecryptState::Terminated(terminated);
         }
         Poll::Ready(result)
     }
@@ -83,37 +120,38 @@ impl<Reader: AsyncRead> Decrypt<Reader> {
         loop {
             let mut this = self.as_mut().project();
             match &mut this.state {
-                DecryptState::Closed => return Poll::Ready(None),
+                DecryptState::Terminated(_) => return Poll::Ready(None),
                 DecryptState::ReadingHeader { buffer } => {
                     let boxed_header = futures::ready!(buffer.poll_read(cx, this.reader))?;
+                    this.bytes_read.add(boxed_header.len());
                     let mut boxed_header_array = [0u8; crate::cipher::BOXED_HEADER_SIZE];
                     boxed_header_array.copy_from_slice(&boxed_header);
                     match this
                         .params
                         .decrypt_header(&boxed_header_array)
-                        .map_err(|()| DecryptError::UnboxHeader)?
+                        .map_err(|_| DecryptError::UnboxHeader)?
                     {
-                        Some((len, auth_tag)) => {
-                            if len >= crate::cipher::MAX_PACKET_SIZE_BYTES {
-                                *this.state = DecryptState::Closed;
+                        Some(header) => {
+                            if header.body_len > crate::cipher::MAX_PACKET_SIZE_BYTES {
                                 return Poll::Ready(Some(Err(DecryptError::ExceededMaxPacketSize)));
                             }
                             *this.state = DecryptState::ReadingBody {
-                                auth_tag,
-                                buffer: ReadBuffer::new(len as usize),
+                                auth_tag: header.body_tag,
+                                buffer: ReadBuffer::new(header.body_len as usize),
                             }
                         }
                         None => {
-                            *this.state = DecryptState::Closed;
+                            *this.state = DecryptState::Terminated(Terminated::Goodbye);
                         }
                     }
                 }
                 DecryptState::ReadingBody { auth_tag, buffer } => {
                     let boxed_body = futures::ready!(buffer.poll_read(cx, this.reader))?;
+                    this.bytes_read.add(boxed_body.len());
                     let body = this
                         .params
                         .decrypt_body(auth_tag, &boxed_body)
-                        .map_err(|()| DecryptError::UnboxBody)?;
+                        .map_err(|_| DecryptError::UnboxBody)?;
                     *this.state = DecryptState::init();
                     return Poll::Ready(Some(Ok(body)));
                 }
@@ -3,14 +3,26 @@ use futures::prelude::*;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
+/// Amount of buffered encrypted data above which [Encrypt] writes to the underlying writer
+/// before accepting more data.
+const FLUSH_THRESHOLD: usize = 64 * 1024;
+
 /// A [Sink] for `Vec<u8>` that encrypts data and sends it to the underlying `Writer`
+///
+/// Encrypted packets are collected in a buffer and only written when the buffer exceeds a
+/// threshold or when the sink is flushed. Sending many small items with [SinkExt::feed] or
+/// [SinkExt::send_all] results in few writes to the underlying writer.
 #[pin_project::pin_project]
 pub struct Encrypt<Writer: AsyncWrite> {
     #[pin]
     writer: Writer,
     params: crate::cipher::Params,
-    /// Encrypted bytes to be written to the underlying `writer`.
-    buffer: bytes::Bytes,
+    /// Encrypted bytes to be written to the underlying `writer`. The allocation is reused once
+    /// the buffer has been written.
+    buffer: bytes::BytesMut,
+    /// `true` if the goodbye packet has been appended to `buffer`.
+    goodbye: bool,
+    bytes_written: crate::ByteCounter,
 }
 
 impl<Writer: AsyncWrite> Encrypt<Writer> {
@@ -18,22 +30,45 @@ impl<Writer: AsyncWrite> Encrypt<Writer> {
         Encrypt {
             writer,
             params,
-            buffer: bytes::Bytes::new(),
+            buffer: bytes::BytesMut::new(),
+            goodbye: false,
+            bytes_written: crate::ByteCounter::default(),
         }
     }
 
+    /// Number of encrypted bytes written to the underlying writer, including packet headers.
+    pub fn bytes_written(&self) -> &crate::ByteCounter {
+        &self.bytes_written
+    }
+
+    /// Encrypt the concatenation of `slices` without writing it.
+    ///
+    /// Like [Sink::start_send] this must only be called after [Sink::poll_ready] returned
+    /// successfully.
+    pub(crate) fn start_send_vectored(
+        self: Pin<&mut Self>,
+        slices: &[std::io::IoSlice<'_>],
+    ) -> Result<(), std::io::Error> {
+        let this = self.project();
+        this.params.encrypt_vectored(this.buffer, slices);
+        Ok(())
+    }
+
     fn poll_flush_buffer(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), std::io::Error>> {
         let mut this = self.project();
-        loop {
-            if this.buffer.is_empty() {
-                return Poll::Ready(Ok(()));
-            }
+        while !this.buffer.is_empty() {
             let written = futures::ready!(this.writer.as_mut().poll_write(cx, &*this.buffer))?;
+            if written == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
             this.buffer.advance(written);
+            this.bytes_written.add(written);
         }
+        this.buffer.clear();
+        Poll::Ready(Ok(()))
     }
 }
 
@@ -41,16 +76,15 @@ impl<Writer: AsyncWrite> Sink<Vec<u8>> for Encrypt<Writer> {
     type Error = std::io::Error;
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        futures::ready!(self.poll_flush_buffer(cx))?;
+        if self.buffer.len() >= FLUSH_THRESHOLD {
+            futures::ready!(self.poll_flush_buffer(cx))?;
+        }
         Poll::Ready(Ok(()))
     }
 
     fn start_send(self: Pin<&mut Self>, data: Vec<u8>) -> Result<(), Self::Error> {
-        debug_assert!(self.buffer.is_empty());
         let this = self.project();
-        let mut buffer = bytes::BytesMut::new();
-        this.params.encrypt(&mut buffer, &data);
-        *this.buffer = buffer.freeze();
+        this.params.encrypt(this.buffer, &data);
         Ok(())
     }
 
@@ -60,14 +94,67 @@ impl<Writer: AsyncWrite> Sink<Vec<u8>> for Encrypt<Writer> {
         Poll::Ready(Ok(()))
     }
 
+    /// Sends all buffered data followed by the goodbye packet and closes the underlying writer.
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
         let this = self.as_mut().project();
-        if this.buffer.is_empty() {
-            let goodbye = this.params.goodbye();
-            *this.buffer = bytes::Bytes::from(goodbye);
+        if !*this.goodbye {
+            this.buffer.extend_from_slice(&this.params.goodbye());
+            *this.goodbye = true;
         }
         futures::ready!(self.as_mut().poll_flush_buffer(cx))?;
         futures::ready!(self.project().writer.poll_close(cx))?;
         Poll::Ready(Ok(()))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Writer that counts the number of writes.
+    #[derive(Default)]
+    struct CountWrites {
+        data: Vec<u8>,
+        writes: usize,
+    }
+
+    impl AsyncWrite for CountWrites {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.data.extend_from_slice(buf);
+            self.writes += 1;
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[async_std::test]
+    async fn coalesce_writes() {
+        let params = crate::cipher::Params::arbitrary();
+        let mut encrypt = Encrypt::new(CountWrites::default(), params.clone());
+        for _ in 0..100 {
+            encrypt.feed(b"hello".to_vec()).await.unwrap();
+        }
+        encrypt.close().await.unwrap();
+        assert_eq!(encrypt.writer.writes, 1);
+        assert_eq!(
+            encrypt.bytes_written().get(),
+            encrypt.writer.data.len() as u64
+        );
+
+        let mut decrypt = crate::Decrypt::new(&encrypt.writer.data[..], params);
+        let packets = decrypt.by_ref().try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(packets.len(), 100);
+        assert_eq!(decrypt.bytes_read().get(), encrypt.writer.data.len() as u64);
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto as _;
+use std::sync::{Arc, Mutex};
+
+use crate::crypto;
+
+/// Reuses the conversion of identity keys to key exchange keys between handshakes.
+///
+/// Every handshake converts the Ed25519 identity key of the remote to a Curve25519 key. The
+/// conversion only depends on the long-term key, so repeated connections to the same peer can
+/// skip it. The shared secrets of a handshake always involve a fresh session key and are never
+/// cached.
+///
+/// Clones share the cache. Pass the same cache to all clients and servers of an application
+/// with [Client::with_cache][super::Client::with_cache] and
+/// [Server::with_cache][super::Server::with_cache]. At most `capacity` remote keys are kept. The
+/// keys that were added first are evicted first.
+#[derive(Clone)]
+pub struct HandshakeCache {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    capacity: usize,
+    /// Converted identity keys of remotes. `None` if the key cannot be converted.
+    remote: HashMap<[u8; 32], Option<crypto::box_::PublicKey>>,
+    /// Keys of `remote` in the order they were added
+    order: VecDeque<[u8; 32]>,
+    hits: u64,
+    misses: u64,
+}
+
+impl HandshakeCache {
+    /// Cache with room for 1000 remote keys.
+    pub fn new() -> Self {
+        Self::with_capacity(1000)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                capacity,
+                remote: HashMap::new(),
+                order: VecDeque::new(),
+                hits: 0,
+                misses: 0,
+            })),
+        }
+    }
+
+    /// Number of cached remote keys.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().remote.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of conversions that were answered from the cache.
+    pub fn hits(&self) -> u64 {
+        self.inner.lock().unwrap().hits
+    }
+
+    /// Number of conversions that were computed and added to the cache.
+    pub fn misses(&self) -> u64 {
+        self.inner.lock().unwrap().misses
+    }
+
+    /// Like [crypto::sign_to_box_pk] for the identity key of a remote.
+    pub(super) fn box_pk(
+        &self,
+        public_key: &crypto::sign::PublicKey,
+    ) -> Option<crypto::box_::PublicKey> {
+        let key = key_bytes(public_key);
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(&box_pk) = inner.remote.get(&key) {
+            inner.hits += 1;
+            return box_pk;
+        }
+        inner.misses += 1;
+        let box_pk = crypto::sign_to_box_pk(public_key);
+        if inner.capacity == 0 {
+            return box_pk;
+        }
+        if inner.remote.len() >= inner.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.remote.remove(&oldest);
+            }
+        }
+        inner.remote.insert(key, box_pk);
+        inner.order.push_back(key);
+        box_pk
+    }
+}
+
+impl Default for HandshakeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for HandshakeCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        f.debug_struct("HandshakeCache")
+            .field("capacity", &inner.capacity)
+            .field("remote", &inner.remote.len())
+            .field("hits", &inner.hits)
+            .field("misses", &inner.misses)
+            .finish()
+    }
+}
+
+fn key_bytes(public_key: &crypto::sign::PublicKey) -> [u8; 32] {
+    public_key
+        .as_ref()
+        .try_into()
+        .expect("Identity keys have 32 bytes")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn evict_oldest() {
+        let _ = crypto::init();
+        let cache = HandshakeCache::with_capacity(2);
+        let keys = (0..3)
+            .map(|_| crypto::sign::gen_keypair().0)
+            .collect::<Vec<_>>();
+        for key in &keys {
+            assert_eq!(cache.box_pk(key), crypto::sign_to_box_pk(key));
+        }
+        assert_eq!(cache.len(), 2);
+        assert_eq!((cache.hits(), cache.misses()), (0, 3));
+
+        cache.box_pk(&keys[2]);
+        assert_eq!(cache.hits(), 1);
+        cache.box_pk(&keys[0]);
+        assert_eq!(cache.misses(), 4);
+    }
+}
@@ -0,0 +1,742 @@
+//! Sans-IO implementation of the handshake.
+//!
+//! [HandshakeMachine] computes the messages of the handshake but leaves reading and writing them
+//! to the caller. This makes it possible to run the handshake in custom event loops and to test
+//! it deterministically. [Client][super::Client] and [Server][super::Server] drive the machine
+//! with an async stream.
+use std::convert::TryInto as _;
+
+use super::*;
+
+/// Input for [HandshakeMachine::advance].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeInput<'a> {
+    /// Start the handshake. This must be the first input.
+    Start,
+    /// The data of the last [HandshakeOutput::Send] has been sent to the peer.
+    Sent,
+    /// Data received from the peer. Must have exactly the length requested by the last
+    /// [HandshakeOutput::Receive].
+    Received(&'a [u8]),
+    /// Whether the client identity from the last [HandshakeOutput::Authorize] is allowed to
+    /// connect.
+    Authorized(bool),
+}
+
+/// Output of [HandshakeMachine::advance] that tells the caller what to do next.
+#[derive(Debug)]
+pub enum HandshakeOutput {
+    /// Send the data to the peer and then call [HandshakeMachine::advance] with
+    /// [HandshakeInput::Sent].
+    Send(Vec<u8>),
+    /// Read exactly this many bytes from the peer and pass them to [HandshakeMachine::advance]
+    /// with [HandshakeInput::Received].
+    Receive(usize),
+    /// Only for servers: decide whether the client with this identity may connect and call
+    /// [HandshakeMachine::advance] with [HandshakeInput::Authorized].
+    Authorize(PublicKey),
+    /// The handshake completed successfully.
+    Done {
+        params: crate::BoxStreamParams,
+        remote_identity_pk: PublicKey,
+    },
+}
+
+/// State machine for the client or server side of the handshake.
+///
+/// Start the handshake by calling [HandshakeMachine::advance] with [HandshakeInput::Start] and
+/// follow the returned [HandshakeOutput]s until [HandshakeOutput::Done] is returned.
+///
+/// ```
+/// # use ssb_box_stream::*;
+/// let server_identity = SecretKey::generate();
+/// let client_identity = SecretKey::generate();
+/// let mut client = HandshakeMachine::client(
+///     &NetworkKey::MAIN_NET,
+///     &server_identity.public_key(),
+///     &client_identity,
+/// );
+/// let mut server = HandshakeMachine::server(&NetworkKey::MAIN_NET, &server_identity);
+///
+/// assert!(matches!(
+///     server.advance(HandshakeInput::Start),
+///     Ok(HandshakeOutput::Receive(64))
+/// ));
+/// let client_hello = match client.advance(HandshakeInput::Start) {
+///     Ok(HandshakeOutput::Send(data)) => data,
+///     _ => unreachable!(),
+/// };
+/// assert!(matches!(
+///     server.advance(HandshakeInput::Received(&client_hello)),
+///     Ok(HandshakeOutput::Send(_))
+/// ));
+/// ```
+///
+/// # Panics
+///
+/// [HandshakeMachine::advance] panics if the input does not match the last output or if it is
+/// called after the handshake completed or failed.
+#[derive(Debug)]
+pub struct HandshakeMachine {
+    state: State,
+}
+
+#[derive(Debug)]
+enum State {
+    ClientStart(ClientState),
+    ClientSendingHello(ClientState),
+    ClientAwaitingHello(ClientState),
+    ClientSendingAuthenticate {
+        client: ClientState,
+        server_session_pk: crypto::box_::PublicKey,
+        accept: Accept,
+    },
+    ClientAwaitingAccept {
+        client: ClientState,
+        server_session_pk: crypto::box_::PublicKey,
+        accept: Accept,
+    },
+    ServerStart(Endpoint),
+    ServerAwaitingHello(Endpoint),
+    ServerSendingHello {
+        server: Endpoint,
+        client_session_pk: crypto::box_::PublicKey,
+        authenticate: Authenticate,
+    },
+    ServerAwaitingAuthenticate {
+        server: Endpoint,
+        client_session_pk: crypto::box_::PublicKey,
+        authenticate: Authenticate,
+    },
+    ServerAwaitingAuthorization {
+        server: Endpoint,
+        client_session_pk: crypto::box_::PublicKey,
+        accept: Accept,
+    },
+    ServerSendingAccept {
+        server: Endpoint,
+        client_session_pk: crypto::box_::PublicKey,
+        accept: Accept,
+    },
+    Finished,
+}
+
+#[derive(Debug)]
+struct ClientState {
+    endpoint: Endpoint,
+    server_identity_pk: crypto::sign::PublicKey,
+}
+
+impl HandshakeMachine {
+    /// Create the client side of the handshake.
+    pub fn client(
+        network_key: &NetworkKey,
+        server_identity_pk: &PublicKey,
+        identity_sk: &SecretKey,
+    ) -> Self {
+        Self::client_from_signer(
+            network_key,
+            server_identity_pk,
+            Arc::new(identity_sk.clone()),
+        )
+    }
+
+    /// Create the server side of the handshake.
+    pub fn server(network_key: &NetworkKey, identity_sk: &SecretKey) -> Self {
+        Self::server_from_signer(network_key, Arc::new(identity_sk.clone()))
+    }
+
+    /// Like [HandshakeMachine::client] but delegates the operations with the identity key to
+    /// `signer`.
+    pub fn client_from_signer(
+        network_key: &NetworkKey,
+        server_identity_pk: &PublicKey,
+        signer: Arc<dyn Signer>,
+    ) -> Self {
+        Self::new_client(
+            network_key.to_crypto(),
+            signer.public_key().to_crypto(),
+            signer,
+            server_identity_pk.to_crypto(),
+        )
+    }
+
+    /// Like [HandshakeMachine::server] but delegates the operations with the identity key to
+    /// `signer`.
+    pub fn server_from_signer(network_key: &NetworkKey, signer: Arc<dyn Signer>) -> Self {
+        Self::new_server(
+            network_key.to_crypto(),
+            signer.public_key().to_crypto(),
+            signer,
+        )
+    }
+
+    pub(super) fn new_client(
+        network_identifier: crypto::auth::Key,
+        identity_pk: crypto::sign::PublicKey,
+        signer: Arc<dyn Signer>,
+        server_identity_pk: crypto::sign::PublicKey,
+    ) -> Self {
+        let endpoint = Endpoint::generate(network_identifier, identity_pk, signer);
+        Self {
+            state: State::ClientStart(ClientState {
+                endpoint,
+                server_identity_pk,
+            }),
+        }
+    }
+
+    pub(super) fn new_server(
+        network_identifier: crypto::auth::Key,
+        identity_pk: crypto::sign::PublicKey,
+        signer: Arc<dyn Signer>,
+    ) -> Self {
+        let endpoint = Endpoint::generate(network_identifier, identity_pk, signer);
+        Self {
+            state: State::ServerStart(endpoint),
+        }
+    }
+
+    /// Use the session key pair derived from `session_sk` instead of a random one.
+    ///
+    /// The messages of the handshake are fully determined by the session keys. Fixing them
+    /// allows checking the messages against test vectors.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the handshake has already been started.
+    pub(crate) fn with_session_key(mut self, session_sk: &[u8; 32]) -> Self {
+        let session_sk = crypto::box_::SecretKey::from_slice(session_sk).unwrap();
+        match &mut self.state {
+            State::ClientStart(client) => client.endpoint.set_session_key(session_sk),
+            State::ServerStart(server) => server.set_session_key(session_sk),
+            state => panic!("Cannot set session key in state {:?}", state),
+        }
+        self
+    }
+
+    /// Reuse key conversions from `cache`. See [HandshakeCache].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the handshake has already been started.
+    pub fn with_cache(mut self, cache: HandshakeCache) -> Self {
+        match &mut self.state {
+            State::ClientStart(client) => client.endpoint.cache = Some(cache),
+            State::ServerStart(server) => server.cache = Some(cache),
+            state => panic!("Cannot set cache in state {:?}", state),
+        }
+        self
+    }
+
+    /// Advance the handshake with `input` and return what the caller needs to do next.
+    ///
+    /// Once an error is returned the handshake has failed and must not be advanced further.
+    pub fn advance(&mut self, input: HandshakeInput<'_>) -> Result<HandshakeOutput, Error> {
+        let state = std::mem::replace(&mut self.state, State::Finished);
+        let (state, output) = match (state, input) {
+            (State::ClientStart(client), HandshakeInput::Start) => {
+                let hello = client.endpoint.hello_message();
+                (
+                    State::ClientSendingHello(client),
+                    HandshakeOutput::Send(hello),
+                )
+            }
+            (State::ClientSendingHello(client), HandshakeInput::Sent) => (
+                State::ClientAwaitingHello(client),
+                HandshakeOutput::Receive(HELLO_MESSAGE_LEN),
+            ),
+            (State::ClientAwaitingHello(client), HandshakeInput::Received(data)) => {
+                let server_session_pk = client.endpoint.hello_verify(expect_data(data))?;
+                let authenticate = Authenticate::for_client(
+                    &client.endpoint,
+                    &client.server_identity_pk,
+                    &server_session_pk,
+                )?;
+                let accept =
+                    Accept::for_client(&client.endpoint, &client.server_identity_pk, authenticate)?;
+                let message = authenticate_message(&client.endpoint, &accept);
+                (
+                    State::ClientSendingAuthenticate {
+                        client,
+                        server_session_pk,
+                        accept,
+                    },
+                    HandshakeOutput::Send(message),
+                )
+            }
+            (
+                State::ClientSendingAuthenticate {
+                    client,
+                    server_session_pk,
+                    accept,
+                },
+                HandshakeInput::Sent,
+            ) => (
+                State::ClientAwaitingAccept {
+                    client,
+                    server_session_pk,
+                    accept,
+                },
+                HandshakeOutput::Receive(ACCEPT_CIPHER_MESSAGE_LEN),
+            ),
+            (
+                State::ClientAwaitingAccept {
+                    client,
+                    server_session_pk,
+                    accept,
+                },
+                HandshakeInput::Received(data),
+            ) => {
+                accept_message_verify(&client.server_identity_pk, &accept, expect_data(data))?;
+                let params = box_stream_params(
+                    &client.endpoint,
+                    &accept,
+                    &client.server_identity_pk,
+                    &server_session_pk,
+                );
+                (
+                    State::Finished,
+                    HandshakeOutput::Done {
+                        params,
+                        remote_identity_pk: PublicKey::from_crypto(&client.server_identity_pk),
+                    },
+                )
+            }
+
+            (State::ServerStart(server), HandshakeInput::Start) => (
+                State::ServerAwaitingHello(server),
+                HandshakeOutput::Receive(HELLO_MESSAGE_LEN),
+            ),
+            (State::ServerAwaitingHello(server), HandshakeInput::Received(data)) => {
+                let client_session_pk = server.hello_verify(expect_data(data))?;
+                let authenticate = Authenticate::for_server(&server, &client_session_pk)?;
+                let hello = server.hello_message();
+                (
+                    State::ServerSendingHello {
+                        server,
+                        client_session_pk,
+                        authenticate,
+                    },
+                    HandshakeOutput::Send(hello),
+                )
+            }
+            (
+                State::ServerSendingHello {
+                    server,
+                    client_session_pk,
+                    authenticate,
+                },
+                HandshakeInput::Sent,
+            ) => (
+                State::ServerAwaitingAuthenticate {
+                    server,
+                    client_session_pk,
+                    authenticate,
+                },
+                HandshakeOutput::Receive(CLIENT_AUTHENTICATE_MESSAGE_LEN),
+            ),
+            (
+                State::ServerAwaitingAuthenticate {
+                    server,
+                    client_session_pk,
+                    authenticate,
+                },
+                HandshakeInput::Received(data),
+            ) => {
+                let accept = authenticate.verify_and_accept(&server, &expect_data(data))?;
+                let client_identity_pk = PublicKey::from_crypto(&accept.client_identity_pk);
+                (
+                    State::ServerAwaitingAuthorization {
+                        server,
+                        client_session_pk,
+                        accept,
+                    },
+                    HandshakeOutput::Authorize(client_identity_pk),
+                )
+            }
+            (
+                State::ServerAwaitingAuthorization {
+                    server,
+                    client_session_pk,
+                    accept,
+                },
+                HandshakeInput::Authorized(authorized),
+            ) => {
+                if !authorized {
+                    return Err(Error::ClientUnauthorized(PublicKey::from_crypto(
+                        &accept.client_identity_pk,
+                    )));
+                }
+                let message = accept_message(&server, &accept)?;
+                (
+                    State::ServerSendingAccept {
+                        server,
+                        client_session_pk,
+                        accept,
+                    },
+                    HandshakeOutput::Send(message),
+                )
+            }
+            (
+                State::ServerSendingAccept {
+                    server,
+                    client_session_pk,
+                    accept,
+                },
+                HandshakeInput::Sent,
+            ) => {
+                let params = box_stream_params(
+                    &server,
+                    &accept,
+                    &accept.client_identity_pk,
+                    &client_session_pk,
+                );
+                (
+                    State::Finished,
+                    HandshakeOutput::Done {
+                        params,
+                        remote_identity_pk: PublicKey::from_crypto(&accept.client_identity_pk),
+                    },
+                )
+            }
+
+            (State::Finished, input) => {
+                panic!("Handshake advanced with {:?} after it finished", input)
+            }
+            (state, input) => panic!("Unexpected input {:?} in state {:?}", input, state),
+        };
+        self.state = state;
+        Ok(output)
+    }
+
+    /// Convert an error from reading the data requested by [HandshakeOutput::Receive] into a handshake
+    /// [Error].
+    ///
+    /// If the server closes the connection instead of sending the `accept` message it most likely
+    /// rejected the client. In that case [Error::AcceptConnectionClosed] is returned.
+    pub fn read_error(&self, error: std::io::Error) -> Error {
+        match self.state {
+            State::ClientAwaitingAccept { .. }
+                if error.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                Error::AcceptConnectionClosed
+            }
+            _ => Error::ReadFailed(error),
+        }
+    }
+}
+
+/// Convert the data of [HandshakeInput::Received] into an array of the length that was requested.
+fn expect_data<const N: usize>(data: &[u8]) -> [u8; N] {
+    data.try_into().unwrap_or_else(|_| {
+        panic!(
+            "Received {} bytes but {} bytes were requested",
+            data.len(),
+            N
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Runs the handshake between two machines without any IO.
+    fn run(
+        mut client: HandshakeMachine,
+        mut server: HandshakeMachine,
+        authorized: bool,
+    ) -> (
+        Result<HandshakeOutput, Error>,
+        Result<HandshakeOutput, Error>,
+    ) {
+        let mut client_output = client.advance(HandshakeInput::Start);
+        let mut server_output = server.advance(HandshakeInput::Start);
+        loop {
+            match (&client_output, &server_output) {
+                (Ok(HandshakeOutput::Send(data)), Ok(HandshakeOutput::Receive(_))) => {
+                    let data = data.clone();
+                    client_output = client.advance(HandshakeInput::Sent);
+                    server_output = server.advance(HandshakeInput::Received(&data));
+                }
+                (Ok(HandshakeOutput::Receive(_)), Ok(HandshakeOutput::Send(data))) => {
+                    let data = data.clone();
+                    server_output = server.advance(HandshakeInput::Sent);
+                    client_output = client.advance(HandshakeInput::Received(&data));
+                }
+                (_, Ok(HandshakeOutput::Authorize(_))) => {
+                    server_output = server.advance(HandshakeInput::Authorized(authorized));
+                }
+                _ => return (client_output, server_output),
+            }
+        }
+    }
+
+    #[test]
+    fn handshake() {
+        let server_identity = SecretKey::generate();
+        let client_identity = SecretKey::generate();
+        let client = HandshakeMachine::client(
+            &NetworkKey::MAIN_NET,
+            &server_identity.public_key(),
+            &client_identity,
+        );
+        let server = HandshakeMachine::server(&NetworkKey::MAIN_NET, &server_identity);
+
+        match run(client, server, true) {
+            (
+                Ok(HandshakeOutput::Done {
+                    params: client_params,
+                    remote_identity_pk: server_pk,
+                }),
+                Ok(HandshakeOutput::Done {
+                    params: server_params,
+                    remote_identity_pk: client_pk,
+                }),
+            ) => {
+                assert_eq!(client_params.send, server_params.receive);
+                assert_eq!(client_params.receive, server_params.send);
+                assert_eq!(server_pk, server_identity.public_key());
+                assert_eq!(client_pk, client_identity.public_key());
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn unauthorized() {
+        let server_identity = SecretKey::generate();
+        let client_identity = SecretKey::generate();
+        let client = HandshakeMachine::client(
+            &NetworkKey::MAIN_NET,
+            &server_identity.public_key(),
+            &client_identity,
+        );
+        let server = HandshakeMachine::server(&NetworkKey::MAIN_NET, &server_identity);
+
+        let (client_output, server_output) = run(client, server, false);
+        assert!(matches!(client_output, Ok(HandshakeOutput::Receive(_))));
+        assert!(matches!(
+            server_output,
+            Err(Error::ClientUnauthorized(client_pk)) if client_pk == client_identity.public_key()
+        ));
+    }
+
+    /// Signer that counts its operations and fails if `fail` is set.
+    #[derive(Debug)]
+    struct TestSigner {
+        key: SecretKey,
+        fail: bool,
+        calls: std::sync::atomic::AtomicUsize,
+    }
+
+    impl Signer for TestSigner {
+        fn public_key(&self) -> PublicKey {
+            self.key.public_key()
+        }
+
+        fn sign(&self, data: &[u8]) -> Result<[u8; 64], SignerError> {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if self.fail {
+                return Err(SignerError::new("device not available"));
+            }
+            Signer::sign(&self.key, data)
+        }
+
+        fn key_exchange(&self, public_key: &[u8; 32]) -> Result<[u8; 32], SignerError> {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.key.key_exchange(public_key)
+        }
+    }
+
+    fn test_signer(fail: bool) -> Arc<TestSigner> {
+        Arc::new(TestSigner {
+            key: SecretKey::generate(),
+            fail,
+            calls: Default::default(),
+        })
+    }
+
+    #[test]
+    fn signer() {
+        let server_signer = test_signer(false);
+        let client_signer = test_signer(false);
+        let client = HandshakeMachine::client_from_signer(
+            &NetworkKey::MAIN_NET,
+            &server_signer.public_key(),
+            client_signer.clone(),
+        );
+        let server =
+            HandshakeMachine::server_from_signer(&NetworkKey::MAIN_NET, server_signer.clone());
+
+        let (client_output, server_output) = run(client, server, true);
+        assert!(matches!(client_output, Ok(HandshakeOutput::Done { .. })));
+        assert!(matches!(
+            server_output,
+            Ok(HandshakeOutput::Done { remote_identity_pk, .. })
+                if remote_identity_pk == client_signer.public_key()
+        ));
+        for signer in &[client_signer, server_signer] {
+            assert_eq!(signer.calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+        }
+    }
+
+    #[test]
+    fn signer_fails() {
+        let server_identity = SecretKey::generate();
+        let client = HandshakeMachine::client_from_signer(
+            &NetworkKey::MAIN_NET,
+            &server_identity.public_key(),
+            test_signer(true),
+        );
+        let server = HandshakeMachine::server(&NetworkKey::MAIN_NET, &server_identity);
+
+        let (client_output, _) = run(client, server, true);
+        assert!(matches!(client_output, Err(Error::Signer(_))));
+    }
+
+    #[test]
+    fn cached_handshake() {
+        let server_identity = SecretKey::generate();
+        let client_identity = SecretKey::generate();
+        let cache = HandshakeCache::new();
+        for _ in 0..2 {
+            let client = HandshakeMachine::client(
+                &NetworkKey::MAIN_NET,
+                &server_identity.public_key(),
+                &client_identity,
+            )
+            .with_cache(cache.clone());
+            let server = HandshakeMachine::server(&NetworkKey::MAIN_NET, &server_identity)
+                .with_cache(cache.clone());
+            let (client_output, server_output) = run(client, server, true);
+            assert!(matches!(client_output, Ok(HandshakeOutput::Done { .. })));
+            assert!(matches!(server_output, Ok(HandshakeOutput::Done { .. })));
+        }
+        // Each handshake converts the identity keys of the client and the server
+        assert_eq!((cache.misses(), cache.hits()), (2, 2));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn wrong_network_key() {
+        let server_identity = SecretKey::generate();
+        let client_identity = SecretKey::generate();
+        let client = HandshakeMachine::client(
+            &NetworkKey([1u8; 32]),
+            &server_identity.public_key(),
+            &client_identity,
+        );
+        let server = HandshakeMachine::server(&NetworkKey::MAIN_NET, &server_identity);
+
+        let (_, server_output) = run(client, server, true);
+        assert!(matches!(server_output, Err(Error::HelloMessageInvalid)));
+    }
+
+    /// Returns a valid `hello` message for `session_pk`.
+    fn hello_message(network_key: &NetworkKey, session_pk: &[u8; 32]) -> Vec<u8> {
+        let tag = crypto::auth::authenticate(session_pk, &network_key.to_crypto());
+        [tag.as_ref(), session_pk].concat()
+    }
+
+    fn expect_send(output: Result<HandshakeOutput, Error>) -> Vec<u8> {
+        match output {
+            Ok(HandshakeOutput::Send(data)) => data,
+            output => panic!("Unexpected output {:?}", output),
+        }
+    }
+
+    #[test]
+    fn client_wrong_network_key() {
+        let server_identity = SecretKey::generate();
+        let mut client = HandshakeMachine::client(
+            &NetworkKey::MAIN_NET,
+            &server_identity.public_key(),
+            &SecretKey::generate(),
+        );
+        let (server_session_pk, _) = crypto::box_::gen_keypair();
+        let server_hello = hello_message(&NetworkKey([1u8; 32]), &server_session_pk.0);
+
+        expect_send(client.advance(HandshakeInput::Start));
+        client.advance(HandshakeInput::Sent).unwrap();
+        let output = client.advance(HandshakeInput::Received(&server_hello));
+        assert!(matches!(output, Err(Error::HelloMessageInvalid)));
+    }
+
+    #[test]
+    fn reflected_hello() {
+        let server_identity = SecretKey::generate();
+        let mut client = HandshakeMachine::client(
+            &NetworkKey::MAIN_NET,
+            &server_identity.public_key(),
+            &SecretKey::generate(),
+        );
+
+        let client_hello = expect_send(client.advance(HandshakeInput::Start));
+        client.advance(HandshakeInput::Sent).unwrap();
+        let output = client.advance(HandshakeInput::Received(&client_hello));
+        assert!(matches!(output, Err(Error::HelloMessageReflected)));
+    }
+
+    #[test]
+    fn server_low_order_session_key() {
+        for session_pk in low_order_points() {
+            let mut server =
+                HandshakeMachine::server(&NetworkKey::MAIN_NET, &SecretKey::generate());
+            let client_hello = hello_message(&NetworkKey::MAIN_NET, &session_pk);
+
+            server.advance(HandshakeInput::Start).unwrap();
+            let output = server.advance(HandshakeInput::Received(&client_hello));
+            assert!(matches!(output, Err(Error::SessionKeyInvalid)));
+        }
+    }
+
+    #[test]
+    fn client_low_order_session_key() {
+        for session_pk in low_order_points() {
+            let mut client = HandshakeMachine::client(
+                &NetworkKey::MAIN_NET,
+                &SecretKey::generate().public_key(),
+                &SecretKey::generate(),
+            );
+            let server_hello = hello_message(&NetworkKey::MAIN_NET, &session_pk);
+
+            expect_send(client.advance(HandshakeInput::Start));
+            client.advance(HandshakeInput::Sent).unwrap();
+            let output = client.advance(HandshakeInput::Received(&server_hello));
+            assert!(matches!(output, Err(Error::SessionKeyInvalid)));
+        }
+    }
+
+    #[test]
+    fn client_low_order_server_identity() {
+        // Encoding of the neutral element of the ed25519 group
+        let mut identity = [0u8; 32];
+        identity[0] = 1;
+        let server_identity_pk = PublicKey(identity);
+        let mut client = HandshakeMachine::client(
+            &NetworkKey::MAIN_NET,
+            &server_identity_pk,
+            &SecretKey::generate(),
+        );
+        let mut server = HandshakeMachine::server(&NetworkKey::MAIN_NET, &SecretKey::generate());
+
+        server.advance(HandshakeInput::Start).unwrap();
+        let client_hello = expect_send(client.advance(HandshakeInput::Start));
+        client.advance(HandshakeInput::Sent).unwrap();
+        let server_hello = expect_send(server.advance(HandshakeInput::Received(&client_hello)));
+        let output = client.advance(HandshakeInput::Received(&server_hello));
+        assert!(matches!(
+            output,
+            Err(Error::IdentityKeyInvalid(pk)) if pk == server_identity_pk
+        ));
+    }
+
+    /// Curve25519 points that result in an all-zero shared secret for any secret key.
+    fn low_order_points() -> Vec<[u8; 32]> {
+        let mut one = [0u8; 32];
+        one[0] = 1;
+        vec![[0u8; 32], one]
+    }
+}
@@ -0,0 +1,957 @@
+// We allow this to align with the names used in the protocol guide.
+#![allow(non_snake_case)]
+
+use futures::prelude::*;
+use std::sync::Arc;
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+use crate::crypto;
+use crate::{NetworkKey, PublicKey, SecretKey, Signer, SignerError};
+
+mod cache;
+mod machine;
+pub use cache::HandshakeCache;
+pub use machine::{HandshakeInput, HandshakeMachine, HandshakeOutput};
+
+const HELLO_MESSAGE_LEN: usize = 64;
+const CLIENT_AUTHENTICATE_MESSAGE_LEN: usize = 112;
+const ACCEPT_CIPHER_MESSAGE_LEN: usize = 80;
+
+/// Errors returned when running the handshake protocol.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Failed to read data from remote
+    #[error("Failed to read data from remote")]
+    ReadFailed(#[source] std::io::Error),
+    /// Failed to write data to remote
+    #[error("Failed to write data to remote")]
+    WriteFailed(#[source] std::io::Error),
+
+    /// Failed to verify `hello` message from remote. This happens if the remote uses a
+    /// different network key.
+    #[error(
+        "Failed to verify `hello` message from remote. Remote may use a different network key"
+    )]
+    HelloMessageInvalid,
+    /// The remote sent our own `hello` message back
+    #[error("Remote sent our own `hello` message back")]
+    HelloMessageReflected,
+    /// The session key from the remote `hello` message is a low-order point and results in an
+    /// all-zero shared secret
+    #[error("Session key of remote is invalid")]
+    SessionKeyInvalid,
+    /// The identity key of the remote cannot be used for key exchange because it is not a valid
+    /// point or has low order
+    #[error("Identity key {0} of remote is invalid")]
+    IdentityKeyInvalid(PublicKey),
+
+    /// Failed to decrypt `authenticate` message
+    #[error("Failed to decrypt `authenticate` message")]
+    AuthenticateMessageDecryptFailed,
+    /// Invalid signature in `authenticate` message
+    #[error("Invalid signature in `authenticate` message")]
+    AuthenticateSignatureInvalid,
+
+    #[error("Server did not accept handshake. Server identity may be wrong")]
+    AcceptConnectionClosed,
+
+    /// Failed to decrypt `accept` message
+    #[error("Failed to decrypt `accept` message. Client may have used the wrong key")]
+    AcceptMessageDecryptFailed,
+    /// Invalid signature in `accept` message
+    #[error("Invalid signature in `accept` message")]
+    AcceptSignatureInvalid,
+
+    /// The authorization callback passed to [Server::accept_with] rejected the client
+    #[error("Client {0} is not authorized")]
+    ClientUnauthorized(PublicKey),
+
+    /// The remote did not complete a step of the handshake or the whole handshake in time
+    #[error("Handshake timed out")]
+    Timeout,
+
+    /// The [Signer] of the identity key failed
+    #[error("Failed to use identity key")]
+    Signer(#[source] SignerError),
+}
+
+/// Limits for how long the handshake may take.
+///
+/// A limit of `None` disables the timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Maximum time for reading or writing a single handshake message
+    pub phase: Option<Duration>,
+    /// Maximum time for the whole handshake
+    pub total: Option<Duration>,
+}
+
+impl Timeouts {
+    /// No timeouts. The handshake may wait forever for the remote.
+    pub const NONE: Timeouts = Timeouts {
+        phase: None,
+        total: None,
+    };
+
+    async fn phase<T>(&self, future: impl Future<Output = Result<T, Error>>) -> Result<T, Error> {
+        with_timeout(self.phase, future).await
+    }
+
+    async fn total<T>(&self, future: impl Future<Output = Result<T, Error>>) -> Result<T, Error> {
+        with_timeout(self.total, future).await
+    }
+}
+
+impl Default for Timeouts {
+    /// Five seconds for every message and fifteen seconds for the whole handshake.
+    fn default() -> Self {
+        Self {
+            phase: Some(Duration::from_secs(5)),
+            total: Some(Duration::from_secs(15)),
+        }
+    }
+}
+
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return future.await,
+    };
+    futures::pin_mut!(future);
+    match future::select(future, futures_timer::Delay::new(timeout)).await {
+        future::Either::Left((result, _)) => result,
+        future::Either::Right(((), _)) => Err(Error::Timeout),
+    }
+}
+
+/// Parameters to establish a secure connection as a client
+///
+/// ```no_run
+/// # use ssb_box_stream::*;
+/// # use futures::prelude::*;
+/// # #[async_std::main]
+/// # async fn main () -> Result<(), Box<dyn std::error::Error>> {
+/// let server_identity_pk = SecretKey::generate().public_key();
+/// let client_identity = SecretKey::generate();
+/// let client = Client::new(
+///     &NetworkKey::MAIN_NET,
+///     &server_identity_pk,
+///     &client_identity.public_key(),
+///     &client_identity,
+/// );
+///
+/// let mut stream = async_std::net::TcpStream::connect("localhost:5555").await.unwrap();
+/// let (send, recv) = client.connect(stream).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Client {
+    network_identifier: crypto::auth::Key,
+    identity_pk: crypto::sign::PublicKey,
+    signer: Arc<dyn Signer>,
+    server_identity_pk: crypto::sign::PublicKey,
+    timeouts: Timeouts,
+    cache: Option<HandshakeCache>,
+}
+
+impl Client {
+    pub fn new(
+        network_key: &NetworkKey,
+        server_identity_pk: &PublicKey,
+        identity_pk: &PublicKey,
+        identity_sk: &SecretKey,
+    ) -> Self {
+        Self {
+            network_identifier: network_key.to_crypto(),
+            identity_pk: identity_pk.to_crypto(),
+            signer: Arc::new(identity_sk.clone()),
+            server_identity_pk: server_identity_pk.to_crypto(),
+            timeouts: Timeouts::default(),
+            cache: None,
+        }
+    }
+
+    /// Like [Client::new] but delegates the operations with the identity key to `signer`.
+    pub fn from_signer(
+        network_key: &NetworkKey,
+        server_identity_pk: &PublicKey,
+        signer: Arc<dyn Signer>,
+    ) -> Self {
+        Self {
+            network_identifier: network_key.to_crypto(),
+            identity_pk: signer.public_key().to_crypto(),
+            signer,
+            server_identity_pk: server_identity_pk.to_crypto(),
+            timeouts: Timeouts::default(),
+            cache: None,
+        }
+    }
+
+    /// Use `timeouts` for the handshake instead of [Timeouts::default].
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Reuse key conversions from `cache`. See [HandshakeCache].
+    pub fn with_cache(mut self, cache: HandshakeCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Execute the handshake protocol for the client and return the encrypted connection.
+    pub async fn connect<Stream: AsyncWrite + AsyncRead + Unpin>(
+        &self,
+        mut stream: Stream,
+    ) -> Result<
+        (
+            crate::Encrypt<futures::io::WriteHalf<Stream>>,
+            crate::Decrypt<futures::io::ReadHalf<Stream>>,
+        ),
+        Error,
+    > {
+        let params = self.handshake(&mut stream).await?;
+        Ok(crate::box_stream(stream, params))
+    }
+
+    async fn handshake(
+        &self,
+        stream: impl AsyncRead + AsyncWrite + Unpin,
+    ) -> Result<crate::BoxStreamParams, Error> {
+        self.timeouts.total(self.handshake_steps(stream)).await
+    }
+
+    async fn handshake_steps(
+        &self,
+        stream: impl AsyncRead + AsyncWrite + Unpin,
+    ) -> Result<crate::BoxStreamParams, Error> {
+        let mut machine = HandshakeMachine::new_client(
+            self.network_identifier.clone(),
+            self.identity_pk,
+            self.signer.clone(),
+            self.server_identity_pk,
+        );
+        if let Some(cache) = &self.cache {
+            machine = machine.with_cache(cache.clone());
+        }
+        let (params, _) =
+            run_machine(machine, stream, &self.timeouts, |_| future::ready(true)).await?;
+        Ok(params)
+    }
+}
+
+/// Parameters to establish a secure connection as a server
+///
+/// ```no_run
+/// # use ssb_box_stream::*;
+/// # use futures::prelude::*;
+/// # #[async_std::main]
+/// # async fn main () -> Result<(), Box<dyn std::error::Error>> {
+/// let server_identity = SecretKey::generate();
+/// let server = Server::new(
+///     &NetworkKey::MAIN_NET,
+///     &server_identity.public_key(),
+///     &server_identity,
+/// );
+///
+/// let mut listener = async_std::net::TcpListener::bind("localhost:5555").await.unwrap();
+/// let (stream, _) = listener.accept().await?;
+///
+/// let (send, recv, client_key) = server.accept(stream).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Server {
+    network_identifier: crypto::auth::Key,
+    identity_pk: crypto::sign::PublicKey,
+    signer: Arc<dyn Signer>,
+    timeouts: Timeouts,
+    cache: Option<HandshakeCache>,
+}
+
+impl Server {
+    pub fn new(network_key: &NetworkKey, identity_pk: &PublicKey, identity_sk: &SecretKey) -> Self {
+        Self {
+            network_identifier: network_key.to_crypto(),
+            identity_pk: identity_pk.to_crypto(),
+            signer: Arc::new(identity_sk.clone()),
+            timeouts: Timeouts::default(),
+            cache: None,
+        }
+    }
+
+    /// Like [Server::new] but delegates the operations with the identity key to `signer`.
+    pub fn from_signer(network_key: &NetworkKey, signer: Arc<dyn Signer>) -> Self {
+        Self {
+            network_identifier: network_key.to_crypto(),
+            identity_pk: signer.public_key().to_crypto(),
+            signer,
+            timeouts: Timeouts::default(),
+            cache: None,
+        }
+    }
+
+    /// Use `timeouts` for the handshake instead of [Timeouts::default].
+    ///
+    /// Servers that accept connections from anyone should keep timeouts enabled so that stalled
+    /// clients cannot tie up connections.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Reuse key conversions from `cache`. See [HandshakeCache].
+    pub fn with_cache(mut self, cache: HandshakeCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Execute the handshake protocol for the server and return the encrypted connection
+    /// and the clients public identity key
+    pub async fn accept<Stream: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: Stream,
+    ) -> Result<
+        (
+            crate::Encrypt<futures::io::WriteHalf<Stream>>,
+            crate::Decrypt<futures::io::ReadHalf<Stream>>,
+            PublicKey,
+        ),
+        Error,
+    > {
+        self.accept_with(stream, |_| future::ready(true)).await
+    }
+
+    /// Like [Server::accept] but only accepts clients for which `authorize` resolves to `true`.
+    ///
+    /// `authorize` is called with the clients public identity key after the client has
+    /// authenticated itself. If it resolves to `false` the handshake fails with
+    /// [Error::ClientUnauthorized] before the server sends its `accept` message. The time spent
+    /// in `authorize` counts towards the total handshake timeout.
+    pub async fn accept_with<Stream, Authorize, AuthorizeFuture>(
+        &self,
+        stream: Stream,
+        authorize: Authorize,
+    ) -> Result<
+        (
+            crate::Encrypt<futures::io::WriteHalf<Stream>>,
+            crate::Decrypt<futures::io::ReadHalf<Stream>>,
+            PublicKey,
+        ),
+        Error,
+    >
+    where
+        Stream: AsyncRead + AsyncWrite + Unpin,
+        Authorize: FnOnce(PublicKey) -> AuthorizeFuture,
+        AuthorizeFuture: Future<Output = bool>,
+    {
+        let mut stream = stream;
+        let (params, client_identity_pk) = self.handshake_with(&mut stream, authorize).await?;
+        let (sink, stream) = crate::box_stream(stream, params);
+        Ok((sink, stream, client_identity_pk))
+    }
+
+    /// Execute the handshake protocol for the server and return the box stream
+    /// parameters and the clients public identity key
+    #[cfg(test)]
+    async fn handshake(
+        &self,
+        stream: impl AsyncRead + AsyncWrite + Unpin,
+    ) -> Result<(crate::BoxStreamParams, PublicKey), Error> {
+        self.handshake_with(stream, |_| future::ready(true)).await
+    }
+
+    async fn handshake_with<AuthorizeFuture: Future<Output = bool>>(
+        &self,
+        stream: impl AsyncRead + AsyncWrite + Unpin,
+        authorize: impl FnOnce(PublicKey) -> AuthorizeFuture,
+    ) -> Result<(crate::BoxStreamParams, PublicKey), Error> {
+        self.timeouts
+            .total(self.handshake_steps(stream, authorize))
+            .await
+    }
+
+    async fn handshake_steps<AuthorizeFuture: Future<Output = bool>>(
+        &self,
+        stream: impl AsyncRead + AsyncWrite + Unpin,
+        authorize: impl FnOnce(PublicKey) -> AuthorizeFuture,
+    ) -> Result<(crate::BoxStreamParams, PublicKey), Error> {
+        let mut machine = HandshakeMachine::new_server(
+            self.network_identifier.clone(),
+            self.identity_pk,
+            self.signer.clone(),
+        );
+        if let Some(cache) = &self.cache {
+            machine = machine.with_cache(cache.clone());
+        }
+        run_machine(machine, stream, &self.timeouts, authorize).await
+    }
+}
+
+/// Drive `machine` by reading from and writing to `stream`.
+async fn run_machine<AuthorizeFuture: Future<Output = bool>>(
+    mut machine: HandshakeMachine,
+    mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    timeouts: &Timeouts,
+    authorize: impl FnOnce(PublicKey) -> AuthorizeFuture,
+) -> Result<(crate::BoxStreamParams, PublicKey), Error> {
+    let mut authorize = Some(authorize);
+    let mut buffer = Vec::new();
+    let mut output = machine.advance(HandshakeInput::Start)?;
+    loop {
+        output = match output {
+            HandshakeOutput::Send(data) => {
+                timeouts
+                    .phase(stream.write_all(&data).map_err(Error::WriteFailed))
+                    .await?;
+                machine.advance(HandshakeInput::Sent)?
+            }
+            HandshakeOutput::Receive(len) => {
+                buffer.resize(len, 0);
+                timeouts
+                    .phase(
+                        stream
+                            .read_exact(&mut buffer)
+                            .map_err(|error| machine.read_error(error)),
+                    )
+                    .await?;
+                machine.advance(HandshakeInput::Received(&buffer))?
+            }
+            HandshakeOutput::Authorize(client_identity_pk) => {
+                let authorize = authorize.take().expect("Authorization requested twice");
+                let authorized = authorize(client_identity_pk).await;
+                machine.advance(HandshakeInput::Authorized(authorized))?
+            }
+            HandshakeOutput::Done {
+                params,
+                remote_identity_pk,
+            } => return Ok((params, remote_identity_pk)),
+        }
+    }
+}
+
+fn authenticate_message(client: &Endpoint, accept: &Accept) -> Vec<u8> {
+    let key = accept.authenticate.message_key();
+    let msg = [
+        accept.detached_signature_A.as_ref(),
+        client.identity_pk.as_ref(),
+    ]
+    .concat();
+    crypto::secretbox::seal(&msg, &zero_nonce(), &key)
+}
+
+fn accept_message(server: &Endpoint, shared_secrets: &Accept) -> Result<Vec<u8>, Error> {
+    let msg = shared_secrets.signature_payload();
+    let detached_signature_B = server.sign(&msg)?;
+
+    Ok(crypto::secretbox::seal(
+        detached_signature_B.as_ref(),
+        &zero_nonce(),
+        &shared_secrets.message_key(),
+    ))
+}
+
+fn accept_message_verify(
+    server_identity_pk: &crypto::sign::PublicKey,
+    accept: &Accept,
+    cipher_msg: [u8; ACCEPT_CIPHER_MESSAGE_LEN],
+) -> Result<(), Error> {
+    let detached_signature_B_payload =
+        crypto::secretbox::open(&cipher_msg, &zero_nonce(), &accept.message_key())
+            .map_err(|()| Error::AcceptMessageDecryptFailed)?;
+    let detached_signature_B =
+        crypto::sign::Signature::from_slice(&detached_signature_B_payload).unwrap();
+
+    let msg = accept.signature_payload();
+    if crypto::sign::verify_detached(&detached_signature_B, &msg, server_identity_pk) {
+        Ok(())
+    } else {
+        Err(Error::AcceptSignatureInvalid)
+    }
+}
+
+/// Data that identifies an endpoint (client or server) at the start of the handshake.
+#[derive(Debug, Clone)]
+struct Endpoint {
+    identity_pk: crypto::sign::PublicKey,
+    signer: Arc<dyn Signer>,
+    session_pk: crypto::box_::PublicKey,
+    session_sk: crypto::box_::SecretKey,
+    network_identifier: crypto::auth::Key,
+    cache: Option<HandshakeCache>,
+}
+
+impl Endpoint {
+    /// Create an endpoint with a new session key pair.
+    fn generate(
+        network_identifier: crypto::auth::Key,
+        identity_pk: crypto::sign::PublicKey,
+        signer: Arc<dyn Signer>,
+    ) -> Self {
+        let (session_pk, session_sk) = crypto::box_::gen_keypair();
+        Self {
+            identity_pk,
+            signer,
+            session_pk,
+            session_sk,
+            network_identifier,
+            cache: None,
+        }
+    }
+
+    fn sign(&self, msg: &[u8]) -> Result<crypto::sign::Signature, Error> {
+        let signature = self.signer.sign(msg).map_err(Error::Signer)?;
+        Ok(crypto::sign::Signature::from_slice(&signature).unwrap())
+    }
+
+    /// Compute the shared secret of our identity key and the session key of the remote.
+    fn share_session_key_with_identity(
+        &self,
+        remote_session_pk: &crypto::box_::PublicKey,
+    ) -> Result<crypto::box_::SecretKey, Error> {
+        let mut public_key = [0u8; 32];
+        public_key.copy_from_slice(remote_session_pk.as_ref());
+        let shared = Zeroizing::new(
+            self.signer
+                .key_exchange(&public_key)
+                .map_err(Error::Signer)?,
+        );
+        if crypto::ct::is_zero(&shared[..]) {
+            return Err(Error::SessionKeyInvalid);
+        }
+        Ok(crypto::box_::SecretKey::from_slice(&shared[..]).unwrap())
+    }
+
+    /// Compute the shared secret of our session key and the identity key of the remote.
+    fn share_identity_key(
+        &self,
+        remote_identity_pk: &crypto::sign::PublicKey,
+    ) -> Result<crypto::box_::SecretKey, Error> {
+        let remote_box_pk = match &self.cache {
+            Some(cache) => cache.box_pk(remote_identity_pk),
+            None => crypto::sign_to_box_pk(remote_identity_pk),
+        };
+        remote_box_pk
+            .and_then(|remote_box_pk| crypto::share_key(&remote_box_pk, &self.session_sk))
+            .ok_or_else(|| Error::IdentityKeyInvalid(PublicKey::from_crypto(remote_identity_pk)))
+    }
+
+    /// Replace the session key pair with the one for `session_sk`.
+    fn set_session_key(&mut self, session_sk: crypto::box_::SecretKey) {
+        self.session_pk = session_sk.public_key();
+        self.session_sk = session_sk;
+    }
+
+    fn hello_message(&self) -> Vec<u8> {
+        [
+            crypto::auth::authenticate(self.session_pk.as_ref(), &self.network_identifier).as_ref(),
+            self.session_pk.as_ref(),
+        ]
+        .concat()
+    }
+
+    fn hello_verify(&self, msg: [u8; HELLO_MESSAGE_LEN]) -> Result<crypto::box_::PublicKey, Error> {
+        let (tag, payload) = msg.split_at(crypto::auth::TAGBYTES);
+        let tag = crypto::auth::Tag::from_slice(tag).unwrap();
+
+        if !crypto::auth::verify(&tag, payload, &self.network_identifier) {
+            return Err(Error::HelloMessageInvalid);
+        }
+        let remote_session_public = crypto::box_::PublicKey::from_slice(payload).unwrap();
+        if crypto::ct::eq(remote_session_public.as_ref(), self.session_pk.as_ref()) {
+            return Err(Error::HelloMessageReflected);
+        }
+        Ok(remote_session_public)
+    }
+}
+
+/// Compute the shared secret with the remote session key.
+fn share_session_key(
+    remote_session_pk: &crypto::box_::PublicKey,
+    secret_key: &crypto::box_::SecretKey,
+) -> Result<crypto::box_::SecretKey, Error> {
+    crypto::share_key(remote_session_pk, secret_key).ok_or(Error::SessionKeyInvalid)
+}
+
+/// Data that is shared by the server and client before the client sends the `authenticate` message.
+#[derive(Debug)]
+struct Authenticate {
+    ab: crypto::box_::SecretKey,
+    aB: crypto::box_::SecretKey,
+    network_identifier: crypto::auth::Key,
+    server_session_pk: crypto::box_::PublicKey,
+}
+
+impl Authenticate {
+    fn for_client(
+        client: &Endpoint,
+        server_identity_pk: &crypto::sign::PublicKey,
+        server_session_pk: &crypto::box_::PublicKey,
+    ) -> Result<Self, Error> {
+        let ab = share_session_key(server_session_pk, &client.session_sk)?;
+        let aB = client.share_identity_key(server_identity_pk)?;
+
+        Ok(Self {
+            ab,
+            aB,
+            network_identifier: client.network_identifier.clone(),
+            server_session_pk: *server_session_pk,
+        })
+    }
+    fn for_server(
+        server: &Endpoint,
+        client_session_pk: &crypto::box_::PublicKey,
+    ) -> Result<Self, Error> {
+        let ab = share_session_key(client_session_pk, &server.session_sk)?;
+        let aB = server.share_session_key_with_identity(client_session_pk)?;
+
+        Ok(Self {
+            ab,
+            aB,
+            network_identifier: server.network_identifier.clone(),
+            server_session_pk: server.session_pk,
+        })
+    }
+
+    /// Verifies a clients `authenticate` messages and return the [Accept] data.
+    fn verify_and_accept(
+        self,
+        server: &Endpoint,
+        client_authenticate_msg: &[u8; CLIENT_AUTHENTICATE_MESSAGE_LEN],
+    ) -> Result<Accept, Error> {
+        let key = self.message_key();
+        let msg = crypto::secretbox::open(&client_authenticate_msg[..], &zero_nonce(), &key)
+            .map_err(|()| Error::AuthenticateMessageDecryptFailed)?;
+        let (detached_signature_A, client_identity_pk) = msg.split_at(crypto::sign::SIGNATUREBYTES);
+        let detached_signature_A =
+            crypto::sign::Signature::from_slice(detached_signature_A).unwrap();
+        let client_identity_pk = crypto::sign::PublicKey::from_slice(client_identity_pk).unwrap();
+        let signature_payload = self.signature_payload(&server.identity_pk);
+        if crypto::sign::verify_detached(
+            &detached_signature_A,
+            &signature_payload,
+            &client_identity_pk,
+        ) {
+            Accept::for_server(server, self, &client_identity_pk, &detached_signature_A)
+        } else {
+            Err(Error::AuthenticateSignatureInvalid)
+        }
+    }
+
+    /// Returns the key that encrypts the `authenticate` message of the client.
+    fn message_key(&self) -> crypto::secretbox::Key {
+        let key_data = Zeroizing::new(crypto::hash(Zeroizing::new(
+            [
+                self.network_identifier.as_ref(),
+                self.ab.as_ref(),
+                self.aB.as_ref(),
+            ]
+            .concat(),
+        )));
+        crypto::secretbox::key_from_array(&key_data)
+    }
+
+    /// Returns the payload that is signed by the client and part of the `authenticate` message.
+    fn signature_payload(&self, server_identity_pk: &crypto::sign::PublicKey) -> Vec<u8> {
+        [
+            self.network_identifier.as_ref(),
+            server_identity_pk.as_ref(),
+            crypto::hash(&self.ab).as_ref(),
+        ]
+        .concat()
+    }
+}
+
+/// Data that is shared by the server and client before the server sends the `accept` message.
+#[derive(Debug)]
+struct Accept {
+    authenticate: Authenticate,
+    Ab: crypto::box_::SecretKey,
+    detached_signature_A: crypto::sign::Signature,
+    client_identity_pk: crypto::sign::PublicKey,
+}
+
+impl Accept {
+    fn for_client(
+        client: &Endpoint,
+        server_identity_pk: &crypto::sign::PublicKey,
+        authenticate: Authenticate,
+    ) -> Result<Self, Error> {
+        let Ab = client.share_session_key_with_identity(&authenticate.server_session_pk)?;
+
+        let msg = authenticate.signature_payload(server_identity_pk);
+        let detached_signature_A = client.sign(&msg)?;
+
+        Ok(Self {
+            authenticate,
+            Ab,
+            detached_signature_A,
+            client_identity_pk: client.identity_pk,
+        })
+    }
+
+    fn for_server(
+        server: &Endpoint,
+        authenticate: Authenticate,
+        client_identity_pk: &crypto::sign::PublicKey,
+        detached_signature_A: &crypto::sign::Signature,
+    ) -> Result<Self, Error> {
+        let Ab = server.share_identity_key(client_identity_pk)?;
+
+        Ok(Self {
+            authenticate,
+            Ab,
+            detached_signature_A: *detached_signature_A,
+            client_identity_pk: *client_identity_pk,
+        })
+    }
+
+    /// Returns the key that encrypts the `accept` message of the server.
+    fn message_key(&self) -> crypto::secretbox::Key {
+        let key_data = Zeroizing::new(crypto::hash(Zeroizing::new(
+            [
+                self.authenticate.network_identifier.as_ref(),
+                self.authenticate.ab.as_ref(),
+                self.authenticate.aB.as_ref(),
+                self.Ab.as_ref(),
+            ]
+            .concat(),
+        )));
+        crypto::secretbox::key_from_array(&key_data)
+    }
+
+    /// Returns the payload that is signed by the server and part of the `accept` message.
+    fn signature_payload(&self) -> Vec<u8> {
+        [
+            self.authenticate.network_identifier.as_ref(),
+            self.detached_signature_A.as_ref(),
+            self.client_identity_pk.as_ref(),
+            crypto::hash(&self.authenticate.ab).as_ref(),
+        ]
+        .concat()
+    }
+}
+
+fn box_stream_params(
+    local: &Endpoint,
+    accept: &Accept,
+    remote_identity_pk: &crypto::sign::PublicKey,
+    remote_session_pk: &crypto::box_::PublicKey,
+) -> crate::BoxStreamParams {
+    crate::BoxStreamParams {
+        send: crate::cipher::Params::new(
+            box_stream_key(accept, remote_identity_pk),
+            box_stream_nonce(local, remote_session_pk),
+        ),
+        receive: crate::cipher::Params::new(
+            box_stream_key(accept, &local.identity_pk),
+            box_stream_nonce(local, &local.session_pk),
+        ),
+    }
+}
+
+fn box_stream_nonce(
+    endpoint: &Endpoint,
+    receiver_key: &crypto::box_::PublicKey,
+) -> crypto::secretbox::Nonce {
+    crypto::secretbox::Nonce::from_slice(
+        &crypto::auth::authenticate(receiver_key.as_ref(), &endpoint.network_identifier).as_ref()
+            [0..crypto::secretbox::NONCEBYTES],
+    )
+    .unwrap()
+}
+
+fn box_stream_key(
+    accept: &Accept,
+    receiver_session_key: &crypto::sign::PublicKey,
+) -> crypto::secretbox::Key {
+    let message_key_hash = Zeroizing::new(crypto::hash(accept.message_key()));
+    let key_data = Zeroizing::new(crypto::hash(Zeroizing::new(
+        [&message_key_hash[..], receiver_session_key.as_ref()].concat(),
+    )));
+    crypto::secretbox::key_from_array(&key_data)
+}
+
+fn zero_nonce() -> crypto::secretbox::Nonce {
+    crypto::secretbox::Nonce::from_slice(&[0u8; 24]).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn run() {
+        let _ = crypto::init();
+
+        let (mut client_stream, mut server_stream) = duplex_pipe();
+
+        let network_key = NetworkKey([0u8; 32]);
+        let server_identity = SecretKey::generate();
+        let server = Server::new(
+            &network_key,
+            &server_identity.public_key(),
+            &server_identity,
+        );
+
+        let client_identity = SecretKey::generate();
+        let client = Client::new(
+            &network_key,
+            &server_identity.public_key(),
+            &client_identity.public_key(),
+            &client_identity,
+        );
+
+        let (client_result, server_result) = futures::join!(
+            client.handshake(&mut client_stream),
+            server.handshake(&mut server_stream)
+        );
+
+        let client_params = client_result.unwrap();
+        let (server_params, client_identity_pk) = server_result.unwrap();
+
+        assert_eq!(client_params.send, server_params.receive);
+        assert_eq!(client_params.receive, server_params.send);
+        assert_eq!(client_identity_pk, client_identity.public_key());
+    }
+
+    #[async_std::test]
+    async fn client_with_invalid_server_key() {
+        let _ = crypto::init();
+
+        let (mut client_stream, mut server_stream) = duplex_pipe();
+
+        let network_key = NetworkKey([0u8; 32]);
+        let server_identity = SecretKey::generate();
+        let server = Server::new(
+            &network_key,
+            &server_identity.public_key(),
+            &server_identity,
+        );
+
+        let client_identity = SecretKey::generate();
+        let invalid_server_pk = SecretKey::generate().public_key();
+        let client = Client::new(
+            &network_key,
+            &invalid_server_pk,
+            &client_identity.public_key(),
+            &client_identity,
+        );
+
+        let (client_result, server_result) =
+            futures::join!(client.handshake(&mut client_stream), async move {
+                let result = server.handshake(&mut server_stream).await;
+                server_stream.close().await.unwrap();
+                result
+            });
+
+        assert!(matches!(client_result, Err(Error::AcceptConnectionClosed)));
+
+        assert!(matches!(
+            server_result,
+            Err(Error::AuthenticateMessageDecryptFailed)
+        ));
+    }
+
+    #[async_std::test]
+    async fn server_rejects_client() {
+        let (mut client_stream, mut server_stream) = duplex_pipe();
+
+        let server_identity = SecretKey::generate();
+        let server = Server::new(
+            &NetworkKey::MAIN_NET,
+            &server_identity.public_key(),
+            &server_identity,
+        );
+        let client_identity = SecretKey::generate();
+        let client = Client::new(
+            &NetworkKey::MAIN_NET,
+            &server_identity.public_key(),
+            &client_identity.public_key(),
+            &client_identity,
+        );
+        let client_identity_pk = client_identity.public_key();
+
+        let (client_result, server_result) =
+            futures::join!(client.handshake(&mut client_stream), async move {
+                let result = server
+                    .handshake_with(&mut server_stream, |client_pk| {
+                        future::ready(client_pk != client_identity_pk)
+                    })
+                    .await;
+                server_stream.close().await.unwrap();
+                result
+            });
+
+        assert!(matches!(client_result, Err(Error::AcceptConnectionClosed)));
+        assert!(matches!(
+            server_result,
+            Err(Error::ClientUnauthorized(client_pk)) if client_pk == client_identity_pk
+        ));
+    }
+
+    #[async_std::test]
+    async fn server_timeout_partial_hello() {
+        let (mut client_stream, mut server_stream) = duplex_pipe();
+        let server_identity = SecretKey::generate();
+        let server = Server::new(
+            &NetworkKey::MAIN_NET,
+            &server_identity.public_key(),
+            &server_identity,
+        )
+        .with_timeouts(Timeouts {
+            phase: Some(Duration::from_millis(50)),
+            total: None,
+        });
+
+        client_stream.write_all(&[0u8; 10]).await.unwrap();
+        let result = server.handshake(&mut server_stream).await;
+        assert!(matches!(result, Err(Error::Timeout)));
+    }
+
+    #[async_std::test]
+    async fn server_timeout_slow_client() {
+        let (mut client_stream, mut server_stream) = duplex_pipe();
+        let server_identity = SecretKey::generate();
+        let server = Server::new(
+            &NetworkKey::MAIN_NET,
+            &server_identity.public_key(),
+            &server_identity,
+        )
+        .with_timeouts(Timeouts {
+            phase: Some(Duration::from_millis(100)),
+            total: Some(Duration::from_millis(200)),
+        });
+
+        // Send one byte at a time so that every read makes progress within the phase timeout.
+        let trickle = async move {
+            for _ in 0..HELLO_MESSAGE_LEN {
+                client_stream.write_all(&[0u8]).await.unwrap();
+                async_std::task::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        let handshake = server.handshake(&mut server_stream);
+        futures::pin_mut!(trickle, handshake);
+        let result = match future::select(trickle, handshake).await {
+            future::Either::Left(((), _)) => panic!("handshake did not time out"),
+            future::Either::Right((result, _)) => result,
+        };
+        assert!(matches!(result, Err(Error::Timeout)));
+    }
+
+    /// Create a pair of connected read-write pipes
+    fn duplex_pipe() -> (impl AsyncRead + AsyncWrite, impl AsyncRead + AsyncWrite) {
+        let (a_writer, a_reader) = async_std::os::unix::net::UnixStream::pair().unwrap();
+        let (b_writer, b_reader) = async_std::os::unix::net::UnixStream::pair().unwrap();
+
+        let a_to_b = duplexify::Duplex::new(b_reader, a_writer);
+        let b_to_a = duplexify::Duplex::new(a_reader, b_writer);
+        (a_to_b, b_to_a)
+    }
+}
@@ -0,0 +1,233 @@
+//! [AsyncRead] and [AsyncWrite] adapters for [Decrypt] and [Encrypt].
+//!
+//! The adapters make it possible to use an encrypted connection with code that expects standard
+//! IO traits. Packet boundaries are not preserved: a single write may be split into several
+//! packets and a single read may return part of a packet.
+use futures::prelude::*;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::{Decrypt, DecryptError, Encrypt};
+
+/// Implements [AsyncRead] for a [Decrypt] stream.
+///
+/// The reader ends when the remote sends the goodbye packet or the underlying reader ends.
+/// Decryption errors are returned as [std::io::Error]s of kind
+/// [std::io::ErrorKind::InvalidData].
+#[pin_project::pin_project]
+pub struct BoxReader<Reader: AsyncRead> {
+    #[pin]
+    decrypt: Decrypt<Reader>,
+    /// Decrypted data that has not been read yet.
+    buffer: bytes::Bytes,
+}
+
+impl<Reader: AsyncRead> BoxReader<Reader> {
+    pub fn new(decrypt: Decrypt<Reader>) -> Self {
+        Self {
+            decrypt,
+            buffer: bytes::Bytes::new(),
+        }
+    }
+
+    /// Returns the underlying stream. Data that was decrypted but not read is discarded.
+    pub fn into_inner(self) -> Decrypt<Reader> {
+        self.decrypt
+    }
+}
+
+impl<Reader: AsyncRead> AsyncRead for BoxReader<Reader> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut this = self.project();
+        while this.buffer.is_empty() {
+            match futures::ready!(this.decrypt.as_mut().poll_next(cx)) {
+                Some(Ok(data)) => *this.buffer = bytes::Bytes::from(data),
+                Some(Err(error)) => return Poll::Ready(Err(decrypt_error_to_io(error))),
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+        let len = std::cmp::min(buf.len(), this.buffer.len());
+        buf[..len].copy_from_slice(&this.buffer.split_to(len));
+        Poll::Ready(Ok(len))
+    }
+}
+
+fn decrypt_error_to_io(error: DecryptError) -> std::io::Error {
+    match error {
+        DecryptError::Io(error) => error,
+        error => std::io::Error::new(std::io::ErrorKind::InvalidData, error),
+    }
+}
+
+/// Implements [AsyncWrite] for an [Encrypt] sink.
+///
+/// Every write is encrypted as one or more packets. Like [Encrypt], the writer buffers packets
+/// and only sends them when the buffer is large enough or when the writer is flushed. Closing the
+/// writer sends the goodbye packet.
+#[pin_project::pin_project]
+pub struct BoxWriter<Writer: AsyncWrite> {
+    #[pin]
+    encrypt: Encrypt<Writer>,
+}
+
+impl<Writer: AsyncWrite> BoxWriter<Writer> {
+    pub fn new(encrypt: Encrypt<Writer>) -> Self {
+        Self { encrypt }
+    }
+
+    pub fn into_inner(self) -> Encrypt<Writer> {
+        self.encrypt
+    }
+}
+
+impl<Writer: AsyncWrite> AsyncWrite for BoxWriter<Writer> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.poll_write_vectored(cx, &[std::io::IoSlice::new(buf)])
+    }
+
+    /// Encrypts all slices without copying them into intermediate buffers. Small slices are
+    /// combined into one packet.
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut encrypt = self.project().encrypt;
+        futures::ready!(encrypt.as_mut().poll_ready(cx))?;
+        encrypt.start_send_vectored(bufs)?;
+        Poll::Ready(Ok(bufs.iter().map(|buf| buf.len()).sum()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().encrypt.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().encrypt.poll_close(cx)
+    }
+}
+
+/// Combines a [BoxReader] and a [BoxWriter] into one value that implements [AsyncRead] and
+/// [AsyncWrite].
+///
+/// ```no_run
+/// # use futures::prelude::*;
+/// # async fn run(client: ssb_box_stream::Client) -> Result<(), Box<dyn std::error::Error>> {
+/// let stream = async_std::net::TcpStream::connect("localhost:5555").await?;
+/// let (encrypt, decrypt) = client.connect(stream).await?;
+/// let mut duplex = ssb_box_stream::BoxDuplex::new(decrypt, encrypt);
+/// duplex.write_all(b"hello").await?;
+/// # Ok(())
+/// # }
+/// ```
+#[pin_project::pin_project]
+pub struct BoxDuplex<Reader: AsyncRead, Writer: AsyncWrite> {
+    #[pin]
+    reader: BoxReader<Reader>,
+    #[pin]
+    writer: BoxWriter<Writer>,
+}
+
+impl<Reader: AsyncRead, Writer: AsyncWrite> BoxDuplex<Reader, Writer> {
+    pub fn new(decrypt: Decrypt<Reader>, encrypt: Encrypt<Writer>) -> Self {
+        Self {
+            reader: BoxReader::new(decrypt),
+            writer: BoxWriter::new(encrypt),
+        }
+    }
+
+    pub fn split(self) -> (BoxReader<Reader>, BoxWriter<Writer>) {
+        (self.reader, self.writer)
+    }
+}
+
+impl<Reader: AsyncRead, Writer: AsyncWrite> AsyncRead for BoxDuplex<Reader, Writer> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.project().reader.poll_read(cx, buf)
+    }
+}
+
+impl<Reader: AsyncRead, Writer: AsyncWrite> AsyncWrite for BoxDuplex<Reader, Writer> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.project().writer.poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        self.project().writer.poll_write_vectored(cx, bufs)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().writer.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().writer.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn duplex_roundtrip() {
+        let params = crate::cipher::Params::arbitrary();
+        let (a, b) = async_std::os::unix::net::UnixStream::pair().unwrap();
+        let (a_reader, a_writer) = a.split();
+        let (b_reader, b_writer) = b.split();
+        let mut a = BoxDuplex::new(
+            Decrypt::new(a_reader, params.clone()),
+            Encrypt::new(a_writer, params.clone()),
+        );
+        let mut b = BoxDuplex::new(
+            Decrypt::new(b_reader, params.clone()),
+            Encrypt::new(b_writer, params),
+        );
+
+        let data = (0..10_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let expected = data.clone();
+        let write = async_std::task::spawn(async move {
+            a.write_all(&data).await.unwrap();
+            a.close().await.unwrap();
+        });
+
+        let mut first = [0u8; 3];
+        b.read_exact(&mut first).await.unwrap();
+        let mut rest = Vec::new();
+        b.read_to_end(&mut rest).await.unwrap();
+        assert_eq!([&first[..], &rest].concat(), expected);
+        write.await;
+    }
+
+    #[async_std::test]
+    async fn reader_invalid_data() {
+        let (a, b) = async_std::os::unix::net::UnixStream::pair().unwrap();
+        let mut writer = BoxWriter::new(Encrypt::new(a, crate::cipher::Params::arbitrary()));
+        let mut reader = BoxReader::new(Decrypt::new(b, crate::cipher::Params::arbitrary()));
+
+        writer.write_all(b"hello").await.unwrap();
+        writer.flush().await.unwrap();
+        let error = reader.read(&mut [0u8; 5]).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,344 @@
+//! Key types used in the public API.
+//!
+//! The types are plain byte arrays that do not depend on the crypto backend. They are encoded
+//! as base64 strings by [std::fmt::Display], [std::str::FromStr] and, with the `serde` feature,
+//! by `Serialize` and `Deserialize`.
+use std::convert::TryFrom;
+
+use crate::crypto;
+
+/// Error returned when parsing a key from bytes or a base64 string fails.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum KeyError {
+    #[error("Invalid base64 encoding")]
+    Base64,
+    #[error("Invalid key length {actual}, expected {expected}")]
+    Length { expected: usize, actual: usize },
+}
+
+fn array_from_slice<const N: usize>(bytes: &[u8]) -> Result<[u8; N], KeyError> {
+    <[u8; N]>::try_from(bytes).map_err(|_| KeyError::Length {
+        expected: N,
+        actual: bytes.len(),
+    })
+}
+
+fn array_from_base64<const N: usize>(s: &str) -> Result<[u8; N], KeyError> {
+    let bytes = zeroize::Zeroizing::new(base64::decode(s).map_err(|_| KeyError::Base64)?);
+    array_from_slice(&bytes)
+}
+
+/// Implements base64 string conversions, `serde` support and byte conversions for a key type.
+macro_rules! impl_key_conversions {
+    ($name:ident, $size:expr) => {
+        impl $name {
+            pub fn from_slice(bytes: &[u8]) -> Result<Self, KeyError> {
+                array_from_slice(bytes).map(Self)
+            }
+
+            pub fn as_bytes(&self) -> &[u8; $size] {
+                &self.0
+            }
+
+            pub fn to_base64(&self) -> String {
+                base64::encode(&self.0[..])
+            }
+
+            pub fn from_base64(s: &str) -> Result<Self, KeyError> {
+                array_from_base64(s).map(Self)
+            }
+        }
+
+        impl From<[u8; $size]> for $name {
+            fn from(bytes: [u8; $size]) -> Self {
+                Self(bytes)
+            }
+        }
+
+        impl From<$name> for [u8; $size] {
+            fn from(key: $name) -> Self {
+                key.0
+            }
+        }
+
+        impl AsRef<[u8]> for $name {
+            fn as_ref(&self) -> &[u8] {
+                &self.0
+            }
+        }
+
+        impl std::str::FromStr for $name {
+            type Err = KeyError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                Self::from_base64(s)
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl serde::Serialize for $name {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(&self.to_base64())
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = <std::borrow::Cow<str>>::deserialize(deserializer)?;
+                Self::from_base64(&s).map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+
+/// Ed25519 public key that identifies a peer.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PublicKey(pub [u8; 32]);
+
+impl_key_conversions!(PublicKey, 32);
+
+impl std::fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_base64())
+    }
+}
+
+impl std::fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("PublicKey").field(&self.to_base64()).finish()
+    }
+}
+
+impl PublicKey {
+    pub(crate) fn to_crypto(self) -> crypto::sign::PublicKey {
+        crypto::sign::PublicKey::from_slice(&self.0).unwrap()
+    }
+
+    pub(crate) fn from_crypto(public_key: &crypto::sign::PublicKey) -> Self {
+        Self::from_slice(public_key.as_ref()).unwrap()
+    }
+}
+
+/// Ed25519 secret key of a peer. Consists of the 32 byte seed followed by the public key.
+///
+/// The `Debug` implementation does not show the key and keys are compared in constant time. The
+/// key is overwritten with zeros when the value is dropped. Conversions to other types like
+/// `[u8; 64]` return copies that are not wiped.
+#[derive(Clone, Eq)]
+pub struct SecretKey(pub [u8; 64]);
+
+impl_key_conversions!(SecretKey, 64);
+
+impl PartialEq for SecretKey {
+    fn eq(&self, other: &Self) -> bool {
+        crypto::ct::eq(&self.0, &other.0)
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        zeroize::Zeroize::zeroize(&mut self.0);
+    }
+}
+
+impl zeroize::ZeroizeOnDrop for SecretKey {}
+
+impl std::fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("SecretKey").field(&"****").finish()
+    }
+}
+
+impl SecretKey {
+    /// Generate a new random key.
+    pub fn generate() -> Self {
+        Self::from_crypto(&crypto::sign::gen_keypair().1)
+    }
+
+    /// Derive the key from a 32 byte seed.
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        let seed = crypto::sign::Seed::from_slice(seed).unwrap();
+        Self::from_crypto(&crypto::sign::keypair_from_seed(&seed).1)
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from_slice(&self.0[32..]).unwrap()
+    }
+
+    pub(crate) fn to_crypto(&self) -> crypto::sign::SecretKey {
+        crypto::sign::SecretKey::from_slice(&self.0).unwrap()
+    }
+
+    fn from_crypto(secret_key: &crypto::sign::SecretKey) -> Self {
+        Self::from_slice(secret_key.as_ref()).unwrap()
+    }
+}
+
+/// Key that identifies the network peers connect to. Peers with different network keys cannot
+/// establish a connection.
+///
+/// Keys of other networks are parsed from base64 with [NetworkKey::from_base64] or derived with
+/// [NetworkKey::derive].
+///
+/// Keys are compared in constant time.
+#[derive(Clone, Copy, Eq)]
+pub struct NetworkKey(pub [u8; 32]);
+
+impl_key_conversions!(NetworkKey, 32);
+
+impl PartialEq for NetworkKey {
+    fn eq(&self, other: &Self) -> bool {
+        crypto::ct::eq(&self.0, &other.0)
+    }
+}
+
+impl std::hash::Hash for NetworkKey {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl NetworkKey {
+    /// Key of the main Scuttlebutt network
+    pub const MAIN_NET: NetworkKey = NetworkKey([
+        0xd4, 0xa1, 0xcb, 0x88, 0xa6, 0x6f, 0x02, 0xf8, 0xdb, 0x63, 0x5c, 0xe2, 0x64, 0x41, 0xcc,
+        0x5d, 0xac, 0x1b, 0x08, 0x42, 0x0c, 0xea, 0xac, 0x23, 0x08, 0x39, 0xb7, 0x55, 0x84, 0x5a,
+        0x9f, 0xfb,
+    ]);
+
+    #[deprecated(note = "Use `NetworkKey::MAIN_NET`")]
+    pub const SCUTTLEBUTT: NetworkKey = Self::MAIN_NET;
+
+    /// Derive the key of a separate network from this key and `label`.
+    ///
+    /// The derived key is the HMAC-SHA-512-256 of `label` with this key, so all peers that use
+    /// the same parent key and label end up on the same network.
+    pub fn derive(&self, label: &[u8]) -> NetworkKey {
+        let tag = crypto::auth::authenticate(label, &self.to_crypto());
+        let mut key = [0u8; 32];
+        key.copy_from_slice(tag.as_ref());
+        NetworkKey(key)
+    }
+
+    /// Key of a private test network called `name` that is separate from the main network.
+    ///
+    /// Same as `NetworkKey::MAIN_NET.derive(name.as_bytes())`.
+    pub fn test_network(name: &str) -> NetworkKey {
+        Self::MAIN_NET.derive(name.as_bytes())
+    }
+
+    pub(crate) fn to_crypto(self) -> crypto::auth::Key {
+        crypto::auth::key_from_array(&self.0)
+    }
+}
+
+impl Default for NetworkKey {
+    fn default() -> Self {
+        Self::MAIN_NET
+    }
+}
+
+impl std::fmt::Display for NetworkKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_base64())
+    }
+}
+
+impl std::fmt::Debug for NetworkKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("NetworkKey")
+            .field(&self.to_base64())
+            .finish()
+    }
+}
+
+#[cfg(feature = "sodium")]
+mod sodiumoxide_conversions {
+    use super::*;
+    use sodiumoxide::crypto::sign;
+
+    impl From<sign::PublicKey> for PublicKey {
+        fn from(public_key: sign::PublicKey) -> Self {
+            Self(public_key.0)
+        }
+    }
+
+    impl From<PublicKey> for sign::PublicKey {
+        fn from(public_key: PublicKey) -> Self {
+            sign::PublicKey(public_key.0)
+        }
+    }
+
+    impl From<&sign::SecretKey> for SecretKey {
+        fn from(secret_key: &sign::SecretKey) -> Self {
+            Self(secret_key.0)
+        }
+    }
+
+    impl From<&SecretKey> for sign::SecretKey {
+        fn from(secret_key: &SecretKey) -> Self {
+            sign::SecretKey(secret_key.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn secret_key() {
+        let secret_key = SecretKey::from_seed(&[4u8; 32]);
+        let public_key = secret_key.public_key();
+        assert_eq!(
+            public_key.to_base64(),
+            "ypOsFwUYcHHWe4PH/w7+gQjo7EUwV113JoeTM9vavnw="
+        );
+        assert_eq!(format!("{:?}", secret_key), "SecretKey(\"****\")");
+        assert_eq!(
+            SecretKey::from_base64(&secret_key.to_base64()),
+            Ok(secret_key)
+        );
+        assert_ne!(SecretKey::generate(), SecretKey::generate());
+    }
+
+    #[test]
+    fn secret_key_zeroize_on_drop() {
+        let mut secret_key = std::mem::ManuallyDrop::new(SecretKey::generate());
+        assert_ne!(secret_key.0, [0u8; 64]);
+        // The bytes of the key are still valid after `drop()` returned because they are not
+        // deallocated.
+        unsafe { std::mem::ManuallyDrop::drop(&mut secret_key) };
+        assert_eq!(secret_key.0, [0u8; 64]);
+    }
+
+    #[test]
+    fn parse() {
+        let key = PublicKey([7u8; 32]);
+        assert_eq!(key.to_string().parse::<PublicKey>(), Ok(key));
+        assert_eq!(
+            "1KHLiKZvAvjbY1ziZEHMXawbCEIM6qwjCDm3VYRan/s=".parse::<NetworkKey>(),
+            Ok(NetworkKey::MAIN_NET)
+        );
+        assert_eq!(
+            "AAAA".parse::<PublicKey>(),
+            Err(KeyError::Length {
+                expected: 32,
+                actual: 3
+            })
+        );
+        assert_eq!("!".parse::<PublicKey>(), Err(KeyError::Base64));
+    }
+
+    #[test]
+    fn derive_network_key() {
+        let key = NetworkKey::test_network("test");
+        assert_eq!(
+            key.to_base64(),
+            "ZgRY+6MFRj0IxZLuwbjOzijuqnr48OoRoeS5bjwRuoE="
+        );
+        assert_eq!(key, NetworkKey::MAIN_NET.derive(b"test"));
+        assert_ne!(key, NetworkKey::test_network("other"));
+    }
+}
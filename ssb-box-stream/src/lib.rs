@@ -11,12 +11,12 @@ connection between two peers.
 A simple echo server (see [`examples/echo_server.rs`][echo-server])
 
 ```rust
-let server_identity = sodiumoxide::crypto::sign::gen_keypair().unwrap();
+let server_identity = ssb_box_stream::SecretKey::generate();
 
 let listener = async_std::net::TcpListener::bind("localhost:5555").await?;
 let (stream, _) = listener.accept().await?;
 let server =
-    ssb_box_stream::Server::new(&NETWORK_IDENTIFIER, &server_identity.0, &server_identity.1);
+    ssb_box_stream::Server::new(&NETWORK_KEY, &server_identity.public_key(), &server_identity);
 let (mut sender, mut receiver, client_key) = server.accept(stream).await?;
 println!("Connected to client {:?}", client_key);
 
@@ -32,41 +32,67 @@ A client (see [`examples/client.rs`][client]).
 
 ```rust
 // This needs to match the server identity keypair
-let server_identity_pk = sodiumoxide::crypto::sign::gen_keypair().0;
-let client_identity = sodiumoxide::crypto::sign::gen_keypair();
+let server_identity_pk = ssb_box_stream::SecretKey::generate().public_key();
+let client_identity = ssb_box_stream::SecretKey::generate();
 
 let stream = async_std::net::TcpStream::connect("localhost:5555").await?;
 
 let client = ssb_box_stream::Client::new(
-    &NETWORK_IDENTIFIER,
+    &NETWORK_KEY,
     &server_identity_pk,
-    &client_identity.0,
-    &client_identity.1,
+    &client_identity.public_key(),
+    &client_identity,
 );
 
 let (mut sender, _receiver) = client.connect(stream).await?;
 sender.send(Vec::from(b"hello world")).await?;
 ```
 
+With the `tcp` feature enabled, [`connect_tcp`][connect-tcp] and [`listen_tcp`][listen-tcp]
+take care of opening the socket and running the handshake for applications that use
+`async_std`.
+
 [scuttlebutt]: https://scuttlebutt.nz/
 [handshake]: https://ssbc.github.io/scuttlebutt-protocol-guide/#handshake
 [box-stream]: https://ssbc.github.io/scuttlebutt-protocol-guide/#handshake
 [echo-server]: ./examples/echo_server.rs
 [client]: ./examples/client.rs
+[connect-tcp]: crate::connect_tcp
+[listen-tcp]: crate::listen_tcp
 */
 use futures::prelude::*;
 
 mod cipher;
+mod counter;
 mod crypto;
 mod decrypt;
 mod encrypt;
 mod handshake;
+mod io;
+mod keys;
+mod signer;
+#[cfg(feature = "tcp")]
+mod tcp;
+pub mod testing;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod throttle;
 mod utils;
 
 pub use cipher::Params as CipherParams;
-pub use decrypt::{Decrypt, DecryptError};
+pub use counter::ByteCounter;
+pub use decrypt::{Decrypt, DecryptError, Terminated};
 pub use encrypt::Encrypt;
-pub use handshake::{Client, Error, Server};
+pub use handshake::{
+    Client, Error, HandshakeCache, HandshakeInput, HandshakeMachine, HandshakeOutput, Server,
+    Timeouts,
+};
+pub use io::{BoxDuplex, BoxReader, BoxWriter};
+pub use keys::{KeyError, NetworkKey, PublicKey, SecretKey};
+pub use signer::{Signer, SignerError};
+#[cfg(feature = "tcp")]
+pub use tcp::{connect_tcp, listen_tcp, ConnectError, TcpReceiver, TcpSender};
+#[cfg(not(target_arch = "wasm32"))]
+pub use throttle::{RateLimit, Throttled};
 
 /// Take a duplex stream and create a [Sink] for sending encrypted data and a [Stream] for
 /// receiving and decrypting data.
@@ -97,9 +123,28 @@ mod test {
     use super::*;
     use proptest::prelude::*;
 
+    #[async_std::test]
+    async fn close_sends_goodbye() {
+        let params = crate::cipher::Params::arbitrary();
+        let (raw_writer, raw_reader) = async_std::os::unix::net::UnixStream::pair().unwrap();
+        let mut reader = Decrypt::new(raw_reader, params.clone());
+        let mut writer = Encrypt::new(raw_writer, params);
+
+        // Buffered data that has not been flushed is sent before the goodbye packet.
+        futures::future::poll_fn(|cx| writer.poll_ready_unpin(cx))
+            .await
+            .unwrap();
+        writer.start_send_unpin(b"hello".to_vec()).unwrap();
+        writer.close().await.unwrap();
+
+        assert_eq!(reader.next().await.unwrap().unwrap(), b"hello");
+        assert!(reader.next().await.is_none());
+        assert_eq!(reader.terminated(), Some(Terminated::Goodbye));
+    }
+
     #[test_strategy::proptest]
     fn crypt_stream(messages: Vec<Vec<u8>>) {
-        let _ = sodiumoxide::init();
+        let _ = crypto::init();
         async_std::task::block_on(async move {
             let params = crate::cipher::Params::arbitrary();
             let (writer, reader) = async_std::os::unix::net::UnixStream::pair().unwrap();
@@ -125,7 +170,7 @@ mod test {
         #[strategy(proptest::collection::vec(any::<u8>(), 1..30))] data: Vec<u8>,
         cutoff: proptest::sample::Index,
     ) {
-        let _ = sodiumoxide::init();
+        let _ = crypto::init();
         async_std::task::block_on(async move {
             let params = crate::cipher::Params::arbitrary();
             let (raw_writer, raw_reader) = async_std::os::unix::net::UnixStream::pair().unwrap();
@@ -138,7 +183,8 @@ mod test {
                 let _ = writer.send(data).await;
             });
 
-            let items = reader.collect::<Vec<_>>().await;
+            let mut reader = reader;
+            let items = (&mut reader).collect::<Vec<_>>().await;
             let err = items.last().unwrap().as_ref().unwrap_err();
             match err {
                 DecryptError::Io(io_error) => {
@@ -146,6 +192,7 @@ mod test {
                 }
                 _ => prop_assert!(false),
             }
+            prop_assert_eq!(reader.terminated(), Some(Terminated::Eof));
             Ok(())
         })?;
     }
@@ -0,0 +1,61 @@
+//! Delegate operations with the identity secret key.
+use crate::crypto;
+use crate::keys::{PublicKey, SecretKey};
+
+/// Error returned by a [Signer] that could not complete an operation, for example because the
+/// device holding the key is not available.
+#[derive(thiserror::Error, Debug)]
+#[error(transparent)]
+pub struct SignerError(Box<dyn std::error::Error + Send + Sync + 'static>);
+
+impl SignerError {
+    pub fn new(error: impl Into<Box<dyn std::error::Error + Send + Sync + 'static>>) -> Self {
+        Self(error.into())
+    }
+}
+
+/// Holds the secret identity key of a peer and performs the operations of the handshake that
+/// require it.
+///
+/// Implement this trait to keep the secret key out of process memory, for example in a hardware
+/// security module, the OS keychain or a remote signer. [SecretKey] implements the trait with
+/// the key in memory.
+///
+/// The handshake calls the signer synchronously once for [Signer::sign] and once for
+/// [Signer::key_exchange].
+pub trait Signer: std::fmt::Debug + Send + Sync {
+    fn public_key(&self) -> PublicKey;
+
+    /// Ed25519 signature of `data` with the identity key.
+    fn sign(&self, data: &[u8]) -> Result<[u8; 64], SignerError>;
+
+    /// X25519 shared secret of the identity key converted to Curve25519 and the Curve25519
+    /// `public_key`.
+    ///
+    /// The result is all zeros if `public_key` has low order. The handshake rejects such keys.
+    fn key_exchange(&self, public_key: &[u8; 32]) -> Result<[u8; 32], SignerError>;
+}
+
+impl Signer for SecretKey {
+    fn public_key(&self) -> PublicKey {
+        SecretKey::public_key(self)
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<[u8; 64], SignerError> {
+        let signature = crypto::sign::sign_detached(data, &self.to_crypto());
+        let mut bytes = [0u8; 64];
+        bytes.copy_from_slice(signature.as_ref());
+        Ok(bytes)
+    }
+
+    fn key_exchange(&self, public_key: &[u8; 32]) -> Result<[u8; 32], SignerError> {
+        let secret_key = crypto::sign_to_box_sk(&self.to_crypto())
+            .expect("Identity secret keys can always be converted");
+        let public_key = crypto::box_::PublicKey::from_slice(public_key).unwrap();
+        let mut bytes = [0u8; 32];
+        if let Some(shared) = crypto::share_key(&public_key, &secret_key) {
+            bytes.copy_from_slice(shared.as_ref());
+        }
+        Ok(bytes)
+    }
+}
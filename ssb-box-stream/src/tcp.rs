@@ -0,0 +1,154 @@
+//! Encrypted TCP connections for applications that use `async_std`.
+//!
+//! Requires the `tcp` feature.
+use futures::prelude::*;
+
+use crate::{Client, Decrypt, Encrypt, NetworkKey, PublicKey, SecretKey, Server};
+
+/// Sends encrypted data over a TCP connection.
+pub type TcpSender = Encrypt<futures::io::WriteHalf<async_std::net::TcpStream>>;
+
+/// Receives and decrypts data from a TCP connection.
+pub type TcpReceiver = Decrypt<futures::io::ReadHalf<async_std::net::TcpStream>>;
+
+/// Error returned by [connect_tcp].
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectError {
+    #[error("Failed to connect")]
+    Io(#[from] std::io::Error),
+    #[error("Handshake failed")]
+    Handshake(#[from] crate::Error),
+}
+
+/// Connect to the server at `addr` and run the handshake as a client.
+///
+/// ```no_run
+/// # use futures::prelude::*;
+/// # #[async_std::main]
+/// # async fn main () -> Result<(), Box<dyn std::error::Error>> {
+/// let server_identity_pk = ssb_box_stream::SecretKey::generate().public_key();
+/// let identity = ssb_box_stream::SecretKey::generate();
+/// let (mut sender, _receiver) = ssb_box_stream::connect_tcp(
+///     "localhost:5555",
+///     &ssb_box_stream::NetworkKey::MAIN_NET,
+///     &server_identity_pk,
+///     &identity,
+/// )
+/// .await?;
+/// sender.send(Vec::from(b"hello world")).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn connect_tcp(
+    addr: impl async_std::net::ToSocketAddrs,
+    network_key: &NetworkKey,
+    server_identity_pk: &PublicKey,
+    identity: &SecretKey,
+) -> Result<(TcpSender, TcpReceiver), ConnectError> {
+    let stream = async_std::net::TcpStream::connect(addr).await?;
+    let client = Client::new(
+        network_key,
+        server_identity_pk,
+        &identity.public_key(),
+        identity,
+    );
+    let (sender, receiver) = client.connect(stream).await?;
+    Ok((sender, receiver))
+}
+
+/// Accept connections on `addr` and run `handler` for every client that completes the
+/// handshake.
+///
+/// `handler` is called with the encrypted connection and the public identity key of the client.
+/// Every connection is handled in its own task. Connections that fail the handshake are
+/// dropped. Returns only if binding to `addr` or accepting a connection fails.
+///
+/// ```no_run
+/// # use futures::prelude::*;
+/// # #[async_std::main]
+/// # async fn main () -> Result<(), Box<dyn std::error::Error>> {
+/// let identity = ssb_box_stream::SecretKey::generate();
+/// ssb_box_stream::listen_tcp(
+///     "localhost:5555",
+///     &ssb_box_stream::NetworkKey::MAIN_NET,
+///     &identity,
+///     |mut sender, mut receiver, client_identity_pk| async move {
+///         println!("Connected to client {}", client_identity_pk);
+///         while let Some(Ok(data)) = receiver.next().await {
+///             let _ = sender.send(data).await;
+///         }
+///     },
+/// )
+/// .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn listen_tcp<Handler, HandlerFuture>(
+    addr: impl async_std::net::ToSocketAddrs,
+    network_key: &NetworkKey,
+    identity: &SecretKey,
+    handler: Handler,
+) -> std::io::Result<()>
+where
+    Handler: Fn(TcpSender, TcpReceiver, PublicKey) -> HandlerFuture + Send + Sync + 'static,
+    HandlerFuture: Future<Output = ()> + Send + 'static,
+{
+    let listener = async_std::net::TcpListener::bind(addr).await?;
+    let server = Server::new(network_key, &identity.public_key(), identity);
+    let handler = std::sync::Arc::new(handler);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let server = server.clone();
+        let handler = handler.clone();
+        async_std::task::spawn(async move {
+            if let Ok((sender, receiver, client_identity_pk)) = server.accept(stream).await {
+                handler(sender, receiver, client_identity_pk).await
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn echo() {
+        const ADDR: &str = "127.0.0.1:15738";
+        let server_identity = SecretKey::generate();
+        let client_identity = SecretKey::generate();
+        let client_identity_pk = client_identity.public_key();
+        let network_key = NetworkKey::MAIN_NET;
+
+        let server_identity_pk = server_identity.public_key();
+        async_std::task::spawn(async move {
+            listen_tcp(
+                ADDR,
+                &network_key,
+                &server_identity,
+                move |mut sender, mut receiver, remote_identity_pk| async move {
+                    assert_eq!(remote_identity_pk, client_identity_pk);
+                    while let Some(data) = receiver.try_next().await.unwrap() {
+                        sender.send(data).await.unwrap();
+                    }
+                    sender.close().await.unwrap();
+                },
+            )
+            .await
+            .unwrap();
+        });
+
+        let (mut sender, receiver) = loop {
+            match connect_tcp(ADDR, &network_key, &server_identity_pk, &client_identity).await {
+                Ok(connection) => break connection,
+                // The server may not be listening yet.
+                Err(ConnectError::Io(_)) => async_std::task::yield_now().await,
+                Err(error) => panic!("{}", error),
+            }
+        };
+        sender.send(b"hello".to_vec()).await.unwrap();
+        sender.close().await.unwrap();
+        let received = receiver.try_concat().await.unwrap();
+        assert_eq!(received, b"hello");
+    }
+}
@@ -0,0 +1,311 @@
+//! Conformance checks against known-good protocol data.
+//!
+//! [run_shs1_vectors] runs one side of the handshake against recorded transcripts and checks
+//! every message and the resulting [BoxStreamParams][crate::BoxStreamParams]. Since the
+//! cryptographic backend is selected with cargo features, this validates the backend the crate
+//! was compiled with. The vectors are checked independently of the crate by
+//! `scripts/check-shs1-vectors.js`.
+//!
+//! ```
+//! ssb_box_stream::testing::run_shs1_vectors(ssb_box_stream::testing::Side::Client).unwrap();
+//! ssb_box_stream::testing::run_shs1_vectors(ssb_box_stream::testing::Side::Server).unwrap();
+//! ```
+use std::convert::TryInto as _;
+
+use crate::cipher::Params;
+use crate::{BoxStreamParams, Error, HandshakeInput, HandshakeMachine, HandshakeOutput};
+use crate::{NetworkKey, SecretKey};
+
+/// Side of the handshake that is checked by [run_shs1_vectors].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+/// Error returned by [run_shs1_vectors] for the first vector that does not pass.
+#[derive(Debug, thiserror::Error)]
+pub enum VectorError {
+    /// The handshake failed with data that is known to be valid
+    #[error("Vector `{vector}`: handshake failed")]
+    Handshake {
+        vector: &'static str,
+        #[source]
+        error: Error,
+    },
+    /// The handshake produced a different result than expected
+    #[error("Vector `{vector}`: unexpected {step}")]
+    Mismatch {
+        vector: &'static str,
+        step: &'static str,
+    },
+    /// The handshake succeeded even though a message from the peer was corrupted
+    #[error("Vector `{vector}`: accepted corrupted {step}")]
+    CorruptedAccepted {
+        vector: &'static str,
+        step: &'static str,
+    },
+}
+
+/// Run the `side` of the handshake against all embedded shs1 test vectors.
+///
+/// For every vector the handshake is run with fixed session keys. The messages the handshake
+/// sends, the remote identity and the resulting box stream parameters must match the vector
+/// exactly. In addition, the handshake must fail if any message received from the peer is
+/// corrupted by flipping a single bit.
+pub fn run_shs1_vectors(side: Side) -> Result<(), VectorError> {
+    for vector in SHS1_VECTORS {
+        vector.run(side)?;
+    }
+    Ok(())
+}
+
+/// Transcript of a successful handshake. All values are hex encoded.
+struct Shs1Vector {
+    name: &'static str,
+    network_key: &'static str,
+    /// Seed of the client’s ed25519 identity key
+    client_seed: &'static str,
+    /// Secret curve25519 session key of the client
+    client_session_sk: &'static str,
+    /// Seed of the server’s ed25519 identity key
+    server_seed: &'static str,
+    /// Secret curve25519 session key of the server
+    server_session_sk: &'static str,
+    client_hello: &'static str,
+    server_hello: &'static str,
+    client_authenticate: &'static str,
+    server_accept: &'static str,
+    /// Key and nonce of the box stream from the client to the server
+    client_to_server_key: &'static str,
+    client_to_server_nonce: &'static str,
+    /// Key and nonce of the box stream from the server to the client
+    server_to_client_key: &'static str,
+    server_to_client_nonce: &'static str,
+}
+
+/// Message received from the peer in one step of the handshake.
+#[derive(Clone)]
+struct Received {
+    step: &'static str,
+    data: Vec<u8>,
+}
+
+impl Shs1Vector {
+    fn run(&self, side: Side) -> Result<(), VectorError> {
+        let received = match side {
+            Side::Client => vec![
+                self.received("server hello", self.server_hello),
+                self.received("server accept", self.server_accept),
+            ],
+            Side::Server => vec![
+                self.received("client hello", self.client_hello),
+                self.received("client authenticate", self.client_authenticate),
+            ],
+        };
+        self.check(side, &received)
+            .map_err(|error| error.into_vector_error(self.name))?;
+
+        for index in 0..received.len() {
+            let mut corrupted = received.clone();
+            let data = &mut corrupted[index].data;
+            let position = data.len() / 2;
+            data[position] ^= 1;
+            if self.check(side, &corrupted).is_ok() {
+                return Err(VectorError::CorruptedAccepted {
+                    vector: self.name,
+                    step: received[index].step,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn received(&self, step: &'static str, data: &str) -> Received {
+        Received {
+            step,
+            data: decode_hex(data),
+        }
+    }
+
+    /// Run the handshake for `side` with `received` as the messages from the peer and compare
+    /// the outputs with the vector.
+    fn check(&self, side: Side, received: &[Received]) -> Result<(), CheckError> {
+        let network_key = NetworkKey(decode_array(self.network_key));
+        let client_sk = SecretKey::from_seed(&decode_array(self.client_seed));
+        let server_sk = SecretKey::from_seed(&decode_array(self.server_seed));
+        let client_to_server = Params::from_bytes(
+            &decode_array(self.client_to_server_key),
+            &decode_array(self.client_to_server_nonce),
+        );
+        let server_to_client = Params::from_bytes(
+            &decode_array(self.server_to_client_key),
+            &decode_array(self.server_to_client_nonce),
+        );
+
+        let (mut machine, sent, expected_remote, expected_params) = match side {
+            Side::Client => (
+                HandshakeMachine::client(&network_key, &server_sk.public_key(), &client_sk)
+                    .with_session_key(&decode_array(self.client_session_sk)),
+                [
+                    ("client hello", self.client_hello),
+                    ("client authenticate", self.client_authenticate),
+                ],
+                server_sk.public_key(),
+                BoxStreamParams {
+                    send: client_to_server,
+                    receive: server_to_client,
+                },
+            ),
+            Side::Server => (
+                HandshakeMachine::server(&network_key, &server_sk)
+                    .with_session_key(&decode_array(self.server_session_sk)),
+                [
+                    ("server hello", self.server_hello),
+                    ("server accept", self.server_accept),
+                ],
+                client_sk.public_key(),
+                BoxStreamParams {
+                    send: server_to_client,
+                    receive: client_to_server,
+                },
+            ),
+        };
+
+        let mut sent = sent.iter();
+        let mut received = received.iter();
+        let mut input = HandshakeInput::Start;
+        loop {
+            match machine.advance(input).map_err(CheckError::Handshake)? {
+                HandshakeOutput::Send(data) => {
+                    let (step, expected) = sent.next().ok_or(CheckError::Mismatch("message"))?;
+                    if data != decode_hex(expected) {
+                        return Err(CheckError::Mismatch(step));
+                    }
+                    input = HandshakeInput::Sent;
+                }
+                HandshakeOutput::Receive(len) => {
+                    let message = received
+                        .next()
+                        .ok_or(CheckError::Mismatch("receive request"))?;
+                    if message.data.len() != len {
+                        return Err(CheckError::Mismatch("message length"));
+                    }
+                    input = HandshakeInput::Received(&message.data);
+                }
+                HandshakeOutput::Authorize(client_identity_pk) => {
+                    if client_identity_pk != expected_remote {
+                        return Err(CheckError::Mismatch("client identity"));
+                    }
+                    input = HandshakeInput::Authorized(true);
+                }
+                HandshakeOutput::Done {
+                    params,
+                    remote_identity_pk,
+                } => {
+                    if remote_identity_pk != expected_remote {
+                        return Err(CheckError::Mismatch("remote identity"));
+                    }
+                    if params != expected_params {
+                        return Err(CheckError::Mismatch("box stream params"));
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+enum CheckError {
+    Handshake(Error),
+    Mismatch(&'static str),
+}
+
+impl CheckError {
+    fn into_vector_error(self, vector: &'static str) -> VectorError {
+        match self {
+            CheckError::Handshake(error) => VectorError::Handshake { vector, error },
+            CheckError::Mismatch(step) => VectorError::Mismatch { vector, step },
+        }
+    }
+}
+
+fn decode_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).unwrap())
+        .collect()
+}
+
+fn decode_array<const N: usize>(hex: &str) -> [u8; N] {
+    decode_hex(hex).try_into().unwrap()
+}
+
+// Every message and key of the vectors is recomputed by `scripts/check-shs1-vectors.js`, an
+// implementation of the handshake from the Scuttlebutt Protocol Guide that shares no code with
+// this crate or its backends. CI runs the script and checks both backends against the vectors.
+const SHS1_VECTORS: &[Shs1Vector] = &[
+    Shs1Vector {
+        name: "main network",
+        network_key: "d4a1cb88a66f02f8db635ce26441cc5dac1b08420ceaac230839b755845a9ffb",
+        client_seed: "7b793de860c2abaf0183ee97225bad60ee01b093593f569927efbfdf14473532",
+        client_session_sk: "72ebf49c64e0043d2701b697ef84d3d53cfa98a22fd1e9c6061ebacf3abe2380",
+        server_seed: "f5d1873383cca45788e0af9f3880cfbdeef4cec3aed65d3ac50cedc9ad9adb24",
+        server_session_sk: "0d1e1ef09feccb380984f3051e0c3a8322f464dff57edc33c5971b50bfca5f52",
+        client_hello: "bf53502dc8e0c1b8ecc7a026af803da7530bb676755c9449522b5bb32565f8fe737eb672426067a966fda58e84df404134f3a3cc47a0afe320c3106c43fc983f",
+        server_hello: "02db45ebfca35f3c09fa01a2b117959e41ea1a7920a0672fc9fa7a51bc020dcfc57730d3f619d08870956e686e93afb094671a19cb3df804ab5362a7ccac7c1d",
+        client_authenticate: "7d8b1f490a6b0d42653893c1127b6a0799e39e2ebd88e4e48090ac6ae956bee3779f7ee74a386a3e04661a67c7fd03c22e4df902b00dba5f12ec0abc0fbe7d16917b5d1b0c23b72ecd80ddd5c88bda23aee4ae804b90a6c9abee563a7d7506d3895e438d52390798c981dc4b8de68dc8",
+        server_accept: "a1a642303c183f153291643aaf97363939bf1c196ac7bd60b786f568b0304f2a11c27598796d86da609c2398eb5e2b071adcf37acc381afd7665fcd7e7bebb9ce14e576d88829ce920725c986bb7052d",
+        client_to_server_key: "017ee8c85d08f0fb15968e375c1dbacf68e1d2cd7af95cda38691611c960a127",
+        client_to_server_nonce: "02db45ebfca35f3c09fa01a2b117959e41ea1a7920a0672f",
+        server_to_client_key: "1ec380cba983d39cd0f68b0ac1ccd50bb7af7321594aba1c3137e9d600b11d25",
+        server_to_client_nonce: "bf53502dc8e0c1b8ecc7a026af803da7530bb676755c9449",
+    },
+    Shs1Vector {
+        name: "random network 1",
+        network_key: "84e4c617cf7ab6062d6bf1835d9c08f047c8ed68316b9cc346e380ce7a7c4b08",
+        client_seed: "af7de30e398e6344c892e4cd069b572e7fa2326dd15b374d882a9c1f14ccfda6",
+        client_session_sk: "8a930b8ad0e063037a3b0142009ecd4602acfb719e03550f3617ad2e61c262d1",
+        server_seed: "bb759d64ce604d89040596d1b29ce464ddc465d63b4baa4ea0564ba534f0908e",
+        server_session_sk: "0db4c5bd7bd0dc00e1e946d3a1f4a14152918a77b7f4cb2a1d8487e3faf89efd",
+        client_hello: "4d082e6e4da439ed3a7cdd204d83639877c72f7d35fd5424e9fe8d05a1feed1c4a2163f0a225b691bba8879d158bfed5dde2d6cc5bad222d575e46ac4015ad06",
+        server_hello: "6ba066ff2403dd3ddc161b04dda683044437824c4d718479ad7492836aa77e2493aff73d5650108456e2c51b1d85d7c271e0750bb6bd4447c781c081ee73245d",
+        client_authenticate: "5023c2d02f801c0c0c2adf7b642019a29f503eddb60d792f9510a95d150fa42a9ddc83349e485aa0ba7086b62ea7c041a30d20e21bf9f4303bde9753e4f5a97f403a4b22e895adf98390f8243cd83474b685afe3879b19916c1245e9e571913c4f92ad40a542c8a182854bdcbf135428",
+        server_accept: "e14d476c1a1f683b250d81fdea52181f1a12989fe05d08ecc3a9b728dd9f61b064f7d4acd4f7dee3149702ad2c11f1eaf611e740644369e28ca73af7a37181da0cd7b0865d3fe73484ad0a87b50af0b5",
+        client_to_server_key: "bd4fa972e6a03ca4c43810be8d66e51338d79be55b44aae8fde43b060e3bad38",
+        client_to_server_nonce: "6ba066ff2403dd3ddc161b04dda683044437824c4d718479",
+        server_to_client_key: "caa9732fdf32e8d77ce18fe12a5501291dc2efaeae41bb33b0edafd9ec239be7",
+        server_to_client_nonce: "4d082e6e4da439ed3a7cdd204d83639877c72f7d35fd5424",
+    },
+    Shs1Vector {
+        name: "random network 2",
+        network_key: "9ffc314d8748e69b576ccfcc3ed4982cf7ae954636b3ceac4b98875e144ae48c",
+        client_seed: "314ece645a0d926aa8bc95c2e9269b676df5d02c51fc52c156c838c69a8e1683",
+        client_session_sk: "63ad1827ba08326b479102479eaf238534baf362e858cad4df0f51806ed2c9bc",
+        server_seed: "8b84a17c7ebbe5fc2d1c2dbce105ac5ab49f36fdde7cafb13336b1d7024aa15a",
+        server_session_sk: "93e3f12374bc94a33d8ed11e27d1748a14004d7f534bbdda94150c32baee0842",
+        client_hello: "b5063eb2e20554e530416805deeb69d5650b892d446346bc9dfca94b907a8222e68e93134a782ea53b55e21c6ff1aa35b0a7cef7dfcfd65e583686bbb7a8493f",
+        server_hello: "fe891cc02f65b85472fcfe78823c1a8d9da47d422a97a2cda3598a48a90175127cd1d4a8823a675905a8bf06c508dc12ca34f49ef56ebab67106286a9dcb7c20",
+        client_authenticate: "c9935fee2274a7129130ac8103758abeacd84f05129620d612c2683ee06098e5f99a6f2985e9c8077e16ee568b1b30165cb6a9938b13603d2357c7bf545e8872da3d3415800c26d19c2871bcaad292a1ab5ec6976ef551f572411d6bd8714ce2460e8eee066221f2994012df52473580",
+        server_accept: "339b2c992da2647192e021997dae320b3064a178d85684bac2ca551aa8e8501400c27d70564f2a0924efdc9bdd79be884c6801dda8fa5254c36298100da8c0d9753dd0ded5937a7eeae7b61a669abdb1",
+        client_to_server_key: "a988f27dc76890389c4ef04b194d5e8692cefa10f9acb34cee0bc1b962bc3db0",
+        client_to_server_nonce: "fe891cc02f65b85472fcfe78823c1a8d9da47d422a97a2cd",
+        server_to_client_key: "31fb7b2dcc79f423e1d135d50f4871d8d0202b0bf4d37a46ed479f1b0bda5b89",
+        server_to_client_nonce: "b5063eb2e20554e530416805deeb69d5650b892d446346bc",
+    },
+];
+
+#[cfg(test)]
+mod conformance {
+    use super::*;
+
+    #[test]
+    fn shs1_client() {
+        run_shs1_vectors(Side::Client).unwrap();
+    }
+
+    #[test]
+    fn shs1_server() {
+        run_shs1_vectors(Side::Server).unwrap();
+    }
+}
@@ -0,0 +1,262 @@
+//! Bandwidth limits for connections.
+//!
+//! [Throttled] wraps the raw connection and limits the bytes read and written per second with
+//! [RateLimit] token buckets. Pass it to [Client::connect][crate::Client::connect] or
+//! [Server::accept][crate::Server::accept] to throttle the encrypted connection.
+//!
+//! ```rust
+//! # use futures::prelude::*;
+//! # async fn run(stream: async_std::net::TcpStream, client: ssb_box_stream::Client) -> Result<(), ssb_box_stream::Error> {
+//! use ssb_box_stream::{RateLimit, Throttled};
+//! // 64 KiB/s up and 256 KiB/s down
+//! let stream = Throttled::new(stream)
+//!     .up(RateLimit::new(64 * 1024))
+//!     .down(RateLimit::new(256 * 1024));
+//! let (sender, receiver) = client.connect(stream).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Not available on `wasm32` targets.
+use futures::prelude::*;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Token bucket that allows `bytes_per_second` on average and bursts of up to one second worth
+/// of data.
+///
+/// Clones share the bucket. Use the same [RateLimit] for several connections to limit their
+/// combined bandwidth.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket::new(bytes_per_second, Instant::now()))),
+        }
+    }
+
+    /// Change the limit. Takes effect for all connections that share the bucket.
+    pub fn set_rate(&self, bytes_per_second: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(Instant::now());
+        bucket.rate = bytes_per_second.max(1) as f64;
+        bucket.tokens = bucket.tokens.min(bucket.rate);
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().unwrap().rate as u64
+    }
+
+    fn take(&self, wanted: usize) -> Result<usize, Duration> {
+        self.bucket.lock().unwrap().take(Instant::now(), wanted)
+    }
+
+    fn give_back(&self, unused: usize) {
+        self.bucket.lock().unwrap().give_back(unused)
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Tokens, that is bytes, added per second. Also the capacity of the bucket.
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn new(bytes_per_second: u64, now: Instant) -> Self {
+        let rate = bytes_per_second.max(1) as f64;
+        Self {
+            rate,
+            tokens: rate,
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        self.refilled_at = now;
+    }
+
+    /// Take up to `wanted` tokens. If there are not enough tokens returns the time to wait until
+    /// a reasonable amount is available so that callers do not wake up for every byte.
+    fn take(&mut self, now: Instant, wanted: usize) -> Result<usize, Duration> {
+        self.refill(now);
+        let threshold = (wanted as f64).min(self.rate / 16.0).max(1.0);
+        if self.tokens < threshold {
+            return Err(Duration::from_secs_f64(
+                (threshold - self.tokens) / self.rate,
+            ));
+        }
+        let taken = (wanted as f64).min(self.tokens.floor());
+        self.tokens -= taken;
+        Ok(taken as usize)
+    }
+
+    fn give_back(&mut self, unused: usize) {
+        self.tokens = (self.tokens + unused as f64).min(self.rate);
+    }
+}
+
+/// Limits the bandwidth of `Io`. See the [module documentation][self].
+#[pin_project::pin_project]
+#[derive(Debug)]
+pub struct Throttled<Io> {
+    #[pin]
+    io: Io,
+    up: Limiter,
+    down: Limiter,
+}
+
+impl<Io> Throttled<Io> {
+    /// Wrap `io` without limits.
+    pub fn new(io: Io) -> Self {
+        Self {
+            io,
+            up: Limiter::default(),
+            down: Limiter::default(),
+        }
+    }
+
+    /// Limit the data written to `io`.
+    pub fn up(mut self, limit: RateLimit) -> Self {
+        self.up.limit = Some(limit);
+        self
+    }
+
+    /// Limit the data read from `io`.
+    pub fn down(mut self, limit: RateLimit) -> Self {
+        self.down.limit = Some(limit);
+        self
+    }
+
+    pub fn into_inner(self) -> Io {
+        self.io
+    }
+}
+
+#[derive(Debug, Default)]
+struct Limiter {
+    limit: Option<RateLimit>,
+    delay: Option<futures_timer::Delay>,
+}
+
+impl Limiter {
+    /// Wait until some of the `wanted` bytes may be transferred and return how many.
+    fn poll_allowed(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<usize> {
+        let limit = match &self.limit {
+            Some(limit) if wanted > 0 => limit,
+            _ => return Poll::Ready(wanted),
+        };
+        loop {
+            if let Some(delay) = &mut self.delay {
+                futures::ready!(delay.poll_unpin(cx));
+                self.delay = None;
+            }
+            match limit.take(wanted) {
+                Ok(allowed) => return Poll::Ready(allowed),
+                Err(wait) => self.delay = Some(futures_timer::Delay::new(wait)),
+            }
+        }
+    }
+
+    /// Return the tokens for the bytes that were allowed but not transferred.
+    fn finish(
+        &self,
+        allowed: usize,
+        result: Poll<std::io::Result<usize>>,
+    ) -> Poll<std::io::Result<usize>> {
+        if let Some(limit) = &self.limit {
+            let transferred = match &result {
+                Poll::Ready(Ok(transferred)) => *transferred,
+                _ => 0,
+            };
+            limit.give_back(allowed - transferred);
+        }
+        result
+    }
+}
+
+impl<Io: AsyncRead> AsyncRead for Throttled<Io> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        let allowed = futures::ready!(this.down.poll_allowed(cx, buf.len()));
+        let result = this.io.poll_read(cx, &mut buf[..allowed]);
+        this.down.finish(allowed, result)
+    }
+}
+
+impl<Io: AsyncWrite> AsyncWrite for Throttled<Io> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        let allowed = futures::ready!(this.up.poll_allowed(cx, buf.len()));
+        let result = this.io.poll_write(cx, &buf[..allowed]);
+        this.up.finish(allowed, result)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().io.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().io.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(1600, start);
+        assert_eq!(bucket.take(start, 1000), Ok(1000));
+        assert_eq!(bucket.take(start, 1000), Ok(600));
+        // Waits until a sixteenth of the rate is available
+        assert_eq!(
+            bucket.take(start, 1000),
+            Err(Duration::from_millis(62500) / 1000)
+        );
+        assert_eq!(
+            bucket.take(start + Duration::from_millis(500), 1000),
+            Ok(800)
+        );
+        bucket.give_back(300);
+        assert_eq!(
+            bucket.take(start + Duration::from_millis(500), 1000),
+            Ok(300)
+        );
+        // The bucket holds at most one second of data
+        assert_eq!(bucket.take(start + Duration::from_secs(10), 2000), Ok(1600));
+    }
+
+    #[async_std::test]
+    async fn throttle_write() {
+        let limit = RateLimit::new(1000);
+        let mut writer = Throttled::new(Vec::new()).up(limit.clone());
+        let start = Instant::now();
+        writer.write_all(&[0u8; 1500]).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(writer.into_inner().len(), 1500);
+
+        limit.set_rate(1_000_000);
+        assert_eq!(limit.rate(), 1_000_000);
+    }
+}
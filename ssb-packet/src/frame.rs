@@ -0,0 +1,245 @@
+use alloc::vec::Vec;
+
+use crate::header::{Header, HeaderParseError};
+
+/// Header and body of a packet. The body is not interpreted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub header: Header,
+    pub body: Vec<u8>,
+}
+
+impl Frame {
+    /// Bytes of the header followed by the body. The caller is responsible for
+    /// [Header::body_len] matching the body.
+    pub fn build(self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(Header::SIZE + self.body.len());
+        data.extend_from_slice(&self.header.build());
+        data.extend_from_slice(&self.body);
+        data
+    }
+}
+
+/// Error returned by [FrameDecoder::put].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    InvalidHeader(HeaderParseError),
+    BodyTooLarge { len: u32, max: u32 },
+}
+
+impl core::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FrameError::InvalidHeader(_) => write!(f, "Failed to parse packet header"),
+            FrameError::BodyTooLarge { len, max } => write!(
+                f,
+                "Packet body of {} bytes exceeds the limit of {} bytes",
+                len, max
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FrameError::InvalidHeader(error) => Some(error),
+            FrameError::BodyTooLarge { .. } => None,
+        }
+    }
+}
+
+impl From<HeaderParseError> for FrameError {
+    fn from(error: HeaderParseError) -> Self {
+        FrameError::InvalidHeader(error)
+    }
+}
+
+/// Largest body size for which [FrameDecoder] allocates the buffer before the body arrives.
+const MAX_BODY_PREALLOCATION: usize = 1024 * 1024;
+
+/// Maximum number of bytes kept by [FrameDecoder::rejected].
+pub const MAX_REJECTED_BYTES: usize = 64;
+
+/// Decoder that is fed bytes until it produces a [Frame].
+///
+/// Call [FrameDecoder::put] repeatedly until a [Frame] or an error is returned.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    /// Header of the frame whose body is being read. `None` while reading the header.
+    header: Option<Header>,
+    buffer: Vec<u8>,
+    max_body_len: Option<u32>,
+    /// Start of the frame that failed to decode
+    rejected: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail with [FrameError::BodyTooLarge] when a header announces a body that is longer than
+    /// `max` bytes. Bodies of any length are accepted if `max` is `None`.
+    pub fn set_max_body_len(&mut self, max: Option<u32>) {
+        self.max_body_len = max;
+    }
+
+    /// Start of the frame that made [FrameDecoder::put] fail or that was passed to
+    /// [FrameDecoder::reject], for example to log it. Contains the header and at most
+    /// [MAX_REJECTED_BYTES] bytes in total. Empty if no error occurred.
+    pub fn rejected(&self) -> &[u8] {
+        &self.rejected
+    }
+
+    /// Record a frame that was decoded but could not be interpreted, so that it is returned by
+    /// [FrameDecoder::rejected].
+    pub fn reject(&mut self, header: &Header, body: &[u8]) {
+        let body_len = core::cmp::min(body.len(), MAX_REJECTED_BYTES - Header::SIZE);
+        self.rejected = header.build().to_vec();
+        self.rejected.extend_from_slice(&body[..body_len]);
+    }
+
+    /// Consume bytes from the front of `data` until a frame is complete.
+    ///
+    /// Returns `None` if all of `data` was consumed without completing a frame. Returns
+    /// `Some(Ok(None))` if the goodbye header was read. Remaining bytes are left in `data`.
+    pub fn put(&mut self, data: &mut &[u8]) -> Option<Result<Option<Frame>, FrameError>> {
+        loop {
+            let needed = match self.header {
+                None => Header::SIZE,
+                Some(header) => header.body_len as usize,
+            };
+            let len = core::cmp::min(data.len(), needed - self.buffer.len());
+            self.buffer.extend_from_slice(&data[..len]);
+            *data = &data[len..];
+            if self.buffer.len() < needed {
+                return None;
+            }
+
+            let buffer = core::mem::take(&mut self.buffer);
+            match self.header.take() {
+                None => {
+                    let mut header_data = [0u8; Header::SIZE];
+                    header_data.copy_from_slice(&buffer);
+                    match Header::parse(header_data) {
+                        Ok(Some(header)) => {
+                            if let Some(max) =
+                                self.max_body_len.filter(|max| header.body_len > *max)
+                            {
+                                self.rejected = buffer;
+                                return Some(Err(FrameError::BodyTooLarge {
+                                    len: header.body_len,
+                                    max,
+                                }));
+                            }
+                            // Avoid copying large bodies every time the buffer grows. The peer
+                            // controls the length so we don’t trust it for huge bodies.
+                            self.buffer = Vec::with_capacity(core::cmp::min(
+                                header.body_len as usize,
+                                MAX_BODY_PREALLOCATION,
+                            ));
+                            self.header = Some(header)
+                        }
+                        Ok(None) => return Some(Ok(None)),
+                        Err(error) => {
+                            self.rejected = buffer;
+                            return Some(Err(FrameError::InvalidHeader(error)));
+                        }
+                    }
+                }
+                Some(header) => {
+                    return Some(Ok(Some(Frame {
+                        header,
+                        body: buffer,
+                    })))
+                }
+            }
+        }
+    }
+
+    /// Returns `true` if the decoder has not consumed any bytes of the next frame.
+    pub fn is_empty(&self) -> bool {
+        self.header.is_none() && self.buffer.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::header::{BodyType, HeaderFlags};
+    use alloc::vec;
+
+    fn frame(body: &[u8]) -> Frame {
+        Frame {
+            header: Header {
+                flags: HeaderFlags {
+                    is_stream: false,
+                    is_end_or_error: false,
+                    is_compressed: false,
+                },
+                body_type: BodyType::Binary,
+                body_len: body.len() as u32,
+                request_number: -3,
+            },
+            body: body.to_vec(),
+        }
+    }
+
+    #[test]
+    fn decode_byte_by_byte() {
+        let frames = vec![frame(b"abc"), frame(b""), frame(&[7; 300])];
+        let data = frames
+            .iter()
+            .cloned()
+            .flat_map(Frame::build)
+            .chain(vec![0u8; Header::SIZE])
+            .collect::<Vec<_>>();
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = vec![];
+        for byte in data.chunks(1) {
+            let mut byte = byte;
+            while let Some(result) = decoder.put(&mut byte) {
+                decoded.push(result.unwrap());
+            }
+            assert!(byte.is_empty());
+        }
+        assert!(decoder.is_empty());
+        let expected = frames.into_iter().map(Some).chain(vec![None]);
+        assert_eq!(decoded, expected.collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn leaves_remaining_bytes() {
+        let data = [frame(b"a").build(), frame(b"b").build()].concat();
+        let mut data = &data[..];
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(decoder.put(&mut data), Some(Ok(Some(frame(b"a")))));
+        assert_eq!(data, &frame(b"b").build()[..]);
+    }
+
+    #[test]
+    fn body_too_large() {
+        let data = frame(&[0; 11]).build();
+        let mut decoder = FrameDecoder::new();
+        decoder.set_max_body_len(Some(10));
+        assert_eq!(
+            decoder.put(&mut &data[..]),
+            Some(Err(FrameError::BodyTooLarge { len: 11, max: 10 }))
+        );
+        assert_eq!(decoder.rejected(), &data[..Header::SIZE]);
+    }
+
+    #[test]
+    fn invalid_header() {
+        // Request number zero
+        let data = [2, 0, 0, 0, 3, 0, 0, 0, 0, 1, 2, 3];
+        let mut decoder = FrameDecoder::new();
+        assert!(matches!(
+            decoder.put(&mut &data[..]),
+            Some(Err(FrameError::InvalidHeader(_)))
+        ));
+        assert_eq!(decoder.rejected(), &data[..Header::SIZE]);
+    }
+}
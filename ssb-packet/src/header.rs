@@ -3,6 +3,8 @@ use proptest::strategy::Strategy as _;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(test, derive(test_strategy::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
 pub struct Header {
     pub flags: HeaderFlags,
     pub body_type: BodyType,
@@ -14,63 +16,80 @@ pub struct Header {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(test, derive(test_strategy::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
 pub struct HeaderFlags {
     pub is_stream: bool,
     pub is_end_or_error: bool,
+    /// The body is compressed with the algorithm both peers agreed on. Only set by peers that
+    /// enabled compression.
+    pub is_compressed: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(test, derive(test_strategy::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
 #[repr(u8)]
 pub enum BodyType {
     Binary = 0,
     Utf8String = 1,
     Json = 2,
+    /// JSON value encoded as CBOR. Only sent to peers that agreed to it. The variant exists
+    /// regardless of features so that every header can be parsed. Whether the body is accepted
+    /// is up to the receiver.
+    Cbor = 3,
 }
 
 /// Error returned from [Header::parse].
-#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HeaderParseError {
-    #[error("Invalid body type {value}")]
-    InvalidBodyType { value: u8 },
-    #[error("Request number is zero")]
     RequestNumberZero,
 }
 
+impl core::fmt::Display for HeaderParseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            HeaderParseError::RequestNumberZero => write!(f, "Request number is zero"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for HeaderParseError {}
+
 impl BodyType {
-    fn from_flags(value: u8) -> Result<Self, HeaderParseError> {
+    fn from_flags(value: u8) -> Self {
         const BODY_TYPE_MASK: u8 = 0b0000_0011;
         match value & BODY_TYPE_MASK {
-            0 => Ok(BodyType::Binary),
-            1 => Ok(BodyType::Utf8String),
-            2 => Ok(BodyType::Json),
-            value => Err(HeaderParseError::InvalidBodyType { value }),
+            0 => BodyType::Binary,
+            1 => BodyType::Utf8String,
+            2 => BodyType::Json,
+            _ => BodyType::Cbor,
         }
     }
 }
 
 const IS_STREAM_MASK: u8 = 0b1000;
 const IS_END_OR_ERROR_MASK: u8 = 0b0100;
+const IS_COMPRESSED_MASK: u8 = 0b1_0000;
 
 impl Header {
     pub const SIZE: usize = 9;
 
+    /// Parse a header. Returns `None` for the goodbye header that consists of zeros.
     pub fn parse(data: [u8; Self::SIZE]) -> Result<Option<Self>, HeaderParseError> {
-        use bytes::Buf as _;
-
         if data == [0u8; Self::SIZE] {
             return Ok(None);
         }
 
-        let mut bytes = bytes::Bytes::copy_from_slice(&data);
-
-        let flags = bytes.get_u8();
+        let flags = data[0];
         let is_stream = flags & IS_STREAM_MASK != 0;
         let is_end_or_error = flags & IS_END_OR_ERROR_MASK != 0;
-        let body_type = BodyType::from_flags(flags)?;
-        let body_len = bytes.get_u32();
-        let request_number = bytes.get_i32();
-        debug_assert!(!bytes.has_remaining());
+        let is_compressed = flags & IS_COMPRESSED_MASK != 0;
+        let body_type = BodyType::from_flags(flags);
+        let body_len = u32::from_be_bytes([data[1], data[2], data[3], data[4]]);
+        let request_number = i32::from_be_bytes([data[5], data[6], data[7], data[8]]);
 
         if request_number == 0 {
             return Err(HeaderParseError::RequestNumberZero);
@@ -80,6 +99,7 @@ impl Header {
             flags: HeaderFlags {
                 is_stream,
                 is_end_or_error,
+                is_compressed,
             },
             body_type,
             body_len,
@@ -88,10 +108,6 @@ impl Header {
     }
 
     pub fn build(&self) -> [u8; Self::SIZE] {
-        use bytes::BufMut as _;
-
-        let mut header = [0u8; Self::SIZE];
-        let cursor = &mut &mut header[..];
         let mut flags = self.body_type as u8;
         if self.flags.is_stream {
             flags |= IS_STREAM_MASK;
@@ -99,10 +115,13 @@ impl Header {
         if self.flags.is_end_or_error {
             flags |= IS_END_OR_ERROR_MASK;
         }
-        cursor.put_u8(flags);
-        cursor.put_u32(self.body_len);
-        cursor.put_i32(self.request_number);
-        debug_assert!(!cursor.has_remaining_mut());
+        if self.flags.is_compressed {
+            flags |= IS_COMPRESSED_MASK;
+        }
+        let mut header = [0u8; Self::SIZE];
+        header[0] = flags;
+        header[1..5].copy_from_slice(&self.body_len.to_be_bytes());
+        header[5..].copy_from_slice(&self.request_number.to_be_bytes());
         header
     }
 }
@@ -110,7 +129,8 @@ impl Header {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::test_utils::*;
+    use proptest::prelude::*;
+    use test_strategy::proptest;
 
     #[proptest]
     fn header_parse_build(header: Header) {
@@ -120,20 +140,20 @@ mod test {
     #[proptest]
     fn header_build_parse(header_data: [u8; Header::SIZE]) {
         let mut header_data = header_data;
-        header_data[0] &= 0b0000_1111;
+        header_data[0] &= 0b0001_1111;
         let header = match Header::parse(header_data) {
             Ok(Some(header)) => header,
-            _ => prop_reject!(),
+            _ => return Err(TestCaseError::reject("Invalid header")),
         };
         prop_assert_eq!(header.build(), header_data);
     }
 
     #[proptest]
-    fn header_invalid_type(header_data: [u8; Header::SIZE]) {
-        let mut header_data = header_data;
+    fn header_cbor_type(header: Header) {
+        let mut header_data = header.build();
         header_data[0] |= 0b0000_0011;
-        let result = Header::parse(header_data);
-        prop_assert_eq!(result, Err(HeaderParseError::InvalidBodyType { value: 3 }));
+        let parsed = Header::parse(header_data).unwrap().unwrap();
+        prop_assert_eq!(parsed.body_type, BodyType::Cbor);
     }
 
     #[test]
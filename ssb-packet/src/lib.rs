@@ -0,0 +1,45 @@
+//! Encoding and decoding of Scuttlebutt RPC (muxrpc) packet headers and frames.
+//!
+//! The crate is `#![no_std]` and only requires `alloc`. It does not perform IO:
+//! [FrameDecoder] is fed bytes from any source and [Frame::build] returns the bytes to send. The
+//! bodies are not interpreted. The `ssb` crate decodes them into requests and responses.
+//!
+//! ```
+//! # use ssb_packet::*;
+//! let frame = Frame {
+//!     header: Header {
+//!         flags: HeaderFlags {
+//!             is_stream: true,
+//!             is_end_or_error: false,
+//!             is_compressed: false,
+//!         },
+//!         body_type: BodyType::Utf8String,
+//!         body_len: 5,
+//!         request_number: 1,
+//!     },
+//!     body: b"hello".to_vec(),
+//! };
+//! let data = frame.clone().build();
+//! let (first, second) = data.split_at(3);
+//!
+//! let mut decoder = FrameDecoder::new();
+//! assert_eq!(decoder.put(&mut &first[..]), None);
+//! assert_eq!(decoder.put(&mut &second[..]), Some(Ok(Some(frame))));
+//! ```
+//!
+//! # Features
+//!
+//! * `std` (default): Implements `std::error::Error` for the error types.
+//! * `serde`: `Serialize` and `Deserialize` for [Header].
+// Tests use `std` for the proptest macros.
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+#[cfg(all(feature = "std", not(test)))]
+extern crate std;
+
+mod frame;
+mod header;
+
+pub use frame::{Frame, FrameDecoder, FrameError, MAX_REJECTED_BYTES};
+pub use header::{BodyType, Header, HeaderFlags, HeaderParseError};
@@ -0,0 +1,68 @@
+//! Measures encoding and decoding of RPC packets and the latency of requests between two
+//! endpoints that are connected in memory.
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::prelude::*;
+use std::convert::TryFrom as _;
+
+use ssb::rpc::base::packet::{Header, Packet, Request, RequestType};
+use ssb::rpc::base::service::AsyncResponse;
+use ssb::rpc::base::{Endpoint, Service};
+
+fn request() -> Packet {
+    Packet::Request(Request::Async {
+        number: 42,
+        method: vec!["blobs".to_string(), "has".to_string()],
+        type_: RequestType::Async,
+        args: vec![serde_json::json!(
+            "&WWw4tQJ6ZrM7o3gA8lOEAcO4zmyqXqb/3bmIKTLQepo=.sha256"
+        )],
+    })
+}
+
+fn packet(c: &mut Criterion) {
+    let data = request().build();
+    let header_data = <[u8; Header::SIZE]>::try_from(&data[..Header::SIZE]).unwrap();
+    let header = Header::parse(header_data).unwrap().unwrap();
+
+    c.bench_function("header/parse", |b| b.iter(|| Header::parse(header_data)));
+    c.bench_function("header/build", |b| b.iter(|| header.build()));
+    c.bench_function("packet/parse", |b| {
+        b.iter(|| Packet::parse(header, data[Header::SIZE..].to_vec()).unwrap())
+    });
+    c.bench_function("packet/build", |b| b.iter(|| request().build()));
+}
+
+/// Create an [Endpoint] connected to a server endpoint that provides an `echo` method.
+fn connect() -> Endpoint {
+    let (client_sender, server_receiver) = futures::channel::mpsc::channel::<Vec<u8>>(10);
+    let (server_sender, client_receiver) = futures::channel::mpsc::channel::<Vec<u8>>(10);
+
+    let mut service = Service::new();
+    service.add_async("echo", |(value,): (serde_json::Value,)| async move {
+        AsyncResponse::json_ok(&value)
+    });
+    // The server endpoint runs until the client endpoint is dropped.
+    let _server = Endpoint::new(
+        server_sender,
+        server_receiver.map(Ok::<_, std::io::Error>),
+        service,
+    );
+    Endpoint::new_client(client_sender, client_receiver.map(Ok::<_, std::io::Error>))
+}
+
+fn request_response(c: &mut Criterion) {
+    let mut endpoint = connect();
+    c.bench_function("endpoint/async_request", |b| {
+        b.iter(|| {
+            async_std::task::block_on(
+                endpoint
+                    .client()
+                    .send_async(vec!["echo".to_string()], vec![serde_json::json!("hello")]),
+            )
+            .unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, packet, request_response);
+criterion_main!(benches);
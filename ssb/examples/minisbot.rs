@@ -0,0 +1,345 @@
+//! Minimal SSB peer that ties the building blocks of this crate together.
+//!
+//! The peer loads its identity from a secret file, accepts secret handshake connections, announces
+//! itself on the local network and dials the peers it discovers there and the peers given with
+//! `--connect`. Every connection is served with `manifest`, `whoami` and `createHistoryStream`
+//! backed by an in-memory feed store. The peer replicates its own feed and the feeds it follows
+//! from every outgoing connection.
+//!
+//! ```bash
+//! cargo run --example minisbot -- --secret ./secret --listen 0.0.0.0:8008 --host 192.168.1.10
+//! ```
+use anyhow::Context as _;
+use futures::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use structopt::StructOpt;
+
+use ssb::conn::{
+    ConnEvent, ConnEvents, Connection, ConnectionManager, Direction, DisconnectCause,
+    DuplicatePolicy, LanStrategy, ManualStrategy, PeerConnections, Traffic,
+};
+use ssb::crypto::sign::PublicKey;
+use ssb::discovery::{DiscoveryConfig, PeerDiscovery};
+use ssb::feed::{FeedId, MemoryFeedStore};
+use ssb::graph::Graph;
+use ssb::multi_address::{Address, MultiAddress};
+use ssb::plugin::{Context, Plugins};
+use ssb::replicate::Replicator;
+use ssb::rpc::base::service::AsyncResponse;
+use ssb::rpc::base::{Endpoint, Service};
+
+/// Time between two polls of the connection manager
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Minimal SSB peer that replicates feeds into an in-memory store
+#[derive(Debug, StructOpt)]
+struct Options {
+    /// Path of the secret file with the identity. Defaults to `~/.ssb/secret`
+    #[structopt(long)]
+    secret: Option<std::path::PathBuf>,
+
+    /// Address to listen on for secret handshake connections
+    #[structopt(long, default_value = "0.0.0.0:8008")]
+    listen: String,
+
+    /// IPv4 address of this machine on the local network. If given, the peer announces itself
+    /// with this address and the port of `--listen`
+    #[structopt(long)]
+    host: Option<std::net::Ipv4Addr>,
+
+    /// Disable discovery of peers on the local network
+    #[structopt(long)]
+    no_discovery: bool,
+
+    /// Dial this peer, for example `net:192.168.1.11:8008~shs:<key>`. May be given multiple times
+    #[structopt(long)]
+    connect: Vec<Address>,
+
+    /// Feeds up to this many hops from the identity are replicated
+    #[structopt(long, default_value = "1")]
+    hops: u32,
+
+    /// Largest number of connections that are open or being dialed at the same time
+    #[structopt(long, default_value = "3")]
+    max_connections: usize,
+}
+
+/// State shared by the listener, the dialer and the connections. Clones share the state.
+#[derive(Clone)]
+struct Peer {
+    id: FeedId,
+    identity: ssb_box_stream::SecretKey,
+    network_key: ssb::NetworkKey,
+    replicator: Replicator,
+    plugins: Plugins,
+    connections: PeerConnections,
+    events: ConnEvents,
+    manager: Arc<Mutex<ConnectionManager>>,
+}
+
+impl Peer {
+    fn new(options: &Options, identity: ssb_box_stream::SecretKey) -> anyhow::Result<Self> {
+        let id = FeedId(PublicKey(identity.public_key().0));
+        let graph = Graph::new(id, options.hops);
+        let replicator = Replicator::new(
+            Arc::new(MemoryFeedStore::new()),
+            Arc::new(Mutex::new(graph)),
+        );
+        let mut plugins = Plugins::new();
+        plugins.add(replicator.clone())?;
+        let connections = PeerConnections::new(id.0, DuplicatePolicy::ReplaceOld);
+        let events = ConnEvents::new();
+        let manager = ConnectionManager::new(options.max_connections)
+            .with(LanStrategy::new())
+            .with(ManualStrategy::new(options.connect.clone()))
+            .with_connections(connections.clone())
+            .with_events(events.clone());
+        Ok(Self {
+            id,
+            identity,
+            network_key: ssb::SCUTTLEBUTT_NETWORK_KEY,
+            replicator,
+            plugins,
+            connections,
+            events,
+            manager: Arc::new(Mutex::new(manager)),
+        })
+    }
+
+    /// Service for a connection to `peer`.
+    ///
+    /// The replicator contributes `createHistoryStream`. The service answers `manifest` by
+    /// itself.
+    fn service(&self, peer: FeedId) -> Service {
+        let mut service = self.plugins.service(&Context { peer: Some(peer.0) });
+        let id = self.id;
+        service.add_sync("whoami", move |_: Vec<()>| {
+            AsyncResponse::json_ok(&serde_json::json!({ "id": id }))
+        });
+        service
+    }
+
+    /// Accept connections on `addr` until accepting fails.
+    async fn listen(&self, addr: &str) -> anyhow::Result<()> {
+        let peer = self.clone();
+        ssb::conn::listen_tcp(
+            addr,
+            &self.network_key,
+            &self.identity,
+            self.events.clone(),
+            move |sender, receiver, remote| {
+                let peer = peer.clone();
+                async move {
+                    let connection = peer.connections.open(remote.0, Direction::Inbound)?;
+                    let endpoint = Endpoint::builder()
+                        .service(peer.service(remote))
+                        .peer(remote.into())
+                        .direction(Direction::Inbound)
+                        .build(sender, receiver);
+                    peer.serve(endpoint, connection, remote, Direction::Inbound)
+                        .await
+                }
+            },
+        )
+        .await
+        .context("Failed to accept connections")
+    }
+
+    /// Add the peers discovered on the local network to the connection manager. Never returns
+    /// unless discovery fails.
+    async fn discover(&self, announce: Option<MultiAddress>) -> anyhow::Result<()> {
+        let mut peers = PeerDiscovery::start(DiscoveryConfig {
+            announce,
+            ..DiscoveryConfig::default()
+        })
+        .context("Failed to start discovery")?;
+        while let Some(discovered) = peers.try_next().await? {
+            let own = discovered
+                .multi_address
+                .addresses
+                .iter()
+                .any(|address| address.shs_key().ok().as_deref() == Some(&self.id.0 .0[..]));
+            if own {
+                continue;
+            }
+            let mut manager = self.manager.lock().unwrap();
+            if let Some(lan) = manager.strategy_mut::<LanStrategy>() {
+                lan.discovered(&discovered, SystemTime::now());
+            }
+        }
+        Ok(())
+    }
+
+    /// Dial the addresses proposed by the connection manager. Never returns.
+    async fn dial(&self) -> anyhow::Result<()> {
+        loop {
+            let dials = self.manager.lock().unwrap().poll(SystemTime::now());
+            for dial in dials {
+                let peer = self.clone();
+                async_std::task::spawn(async move { peer.connect(dial.address).await });
+            }
+            async_std::task::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Connect to `address`, replicate with the peer and report the outcome to the connection
+    /// manager.
+    async fn connect(&self, address: Address) {
+        let dialer = self.manager.lock().unwrap().dialer().clone();
+        let identity = &self.identity;
+        let network_key = &self.network_key;
+        let connected = dialer
+            .connect(
+                &MultiAddress::from(address.clone()),
+                |address, stream| async move {
+                    let remote = ssb_box_stream::PublicKey::from_slice(&address.shs_key()?)?;
+                    let (sender, receiver) = ssb_box_stream::Client::new(
+                        network_key,
+                        &remote,
+                        &identity.public_key(),
+                        identity,
+                    )
+                    .connect(stream)
+                    .await?;
+                    Ok::<_, anyhow::Error>((sender, receiver, FeedId::from(remote)))
+                },
+            )
+            .await;
+        let (sender, receiver, remote) = match connected {
+            Ok((_, connection)) => connection,
+            Err(error) => {
+                tracing::debug!(%address, ?error, "dialing failed");
+                let reason = format!("{:#}", anyhow::Error::from(error));
+                self.manager.lock().unwrap().record_handshake_failure(
+                    &address,
+                    SystemTime::now(),
+                    &reason,
+                );
+                return;
+            }
+        };
+        self.manager
+            .lock()
+            .unwrap()
+            .record_success(&address, SystemTime::now());
+
+        let sent = sender.bytes_written().clone();
+        let received = receiver.bytes_read().clone();
+        let result = async {
+            let connection = self.connections.open(remote.0, Direction::Outbound)?;
+            let endpoint = Endpoint::builder()
+                .service(self.service(remote))
+                .peer(remote.into())
+                .direction(Direction::Outbound)
+                .build(sender, receiver);
+            self.serve(endpoint, connection, remote, Direction::Outbound)
+                .await
+        }
+        .await;
+        let cause = match result {
+            Ok(()) => DisconnectCause::Closed,
+            Err(error) => DisconnectCause::Error(format!("{:#}", error)),
+        };
+        self.manager.lock().unwrap().record_closed(
+            &address,
+            cause,
+            Traffic {
+                sent: sent.get(),
+                received: received.get(),
+            },
+        );
+    }
+
+    /// Serve `endpoint` until the peer closes it or a newer connection to the same peer replaces
+    /// it. Replicates with the peer first if we dialed it.
+    async fn serve(
+        &self,
+        mut endpoint: Endpoint,
+        mut connection: Connection,
+        remote: FeedId,
+        direction: Direction,
+    ) -> anyhow::Result<()> {
+        let close = endpoint.close_handle();
+        let serve = async {
+            if direction == Direction::Outbound {
+                if let Err(error) = self.replicator.replicate_history(endpoint.client()).await {
+                    tracing::warn!(%remote, ?error, "replication failed");
+                }
+            }
+            endpoint.join().await
+        };
+        let replaced = connection.replaced();
+        futures::pin_mut!(serve, replaced);
+        match future::select(replaced, serve).await {
+            future::Either::Left(((), serve)) => {
+                tracing::info!(%remote, "closing replaced connection");
+                close.close();
+                serve.await
+            }
+            future::Either::Right((result, _)) => result,
+        }
+    }
+}
+
+/// Log every connection event. Never returns.
+async fn log_events(
+    mut events: futures::channel::mpsc::UnboundedReceiver<ConnEvent>,
+) -> anyhow::Result<()> {
+    while let Some(event) = events.next().await {
+        match event {
+            ConnEvent::Connecting { addr } => tracing::debug!(%addr, "connecting"),
+            ConnEvent::HandshakeFailed { addr, reason } => {
+                tracing::info!(%addr, %reason, "handshake failed")
+            }
+            ConnEvent::Connected { peer, addr } => tracing::info!(%peer, %addr, "connected"),
+            ConnEvent::Disconnected { peer, cause, bytes } => tracing::info!(
+                %peer,
+                ?cause,
+                sent = bytes.sent,
+                received = bytes.received,
+                "disconnected"
+            ),
+        }
+    }
+    future::pending().await
+}
+
+#[async_std::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let options = Options::from_args();
+
+    let secret_key = match &options.secret {
+        Some(path) => ssb::secret_file::load(path),
+        None => ssb::secret_file::load_default(),
+    }
+    .context("Failed to load identity")?;
+    let peer = Peer::new(&options, ssb_box_stream::SecretKey(secret_key.0))?;
+
+    let port = options
+        .listen
+        .parse::<std::net::SocketAddr>()
+        .context("Invalid listen address")?
+        .port();
+    let announce = options.host.map(|host| {
+        Address::net_shs(&std::net::SocketAddrV4::new(host, port), &peer.id.0 .0[..]).into()
+    });
+    let discover = async {
+        if options.no_discovery {
+            future::pending().await
+        } else {
+            peer.discover(announce).await
+        }
+    };
+
+    tracing::info!(id = %peer.id, addr = %options.listen, "starting minisbot");
+    futures::try_join!(
+        peer.listen(&options.listen),
+        discover,
+        peer.dial(),
+        peer.plugins.run(),
+        log_events(peer.events.subscribe()),
+    )?;
+    Ok(())
+}
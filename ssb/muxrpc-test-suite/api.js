@@ -2,7 +2,28 @@ const { callbackify } = require("util");
 const lodash = require("lodash");
 const pull = require("pull-stream");
 
+// Upper bound for the sizes requested from `asyncLarge` and
+// `sourceLarge`.
+const MAX_PAYLOAD_SIZE = 16 * 1024 * 1024;
+
+function largePayload(size) {
+  if (size > MAX_PAYLOAD_SIZE) {
+    const error = new Error(
+      `Payloads are limited to ${MAX_PAYLOAD_SIZE} bytes`,
+    );
+    error.name = "PayloadTooLarge";
+    throw error;
+  }
+  return "x".repeat(size);
+}
+
 const api = {
+  // Responds with the first argument
+  syncEcho: {
+    type: "sync",
+    func: (value) => value,
+  },
+
   // Responds with the first argument
   asyncEcho: {
     type: "async",
@@ -18,6 +39,12 @@ const api = {
     },
   },
 
+  // Responds with a string of `size` bytes.
+  asyncLarge: {
+    type: "async",
+    func: async (size) => largePayload(size),
+  },
+
   // Takes an array of values as the first argument and streams the
   // values back.
   sourceEcho: {
@@ -36,7 +63,7 @@ const api = {
         if (values.length == 0) {
           cb(error);
         } else {
-          cb(null, values.pop());
+          cb(null, values.shift());
         }
       };
     },
@@ -54,6 +81,37 @@ const api = {
     },
   },
 
+  // Streams the given values waiting `delay` milliseconds before each
+  // value.
+  sourceSlow: {
+    type: "source",
+    func: (values, delay) => {
+      values = values.slice();
+      return function (end, cb) {
+        if (end) {
+          cb(end);
+        } else if (values.length == 0) {
+          cb(true);
+        } else {
+          setTimeout(() => cb(null, values.shift()), delay);
+        }
+      };
+    },
+  },
+
+  // Streams `count` strings of `size` bytes.
+  sourceLarge: {
+    type: "source",
+    func: (count, size) => {
+      try {
+        const payload = largePayload(size);
+        return pull.values(new Array(count).fill(payload));
+      } catch (error) {
+        return pull.error(error);
+      }
+    },
+  },
+
   // Takes a number as an argument and a stream of numbers. Streams the
   // numbers back adding the number argument.
   duplexAdd: {
@@ -49,6 +49,11 @@ suite("client", function () {
     }
   });
 
+  test("syncEcho", async function () {
+    const response = await this.client.syncEcho("foo");
+    assert.equal(response, "foo");
+  });
+
   test("asyncEcho", async function () {
     const response = await this.client.asyncEcho("foo");
     assert.equal(response, "foo");
@@ -76,6 +81,25 @@ suite("client", function () {
     assert.deepStrictEqual(errorResult, error);
   });
 
+  test("asyncLarge", async function () {
+    const response = await this.client.asyncLarge(100000);
+    assert.equal(response.length, 100000);
+  });
+
+  test("sourceSlow", async function () {
+    const values = [1, 2, 3];
+    const valuesResult = await collect(this.client.sourceSlow(values, 5));
+    assert.deepStrictEqual(valuesResult, values);
+  });
+
+  test("sourceLarge", async function () {
+    const valuesResult = await collect(this.client.sourceLarge(3, 70000));
+    assert.equal(valuesResult.length, 3);
+    for (const value of valuesResult) {
+      assert.equal(value.length, 70000);
+    }
+  });
+
   test("sourceInifite abort", async function () {
     const source = this.client.sourceInifite();
     await new Promise((resolve, reject) => {
@@ -0,0 +1,249 @@
+//! Names, images and descriptions of feeds computed from `about` messages.
+//!
+//! A feed describes itself and other feeds with `about` messages. Each message may set any of
+//! the fields. [About] keeps the latest value of every field per author and subject.
+//!
+//! ```rust
+//! # use ssb::about::About;
+//! # use ssb::feed::{FeedId, MemoryFeedStore};
+//! # use ssb::crypto::sign::KeyPair;
+//! # let alice = FeedId::from(KeyPair::gen().public);
+//! # let store = MemoryFeedStore::new();
+//! let mut about = About::new();
+//! about.load(&store).unwrap();
+//! let profile = about.resolve(&alice);
+//! println!("{}", profile.name().unwrap_or("unknown"));
+//! ```
+
+use futures::channel::mpsc;
+use futures::prelude::*;
+use std::collections::HashMap;
+
+use crate::feed::{FeedId, FeedStore, Message, StoreError};
+
+/// Fields of a feed’s profile set by one author.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProfileFields {
+    pub name: Option<String>,
+    /// Blob ID of the image
+    pub image: Option<String>,
+    pub description: Option<String>,
+}
+
+impl ProfileFields {
+    /// Overwrite the fields that `other` sets.
+    fn update(&mut self, other: ProfileFields) -> bool {
+        let before = self.clone();
+        if other.name.is_some() {
+            self.name = other.name;
+        }
+        if other.image.is_some() {
+            self.image = other.image;
+        }
+        if other.description.is_some() {
+            self.description = other.description;
+        }
+        *self != before
+    }
+}
+
+/// Profile of a feed as described by itself and by other feeds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// Fields the feed assigned to itself.
+    pub own: ProfileFields,
+    /// Fields other feeds assigned to the feed, by author.
+    pub assigned: HashMap<FeedId, ProfileFields>,
+}
+
+impl Profile {
+    /// The name the feed gave itself.
+    pub fn name(&self) -> Option<&str> {
+        self.own.name.as_deref()
+    }
+
+    /// The image the feed gave itself.
+    pub fn image(&self) -> Option<&str> {
+        self.own.image.as_deref()
+    }
+
+    /// The description the feed gave itself.
+    pub fn description(&self) -> Option<&str> {
+        self.own.description.as_deref()
+    }
+}
+
+/// Profile of a feed after an `about` message changed it.
+pub type ProfileUpdate = (FeedId, Profile);
+
+#[derive(Debug, Default)]
+pub struct About {
+    profiles: HashMap<FeedId, Profile>,
+    subscribers: Vec<mpsc::UnboundedSender<ProfileUpdate>>,
+}
+
+#[derive(serde::Deserialize)]
+struct AboutContent {
+    about: FeedId,
+    name: Option<String>,
+    image: Option<Image>,
+    description: Option<String>,
+}
+
+/// The image is either a blob ID or an object with the blob ID in `link`.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Image {
+    Link(String),
+    Object { link: String },
+}
+
+impl About {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the profiles with a message. Messages that are not `about` messages for a feed or
+    /// that are malformed are ignored.
+    ///
+    /// Messages must be applied in the order they were published by their author.
+    pub fn apply(&mut self, message: &Message) {
+        if message.value.content_type() != Some("about") {
+            return;
+        }
+        let content = match serde_json::from_value::<AboutContent>(message.value.content.clone()) {
+            Ok(content) => content,
+            Err(_) => return,
+        };
+        let fields = ProfileFields {
+            name: content.name,
+            image: content.image.map(|image| match image {
+                Image::Link(link) | Image::Object { link } => link,
+            }),
+            description: content.description,
+        };
+        self.assign(message.value.author, content.about, fields);
+    }
+
+    /// Set the fields of `subject` as described by `author`. Fields that are `None` are kept.
+    pub fn assign(&mut self, author: FeedId, subject: FeedId, fields: ProfileFields) {
+        let profile = self.profiles.entry(subject).or_default();
+        let changed = if author == subject {
+            profile.own.update(fields)
+        } else {
+            profile.assigned.entry(author).or_default().update(fields)
+        };
+        if changed {
+            let update = (subject, profile.clone());
+            self.subscribers
+                .retain(|subscriber| subscriber.unbounded_send(update.clone()).is_ok());
+        }
+    }
+
+    /// Returns the profile of `feed`. The profile is empty if there are no `about` messages for
+    /// the feed.
+    pub fn resolve(&self, feed: &FeedId) -> Profile {
+        self.profiles.get(feed).cloned().unwrap_or_default()
+    }
+
+    /// Stream of profiles that changed after the call.
+    pub fn updates(&mut self) -> impl Stream<Item = ProfileUpdate> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Apply the messages of all feeds in `store`.
+    pub fn load(&mut self, store: &dyn FeedStore) -> Result<(), StoreError> {
+        for feed in store.feeds()? {
+            for message in store.history(&feed, 1, None)? {
+                self.apply(&message);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Apply all messages from `log` to `about`. Resolves when the log stream ends.
+///
+/// The lock is only held while a message is applied so profiles can be resolved concurrently.
+pub async fn ingest(about: &std::sync::Mutex<About>, log: impl Stream<Item = Message>) {
+    futures::pin_mut!(log);
+    while let Some(message) = log.next().await {
+        about.lock().unwrap().apply(&message);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::sign::KeyPair;
+    use crate::feed::MemoryFeedStore;
+
+    #[test]
+    fn own_and_assigned_fields() {
+        let alice = KeyPair::gen();
+        let bob = KeyPair::gen();
+        let alice_id = FeedId(alice.public);
+        let bob_id = FeedId(bob.public);
+        let store = MemoryFeedStore::new();
+        let mut previous = None;
+        for content in [
+            serde_json::json!({ "type": "about", "about": alice_id, "name": "alice" }),
+            serde_json::json!({ "type": "about", "about": alice_id, "image": { "link": "&img" } }),
+            serde_json::json!({ "type": "about", "about": alice_id, "name": "Alice" }),
+            serde_json::json!({ "type": "post", "about": alice_id, "name": "ignored" }),
+        ] {
+            let message = crate::feed::validate::sign(&alice, previous.as_ref(), 0u64, content);
+            store.append(message.clone()).unwrap();
+            previous = Some(message);
+        }
+        let message = crate::feed::validate::sign(
+            &bob,
+            None,
+            0u64,
+            serde_json::json!({ "type": "about", "about": alice_id, "name": "al" }),
+        );
+        store.append(message).unwrap();
+
+        let mut about = About::new();
+        about.load(&store).unwrap();
+        let profile = about.resolve(&alice_id);
+        assert_eq!(profile.name(), Some("Alice"));
+        assert_eq!(profile.image(), Some("&img"));
+        assert_eq!(profile.description(), None);
+        assert_eq!(profile.assigned[&bob_id].name.as_deref(), Some("al"));
+        assert_eq!(about.resolve(&bob_id), Profile::default());
+    }
+
+    #[async_std::test]
+    async fn updates() {
+        let alice = FeedId(KeyPair::gen().public);
+        let mut about = About::new();
+        let mut updates = about.updates();
+
+        let fields = ProfileFields {
+            name: Some("alice".to_string()),
+            ..ProfileFields::default()
+        };
+        about.assign(alice, alice, fields.clone());
+        // Unchanged profiles are not sent.
+        about.assign(alice, alice, fields);
+        about.assign(
+            alice,
+            alice,
+            ProfileFields {
+                description: Some("hi".to_string()),
+                ..ProfileFields::default()
+            },
+        );
+
+        let (feed, profile) = updates.next().await.unwrap();
+        assert_eq!(feed, alice);
+        assert_eq!(profile.name(), Some("alice"));
+        let (_, profile) = updates.next().await.unwrap();
+        assert_eq!(profile.description(), Some("hi"));
+        drop(about);
+        assert!(updates.next().await.is_none());
+    }
+}
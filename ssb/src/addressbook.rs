@@ -0,0 +1,483 @@
+//! Addresses of peers that are announced in feeds.
+//!
+//! The [AddressBook] collects the addresses of pubs and rooms from `pub` and `room` messages in a
+//! [FeedStore]. Both message types announce an address in the `address` field of their content,
+//! either as a multiserver address string or in the legacy `{ host, port, key }` form. Addresses
+//! are normalized so that the same peer announced by different feeds is only stored once.
+//!
+//! The address book remembers when connecting to a peer last succeeded or failed and uses that
+//! to rank the candidates for new connections.
+//!
+//! ```rust
+//! # use ssb::addressbook::AddressBook;
+//! # use ssb::feed::MemoryFeedStore;
+//! let store = MemoryFeedStore::new();
+//! let mut address_book = AddressBook::new();
+//! address_book.ingest(&store).unwrap();
+//! for candidate in address_book.dial_candidates(std::time::SystemTime::now()) {
+//!     println!("{}", candidate.address);
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::feed::{FeedId, FeedStore, Message, StoreError};
+use crate::multi_address::{Address, MultiAddress, Protocol, Transport};
+
+/// Time to wait before dialing an address again after the first failure. The delay doubles with
+/// every consecutive failure.
+pub const MIN_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Upper limit for the time to wait before dialing an address again after it failed.
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// How the address book learned about an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AddressSource {
+    /// `pub` message
+    Pub,
+    /// `room` message
+    Room,
+    /// Added with [AddressBook::insert]
+    Manual,
+}
+
+/// Address of a peer together with the outcome of previous connection attempts.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerAddress {
+    pub address: Address,
+    pub source: AddressSource,
+    /// Feed that announced the address first. `None` for manually added addresses.
+    pub announced_by: Option<FeedId>,
+    pub last_success: Option<SystemTime>,
+    pub last_failure: Option<SystemTime>,
+    /// Number of failed connection attempts since the last success
+    pub failures: u32,
+}
+
+impl PeerAddress {
+    /// Returns the earliest time at which the address should be dialed again.
+    pub fn retry_at(&self) -> Option<SystemTime> {
+        let last_failure = self.last_failure?;
+        if self.failures == 0 {
+            return None;
+        }
+        let factor = 2u32.saturating_pow(self.failures - 1);
+        let delay = MIN_RETRY_DELAY
+            .checked_mul(factor)
+            .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY));
+        Some(last_failure + delay)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AddressBookError {
+    #[error("Failed to access address book file {path}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        error: std::io::Error,
+    },
+    #[error("Failed to decode address book")]
+    Json(
+        #[source]
+        #[from]
+        serde_json::Error,
+    ),
+}
+
+/// Known peer addresses. See the [module documentation][self].
+#[derive(Debug, Default, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct AddressBook {
+    #[serde(with = "peers_list")]
+    peers: HashMap<Address, PeerAddress>,
+    /// Latest sequence number of every feed that was ingested.
+    ingested: HashMap<FeedId, u64>,
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the address book from the JSON file at `path`. Returns an empty address book if the
+    /// file does not exist.
+    pub fn load(path: &Path) -> Result<Self, AddressBookError> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(error) => {
+                return Err(AddressBookError::Io {
+                    path: path.to_owned(),
+                    error,
+                })
+            }
+        };
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Write the address book as JSON to `path`.
+    ///
+    /// The data is written to a temporary file first that then replaces `path`. A crash while
+    /// saving never leaves a partially written address book behind.
+    pub fn save(&self, path: &Path) -> Result<(), AddressBookError> {
+        let data = serde_json::to_vec(self)?;
+        let tmp_path = path.with_extension("tmp");
+        let io_error = |error| AddressBookError::Io {
+            path: path.to_owned(),
+            error,
+        };
+        std::fs::write(&tmp_path, data).map_err(io_error)?;
+        std::fs::rename(&tmp_path, path).map_err(io_error)
+    }
+
+    /// Apply all messages in `store` that have not been ingested yet. Returns the number of
+    /// addresses that were added.
+    pub fn ingest(&mut self, store: &dyn FeedStore) -> Result<usize, StoreError> {
+        let mut added = 0;
+        for feed in store.feeds()? {
+            let from = self.ingested.get(&feed).map_or(1, |sequence| sequence + 1);
+            for message in store.history(&feed, from, None)? {
+                added += self.apply(&message);
+                self.ingested.insert(feed, message.value.sequence);
+            }
+        }
+        Ok(added)
+    }
+
+    /// Add the addresses announced by `message`. Messages that are not `pub` or `room` messages
+    /// and malformed addresses are ignored. Returns the number of addresses that were added.
+    pub fn apply(&mut self, message: &Message) -> usize {
+        let source = match message.value.content_type() {
+            Some("pub") => AddressSource::Pub,
+            Some("room") => AddressSource::Room,
+            _ => return 0,
+        };
+        let addresses = match message.value.content.get("address") {
+            Some(address) => parse_announcement(address),
+            None => return 0,
+        };
+        addresses
+            .into_iter()
+            .filter(|address| self.add(address.clone(), source, Some(message.value.author)))
+            .count()
+    }
+
+    /// Add `address` if it is valid and not known yet. Returns `true` if the address was added.
+    pub fn insert(&mut self, address: &Address) -> bool {
+        match normalize(address) {
+            Some(address) => self.add(address, AddressSource::Manual, None),
+            None => false,
+        }
+    }
+
+    fn add(
+        &mut self,
+        address: Address,
+        source: AddressSource,
+        announced_by: Option<FeedId>,
+    ) -> bool {
+        if self.peers.contains_key(&address) {
+            return false;
+        }
+        let peer = PeerAddress {
+            address: address.clone(),
+            source,
+            announced_by,
+            last_success: None,
+            last_failure: None,
+            failures: 0,
+        };
+        self.peers.insert(address, peer);
+        true
+    }
+
+    pub fn get(&self, address: &Address) -> Option<&PeerAddress> {
+        self.peers.get(&normalize(address)?)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &PeerAddress> {
+        self.peers.values()
+    }
+
+    /// Record that connecting to `address` succeeded at `time`. Unknown addresses are ignored.
+    pub fn record_success(&mut self, address: &Address, time: SystemTime) {
+        if let Some(peer) = self.peer_mut(address) {
+            peer.last_success = Some(time);
+            peer.failures = 0;
+        }
+    }
+
+    /// Record that connecting to `address` failed at `time`. Unknown addresses are ignored.
+    pub fn record_failure(&mut self, address: &Address, time: SystemTime) {
+        if let Some(peer) = self.peer_mut(address) {
+            peer.last_failure = Some(time);
+            peer.failures = peer.failures.saturating_add(1);
+        }
+    }
+
+    fn peer_mut(&mut self, address: &Address) -> Option<&mut PeerAddress> {
+        self.peers.get_mut(&normalize(address)?)
+    }
+
+    /// Addresses to dial at `now`, best candidates first.
+    ///
+    /// Addresses that failed recently are left out until [PeerAddress::retry_at]. The remaining
+    /// addresses are ordered like [AddressBook::ranked].
+    pub fn dial_candidates(&self, now: SystemTime) -> Vec<&PeerAddress> {
+        let mut candidates = self.ranked();
+        candidates.retain(|peer| peer.retry_at().into_iter().all(|retry_at| retry_at <= now));
+        candidates
+    }
+
+    /// All addresses, best candidates first.
+    ///
+    /// Addresses are ordered by the number of consecutive failures and then by the time of the
+    /// last successful connection, most recent first. Addresses that never connected come after
+    /// those that did.
+    pub fn ranked(&self) -> Vec<&PeerAddress> {
+        let mut peers = self.peers.values().collect::<Vec<_>>();
+        peers.sort_by(|a, b| {
+            a.failures
+                .cmp(&b.failures)
+                .then_with(|| b.last_success.cmp(&a.last_success))
+                .then_with(|| a.address.to_string().cmp(&b.address.to_string()))
+        });
+        peers
+    }
+}
+
+/// Legacy form of the `address` field in `pub` messages.
+#[derive(serde::Deserialize)]
+struct LegacyAddress {
+    host: String,
+    port: u16,
+    key: FeedId,
+}
+
+/// Parse the `address` field of an announcement and return the valid addresses in normalized
+/// form.
+fn parse_announcement(value: &serde_json::Value) -> Vec<Address> {
+    if let Some(multi_address) = value.as_str() {
+        return match multi_address.parse::<MultiAddress>() {
+            Ok(multi_address) => multi_address
+                .addresses
+                .iter()
+                .filter_map(normalize)
+                .collect(),
+            Err(_) => vec![],
+        };
+    }
+    match serde_json::from_value::<LegacyAddress>(value.clone()) {
+        Ok(legacy) => {
+            let address = Address {
+                protocols: vec![
+                    Protocol {
+                        name: "net".to_string(),
+                        data: vec![legacy.host, legacy.port.to_string()],
+                    },
+                    Protocol::shs(legacy.key.public_key().as_ref()),
+                ],
+            };
+            normalize(&address).into_iter().collect()
+        }
+        Err(_) => vec![],
+    }
+}
+
+/// Returns the address with only the transport and `shs` protocols and lower case host names.
+/// Returns `None` if the address cannot be dialed.
+fn normalize(address: &Address) -> Option<Address> {
+    let key = address.shs_key().ok()?;
+    if key.len() != 32 {
+        return None;
+    }
+    let transport = match address.transport().ok()? {
+        Transport::Net(socket_addr) => Protocol {
+            name: "net".to_string(),
+            data: vec![socket_addr.ip().to_string(), socket_addr.port().to_string()],
+        },
+        Transport::Dns { host, port } => Protocol {
+            name: "net".to_string(),
+            data: vec![host.to_lowercase(), port.to_string()],
+        },
+        Transport::Onion { host, port } => Protocol::onion(&host.to_lowercase(), port),
+    };
+    Some(Address {
+        protocols: vec![transport, Protocol::shs(&key)],
+    })
+}
+
+/// Serializes the peers as a list because JSON objects only have string keys.
+mod peers_list {
+    use super::*;
+
+    pub fn serialize<S: serde::Serializer>(
+        peers: &HashMap<Address, PeerAddress>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(peers.values())
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<Address, PeerAddress>, D::Error> {
+        let peers = <Vec<PeerAddress> as serde::Deserialize>::deserialize(deserializer)?;
+        Ok(peers
+            .into_iter()
+            .map(|peer| (peer.address.clone(), peer))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::sign::KeyPair;
+    use crate::feed::{MemoryFeedStore, MessageId, MessageValue};
+
+    fn feed() -> FeedId {
+        FeedId::from(KeyPair::gen().public)
+    }
+
+    fn message(author: FeedId, sequence: u64, content: serde_json::Value) -> Message {
+        Message {
+            key: MessageId([0; 32]),
+            value: MessageValue {
+                previous: None,
+                author,
+                sequence,
+                timestamp: 0.into(),
+                hash: "sha256".to_string(),
+                content,
+                signature: String::new(),
+            },
+            timestamp: 0.0,
+        }
+    }
+
+    fn key() -> String {
+        base64::encode(KeyPair::gen().public)
+    }
+
+    #[test]
+    fn ingest_announcements() {
+        let store = MemoryFeedStore::new();
+        let author = feed();
+        let pub_feed = feed();
+        let room_key = key();
+        let messages = vec![
+            serde_json::json!({
+                "type": "pub",
+                "address": { "host": "Example.com", "port": 8008, "key": pub_feed.to_string() },
+            }),
+            // Same address as the first message
+            serde_json::json!({
+                "type": "pub",
+                "address": format!("net:example.com:8008~shs:{}", base64::encode(pub_feed.public_key())),
+            }),
+            serde_json::json!({
+                "type": "room",
+                "address": format!("net:10.0.0.1:8008~shs:{};onion:foo.onion:8008~shs:{}", room_key, room_key),
+            }),
+            serde_json::json!({ "type": "pub", "address": "net:10.0.0.2:8008" }),
+            serde_json::json!({ "type": "post", "text": "hello" }),
+        ];
+        for (index, content) in messages.into_iter().enumerate() {
+            store
+                .append(message(author, index as u64 + 1, content))
+                .unwrap();
+        }
+
+        let mut address_book = AddressBook::new();
+        assert_eq!(address_book.ingest(&store).unwrap(), 3);
+        let pub_address: Address = format!(
+            "net:example.com:8008~shs:{}",
+            base64::encode(pub_feed.public_key())
+        )
+        .parse()
+        .unwrap();
+        let peer = address_book.get(&pub_address).unwrap();
+        assert_eq!(peer.source, AddressSource::Pub);
+        assert_eq!(peer.announced_by, Some(author));
+        let room_address: Address = format!("onion:foo.onion:8008~shs:{}", room_key)
+            .parse()
+            .unwrap();
+        assert_eq!(
+            address_book.get(&room_address).unwrap().source,
+            AddressSource::Room
+        );
+
+        // Only new messages are applied
+        let address = format!("net:10.0.0.3:8008~shs:{}", key());
+        store
+            .append(message(
+                author,
+                6,
+                serde_json::json!({ "type": "pub", "address": address }),
+            ))
+            .unwrap();
+        assert_eq!(address_book.ingest(&store).unwrap(), 1);
+        assert_eq!(address_book.ingest(&store).unwrap(), 0);
+    }
+
+    #[test]
+    fn dial_candidates() {
+        let mut address_book = AddressBook::new();
+        let addresses = (1..=3)
+            .map(|n| {
+                format!("net:10.0.0.{}:8008~shs:{}", n, key())
+                    .parse::<Address>()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        for address in &addresses {
+            assert!(address_book.insert(address));
+        }
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        address_book.record_success(&addresses[1], start);
+        address_book.record_failure(&addresses[2], start);
+
+        let candidates = |now| {
+            address_book
+                .dial_candidates(now)
+                .into_iter()
+                .map(|peer| peer.address.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            candidates(start),
+            vec![addresses[1].clone(), addresses[0].clone()]
+        );
+        assert_eq!(
+            candidates(start + MIN_RETRY_DELAY),
+            vec![
+                addresses[1].clone(),
+                addresses[0].clone(),
+                addresses[2].clone()
+            ]
+        );
+
+        address_book.record_failure(&addresses[2], start);
+        let peer = address_book.get(&addresses[2]).unwrap();
+        assert_eq!(peer.retry_at(), Some(start + MIN_RETRY_DELAY * 2));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("addressbook.json");
+        assert_eq!(AddressBook::load(&path).unwrap(), AddressBook::new());
+
+        let mut address_book = AddressBook::new();
+        let address: Address = format!("net:10.0.0.1:8008~shs:{}", key()).parse().unwrap();
+        address_book.insert(&address);
+        address_book.record_failure(&address, SystemTime::UNIX_EPOCH);
+        address_book.save(&path).unwrap();
+        assert_eq!(AddressBook::load(&path).unwrap(), address_book);
+    }
+}
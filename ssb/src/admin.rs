@@ -0,0 +1,417 @@
+//! Inspect and control a running [PubServer] over a local connection.
+//!
+//! [service] provides the methods of the `admin` group that [PubServer::service] adds for local
+//! connections, for example over the unix socket of [Config::socket]. Peers that connect over
+//! the network cannot call them.
+//!
+//! | Method              | Type  | Response                                       |
+//! |---------------------|-------|------------------------------------------------|
+//! | `admin.connections` | sync  | Open connections as [ConnectionStatus] list    |
+//! | `admin.replication` | sync  | Replication state as [FeedProgress] list       |
+//! | `admin.connect`     | async | Feed ID of the peer at the given multiaddress  |
+//! | `admin.disconnect`  | sync  | Number of connections to the given feed closed |
+//!
+//! The open connections and their streams are tracked by a [Monitor] that is passed as
+//! [EndpointMetrics] to the endpoint of every connection. `ssbc status` prints the responses.
+//!
+//! [Config::socket]: crate::pub_server::Config::socket
+use std::collections::{btree_map, BTreeMap};
+use std::sync::{Arc, Mutex};
+
+use crate::conn::Direction;
+use crate::feed::FeedId;
+use crate::multi_address::MultiAddress;
+use crate::pub_server::PubServer;
+use crate::replicate::FeedProgress;
+use crate::rpc::base::packet::{Packet, Request, Response};
+use crate::rpc::base::service::AsyncResponse;
+use crate::rpc::base::{EndpointMetrics, Error, Service, StreamMessage};
+
+/// Connection to a peer returned by `admin.connections`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionStatus {
+    pub peer: FeedId,
+    pub direction: Direction,
+    /// Encrypted bytes sent over the connection. `0` if the traffic is not counted.
+    pub sent: u64,
+    /// Encrypted bytes received over the connection. `0` if the traffic is not counted.
+    pub received: u64,
+    /// Streams that are open in at least one direction, ordered by request number
+    pub streams: Vec<StreamStatus>,
+}
+
+/// Stream of a connection returned by `admin.connections`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamStatus {
+    pub number: u32,
+    /// `true` if the stream was requested by us, `false` if it was requested by the peer
+    pub local: bool,
+    /// Method of the stream request. Empty if the request could not be decoded.
+    pub method: Vec<String>,
+}
+
+/// Open connections with their streams and traffic. Clones share the connections.
+///
+/// A connection is tracked from [Monitor::track] until the returned [TrackedConnection] is
+/// dropped, usually together with the endpoint it was passed to.
+#[derive(Debug, Clone, Default)]
+pub struct Monitor {
+    inner: Arc<Mutex<MonitorInner>>,
+}
+
+#[derive(Debug, Default)]
+struct MonitorInner {
+    connections: BTreeMap<u64, Tracked>,
+    next_id: u64,
+}
+
+#[derive(Debug)]
+struct Tracked {
+    peer: FeedId,
+    direction: Direction,
+    traffic: Option<(ssb_box_stream::ByteCounter, ssb_box_stream::ByteCounter)>,
+    /// Open streams by whether we requested them and request number
+    streams: BTreeMap<(bool, u32), OpenStream>,
+}
+
+#[derive(Debug)]
+struct OpenStream {
+    method: Vec<String>,
+    sent_end: bool,
+    received_end: bool,
+}
+
+impl Monitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track a new connection to `peer`. Pass the result to
+    /// [EndpointBuilder::metrics][crate::rpc::base::EndpointBuilder::metrics].
+    pub fn track(&self, peer: FeedId, direction: Direction) -> TrackedConnection {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.connections.insert(
+            id,
+            Tracked {
+                peer,
+                direction,
+                traffic: None,
+                streams: BTreeMap::new(),
+            },
+        );
+        TrackedConnection {
+            monitor: self.clone(),
+            id,
+        }
+    }
+
+    /// Status of all tracked connections, oldest first.
+    pub fn connections(&self) -> Vec<ConnectionStatus> {
+        self.inner
+            .lock()
+            .unwrap()
+            .connections
+            .values()
+            .map(|connection| {
+                let (sent, received) = connection
+                    .traffic
+                    .as_ref()
+                    .map_or((0, 0), |(sent, received)| (sent.get(), received.get()));
+                ConnectionStatus {
+                    peer: connection.peer,
+                    direction: connection.direction,
+                    sent,
+                    received,
+                    streams: connection
+                        .streams
+                        .iter()
+                        .map(|((local, number), stream)| StreamStatus {
+                            number: *number,
+                            local: *local,
+                            method: stream.method.clone(),
+                        })
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Connection tracked by a [Monitor]. Records the streams of the endpoint it is passed to as
+/// [EndpointMetrics]. The connection is removed from the monitor when this is dropped.
+#[derive(Debug)]
+pub struct TrackedConnection {
+    monitor: Monitor,
+    id: u64,
+}
+
+impl TrackedConnection {
+    /// Report the encrypted bytes counted by the box stream of the connection.
+    pub fn traffic(
+        self,
+        sent: &ssb_box_stream::ByteCounter,
+        received: &ssb_box_stream::ByteCounter,
+    ) -> Self {
+        if let Some(connection) = self
+            .monitor
+            .inner
+            .lock()
+            .unwrap()
+            .connections
+            .get_mut(&self.id)
+        {
+            connection.traffic = Some((sent.clone(), received.clone()));
+        }
+        self
+    }
+
+    fn stream_packet(&self, packet: &Packet, sent: bool) {
+        // Requesters send stream packets as requests and responders send them as responses.
+        let (local, number, message) = match packet {
+            Packet::Request(Request::Stream { number, message }) => (sent, *number, message),
+            Packet::Response(Response::Stream { number, message }) => (!sent, *number, message),
+            _ => return,
+        };
+        let mut inner = self.monitor.inner.lock().unwrap();
+        let streams = match inner.connections.get_mut(&self.id) {
+            Some(connection) => &mut connection.streams,
+            None => return,
+        };
+        let stream = match streams.entry((local, number)) {
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            btree_map::Entry::Vacant(entry) => {
+                let body = match message {
+                    StreamMessage::Data(body) => body,
+                    // End of a stream we did not see data for
+                    _ => return,
+                };
+                #[derive(serde::Deserialize)]
+                struct StreamRequestName {
+                    name: Vec<String>,
+                }
+                let method = body
+                    .decode_json::<StreamRequestName>()
+                    .map(|request| request.name)
+                    .unwrap_or_default();
+                entry.insert(OpenStream {
+                    method,
+                    sent_end: false,
+                    received_end: false,
+                })
+            }
+        };
+        if !matches!(message, StreamMessage::Data(_)) {
+            if sent {
+                stream.sent_end = true;
+            } else {
+                stream.received_end = true;
+            }
+        }
+        if stream.sent_end && stream.received_end {
+            streams.remove(&(local, number));
+        }
+    }
+}
+
+impl EndpointMetrics for TrackedConnection {
+    fn packet_received(&self, packet: &Packet) {
+        self.stream_packet(packet, false);
+    }
+
+    fn packet_sent(&self, packet: &Packet) {
+        self.stream_packet(packet, true);
+    }
+}
+
+impl Drop for TrackedConnection {
+    fn drop(&mut self) {
+        self.monitor
+            .inner
+            .lock()
+            .unwrap()
+            .connections
+            .remove(&self.id);
+    }
+}
+
+/// Methods of the `admin` group for local connections to `server`.
+pub fn service(server: &PubServer) -> Service {
+    let mut service = Service::new();
+
+    let monitor = server.monitor().clone();
+    service.add_sync("connections", move |_: Vec<()>| {
+        AsyncResponse::json_ok(&monitor.connections())
+    });
+    service.describe("connections", "List the open connections and streams", &[]);
+
+    let replicator = server.replicator().clone();
+    service.add_sync("replication", move |_: Vec<()>| {
+        match replicator.progress() {
+            Ok(progress) => AsyncResponse::json_ok(&progress),
+            Err(error) => AsyncResponse::Err(Error::new("Error", error)),
+        }
+    });
+    service.describe(
+        "replication",
+        "Compare the latest sequence numbers of the replicated feeds with the peers",
+        &[],
+    );
+
+    let connect_server = server.clone();
+    service.add_async("connect", move |(address,): (MultiAddress,)| {
+        let server = connect_server.clone();
+        async move {
+            match server.connect(&address).await {
+                Ok(peer) => AsyncResponse::json_ok(&peer),
+                Err(error) => AsyncResponse::Err(Error::new("Error", format!("{:#}", error))),
+            }
+        }
+    });
+    service.describe(
+        "connect",
+        "Connect to a peer and replicate with it",
+        &[("address", "Multiaddress of the peer")],
+    );
+
+    let connections = server.connections().clone();
+    service.add_sync("disconnect", move |(feed,): (FeedId,)| {
+        AsyncResponse::json_ok(&connections.disconnect(&feed.0))
+    });
+    service.describe(
+        "disconnect",
+        "Close all connections to a peer",
+        &[("feed", "Feed ID of the peer")],
+    );
+
+    service
+}
+
+/// Client for the `admin` methods, see [crate::rpc::ssb::Client::admin].
+#[derive(Debug)]
+pub struct AdminClient<'a> {
+    client: &'a mut crate::rpc::ssb::Client,
+}
+
+impl<'a> AdminClient<'a> {
+    pub(crate) fn new(client: &'a mut crate::rpc::ssb::Client) -> Self {
+        Self { client }
+    }
+
+    pub async fn connections(&mut self) -> Result<Vec<ConnectionStatus>, crate::rpc::ssb::Error> {
+        self.client
+            .send_async_typed::<crate::rpc::ssb::Json<_>>(&["admin", "connections"], vec![])
+            .await
+            .map(|crate::rpc::ssb::Json(connections)| connections)
+    }
+
+    pub async fn replication(&mut self) -> Result<Vec<FeedProgress>, crate::rpc::ssb::Error> {
+        self.client
+            .send_async_typed::<crate::rpc::ssb::Json<_>>(&["admin", "replication"], vec![])
+            .await
+            .map(|crate::rpc::ssb::Json(progress)| progress)
+    }
+
+    /// Ask the server to connect to the peer at `address`. Returns the feed ID of the peer once
+    /// the handshake succeeded.
+    pub async fn connect(
+        &mut self,
+        address: &MultiAddress,
+    ) -> Result<FeedId, crate::rpc::ssb::Error> {
+        self.client
+            .send_async_typed::<crate::rpc::ssb::Json<_>>(
+                &["admin", "connect"],
+                vec![serde_json::to_value(address).unwrap()],
+            )
+            .await
+            .map(|crate::rpc::ssb::Json(peer)| peer)
+    }
+
+    /// Ask the server to close all connections to `feed`. Returns the number of closed
+    /// connections.
+    pub async fn disconnect(&mut self, feed: &FeedId) -> Result<usize, crate::rpc::ssb::Error> {
+        self.client
+            .send_async_typed::<crate::rpc::ssb::Json<_>>(
+                &["admin", "disconnect"],
+                vec![serde_json::to_value(feed).unwrap()],
+            )
+            .await
+            .map(|crate::rpc::ssb::Json(count)| count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::sign::KeyPair;
+    use crate::feed::MemoryFeedStore;
+    use crate::pub_server::Config;
+    use crate::rpc::base::Endpoint;
+    use async_std::os::unix::net::UnixStream;
+    use futures::prelude::*;
+
+    #[async_std::test]
+    async fn track_streams() {
+        let monitor = Monitor::new();
+        let peer = FeedId(KeyPair::gen().public);
+        drop(monitor.track(peer, Direction::Outbound));
+        assert!(monitor.connections().is_empty());
+
+        let mut service = Service::new();
+        service.add_source("numbers", |_: Vec<()>| {
+            futures::stream::iter(vec![Ok(1u32), Ok(2)])
+                .map_ok(|number| crate::rpc::base::Body::json(&number))
+                .chain(futures::stream::pending())
+        });
+        let (client_io, server_io) = UnixStream::pair().unwrap();
+        let _server_endpoint = Endpoint::builder()
+            .service(service)
+            .metrics(monitor.track(peer, Direction::Inbound))
+            .build_io(server_io);
+        let mut client = Endpoint::from_io(client_io, Service::new());
+
+        let mut source = client
+            .client()
+            .start_source(vec!["numbers".to_string()], vec![])
+            .await
+            .unwrap();
+        source.next().await.unwrap().unwrap();
+        let connections = monitor.connections();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].peer, peer);
+        assert_eq!(
+            connections[0].streams,
+            vec![StreamStatus {
+                number: 1,
+                local: false,
+                method: vec!["numbers".to_string()],
+            }]
+        );
+    }
+
+    #[async_std::test]
+    async fn local_only() {
+        let config = Config::new(KeyPair::gen(), "/nonexistent");
+        let server = PubServer::new(&config, Arc::new(MemoryFeedStore::new())).unwrap();
+        assert!(server.service(None).manifest()["admin"].is_object());
+        assert!(server
+            .service(Some(KeyPair::gen().public))
+            .manifest()
+            .get("admin")
+            .is_none());
+
+        let (client_io, server_io) = UnixStream::pair().unwrap();
+        let endpoint = Endpoint::from_io(server_io, server.service(None));
+        async_std::task::spawn(async move { endpoint.join().await });
+        let mut client = crate::rpc::ssb::Client::from_io(client_io);
+        assert_eq!(client.admin().connections().await.unwrap(), vec![]);
+        let progress = client.admin().replication().await.unwrap();
+        assert_eq!(progress.len(), 1);
+        assert_eq!(progress[0].feed, server.id());
+        let peer = FeedId(KeyPair::gen().public);
+        assert_eq!(client.admin().disconnect(&peer).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,19 @@
+use structopt::StructOpt;
+
+/// Serve the muxrpc compatibility service for cross-implementation tests.
+///
+/// See `ssb::rpc::base::test_server` for the methods it provides.
+#[derive(Debug, StructOpt)]
+struct Options {
+    /// Address to listen on for TCP connections
+    #[structopt(long, default_value = "127.0.0.1:19423")]
+    listen: String,
+}
+
+#[async_std::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let options = Options::from_args();
+    tracing::info!(address = %options.listen, "listening");
+    ssb::rpc::base::test_server::run(options.listen).await
+}
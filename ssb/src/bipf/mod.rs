@@ -0,0 +1,357 @@
+//! [BIPF] (Binary In-Place Format) encoding used by ssb-db2 and the buttwoo feed format.
+//!
+//! Every value starts with a varint tag `length << 3 | type` followed by `length` bytes of data.
+//!
+//! Values are represented by [Value]. [to_vec] and [from_slice] encode and decode any type that
+//! implements [serde::Serialize] and [serde::Deserialize].
+//!
+//! ```rust
+//! #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+//! struct Vote {
+//!     link: String,
+//!     value: i64,
+//! }
+//!
+//! let vote = Vote { link: "%R7lJEkz27lNijPhYNDzYoPjM0Fp+bFWzwX0SmNJB/ZE=.sha256".to_string(), value: 1 };
+//! let data = ssb::bipf::to_vec(&vote).unwrap();
+//! assert_eq!(ssb::bipf::from_slice::<Vote>(&data).unwrap(), vote);
+//! ```
+//!
+//! [BIPF]: https://github.com/ssbc/bipf-spec
+
+use std::convert::TryFrom;
+
+mod de;
+mod ser;
+pub use de::from_value;
+pub use ser::to_value;
+
+const TYPE_STRING: u64 = 0;
+const TYPE_BUFFER: u64 = 1;
+const TYPE_INT: u64 = 2;
+const TYPE_DOUBLE: u64 = 3;
+const TYPE_ARRAY: u64 = 4;
+const TYPE_OBJECT: u64 = 5;
+const TYPE_BOOL_NULL: u64 = 6;
+
+/// Largest integer that JavaScript represents exactly.
+const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_991.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Buffer(Vec<u8>),
+    Int(i32),
+    Double(f64),
+    Array(Vec<Value>),
+    /// Entries of an object in order
+    Object(Vec<(Value, Value)>),
+    Bool(bool),
+    Null,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid BIPF at byte {position}: {reason}")]
+pub struct DecodeError {
+    pub position: usize,
+    pub reason: &'static str,
+}
+
+/// Error of [to_value], [to_vec], [from_value] and [from_slice].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    #[error("{0}")]
+    Message(String),
+}
+
+impl serde::ser::Error for Error {
+    fn custom<T: std::fmt::Display>(message: T) -> Self {
+        Error::Message(message.to_string())
+    }
+}
+
+impl serde::de::Error for Error {
+    fn custom<T: std::fmt::Display>(message: T) -> Self {
+        Error::Message(message.to_string())
+    }
+}
+
+/// Encode `value` as BIPF. See [to_value] for how values are represented.
+pub fn to_vec<T: serde::Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, Error> {
+    Ok(encode(&to_value(value)?))
+}
+
+/// Decode `T` from BIPF `data`.
+pub fn from_slice<T: serde::de::DeserializeOwned>(data: &[u8]) -> Result<T, Error> {
+    from_value(decode(data)?)
+}
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut output = Vec::new();
+    encode_into(value, &mut output);
+    output
+}
+
+fn encode_into(value: &Value, output: &mut Vec<u8>) {
+    let (type_, data) = match value {
+        Value::String(string) => (TYPE_STRING, string.as_bytes().to_vec()),
+        Value::Buffer(buffer) => (TYPE_BUFFER, buffer.clone()),
+        Value::Int(int) => (TYPE_INT, int.to_le_bytes().to_vec()),
+        Value::Double(double) => (TYPE_DOUBLE, double.to_le_bytes().to_vec()),
+        Value::Array(items) => {
+            let mut data = Vec::new();
+            for item in items {
+                encode_into(item, &mut data);
+            }
+            (TYPE_ARRAY, data)
+        }
+        Value::Object(entries) => {
+            let mut data = Vec::new();
+            for (key, value) in entries {
+                encode_into(key, &mut data);
+                encode_into(value, &mut data);
+            }
+            (TYPE_OBJECT, data)
+        }
+        Value::Bool(value) => (TYPE_BOOL_NULL, vec![*value as u8]),
+        Value::Null => (TYPE_BOOL_NULL, Vec::new()),
+    };
+    write_varint((data.len() as u64) << 3 | type_, output);
+    output.extend(data);
+}
+
+fn write_varint(mut value: u64, output: &mut Vec<u8>) {
+    while value >= 0x80 {
+        output.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+/// Decode a single value that must span all of `data`.
+pub fn decode(data: &[u8]) -> Result<Value, DecodeError> {
+    let mut position = 0;
+    let value = decode_at(data, &mut position)?;
+    if position != data.len() {
+        return Err(DecodeError {
+            position,
+            reason: "trailing data",
+        });
+    }
+    Ok(value)
+}
+
+fn decode_at(data: &[u8], position: &mut usize) -> Result<Value, DecodeError> {
+    let error = |position: usize, reason| DecodeError { position, reason };
+    let tag = read_varint(data, position)?;
+    let (length, type_) = ((tag >> 3) as usize, tag & 7);
+    let start = *position;
+    let body = start
+        .checked_add(length)
+        .and_then(|end| data.get(start..end))
+        .ok_or_else(|| error(start, "unexpected end of data"))?;
+    *position = start + length;
+    let value = match type_ {
+        TYPE_STRING => Value::String(
+            String::from_utf8(body.to_vec()).map_err(|_| error(start, "invalid UTF-8"))?,
+        ),
+        TYPE_BUFFER => Value::Buffer(body.to_vec()),
+        TYPE_INT if length == 4 => {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(body);
+            Value::Int(i32::from_le_bytes(bytes))
+        }
+        TYPE_DOUBLE if length == 8 => {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(body);
+            Value::Double(f64::from_le_bytes(bytes))
+        }
+        TYPE_ARRAY => {
+            let mut items = Vec::new();
+            let mut offset = 0;
+            while offset < body.len() {
+                items.push(decode_at(body, &mut offset)?);
+            }
+            Value::Array(items)
+        }
+        TYPE_OBJECT => {
+            let mut entries = Vec::new();
+            let mut offset = 0;
+            while offset < body.len() {
+                let key = decode_at(body, &mut offset)?;
+                let value = decode_at(body, &mut offset)?;
+                entries.push((key, value));
+            }
+            Value::Object(entries)
+        }
+        TYPE_BOOL_NULL => match body {
+            [] => Value::Null,
+            [0] => Value::Bool(false),
+            [1] => Value::Bool(true),
+            _ => return Err(error(start, "invalid boolean")),
+        },
+        _ => return Err(error(start, "invalid type or length")),
+    };
+    Ok(value)
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> Result<u64, DecodeError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*position).ok_or(DecodeError {
+            position: *position,
+            reason: "unexpected end of data",
+        })?;
+        *position += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(DecodeError {
+        position: *position,
+        reason: "varint too long",
+    })
+}
+
+/// Convert JSON to BIPF. Integers that do not fit into 32 bits are encoded as doubles.
+pub fn from_json(json: &serde_json::Value) -> Value {
+    match json {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(value) => Value::Bool(*value),
+        serde_json::Value::Number(number) => {
+            match number.as_i64().and_then(|int| i32::try_from(int).ok()) {
+                Some(int) => Value::Int(int),
+                None => Value::Double(number.as_f64().unwrap_or(0.0)),
+            }
+        }
+        serde_json::Value::String(string) => Value::String(string.clone()),
+        serde_json::Value::Array(items) => Value::Array(items.iter().map(from_json).collect()),
+        serde_json::Value::Object(entries) => Value::Object(
+            entries
+                .iter()
+                .map(|(key, value)| (Value::String(key.clone()), from_json(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Convert BIPF to JSON. Buffers are encoded as base64 strings and object keys that are not
+/// strings are dropped. Doubles without a fractional part become integers like in JavaScript.
+pub fn to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::String(string) => string.clone().into(),
+        Value::Buffer(buffer) => base64::encode(buffer).into(),
+        Value::Int(int) => (*int).into(),
+        Value::Double(double) if double.fract() == 0.0 && double.abs() <= MAX_SAFE_INTEGER => {
+            (*double as i64).into()
+        }
+        Value::Double(double) => serde_json::Number::from_f64(*double)
+            .map_or(serde_json::Value::Null, serde_json::Value::Number),
+        Value::Array(items) => items.iter().map(to_json).collect(),
+        Value::Object(entries) => serde_json::Value::Object(
+            entries
+                .iter()
+                .filter_map(|(key, value)| match key {
+                    Value::String(key) => Some((key.clone(), to_json(value))),
+                    _ => None,
+                })
+                .collect(),
+        ),
+        Value::Bool(value) => (*value).into(),
+        Value::Null => serde_json::Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let json = serde_json::json!({
+            "type": "post",
+            "count": 3,
+            "list": [true, null, "x"],
+        });
+        let value = from_json(&json);
+        let encoded = encode(&value);
+        assert_eq!(decode(&encoded), Ok(value.clone()));
+        assert_eq!(to_json(&value), json);
+        assert_eq!(encode(&Value::Int(1)), vec![0x22, 1, 0, 0, 0]);
+        assert_eq!(
+            from_json(&serde_json::json!(1_638_000_000_000u64)),
+            Value::Double(1_638_000_000_000.0)
+        );
+        assert_eq!(
+            to_json(&Value::Double(1_638_000_000_000.0)),
+            serde_json::json!(1_638_000_000_000u64)
+        );
+        assert_eq!(to_json(&Value::Double(0.5)), serde_json::json!(0.5));
+        assert_eq!(encode(&Value::String("a".repeat(16))), {
+            let mut expected = vec![0x80, 0x01];
+            expected.extend("a".repeat(16).as_bytes());
+            expected
+        });
+    }
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    enum Content {
+        Empty,
+        Text(String),
+        Pair(u8, bool),
+        Post {
+            timestamp: u64,
+            root: Option<String>,
+        },
+    }
+
+    /// Serializes as a BIPF buffer.
+    #[derive(Debug, PartialEq)]
+    struct Bytes(Vec<u8>);
+
+    impl serde::Serialize for Bytes {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+
+    #[test]
+    fn serde() {
+        let contents = vec![
+            Content::Empty,
+            Content::Text("hi".to_string()),
+            Content::Pair(7, true),
+            Content::Post {
+                timestamp: 1_638_000_000_000,
+                root: None,
+            },
+        ];
+        let data = to_vec(&contents).unwrap();
+        assert_eq!(from_slice::<Vec<Content>>(&data).unwrap(), contents);
+        assert_eq!(
+            to_json(&decode(&data).unwrap()),
+            serde_json::json!([
+                "Empty",
+                { "Text": "hi" },
+                { "Pair": [7, true] },
+                { "Post": { "timestamp": 1_638_000_000_000u64, "root": null } },
+            ])
+        );
+
+        assert_eq!(
+            to_value(&Bytes(vec![1, 2])).unwrap(),
+            Value::Buffer(vec![1, 2])
+        );
+
+        let json = serde_json::json!({ "a": [1.5, -3, "x"], "b": null });
+        assert_eq!(from_value::<serde_json::Value>(from_json(&json)), Ok(json));
+        assert!(matches!(
+            from_slice::<u64>(&encode(&Value::Double(0.5))),
+            Err(Error::Message(_))
+        ));
+        assert!(matches!(from_slice::<u64>(&[0xff]), Err(Error::Decode(_))));
+    }
+}
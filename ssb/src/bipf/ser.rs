@@ -0,0 +1,346 @@
+use serde::ser::{self, Serialize};
+use std::convert::TryFrom;
+
+use super::{Error, Value};
+
+/// Convert `value` to BIPF.
+///
+/// Integers that do not fit into 32 bits are encoded as doubles. Byte arrays become buffers.
+/// Enums are encoded like `serde_json` does: unit variants as strings and other variants as
+/// objects with the variant name as the only key.
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value, Error> {
+    value.serialize(Serializer)
+}
+
+struct Serializer;
+
+fn integer(value: impl Into<i128>) -> Value {
+    let value = value.into();
+    match i32::try_from(value) {
+        Ok(int) => Value::Int(int),
+        Err(_) => Value::Double(value as f64),
+    }
+}
+
+fn variant(name: &'static str, value: Value) -> Value {
+    Value::Object(vec![(Value::String(name.to_string()), value)])
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = SerializeArray;
+    type SerializeTuple = SerializeArray;
+    type SerializeTupleStruct = SerializeArray;
+    type SerializeTupleVariant = SerializeArray;
+    type SerializeMap = SerializeObject;
+    type SerializeStruct = SerializeObject;
+    type SerializeStructVariant = SerializeObject;
+
+    fn serialize_bool(self, value: bool) -> Result<Value, Error> {
+        Ok(Value::Bool(value))
+    }
+
+    fn serialize_i8(self, value: i8) -> Result<Value, Error> {
+        Ok(integer(value))
+    }
+
+    fn serialize_i16(self, value: i16) -> Result<Value, Error> {
+        Ok(integer(value))
+    }
+
+    fn serialize_i32(self, value: i32) -> Result<Value, Error> {
+        Ok(integer(value))
+    }
+
+    fn serialize_i64(self, value: i64) -> Result<Value, Error> {
+        Ok(integer(value))
+    }
+
+    fn serialize_u8(self, value: u8) -> Result<Value, Error> {
+        Ok(integer(value))
+    }
+
+    fn serialize_u16(self, value: u16) -> Result<Value, Error> {
+        Ok(integer(value))
+    }
+
+    fn serialize_u32(self, value: u32) -> Result<Value, Error> {
+        Ok(integer(value))
+    }
+
+    fn serialize_u64(self, value: u64) -> Result<Value, Error> {
+        Ok(integer(value))
+    }
+
+    fn serialize_f32(self, value: f32) -> Result<Value, Error> {
+        Ok(Value::Double(value.into()))
+    }
+
+    fn serialize_f64(self, value: f64) -> Result<Value, Error> {
+        Ok(Value::Double(value))
+    }
+
+    fn serialize_char(self, value: char) -> Result<Value, Error> {
+        Ok(Value::String(value.to_string()))
+    }
+
+    fn serialize_str(self, value: &str) -> Result<Value, Error> {
+        Ok(Value::String(value.to_string()))
+    }
+
+    fn serialize_bytes(self, value: &[u8]) -> Result<Value, Error> {
+        Ok(Value::Buffer(value.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Value, Error> {
+        Ok(Value::String(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        Ok(variant(name, to_value(value)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeArray, Error> {
+        Ok(SerializeArray {
+            variant: None,
+            items: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeArray, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeArray, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeArray, Error> {
+        Ok(SerializeArray {
+            variant: Some(variant),
+            items: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeObject, Error> {
+        Ok(SerializeObject {
+            variant: None,
+            entries: Vec::with_capacity(len.unwrap_or(0)),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeObject, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeObject, Error> {
+        Ok(SerializeObject {
+            variant: Some(variant),
+            entries: Vec::with_capacity(len),
+            key: None,
+        })
+    }
+}
+
+struct SerializeArray {
+    /// Name of the enum variant the array belongs to.
+    variant: Option<&'static str>,
+    items: Vec<Value>,
+}
+
+impl SerializeArray {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.items.push(to_value(value)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Value, Error> {
+        let array = Value::Array(self.items);
+        Ok(match self.variant {
+            Some(name) => variant(name, array),
+            None => array,
+        })
+    }
+}
+
+impl ser::SerializeSeq for SerializeArray {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeArray {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeArray {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeArray {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+struct SerializeObject {
+    /// Name of the enum variant the object belongs to.
+    variant: Option<&'static str>,
+    entries: Vec<(Value, Value)>,
+    /// Key of the entry whose value is serialized next.
+    key: Option<Value>,
+}
+
+impl SerializeObject {
+    fn finish(self) -> Result<Value, Error> {
+        let object = Value::Object(self.entries);
+        Ok(match self.variant {
+            Some(name) => variant(name, object),
+            None => object,
+        })
+    }
+}
+
+impl ser::SerializeMap for SerializeObject {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(to_value(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| <Error as ser::Error>::custom("value serialized before key"))?;
+        self.entries.push((key, to_value(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for SerializeObject {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.entries
+            .push((Value::String(key.to_string()), to_value(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeObject {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        ser::SerializeStruct::serialize_field(self, key, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
@@ -0,0 +1,70 @@
+//! Client side of the `blobs` RPC methods.
+use futures::prelude::*;
+
+use super::BlobId;
+use crate::rpc::base::{Body, Client, Error};
+
+/// Download the blob with `id` from the peer with `blobs.get`.
+///
+/// Fails if the blob is larger than `max` bytes or if the received data does not match `id`. If
+/// `max` is `None` the peer’s limit applies.
+pub async fn get(client: &mut Client, id: &BlobId, max: Option<u64>) -> anyhow::Result<Vec<u8>> {
+    let args = match max {
+        Some(max) => serde_json::json!({ "key": id, "max": max }),
+        None => serde_json::json!({ "key": id }),
+    };
+    let mut source = client
+        .start_source(vec!["blobs".to_string(), "get".to_string()], vec![args])
+        .await?;
+    let mut data = Vec::new();
+    while let Some(body) = source.next().await {
+        match body {
+            Ok(Body::Blob(chunk)) => data.extend_from_slice(&chunk),
+            Ok(body) => anyhow::bail!("Unexpected body {:?}", body),
+            Err(Error { name, message }) => anyhow::bail!("{}: {}", name, message),
+        }
+        if let Some(max) = max {
+            anyhow::ensure!(data.len() as u64 <= max, "Blob exceeds maximum size");
+        }
+    }
+    anyhow::ensure!(
+        &BlobId::for_data(&data) == id,
+        "Received data does not match blob ID"
+    );
+    Ok(data)
+}
+
+/// Upload a blob to the peer with `blobs.add` and return its ID.
+///
+/// The blob is sent in the chunks that `chunks` yields. If reading a chunk fails the stream is
+/// aborted and the peer discards the data.
+pub async fn add<Chunks, E>(client: &mut Client, chunks: Chunks) -> anyhow::Result<BlobId>
+where
+    Chunks: Stream<Item = Result<Vec<u8>, E>>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let (mut response, mut sink) = client
+        .start_sink(vec!["blobs".to_string(), "add".to_string()], vec![])
+        .await?;
+    let mut hasher = crate::crypto::Hasher::new();
+    futures::pin_mut!(chunks);
+    loop {
+        match chunks.try_next().await {
+            Ok(Some(chunk)) => {
+                hasher.update(&chunk);
+                sink.send(Body::Blob(chunk)).await?;
+            }
+            Ok(None) => break,
+            Err(error) => {
+                let _ = sink.error(Error::new("Error", &error)).await;
+                return Err(error.into());
+            }
+        }
+    }
+    sink.close().await?;
+    match response.next().await {
+        None => Ok(BlobId(hasher.finalize())),
+        Some(Ok(body)) => anyhow::bail!("Unexpected body {:?}", body),
+        Some(Err(Error { name, message })) => anyhow::bail!("{}: {}", name, message),
+    }
+}
@@ -0,0 +1,561 @@
+//! Content-addressed blob storage and the `blobs` RPC plugin.
+//!
+//! Blobs are identified by the SHA256 hash of their content, for example
+//! `&uaGieSQDJcHfUp6hjIcIq55GoZh4Ug7tNmgaohoxrpw=.sha256`, and stored on disk by [BlobStore].
+//!
+//! The [Blobs] plugin serves the `blobs.get`, `blobs.add`, `blobs.has`, `blobs.size`,
+//! `blobs.want` and `blobs.createWants` methods. The [client] module calls `blobs.get` and
+//! `blobs.add` on a peer. Missing blobs are fetched from connected peers with the want/have
+//! protocol implemented by [Blobs::replicate]. Each peer calls `blobs.createWants` on the other
+//! peer. The resulting stream carries maps from blob IDs to numbers. A negative number announces
+//! that the sender wants the blob. A non-negative number announces that the sender has the blob
+//! and is its size in bytes.
+
+use anyhow::Context as _;
+use futures::channel::{mpsc, oneshot};
+use futures::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+
+use crate::crypto::sign::PublicKey;
+use crate::plugin::{Context, Plugin};
+use crate::rpc::base::service::{AsyncResponse, Body, Error, Service, SinkError};
+use crate::rpc::base::{Client, StreamMessage, TypedSource};
+
+pub mod client;
+mod store;
+pub use store::BlobStore;
+
+/// Default maximum size of blobs that are fetched from, served to and added by peers.
+pub const DEFAULT_MAX_SIZE: u64 = 5 * 1024 * 1024;
+
+/// Size of the chunks a blob is sent in by `blobs.get`.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// SHA256 hash that identifies a blob.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlobId([u8; 32]);
+
+impl BlobId {
+    /// Compute the ID of a blob with the given content.
+    pub fn for_data(data: &[u8]) -> Self {
+        Self(crate::crypto::hash(data))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    fn to_hex(self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() != 64 || !hex.is_ascii() {
+            return None;
+        }
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+        }
+        Some(Self(bytes))
+    }
+}
+
+impl std::fmt::Display for BlobId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "&{}.sha256", base64::encode(self.0))
+    }
+}
+
+impl std::fmt::Debug for BlobId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("BlobId").field(&self.to_string()).finish()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("Invalid blob ID {id:?}")]
+pub struct BlobIdParseError {
+    pub id: String,
+}
+
+impl std::str::FromStr for BlobId {
+    type Err = BlobIdParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || BlobIdParseError { id: s.to_string() };
+        let encoded = s
+            .strip_prefix('&')
+            .and_then(|s| s.strip_suffix(".sha256"))
+            .ok_or_else(error)?;
+        let bytes = base64::decode(encoded).map_err(|_| error())?;
+        let bytes = <[u8; 32]>::try_from(bytes.as_slice()).map_err(|_| error())?;
+        Ok(Self(bytes))
+    }
+}
+
+impl serde::Serialize for BlobId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for BlobId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Message sent on `blobs.createWants` streams.
+type WantsMessage = BTreeMap<BlobId, i64>;
+
+/// Plugin that stores blobs and exchanges them with peers.
+///
+/// Cloning returns a handle to the same store and wants.
+#[derive(Clone)]
+pub struct Blobs {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    store: BlobStore,
+    max_size: u64,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Blobs we want to fetch from peers.
+    wants: HashMap<BlobId, Vec<oneshot::Sender<()>>>,
+    /// Senders for the `blobs.createWants` streams we serve.
+    peers: Vec<(Option<PublicKey>, mpsc::UnboundedSender<WantsMessage>)>,
+    /// Messages for peers that have not called `blobs.createWants` yet.
+    pending: HashMap<PublicKey, WantsMessage>,
+}
+
+impl State {
+    /// Send `message` to the `createWants` streams of all peers.
+    fn broadcast(&mut self, message: WantsMessage) {
+        self.peers
+            .retain(|(_, sender)| sender.unbounded_send(message.clone()).is_ok());
+    }
+
+    /// Send `message` to the `createWants` stream of `peer`.
+    ///
+    /// If the peer has not called `createWants` yet the message is sent once it does.
+    fn send_to(&mut self, peer: PublicKey, message: WantsMessage) {
+        self.peers.retain(|(_, sender)| !sender.is_closed());
+        let sender = self
+            .peers
+            .iter()
+            .find(|(p, _)| p.as_ref() == Some(&peer))
+            .map(|(_, sender)| sender);
+        match sender {
+            Some(sender) => {
+                let _ = sender.unbounded_send(message);
+            }
+            None => self.pending.entry(peer).or_default().extend(message),
+        }
+    }
+}
+
+impl Blobs {
+    pub fn new(store: BlobStore) -> Self {
+        Self::with_max_size(store, DEFAULT_MAX_SIZE)
+    }
+
+    /// Create the plugin with a custom maximum size for blobs exchanged with peers.
+    pub fn with_max_size(store: BlobStore, max_size: u64) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                store,
+                max_size,
+                state: Mutex::new(State::default()),
+            }),
+        }
+    }
+
+    pub fn store(&self) -> &BlobStore {
+        &self.inner.store
+    }
+
+    /// Store a blob, resolve pending [Blobs::want]s for it and announce it to peers that want it.
+    pub async fn add(&self, data: &[u8]) -> std::io::Result<BlobId> {
+        let id = self.inner.store.add(data).await?;
+        let mut state = self.inner.state.lock().unwrap();
+        if let Some(waiters) = state.wants.remove(&id) {
+            for waiter in waiters {
+                let _ = waiter.send(());
+            }
+            let mut have = WantsMessage::new();
+            have.insert(id, data.len() as i64);
+            state.broadcast(have);
+        }
+        Ok(id)
+    }
+
+    /// Resolves once the blob is available in the store.
+    ///
+    /// If the blob is not stored yet the want is announced to all connected peers and the blob is
+    /// fetched from the first peer that has it.
+    pub async fn want(&self, id: &BlobId) -> std::io::Result<()> {
+        if self.inner.store.has(id).await {
+            return Ok(());
+        }
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            let waiters = state.wants.entry(*id).or_default();
+            waiters.push(sender);
+            if waiters.len() == 1 {
+                let mut want = WantsMessage::new();
+                want.insert(*id, -1);
+                state.broadcast(want);
+            }
+        }
+        // The sender is owned by `self` and is only dropped after it was used.
+        receiver.await.expect("Want sender dropped");
+        Ok(())
+    }
+
+    /// Exchange wants and haves with the peer connected through `client` and fetch the blobs we
+    /// want from it.
+    ///
+    /// `peer` must be the same key that is passed in the [Context] of the connection so that
+    /// haves are sent on the right `createWants` stream. Wants of the peer are not forwarded to
+    /// other peers.
+    ///
+    /// Runs until the peer closes its `createWants` stream.
+    pub async fn replicate(&self, client: &mut Client, peer: PublicKey) -> anyhow::Result<()> {
+        let wants = client
+            .start_source(vec!["blobs".to_string(), "createWants".to_string()], vec![])
+            .await?;
+        let mut wants = TypedSource::<WantsMessage>::new(wants);
+        while let Some(message) = wants.next().await {
+            let message = message.context("blobs.createWants failed")?;
+            for (id, value) in message {
+                if value < 0 {
+                    if let Some(size) = self.inner.store.size(&id).await? {
+                        let mut have = WantsMessage::new();
+                        have.insert(id, size as i64);
+                        let mut state = self.inner.state.lock().unwrap();
+                        state.send_to(peer, have);
+                    }
+                } else if value as u64 <= self.inner.max_size && self.is_wanted(&id) {
+                    if let Err(error) = self.fetch(client, &id).await {
+                        tracing::warn!(%id, ?error, "failed to fetch blob");
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_wanted(&self, id: &BlobId) -> bool {
+        self.inner.state.lock().unwrap().wants.contains_key(id)
+    }
+
+    /// Fetch a blob from the peer with `blobs.get` and store it.
+    async fn fetch(&self, client: &mut Client, id: &BlobId) -> anyhow::Result<()> {
+        let data = client::get(client, id, Some(self.inner.max_size)).await?;
+        self.add(&data).await?;
+        Ok(())
+    }
+
+    /// Read a blob for `blobs.get` and split it into chunks.
+    async fn get_chunks(&self, args: GetArgs) -> Result<Vec<Body>, Error> {
+        let (id, max) = match args {
+            GetArgs::Id(id) => (id, self.inner.max_size),
+            GetArgs::Options { key, max } => (key, max.unwrap_or(self.inner.max_size)),
+        };
+        let data = self
+            .inner
+            .store
+            .get(&id)
+            .await
+            .map_err(io_error)?
+            .ok_or_else(|| Error::new("Error", format!("Blob {} not found", id)))?;
+        if data.len() as u64 > max {
+            return Err(Error::new(
+                "Error",
+                format!("Blob {} is larger than {} bytes", id, max),
+            ));
+        }
+        Ok(data
+            .chunks(CHUNK_SIZE)
+            .map(|chunk| Body::Blob(chunk.to_vec()))
+            .collect())
+    }
+
+    /// Handle a message on a `blobs.add` stream. `data` holds the chunks received so far.
+    ///
+    /// Stores the blob once the peer ends the stream.
+    async fn receive_chunk(
+        self,
+        expected: Option<BlobId>,
+        mut data: Vec<u8>,
+        stream_message: StreamMessage,
+    ) -> Result<Vec<u8>, SinkError> {
+        match stream_message {
+            StreamMessage::Data(Body::Blob(chunk)) => {
+                data.extend_from_slice(&chunk);
+                if data.len() as u64 > self.inner.max_size {
+                    return Err(SinkError::Error(Error::new(
+                        "Error",
+                        format!("Blob is larger than {} bytes", self.inner.max_size),
+                    )));
+                }
+                Ok(data)
+            }
+            StreamMessage::Data(body) => Err(SinkError::Error(Error::new(
+                "Error",
+                format!("Unexpected body {:?}", body),
+            ))),
+            StreamMessage::Error(_) => Err(SinkError::Done),
+            StreamMessage::End => {
+                let id = BlobId::for_data(&data);
+                if let Some(expected) = expected {
+                    if id != expected {
+                        return Err(SinkError::Error(Error::new(
+                            "Error",
+                            format!("Received data does not match blob ID {}", expected),
+                        )));
+                    }
+                }
+                self.add(&data)
+                    .await
+                    .map_err(|error| SinkError::Error(io_error(error)))?;
+                tracing::debug!(%id, "added blob");
+                Err(SinkError::Done)
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Blobs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Blobs")
+            .field("store", &self.inner.store)
+            .field("max_size", &self.inner.max_size)
+            .finish()
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum GetArgs {
+    Id(BlobId),
+    Options { key: BlobId, max: Option<u64> },
+}
+
+impl Plugin for Blobs {
+    fn name(&self) -> &str {
+        "blobs"
+    }
+
+    fn init(&self, service: &mut Service, context: &Context) {
+        let blobs = self.clone();
+        service.add_source("get", move |(args,): (GetArgs,)| {
+            let blobs = blobs.clone();
+            async move { blobs.get_chunks(args).await }
+                .map(|result| match result {
+                    Ok(chunks) => futures::stream::iter(chunks.into_iter().map(Ok)).left_stream(),
+                    Err(error) => futures::stream::once(async { Err(error) }).right_stream(),
+                })
+                .flatten_stream()
+        });
+
+        let blobs = self.clone();
+        service.add_sink("add", move |ids: Vec<BlobId>| {
+            let blobs = blobs.clone();
+            // The peer may pass the expected ID of the blob.
+            let expected = ids.first().copied();
+            futures::sink::unfold(Vec::new(), move |data, stream_message| {
+                blobs.clone().receive_chunk(expected, data, stream_message)
+            })
+        });
+
+        let blobs = self.clone();
+        service.add_async("has", move |(id,): (BlobId,)| {
+            let blobs = blobs.clone();
+            async move { AsyncResponse::json_ok(&blobs.inner.store.has(&id).await) }
+        });
+
+        let blobs = self.clone();
+        service.add_async("size", move |(id,): (BlobId,)| {
+            let blobs = blobs.clone();
+            async move {
+                match blobs.inner.store.size(&id).await {
+                    Ok(size) => AsyncResponse::json_ok(&size),
+                    Err(error) => AsyncResponse::Err(io_error(error)),
+                }
+            }
+        });
+
+        let blobs = self.clone();
+        service.add_async("want", move |(id,): (BlobId,)| {
+            let blobs = blobs.clone();
+            async move {
+                match blobs.want(&id).await {
+                    Ok(()) => AsyncResponse::json_ok(&true),
+                    Err(error) => AsyncResponse::Err(io_error(error)),
+                }
+            }
+        });
+
+        let blobs = self.clone();
+        let peer = context.peer;
+        service.add_source("createWants", move |_: Vec<()>| {
+            let (sender, receiver) = mpsc::unbounded();
+            let mut state = blobs.inner.state.lock().unwrap();
+            let mut initial = peer
+                .and_then(|peer| state.pending.remove(&peer))
+                .unwrap_or_default();
+            initial.extend(state.wants.keys().map(|id| (*id, -1)));
+            let _ = sender.unbounded_send(initial);
+            state.peers.push((peer, sender));
+            receiver.map(|message| Ok(Body::json(&message)))
+        });
+    }
+}
+
+fn io_error(error: std::io::Error) -> Error {
+    Error::new("Error", error)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::sign::KeyPair;
+    use crate::rpc::base::Endpoint;
+
+    #[test]
+    fn blob_id_string() {
+        let id = BlobId::for_data(b"hello");
+        let string = id.to_string();
+        assert_eq!(
+            string,
+            "&LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=.sha256"
+        );
+        assert_eq!(string.parse::<BlobId>().unwrap(), id);
+        assert_eq!(BlobId::from_hex(&id.to_hex()), Some(id));
+        assert!("%LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=.sha256"
+            .parse::<BlobId>()
+            .is_err());
+    }
+
+    #[async_std::test]
+    async fn store_add_get() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BlobStore::new(dir.path());
+        let id = store.add(b"hello").await.unwrap();
+        assert!(store
+            .path(&id)
+            .starts_with(dir.path().join("sha256").join("2c")));
+        assert_eq!(store.get(&id).await.unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(store.size(&id).await.unwrap(), Some(5));
+        assert_eq!(store.ls().await.unwrap(), vec![id]);
+
+        let missing = BlobId::for_data(b"missing");
+        assert_eq!(store.get(&missing).await.unwrap(), None);
+        assert!(!store.has(&missing).await);
+    }
+
+    #[async_std::test]
+    async fn fetch_wanted_blob_from_peer() {
+        let _ = tracing_subscriber::fmt::try_init();
+        let dir_a = tempfile::tempdir().unwrap();
+        let dir_b = tempfile::tempdir().unwrap();
+        let blobs_a = Blobs::new(BlobStore::new(dir_a.path()));
+        let blobs_b = Blobs::new(BlobStore::new(dir_b.path()));
+        let key_a = KeyPair::gen().public;
+        let key_b = KeyPair::gen().public;
+
+        let data = vec![7u8; CHUNK_SIZE * 2 + 10];
+        let id = blobs_a.add(&data).await.unwrap();
+
+        let (a_to_b_sender, a_to_b_receiver) = mpsc::channel(10);
+        let (b_to_a_sender, b_to_a_receiver) = mpsc::channel(10);
+        let mut endpoint_a = Endpoint::new(
+            a_to_b_sender,
+            b_to_a_receiver.map(Ok::<_, std::io::Error>),
+            service(&blobs_a, key_b),
+        );
+        let mut endpoint_b = Endpoint::new(
+            b_to_a_sender,
+            a_to_b_receiver.map(Ok::<_, std::io::Error>),
+            service(&blobs_b, key_a),
+        );
+
+        let replicate = futures::future::try_join(
+            blobs_a.replicate(endpoint_a.client(), key_b),
+            blobs_b.replicate(endpoint_b.client(), key_a),
+        );
+        let want = async_std::future::timeout(std::time::Duration::from_secs(5), blobs_b.want(&id));
+        futures::pin_mut!(want, replicate);
+        match futures::future::select(want, replicate).await {
+            future::Either::Left((result, _)) => result.unwrap().unwrap(),
+            future::Either::Right((result, _)) => panic!("replicate finished: {:?}", result),
+        }
+
+        assert_eq!(blobs_b.store().get(&id).await.unwrap(), Some(data));
+    }
+
+    #[async_std::test]
+    async fn client_add_get() {
+        let _ = tracing_subscriber::fmt::try_init();
+        let dir = tempfile::tempdir().unwrap();
+        let blobs = Blobs::with_max_size(BlobStore::new(dir.path()), CHUNK_SIZE as u64 * 3);
+
+        let (client_sender, server_receiver) = mpsc::channel(10);
+        let (server_sender, client_receiver) = mpsc::channel(10);
+        let _server = Endpoint::new(
+            server_sender,
+            server_receiver.map(Ok::<_, std::io::Error>),
+            service(&blobs, KeyPair::gen().public),
+        );
+        let mut client = Endpoint::new(
+            client_sender,
+            client_receiver.map(Ok::<_, std::io::Error>),
+            Service::new(),
+        );
+
+        let data = vec![7u8; CHUNK_SIZE * 2 + 10];
+        let chunks = data
+            .chunks(1000)
+            .map(|chunk| Ok::<_, std::io::Error>(chunk.to_vec()))
+            .collect::<Vec<_>>();
+        let id = client::add(client.client(), futures::stream::iter(chunks))
+            .await
+            .unwrap();
+        assert_eq!(id, BlobId::for_data(&data));
+        assert_eq!(blobs.store().get(&id).await.unwrap(), Some(data.clone()));
+
+        let received = client::get(client.client(), &id, None).await.unwrap();
+        assert_eq!(received, data);
+
+        let error = client::get(client.client(), &id, Some(100))
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("larger than 100 bytes"),
+            "{}",
+            error
+        );
+
+        let too_large = vec![Ok::<_, std::io::Error>(vec![0u8; CHUNK_SIZE * 4])];
+        let error = client::add(client.client(), futures::stream::iter(too_large))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("Blob is larger"), "{}", error);
+    }
+
+    fn service(blobs: &Blobs, peer: PublicKey) -> Service {
+        let mut plugins = crate::plugin::Plugins::new();
+        plugins.add(blobs.clone()).unwrap();
+        plugins.service(&Context { peer: Some(peer) })
+    }
+}
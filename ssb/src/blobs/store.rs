@@ -0,0 +1,100 @@
+use async_std::fs;
+use async_std::path::{Path, PathBuf};
+use futures::prelude::*;
+
+use super::BlobId;
+
+/// Content-addressed blob storage on disk.
+///
+/// Uses the same layout as [ssb-blobs]: a blob with hash `abcdef…` is stored at
+/// `<dir>/sha256/ab/cdef…`.
+///
+/// [ssb-blobs]: https://github.com/ssbc/ssb-blobs
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    dir: PathBuf,
+}
+
+impl BlobStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Open the store in `~/.ssb/blobs`.
+    pub fn default_location() -> Option<Self> {
+        let home_dir = dirs::home_dir()?;
+        Some(Self::new(home_dir.join(".ssb").join("blobs")))
+    }
+
+    /// Path of the file that contains the blob with `id`.
+    pub fn path(&self, id: &BlobId) -> PathBuf {
+        let hex = id.to_hex();
+        let (prefix, rest) = hex.split_at(2);
+        self.dir.join("sha256").join(prefix).join(rest)
+    }
+
+    /// Store `data` and return its ID. Adding a blob that is already stored is a no-op.
+    pub async fn add(&self, data: &[u8]) -> std::io::Result<BlobId> {
+        let id = BlobId::for_data(data);
+        let path = self.path(&id);
+        if path.exists().await {
+            return Ok(id);
+        }
+        let parent = path.parent().expect("Blob path has a parent");
+        fs::create_dir_all(parent).await?;
+        // Write to a temporary file first so that readers never see partial blobs.
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, data).await?;
+        fs::rename(&tmp_path, &path).await?;
+        Ok(id)
+    }
+
+    /// Read the blob with `id`. Returns `None` if the blob is not stored.
+    pub async fn get(&self, id: &BlobId) -> std::io::Result<Option<Vec<u8>>> {
+        not_found_to_none(fs::read(self.path(id)).await)
+    }
+
+    /// Size of the blob with `id` in bytes. Returns `None` if the blob is not stored.
+    pub async fn size(&self, id: &BlobId) -> std::io::Result<Option<u64>> {
+        let metadata = not_found_to_none(fs::metadata(self.path(id)).await)?;
+        Ok(metadata.map(|metadata| metadata.len()))
+    }
+
+    pub async fn has(&self, id: &BlobId) -> bool {
+        self.path(id).exists().await
+    }
+
+    /// List the IDs of all stored blobs.
+    pub async fn ls(&self) -> std::io::Result<Vec<BlobId>> {
+        let sha256_dir = self.dir.join("sha256");
+        let mut ids = Vec::new();
+        let mut prefix_dirs = match fs::read_dir(&sha256_dir).await {
+            Ok(dirs) => dirs,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(ids),
+            Err(error) => return Err(error),
+        };
+        while let Some(prefix_dir) = prefix_dirs.try_next().await? {
+            let prefix = prefix_dir.file_name().to_string_lossy().into_owned();
+            let mut entries = fs::read_dir(prefix_dir.path()).await?;
+            while let Some(entry) = entries.try_next().await? {
+                let rest = entry.file_name().to_string_lossy().into_owned();
+                if let Some(id) = BlobId::from_hex(&format!("{}{}", prefix, rest)) {
+                    ids.push(id);
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+fn not_found_to_none<T>(result: std::io::Result<T>) -> std::io::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
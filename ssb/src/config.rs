@@ -0,0 +1,364 @@
+//! [load] the JSON configuration of [ssb-server] from `~/.ssb/config`.
+//!
+//! Missing settings take the defaults of ssb-server. Like ssb-server, settings can be overridden
+//! with environment variables that start with `ssb_`. Nested settings are separated with `__`.
+//! Values are parsed as JSON and used as strings if that fails.
+//!
+//! ```rust
+//! # use ssb::config::Config;
+//! let config = Config::from_json(
+//!     r#"{ "port": 8009, "friends": { "hops": 3 }, "blobs": { "max": 1000 } }"#,
+//!     vec![("ssb_friends__hops".to_string(), "1".to_string())],
+//! )
+//! .unwrap();
+//! assert_eq!(config.listen_addr(), "0.0.0.0:8009");
+//! assert_eq!(config.friends.hops, 1);
+//! assert_eq!(config.plugin::<serde_json::Value>("blobs").unwrap().unwrap()["max"], 1000);
+//! ```
+//!
+//! [ssb-server]: https://github.com/ssbc/ssb-server
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::NetworkKey;
+
+/// Prefix of environment variables that override settings.
+pub const ENV_PREFIX: &str = "ssb_";
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("Cannot read file {path}")]
+    ReadIo {
+        path: PathBuf,
+        #[source]
+        error: std::io::Error,
+    },
+
+    #[error("Failed to decode config")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Invalid network key in caps.shs")]
+    NetworkKey(#[source] ssb_box_stream::KeyError),
+
+    #[error("Invalid options for plugin {name}")]
+    Plugin {
+        name: String,
+        #[source]
+        error: serde_json::Error,
+    },
+
+    #[error("Cannot determine home directory")]
+    NoHomeDir,
+}
+
+/// Settings of an SSB server.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Port to accept connections on
+    pub port: u16,
+    /// Host to accept connections on and to announce to peers
+    pub host: Option<String>,
+    pub caps: Caps,
+    pub connections: Connections,
+    pub friends: Friends,
+    pub gossip: Gossip,
+    /// All other settings by name. Plugins read their options with [Config::plugin].
+    #[serde(flatten)]
+    pub plugins: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: 8008,
+            host: None,
+            caps: Caps::default(),
+            connections: Connections::default(),
+            friends: Friends::default(),
+            gossip: Gossip::default(),
+            plugins: serde_json::Map::new(),
+        }
+    }
+}
+
+/// Capabilities that separate networks.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(default)]
+pub struct Caps {
+    /// Base64 encoded network key for the secret handshake. The main network if `None`.
+    pub shs: Option<String>,
+    /// Base64 encoded HMAC key for message signatures
+    pub sign: Option<String>,
+}
+
+/// Transports the server accepts connections on and dials, by transport name, for example
+/// `net` or `unix`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(default)]
+pub struct Connections {
+    pub incoming: BTreeMap<String, Vec<Transport>>,
+    pub outgoing: BTreeMap<String, Vec<Transport>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(default)]
+pub struct Transport {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    /// Scopes like `public`, `local` or `device` the transport is announced in
+    #[serde(deserialize_with = "one_or_many")]
+    pub scope: Vec<String>,
+    /// Transform applied to connections, usually `shs`
+    pub transform: Option<String>,
+}
+
+/// Replication range
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default)]
+pub struct Friends {
+    /// Feeds up to this many hops away are replicated
+    pub hops: u32,
+    /// Largest number of feeds that are followed
+    pub dunbar: u32,
+}
+
+impl Default for Friends {
+    fn default() -> Self {
+        Self {
+            hops: 2,
+            dunbar: 150,
+        }
+    }
+}
+
+/// Connection limits
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default)]
+pub struct Gossip {
+    /// Largest number of outgoing connections that are open at the same time
+    pub connections: usize,
+}
+
+impl Default for Gossip {
+    fn default() -> Self {
+        Self { connections: 3 }
+    }
+}
+
+/// Accepts a single string or a list of strings.
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match serde::Deserialize::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+impl Config {
+    /// Parse the config file content `data` and apply the overrides in `vars`. Variables without
+    /// the [ENV_PREFIX] are ignored.
+    pub fn from_json(
+        data: &str,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let mut value = if data.trim().is_empty() {
+            serde_json::Value::Object(serde_json::Map::new())
+        } else {
+            serde_json::from_str(data)?
+        };
+        for (name, var_value) in vars {
+            if let Some(path) = name.strip_prefix(ENV_PREFIX) {
+                let var_value = serde_json::from_str(&var_value)
+                    .unwrap_or(serde_json::Value::String(var_value));
+                set_path(&mut value, path.split("__"), var_value);
+            }
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Key for the secret handshake from [Caps::shs].
+    pub fn network_key(&self) -> Result<NetworkKey, ConfigError> {
+        match &self.caps.shs {
+            Some(shs) => NetworkKey::from_base64(shs).map_err(ConfigError::NetworkKey),
+            None => Ok(crate::SCUTTLEBUTT_NETWORK_KEY),
+        }
+    }
+
+    /// Address to accept TCP connections on.
+    ///
+    /// Uses the first `net` transport in `connections.incoming` and falls back to [Config::host]
+    /// and [Config::port]. Listens on all interfaces if no host is configured.
+    pub fn listen_addr(&self) -> String {
+        let net = self
+            .connections
+            .incoming
+            .get("net")
+            .and_then(|transports| transports.first());
+        let host = net
+            .and_then(|net| net.host.as_deref())
+            .or(self.host.as_deref())
+            .unwrap_or("0.0.0.0");
+        let port = net.and_then(|net| net.port).unwrap_or(self.port);
+        format!("{}:{}", host, port)
+    }
+
+    /// Options for the plugin `name`. Returns `None` if the config has no options for the plugin.
+    pub fn plugin<T: serde::de::DeserializeOwned>(
+        &self,
+        name: &str,
+    ) -> Result<Option<T>, ConfigError> {
+        self.plugins
+            .get(name)
+            .map(|options| {
+                serde_json::from_value(options.clone()).map_err(|error| ConfigError::Plugin {
+                    name: name.to_string(),
+                    error,
+                })
+            })
+            .transpose()
+    }
+}
+
+/// Set the value at `path` in `target`, replacing values that are not objects on the way.
+fn set_path<'a>(
+    target: &mut serde_json::Value,
+    mut path: impl Iterator<Item = &'a str>,
+    value: serde_json::Value,
+) {
+    let key = match path.next() {
+        Some(key) if !key.is_empty() => key,
+        _ => {
+            *target = value;
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = serde_json::Value::Object(serde_json::Map::new());
+    }
+    if let serde_json::Value::Object(map) = target {
+        let child = map
+            .entry(key.to_string())
+            .or_insert(serde_json::Value::Null);
+        set_path(child, path, value);
+    }
+}
+
+/// Load the config from `path` and apply the overrides from the environment. Uses the defaults
+/// if the file does not exist.
+pub fn load(path: &Path) -> Result<Config, ConfigError> {
+    let data = match std::fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(error) => {
+            return Err(ConfigError::ReadIo {
+                path: path.to_owned(),
+                error,
+            })
+        }
+    };
+    Config::from_json(&data, std::env::vars())
+}
+
+/// [load] the default config file `~/.ssb/config`.
+pub fn load_default() -> Result<Config, ConfigError> {
+    let home_dir = dirs::home_dir().ok_or(ConfigError::NoHomeDir)?;
+    load(&home_dir.join(".ssb").join("config"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn defaults() {
+        let config = Config::from_json("", vec![]).unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.listen_addr(), "0.0.0.0:8008");
+        assert_eq!(
+            config.network_key().unwrap(),
+            crate::SCUTTLEBUTT_NETWORK_KEY
+        );
+    }
+
+    #[test]
+    fn ssb_server_config() {
+        let data = r#"{
+            "caps": { "shs": "1KHLiKZvAvjbY1ziZEHMXawbCEIM6qwjCDm3VYRan/s=" },
+            "connections": {
+                "incoming": {
+                    "net": [{ "scope": "public", "host": "pub.example.com", "port": 8010,
+                              "transform": "shs" }],
+                    "unix": [{ "scope": ["device", "local"], "transform": "noauth" }]
+                },
+                "outgoing": { "net": [{ "transform": "shs" }] }
+            },
+            "gossip": { "connections": 5 },
+            "logging": { "level": "info" }
+        }"#;
+        let config = Config::from_json(data, vec![]).unwrap();
+        assert_eq!(config.listen_addr(), "pub.example.com:8010");
+        assert_eq!(
+            config.network_key().unwrap(),
+            crate::SCUTTLEBUTT_NETWORK_KEY
+        );
+        assert_eq!(
+            config.connections.incoming["unix"][0].scope,
+            vec!["device", "local"]
+        );
+        assert_eq!(config.gossip.connections, 5);
+        assert_eq!(config.friends, Friends::default());
+
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Logging {
+            level: String,
+        }
+        assert_eq!(
+            config.plugin::<Logging>("logging").unwrap(),
+            Some(Logging {
+                level: "info".to_string()
+            })
+        );
+        assert!(config.plugin::<u32>("logging").is_err());
+        assert_eq!(config.plugin::<Logging>("missing").unwrap(), None);
+    }
+
+    #[test]
+    fn env_overrides() {
+        let data = r#"{ "port": 8009, "caps": { "shs": "invalid" } }"#;
+        let config = Config::from_json(
+            data,
+            vars(&[
+                ("ssb_port", "8010"),
+                ("ssb_host", "localhost"),
+                (
+                    "ssb_caps__shs",
+                    "1KHLiKZvAvjbY1ziZEHMXawbCEIM6qwjCDm3VYRan/s=",
+                ),
+                ("ssb_friends__hops", "3"),
+                ("ssb_gossip", "{\"connections\": 1}"),
+                ("HOME", "/root"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.listen_addr(), "localhost:8010");
+        assert!(config.network_key().is_ok());
+        assert_eq!(config.friends.hops, 3);
+        assert_eq!(config.gossip.connections, 1);
+        assert!(!config.plugins.contains_key("HOME"));
+
+        let error = Config::from_json(data, vars(&[("ssb_port", "not a port")]));
+        assert!(matches!(error, Err(ConfigError::Json(_))));
+    }
+}
@@ -0,0 +1,320 @@
+//! Detect several connections to the same identity.
+//!
+//! Two peers that dial each other at the same time end up with two connections. [PeerConnections]
+//! tracks the open connections by the public key of the peer and decides with a
+//! [DuplicatePolicy] whether a new connection is accepted and which connection is closed.
+//!
+//! If the connections were dialed by different peers, both peers keep the connection that was
+//! dialed by the peer with the smaller public key, regardless of the order in which they saw the
+//! connections. Otherwise each peer could close the connection the other peer keeps and both
+//! would reconnect again and again. The policy only decides between connections that were
+//! dialed by the same peer, for example when a peer reconnects while its old connection has not
+//! timed out yet.
+//!
+//! ```rust
+//! # use ssb::conn::{DuplicatePolicy, Direction, PeerConnections};
+//! # use ssb::crypto::sign::KeyPair;
+//! # let local = KeyPair::gen().public;
+//! # let peer = KeyPair::gen().public;
+//! let connections = PeerConnections::new(local, DuplicatePolicy::RejectNew);
+//! let first = connections.open(peer, Direction::Inbound).unwrap();
+//! assert!(connections.open(peer, Direction::Inbound).is_err());
+//! drop(first);
+//! assert!(connections.open(peer, Direction::Inbound).is_ok());
+//! ```
+use futures::channel::oneshot;
+use futures::prelude::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::crypto::sign::PublicKey;
+use crate::feed::FeedId;
+
+/// What to do when a connection to a peer is opened while other connections to the same peer
+/// are open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Keep the open connection and reject the new one.
+    RejectNew,
+    /// Close the open connection in favor of the new one.
+    ReplaceOld,
+    /// Allow up to this many connections and reject further ones.
+    Allow(usize),
+}
+
+/// Who dialed a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Direction {
+    /// The peer dialed us.
+    Inbound,
+    /// We dialed the peer.
+    Outbound,
+}
+
+impl Direction {
+    /// `"inbound"` or `"outbound"`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Direction::Inbound => "inbound",
+            Direction::Outbound => "outbound",
+        }
+    }
+}
+
+/// Returned by [PeerConnections::open] if the connection should be closed right away.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Already connected to {peer}")]
+pub struct DuplicateConnectionError {
+    pub peer: FeedId,
+}
+
+/// Open connections by peer. Clones share the connections.
+#[derive(Debug, Clone)]
+pub struct PeerConnections {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    local: PublicKey,
+    policy: DuplicatePolicy,
+    /// Open connections of every peer, oldest first
+    peers: HashMap<PublicKey, Vec<OpenConnection>>,
+    next_id: u64,
+}
+
+#[derive(Debug)]
+struct OpenConnection {
+    id: u64,
+    direction: Direction,
+    /// Notifies the [Connection] when it is replaced
+    replaced: oneshot::Sender<()>,
+}
+
+impl PeerConnections {
+    /// Track connections of the peer with the public key `local`.
+    pub fn new(local: PublicKey, policy: DuplicatePolicy) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                local,
+                policy,
+                peers: HashMap::new(),
+                next_id: 0,
+            })),
+        }
+    }
+
+    pub fn policy(&self) -> DuplicatePolicy {
+        self.inner.lock().unwrap().policy
+    }
+
+    /// Number of open connections to `peer`.
+    pub fn count(&self, peer: &PublicKey) -> usize {
+        self.inner
+            .lock()
+            .unwrap()
+            .peers
+            .get(peer)
+            .map_or(0, Vec::len)
+    }
+
+    pub fn is_connected(&self, peer: &PublicKey) -> bool {
+        self.count(peer) > 0
+    }
+
+    /// Register a new connection to `peer` after the handshake.
+    ///
+    /// Fails if the connection should be closed. Otherwise the connection counts as open until
+    /// the returned [Connection] is dropped. If the new connection replaces an open one,
+    /// [Connection::replaced] of the open connection resolves and the application closes it.
+    pub fn open(
+        &self,
+        peer: PublicKey,
+        direction: Direction,
+    ) -> Result<Connection, DuplicateConnectionError> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let limit = match inner.policy {
+            DuplicatePolicy::RejectNew | DuplicatePolicy::ReplaceOld => 1,
+            DuplicatePolicy::Allow(limit) => limit,
+        };
+        // Direction of the connection that both peers keep: the one dialed by the smaller key.
+        let preferred = if inner.local < peer {
+            Direction::Outbound
+        } else {
+            Direction::Inbound
+        };
+        let open = inner.peers.entry(peer).or_default();
+        if open.len() >= limit.max(1) {
+            let other_direction = open
+                .iter()
+                .position(|connection| connection.direction != direction);
+            let replaced = match other_direction {
+                Some(index) if direction == preferred => Some(index),
+                Some(_) => None,
+                None if inner.policy == DuplicatePolicy::ReplaceOld => Some(0),
+                None => None,
+            };
+            match replaced {
+                Some(index) => {
+                    let replaced = open.remove(index);
+                    let _ = replaced.replaced.send(());
+                    tracing::debug!(peer = %FeedId(peer), ?direction, "replacing connection");
+                }
+                None => {
+                    tracing::debug!(peer = %FeedId(peer), ?direction, "rejecting connection");
+                    return Err(DuplicateConnectionError { peer: FeedId(peer) });
+                }
+            }
+        }
+        let id = inner.next_id;
+        inner.next_id += 1;
+        let (replaced_sender, replaced) = oneshot::channel();
+        open.push(OpenConnection {
+            id,
+            direction,
+            replaced: replaced_sender,
+        });
+        Ok(Connection {
+            connections: self.clone(),
+            peer,
+            id,
+            replaced,
+        })
+    }
+
+    /// Close all connections to `peer` and return their number.
+    ///
+    /// [Connection::replaced] of every connection resolves and the application closes it. The
+    /// connections do not count as open anymore.
+    pub fn disconnect(&self, peer: &PublicKey) -> usize {
+        let open = self
+            .inner
+            .lock()
+            .unwrap()
+            .peers
+            .remove(peer)
+            .unwrap_or_default();
+        let count = open.len();
+        for connection in open {
+            let _ = connection.replaced.send(());
+        }
+        if count > 0 {
+            tracing::debug!(peer = %FeedId(*peer), count, "disconnecting peer");
+        }
+        count
+    }
+
+    fn close(&self, peer: &PublicKey, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(open) = inner.peers.get_mut(peer) {
+            open.retain(|connection| connection.id != id);
+            if open.is_empty() {
+                inner.peers.remove(peer);
+            }
+        }
+    }
+}
+
+/// Connection registered with [PeerConnections::open]. The connection counts as open until this
+/// is dropped.
+#[derive(Debug)]
+pub struct Connection {
+    connections: PeerConnections,
+    peer: PublicKey,
+    id: u64,
+    replaced: oneshot::Receiver<()>,
+}
+
+impl Connection {
+    pub fn peer(&self) -> &PublicKey {
+        &self.peer
+    }
+
+    /// Resolves when a new connection to the peer replaces this one or when the peer is
+    /// [disconnected][PeerConnections::disconnect]. The connection should be closed then. It does
+    /// not count as open anymore.
+    pub fn replaced(&mut self) -> impl Future<Output = ()> + '_ {
+        (&mut self.replaced).map(|_| ())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.connections.close(&self.peer, self.id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::sign::KeyPair;
+
+    /// Two public keys, the smaller one first
+    fn keys() -> (PublicKey, PublicKey) {
+        let a = KeyPair::gen().public;
+        let b = KeyPair::gen().public;
+        if a < b {
+            (a, b)
+        } else {
+            (b, a)
+        }
+    }
+
+    #[test]
+    fn policies() {
+        let (local, peer) = keys();
+
+        let connections = PeerConnections::new(local, DuplicatePolicy::ReplaceOld);
+        let mut first = connections.open(peer, Direction::Inbound).unwrap();
+        let _second = connections.open(peer, Direction::Inbound).unwrap();
+        assert_eq!(first.replaced().now_or_never(), Some(()));
+        assert_eq!(connections.count(&peer), 1);
+        drop(first);
+        assert_eq!(connections.count(&peer), 1);
+
+        let connections = PeerConnections::new(local, DuplicatePolicy::Allow(2));
+        let _first = connections.open(peer, Direction::Inbound).unwrap();
+        let second = connections.open(peer, Direction::Inbound).unwrap();
+        assert_eq!(
+            connections.open(peer, Direction::Inbound).unwrap_err(),
+            DuplicateConnectionError { peer: FeedId(peer) }
+        );
+        drop(second);
+        assert!(connections.open(peer, Direction::Inbound).is_ok());
+    }
+
+    #[test]
+    fn disconnect() {
+        let (local, peer) = keys();
+        let connections = PeerConnections::new(local, DuplicatePolicy::Allow(2));
+        let mut first = connections.open(peer, Direction::Inbound).unwrap();
+        let mut second = connections.open(peer, Direction::Outbound).unwrap();
+        assert_eq!(connections.disconnect(&peer), 2);
+        assert_eq!(first.replaced().now_or_never(), Some(()));
+        assert_eq!(second.replaced().now_or_never(), Some(()));
+        assert!(!connections.is_connected(&peer));
+        assert_eq!(connections.disconnect(&peer), 0);
+    }
+
+    /// Both peers dial each other at the same time and see the connections in a different
+    /// order. They must keep the same connection.
+    #[test]
+    fn simultaneous_dial() {
+        let (small, large) = keys();
+        for policy in &[DuplicatePolicy::RejectNew, DuplicatePolicy::ReplaceOld] {
+            // The connection dialed by `small` is outbound for `small` and inbound for `large`.
+            let small_connections = PeerConnections::new(small, *policy);
+            let mut small_dialed = small_connections.open(large, Direction::Outbound).unwrap();
+            assert!(small_connections.open(large, Direction::Inbound).is_err());
+            assert_eq!(small_dialed.replaced().now_or_never(), None);
+
+            let large_connections = PeerConnections::new(large, *policy);
+            let mut large_dialed = large_connections.open(small, Direction::Outbound).unwrap();
+            let mut small_dialed = large_connections.open(small, Direction::Inbound).unwrap();
+            assert_eq!(large_dialed.replaced().now_or_never(), Some(()));
+            assert_eq!(small_dialed.replaced().now_or_never(), None);
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Events of incoming and outgoing connections for a network status UI.
+//!
+//! [ConnEvents] broadcasts [ConnEvent]s to every subscriber. The [ConnectionManager] reports the
+//! connections it dials when it is created with [ConnectionManager::with_events] and
+//! [listen_tcp] reports the connections it accepts.
+//!
+//! ```rust
+//! # use ssb::conn::{ConnEvent, ConnEvents};
+//! # use futures::prelude::*;
+//! # async_std::task::block_on(async {
+//! let events = ConnEvents::new();
+//! let mut subscription = events.subscribe();
+//! events.emit(ConnEvent::Connecting {
+//!     addr: "127.0.0.1:8008".to_string(),
+//! });
+//! assert!(matches!(subscription.next().await, Some(ConnEvent::Connecting { .. })));
+//! # });
+//! ```
+//!
+//! [ConnectionManager]: super::ConnectionManager
+//! [ConnectionManager::with_events]: super::ConnectionManager::with_events
+use futures::channel::mpsc;
+use futures::prelude::*;
+use std::sync::{Arc, Mutex};
+
+use crate::feed::FeedId;
+
+/// Change of the state of a connection.
+///
+/// With the `serde` feature events are serialized as objects with a `type` field, for example
+/// `{"type":"connecting","addr":"127.0.0.1:8008"}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "camelCase"))]
+pub enum ConnEvent {
+    /// A connection to `addr` is being dialed or was accepted from `addr`.
+    Connecting { addr: String },
+    /// The secret handshake with `addr` failed.
+    HandshakeFailed { addr: String, reason: String },
+    /// The handshake with `peer` at `addr` succeeded.
+    Connected { peer: FeedId, addr: String },
+    /// The connection to `peer` was closed after transferring `bytes`.
+    Disconnected {
+        peer: FeedId,
+        cause: DisconnectCause,
+        bytes: Traffic,
+    },
+}
+
+/// Why a connection was closed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub enum DisconnectCause {
+    /// One of the peers closed the connection.
+    Closed,
+    /// The connection failed with this error.
+    Error(String),
+}
+
+/// Number of encrypted bytes transferred over a connection, including packet headers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Traffic {
+    pub sent: u64,
+    pub received: u64,
+}
+
+/// Broadcasts [ConnEvent]s. Clones share the subscribers.
+#[derive(Debug, Clone, Default)]
+pub struct ConnEvents {
+    subscribers: Arc<Mutex<Vec<mpsc::UnboundedSender<ConnEvent>>>>,
+}
+
+impl ConnEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Receive all events emitted from now on.
+    pub fn subscribe(&self) -> mpsc::UnboundedReceiver<ConnEvent> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Send `event` to all subscribers. Subscribers that were dropped are removed.
+    pub fn emit(&self, event: ConnEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+/// Like [ssb_box_stream::listen_tcp] but reports every connection to `events`.
+///
+/// The connection is reported as disconnected when the future returned by `handler` resolves.
+/// An error returned by the handler is the [cause][DisconnectCause::Error]. Returns only if
+/// binding to `addr` or accepting a connection fails.
+pub async fn listen_tcp<Handler, HandlerFuture>(
+    addr: impl async_std::net::ToSocketAddrs,
+    network_key: &ssb_box_stream::NetworkKey,
+    identity: &ssb_box_stream::SecretKey,
+    events: ConnEvents,
+    handler: Handler,
+) -> std::io::Result<()>
+where
+    Handler: Fn(ssb_box_stream::TcpSender, ssb_box_stream::TcpReceiver, FeedId) -> HandlerFuture
+        + Send
+        + Sync
+        + 'static,
+    HandlerFuture: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let listener = async_std::net::TcpListener::bind(addr).await?;
+    let server = ssb_box_stream::Server::new(network_key, &identity.public_key(), identity);
+    let handler = Arc::new(handler);
+    loop {
+        let (stream, remote_addr) = listener.accept().await?;
+        let addr = remote_addr.to_string();
+        let server = server.clone();
+        let handler = Arc::clone(&handler);
+        let events = events.clone();
+        async_std::task::spawn(async move {
+            events.emit(ConnEvent::Connecting { addr: addr.clone() });
+            let (sender, receiver, peer) = match server.accept(stream).await {
+                Ok(connection) => connection,
+                Err(error) => {
+                    events.emit(ConnEvent::HandshakeFailed {
+                        addr,
+                        reason: error.to_string(),
+                    });
+                    return;
+                }
+            };
+            let peer = FeedId::from(peer);
+            events.emit(ConnEvent::Connected { peer, addr });
+            let sent = sender.bytes_written().clone();
+            let received = receiver.bytes_read().clone();
+            let cause = match handler(sender, receiver, peer).await {
+                Ok(()) => DisconnectCause::Closed,
+                Err(error) => DisconnectCause::Error(format!("{:#}", error)),
+            };
+            events.emit(ConnEvent::Disconnected {
+                peer,
+                cause,
+                bytes: Traffic {
+                    sent: sent.get(),
+                    received: received.get(),
+                },
+            });
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn listen() {
+        const ADDR: &str = "127.0.0.1:15739";
+        let network_key = ssb_box_stream::NetworkKey::MAIN_NET;
+        let server_identity = ssb_box_stream::SecretKey::generate();
+        let server_identity_pk = server_identity.public_key();
+        let events = ConnEvents::new();
+        let mut subscription = events.subscribe();
+        async_std::task::spawn(async move {
+            listen_tcp(
+                ADDR,
+                &network_key,
+                &server_identity,
+                events,
+                |_sender, receiver, _peer| async move {
+                    receiver.try_concat().await?;
+                    anyhow::bail!("peer left")
+                },
+            )
+            .await
+            .unwrap();
+        });
+
+        let client_identity = ssb_box_stream::SecretKey::generate();
+        let (mut sender, _receiver) = loop {
+            match ssb_box_stream::connect_tcp(
+                ADDR,
+                &network_key,
+                &server_identity_pk,
+                &client_identity,
+            )
+            .await
+            {
+                Ok(connection) => break connection,
+                // The server may not be listening yet.
+                Err(ssb_box_stream::ConnectError::Io(_)) => async_std::task::yield_now().await,
+                Err(error) => panic!("{}", error),
+            }
+        };
+        sender.send(b"hello".to_vec()).await.unwrap();
+        sender.close().await.unwrap();
+
+        let client = FeedId::from(client_identity.public_key());
+        assert!(matches!(
+            subscription.next().await,
+            Some(ConnEvent::Connecting { .. })
+        ));
+        match subscription.next().await {
+            Some(ConnEvent::Connected { peer, .. }) => assert_eq!(peer, client),
+            event => panic!("unexpected event {:?}", event),
+        }
+        match subscription.next().await {
+            Some(ConnEvent::Disconnected { peer, cause, bytes }) => {
+                assert_eq!(peer, client);
+                assert_eq!(cause, DisconnectCause::Error("peer left".to_string()));
+                assert!(bytes.received > 5);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        // A client that does not know the server key fails the handshake
+        let wrong_server = ssb_box_stream::SecretKey::generate().public_key();
+        let result =
+            ssb_box_stream::connect_tcp(ADDR, &network_key, &wrong_server, &client_identity).await;
+        assert!(result.is_err());
+        assert!(matches!(
+            subscription.next().await,
+            Some(ConnEvent::Connecting { .. })
+        ));
+        assert!(matches!(
+            subscription.next().await,
+            Some(ConnEvent::HandshakeFailed { .. })
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        let event = ConnEvent::Connecting {
+            addr: "127.0.0.1:8008".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({ "type": "connecting", "addr": "127.0.0.1:8008" })
+        );
+
+        let event = ConnEvent::Disconnected {
+            peer: FeedId::from(ssb_box_stream::SecretKey::generate().public_key()),
+            cause: DisconnectCause::Error("peer left".to_string()),
+            bytes: Traffic {
+                sent: 1,
+                received: 2,
+            },
+        };
+        let value = serde_json::to_value(&event).unwrap();
+        assert_eq!(value["cause"], serde_json::json!({ "error": "peer left" }));
+        assert_eq!(serde_json::from_value::<ConnEvent>(value).unwrap(), event);
+    }
+}
@@ -0,0 +1,1079 @@
+//! Decide which peers to connect to.
+//!
+//! The [ConnectionManager] asks a list of [Strategy]s for addresses to dial. Every strategy has
+//! its own [Policy] that determines how often it is asked and how long to wait before dialing an
+//! address again after it failed. Applications compose the strategies they need:
+//!
+//! * [LanStrategy] dials peers found by [discovery][crate::discovery] on the local network.
+//! * [PubStrategy] dials pubs from the [AddressBook].
+//! * [RoomStrategy] dials rooms. Through a room the application reaches peers it has no direct
+//!   address for. By default it is a fallback that only runs while no other connection is open.
+//! * [ManualStrategy] dials a fixed list of addresses.
+//!
+//! The connection manager does not open connections itself. The application calls
+//! [ConnectionManager::poll], dials the returned addresses with [ConnectionManager::dialer] and
+//! reports the outcome. Addresses the dialer does not support, for example onion addresses
+//! without a SOCKS5 proxy, are skipped. Failures of
+//! addresses in the [AddressBook] are recorded there so that the backoff survives restarts when
+//! the application saves the address book. [ConnectionManager::subscribe] reports every dial and
+//! its outcome.
+//!
+//! With [ConnectionManager::record_rtt] the application reports the round trip times measured by
+//! the RTT probes of its endpoints, see
+//! [EndpointConfig::rtt_probe_interval][crate::rpc::base::EndpointConfig::rtt_probe_interval].
+//! [ConnectionManager::connected_by_latency] then lists the fastest peers first for interactive
+//! requests.
+//!
+//! [ConnEvents] broadcasts structured events of dialed and accepted connections, for example to
+//! render a network status UI. See [ConnectionManager::with_events] and [listen_tcp].
+//!
+//! [PeerConnections] detects several connections to the same peer, see [DuplicatePolicy]. The
+//! connection manager skips addresses of peers that are connected already, through another
+//! address or, with [ConnectionManager::with_connections], because the peer dialed us.
+//!
+//! ```rust
+//! # use ssb::conn::{ConnectionManager, LanStrategy, ManualStrategy, RoomStrategy};
+//! # use std::time::SystemTime;
+//! let pub_address = "net:10.0.0.1:8008~shs:AA==".parse().unwrap();
+//! let mut manager = ConnectionManager::new(3)
+//!     .with(LanStrategy::new())
+//!     .with(ManualStrategy::new(vec![pub_address]))
+//!     .with(RoomStrategy::new(vec![]));
+//! for dial in manager.poll(SystemTime::now()) {
+//!     // Connect to `dial.address`, then report the outcome
+//!     manager.record_failure(&dial.address, SystemTime::now());
+//! }
+//! ```
+use futures::channel::mpsc;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use crate::addressbook::{AddressBook, AddressSource};
+use crate::crypto::sign::PublicKey;
+use crate::feed::FeedId;
+use crate::multi_address::{Address, Dialer};
+
+mod duplicate;
+pub use duplicate::{
+    Connection, Direction, DuplicateConnectionError, DuplicatePolicy, PeerConnections,
+};
+mod events;
+pub use events::{listen_tcp, ConnEvent, ConnEvents, DisconnectCause, Traffic};
+
+/// Scheduling and backoff of a [Strategy].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    /// Time between two calls of [Strategy::candidates]
+    pub interval: Duration,
+    /// Time to wait before dialing an address again after the first failure. The delay doubles
+    /// with every consecutive failure.
+    pub min_retry_delay: Duration,
+    /// Upper limit for the time to wait after a failure
+    pub max_retry_delay: Duration,
+    /// Fraction of the retry delay that is randomly taken off so that peers that failed together
+    /// are not dialed again at the same time. Between `0.0` and `1.0`.
+    pub jitter: f64,
+    /// Largest number of connections of the strategy that may be open or being dialed at the
+    /// same time. Unlimited if `None`.
+    pub max_active: Option<usize>,
+    /// Only run the strategy while there are no other connections.
+    pub fallback: bool,
+}
+
+impl Policy {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            min_retry_delay: interval,
+            max_retry_delay: interval * 60,
+            jitter: 0.0,
+            max_active: None,
+            fallback: false,
+        }
+    }
+
+    pub fn retry_delay(mut self, min: Duration, max: Duration) -> Self {
+        self.min_retry_delay = min;
+        self.max_retry_delay = max;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn max_active(mut self, max_active: usize) -> Self {
+        self.max_active = Some(max_active);
+        self
+    }
+
+    pub fn fallback(mut self, fallback: bool) -> Self {
+        self.fallback = fallback;
+        self
+    }
+
+    /// Time to wait before dialing an address that failed `failures` times in a row, without
+    /// jitter.
+    pub fn backoff(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return Duration::ZERO;
+        }
+        let factor = 2u32.saturating_pow(failures - 1);
+        self.min_retry_delay
+            .checked_mul(factor)
+            .map_or(self.max_retry_delay, |delay| {
+                delay.min(self.max_retry_delay)
+            })
+    }
+
+    /// [Policy::backoff] with [Policy::jitter] applied. `random` is uniformly distributed
+    /// between `0.0` and `1.0`.
+    pub fn backoff_with_jitter(&self, failures: u32, random: f64) -> Duration {
+        self.backoff(failures)
+            .mul_f64(1.0 - self.jitter * random.clamp(0.0, 1.0))
+    }
+}
+
+/// Source of addresses for the [ConnectionManager].
+pub trait Strategy: AsAny + Send + std::fmt::Debug {
+    /// Name of the strategy that is reported with [Dial].
+    fn name(&self) -> &'static str;
+
+    fn policy(&self) -> &Policy;
+
+    /// Addresses to dial at `now`, best candidates first.
+    fn candidates(&mut self, now: SystemTime, address_book: &AddressBook) -> Vec<Address>;
+}
+
+/// Allows [ConnectionManager::strategy_mut] to downcast strategies. Implemented for all types.
+pub trait AsAny {
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any;
+}
+
+impl<T: std::any::Any> AsAny for T {
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+/// Dials peers that announce themselves on the local network.
+///
+/// Call [LanStrategy::discovered] for every peer that [PeerDiscovery][crate::discovery::PeerDiscovery]
+/// reports. Peers that have not been announced for [LanStrategy::PEER_TTL] are forgotten.
+#[derive(Debug, Clone)]
+pub struct LanStrategy {
+    peers: HashMap<Address, SystemTime>,
+    policy: Policy,
+}
+
+impl LanStrategy {
+    /// Peers announce themselves every second. A peer that has been silent much longer has left.
+    pub const PEER_TTL: Duration = Duration::from_secs(30);
+
+    pub fn new() -> Self {
+        Self {
+            peers: HashMap::new(),
+            policy: Policy::new(Duration::from_secs(5))
+                .retry_delay(Duration::from_secs(10), Duration::from_secs(120))
+                .jitter(0.2),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Remember the addresses of a peer announced at `now`.
+    pub fn discovered(&mut self, peer: &crate::discovery::DiscoveredPeer, now: SystemTime) {
+        for address in &peer.multi_address.addresses {
+            self.peers.insert(address.clone(), now);
+        }
+    }
+}
+
+impl Default for LanStrategy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Strategy for LanStrategy {
+    fn name(&self) -> &'static str {
+        "lan"
+    }
+
+    fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    fn candidates(&mut self, now: SystemTime, _address_book: &AddressBook) -> Vec<Address> {
+        self.peers.retain(|_, announced| {
+            now.duration_since(*announced)
+                .map_or(true, |age| age <= Self::PEER_TTL)
+        });
+        let mut candidates = self.peers.iter().collect::<Vec<_>>();
+        candidates.sort_by(|(_, a), (_, b)| b.cmp(a));
+        candidates
+            .into_iter()
+            .map(|(address, _)| address.clone())
+            .collect()
+    }
+}
+
+/// Dials pubs announced in `pub` messages and addresses added to the [AddressBook] manually.
+#[derive(Debug, Clone)]
+pub struct PubStrategy {
+    policy: Policy,
+}
+
+impl PubStrategy {
+    pub fn new() -> Self {
+        Self {
+            policy: Policy::new(Duration::from_secs(30))
+                .retry_delay(
+                    crate::addressbook::MIN_RETRY_DELAY,
+                    crate::addressbook::MAX_RETRY_DELAY,
+                )
+                .jitter(0.2)
+                .max_active(3),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+impl Default for PubStrategy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Strategy for PubStrategy {
+    fn name(&self) -> &'static str {
+        "pub"
+    }
+
+    fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    fn candidates(&mut self, _now: SystemTime, address_book: &AddressBook) -> Vec<Address> {
+        address_book
+            .ranked()
+            .into_iter()
+            .filter(|peer| peer.source != AddressSource::Room)
+            .map(|peer| peer.address.clone())
+            .collect()
+    }
+}
+
+/// Dials rooms to reach peers through room tunnels.
+///
+/// Dials the given rooms and the rooms announced in `room` messages. By default this is a
+/// fallback strategy: rooms are only dialed while no other connection is open.
+#[derive(Debug, Clone)]
+pub struct RoomStrategy {
+    rooms: Vec<Address>,
+    policy: Policy,
+}
+
+impl RoomStrategy {
+    pub fn new(rooms: Vec<Address>) -> Self {
+        Self {
+            rooms,
+            policy: Policy::new(Duration::from_secs(60))
+                .retry_delay(Duration::from_secs(60), Duration::from_secs(60 * 60))
+                .jitter(0.2)
+                .max_active(1)
+                .fallback(true),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+impl Strategy for RoomStrategy {
+    fn name(&self) -> &'static str {
+        "room"
+    }
+
+    fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    fn candidates(&mut self, _now: SystemTime, address_book: &AddressBook) -> Vec<Address> {
+        let announced = address_book
+            .ranked()
+            .into_iter()
+            .filter(|peer| peer.source == AddressSource::Room)
+            .map(|peer| &peer.address)
+            .filter(|address| !self.rooms.contains(address))
+            .cloned()
+            .collect::<Vec<_>>();
+        self.rooms.iter().cloned().chain(announced).collect()
+    }
+}
+
+/// Dials a fixed list of addresses, for example from the configuration of the application.
+///
+/// The backoff of the addresses is only persisted if they are added to the [AddressBook] with
+/// [AddressBook::insert].
+#[derive(Debug, Clone)]
+pub struct ManualStrategy {
+    addresses: Vec<Address>,
+    policy: Policy,
+}
+
+impl ManualStrategy {
+    pub fn new(addresses: Vec<Address>) -> Self {
+        Self {
+            addresses,
+            policy: Policy::new(Duration::from_secs(10))
+                .retry_delay(Duration::from_secs(5), Duration::from_secs(5 * 60)),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+impl Strategy for ManualStrategy {
+    fn name(&self) -> &'static str {
+        "manual"
+    }
+
+    fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    fn candidates(&mut self, _now: SystemTime, _address_book: &AddressBook) -> Vec<Address> {
+        self.addresses.clone()
+    }
+}
+
+/// Address returned by [ConnectionManager::poll].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dial {
+    pub address: Address,
+    /// [Strategy::name] of the strategy that proposed the address
+    pub strategy: &'static str,
+}
+
+/// Event reported by [ConnectionManager::subscribe].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DialEvent {
+    pub address: Address,
+    /// [Strategy::name] of the strategy that proposed the address
+    pub strategy: &'static str,
+    pub outcome: DialOutcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DialOutcome {
+    /// Returned by [ConnectionManager::poll]
+    Dialing,
+    Connected,
+    /// Dialing failed `failures` times in a row. The address is not dialed before `retry_at`.
+    Failed {
+        failures: u32,
+        retry_at: SystemTime,
+    },
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PeerState {
+    Idle,
+    Dialing,
+    Connected,
+}
+
+#[derive(Debug)]
+struct Peer {
+    state: PeerState,
+    /// Index of the strategy that proposed the address last
+    strategy: usize,
+    failures: u32,
+    retry_at: Option<SystemTime>,
+    /// Smoothed round trip time of the open connection
+    rtt: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct ScheduledStrategy {
+    strategy: Box<dyn Strategy>,
+    next_run: Option<SystemTime>,
+}
+
+/// Chooses addresses to dial from [Strategy]s. See the [module documentation][self].
+#[derive(Debug)]
+pub struct ConnectionManager {
+    strategies: Vec<ScheduledStrategy>,
+    peers: HashMap<Address, Peer>,
+    max_connections: usize,
+    address_book: AddressBook,
+    dialer: Dialer,
+    handshake_cache: ssb_box_stream::HandshakeCache,
+    connections: Option<PeerConnections>,
+    events: Option<ConnEvents>,
+    subscribers: Vec<mpsc::UnboundedSender<DialEvent>>,
+}
+
+impl ConnectionManager {
+    /// Create a connection manager that keeps at most `max_connections` connections open or
+    /// being dialed.
+    pub fn new(max_connections: usize) -> Self {
+        Self {
+            strategies: Vec::new(),
+            peers: HashMap::new(),
+            max_connections,
+            address_book: AddressBook::new(),
+            dialer: Dialer::new(),
+            handshake_cache: ssb_box_stream::HandshakeCache::new(),
+            connections: None,
+            events: None,
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// Add a strategy. Strategies added first are asked first and take precedence when the
+    /// number of connections is limited.
+    pub fn with(mut self, strategy: impl Strategy + 'static) -> Self {
+        self.strategies.push(ScheduledStrategy {
+            strategy: Box::new(strategy),
+            next_run: None,
+        });
+        self
+    }
+
+    /// Use `address_book` for [PubStrategy] and [RoomStrategy] and to persist failures.
+    ///
+    /// Addresses that failed before are not dialed until their backoff has passed.
+    pub fn with_address_book(mut self, address_book: AddressBook) -> Self {
+        self.address_book = address_book;
+        self
+    }
+
+    /// Use `dialer` to open connections, for example to connect through a SOCKS5 proxy.
+    pub fn with_dialer(mut self, dialer: Dialer) -> Self {
+        self.dialer = dialer;
+        self
+    }
+
+    /// Share `cache` with the handshakes of other connections instead of a new cache.
+    pub fn with_handshake_cache(mut self, cache: ssb_box_stream::HandshakeCache) -> Self {
+        self.handshake_cache = cache;
+        self
+    }
+
+    /// Do not dial peers that have an open connection in `connections`, for example because they
+    /// dialed us.
+    pub fn with_connections(mut self, connections: PeerConnections) -> Self {
+        self.connections = Some(connections);
+        self
+    }
+
+    /// Report dialed connections to `events`. [ConnEvent::Connecting] and
+    /// [ConnEvent::Connected] are emitted by [ConnectionManager::poll] and
+    /// [ConnectionManager::record_success]. The application reports the other events with
+    /// [ConnectionManager::record_handshake_failure] and [ConnectionManager::record_closed].
+    pub fn with_events(mut self, events: ConnEvents) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn dialer(&self) -> &Dialer {
+        &self.dialer
+    }
+
+    /// Cache for the handshakes of dialed and accepted connections. Pass it to
+    /// [ssb_box_stream::Client::with_cache] and [ssb_box_stream::Server::with_cache] so that
+    /// reconnecting to a peer skips the identity key conversions.
+    pub fn handshake_cache(&self) -> &ssb_box_stream::HandshakeCache {
+        &self.handshake_cache
+    }
+
+    pub fn address_book(&self) -> &AddressBook {
+        &self.address_book
+    }
+
+    /// Access the address book, for example to ingest new messages.
+    pub fn address_book_mut(&mut self) -> &mut AddressBook {
+        &mut self.address_book
+    }
+
+    /// Access the strategy with the given type, for example to add discovered peers.
+    pub fn strategy_mut<S: Strategy + 'static>(&mut self) -> Option<&mut S> {
+        self.strategies.iter_mut().find_map(|scheduled| {
+            <dyn Strategy as AsAny>::as_any_mut(&mut *scheduled.strategy).downcast_mut::<S>()
+        })
+    }
+
+    /// Stream of dial events from now on.
+    pub fn subscribe(&mut self) -> mpsc::UnboundedReceiver<DialEvent> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Run the strategies that are due at `now` and return the addresses to dial.
+    ///
+    /// Every returned address counts as being dialed until the application calls
+    /// [ConnectionManager::record_success] or [ConnectionManager::record_failure].
+    pub fn poll(&mut self, now: SystemTime) -> Vec<Dial> {
+        let mut active = self.active(None);
+        // Peers that are connected or being dialed, possibly through another address
+        let mut active_keys = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.state != PeerState::Idle)
+            .filter_map(|(address, _)| address_key(address))
+            .collect::<std::collections::HashSet<_>>();
+        let mut dials = Vec::new();
+        for index in 0..self.strategies.len() {
+            let scheduled = &mut self.strategies[index];
+            let policy = scheduled.strategy.policy().clone();
+            if policy.fallback && active > 0 {
+                continue;
+            }
+            if matches!(scheduled.next_run, Some(next_run) if next_run > now) {
+                continue;
+            }
+            scheduled.next_run = Some(now + policy.interval);
+            let name = scheduled.strategy.name();
+            let candidates = scheduled.strategy.candidates(now, &self.address_book);
+            let mut strategy_active = self.active(Some(index));
+            for address in candidates {
+                if !self.dialer.can_dial(&address) {
+                    continue;
+                }
+                let key = address_key(&address);
+                if let Some(key) = &key {
+                    let connected = matches!(
+                        &self.connections,
+                        Some(connections) if connections.is_connected(key)
+                    );
+                    if connected || active_keys.contains(key) {
+                        continue;
+                    }
+                }
+                if active >= self.max_connections
+                    || matches!(policy.max_active, Some(max_active) if strategy_active >= max_active)
+                {
+                    break;
+                }
+                let address_book = &self.address_book;
+                let peer = self.peers.entry(address.clone()).or_insert_with(|| {
+                    // Restore the backoff of an address that failed before a restart
+                    let failures = address_book.get(&address).map_or(0, |peer| peer.failures);
+                    let retry_at = address_book
+                        .get(&address)
+                        .and_then(|peer| peer.last_failure)
+                        .map(|last_failure| last_failure + policy.backoff(failures));
+                    Peer {
+                        state: PeerState::Idle,
+                        strategy: index,
+                        failures,
+                        retry_at,
+                        rtt: None,
+                    }
+                });
+                if peer.state != PeerState::Idle
+                    || matches!(peer.retry_at, Some(retry_at) if retry_at > now)
+                {
+                    continue;
+                }
+                peer.state = PeerState::Dialing;
+                peer.strategy = index;
+                active += 1;
+                strategy_active += 1;
+                active_keys.extend(key);
+                dials.push(Dial {
+                    address,
+                    strategy: name,
+                });
+            }
+        }
+        for dial in &dials {
+            self.emit(&dial.address, DialOutcome::Dialing);
+            self.emit_conn_event(ConnEvent::Connecting {
+                addr: dial.address.to_string(),
+            });
+        }
+        dials
+    }
+
+    /// Earliest time at which [ConnectionManager::poll] runs a strategy again. The time is in the
+    /// past if a strategy is due already. Returns `None` if no strategy can run.
+    pub fn next_poll(&self) -> Option<SystemTime> {
+        let active = self.active(None);
+        self.strategies
+            .iter()
+            .filter(|scheduled| !(scheduled.strategy.policy().fallback && active > 0))
+            .map(|scheduled| scheduled.next_run.unwrap_or(SystemTime::UNIX_EPOCH))
+            .min()
+    }
+
+    /// Number of connections that are open or being dialed, optionally only those of one
+    /// strategy.
+    fn active(&self, strategy: Option<usize>) -> usize {
+        self.peers
+            .values()
+            .filter(|peer| peer.state != PeerState::Idle)
+            .filter(|peer| strategy.is_none() || strategy == Some(peer.strategy))
+            .count()
+    }
+
+    /// Record that `address` was connected at `now`.
+    pub fn record_success(&mut self, address: &Address, now: SystemTime) {
+        if let Some(peer) = self.peers.get_mut(address) {
+            peer.state = PeerState::Connected;
+            peer.failures = 0;
+            peer.retry_at = None;
+            self.address_book.record_success(address, now);
+            self.emit(address, DialOutcome::Connected);
+            if let Some(peer) = address_key(address) {
+                self.emit_conn_event(ConnEvent::Connected {
+                    peer: FeedId(peer),
+                    addr: address.to_string(),
+                });
+            }
+        }
+    }
+
+    /// Like [ConnectionManager::record_failure] for a connection that failed the secret
+    /// handshake. Emits [ConnEvent::HandshakeFailed] with `reason`.
+    pub fn record_handshake_failure(&mut self, address: &Address, now: SystemTime, reason: &str) {
+        self.record_failure(address, now);
+        self.emit_conn_event(ConnEvent::HandshakeFailed {
+            addr: address.to_string(),
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Record that dialing `address` failed at `now`. The address is not dialed again before the
+    /// backoff of the strategy that proposed it has passed.
+    pub fn record_failure(&mut self, address: &Address, now: SystemTime) {
+        if let Some(peer) = self.peers.get_mut(address) {
+            peer.state = PeerState::Idle;
+            peer.failures = peer.failures.saturating_add(1);
+            let policy = self.strategies[peer.strategy].strategy.policy();
+            let retry_at = now + policy.backoff_with_jitter(peer.failures, random());
+            peer.retry_at = Some(retry_at);
+            let failures = peer.failures;
+            self.address_book.record_failure(address, now);
+            self.emit(address, DialOutcome::Failed { failures, retry_at });
+        }
+    }
+
+    /// Record a round trip time sample of the connection to `address`, for example from
+    /// [EndpointMetrics::rtt_measured][crate::rpc::base::EndpointMetrics::rtt_measured].
+    pub fn record_rtt(&mut self, address: &Address, rtt: Duration) {
+        if let Some(peer) = self.peers.get_mut(address) {
+            if peer.state == PeerState::Connected {
+                peer.rtt = Some(crate::rpc::base::smooth_rtt(peer.rtt, rtt));
+            }
+        }
+    }
+
+    /// Smoothed round trip time of the connection to `address`.
+    pub fn rtt(&self, address: &Address) -> Option<Duration> {
+        self.peers.get(address).and_then(|peer| peer.rtt)
+    }
+
+    /// Connected addresses, lowest round trip time first. Addresses without a measurement come
+    /// last. Interactive requests should go to the first peers.
+    pub fn connected_by_latency(&self) -> Vec<Address> {
+        let mut connected = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.state == PeerState::Connected)
+            .map(|(address, peer)| (peer.rtt, address))
+            .collect::<Vec<_>>();
+        connected.sort_by_key(|(rtt, _)| (rtt.is_none(), *rtt));
+        connected
+            .into_iter()
+            .map(|(_, address)| address.clone())
+            .collect()
+    }
+
+    /// Record that the connection to `address` was closed. The address may be dialed again
+    /// right away.
+    pub fn record_disconnect(&mut self, address: &Address) {
+        if let Some(peer) = self.peers.get_mut(address) {
+            peer.state = PeerState::Idle;
+            peer.rtt = None;
+            self.emit(address, DialOutcome::Disconnected);
+        }
+    }
+
+    /// Like [ConnectionManager::record_disconnect]. Emits [ConnEvent::Disconnected] with `cause`
+    /// and the `bytes` transferred over the connection.
+    pub fn record_closed(&mut self, address: &Address, cause: DisconnectCause, bytes: Traffic) {
+        self.record_disconnect(address);
+        if let Some(peer) = address_key(address) {
+            self.emit_conn_event(ConnEvent::Disconnected {
+                peer: FeedId(peer),
+                cause,
+                bytes,
+            });
+        }
+    }
+
+    /// Number of open connections.
+    pub fn connections(&self) -> usize {
+        self.peers
+            .values()
+            .filter(|peer| peer.state == PeerState::Connected)
+            .count()
+    }
+
+    fn emit_conn_event(&self, event: ConnEvent) {
+        if let Some(events) = &self.events {
+            events.emit(event);
+        }
+    }
+
+    fn emit(&mut self, address: &Address, outcome: DialOutcome) {
+        let strategy = match self.peers.get(address) {
+            Some(peer) => self.strategies[peer.strategy].strategy.name(),
+            None => return,
+        };
+        let event = DialEvent {
+            address: address.clone(),
+            strategy,
+            outcome,
+        };
+        self.subscribers
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+/// Public key of the peer at `address`.
+fn address_key(address: &Address) -> Option<PublicKey> {
+    address
+        .shs_key()
+        .ok()
+        .and_then(|key| PublicKey::from_slice(&key))
+}
+
+/// Uniformly distributed number between `0.0` and `1.0`.
+fn random() -> f64 {
+    const RESOLUTION: u32 = 1 << 24;
+    f64::from(crate::crypto::randombytes::randombytes_uniform(RESOLUTION)) / f64::from(RESOLUTION)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn address(n: u8) -> Address {
+        format!("net:10.0.0.{}:8008~shs:{}", n, base64::encode([n; 32]))
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = Policy::new(Duration::from_secs(1))
+            .retry_delay(Duration::from_secs(10), Duration::from_secs(30));
+        assert_eq!(policy.backoff(0), Duration::ZERO);
+        assert_eq!(policy.backoff(1), Duration::from_secs(10));
+        assert_eq!(policy.backoff(2), Duration::from_secs(20));
+        assert_eq!(policy.backoff(3), Duration::from_secs(30));
+        assert_eq!(policy.backoff(100), Duration::from_secs(30));
+
+        let policy = policy.jitter(0.5);
+        assert_eq!(policy.backoff_with_jitter(2, 0.0), Duration::from_secs(20));
+        assert_eq!(policy.backoff_with_jitter(2, 1.0), Duration::from_secs(10));
+        for _ in 0..100 {
+            let delay = policy.backoff_with_jitter(2, random());
+            assert!(delay >= Duration::from_secs(10) && delay <= Duration::from_secs(20));
+        }
+    }
+
+    #[test]
+    fn skip_unsupported_addresses() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let onion = "onion:example.onion:8008~shs:AA=="
+            .parse::<Address>()
+            .unwrap();
+        let strategy = || ManualStrategy::new(vec![onion.clone(), address(1)]);
+        let mut manager = ConnectionManager::new(10).with(strategy());
+        assert_eq!(manager.poll(now).len(), 1);
+
+        let dialer = Dialer::new().with_socks5_proxy("127.0.0.1:9050".parse().unwrap());
+        let mut manager = ConnectionManager::new(10)
+            .with(strategy())
+            .with_dialer(dialer);
+        assert_eq!(manager.poll(now).len(), 2);
+    }
+
+    #[test]
+    fn strategy_limit() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let manual = ManualStrategy::new(vec![address(1), address(2), address(3)])
+            .with_policy(Policy::new(Duration::from_secs(10)).max_active(1));
+        let mut manager = ConnectionManager::new(10)
+            .with(manual)
+            .with(ManualStrategy::new(vec![address(4)]));
+        let strategies = |dials: Vec<Dial>| {
+            dials
+                .into_iter()
+                .map(|dial| (dial.address, dial.strategy))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            strategies(manager.poll(now)),
+            vec![(address(1), "manual"), (address(4), "manual")]
+        );
+        manager.record_disconnect(&address(1));
+        assert_eq!(
+            strategies(manager.poll(now + Duration::from_secs(10))),
+            vec![(address(1), "manual")]
+        );
+    }
+
+    #[test]
+    fn skip_connected_peers() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        // Another address of the peer at `address(1)`
+        let other_address = format!("net:10.0.1.1:8008~shs:{}", base64::encode([1; 32]))
+            .parse::<Address>()
+            .unwrap();
+        let connections = PeerConnections::new(
+            crate::crypto::sign::KeyPair::gen().public,
+            DuplicatePolicy::RejectNew,
+        );
+        let _connection = connections
+            .open(address_key(&address(2)).unwrap(), Direction::Inbound)
+            .unwrap();
+        let mut manager = ConnectionManager::new(10)
+            .with(
+                ManualStrategy::new(vec![address(1), other_address.clone(), address(2)])
+                    .with_policy(
+                        Policy::new(Duration::from_secs(10))
+                            .retry_delay(Duration::from_secs(60), Duration::from_secs(60)),
+                    ),
+            )
+            .with_connections(connections);
+        assert_eq!(
+            manager.poll(now),
+            vec![Dial {
+                address: address(1),
+                strategy: "manual"
+            }]
+        );
+
+        // The other address is tried when the first one fails
+        manager.record_failure(&address(1), now);
+        assert_eq!(
+            manager.poll(now + Duration::from_secs(10)),
+            vec![Dial {
+                address: other_address,
+                strategy: "manual"
+            }]
+        );
+    }
+
+    #[test]
+    fn latency() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let mut manager = ConnectionManager::new(10).with(ManualStrategy::new(vec![
+            address(1),
+            address(2),
+            address(3),
+        ]));
+        for dial in manager.poll(now) {
+            manager.record_success(&dial.address, now);
+        }
+        manager.record_rtt(&address(1), Duration::from_millis(200));
+        manager.record_rtt(&address(2), Duration::from_millis(20));
+        assert_eq!(
+            manager.connected_by_latency(),
+            vec![address(2), address(1), address(3)]
+        );
+
+        manager.record_rtt(&address(2), Duration::from_millis(100));
+        assert_eq!(manager.rtt(&address(2)), Some(Duration::from_millis(30)));
+        manager.record_disconnect(&address(2));
+        assert_eq!(manager.rtt(&address(2)), None);
+        assert_eq!(manager.connected_by_latency(), vec![address(1), address(3)]);
+    }
+
+    #[test]
+    fn conn_events() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let events = ConnEvents::new();
+        let mut subscription = events.subscribe();
+        let mut manager = ConnectionManager::new(10)
+            .with(ManualStrategy::new(vec![address(1), address(2)]))
+            .with_events(events);
+        manager.poll(now);
+        manager.record_success(&address(1), now);
+        manager.record_handshake_failure(&address(2), now, "wrong network key");
+        let bytes = Traffic {
+            sent: 10,
+            received: 20,
+        };
+        manager.record_closed(&address(1), DisconnectCause::Closed, bytes);
+
+        let peer = FeedId(address_key(&address(1)).unwrap());
+        let events = std::iter::from_fn(|| subscription.try_recv().ok());
+        assert_eq!(
+            events.collect::<Vec<_>>(),
+            vec![
+                ConnEvent::Connecting {
+                    addr: address(1).to_string()
+                },
+                ConnEvent::Connecting {
+                    addr: address(2).to_string()
+                },
+                ConnEvent::Connected {
+                    peer,
+                    addr: address(1).to_string()
+                },
+                ConnEvent::HandshakeFailed {
+                    addr: address(2).to_string(),
+                    reason: "wrong network key".to_string()
+                },
+                ConnEvent::Disconnected {
+                    peer,
+                    cause: DisconnectCause::Closed,
+                    bytes
+                },
+            ]
+        );
+        assert_eq!(manager.connections(), 0);
+    }
+
+    #[test]
+    fn persist_backoff() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let mut address_book = AddressBook::new();
+        address_book.insert(&address(1));
+        let policy = Policy::new(Duration::from_secs(10))
+            .retry_delay(Duration::from_secs(60), Duration::from_secs(600));
+        let mut manager = ConnectionManager::new(10)
+            .with(PubStrategy::new().with_policy(policy.clone()))
+            .with_address_book(address_book);
+        let mut events = manager.subscribe();
+
+        assert_eq!(manager.poll(now).len(), 1);
+        manager.record_failure(&address(1), now);
+        assert_eq!(manager.address_book().get(&address(1)).unwrap().failures, 1);
+        assert_eq!(events.try_recv().unwrap().outcome, DialOutcome::Dialing);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            DialEvent {
+                address: address(1),
+                strategy: "pub",
+                outcome: DialOutcome::Failed {
+                    failures: 1,
+                    retry_at: now + Duration::from_secs(60),
+                },
+            }
+        );
+
+        // A new manager with the same address book waits for the backoff
+        let address_book = manager.address_book().clone();
+        let mut manager = ConnectionManager::new(10)
+            .with(PubStrategy::new().with_policy(policy))
+            .with_address_book(address_book);
+        assert_eq!(manager.poll(now + Duration::from_secs(30)), vec![]);
+        assert_eq!(manager.poll(now + Duration::from_secs(60)).len(), 1);
+    }
+
+    #[test]
+    fn schedule_and_retry() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let policy = Policy::new(Duration::from_secs(10))
+            .retry_delay(Duration::from_secs(15), Duration::from_secs(60));
+        let mut manager = ConnectionManager::new(2).with(
+            ManualStrategy::new(vec![address(1), address(2), address(3)]).with_policy(policy),
+        );
+
+        let dials = manager.poll(start);
+        assert_eq!(
+            dials,
+            vec![
+                Dial {
+                    address: address(1),
+                    strategy: "manual"
+                },
+                Dial {
+                    address: address(2),
+                    strategy: "manual"
+                },
+            ]
+        );
+        assert_eq!(manager.next_poll(), Some(start + Duration::from_secs(10)));
+        assert_eq!(manager.poll(start + Duration::from_secs(5)), vec![]);
+
+        manager.record_success(&address(1), start);
+        manager.record_failure(&address(2), start);
+        let addresses = |dials: Vec<Dial>| {
+            dials
+                .into_iter()
+                .map(|dial| dial.address)
+                .collect::<Vec<_>>()
+        };
+        // The failed address waits for its backoff
+        assert_eq!(
+            addresses(manager.poll(start + Duration::from_secs(10))),
+            vec![address(3)]
+        );
+        manager.record_failure(&address(3), start + Duration::from_secs(10));
+        assert_eq!(
+            addresses(manager.poll(start + Duration::from_secs(20))),
+            vec![address(2)]
+        );
+        assert_eq!(manager.connections(), 1);
+    }
+
+    #[test]
+    fn fallback() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let mut manager = ConnectionManager::new(4)
+            .with(LanStrategy::new())
+            .with(RoomStrategy::new(vec![address(9)]));
+        let peer = crate::discovery::DiscoveredPeer {
+            multi_address: address(1).into(),
+            mechanism: crate::discovery::Mechanism::Broadcast,
+        };
+        manager
+            .strategy_mut::<LanStrategy>()
+            .unwrap()
+            .discovered(&peer, now);
+
+        let dials = manager.poll(now);
+        assert_eq!(
+            dials,
+            vec![Dial {
+                address: address(1),
+                strategy: "lan"
+            }]
+        );
+
+        // Rooms are only dialed once the LAN peer is gone
+        manager.record_failure(&address(1), now);
+        let later = now + Duration::from_secs(60);
+        assert_eq!(
+            manager.poll(later),
+            vec![Dial {
+                address: address(9),
+                strategy: "room"
+            }]
+        );
+    }
+}
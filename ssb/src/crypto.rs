@@ -14,6 +14,15 @@ pub mod auth {
     }
 }
 
+/// Password hashing with Argon2id
+pub mod pwhash {
+    pub use sodiumoxide::crypto::pwhash::argon2id13::*;
+}
+
+pub mod randombytes {
+    pub use sodiumoxide::randombytes::*;
+}
+
 pub mod secretbox {
     pub use sodiumoxide::crypto::secretbox::*;
 
@@ -42,6 +51,21 @@ pub mod sign {
             Self::new(public, secret)
         }
     }
+
+    impl From<&KeyPair> for ssb_box_stream::SecretKey {
+        fn from(key_pair: &KeyPair) -> Self {
+            Self(key_pair.secret.0)
+        }
+    }
+
+    impl From<&ssb_box_stream::SecretKey> for KeyPair {
+        fn from(secret_key: &ssb_box_stream::SecretKey) -> Self {
+            Self::new(
+                PublicKey(secret_key.public_key().0),
+                SecretKey(secret_key.0),
+            )
+        }
+    }
 }
 
 pub fn share_key(
@@ -59,6 +83,35 @@ pub fn hash(data: impl AsRef<[u8]>) -> [u8; 32] {
     <[u8; 32]>::try_from(sha256::hash(data.as_ref()).as_ref()).unwrap()
 }
 
+/// Incremental version of [hash] for data that is not available at once.
+pub struct Hasher(sha256::State);
+
+impl Hasher {
+    pub fn new() -> Self {
+        Self(sha256::State::new())
+    }
+
+    pub fn update(&mut self, data: impl AsRef<[u8]>) {
+        self.0.update(data.as_ref())
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        <[u8; 32]>::try_from(self.0.finalize().as_ref()).unwrap()
+    }
+}
+
+impl std::fmt::Debug for Hasher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hasher").finish()
+    }
+}
+
+impl Default for Hasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Convert a sign key to an exchange key.
 pub fn sign_to_box_pk(&public_key: &sign::PublicKey) -> Option<box_::PublicKey> {
     let mut curve25519_pk = [0u8; box_::PUBLICKEYBYTES];
@@ -0,0 +1,384 @@
+//! Discovery of peers with DNS-SD over multicast DNS.
+//!
+//! Peers register the service [SERVICE]. Every peer is a service instance with a `TXT` record
+//! that has its multi address in the `multiserverAddress` entry. Browsers send `PTR` queries for
+//! the service and read the multi addresses from the `TXT` records of the responses. `SRV` and
+//! `A` records are not used since the multi address contains the host and port.
+//!
+//! Only the parts of [RFC 6762] and [RFC 6763] needed for this are implemented.
+//!
+//! [RFC 6762]: https://tools.ietf.org/html/rfc6762
+//! [RFC 6763]: https://tools.ietf.org/html/rfc6763
+
+use futures::prelude::*;
+use std::convert::TryFrom;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+
+use crate::multi_address::MultiAddress;
+
+/// Name of the DNS-SD service for SSB peers.
+pub const SERVICE: &str = "_scuttlebutt._tcp.local";
+
+/// Multicast group and port for mDNS.
+const MDNS_ADDRESS: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353);
+
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Tells receivers to replace cached records with the same name and type.
+const CACHE_FLUSH: u16 = 0x8000;
+/// Flags of a response: it is a response and authoritative.
+const FLAGS_RESPONSE: u16 = 0x8400;
+/// Time in seconds that records may be cached, as recommended by RFC 6762.
+const TTL: u32 = 120;
+
+const TXT_KEY: &str = "multiserverAddress=";
+
+/// Announce `multi_address` if given and browse for peers every `interval`.
+///
+/// The stream yields the multi address of every `TXT` record that is received, so the same peer
+/// is yielded repeatedly. Announcements of the local peer are yielded, too. Errors while sending
+/// are logged and do not end the stream.
+pub fn mdns(
+    announce: Option<&MultiAddress>,
+    interval: std::time::Duration,
+) -> std::io::Result<impl Stream<Item = MultiAddress>> {
+    let response = match announce {
+        Some(multi_address) => {
+            let multi_address = multi_address.to_string();
+            if TXT_KEY.len() + multi_address.len() > usize::from(u8::MAX) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "multi address is too long for a TXT record",
+                ));
+            }
+            let hash = crate::crypto::hash(&multi_address);
+            let instance = format!(
+                "ssb-{}.{}",
+                hash[..8]
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>(),
+                SERVICE
+            );
+            Some(Arc::new(response_packet(&instance, &multi_address)))
+        }
+        None => None,
+    };
+    let socket = Arc::new(multicast_socket()?);
+
+    let ticks = async_std::stream::interval(interval).map(|()| None);
+    let packets = packet_stream(socket.clone()).map(Some);
+    let events = stream::select(stream::once(future::ready(None)).chain(ticks), packets);
+    let peers = events.filter_map(move |packet| {
+        let socket = socket.clone();
+        let response = response.clone();
+        async move {
+            let packet = match packet {
+                Some(packet) => packet,
+                None => {
+                    send(&socket, &query_packet()).await;
+                    if let Some(response) = response {
+                        send(&socket, &response).await;
+                    }
+                    return None;
+                }
+            };
+            let message = parse(&packet)?;
+            if message.is_response {
+                return Some(message.multi_addresses);
+            }
+            let asks_for_service = message.questions.iter().any(|(name, type_)| {
+                name.eq_ignore_ascii_case(SERVICE) && (*type_ == TYPE_PTR || *type_ == TYPE_ANY)
+            });
+            if let (true, Some(response)) = (asks_for_service, response) {
+                send(&socket, &response).await;
+            }
+            None
+        }
+    });
+    Ok(peers.flat_map(stream::iter))
+}
+
+/// Creates a UDP socket that is bound to the mDNS port and receives the packets of the mDNS
+/// multicast group.
+fn multicast_socket() -> std::io::Result<async_std::net::UdpSocket> {
+    let socket = socket2::Socket::new(socket2::Domain::ipv4(), socket2::Type::dgram(), None)?;
+    socket.set_reuse_address(true)?;
+    socket.set_multicast_loop_v4(true)?;
+    let bind_address = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, MDNS_ADDRESS.port());
+    socket.bind(&bind_address.into())?;
+    socket.join_multicast_v4(MDNS_ADDRESS.ip(), &Ipv4Addr::UNSPECIFIED)?;
+    Ok(socket.into_udp_socket().into())
+}
+
+async fn send(socket: &async_std::net::UdpSocket, packet: &[u8]) {
+    if let Err(error) = socket.send_to(packet, MDNS_ADDRESS).await {
+        tracing::warn!(?error, "failed to send mDNS packet");
+    }
+}
+
+fn packet_stream(socket: Arc<async_std::net::UdpSocket>) -> impl Stream<Item = Vec<u8>> {
+    stream::unfold(socket, |socket| async move {
+        let mut buf = vec![0u8; 9000];
+        loop {
+            match socket.recv(&mut buf).await {
+                Ok(size) => {
+                    buf.truncate(size);
+                    return Some((buf, socket));
+                }
+                Err(error) => {
+                    tracing::warn!(?error, "failed to receive mDNS packet");
+                    async_std::task::sleep(std::time::Duration::from_secs(1)).await;
+                }
+            }
+        }
+    })
+}
+
+fn write_header(packet: &mut Vec<u8>, flags: u16, questions: u16, answers: u16) {
+    // The ID is always zero for mDNS.
+    for field in &[0, flags, questions, answers, 0, 0] {
+        packet.extend_from_slice(&field.to_be_bytes());
+    }
+}
+
+fn write_name(packet: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+}
+
+fn write_record(packet: &mut Vec<u8>, name: &str, type_: u16, class: u16, data: &[u8]) {
+    write_name(packet, name);
+    packet.extend_from_slice(&type_.to_be_bytes());
+    packet.extend_from_slice(&class.to_be_bytes());
+    packet.extend_from_slice(&TTL.to_be_bytes());
+    packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    packet.extend_from_slice(data);
+}
+
+/// Query for the instances of [SERVICE].
+fn query_packet() -> Vec<u8> {
+    let mut packet = Vec::new();
+    write_header(&mut packet, 0, 1, 0);
+    write_name(&mut packet, SERVICE);
+    packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    packet
+}
+
+/// Response with the `PTR` record from [SERVICE] to `instance` and the `TXT` record of
+/// `instance` with `multi_address`.
+fn response_packet(instance: &str, multi_address: &str) -> Vec<u8> {
+    let mut packet = Vec::new();
+    write_header(&mut packet, FLAGS_RESPONSE, 0, 2);
+    let mut ptr_data = Vec::new();
+    write_name(&mut ptr_data, instance);
+    write_record(&mut packet, SERVICE, TYPE_PTR, CLASS_IN, &ptr_data);
+    let entry = format!("{}{}", TXT_KEY, multi_address);
+    let mut txt_data = vec![entry.len() as u8];
+    txt_data.extend_from_slice(entry.as_bytes());
+    write_record(
+        &mut packet,
+        instance,
+        TYPE_TXT,
+        CLASS_IN | CACHE_FLUSH,
+        &txt_data,
+    );
+    packet
+}
+
+/// Parts of an mDNS message that are relevant for discovery.
+#[derive(Debug, PartialEq)]
+struct Message {
+    is_response: bool,
+    /// Name and type of the questions
+    questions: Vec<(String, u16)>,
+    /// Multi addresses from `TXT` records of [SERVICE] instances
+    multi_addresses: Vec<MultiAddress>,
+}
+
+/// Parse an mDNS message. Returns `None` if the message is malformed.
+fn parse(packet: &[u8]) -> Option<Message> {
+    let mut reader = Reader {
+        packet,
+        position: 0,
+    };
+    let _id = reader.u16()?;
+    let flags = reader.u16()?;
+    let question_count = reader.u16()?;
+    let record_count = [reader.u16()?, reader.u16()?, reader.u16()?]
+        .iter()
+        .map(|count| usize::from(*count))
+        .sum::<usize>();
+
+    let mut questions = Vec::new();
+    for _ in 0..question_count {
+        let name = reader.name()?;
+        let type_ = reader.u16()?;
+        let _class = reader.u16()?;
+        questions.push((name, type_));
+    }
+
+    let service_suffix = format!(".{}", SERVICE);
+    let mut multi_addresses = Vec::new();
+    for _ in 0..record_count {
+        let name = reader.name()?;
+        let type_ = reader.u16()?;
+        let _class = reader.u16()?;
+        let _ttl = [reader.u16()?, reader.u16()?];
+        let length = usize::from(reader.u16()?);
+        let data = reader.bytes(length)?;
+        let is_instance = name
+            .to_ascii_lowercase()
+            .ends_with(&service_suffix.to_ascii_lowercase());
+        if type_ == TYPE_TXT && is_instance {
+            multi_addresses.extend(txt_entries(data).filter_map(|entry| {
+                let value = entry.strip_prefix(TXT_KEY)?;
+                value.parse().ok()
+            }));
+        }
+    }
+
+    Some(Message {
+        is_response: flags & 0x8000 != 0,
+        questions,
+        multi_addresses,
+    })
+}
+
+/// Entries of `TXT` record data that are valid UTF-8.
+fn txt_entries(mut data: &[u8]) -> impl Iterator<Item = &str> {
+    std::iter::from_fn(move || {
+        let (length, rest) = data.split_first()?;
+        let entry = rest.get(..usize::from(*length))?;
+        data = &rest[entry.len()..];
+        Some(std::str::from_utf8(entry).ok())
+    })
+    .flatten()
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        let bytes = self
+            .packet
+            .get(self.position..self.position.checked_add(length)?)?;
+        self.position += length;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = <[u8; 2]>::try_from(self.bytes(2)?).ok()?;
+        Some(u16::from_be_bytes(bytes))
+    }
+
+    /// Read a name that may use message compression.
+    fn name(&mut self) -> Option<String> {
+        let mut labels = Vec::new();
+        let mut position = self.position;
+        // Position after the name if it contains a pointer
+        let mut end = None;
+        // Pointers must point backwards so that they cannot loop.
+        let mut limit = self.position;
+        loop {
+            let length = *self.packet.get(position)?;
+            match length {
+                0 => {
+                    position += 1;
+                    break;
+                }
+                length if length & 0xc0 == 0xc0 => {
+                    let low = *self.packet.get(position + 1)?;
+                    let target = usize::from(length & 0x3f) << 8 | usize::from(low);
+                    if target >= limit {
+                        return None;
+                    }
+                    end.get_or_insert(position + 2);
+                    limit = target;
+                    position = target;
+                }
+                length if length & 0xc0 == 0 => {
+                    let start = position + 1;
+                    let label = self.packet.get(start..start + usize::from(length))?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    position = start + usize::from(length);
+                }
+                _ => return None,
+            }
+        }
+        self.position = end.unwrap_or(position);
+        Some(labels.join("."))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MULTI_ADDRESS: &str =
+        "net:192.168.1.2:8008~shs:FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=";
+
+    #[test]
+    fn response_roundtrip() {
+        let instance = format!("ssb-0011223344556677.{}", SERVICE);
+        let packet = response_packet(&instance, MULTI_ADDRESS);
+        let message = parse(&packet).unwrap();
+        assert!(message.is_response);
+        assert_eq!(
+            message.multi_addresses,
+            vec![MULTI_ADDRESS.parse::<MultiAddress>().unwrap()]
+        );
+
+        let message = parse(&query_packet()).unwrap();
+        assert!(!message.is_response);
+        assert_eq!(message.questions, vec![(SERVICE.to_string(), TYPE_PTR)]);
+        assert!(parse(&packet[..packet.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn compressed_names() {
+        let mut packet = Vec::new();
+        write_header(&mut packet, FLAGS_RESPONSE, 0, 2);
+        // PTR record with the full service name
+        let service_offset = packet.len() as u8;
+        let mut ptr_data = vec![8];
+        ptr_data.extend_from_slice(b"instance");
+        ptr_data.extend_from_slice(&[0xc0, service_offset]);
+        write_record(&mut packet, SERVICE, TYPE_PTR, CLASS_IN, &[]);
+        // Replace the empty data of the PTR record
+        packet.truncate(packet.len() - 2);
+        packet.extend_from_slice(&(ptr_data.len() as u16).to_be_bytes());
+        let instance_offset = packet.len() as u8;
+        packet.extend_from_slice(&ptr_data);
+        // TXT record whose name points to the instance name in the PTR record
+        packet.extend_from_slice(&[0xc0, instance_offset]);
+        packet.extend_from_slice(&TYPE_TXT.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet.extend_from_slice(&TTL.to_be_bytes());
+        let entry = format!("{}{}", TXT_KEY, MULTI_ADDRESS);
+        packet.extend_from_slice(&(entry.len() as u16 + 5).to_be_bytes());
+        packet.push(3);
+        packet.extend_from_slice(b"a=b");
+        packet.push(entry.len() as u8);
+        packet.extend_from_slice(entry.as_bytes());
+
+        let message = parse(&packet).unwrap();
+        assert_eq!(message.multi_addresses.len(), 1);
+
+        // A pointer to itself is rejected.
+        let mut looping = Vec::new();
+        write_header(&mut looping, 0, 1, 0);
+        looping.extend_from_slice(&[0xc0, 12, 0, 12, 0, 1]);
+        assert!(parse(&looping).is_none());
+    }
+}
@@ -0,0 +1,367 @@
+//! Discover and announce SSB peers on the local network.
+//!
+//! Peers are found with UDP broadcast on [PORT] and with [mdns]. [PeerDiscovery] combines both.
+//!
+//! ```no_run
+//! # use futures::prelude::*;
+//! # use ssb::discovery::{DiscoveryConfig, PeerDiscovery};
+//! # async_std::task::block_on(async {
+//! let mut peers = PeerDiscovery::start(DiscoveryConfig::default()).unwrap();
+//! while let Some(peer) = peers.try_next().await.unwrap() {
+//!     println!("{} via {:?}", peer.multi_address, peer.mechanism);
+//! }
+//! # });
+//! ```
+
+use futures::prelude::*;
+use std::collections::HashMap;
+
+pub mod mdns;
+
+/// The default port used for discovery by SSB
+pub const PORT: u16 = 8008;
+
+/// How a peer was discovered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mechanism {
+    Broadcast,
+    Mdns,
+}
+
+/// Announcement of a peer received by [PeerDiscovery].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredPeer {
+    pub multi_address: crate::multi_address::MultiAddress,
+    pub mechanism: Mechanism,
+}
+
+/// Configuration for [PeerDiscovery::start].
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    /// Port for UDP broadcast. Broadcast is disabled if `None`.
+    pub broadcast_port: Option<u16>,
+    /// Use mDNS.
+    pub mdns: bool,
+    /// Address of the local peer to announce. Nothing is announced if `None`.
+    pub announce: Option<crate::multi_address::MultiAddress>,
+    /// Time between announcements and mDNS queries
+    pub interval: std::time::Duration,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            broadcast_port: Some(PORT),
+            mdns: true,
+            announce: None,
+            interval: std::time::Duration::from_secs(1),
+        }
+    }
+}
+
+/// Stream of peers announced on the local network through all enabled mechanisms.
+///
+/// Peers announce themselves repeatedly so the same peer is yielded many times. Announcements
+/// are sent while the stream is polled and stop when it is dropped.
+pub struct PeerDiscovery {
+    peers: stream::BoxStream<'static, anyhow::Result<DiscoveredPeer>>,
+}
+
+impl std::fmt::Debug for PeerDiscovery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PeerDiscovery").finish()
+    }
+}
+
+impl PeerDiscovery {
+    /// Bind the sockets for the enabled mechanisms and start discovery.
+    pub fn start(config: DiscoveryConfig) -> std::io::Result<Self> {
+        let mut streams = Vec::new();
+        if let Some(port) = config.broadcast_port {
+            let peers = discover(port)?.map_ok(|multi_address| DiscoveredPeer {
+                multi_address,
+                mechanism: Mechanism::Broadcast,
+            });
+            streams.push(peers.boxed());
+            if let Some(multi_address) = &config.announce {
+                let announcer = Announcer::new(multi_address, port, config.interval);
+                let errors = async move { announcer.run().await }
+                    .into_stream()
+                    .filter_map(|result| future::ready(result.err().map(Err)));
+                streams.push(errors.boxed());
+            }
+        }
+        if config.mdns {
+            let peers = mdns::mdns(config.announce.as_ref(), config.interval)?;
+            let peers = peers.map(|multi_address| {
+                Ok(DiscoveredPeer {
+                    multi_address,
+                    mechanism: Mechanism::Mdns,
+                })
+            });
+            streams.push(peers.boxed());
+        }
+        Ok(Self {
+            peers: stream::select_all(streams).boxed(),
+        })
+    }
+}
+
+impl Stream for PeerDiscovery {
+    type Item = anyhow::Result<DiscoveredPeer>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.peers.poll_next_unpin(cx)
+    }
+}
+
+/// Continuously announce `multi_address` by broadcasting it over the local network.
+///
+/// See [Announcer] for details. Fails only if the network interfaces cannot be listed initially.
+pub async fn announce(
+    multi_address: &crate::multi_address::MultiAddress,
+    port: u16,
+    interval: std::time::Duration,
+) -> anyhow::Result<()> {
+    Announcer::new(multi_address, port, interval).run().await
+}
+
+/// State of announcements on a network interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceStatus {
+    pub interface: String,
+    pub address: std::net::Ipv4Addr,
+    /// Time of the last successful announcement
+    pub last_sent: Option<std::time::Instant>,
+    /// Error of the last attempt to bind the socket or to send the announcement. Cleared when an
+    /// announcement succeeds.
+    pub error: Option<String>,
+}
+
+/// Broadcasts a multi address via UDP on every IPv4 enabled interface.
+///
+/// The interfaces are listed again before every announcement so that interfaces that come up
+/// later, for example after a wifi reconnect, are used and interfaces that disappear are
+/// dropped. If a socket fails to send it is bound again before the next announcement. Errors
+/// are logged and reported by [Announcer::status] but do not stop the announcements.
+///
+/// Cloning returns a handle to the same status.
+#[derive(Debug, Clone)]
+pub struct Announcer {
+    multi_address: String,
+    port: u16,
+    interval: std::time::Duration,
+    status: std::sync::Arc<std::sync::Mutex<Vec<InterfaceStatus>>>,
+}
+
+/// Interface that announcements are sent on.
+#[derive(Debug)]
+struct Interface {
+    /// `None` if binding failed or the last send failed.
+    socket: Option<async_std::net::UdpSocket>,
+    status: InterfaceStatus,
+}
+
+impl Announcer {
+    pub fn new(
+        multi_address: &crate::multi_address::MultiAddress,
+        port: u16,
+        interval: std::time::Duration,
+    ) -> Self {
+        Self {
+            multi_address: multi_address.to_string(),
+            port,
+            interval,
+            status: Default::default(),
+        }
+    }
+
+    /// Status of the interfaces as of the last announcement ordered by interface name.
+    pub fn status(&self) -> Vec<InterfaceStatus> {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Announce the multi address every `interval`. Runs forever unless the network interfaces
+    /// cannot be listed the first time.
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let broadcast_address =
+            std::net::SocketAddrV4::new(std::net::Ipv4Addr::BROADCAST, self.port);
+        let mut interfaces = HashMap::new();
+        let mut first = true;
+        loop {
+            match interface_addresses_ipv4() {
+                Ok(addresses) => self.update_interfaces(&mut interfaces, addresses),
+                Err(error) if first => return Err(error),
+                Err(error) => tracing::warn!(?error, "failed to list network interfaces"),
+            }
+            first = false;
+
+            for interface in interfaces.values_mut() {
+                let socket = match &interface.socket {
+                    Some(socket) => socket,
+                    None => continue,
+                };
+                let result = socket
+                    .send_to(self.multi_address.as_ref(), &broadcast_address)
+                    .await;
+                match result {
+                    Ok(_) => {
+                        interface.status.last_sent = Some(std::time::Instant::now());
+                        interface.status.error = None;
+                    }
+                    Err(error) => {
+                        tracing::warn!(
+                            interface = %interface.status.interface,
+                            address = %interface.status.address,
+                            ?error,
+                            "failed to send announcement"
+                        );
+                        interface.status.error = Some(error.to_string());
+                        interface.socket = None;
+                    }
+                }
+            }
+
+            let mut status = interfaces
+                .values()
+                .map(|interface| interface.status.clone())
+                .collect::<Vec<_>>();
+            status.sort_by(|a, b| (&a.interface, a.address).cmp(&(&b.interface, b.address)));
+            *self.status.lock().unwrap() = status;
+
+            async_std::task::sleep(self.interval).await;
+        }
+    }
+
+    /// Drop interfaces that are not in `addresses`, add new ones and bind sockets for interfaces
+    /// that do not have one.
+    fn update_interfaces(
+        &self,
+        interfaces: &mut HashMap<std::net::Ipv4Addr, Interface>,
+        addresses: Vec<(String, std::net::Ipv4Addr)>,
+    ) {
+        interfaces.retain(|address, _| addresses.iter().any(|(_, other)| other == address));
+        for (name, address) in addresses {
+            let interface = interfaces.entry(address).or_insert_with(|| Interface {
+                socket: None,
+                status: InterfaceStatus {
+                    interface: name,
+                    address,
+                    last_sent: None,
+                    error: None,
+                },
+            });
+            if interface.socket.is_some() {
+                continue;
+            }
+            match broadcast_socket(std::net::SocketAddrV4::new(address, self.port)) {
+                Ok(socket) => interface.socket = Some(socket),
+                Err(error) => {
+                    tracing::warn!(%address, ?error, "failed to bind announcement socket");
+                    interface.status.error = Some(error.to_string());
+                }
+            }
+        }
+    }
+}
+
+/// Create a UPD socket that binds to the given address and can broadcast.
+///
+/// `SO_REUSEADDR` is set so that the bind address can be reused.
+fn broadcast_socket(addr: std::net::SocketAddrV4) -> std::io::Result<async_std::net::UdpSocket> {
+    let socket = socket2::Socket::new(socket2::Domain::ipv4(), socket2::Type::dgram(), None)?;
+    socket.set_broadcast(true)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&socket2::SockAddr::from(addr))?;
+    Ok(socket.into_udp_socket().into())
+}
+
+/// Get the names and IPv4 addresses of all network interfaces.
+fn interface_addresses_ipv4() -> anyhow::Result<Vec<(String, std::net::Ipv4Addr)>> {
+    let addresses = nix::ifaddrs::getifaddrs()?.filter_map(move |interface| {
+        if let Some(nix::sys::socket::SockAddr::Inet(addr)) = interface.address {
+            match addr.to_std() {
+                std::net::SocketAddr::V4(addr) => Some((interface.interface_name, *addr.ip())),
+                std::net::SocketAddr::V6(_) => None,
+            }
+        } else {
+            None
+        }
+    });
+    Ok(addresses.collect())
+}
+
+/// Listen for multi address broadcast announcements on the given port and return
+/// a stream of announcements.
+pub fn discover(
+    port: u16,
+) -> std::io::Result<impl Stream<Item = anyhow::Result<crate::multi_address::MultiAddress>>> {
+    let socket = broadcast_listen_socket(port)?;
+    let stream = upd_socket_stream(socket).map(|bytes_result| {
+        let bytes = bytes_result?;
+        let data = String::from_utf8(bytes)?;
+        let multi_address = data.parse()?;
+        Ok(multi_address)
+    });
+    Ok(stream)
+}
+
+/// Creates a IPv4 UDP socket that listens for broadcast messages on all interfaces
+fn broadcast_listen_socket(port: u16) -> std::io::Result<async_std::net::UdpSocket> {
+    let socket = socket2::Socket::new(socket2::Domain::ipv4(), socket2::Type::dgram(), None)?;
+    let bind_addr = std::net::SocketAddrV4::new(std::net::Ipv4Addr::BROADCAST, port);
+    socket.set_reuse_address(true)?;
+    socket.bind(&bind_addr.into())?;
+    Ok(socket.into_udp_socket().into())
+}
+
+fn upd_socket_stream(
+    socket: async_std::net::UdpSocket,
+) -> impl Stream<Item = std::io::Result<Vec<u8>>> {
+    let socket = std::sync::Arc::new(socket);
+    futures::stream::repeat(socket).flat_map(|socket| {
+        async move {
+            let mut buf = vec![0u8; 2048];
+            match socket.recv(&mut buf).await {
+                Ok(size) => {
+                    buf.resize(size, 0);
+                    Ok(buf)
+                }
+                Err(err) => Err(err),
+            }
+        }
+        .into_stream()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn update_interfaces() {
+        let multi_address = "net:127.0.0.1:8008~shs:FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY="
+            .parse()
+            .unwrap();
+        let announcer = Announcer::new(&multi_address, 0, std::time::Duration::from_secs(1));
+        let loopback = std::net::Ipv4Addr::LOCALHOST;
+        let mut interfaces = HashMap::new();
+
+        announcer.update_interfaces(&mut interfaces, vec![("lo".to_string(), loopback)]);
+        assert_eq!(interfaces.len(), 1);
+        let interface = &interfaces[&loopback];
+        assert!(interface.socket.is_some());
+        assert_eq!(interface.status.interface, "lo");
+
+        // The address 192.0.2.1 is reserved for documentation and cannot be bound.
+        let unavailable = std::net::Ipv4Addr::new(192, 0, 2, 1);
+        announcer.update_interfaces(&mut interfaces, vec![("eth0".to_string(), unavailable)]);
+        assert_eq!(interfaces.len(), 1);
+        let interface = &interfaces[&unavailable];
+        assert!(interface.socket.is_none());
+        assert!(interface.status.error.is_some());
+    }
+}
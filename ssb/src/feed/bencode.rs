@@ -0,0 +1,194 @@
+//! Minimal [bencode] implementation used by the bendy-butt feed format.
+//!
+//! Decoding is strict and only accepts the canonical encoding of a value. This makes sure that
+//! re-encoding a decoded message yields the exact bytes that were signed.
+//!
+//! [bencode]: https://www.bittorrent.org/beps/bep_0003.html#bencoding
+
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Integer(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid bencode at byte {position}: {reason}")]
+pub struct DecodeError {
+    pub position: usize,
+    pub reason: &'static str,
+}
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut output = Vec::new();
+    encode_into(value, &mut output);
+    output
+}
+
+fn encode_into(value: &Value, output: &mut Vec<u8>) {
+    match value {
+        Value::Integer(integer) => {
+            output.extend_from_slice(format!("i{}e", integer).as_bytes());
+        }
+        Value::Bytes(bytes) => encode_bytes(bytes, output),
+        Value::List(items) => {
+            output.push(b'l');
+            for item in items {
+                encode_into(item, output);
+            }
+            output.push(b'e');
+        }
+        Value::Dict(entries) => {
+            output.push(b'd');
+            for (key, value) in entries {
+                encode_bytes(key, output);
+                encode_into(value, output);
+            }
+            output.push(b'e');
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], output: &mut Vec<u8>) {
+    output.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+    output.extend_from_slice(bytes);
+}
+
+/// Decode a single value that must span all of `data`.
+pub fn decode(data: &[u8]) -> Result<Value, DecodeError> {
+    let mut decoder = Decoder { data, position: 0 };
+    let value = decoder.value()?;
+    if decoder.position != data.len() {
+        return Err(decoder.error("trailing data"));
+    }
+    Ok(value)
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn error(&self, reason: &'static str) -> DecodeError {
+        DecodeError {
+            position: self.position,
+            reason,
+        }
+    }
+
+    fn peek(&self) -> Result<u8, DecodeError> {
+        self.data
+            .get(self.position)
+            .copied()
+            .ok_or_else(|| self.error("unexpected end of data"))
+    }
+
+    fn value(&mut self) -> Result<Value, DecodeError> {
+        match self.peek()? {
+            b'i' => {
+                self.position += 1;
+                let digits = self.digits_until(b'e')?;
+                let integer = digits
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|integer| integer.to_string() == digits)
+                    .ok_or_else(|| self.error("invalid integer"))?;
+                Ok(Value::Integer(integer))
+            }
+            b'l' => {
+                self.position += 1;
+                let mut items = Vec::new();
+                while self.peek()? != b'e' {
+                    items.push(self.value()?);
+                }
+                self.position += 1;
+                Ok(Value::List(items))
+            }
+            b'd' => {
+                self.position += 1;
+                let mut entries = BTreeMap::new();
+                let mut last_key: Option<Vec<u8>> = None;
+                while self.peek()? != b'e' {
+                    let key = self.bytes()?;
+                    if last_key.as_ref().is_some_and(|last_key| *last_key >= key) {
+                        return Err(self.error("dictionary keys are not sorted"));
+                    }
+                    last_key = Some(key.clone());
+                    let value = self.value()?;
+                    entries.insert(key, value);
+                }
+                self.position += 1;
+                Ok(Value::Dict(entries))
+            }
+            b'0'..=b'9' => Ok(Value::Bytes(self.bytes()?)),
+            _ => Err(self.error("unexpected byte")),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let digits = self.digits_until(b':')?;
+        let length = digits
+            .parse::<usize>()
+            .ok()
+            .filter(|length| length.to_string() == digits)
+            .ok_or_else(|| self.error("invalid length"))?;
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| self.error("unexpected end of data"))?;
+        let bytes = self.data[self.position..end].to_vec();
+        self.position = end;
+        Ok(bytes)
+    }
+
+    /// Read the ASCII text up to `terminator` and skip the terminator.
+    fn digits_until(&mut self, terminator: u8) -> Result<&'a str, DecodeError> {
+        let data: &'a [u8] = self.data;
+        let start = self.position;
+        let length = data[start..]
+            .iter()
+            .position(|byte| *byte == terminator)
+            .ok_or_else(|| self.error("unexpected end of data"))?;
+        self.position = start + length + 1;
+        std::str::from_utf8(&data[start..start + length]).map_err(|_| self.error("invalid number"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut dict = BTreeMap::new();
+        dict.insert(b"a".to_vec(), Value::Integer(-3));
+        dict.insert(b"b".to_vec(), Value::Bytes(b"spam".to_vec()));
+        let value = Value::List(vec![Value::Dict(dict), Value::Integer(0)]);
+        let encoded = encode(&value);
+        assert_eq!(encoded, b"ld1:ai-3e1:b4:spamei0ee");
+        assert_eq!(decode(&encoded), Ok(value));
+    }
+
+    #[test]
+    fn reject_non_canonical() {
+        for data in &[
+            &b"i03e"[..],
+            b"i-0e",
+            b"i+1e",
+            b"ie",
+            b"03:abc",
+            b"d1:bi1e1:ai2ee",
+            b"d1:ai1e1:ai2ee",
+            b"i1ei2e",
+            b"l",
+            b"5:abc",
+        ] {
+            assert!(decode(data).is_err(), "{:?}", String::from_utf8_lossy(data));
+        }
+    }
+}
@@ -0,0 +1,437 @@
+//! Messages in the [bendy-butt][spec] format that is used by metafeeds.
+//!
+//! A message is the bencoded list `[payload, signature]`. The payload is the list `[author,
+//! sequence, previous, timestamp, content_section]` with all references encoded as [Bfe] values.
+//! The author signs the bencoded payload. The content section is either the list `[content,
+//! content_signature]` where the content is signed by the subfeed it refers to, or encrypted
+//! content.
+//!
+//! ```rust
+//! # use std::collections::BTreeMap;
+//! # use ssb::crypto::sign::KeyPair;
+//! # use ssb::feed::bendy_butt::{sign, validate, MetafeedId};
+//! # use ssb::feed::bfe::{Bfe, FeedRef};
+//! # use ssb::feed::FeedId;
+//! let metafeed = KeyPair::gen();
+//! let subfeed = KeyPair::gen();
+//! let mut content = BTreeMap::new();
+//! content.insert("type".to_string(), Bfe::String("metafeed/add/existing".to_string()));
+//! content.insert("subfeed".to_string(), Bfe::Feed(FeedRef::Classic(FeedId(subfeed.public))));
+//! content.insert("metafeed".to_string(), Bfe::Feed(FeedRef::BendyButt(MetafeedId(metafeed.public))));
+//! content.insert("feedpurpose".to_string(), Bfe::String("main".to_string()));
+//!
+//! let message = sign(&metafeed, &subfeed, None, 1_638_000_000_000, content);
+//! assert_eq!(validate(None, &message.data).unwrap(), message);
+//! ```
+//!
+//! [spec]: https://github.com/ssbc/bendy-butt-spec
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use super::bencode;
+use super::bfe::{Bfe, BfeError, FeedRef, MessageRef};
+use super::{parse_uri, IdParseError};
+use crate::crypto::sign::{self, KeyPair, PublicKey};
+
+/// Maximum length of an encoded message in bytes.
+pub const MAX_MESSAGE_LENGTH: usize = 8192;
+
+/// Prefix of the data that is signed by the content signature.
+const CONTENT_SIGNATURE_PREFIX: &[u8] = b"bendybutt";
+
+/// Identifies a metafeed, for example
+/// `ssb:feed/bendybutt-v1/FCX_tsDLpubCPKKfIrw4gc-SQkHcaD17s7GI6i_ziWY`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MetafeedId(pub PublicKey);
+
+impl MetafeedId {
+    pub fn public_key(&self) -> &PublicKey {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for MetafeedId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ssb:feed/bendybutt-v1/{}",
+            base64::encode_config(self.0, base64::URL_SAFE_NO_PAD)
+        )
+    }
+}
+
+impl std::fmt::Debug for MetafeedId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("MetafeedId")
+            .field(&self.to_string())
+            .finish()
+    }
+}
+
+impl std::str::FromStr for MetafeedId {
+    type Err = IdParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = parse_uri(s, "ssb:feed/bendybutt-v1/")?;
+        let public_key =
+            PublicKey::from_slice(&bytes).ok_or_else(|| IdParseError { id: s.to_string() })?;
+        Ok(Self(public_key))
+    }
+}
+
+/// Identifies a bendy-butt message by the hash of its encoding, for example
+/// `ssb:message/bendybutt-v1/R7lJEkz27lNijPhYNDzYoPjM0Fp-bFWzwX0SmNJB_ZE`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BendyButtMessageId(pub [u8; 32]);
+
+impl std::fmt::Display for BendyButtMessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ssb:message/bendybutt-v1/{}",
+            base64::encode_config(self.0, base64::URL_SAFE_NO_PAD)
+        )
+    }
+}
+
+impl std::fmt::Debug for BendyButtMessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("BendyButtMessageId")
+            .field(&self.to_string())
+            .finish()
+    }
+}
+
+impl std::str::FromStr for BendyButtMessageId {
+    type Err = IdParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = parse_uri(s, "ssb:message/bendybutt-v1/")?;
+        let hash = <[u8; 32]>::try_from(bytes.as_slice())
+            .map_err(|_| IdParseError { id: s.to_string() })?;
+        Ok(Self(hash))
+    }
+}
+
+impl_serde_via_string!(MetafeedId);
+impl_serde_via_string!(BendyButtMessageId);
+
+/// A validated bendy-butt message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BendyButtMessage {
+    pub key: BendyButtMessageId,
+    pub author: MetafeedId,
+    pub sequence: u64,
+    pub previous: Option<BendyButtMessageId>,
+    /// Time in milliseconds since the Unix epoch claimed by the author.
+    pub timestamp: i64,
+    pub content: Content,
+    /// The encoded message as it was signed.
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Content {
+    /// Content that was signed by the subfeed it refers to.
+    Signed(BTreeMap<String, Bfe>),
+    /// Encrypted content. See [Bfe::Box] for the formats.
+    Encrypted { format: u8, data: Vec<u8> },
+}
+
+impl Content {
+    /// Returns the `type` field of the content if the content is not encrypted.
+    pub fn content_type(&self) -> Option<&str> {
+        match self {
+            Self::Signed(content) => match content.get("type")? {
+                Bfe::String(type_) => Some(type_),
+                _ => None,
+            },
+            Self::Encrypted { .. } => None,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum BendyButtError {
+    #[error(transparent)]
+    Bfe(#[from] BfeError),
+    #[error("Invalid message structure: {reason}")]
+    Structure { reason: &'static str },
+    #[error("Message is longer than {} bytes", MAX_MESSAGE_LENGTH)]
+    TooLarge,
+    #[error("Expected sequence number {expected}, got {actual}")]
+    Sequence { expected: u64, actual: u64 },
+    #[error("Previous message ID does not match the latest message of the feed")]
+    Previous,
+    #[error("Author does not match the author of the previous message")]
+    Author,
+    #[error("Invalid signature")]
+    Signature,
+    #[error("Invalid content signature")]
+    ContentSignature,
+}
+
+/// Decode a message and verify its signatures.
+///
+/// Does not check that the message follows the previous message of the feed. Use [validate] for
+/// this.
+pub fn decode(data: &[u8]) -> Result<BendyButtMessage, BendyButtError> {
+    let structure = |reason| BendyButtError::Structure { reason };
+    if data.len() > MAX_MESSAGE_LENGTH {
+        return Err(BendyButtError::TooLarge);
+    }
+    let message = bencode::decode(data).map_err(BfeError::from)?;
+    let (payload, signature) = match message {
+        bencode::Value::List(items) if items.len() == 2 => {
+            let mut items = items.into_iter();
+            (items.next().unwrap(), items.next().unwrap())
+        }
+        _ => return Err(structure("expected a list of payload and signature")),
+    };
+    let signature = match Bfe::from_bencode(signature)? {
+        Bfe::Signature(signature) => signature,
+        _ => return Err(structure("expected a signature")),
+    };
+    let payload_data = bencode::encode(&payload);
+
+    let fields = match Bfe::from_bencode(payload)? {
+        Bfe::List(fields) if fields.len() == 5 => fields,
+        _ => return Err(structure("expected a payload with five fields")),
+    };
+    let mut fields = fields.into_iter();
+    let author = match fields.next().unwrap() {
+        Bfe::Feed(FeedRef::BendyButt(author)) => author,
+        _ => return Err(structure("author must be a bendy-butt feed")),
+    };
+    let sequence = match fields.next().unwrap() {
+        Bfe::Integer(sequence) if sequence > 0 => sequence as u64,
+        _ => return Err(structure("sequence must be a positive integer")),
+    };
+    let previous = match fields.next().unwrap() {
+        Bfe::Message(MessageRef::BendyButt(previous)) => Some(previous),
+        Bfe::Nil => None,
+        _ => return Err(structure("previous must be a bendy-butt message or nil")),
+    };
+    let timestamp = match fields.next().unwrap() {
+        Bfe::Integer(timestamp) => timestamp,
+        _ => return Err(structure("timestamp must be an integer")),
+    };
+    let content = match fields.next().unwrap() {
+        Bfe::List(section) if section.len() == 2 => {
+            let mut section = section.into_iter();
+            match (section.next().unwrap(), section.next().unwrap()) {
+                (Bfe::Dict(content), Bfe::Signature(content_signature)) => {
+                    verify_content(&content, &content_signature)?;
+                    Content::Signed(content)
+                }
+                _ => return Err(structure("expected content and content signature")),
+            }
+        }
+        Bfe::Box { format, data } => Content::Encrypted { format, data },
+        _ => return Err(structure("invalid content section")),
+    };
+
+    let signature = sign::Signature::from_slice(&signature).ok_or(BendyButtError::Signature)?;
+    if !sign::verify_detached(&signature, &payload_data, author.public_key()) {
+        return Err(BendyButtError::Signature);
+    }
+
+    Ok(BendyButtMessage {
+        key: BendyButtMessageId(crate::crypto::hash(data)),
+        author,
+        sequence,
+        previous,
+        timestamp,
+        content,
+        data: data.to_vec(),
+    })
+}
+
+/// The content must be signed by the feed referenced in its `subfeed` field.
+fn verify_content(
+    content: &BTreeMap<String, Bfe>,
+    content_signature: &[u8],
+) -> Result<(), BendyButtError> {
+    let subfeed = match content.get("subfeed") {
+        Some(Bfe::Feed(subfeed)) => *subfeed,
+        _ => return Err(BendyButtError::ContentSignature),
+    };
+    let signature =
+        sign::Signature::from_slice(content_signature).ok_or(BendyButtError::ContentSignature)?;
+    if sign::verify_detached(
+        &signature,
+        &content_signature_data(content),
+        subfeed.public_key(),
+    ) {
+        Ok(())
+    } else {
+        Err(BendyButtError::ContentSignature)
+    }
+}
+
+fn content_signature_data(content: &BTreeMap<String, Bfe>) -> Vec<u8> {
+    let mut data = CONTENT_SIGNATURE_PREFIX.to_vec();
+    data.extend(Bfe::Dict(content.clone()).encode());
+    data
+}
+
+/// Decode `data` as the message that follows `previous` in a metafeed.
+///
+/// `previous` must be `None` if `data` is the first message of the feed.
+pub fn validate(
+    previous: Option<&BendyButtMessage>,
+    data: &[u8],
+) -> Result<BendyButtMessage, BendyButtError> {
+    let message = decode(data)?;
+    match previous {
+        Some(previous) => {
+            let expected = previous.sequence + 1;
+            if message.sequence != expected {
+                return Err(BendyButtError::Sequence {
+                    expected,
+                    actual: message.sequence,
+                });
+            }
+            if message.previous != Some(previous.key) {
+                return Err(BendyButtError::Previous);
+            }
+            if message.author != previous.author {
+                return Err(BendyButtError::Author);
+            }
+        }
+        None => {
+            if message.sequence != 1 {
+                return Err(BendyButtError::Sequence {
+                    expected: 1,
+                    actual: message.sequence,
+                });
+            }
+            if message.previous.is_some() {
+                return Err(BendyButtError::Previous);
+            }
+        }
+    }
+    Ok(message)
+}
+
+/// Create and sign the message that follows `previous` in the metafeed of `keypair`.
+///
+/// The content is signed with `subfeed_keypair`, which must be the key of the feed referenced
+/// in the `subfeed` field of the content.
+pub fn sign(
+    keypair: &KeyPair,
+    subfeed_keypair: &KeyPair,
+    previous: Option<&BendyButtMessage>,
+    timestamp: i64,
+    content: BTreeMap<String, Bfe>,
+) -> BendyButtMessage {
+    let content_signature =
+        sign::sign_detached(&content_signature_data(&content), &subfeed_keypair.secret);
+    let sequence = previous.map_or(1, |previous| previous.sequence + 1);
+    let payload = Bfe::List(vec![
+        Bfe::Feed(FeedRef::BendyButt(MetafeedId(keypair.public))),
+        Bfe::Integer(sequence as i64),
+        previous.map_or(Bfe::Nil, |previous| {
+            Bfe::Message(MessageRef::BendyButt(previous.key))
+        }),
+        Bfe::Integer(timestamp),
+        Bfe::List(vec![
+            Bfe::Dict(content.clone()),
+            Bfe::Signature(content_signature.as_ref().to_vec()),
+        ]),
+    ]);
+    let signature = sign::sign_detached(&payload.encode(), &keypair.secret);
+    let data = Bfe::List(vec![payload, Bfe::Signature(signature.as_ref().to_vec())]).encode();
+
+    BendyButtMessage {
+        key: BendyButtMessageId(crate::crypto::hash(&data)),
+        author: MetafeedId(keypair.public),
+        sequence,
+        previous: previous.map(|previous| previous.key),
+        timestamp,
+        content: Content::Signed(content),
+        data,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::feed::FeedId;
+
+    fn content(subfeed: &KeyPair) -> BTreeMap<String, Bfe> {
+        let mut content = BTreeMap::new();
+        content.insert(
+            "type".to_string(),
+            Bfe::String("metafeed/add/existing".to_string()),
+        );
+        content.insert(
+            "subfeed".to_string(),
+            Bfe::Feed(FeedRef::Classic(FeedId(subfeed.public))),
+        );
+        content
+    }
+
+    #[test]
+    fn id_roundtrip() {
+        let metafeed = MetafeedId(KeyPair::gen().public);
+        assert_eq!(
+            metafeed.to_string().parse::<MetafeedId>().unwrap(),
+            metafeed
+        );
+        let message = BendyButtMessageId([3; 32]);
+        assert_eq!(
+            message.to_string().parse::<BendyButtMessageId>().unwrap(),
+            message
+        );
+        assert!("ssb:feed/bendybutt-v1/AAAA".parse::<MetafeedId>().is_err());
+    }
+
+    #[test]
+    fn validate_chain() {
+        let keypair = KeyPair::gen();
+        let subfeed = KeyPair::gen();
+        let first = sign(&keypair, &subfeed, None, 1, content(&subfeed));
+        let second = sign(&keypair, &subfeed, Some(&first), 2, content(&subfeed));
+
+        assert_eq!(validate(None, &first.data), Ok(first.clone()));
+        assert_eq!(validate(Some(&first), &second.data), Ok(second.clone()));
+        assert_eq!(
+            validate(None, &second.data),
+            Err(BendyButtError::Sequence {
+                expected: 1,
+                actual: 2
+            })
+        );
+
+        let fork = sign(&keypair, &subfeed, Some(&first), 9, content(&subfeed));
+        let third = sign(&keypair, &subfeed, Some(&fork), 3, content(&subfeed));
+        assert_eq!(
+            validate(Some(&second), &third.data),
+            Err(BendyButtError::Previous)
+        );
+    }
+
+    #[test]
+    fn verify_signatures() {
+        let keypair = KeyPair::gen();
+        let subfeed = KeyPair::gen();
+
+        let wrong_subfeed = sign(&keypair, &KeyPair::gen(), None, 1, content(&subfeed));
+        assert_eq!(
+            decode(&wrong_subfeed.data),
+            Err(BendyButtError::ContentSignature)
+        );
+
+        let message = sign(&keypair, &subfeed, None, 12345, content(&subfeed));
+        let position = message
+            .data
+            .windows(7)
+            .position(|window| window == b"i12345e")
+            .unwrap();
+        let mut tampered = message.data;
+        tampered[position + 5] = b'6';
+        assert_eq!(decode(&tampered), Err(BendyButtError::Signature));
+    }
+}
@@ -0,0 +1,284 @@
+//! [Binary field encoding][bfe] (BFE) used by binary feed formats like bendy-butt.
+//!
+//! A BFE value is a byte string that starts with a type byte and a format byte followed by the
+//! data. Integers, lists and dictionaries are encoded as plain bencode values.
+//!
+//! [bfe]: https://github.com/ssbc/ssb-binary-field-encodings-spec
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use super::bencode;
+use super::bendy_butt::{BendyButtMessageId, MetafeedId};
+use super::buttwoo::{ButtwooFeedId, ButtwooMessageId};
+use super::{FeedId, MessageId};
+use crate::crypto::sign::PublicKey;
+
+const TYPE_FEED: u8 = 0;
+const TYPE_MESSAGE: u8 = 1;
+const TYPE_BLOB: u8 = 2;
+const TYPE_SIGNATURE: u8 = 4;
+const TYPE_BOX: u8 = 5;
+const TYPE_GENERIC: u8 = 6;
+
+const FORMAT_CLASSIC: u8 = 0;
+const FEED_FORMAT_BENDY_BUTT: u8 = 3;
+const FEED_FORMAT_BUTTWOO: u8 = 4;
+/// Message formats are numbered differently from feed formats because of cloaked messages.
+const MESSAGE_FORMAT_BENDY_BUTT: u8 = 4;
+const MESSAGE_FORMAT_BUTTWOO: u8 = 5;
+
+const FORMAT_STRING: u8 = 0;
+const FORMAT_BOOL: u8 = 1;
+const FORMAT_NIL: u8 = 2;
+const FORMAT_BYTES: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bfe {
+    Feed(FeedRef),
+    Message(MessageRef),
+    /// SHA256 hash of a classic blob
+    Blob([u8; 32]),
+    /// Ed25519 signature
+    Signature(Vec<u8>),
+    /// Encrypted data. The format is `0` for box and `1` for box2.
+    Box {
+        format: u8,
+        data: Vec<u8>,
+    },
+    String(String),
+    Bool(bool),
+    Nil,
+    Bytes(Vec<u8>),
+    Integer(i64),
+    List(Vec<Bfe>),
+    Dict(BTreeMap<String, Bfe>),
+}
+
+/// Reference to a feed of any supported format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FeedRef {
+    Classic(FeedId),
+    BendyButt(MetafeedId),
+    Buttwoo(ButtwooFeedId),
+}
+
+impl FeedRef {
+    pub fn public_key(&self) -> &PublicKey {
+        match self {
+            Self::Classic(feed) => feed.public_key(),
+            Self::BendyButt(metafeed) => metafeed.public_key(),
+            Self::Buttwoo(feed) => feed.public_key(),
+        }
+    }
+}
+
+/// Reference to a message of any supported format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum MessageRef {
+    Classic(MessageId),
+    BendyButt(BendyButtMessageId),
+    Buttwoo(ButtwooMessageId),
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum BfeError {
+    #[error(transparent)]
+    Bencode(#[from] bencode::DecodeError),
+    #[error("Unsupported BFE type {type_} with format {format}")]
+    Unsupported { type_: u8, format: u8 },
+    #[error("Invalid data for BFE type {type_} with format {format}")]
+    InvalidData { type_: u8, format: u8 },
+    #[error("BFE value is shorter than two bytes")]
+    Truncated,
+    #[error("Dictionary key is not valid UTF-8")]
+    DictKey,
+}
+
+impl Bfe {
+    /// Encode the value as bencode.
+    pub fn encode(&self) -> Vec<u8> {
+        bencode::encode(&self.to_bencode())
+    }
+
+    /// Decode a bencoded value.
+    pub fn decode(data: &[u8]) -> Result<Self, BfeError> {
+        Self::from_bencode(bencode::decode(data)?)
+    }
+
+    pub(crate) fn to_bencode(&self) -> bencode::Value {
+        match self {
+            Self::Integer(integer) => bencode::Value::Integer(*integer),
+            Self::List(items) => bencode::Value::List(items.iter().map(Self::to_bencode).collect()),
+            Self::Dict(entries) => bencode::Value::Dict(
+                entries
+                    .iter()
+                    .map(|(key, value)| (key.as_bytes().to_vec(), value.to_bencode()))
+                    .collect(),
+            ),
+            value => bencode::Value::Bytes(value.to_tagged().unwrap()),
+        }
+    }
+
+    /// Encode the value as its type and format byte followed by the data.
+    ///
+    /// Returns `None` for integers, lists and dictionaries which are not tagged.
+    pub(crate) fn to_tagged(&self) -> Option<Vec<u8>> {
+        let tagged = |type_: u8, format: u8, data: &[u8]| {
+            let mut bytes = vec![type_, format];
+            bytes.extend_from_slice(data);
+            Some(bytes)
+        };
+        match self {
+            Self::Feed(FeedRef::Classic(feed)) => {
+                tagged(TYPE_FEED, FORMAT_CLASSIC, feed.public_key().as_ref())
+            }
+            Self::Feed(FeedRef::BendyButt(metafeed)) => tagged(
+                TYPE_FEED,
+                FEED_FORMAT_BENDY_BUTT,
+                metafeed.public_key().as_ref(),
+            ),
+            Self::Feed(FeedRef::Buttwoo(feed)) => {
+                tagged(TYPE_FEED, FEED_FORMAT_BUTTWOO, feed.public_key().as_ref())
+            }
+            Self::Message(MessageRef::Classic(message)) => {
+                tagged(TYPE_MESSAGE, FORMAT_CLASSIC, &message.0)
+            }
+            Self::Message(MessageRef::BendyButt(message)) => {
+                tagged(TYPE_MESSAGE, MESSAGE_FORMAT_BENDY_BUTT, &message.0)
+            }
+            Self::Message(MessageRef::Buttwoo(message)) => {
+                tagged(TYPE_MESSAGE, MESSAGE_FORMAT_BUTTWOO, &message.0)
+            }
+            Self::Blob(hash) => tagged(TYPE_BLOB, FORMAT_CLASSIC, hash),
+            Self::Signature(signature) => tagged(TYPE_SIGNATURE, 0, signature),
+            Self::Box { format, data } => tagged(TYPE_BOX, *format, data),
+            Self::String(string) => tagged(TYPE_GENERIC, FORMAT_STRING, string.as_bytes()),
+            Self::Bool(value) => tagged(TYPE_GENERIC, FORMAT_BOOL, &[*value as u8]),
+            Self::Nil => tagged(TYPE_GENERIC, FORMAT_NIL, &[]),
+            Self::Bytes(bytes) => tagged(TYPE_GENERIC, FORMAT_BYTES, bytes),
+            Self::Integer(_) | Self::List(_) | Self::Dict(_) => None,
+        }
+    }
+
+    pub(crate) fn from_bencode(value: bencode::Value) -> Result<Self, BfeError> {
+        match value {
+            bencode::Value::Integer(integer) => Ok(Self::Integer(integer)),
+            bencode::Value::List(items) => Ok(Self::List(
+                items
+                    .into_iter()
+                    .map(Self::from_bencode)
+                    .collect::<Result<_, _>>()?,
+            )),
+            bencode::Value::Dict(entries) => Ok(Self::Dict(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| {
+                        let key = String::from_utf8(key).map_err(|_| BfeError::DictKey)?;
+                        Ok((key, Self::from_bencode(value)?))
+                    })
+                    .collect::<Result<_, BfeError>>()?,
+            )),
+            bencode::Value::Bytes(bytes) => Self::from_tagged(&bytes),
+        }
+    }
+
+    /// Decode a value that starts with a type and format byte.
+    pub(crate) fn from_tagged(bytes: &[u8]) -> Result<Self, BfeError> {
+        let (type_, format, data) = match bytes {
+            [type_, format, data @ ..] => (*type_, *format, data),
+            _ => return Err(BfeError::Truncated),
+        };
+        let invalid = || BfeError::InvalidData { type_, format };
+        let key = || PublicKey::from_slice(data).ok_or_else(invalid);
+        let hash = || <[u8; 32]>::try_from(data).map_err(|_| invalid());
+        let value = match (type_, format) {
+            (TYPE_FEED, FORMAT_CLASSIC) => Self::Feed(FeedRef::Classic(FeedId(key()?))),
+            (TYPE_FEED, FEED_FORMAT_BENDY_BUTT) => {
+                Self::Feed(FeedRef::BendyButt(MetafeedId(key()?)))
+            }
+            (TYPE_FEED, FEED_FORMAT_BUTTWOO) => Self::Feed(FeedRef::Buttwoo(ButtwooFeedId(key()?))),
+            (TYPE_MESSAGE, FORMAT_CLASSIC) => {
+                Self::Message(MessageRef::Classic(MessageId(hash()?)))
+            }
+            (TYPE_MESSAGE, MESSAGE_FORMAT_BENDY_BUTT) => {
+                Self::Message(MessageRef::BendyButt(BendyButtMessageId(hash()?)))
+            }
+            (TYPE_MESSAGE, MESSAGE_FORMAT_BUTTWOO) => {
+                Self::Message(MessageRef::Buttwoo(ButtwooMessageId(hash()?)))
+            }
+            (TYPE_BLOB, FORMAT_CLASSIC) => Self::Blob(hash()?),
+            (TYPE_SIGNATURE, 0) if data.len() == 64 => Self::Signature(data.to_vec()),
+            (TYPE_SIGNATURE, 0) => return Err(invalid()),
+            (TYPE_BOX, 0..=1) => Self::Box {
+                format,
+                data: data.to_vec(),
+            },
+            (TYPE_GENERIC, FORMAT_STRING) => {
+                Self::String(String::from_utf8(data.to_vec()).map_err(|_| invalid())?)
+            }
+            (TYPE_GENERIC, FORMAT_BOOL) => match data {
+                [0] => Self::Bool(false),
+                [1] => Self::Bool(true),
+                _ => return Err(invalid()),
+            },
+            (TYPE_GENERIC, FORMAT_NIL) if data.is_empty() => Self::Nil,
+            (TYPE_GENERIC, FORMAT_NIL) => return Err(invalid()),
+            (TYPE_GENERIC, FORMAT_BYTES) => Self::Bytes(data.to_vec()),
+            _ => return Err(BfeError::Unsupported { type_, format }),
+        };
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::sign::KeyPair;
+
+    #[test]
+    fn roundtrip() {
+        let mut dict = BTreeMap::new();
+        dict.insert(
+            "feed".to_string(),
+            Bfe::Feed(FeedRef::Classic(FeedId(KeyPair::gen().public))),
+        );
+        dict.insert(
+            "metafeed".to_string(),
+            Bfe::Feed(FeedRef::BendyButt(MetafeedId(KeyPair::gen().public))),
+        );
+        dict.insert(
+            "previous".to_string(),
+            Bfe::Message(MessageRef::BendyButt(BendyButtMessageId([7; 32]))),
+        );
+        dict.insert("text".to_string(), Bfe::String("hello".to_string()));
+        dict.insert(
+            "list".to_string(),
+            Bfe::List(vec![Bfe::Nil, Bfe::Bool(true), Bfe::Integer(-5)]),
+        );
+        let value = Bfe::Dict(dict);
+        assert_eq!(Bfe::decode(&value.encode()), Ok(value));
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(Bfe::String("hi".to_string()).encode(), b"4:\x06\x00hi");
+        assert_eq!(Bfe::Nil.encode(), b"2:\x06\x02");
+        let message = Bfe::Message(MessageRef::BendyButt(BendyButtMessageId([0; 32]))).encode();
+        assert_eq!(&message[..5], b"34:\x01\x04");
+        assert_eq!(
+            Bfe::decode(b"2:\x07\x00"),
+            Err(BfeError::Unsupported {
+                type_: 7,
+                format: 0
+            })
+        );
+        assert_eq!(
+            Bfe::decode(b"3:\x00\x00\x01"),
+            Err(BfeError::InvalidData {
+                type_: 0,
+                format: 0
+            })
+        );
+    }
+}
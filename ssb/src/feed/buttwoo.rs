@@ -0,0 +1,383 @@
+//! Messages in the [buttwoo][spec] feed format.
+//!
+//! A message is the [BIPF] array `[value, signature, content]`. The value is itself a BIPF
+//! encoded array `[author, parent, sequence, timestamp, previous, tag, content_length,
+//! content_hash]` which the author signs. Feeds and messages are referenced with [Bfe] values
+//! and the content is hashed with BLAKE3.
+//!
+//! ```rust
+//! # use ssb::crypto::sign::KeyPair;
+//! # use ssb::feed::buttwoo::{sign, validate};
+//! let keypair = KeyPair::gen();
+//! let first = sign(&keypair, None, 1_638_000_000_000.0, serde_json::json!({ "type": "post" }));
+//! let second = sign(&keypair, Some(&first), 1_638_000_001_000.0, serde_json::json!({ "type": "post" }));
+//! assert_eq!(validate(Some(&first), &second.data).unwrap(), second);
+//! ```
+//!
+//! [spec]: https://github.com/ssbc/ssb-buttwoo-spec
+//! [BIPF]: https://github.com/ssbc/bipf-spec
+
+use std::convert::TryFrom;
+
+use super::bfe::{Bfe, FeedRef, MessageRef};
+use super::validate::ValidationError;
+use super::{parse_uri, IdParseError};
+use crate::bipf;
+use crate::crypto::sign::{self, KeyPair, PublicKey};
+
+/// Maximum length of the encoded content in bytes.
+pub const MAX_CONTENT_LENGTH: usize = 16384;
+
+/// Prefix of the content hash that identifies BLAKE3 as the hash function.
+const CONTENT_HASH_BLAKE3: u8 = 0;
+
+/// Identifies a buttwoo feed, for example
+/// `ssb:feed/buttwoo-v1/FCX_tsDLpubCPKKfIrw4gc-SQkHcaD17s7GI6i_ziWY`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ButtwooFeedId(pub PublicKey);
+
+impl ButtwooFeedId {
+    pub fn public_key(&self) -> &PublicKey {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for ButtwooFeedId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ssb:feed/buttwoo-v1/{}",
+            base64::encode_config(self.0, base64::URL_SAFE_NO_PAD)
+        )
+    }
+}
+
+impl std::fmt::Debug for ButtwooFeedId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ButtwooFeedId")
+            .field(&self.to_string())
+            .finish()
+    }
+}
+
+impl std::str::FromStr for ButtwooFeedId {
+    type Err = IdParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = parse_uri(s, "ssb:feed/buttwoo-v1/")?;
+        let public_key =
+            PublicKey::from_slice(&bytes).ok_or_else(|| IdParseError { id: s.to_string() })?;
+        Ok(Self(public_key))
+    }
+}
+
+/// Identifies a buttwoo message by the BLAKE3 hash of its signed value, for example
+/// `ssb:message/buttwoo-v1/R7lJEkz27lNijPhYNDzYoPjM0Fp-bFWzwX0SmNJB_ZE`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ButtwooMessageId(pub [u8; 32]);
+
+impl std::fmt::Display for ButtwooMessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ssb:message/buttwoo-v1/{}",
+            base64::encode_config(self.0, base64::URL_SAFE_NO_PAD)
+        )
+    }
+}
+
+impl std::fmt::Debug for ButtwooMessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ButtwooMessageId")
+            .field(&self.to_string())
+            .finish()
+    }
+}
+
+impl std::str::FromStr for ButtwooMessageId {
+    type Err = IdParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = parse_uri(s, "ssb:message/buttwoo-v1/")?;
+        let hash = <[u8; 32]>::try_from(bytes.as_slice())
+            .map_err(|_| IdParseError { id: s.to_string() })?;
+        Ok(Self(hash))
+    }
+}
+
+impl_serde_via_string!(ButtwooFeedId);
+impl_serde_via_string!(ButtwooMessageId);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tag {
+    Standard,
+    /// The last message of a feed. No messages may follow it.
+    EndOfFeed,
+}
+
+impl Tag {
+    fn byte(self) -> u8 {
+        match self {
+            Self::Standard => 0,
+            Self::EndOfFeed => 1,
+        }
+    }
+}
+
+/// A validated buttwoo message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ButtwooMessage {
+    pub key: ButtwooMessageId,
+    pub author: ButtwooFeedId,
+    /// Message of the parent feed that this feed belongs to.
+    pub parent: Option<ButtwooMessageId>,
+    pub sequence: u64,
+    /// Time in milliseconds since the Unix epoch claimed by the author.
+    pub timestamp: f64,
+    pub previous: Option<ButtwooMessageId>,
+    pub tag: Tag,
+    pub content: serde_json::Value,
+    /// The encoded message as it was received.
+    pub data: Vec<u8>,
+}
+
+/// Decode a message and verify its signature and content hash.
+///
+/// Does not check that the message follows the previous message of the feed. Use [validate] for
+/// this.
+pub fn decode(data: &[u8]) -> Result<ButtwooMessage, ValidationError> {
+    let invalid = |message: &str| ValidationError::Decode {
+        message: message.to_string(),
+    };
+    let decode_bipf = |data: &[u8]| {
+        bipf::decode(data).map_err(|error| ValidationError::Decode {
+            message: error.to_string(),
+        })
+    };
+    let (value_data, signature, content_data) = match decode_bipf(data)? {
+        bipf::Value::Array(items) => match items.as_slice() {
+            [bipf::Value::Buffer(value), bipf::Value::Buffer(signature), bipf::Value::Buffer(content)] => {
+                (value.clone(), signature.clone(), content.clone())
+            }
+            _ => return Err(invalid("expected value, signature and content")),
+        },
+        _ => return Err(invalid("expected an array")),
+    };
+    if content_data.len() > MAX_CONTENT_LENGTH {
+        return Err(ValidationError::TooLarge);
+    }
+
+    let fields = match decode_bipf(&value_data)? {
+        bipf::Value::Array(fields) if fields.len() == 8 => fields,
+        _ => return Err(invalid("expected a value with eight fields")),
+    };
+    let bfe = |field: &bipf::Value| match field {
+        bipf::Value::Buffer(bytes) => {
+            Bfe::from_tagged(bytes).map_err(|error| ValidationError::Decode {
+                message: error.to_string(),
+            })
+        }
+        _ => Err(invalid("expected a BFE buffer")),
+    };
+    let message_ref = |field: &bipf::Value, name: &str| match bfe(field)? {
+        Bfe::Message(MessageRef::Buttwoo(message)) => Ok(Some(message)),
+        Bfe::Nil => Ok(None),
+        _ => Err(invalid(&format!(
+            "{} must be a buttwoo message or nil",
+            name
+        ))),
+    };
+    let integer = |field: &bipf::Value, name: &str| match field {
+        bipf::Value::Int(int) if *int >= 0 => Ok(*int as f64),
+        bipf::Value::Double(double) if *double >= 0.0 && double.fract() == 0.0 => Ok(*double),
+        _ => Err(invalid(&format!("{} must be a non-negative integer", name))),
+    };
+
+    let author = match bfe(&fields[0])? {
+        Bfe::Feed(FeedRef::Buttwoo(author)) => author,
+        _ => return Err(invalid("author must be a buttwoo feed")),
+    };
+    let parent = message_ref(&fields[1], "parent")?;
+    let sequence = integer(&fields[2], "sequence")? as u64;
+    let timestamp = integer(&fields[3], "timestamp")?;
+    let previous = message_ref(&fields[4], "previous")?;
+    let tag = match &fields[5] {
+        bipf::Value::Buffer(tag) if tag.as_slice() == [0] => Tag::Standard,
+        bipf::Value::Buffer(tag) if tag.as_slice() == [1] => Tag::EndOfFeed,
+        _ => return Err(invalid("unknown tag")),
+    };
+    let content_length = integer(&fields[6], "content length")? as usize;
+    let content_hash = match &fields[7] {
+        bipf::Value::Buffer(hash) => hash.as_slice(),
+        _ => return Err(invalid("content hash must be a buffer")),
+    };
+    if content_length != content_data.len() || content_hash != hash_content(&content_data) {
+        return Err(ValidationError::ContentHash);
+    }
+
+    let signature = sign::Signature::from_slice(&signature).ok_or(ValidationError::Signature)?;
+    if !sign::verify_detached(&signature, &value_data, author.public_key()) {
+        return Err(ValidationError::Signature);
+    }
+
+    Ok(ButtwooMessage {
+        key: message_id(&value_data, signature.as_ref()),
+        author,
+        parent,
+        sequence,
+        timestamp,
+        previous,
+        tag,
+        content: bipf::to_json(&decode_bipf(&content_data)?),
+        data: data.to_vec(),
+    })
+}
+
+/// Decode `data` as the message that follows `previous` in a buttwoo feed.
+///
+/// `previous` must be `None` if `data` is the first message of the feed.
+pub fn validate(
+    previous: Option<&ButtwooMessage>,
+    data: &[u8],
+) -> Result<ButtwooMessage, ValidationError> {
+    let message = decode(data)?;
+    match previous {
+        Some(previous) => {
+            let expected = previous.sequence + 1;
+            if previous.tag == Tag::EndOfFeed || message.sequence != expected {
+                return Err(ValidationError::Sequence {
+                    expected,
+                    actual: message.sequence,
+                });
+            }
+            if message.previous != Some(previous.key) {
+                return Err(ValidationError::Previous);
+            }
+            if message.author != previous.author || message.parent != previous.parent {
+                return Err(ValidationError::Author);
+            }
+        }
+        None => {
+            if message.sequence != 1 {
+                return Err(ValidationError::Sequence {
+                    expected: 1,
+                    actual: message.sequence,
+                });
+            }
+            if message.previous.is_some() {
+                return Err(ValidationError::Previous);
+            }
+        }
+    }
+    Ok(message)
+}
+
+/// Create and sign the standard message that follows `previous` in the feed of `keypair`.
+pub fn sign(
+    keypair: &KeyPair,
+    previous: Option<&ButtwooMessage>,
+    timestamp: f64,
+    content: serde_json::Value,
+) -> ButtwooMessage {
+    let content_data = bipf::encode(&bipf::from_json(&content));
+    let sequence = previous.map_or(1, |previous| previous.sequence + 1);
+    let parent = previous.and_then(|previous| previous.parent);
+    let author = ButtwooFeedId(keypair.public);
+    let message_ref = |message: Option<ButtwooMessageId>| {
+        message.map_or(Bfe::Nil, |message| {
+            Bfe::Message(MessageRef::Buttwoo(message))
+        })
+    };
+    let tagged = |value: Bfe| bipf::Value::Buffer(value.to_tagged().unwrap());
+    let value_data = bipf::encode(&bipf::Value::Array(vec![
+        tagged(Bfe::Feed(FeedRef::Buttwoo(author))),
+        tagged(message_ref(parent)),
+        bipf::from_json(&sequence.into()),
+        bipf::Value::Double(timestamp),
+        tagged(message_ref(previous.map(|previous| previous.key))),
+        bipf::Value::Buffer(vec![Tag::Standard.byte()]),
+        bipf::from_json(&content_data.len().into()),
+        bipf::Value::Buffer(hash_content(&content_data)),
+    ]));
+    let signature = sign::sign_detached(&value_data, &keypair.secret);
+    let data = bipf::encode(&bipf::Value::Array(vec![
+        bipf::Value::Buffer(value_data.clone()),
+        bipf::Value::Buffer(signature.as_ref().to_vec()),
+        bipf::Value::Buffer(content_data),
+    ]));
+
+    ButtwooMessage {
+        key: message_id(&value_data, signature.as_ref()),
+        author,
+        parent,
+        sequence,
+        timestamp,
+        previous: previous.map(|previous| previous.key),
+        tag: Tag::Standard,
+        content,
+        data,
+    }
+}
+
+fn hash_content(content: &[u8]) -> Vec<u8> {
+    let mut hash = vec![CONTENT_HASH_BLAKE3];
+    hash.extend_from_slice(blake3::hash(content).as_bytes());
+    hash
+}
+
+fn message_id(value_data: &[u8], signature: &[u8]) -> ButtwooMessageId {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(value_data);
+    hasher.update(signature);
+    ButtwooMessageId(*hasher.finalize().as_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validate_chain() {
+        let keypair = KeyPair::gen();
+        let content = serde_json::json!({ "type": "post", "text": "hello" });
+        let first = sign(&keypair, None, 1.0, content.clone());
+        let second = sign(&keypair, Some(&first), 2.0, content.clone());
+
+        assert_eq!(validate(None, &first.data), Ok(first.clone()));
+        assert_eq!(validate(Some(&first), &second.data), Ok(second.clone()));
+        assert_eq!(second.content, content);
+        assert_eq!(
+            validate(None, &second.data),
+            Err(ValidationError::Sequence {
+                expected: 1,
+                actual: 2
+            })
+        );
+        let other = sign(&KeyPair::gen(), Some(&first), 2.0, content);
+        assert_eq!(
+            validate(Some(&first), &other.data),
+            Err(ValidationError::Author)
+        );
+    }
+
+    #[test]
+    fn verify_content_hash() {
+        let keypair = KeyPair::gen();
+        let message = sign(&keypair, None, 1.0, serde_json::json!({ "type": "post" }));
+        let mut tampered = message.data;
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert_eq!(decode(&tampered), Err(ValidationError::ContentHash));
+    }
+
+    #[test]
+    fn id_roundtrip() {
+        let feed = ButtwooFeedId(KeyPair::gen().public);
+        assert_eq!(feed.to_string().parse::<ButtwooFeedId>().unwrap(), feed);
+        let message = ButtwooMessageId([9; 32]);
+        assert_eq!(
+            message.to_string().parse::<ButtwooMessageId>().unwrap(),
+            message
+        );
+    }
+}
@@ -0,0 +1,443 @@
+//! Walk the tree of feeds announced by a [metafeed][spec].
+//!
+//! A classic main feed announces its metafeed with a `metafeed/announce` message that is signed
+//! by the metafeed key. The metafeed is a bendy-butt feed whose messages add subfeeds with
+//! `metafeed/add/existing` or `metafeed/add/derived` and remove them with `metafeed/tombstone`.
+//! Subfeeds may be metafeeds themselves.
+//!
+//! Subfeeds with the purpose `index` are [index feeds][index-spec]. An index feed is a classic
+//! feed whose `metafeed/index` messages reference the messages of another feed that match the
+//! `ssb-ql-0` query given when the index feed was added. [IndexFeed] describes such a feed. See
+//! [crate::replicate] for how index feeds are used for partial replication.
+//!
+//! [spec]: https://github.com/ssbc/ssb-meta-feeds-spec
+//! [index-spec]: https://github.com/ssbc/ssb-meta-feeds-spec#index-feeds
+
+use std::collections::HashSet;
+
+use super::bendy_butt::{BendyButtMessage, Content, MetafeedId};
+use super::bfe::{Bfe, FeedRef};
+use super::{FeedId, Message, MessageId, MessageValue};
+use crate::crypto::sign;
+
+pub const ANNOUNCE_TYPE: &str = "metafeed/announce";
+pub const ADD_EXISTING_TYPE: &str = "metafeed/add/existing";
+pub const ADD_DERIVED_TYPE: &str = "metafeed/add/derived";
+pub const TOMBSTONE_TYPE: &str = "metafeed/tombstone";
+pub const INDEX_TYPE: &str = "metafeed/index";
+
+/// Purpose of subfeeds that are index feeds.
+pub const INDEX_PURPOSE: &str = "index";
+/// Query language of the queries of index feeds.
+pub const QUERY_LANGUAGE: &str = "ssb-ql-0";
+
+/// Returns the metafeed announced by a classic message.
+///
+/// Returns `None` if the message is not a `metafeed/announce` message or if the announcement is
+/// not signed by the metafeed.
+pub fn announced_metafeed(message: &Message) -> Option<MetafeedId> {
+    if message.value.content_type() != Some(ANNOUNCE_TYPE) {
+        return None;
+    }
+    let mut content = message.value.content.as_object()?.clone();
+    let metafeed = content
+        .get("metafeed")?
+        .as_str()?
+        .parse::<MetafeedId>()
+        .ok()?;
+    let signature = content.remove("signature")?;
+    let signature = signature
+        .as_str()?
+        .strip_suffix(".sig.ed25519")
+        .and_then(|signature| base64::decode(signature).ok())
+        .and_then(|signature| sign::Signature::from_slice(&signature))?;
+    let signed_data = serde_json::to_string_pretty(&content).unwrap();
+    if sign::verify_detached(&signature, signed_data.as_bytes(), metafeed.public_key()) {
+        Some(metafeed)
+    } else {
+        None
+    }
+}
+
+/// Returns the metafeed announced last in `messages` of a main feed.
+pub fn find_metafeed<'a>(messages: impl IntoIterator<Item = &'a Message>) -> Option<MetafeedId> {
+    messages.into_iter().filter_map(announced_metafeed).last()
+}
+
+/// A feed that was added to a metafeed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subfeed {
+    pub feed: FeedRef,
+    /// Describes what the feed is used for, for example `main` or `index`.
+    pub purpose: String,
+    /// Nonce used to derive the key of the feed. `None` if an existing feed was added.
+    pub nonce: Option<Vec<u8>>,
+    /// Query of an index feed. `None` if the feed has no `ssb-ql-0` query.
+    pub query: Option<IndexQuery>,
+}
+
+/// `ssb-ql-0` query that selects the messages referenced by an index feed.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct IndexQuery {
+    pub author: FeedId,
+    #[serde(rename = "type")]
+    pub content_type: String,
+    /// Index feeds of private messages are not supported, so this is always `false` for index
+    /// feeds that are used.
+    #[serde(default)]
+    pub private: bool,
+}
+
+impl IndexQuery {
+    /// Returns `true` if `value` is selected by the query.
+    pub fn matches(&self, value: &MessageValue) -> bool {
+        !self.private
+            && value.author == self.author
+            && value.content_type() == Some(self.content_type.as_str())
+    }
+}
+
+/// Index feed found in a [MetafeedTree].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexFeed {
+    pub feed: FeedId,
+    pub query: IndexQuery,
+}
+
+/// Message of the indexed feed referenced by a `metafeed/index` message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Indexed {
+    pub key: MessageId,
+    pub sequence: u64,
+}
+
+/// Returns the message referenced by a message of an index feed.
+///
+/// Returns `None` if the message is not a `metafeed/index` message.
+pub fn indexed(message: &Message) -> Option<Indexed> {
+    if message.value.content_type() != Some(INDEX_TYPE) {
+        return None;
+    }
+    let indexed = message.value.content.get("indexed")?;
+    Some(Indexed {
+        key: indexed.get("key")?.as_str()?.parse().ok()?,
+        sequence: indexed.get("sequence")?.as_u64()?,
+    })
+}
+
+/// Content of a `metafeed/index` message that references `message`.
+pub fn index_content(message: &Message) -> serde_json::Value {
+    serde_json::json!({
+        "type": INDEX_TYPE,
+        "indexed": {
+            "key": message.key,
+            "sequence": message.value.sequence,
+        },
+    })
+}
+
+/// Returns the subfeeds of a metafeed that have not been tombstoned in the order they were added.
+///
+/// `messages` must be the messages of the metafeed in order. Messages of other types and
+/// encrypted messages are ignored.
+pub fn subfeeds<'a>(messages: impl IntoIterator<Item = &'a BendyButtMessage>) -> Vec<Subfeed> {
+    let mut subfeeds = Vec::<Subfeed>::new();
+    for message in messages {
+        let content = match &message.content {
+            Content::Signed(content) => content,
+            Content::Encrypted { .. } => continue,
+        };
+        let subfeed = match content.get("subfeed") {
+            Some(Bfe::Feed(subfeed)) => *subfeed,
+            _ => continue,
+        };
+        match message.content.content_type() {
+            Some(ADD_EXISTING_TYPE) | Some(ADD_DERIVED_TYPE) => {
+                let purpose = match content.get("feedpurpose") {
+                    Some(Bfe::String(purpose)) => purpose.clone(),
+                    _ => continue,
+                };
+                let nonce = match content.get("nonce") {
+                    Some(Bfe::Bytes(nonce)) => Some(nonce.clone()),
+                    _ => None,
+                };
+                let query = match (content.get("querylang"), content.get("query")) {
+                    (Some(Bfe::String(language)), Some(Bfe::String(query)))
+                        if language == QUERY_LANGUAGE =>
+                    {
+                        serde_json::from_str(query).ok()
+                    }
+                    _ => None,
+                };
+                subfeeds.retain(|existing| existing.feed != subfeed);
+                subfeeds.push(Subfeed {
+                    feed: subfeed,
+                    purpose,
+                    nonce,
+                    query,
+                });
+            }
+            Some(TOMBSTONE_TYPE) => subfeeds.retain(|existing| existing.feed != subfeed),
+            _ => {}
+        }
+    }
+    subfeeds
+}
+
+/// Metafeed and all its subfeeds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetafeedTree {
+    pub id: MetafeedId,
+    pub subfeeds: Vec<SubfeedNode>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubfeedNode {
+    pub subfeed: Subfeed,
+    /// The tree of the subfeed if it is a metafeed.
+    pub tree: Option<MetafeedTree>,
+}
+
+impl MetafeedTree {
+    /// Iterate over all feeds in the tree excluding the root metafeed.
+    pub fn feeds(&self) -> Vec<FeedRef> {
+        let mut feeds = Vec::new();
+        for node in &self.subfeeds {
+            feeds.push(node.subfeed.feed);
+            if let Some(tree) = &node.tree {
+                feeds.extend(tree.feeds());
+            }
+        }
+        feeds
+    }
+
+    /// All classic index feeds in the tree with a query for public messages.
+    pub fn index_feeds(&self) -> Vec<IndexFeed> {
+        let mut index_feeds = Vec::new();
+        for node in &self.subfeeds {
+            match (&node.subfeed.feed, &node.subfeed.query) {
+                (FeedRef::Classic(feed), Some(query))
+                    if node.subfeed.purpose == INDEX_PURPOSE && !query.private =>
+                {
+                    index_feeds.push(IndexFeed {
+                        feed: *feed,
+                        query: query.clone(),
+                    });
+                }
+                _ => {}
+            }
+            if let Some(tree) = &node.tree {
+                index_feeds.extend(tree.index_feeds());
+            }
+        }
+        index_feeds
+    }
+}
+
+/// Build the tree of feeds below `root`.
+///
+/// `load` returns the stored messages of a metafeed in order. Metafeeds that appear more than
+/// once in the tree are only expanded the first time.
+pub fn walk(
+    root: MetafeedId,
+    mut load: impl FnMut(&MetafeedId) -> Vec<BendyButtMessage>,
+) -> MetafeedTree {
+    let mut visited = HashSet::new();
+    walk_inner(root, &mut load, &mut visited)
+}
+
+fn walk_inner(
+    id: MetafeedId,
+    load: &mut impl FnMut(&MetafeedId) -> Vec<BendyButtMessage>,
+    visited: &mut HashSet<MetafeedId>,
+) -> MetafeedTree {
+    visited.insert(id);
+    let messages = load(&id);
+    let subfeeds = subfeeds(&messages)
+        .into_iter()
+        .map(|subfeed| {
+            let tree = match subfeed.feed {
+                FeedRef::BendyButt(metafeed) if !visited.contains(&metafeed) => {
+                    Some(walk_inner(metafeed, load, visited))
+                }
+                _ => None,
+            };
+            SubfeedNode { subfeed, tree }
+        })
+        .collect();
+    MetafeedTree { id, subfeeds }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::sign::KeyPair;
+    use crate::feed::bendy_butt::sign;
+    use crate::feed::FeedId;
+    use std::collections::{BTreeMap, HashMap};
+
+    fn add(
+        metafeed: &KeyPair,
+        subfeed: &KeyPair,
+        subfeed_ref: FeedRef,
+        purpose: &str,
+        previous: Option<&BendyButtMessage>,
+    ) -> BendyButtMessage {
+        let mut content = BTreeMap::new();
+        content.insert(
+            "type".to_string(),
+            Bfe::String(ADD_EXISTING_TYPE.to_string()),
+        );
+        content.insert("subfeed".to_string(), Bfe::Feed(subfeed_ref));
+        content.insert(
+            "metafeed".to_string(),
+            Bfe::Feed(FeedRef::BendyButt(MetafeedId(metafeed.public))),
+        );
+        content.insert("feedpurpose".to_string(), Bfe::String(purpose.to_string()));
+        sign(metafeed, subfeed, previous, 0, content)
+    }
+
+    fn tombstone(
+        metafeed: &KeyPair,
+        subfeed: &KeyPair,
+        subfeed_ref: FeedRef,
+        previous: Option<&BendyButtMessage>,
+    ) -> BendyButtMessage {
+        let mut content = BTreeMap::new();
+        content.insert("type".to_string(), Bfe::String(TOMBSTONE_TYPE.to_string()));
+        content.insert("subfeed".to_string(), Bfe::Feed(subfeed_ref));
+        sign(metafeed, subfeed, previous, 0, content)
+    }
+
+    #[test]
+    fn walk_tree() {
+        let root = KeyPair::gen();
+        let main = KeyPair::gen();
+        let index = KeyPair::gen();
+        let old = KeyPair::gen();
+        let main_ref = FeedRef::Classic(FeedId(main.public));
+        let index_ref = FeedRef::BendyButt(MetafeedId(index.public));
+        let old_ref = FeedRef::Classic(FeedId(old.public));
+
+        let first = add(&root, &main, main_ref, "main", None);
+        let second = add(&root, &index, index_ref, "indexes", Some(&first));
+        let third = add(&root, &old, old_ref, "old", Some(&second));
+        let fourth = tombstone(&root, &old, old_ref, Some(&third));
+        let index_first = add(&index, &main, main_ref, "index", None);
+
+        let mut feeds = HashMap::new();
+        feeds.insert(MetafeedId(root.public), vec![first, second, third, fourth]);
+        feeds.insert(MetafeedId(index.public), vec![index_first]);
+
+        let tree = walk(MetafeedId(root.public), |id| {
+            feeds.get(id).cloned().unwrap_or_default()
+        });
+        assert_eq!(tree.feeds(), vec![main_ref, index_ref, main_ref]);
+        assert_eq!(tree.subfeeds[0].subfeed.purpose, "main");
+        let index_tree = tree.subfeeds[1].tree.as_ref().unwrap();
+        assert_eq!(index_tree.id, MetafeedId(index.public));
+        assert_eq!(index_tree.subfeeds[0].subfeed.purpose, "index");
+    }
+
+    #[test]
+    fn index_feeds() {
+        let root = KeyPair::gen();
+        let main = KeyPair::gen();
+        let index = KeyPair::gen();
+        let main_id = FeedId(main.public);
+        let index_ref = FeedRef::Classic(FeedId(index.public));
+        let query = IndexQuery {
+            author: main_id,
+            content_type: "contact".to_string(),
+            private: false,
+        };
+
+        let mut content = BTreeMap::new();
+        content.insert(
+            "type".to_string(),
+            Bfe::String(ADD_DERIVED_TYPE.to_string()),
+        );
+        content.insert("subfeed".to_string(), Bfe::Feed(index_ref));
+        content.insert(
+            "feedpurpose".to_string(),
+            Bfe::String(INDEX_PURPOSE.to_string()),
+        );
+        content.insert(
+            "querylang".to_string(),
+            Bfe::String(QUERY_LANGUAGE.to_string()),
+        );
+        content.insert(
+            "query".to_string(),
+            Bfe::String(serde_json::to_string(&query).unwrap()),
+        );
+        let first = sign(&root, &index, None, 0, content);
+        let second = add(
+            &root,
+            &main,
+            FeedRef::Classic(main_id),
+            "main",
+            Some(&first),
+        );
+
+        let tree = walk(MetafeedId(root.public), |_| {
+            vec![first.clone(), second.clone()]
+        });
+        assert_eq!(
+            tree.index_feeds(),
+            vec![IndexFeed {
+                feed: FeedId(index.public),
+                query,
+            }]
+        );
+
+        let contact = crate::feed::validate::sign(
+            &main,
+            None,
+            0u64,
+            serde_json::json!({ "type": "contact" }),
+        );
+        let index_message =
+            crate::feed::validate::sign(&index, None, 0u64, index_content(&contact));
+        assert_eq!(
+            indexed(&index_message),
+            Some(Indexed {
+                key: contact.key,
+                sequence: 1,
+            })
+        );
+        assert!(tree.index_feeds()[0].query.matches(&contact.value));
+        assert_eq!(indexed(&contact), None);
+    }
+
+    #[test]
+    fn announce() {
+        let main = KeyPair::gen();
+        let metafeed = KeyPair::gen();
+        let metafeed_id = MetafeedId(metafeed.public);
+        let mut content = serde_json::Map::new();
+        content.insert("type".to_string(), ANNOUNCE_TYPE.into());
+        content.insert("metafeed".to_string(), metafeed_id.to_string().into());
+        let signed_data = serde_json::to_string_pretty(&content).unwrap();
+        let signature = sign::sign_detached(signed_data.as_bytes(), &metafeed.secret);
+        content.insert(
+            "signature".to_string(),
+            format!("{}.sig.ed25519", base64::encode(signature)).into(),
+        );
+
+        let message = crate::feed::validate::sign(
+            &main,
+            None,
+            0u64,
+            serde_json::Value::Object(content.clone()),
+        );
+        assert_eq!(find_metafeed(&[message]), Some(metafeed_id));
+
+        content.insert(
+            "metafeed".to_string(),
+            MetafeedId(main.public).to_string().into(),
+        );
+        let forged =
+            crate::feed::validate::sign(&main, None, 0u64, serde_json::Value::Object(content));
+        assert_eq!(announced_metafeed(&forged), None);
+    }
+}
@@ -0,0 +1,254 @@
+//! Feeds and messages in the classic SSB format.
+//!
+//! Metafeeds that use the bendy-butt format are supported by [bendy_butt] and [metafeed]. The
+//! [buttwoo] format is supported through [validate::FeedFormat].
+//!
+//! [FeedStore] persists messages. [crate::store::db2::Db2FeedStore] reads and appends to the log
+//! of an ssb-db2 database. With the `sled` feature `sled::SledFeedStore` keeps messages in a sled
+//! database.
+//!
+//! See the [Scuttlebutt Protocol Guide][guide] for a description of the format.
+//!
+//! ```rust
+//! # use ssb::feed::{FeedId, Message};
+//! let message: Message = serde_json::from_str(r#"{
+//!     "key": "%R7lJEkz27lNijPhYNDzYoPjM0Fp+bFWzwX0SmNJB/ZE=.sha256",
+//!     "value": {
+//!         "previous": "%XphMUkWQtomKjXQvFGfsGYpt69sgEY7Y4Vou9cEuJho=.sha256",
+//!         "author": "@FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=.ed25519",
+//!         "sequence": 2,
+//!         "timestamp": 1514517078157,
+//!         "hash": "sha256",
+//!         "content": { "type": "post", "text": "Second post!" },
+//!         "signature": "z7W1ERg9UYZjNfE72ZwEuJF79khG+eOHWFp6iF+KLuSrw8Lqa6IousK4cCn9T5qFa8E14GVek4cAMmMbjqDnAg==.sig.ed25519"
+//!     },
+//!     "timestamp": 1514517078160
+//! }"#).unwrap();
+//!
+//! assert_eq!(message.value.sequence, 2);
+//! assert_eq!(message.value.content_type(), Some("post"));
+//! let author: FeedId = "@FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=.ed25519".parse().unwrap();
+//! assert_eq!(message.value.author, author);
+//! ```
+//!
+//! [guide]: https://ssbc.github.io/scuttlebutt-protocol-guide/#structure
+
+use std::convert::TryFrom;
+
+use crate::crypto::sign::PublicKey;
+
+macro_rules! impl_serde_via_string {
+    ($type:ty) => {
+        impl serde::Serialize for $type {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $type {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                s.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+
+mod bencode;
+pub mod bendy_butt;
+pub mod bfe;
+pub mod buttwoo;
+pub mod metafeed;
+#[cfg(feature = "sled")]
+pub mod sled;
+mod store;
+pub use store::{FeedStore, MemoryFeedStore, StoreError};
+
+pub mod validate;
+
+/// Identifies a feed by the public key of its author, for example
+/// `@FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=.ed25519`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FeedId(pub PublicKey);
+
+impl FeedId {
+    pub fn public_key(&self) -> &PublicKey {
+        &self.0
+    }
+}
+
+impl From<PublicKey> for FeedId {
+    fn from(public_key: PublicKey) -> Self {
+        Self(public_key)
+    }
+}
+
+impl From<ssb_box_stream::PublicKey> for FeedId {
+    fn from(public_key: ssb_box_stream::PublicKey) -> Self {
+        Self(PublicKey(public_key.0))
+    }
+}
+
+impl From<FeedId> for ssb_box_stream::PublicKey {
+    fn from(feed_id: FeedId) -> Self {
+        Self(feed_id.0 .0)
+    }
+}
+
+impl std::fmt::Display for FeedId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "@{}.ed25519", base64::encode(self.0))
+    }
+}
+
+impl std::fmt::Debug for FeedId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("FeedId").field(&self.to_string()).finish()
+    }
+}
+
+impl std::str::FromStr for FeedId {
+    type Err = IdParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = parse_id(s, '@', ".ed25519")?;
+        let public_key =
+            PublicKey::from_slice(&bytes).ok_or_else(|| IdParseError { id: s.to_string() })?;
+        Ok(Self(public_key))
+    }
+}
+
+/// Identifies a message by the hash of its value, for example
+/// `%R7lJEkz27lNijPhYNDzYoPjM0Fp+bFWzwX0SmNJB/ZE=.sha256`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageId(pub [u8; 32]);
+
+impl std::fmt::Display for MessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{}.sha256", base64::encode(self.0))
+    }
+}
+
+impl std::fmt::Debug for MessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("MessageId").field(&self.to_string()).finish()
+    }
+}
+
+impl std::str::FromStr for MessageId {
+    type Err = IdParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = parse_id(s, '%', ".sha256")?;
+        let hash = <[u8; 32]>::try_from(bytes.as_slice())
+            .map_err(|_| IdParseError { id: s.to_string() })?;
+        Ok(Self(hash))
+    }
+}
+
+impl_serde_via_string!(FeedId);
+impl_serde_via_string!(MessageId);
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid ID {id:?}")]
+pub struct IdParseError {
+    pub id: String,
+}
+
+/// Decode the base64 data of an ID of the form `<sigil><base64><suffix>`.
+fn parse_id(s: &str, sigil: char, suffix: &str) -> Result<Vec<u8>, IdParseError> {
+    let error = || IdParseError { id: s.to_string() };
+    let encoded = s
+        .strip_prefix(sigil)
+        .and_then(|s| s.strip_suffix(suffix))
+        .ok_or_else(error)?;
+    base64::decode(encoded).map_err(|_| error())
+}
+
+/// Decode the URL safe base64 data of an SSB URI that starts with `prefix`.
+fn parse_uri(s: &str, prefix: &str) -> Result<Vec<u8>, IdParseError> {
+    let error = || IdParseError { id: s.to_string() };
+    let encoded = s.strip_prefix(prefix).ok_or_else(error)?;
+    base64::decode_config(encoded.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+        .map_err(|_| error())
+}
+
+/// A message together with its ID as returned by `createHistoryStream` with `keys: true` or
+/// `createLogStream`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Message {
+    pub key: MessageId,
+    pub value: MessageValue,
+    /// Time in milliseconds since the Unix epoch when the message was received.
+    #[serde(default)]
+    pub timestamp: f64,
+}
+
+/// Signed message value in the classic format.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct MessageValue {
+    pub previous: Option<MessageId>,
+    pub author: FeedId,
+    pub sequence: u64,
+    /// Time in milliseconds since the Unix epoch claimed by the author. Kept as a JSON number so
+    /// that the value serializes exactly as it was signed.
+    pub timestamp: serde_json::Number,
+    pub hash: String,
+    /// Content of the message. Either an object with a `type` field or a string with encrypted
+    /// content.
+    pub content: serde_json::Value,
+    pub signature: String,
+}
+
+impl MessageValue {
+    /// Returns the `type` field of the content if the content is not encrypted.
+    pub fn content_type(&self) -> Option<&str> {
+        self.content.get("type")?.as_str()
+    }
+
+    /// Returns `true` if the content was removed with [FeedStore::delete_content].
+    ///
+    /// The deleted content is `null`, which is never valid content. The message keeps its key,
+    /// sequence number and signature so that the next message of the feed can still be
+    /// validated against it, but the message itself cannot be verified or passed on anymore.
+    pub fn is_deleted(&self) -> bool {
+        self.content.is_null()
+    }
+
+    /// Replace the content with the tombstone checked by [MessageValue::is_deleted].
+    pub fn delete_content(&mut self) {
+        self.content = serde_json::Value::Null;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn id_roundtrip() {
+        let feed = "@FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=.ed25519";
+        assert_eq!(feed.parse::<FeedId>().unwrap().to_string(), feed);
+        let message = "%R7lJEkz27lNijPhYNDzYoPjM0Fp+bFWzwX0SmNJB/ZE=.sha256";
+        assert_eq!(message.parse::<MessageId>().unwrap().to_string(), message);
+
+        assert!("%FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=.ed25519"
+            .parse::<FeedId>()
+            .is_err());
+        assert!("@AAAA.ed25519".parse::<FeedId>().is_err());
+        assert!("%AAAA.sha256".parse::<MessageId>().is_err());
+    }
+
+    #[test]
+    fn box_stream_key() {
+        let feed = "@FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=.ed25519"
+            .parse::<FeedId>()
+            .unwrap();
+        let public_key = ssb_box_stream::PublicKey::from(feed);
+        assert_eq!(
+            public_key.to_string(),
+            "FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY="
+        );
+        assert_eq!(FeedId::from(public_key), feed);
+    }
+}
@@ -0,0 +1,523 @@
+//! Persistent [FeedStore] backed by a [sled] database. Requires the `sled` feature.
+//!
+//! The store uses three trees of the database. The [MESSAGES] tree maps the feed ID and the
+//! big-endian sequence number to the JSON of the message, so the messages of a feed are stored in
+//! order. The [LATEST] tree maps each feed ID to the sequence number of its latest message. The
+//! [KEYS] tree maps message IDs to the feed ID and sequence number of the message.
+//!
+//! An append updates all three trees in one transaction and flushes the database before it
+//! returns, so a message is stored completely or not at all. Writes are serialized by the store.
+//! [SledFeedStore::fsck] checks that the trees agree and repairs them otherwise. sled compacts its
+//! files in the background.
+//!
+//! ```no_run
+//! # use ssb::feed::{FeedStore, sled::SledFeedStore};
+//! let store = SledFeedStore::open("feeds.db").unwrap();
+//! for feed in store.feeds().unwrap() {
+//!     println!("{} {:?}", feed, store.latest_sequence(&feed));
+//! }
+//! ```
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ::sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+
+use super::{FeedId, FeedStore, Message, MessageId, StoreError};
+use crate::crypto::sign::PublicKey;
+
+/// Name of the tree that holds the messages.
+pub const MESSAGES: &str = "messages";
+
+/// Name of the tree that holds the latest sequence number of each feed.
+pub const LATEST: &str = "latest";
+
+/// Name of the tree that maps message IDs to feed IDs and sequence numbers.
+pub const KEYS: &str = "keys";
+
+/// How long [SledFeedStore::open] waits for another handle to release the database.
+pub const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(thiserror::Error, Debug)]
+pub enum SledStoreError {
+    #[error("Database failed")]
+    Sled(#[from] ::sled::Error),
+    /// The database is still open, either in another process or in this one.
+    #[error("Database {path} is locked")]
+    Locked { path: PathBuf },
+    /// The stored message cannot be decoded. [SledFeedStore::fsck] removes it.
+    #[error("Message {sequence} of feed {feed} is damaged")]
+    Damaged { feed: FeedId, sequence: u64 },
+}
+
+impl From<SledStoreError> for StoreError {
+    fn from(error: SledStoreError) -> Self {
+        StoreError::Backend {
+            error: Box::new(error),
+        }
+    }
+}
+
+impl From<::sled::Error> for StoreError {
+    fn from(error: ::sled::Error) -> Self {
+        SledStoreError::from(error).into()
+    }
+}
+
+impl From<TransactionError<StoreError>> for StoreError {
+    fn from(error: TransactionError<StoreError>) -> Self {
+        match error {
+            TransactionError::Abort(error) => error,
+            TransactionError::Storage(error) => error.into(),
+        }
+    }
+}
+
+/// Result of [SledFeedStore::fsck].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    /// Messages that are stored
+    pub messages: usize,
+    /// Messages that were removed because they are damaged or do not follow the previous
+    /// message of the feed
+    pub removed: usize,
+    /// Entries of the [LATEST] and [KEYS] trees that were corrected
+    pub repaired: usize,
+}
+
+/// [FeedStore] that keeps messages in a sled database. See the [module documentation][self].
+#[derive(Debug)]
+pub struct SledFeedStore {
+    db: ::sled::Db,
+    messages: ::sled::Tree,
+    latest: ::sled::Tree,
+    keys: ::sled::Tree,
+    /// Serializes writes. Truncation reads ranges that sled transactions cannot guard.
+    write_lock: Mutex<()>,
+}
+
+impl SledFeedStore {
+    /// Open or create the database at `path`.
+    ///
+    /// sled locks the database while it is open. The lock is released by a background thread of
+    /// sled shortly after the last handle of the database was dropped, so opening the database
+    /// right after closing it may find it locked. `open` retries until the lock is released and
+    /// fails with [SledStoreError::Locked] if it is still held after [LOCK_TIMEOUT].
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, SledStoreError> {
+        let path = path.as_ref();
+        let start = Instant::now();
+        loop {
+            match ::sled::open(path) {
+                Ok(db) => return Self::new(db),
+                Err(error) if is_lock_error(&error) => {
+                    if start.elapsed() > LOCK_TIMEOUT {
+                        return Err(SledStoreError::Locked {
+                            path: path.to_path_buf(),
+                        });
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(error) => return Err(error.into()),
+            }
+        }
+    }
+
+    /// Store messages in the trees [MESSAGES], [LATEST] and [KEYS] of `db`.
+    pub fn new(db: ::sled::Db) -> Result<Self, SledStoreError> {
+        Ok(Self {
+            messages: db.open_tree(MESSAGES)?,
+            latest: db.open_tree(LATEST)?,
+            keys: db.open_tree(KEYS)?,
+            db,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    /// Sequence number of the latest message of `feed` without reading the message.
+    pub fn latest_sequence(&self, feed: &FeedId) -> Result<Option<u64>, StoreError> {
+        Ok(self
+            .latest
+            .get(feed_key(feed))?
+            .map(|value| decode_sequence(&value)))
+    }
+
+    /// Remove all messages of `feed`. Afterwards the feed can be appended to starting with
+    /// sequence number 1.
+    pub fn remove_feed(&self, feed: &FeedId) -> Result<(), StoreError> {
+        self.truncate(feed, 1)
+    }
+
+    /// Check that every feed is stored without gaps from sequence number 1 and that the [LATEST]
+    /// and [KEYS] trees match the messages. Damaged messages and the messages that follow them
+    /// are removed and the other trees are corrected.
+    pub fn fsck(&self) -> Result<FsckReport, StoreError> {
+        let _lock = self.write_lock.lock().unwrap();
+        let mut report = FsckReport::default();
+        let mut latest = HashMap::<FeedId, u64>::new();
+        let mut keys = HashMap::<MessageId, (FeedId, u64)>::new();
+        let mut remove = Vec::new();
+        for entry in self.messages.iter() {
+            let (key, value) = entry?;
+            let position = decode_message_key(&key);
+            let expected = position.map(|(feed, _)| latest.get(&feed).map_or(1, |seq| seq + 1));
+            let message = serde_json::from_slice::<Message>(&value).ok();
+            match (position, expected, message) {
+                (Some((feed, sequence)), Some(expected), Some(message))
+                    if sequence == expected
+                        && message.value.author == feed
+                        && message.value.sequence == sequence =>
+                {
+                    latest.insert(feed, sequence);
+                    keys.insert(message.key, (feed, sequence));
+                    report.messages += 1;
+                }
+                _ => remove.push(key),
+            }
+        }
+        report.removed = remove.len();
+        for key in remove {
+            self.messages.remove(key)?;
+        }
+
+        for entry in self.latest.iter() {
+            let (key, value) = entry?;
+            let stored = feed_from_bytes(&key).map(|feed| (feed, decode_sequence(&value)));
+            match stored.map(|(feed, sequence)| (feed, sequence, latest.remove(&feed))) {
+                Some((_, sequence, Some(expected))) if sequence == expected => {}
+                Some((feed, _, Some(expected))) => {
+                    self.latest
+                        .insert(feed_key(&feed), &expected.to_be_bytes())?;
+                    report.repaired += 1;
+                }
+                _ => {
+                    self.latest.remove(key)?;
+                    report.repaired += 1;
+                }
+            }
+        }
+        for (feed, sequence) in latest {
+            self.latest
+                .insert(feed_key(&feed), &sequence.to_be_bytes())?;
+            report.repaired += 1;
+        }
+
+        for entry in self.keys.iter() {
+            let (key, value) = entry?;
+            let stored = key
+                .as_ref()
+                .try_into()
+                .ok()
+                .map(MessageId)
+                .zip(decode_message_key(&value));
+            match stored {
+                Some((id, position)) if keys.get(&id) == Some(&position) => {
+                    keys.remove(&id);
+                }
+                _ => {
+                    self.keys.remove(key)?;
+                    report.repaired += 1;
+                }
+            }
+        }
+        for (id, (feed, sequence)) in keys {
+            self.keys.insert(id.0, &message_key(&feed, sequence)[..])?;
+            report.repaired += 1;
+        }
+
+        self.db.flush()?;
+        Ok(report)
+    }
+
+    fn read_message(&self, key: &[u8], value: &[u8]) -> Result<Message, StoreError> {
+        serde_json::from_slice(value).map_err(|_| {
+            let (feed, sequence) =
+                decode_message_key(key).expect("Keys of the messages tree are valid");
+            SledStoreError::Damaged { feed, sequence }.into()
+        })
+    }
+
+    fn get_message(&self, feed: &FeedId, sequence: u64) -> Result<Option<Message>, StoreError> {
+        let key = message_key(feed, sequence);
+        self.messages
+            .get(key)?
+            .map(|value| self.read_message(&key, &value))
+            .transpose()
+    }
+}
+
+/// sled reports a locked database only through the message of an IO error.
+fn is_lock_error(error: &::sled::Error) -> bool {
+    match error {
+        ::sled::Error::Io(error) => error.to_string().starts_with("could not acquire lock"),
+        _ => false,
+    }
+}
+
+fn feed_key(feed: &FeedId) -> [u8; 32] {
+    (feed.0).0
+}
+
+fn feed_from_bytes(bytes: &[u8]) -> Option<FeedId> {
+    PublicKey::from_slice(bytes).map(FeedId)
+}
+
+/// Key of a message in the [MESSAGES] tree and value of the [KEYS] tree.
+fn message_key(feed: &FeedId, sequence: u64) -> [u8; 40] {
+    let mut key = [0u8; 40];
+    key[..32].copy_from_slice(&feed_key(feed));
+    key[32..].copy_from_slice(&sequence.to_be_bytes());
+    key
+}
+
+fn decode_message_key(key: &[u8]) -> Option<(FeedId, u64)> {
+    if key.len() != 40 {
+        return None;
+    }
+    let feed = feed_from_bytes(&key[..32])?;
+    Some((feed, decode_sequence(&key[32..])))
+}
+
+/// Decode a big-endian sequence number. Values of the wrong size decode to 0, which is never a
+/// valid sequence number.
+fn decode_sequence(bytes: &[u8]) -> u64 {
+    bytes.try_into().map_or(0, u64::from_be_bytes)
+}
+
+impl FeedStore for SledFeedStore {
+    fn latest(&self, feed: &FeedId) -> Result<Option<Message>, StoreError> {
+        match self.latest_sequence(feed)? {
+            Some(sequence) => self.get_message(feed, sequence),
+            None => Ok(None),
+        }
+    }
+
+    fn get(&self, feed: &FeedId, sequence: u64) -> Result<Option<Message>, StoreError> {
+        self.get_message(feed, sequence)
+    }
+
+    fn history(
+        &self,
+        feed: &FeedId,
+        from: u64,
+        limit: Option<usize>,
+    ) -> Result<Vec<Message>, StoreError> {
+        self.messages
+            .range(message_key(feed, from)..=message_key(feed, u64::MAX))
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|entry| {
+                let (key, value) = entry?;
+                self.read_message(&key, &value)
+            })
+            .collect()
+    }
+
+    fn append(&self, message: Message) -> Result<(), StoreError> {
+        let _lock = self.write_lock.lock().unwrap();
+        let feed = message.value.author;
+        let sequence = message.value.sequence;
+        let data = serde_json::to_vec(&message).expect("Message serializes to JSON");
+        (&self.messages, &self.latest, &self.keys).transaction(|(messages, latest, keys)| {
+            let expected = latest
+                .get(feed_key(&feed))?
+                .map_or(1, |value| decode_sequence(&value) + 1);
+            if sequence != expected {
+                return Err(ConflictableTransactionError::Abort(StoreError::Sequence {
+                    feed,
+                    expected,
+                    actual: sequence,
+                }));
+            }
+            messages.insert(&message_key(&feed, sequence)[..], data.as_slice())?;
+            latest.insert(&feed_key(&feed), &sequence.to_be_bytes())?;
+            keys.insert(&message.key.0, &message_key(&feed, sequence)[..])?;
+            Ok(())
+        })?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn feeds(&self) -> Result<Vec<FeedId>, StoreError> {
+        let mut feeds = Vec::new();
+        for key in self.latest.iter().keys() {
+            if let Some(feed) = feed_from_bytes(&key?) {
+                feeds.push(feed);
+            }
+        }
+        Ok(feeds)
+    }
+
+    fn truncate(&self, feed: &FeedId, from: u64) -> Result<(), StoreError> {
+        let _lock = self.write_lock.lock().unwrap();
+        let from = from.max(1);
+        let mut removed = Vec::new();
+        for entry in self
+            .messages
+            .range(message_key(feed, from)..=message_key(feed, u64::MAX))
+        {
+            let (key, value) = entry?;
+            // Damaged messages have no entry in the keys tree.
+            let id = serde_json::from_slice::<Message>(&value)
+                .ok()
+                .map(|message| message.key);
+            removed.push((key, id));
+        }
+        (&self.messages, &self.latest, &self.keys).transaction(|(messages, latest, keys)| {
+            for (key, id) in &removed {
+                messages.remove(key)?;
+                if let Some(id) = id {
+                    keys.remove(&id.0)?;
+                }
+            }
+            let stored = latest
+                .get(feed_key(feed))?
+                .map(|value| decode_sequence(&value));
+            if stored.is_some_and(|sequence| sequence >= from) {
+                if from == 1 {
+                    latest.remove(&feed_key(feed))?;
+                } else {
+                    latest.insert(&feed_key(feed), &(from - 1).to_be_bytes())?;
+                }
+            }
+            Ok::<_, ConflictableTransactionError<StoreError>>(())
+        })?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn delete_content(&self, id: &MessageId) -> Result<bool, StoreError> {
+        let _lock = self.write_lock.lock().unwrap();
+        let (feed, sequence) = match self.keys.get(id.0)?.as_deref().and_then(decode_message_key) {
+            Some(position) => position,
+            None => return Ok(false),
+        };
+        let mut message = match self.get_message(&feed, sequence)? {
+            Some(message) => message,
+            None => return Ok(false),
+        };
+        message.value.delete_content();
+        let data = serde_json::to_vec(&message).expect("Message serializes to JSON");
+        self.messages.insert(message_key(&feed, sequence), data)?;
+        self.db.flush()?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::sign::KeyPair;
+    use crate::test_utils::{check_persistent_store, posts};
+
+    #[test]
+    fn append_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("feeds.db");
+        let messages = posts(&KeyPair::gen(), 10);
+        check_persistent_store(|| SledFeedStore::open(&path).unwrap(), &messages);
+    }
+
+    #[test]
+    fn truncate_and_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = KeyPair::gen();
+        let bob = KeyPair::gen();
+        let alice_feed = FeedId(alice.public);
+        let bob_feed = FeedId(bob.public);
+        let alice_messages = posts(&alice, 3);
+        let bob_messages = posts(&bob, 3);
+        let store = SledFeedStore::open(dir.path().join("feeds.db")).unwrap();
+        for (alice_message, bob_message) in alice_messages.iter().zip(&bob_messages) {
+            store.append(alice_message.clone()).unwrap();
+            store.append(bob_message.clone()).unwrap();
+        }
+
+        store.remove_feed(&bob_feed).unwrap();
+        assert_eq!(store.latest_sequence(&bob_feed).unwrap(), None);
+        assert_eq!(store.feeds().unwrap(), vec![alice_feed]);
+        assert!(store.history(&bob_feed, 1, None).unwrap().is_empty());
+        store.append(bob_messages[0].clone()).unwrap();
+
+        store.truncate(&alice_feed, 2).unwrap();
+        assert_eq!(store.latest_sequence(&alice_feed).unwrap(), Some(1));
+        assert!(!store.delete_content(&alice_messages[2].key).unwrap());
+        // Truncating after the latest message keeps the feed.
+        store.truncate(&alice_feed, 5).unwrap();
+        assert_eq!(store.latest_sequence(&alice_feed).unwrap(), Some(1));
+        store.append(alice_messages[1].clone()).unwrap();
+
+        assert_eq!(
+            store.fsck().unwrap(),
+            FsckReport {
+                messages: 3,
+                removed: 0,
+                repaired: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn delete_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let keypair = KeyPair::gen();
+        let feed = FeedId(keypair.public);
+        let messages = posts(&keypair, 3);
+        let store = SledFeedStore::open(dir.path().join("feeds.db")).unwrap();
+        for message in &messages {
+            store.append(message.clone()).unwrap();
+        }
+        assert!(store.delete_content(&messages[1].key).unwrap());
+        assert!(!store.delete_content(&MessageId([0; 32])).unwrap());
+
+        let deleted = store.get(&feed, 2).unwrap().unwrap();
+        assert!(deleted.value.is_deleted());
+        assert_eq!(deleted.key, messages[1].key);
+        assert_eq!(deleted.value.signature, messages[1].value.signature);
+        assert!(!store.get(&feed, 3).unwrap().unwrap().value.is_deleted());
+    }
+
+    #[test]
+    fn fsck_repairs() {
+        let dir = tempfile::tempdir().unwrap();
+        let keypair = KeyPair::gen();
+        let feed = FeedId(keypair.public);
+        let messages = posts(&keypair, 4);
+        let db = ::sled::open(dir.path().join("feeds.db")).unwrap();
+        let store = SledFeedStore::new(db.clone()).unwrap();
+        for message in &messages {
+            store.append(message.clone()).unwrap();
+        }
+
+        // Damage the third message. It and the message that follows it are removed.
+        let messages_tree = db.open_tree(MESSAGES).unwrap();
+        messages_tree
+            .insert(message_key(&feed, 3), b"{".to_vec())
+            .unwrap();
+        assert!(matches!(
+            store.history(&feed, 1, None),
+            Err(StoreError::Backend { .. })
+        ));
+        // Drop a key entry. fsck restores it.
+        db.open_tree(KEYS)
+            .unwrap()
+            .remove(messages[0].key.0)
+            .unwrap();
+
+        assert_eq!(
+            store.fsck().unwrap(),
+            FsckReport {
+                messages: 2,
+                removed: 2,
+                // The latest sequence, the restored key and the keys of the removed messages
+                repaired: 4,
+            }
+        );
+        assert_eq!(store.latest_sequence(&feed).unwrap(), Some(2));
+        assert_eq!(store.history(&feed, 1, None).unwrap(), messages[..2]);
+        assert!(store.delete_content(&messages[0].key).unwrap());
+        assert!(!store.delete_content(&messages[3].key).unwrap());
+        store.append(messages[2].clone()).unwrap();
+        assert_eq!(store.fsck().unwrap().repaired, 0);
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use super::{FeedId, Message, MessageId};
+
+/// Storage for the messages of feeds.
+///
+/// Implementations only check that messages are appended in sequence. Messages must be validated
+/// before they are appended, for example with [validate][super::validate::validate].
+pub trait FeedStore: Send + Sync {
+    /// Latest message of `feed`. Returns `None` if no message of the feed is stored.
+    fn latest(&self, feed: &FeedId) -> Result<Option<Message>, StoreError>;
+
+    /// Message of `feed` with the given sequence number.
+    fn get(&self, feed: &FeedId, sequence: u64) -> Result<Option<Message>, StoreError>;
+
+    /// Messages of `feed` starting with sequence number `from` in order. Returns at most `limit`
+    /// messages if given.
+    fn history(
+        &self,
+        feed: &FeedId,
+        from: u64,
+        limit: Option<usize>,
+    ) -> Result<Vec<Message>, StoreError>;
+
+    /// Append `message` to the feed of its author.
+    ///
+    /// Fails with [StoreError::Sequence] if the message does not directly follow the latest
+    /// stored message of the feed.
+    fn append(&self, message: Message) -> Result<(), StoreError>;
+
+    /// All feeds with at least one stored message.
+    fn feeds(&self) -> Result<Vec<FeedId>, StoreError>;
+
+    /// Remove the messages of `feed` with sequence number `from` and later. Afterwards the feed
+    /// continues with sequence number `from`.
+    ///
+    /// Fails with [StoreError::Unsupported] unless the store implements it.
+    fn truncate(&self, feed: &FeedId, from: u64) -> Result<(), StoreError> {
+        let _ = (feed, from);
+        Err(StoreError::Unsupported {
+            operation: "truncate",
+        })
+    }
+
+    /// Replace the content of the message `id` with a tombstone, see
+    /// [MessageValue::is_deleted][super::MessageValue::is_deleted]. Returns `false` if the
+    /// message is not stored.
+    ///
+    /// Fails with [StoreError::Unsupported] unless the store implements it.
+    fn delete_content(&self, id: &MessageId) -> Result<bool, StoreError> {
+        let _ = id;
+        Err(StoreError::Unsupported {
+            operation: "delete_content",
+        })
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum StoreError {
+    #[error("Expected sequence number {expected} for feed {feed}, got {actual}")]
+    Sequence {
+        feed: FeedId,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("Store does not support {operation}")]
+    Unsupported { operation: &'static str },
+    /// Error of the storage backend
+    #[error("Storage backend failed")]
+    Backend {
+        #[source]
+        error: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+/// [FeedStore] that keeps all messages in memory.
+#[derive(Debug, Default)]
+pub struct MemoryFeedStore {
+    feeds: RwLock<HashMap<FeedId, Vec<Message>>>,
+}
+
+impl MemoryFeedStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl FeedStore for MemoryFeedStore {
+    fn latest(&self, feed: &FeedId) -> Result<Option<Message>, StoreError> {
+        let feeds = self.feeds.read().unwrap();
+        Ok(feeds
+            .get(feed)
+            .and_then(|messages| messages.last().cloned()))
+    }
+
+    fn get(&self, feed: &FeedId, sequence: u64) -> Result<Option<Message>, StoreError> {
+        let feeds = self.feeds.read().unwrap();
+        Ok(feeds
+            .get(feed)
+            .and_then(|messages| messages.get(sequence.checked_sub(1)? as usize).cloned()))
+    }
+
+    fn history(
+        &self,
+        feed: &FeedId,
+        from: u64,
+        limit: Option<usize>,
+    ) -> Result<Vec<Message>, StoreError> {
+        let feeds = self.feeds.read().unwrap();
+        let messages = feeds.get(feed).map_or(&[][..], Vec::as_slice);
+        let start = (from.max(1) - 1) as usize;
+        Ok(messages
+            .iter()
+            .skip(start)
+            .take(limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect())
+    }
+
+    fn append(&self, message: Message) -> Result<(), StoreError> {
+        let mut feeds = self.feeds.write().unwrap();
+        let feed = message.value.author;
+        let messages = feeds.entry(feed).or_default();
+        let expected = messages.len() as u64 + 1;
+        if message.value.sequence != expected {
+            return Err(StoreError::Sequence {
+                feed,
+                expected,
+                actual: message.value.sequence,
+            });
+        }
+        messages.push(message);
+        Ok(())
+    }
+
+    fn feeds(&self) -> Result<Vec<FeedId>, StoreError> {
+        Ok(self.feeds.read().unwrap().keys().copied().collect())
+    }
+
+    fn truncate(&self, feed: &FeedId, from: u64) -> Result<(), StoreError> {
+        let mut feeds = self.feeds.write().unwrap();
+        if let Some(messages) = feeds.get_mut(feed) {
+            messages.truncate((from.max(1) - 1) as usize);
+            if messages.is_empty() {
+                feeds.remove(feed);
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_content(&self, id: &MessageId) -> Result<bool, StoreError> {
+        let mut feeds = self.feeds.write().unwrap();
+        let message = feeds
+            .values_mut()
+            .flat_map(|messages| messages.iter_mut())
+            .find(|message| message.key == *id);
+        Ok(match message {
+            Some(message) => {
+                message.value.delete_content();
+                true
+            }
+            None => false,
+        })
+    }
+}
@@ -0,0 +1,551 @@
+//! Signing and validation of classic messages.
+//!
+//! A message is signed by serializing its value without the `signature` field like
+//! `JSON.stringify(value, null, 2)` does. The message ID is the SHA256 hash of the serialized
+//! value including the signature. For compatibility with the JavaScript implementation the hash
+//! is computed over the lowest byte of each UTF-16 code unit of the serialized value.
+//!
+//! ```rust
+//! # use ssb::crypto::sign::KeyPair;
+//! # use ssb::feed::validate::{sign, validate};
+//! let keypair = KeyPair::gen();
+//! let first = sign(&keypair, None, 1514517067954u64, serde_json::json!({
+//!     "type": "post",
+//!     "text": "This is the first post!"
+//! }));
+//! let value = serde_json::to_value(&first.value).unwrap();
+//! assert_eq!(validate(None, &value).unwrap().key, first.key);
+//! ```
+//!
+//! Feeds in other formats are validated through the [FeedFormat] trait. [format_of] selects the
+//! format from a feed ID.
+//!
+//! ```rust
+//! # use ssb::feed::validate::format_of;
+//! let format = format_of("@FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=.ed25519").unwrap();
+//! assert_eq!(format.name(), "classic");
+//! let format = format_of("ssb:feed/buttwoo-v1/FCX_tsDLpubCPKKfIrw4gc-SQkHcaD17s7GI6i_ziWY").unwrap();
+//! assert_eq!(format.name(), "buttwoo-v1");
+//! ```
+
+use crate::crypto::sign::{self, KeyPair};
+
+use super::buttwoo;
+use super::{FeedId, Message, MessageValue};
+
+/// Fields of a message value in the order they must appear in.
+const FIELDS: [&str; 7] = [
+    "previous",
+    "author",
+    "sequence",
+    "timestamp",
+    "hash",
+    "content",
+    "signature",
+];
+
+/// Maximum length of a serialized message in UTF-16 code units.
+pub const MAX_MESSAGE_LENGTH: usize = 8192;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    #[error("Invalid message value: {message}")]
+    Decode { message: String },
+    #[error("Message fields must be {:?} in this order", FIELDS)]
+    FieldOrder,
+    #[error("Unsupported hash function {hash:?}")]
+    Hash { hash: String },
+    #[error("Content must be an object with a type or an encrypted string")]
+    Content,
+    #[error("Message is too large")]
+    TooLarge,
+    #[error("Expected sequence number {expected}, got {actual}")]
+    Sequence { expected: u64, actual: u64 },
+    #[error("Previous message ID does not match the latest message of the feed")]
+    Previous,
+    #[error("Author does not match the author of the previous message")]
+    Author,
+    #[error("Invalid signature")]
+    Signature,
+    #[error("Content does not match the content hash")]
+    ContentHash,
+}
+
+/// Validate `value` as the message that follows `previous` in a feed.
+///
+/// `previous` must be `None` if `value` is the first message of the feed.
+pub fn validate(
+    previous: Option<&Message>,
+    value: &serde_json::Value,
+) -> Result<Message, ValidationError> {
+    let (object, message_value, serialized) = decode(value)?;
+
+    match previous {
+        Some(previous) => {
+            let expected = previous.value.sequence + 1;
+            if message_value.sequence != expected {
+                return Err(ValidationError::Sequence {
+                    expected,
+                    actual: message_value.sequence,
+                });
+            }
+            if message_value.previous != Some(previous.key) {
+                return Err(ValidationError::Previous);
+            }
+            if message_value.author != previous.value.author {
+                return Err(ValidationError::Author);
+            }
+        }
+        None => {
+            if message_value.sequence != 1 {
+                return Err(ValidationError::Sequence {
+                    expected: 1,
+                    actual: message_value.sequence,
+                });
+            }
+            if message_value.previous.is_some() {
+                return Err(ValidationError::Previous);
+            }
+        }
+    }
+
+    verify_signature(object, &message_value)?;
+    Ok(Message {
+        key: message_id(&serialized),
+        value: message_value,
+        timestamp: crate::time::now(),
+    })
+}
+
+/// Validate `value` like [validate] but without checking that it follows the previous message of
+/// the feed.
+///
+/// Use this for a message in the middle of a feed when the previous message is not available.
+pub fn verify(value: &serde_json::Value) -> Result<Message, ValidationError> {
+    let (object, message_value, serialized) = decode(value)?;
+    verify_signature(object, &message_value)?;
+    Ok(Message {
+        key: message_id(&serialized),
+        value: message_value,
+        timestamp: crate::time::now(),
+    })
+}
+
+/// Decode `value` and check the rules that do not depend on other messages. Returns the value as
+/// an object, the decoded value and the serialized value.
+fn decode(
+    value: &serde_json::Value,
+) -> Result<
+    (
+        &serde_json::Map<String, serde_json::Value>,
+        MessageValue,
+        String,
+    ),
+    ValidationError,
+> {
+    let object = value.as_object().ok_or_else(|| ValidationError::Decode {
+        message: "expected an object".to_string(),
+    })?;
+    if !object.keys().map(String::as_str).eq(FIELDS.iter().copied()) {
+        return Err(ValidationError::FieldOrder);
+    }
+    let message_value = serde_json::from_value::<MessageValue>(value.clone()).map_err(|error| {
+        ValidationError::Decode {
+            message: error.to_string(),
+        }
+    })?;
+
+    if message_value.hash != "sha256" {
+        return Err(ValidationError::Hash {
+            hash: message_value.hash,
+        });
+    }
+    let valid_content = match &message_value.content {
+        serde_json::Value::Object(_) => message_value
+            .content_type()
+            .is_some_and(|type_| (3..=52).contains(&type_.len())),
+        serde_json::Value::String(encrypted) => encrypted.ends_with(".box"),
+        _ => false,
+    };
+    if !valid_content {
+        return Err(ValidationError::Content);
+    }
+
+    let serialized = serde_json::to_string_pretty(value).unwrap();
+    if serialized.encode_utf16().count() > MAX_MESSAGE_LENGTH {
+        return Err(ValidationError::TooLarge);
+    }
+    Ok((object, message_value, serialized))
+}
+
+/// Check that the signature of the message `object` was made by its author.
+fn verify_signature(
+    object: &serde_json::Map<String, serde_json::Value>,
+    message_value: &MessageValue,
+) -> Result<(), ValidationError> {
+    let signature = message_value
+        .signature
+        .strip_suffix(".sig.ed25519")
+        .and_then(|signature| base64::decode(signature).ok())
+        .and_then(|signature| sign::Signature::from_slice(&signature))
+        .ok_or(ValidationError::Signature)?;
+    let mut unsigned = object.clone();
+    unsigned.remove("signature");
+    let signed_data = serde_json::to_string_pretty(&unsigned).unwrap();
+    if !sign::verify_detached(
+        &signature,
+        signed_data.as_bytes(),
+        message_value.author.public_key(),
+    ) {
+        return Err(ValidationError::Signature);
+    }
+    Ok(())
+}
+
+/// Create and sign the message that follows `previous` in the feed of `keypair`.
+///
+/// Use [crate::time::Timestamps] to obtain a `timestamp` that is greater than the timestamp of
+/// `previous`.
+pub fn sign(
+    keypair: &KeyPair,
+    previous: Option<&Message>,
+    timestamp: impl Into<serde_json::Number>,
+    content: serde_json::Value,
+) -> Message {
+    let identity = ssb_box_stream::SecretKey::from(keypair);
+    sign_with(&identity, previous, timestamp, content).expect("Signing in memory cannot fail")
+}
+
+/// Like [sign] but delegates the signature to `signer`, for example a key in a hardware security
+/// module.
+pub fn sign_with(
+    signer: &dyn ssb_box_stream::Signer,
+    previous: Option<&Message>,
+    timestamp: impl Into<serde_json::Number>,
+    content: serde_json::Value,
+) -> Result<Message, ssb_box_stream::SignerError> {
+    let mut value = serde_json::Map::new();
+    value.insert(
+        "previous".to_string(),
+        serde_json::to_value(previous.map(|previous| previous.key)).unwrap(),
+    );
+    value.insert(
+        "author".to_string(),
+        serde_json::to_value(super::FeedId(sign::PublicKey(signer.public_key().0))).unwrap(),
+    );
+    let sequence = previous.map_or(1, |previous| previous.value.sequence + 1);
+    value.insert("sequence".to_string(), sequence.into());
+    value.insert(
+        "timestamp".to_string(),
+        serde_json::Value::Number(timestamp.into()),
+    );
+    value.insert("hash".to_string(), "sha256".into());
+    value.insert("content".to_string(), content);
+
+    let signed_data = serde_json::to_string_pretty(&value).unwrap();
+    let signature = signer.sign(signed_data.as_bytes())?;
+    value.insert(
+        "signature".to_string(),
+        format!("{}.sig.ed25519", base64::encode(&signature[..])).into(),
+    );
+
+    let serialized = serde_json::to_string_pretty(&value).unwrap();
+    Ok(Message {
+        key: message_id(&serialized),
+        value: serde_json::from_value(serde_json::Value::Object(value)).unwrap(),
+        timestamp: crate::time::now(),
+    })
+}
+
+/// Hash the lowest byte of every UTF-16 code unit like Node’s `binary` encoding does.
+fn message_id(serialized: &str) -> super::MessageId {
+    let data = serialized
+        .encode_utf16()
+        .map(|unit| unit as u8)
+        .collect::<Vec<_>>();
+    super::MessageId(crate::crypto::hash(data))
+}
+
+/// Encoding and validation rules of a feed format.
+pub trait FeedFormat: Send + Sync + std::fmt::Debug {
+    /// Name of the format as used in SSB URIs and EBT, for example `classic`.
+    fn name(&self) -> &'static str;
+
+    /// Returns `true` if `feed` is the ID of a feed in this format.
+    fn is_feed_id(&self, feed: &str) -> bool;
+
+    /// Validate the encoded message `data` as the message that follows `previous`.
+    ///
+    /// `previous` must have been returned by this format.
+    fn validate(
+        &self,
+        previous: Option<&FormatMessage>,
+        data: &[u8],
+    ) -> Result<FormatMessage, ValidationError>;
+}
+
+/// Validated message of any [FeedFormat].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FormatMessage {
+    pub key: String,
+    pub author: String,
+    pub sequence: u64,
+    pub content: serde_json::Value,
+    /// The encoded message as it was received.
+    pub data: Vec<u8>,
+}
+
+/// Classic format where `data` is the JSON message value.
+#[derive(Debug, Clone, Copy)]
+pub struct Classic;
+
+impl FeedFormat for Classic {
+    fn name(&self) -> &'static str {
+        "classic"
+    }
+
+    fn is_feed_id(&self, feed: &str) -> bool {
+        feed.parse::<FeedId>().is_ok()
+    }
+
+    fn validate(
+        &self,
+        previous: Option<&FormatMessage>,
+        data: &[u8],
+    ) -> Result<FormatMessage, ValidationError> {
+        let decode_error = |error: &dyn std::fmt::Display| ValidationError::Decode {
+            message: error.to_string(),
+        };
+        let previous = previous
+            .map(|previous| {
+                Ok::<_, ValidationError>(Message {
+                    key: previous.key.parse().map_err(|error| decode_error(&error))?,
+                    value: serde_json::from_slice(&previous.data)
+                        .map_err(|error| decode_error(&error))?,
+                    timestamp: 0.0,
+                })
+            })
+            .transpose()?;
+        let value = serde_json::from_slice(data).map_err(|error| decode_error(&error))?;
+        let message = validate(previous.as_ref(), &value)?;
+        Ok(FormatMessage {
+            key: message.key.to_string(),
+            author: message.value.author.to_string(),
+            sequence: message.value.sequence,
+            content: message.value.content,
+            data: data.to_vec(),
+        })
+    }
+}
+
+/// Buttwoo format. See [buttwoo].
+#[derive(Debug, Clone, Copy)]
+pub struct Buttwoo;
+
+impl FeedFormat for Buttwoo {
+    fn name(&self) -> &'static str {
+        "buttwoo-v1"
+    }
+
+    fn is_feed_id(&self, feed: &str) -> bool {
+        feed.parse::<buttwoo::ButtwooFeedId>().is_ok()
+    }
+
+    fn validate(
+        &self,
+        previous: Option<&FormatMessage>,
+        data: &[u8],
+    ) -> Result<FormatMessage, ValidationError> {
+        let previous = previous
+            .map(|previous| buttwoo::decode(&previous.data))
+            .transpose()?;
+        let message = buttwoo::validate(previous.as_ref(), data)?;
+        Ok(FormatMessage {
+            key: message.key.to_string(),
+            author: message.author.to_string(),
+            sequence: message.sequence,
+            content: message.content,
+            data: message.data,
+        })
+    }
+}
+
+/// All supported feed formats.
+pub const FORMATS: &[&dyn FeedFormat] = &[&Classic, &Buttwoo];
+
+/// Returns the format of the feed with ID `feed`.
+pub fn format_of(feed: &str) -> Option<&'static dyn FeedFormat> {
+    FORMATS
+        .iter()
+        .copied()
+        .find(|format| format.is_feed_id(feed))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// First message of the example feed in the Scuttlebutt Protocol Guide.
+    const GUIDE_MESSAGE: &str = r#"{
+  "previous": null,
+  "author": "@FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=.ed25519",
+  "sequence": 1,
+  "timestamp": 1514517067954,
+  "hash": "sha256",
+  "content": {
+    "type": "post",
+    "text": "This is the first post!"
+  },
+  "signature": "QYOR/zU9dxE1aKBaxc3C0DJ4gRyZtlMfPLt+CGJcY73sv5abKKKxr1SqhOvnm8TY784VHE8kZHCD8RdzFl1tBA==.sig.ed25519"
+}"#;
+
+    #[test]
+    fn validate_guide_message() {
+        let value = serde_json::from_str::<serde_json::Value>(GUIDE_MESSAGE).unwrap();
+        let message = validate(None, &value).unwrap();
+        assert_eq!(
+            message.key.to_string(),
+            "%XphMUkWQtomKjXQvFGfsGYpt69sgEY7Y4Vou9cEuJho=.sha256"
+        );
+        assert_eq!(serde_json::to_value(&message.value).unwrap(), value);
+    }
+
+    #[test]
+    fn validate_chain() {
+        let keypair = KeyPair::gen();
+        let first = sign(&keypair, None, 1u64, serde_json::json!({ "type": "post" }));
+        let second = sign(
+            &keypair,
+            Some(&first),
+            2u64,
+            serde_json::json!({ "type": "post" }),
+        );
+        let second_value = serde_json::to_value(&second.value).unwrap();
+
+        assert_eq!(
+            validate(Some(&first), &second_value).unwrap().key,
+            second.key
+        );
+        assert_eq!(
+            validate(None, &second_value),
+            Err(ValidationError::Sequence {
+                expected: 1,
+                actual: 2
+            })
+        );
+
+        assert_eq!(verify(&second_value).unwrap().key, second.key);
+
+        let mut tampered = second_value;
+        tampered["content"]["type"] = "vote".into();
+        assert_eq!(
+            validate(Some(&first), &tampered),
+            Err(ValidationError::Signature)
+        );
+        assert_eq!(verify(&tampered), Err(ValidationError::Signature));
+
+        let other = sign(
+            &KeyPair::gen(),
+            None,
+            1u64,
+            serde_json::json!({ "type": "post" }),
+        );
+        let other_value = serde_json::to_value(&other.value).unwrap();
+        assert_eq!(
+            validate(Some(&first), &other_value),
+            Err(ValidationError::Sequence {
+                expected: 2,
+                actual: 1
+            })
+        );
+    }
+
+    #[derive(Debug)]
+    struct UnavailableSigner(ssb_box_stream::PublicKey);
+
+    impl ssb_box_stream::Signer for UnavailableSigner {
+        fn public_key(&self) -> ssb_box_stream::PublicKey {
+            self.0
+        }
+
+        fn sign(&self, _data: &[u8]) -> Result<[u8; 64], ssb_box_stream::SignerError> {
+            Err(ssb_box_stream::SignerError::new("device not available"))
+        }
+
+        fn key_exchange(
+            &self,
+            _public_key: &[u8; 32],
+        ) -> Result<[u8; 32], ssb_box_stream::SignerError> {
+            Err(ssb_box_stream::SignerError::new("device not available"))
+        }
+    }
+
+    #[test]
+    fn sign_with_signer() {
+        let identity = ssb_box_stream::SecretKey::generate();
+        let content = serde_json::json!({ "type": "post" });
+        let message = sign_with(&identity, None, 1u64, content.clone()).unwrap();
+        assert_eq!(
+            message.value.author,
+            FeedId(sign::PublicKey(identity.public_key().0))
+        );
+        assert_eq!(
+            message.key,
+            sign(&KeyPair::from(&identity), None, 1u64, content.clone()).key
+        );
+
+        let unavailable = UnavailableSigner(identity.public_key());
+        assert!(sign_with(&unavailable, None, 1u64, content).is_err());
+    }
+
+    #[test]
+    fn validate_field_order() {
+        let mut value = serde_json::from_str::<serde_json::Value>(GUIDE_MESSAGE).unwrap();
+        let signature = value.as_object_mut().unwrap().remove("signature").unwrap();
+        let mut reordered = serde_json::Map::new();
+        reordered.insert("signature".to_string(), signature);
+        reordered.extend(value.as_object().unwrap().clone());
+        assert_eq!(
+            validate(None, &serde_json::Value::Object(reordered)),
+            Err(ValidationError::FieldOrder)
+        );
+    }
+
+    #[test]
+    fn feed_formats() {
+        let keypair = KeyPair::gen();
+
+        let classic = format_of(&FeedId(keypair.public).to_string()).unwrap();
+        let first = sign(&keypair, None, 1u64, serde_json::json!({ "type": "post" }));
+        let second = sign(
+            &keypair,
+            Some(&first),
+            2u64,
+            serde_json::json!({ "type": "post" }),
+        );
+        let first = classic
+            .validate(None, &serde_json::to_vec(&first.value).unwrap())
+            .unwrap();
+        let validated = classic
+            .validate(Some(&first), &serde_json::to_vec(&second.value).unwrap())
+            .unwrap();
+        assert_eq!(validated.key, second.key.to_string());
+
+        let feed = buttwoo::ButtwooFeedId(keypair.public).to_string();
+        let buttwoo_format = format_of(&feed).unwrap();
+        assert_eq!(buttwoo_format.name(), "buttwoo-v1");
+        let first = buttwoo::sign(&keypair, None, 1.0, serde_json::json!({ "type": "post" }));
+        let second = buttwoo::sign(
+            &keypair,
+            Some(&first),
+            2.0,
+            serde_json::json!({ "type": "post" }),
+        );
+        let first = buttwoo_format.validate(None, &first.data).unwrap();
+        let validated = buttwoo_format.validate(Some(&first), &second.data).unwrap();
+        assert_eq!(validated.author, feed);
+        assert_eq!(validated.sequence, 2);
+
+        assert!(format_of("%R7lJEkz27lNijPhYNDzYoPjM0Fp+bFWzwX0SmNJB/ZE=.sha256").is_none());
+    }
+}
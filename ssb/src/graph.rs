@@ -0,0 +1,308 @@
+//! Follow and block graph computed from `contact` messages.
+//!
+//! The [Graph] records which feeds follow or block other feeds and computes the distance in hops
+//! of every feed from a root feed. This determines which feeds are replicated.
+//!
+//! ```rust
+//! # use ssb::feed::FeedId;
+//! # use ssb::graph::Graph;
+//! # use ssb::crypto::sign::KeyPair;
+//! # let alice = FeedId::from(KeyPair::gen().public);
+//! # let bob = FeedId::from(KeyPair::gen().public);
+//! # let carol = FeedId::from(KeyPair::gen().public);
+//! let mut graph = Graph::new(alice, 2);
+//! graph.follow(alice, bob);
+//! graph.follow(bob, carol);
+//! graph.block(alice, carol);
+//!
+//! assert_eq!(graph.hops_of(&bob), Some(1));
+//! assert_eq!(graph.hops_of(&carol), Some(-1));
+//! assert!(graph.is_blocked(&alice, &carol));
+//! ```
+
+use futures::channel::mpsc;
+use futures::prelude::*;
+use std::collections::{HashMap, VecDeque};
+
+use crate::feed::{FeedId, Message};
+
+/// Relation of one feed to another as declared by the latest `contact` message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Relation {
+    Follow,
+    Block,
+}
+
+/// Changes to the hops of feeds. `None` means that the feed is no longer within range.
+pub type HopsUpdate = HashMap<FeedId, Option<i64>>;
+
+/// Hops value of feeds that are blocked by the root feed.
+pub const BLOCKED: i64 = -1;
+
+#[derive(Debug)]
+pub struct Graph {
+    root: FeedId,
+    max_hops: u32,
+    edges: HashMap<FeedId, HashMap<FeedId, Relation>>,
+    hops: HashMap<FeedId, i64>,
+    subscribers: Vec<mpsc::UnboundedSender<HopsUpdate>>,
+}
+
+#[derive(serde::Deserialize)]
+struct ContactContent {
+    contact: FeedId,
+    following: Option<bool>,
+    blocking: Option<bool>,
+}
+
+impl Graph {
+    /// Create an empty graph that computes the hops from `root` up to `max_hops`.
+    pub fn new(root: FeedId, max_hops: u32) -> Self {
+        let mut graph = Self {
+            root,
+            max_hops,
+            edges: HashMap::new(),
+            hops: HashMap::new(),
+            subscribers: Vec::new(),
+        };
+        graph.hops = graph.compute_hops();
+        graph
+    }
+
+    pub fn root(&self) -> &FeedId {
+        &self.root
+    }
+
+    /// Update the graph with a message. Messages that are not `contact` messages or that are
+    /// malformed are ignored.
+    ///
+    /// Messages must be applied in the order they were published by their author.
+    pub fn apply(&mut self, message: &Message) {
+        if message.value.content_type() != Some("contact") {
+            return;
+        }
+        let content = match serde_json::from_value::<ContactContent>(message.value.content.clone())
+        {
+            Ok(content) => content,
+            Err(_) => return,
+        };
+        let relation = match (content.following, content.blocking) {
+            (_, Some(true)) => Some(Relation::Block),
+            (Some(true), _) => Some(Relation::Follow),
+            (Some(false), _) | (_, Some(false)) => None,
+            (None, None) => return,
+        };
+        self.set_relation(message.value.author, content.contact, relation);
+    }
+
+    pub fn follow(&mut self, source: FeedId, target: FeedId) {
+        self.set_relation(source, target, Some(Relation::Follow))
+    }
+
+    pub fn block(&mut self, source: FeedId, target: FeedId) {
+        self.set_relation(source, target, Some(Relation::Block))
+    }
+
+    /// Set the relation of `source` to `target`. `None` removes any follow or block.
+    pub fn set_relation(&mut self, source: FeedId, target: FeedId, relation: Option<Relation>) {
+        let edges = self.edges.entry(source).or_default();
+        let changed = match relation {
+            Some(relation) => edges.insert(target, relation) != Some(relation),
+            None => edges.remove(&target).is_some(),
+        };
+        if changed {
+            self.update_hops();
+        }
+    }
+
+    pub fn relation(&self, source: &FeedId, target: &FeedId) -> Option<Relation> {
+        self.edges.get(source)?.get(target).copied()
+    }
+
+    pub fn is_following(&self, source: &FeedId, target: &FeedId) -> bool {
+        self.relation(source, target) == Some(Relation::Follow)
+    }
+
+    /// Returns `true` if `source` blocks `target`.
+    pub fn is_blocked(&self, source: &FeedId, target: &FeedId) -> bool {
+        self.relation(source, target) == Some(Relation::Block)
+    }
+
+    /// Distance of all feeds within range from the root.
+    ///
+    /// The root has hops `0`, feeds it follows have hops `1` and so on. Feeds blocked by the root
+    /// have hops [BLOCKED] and are not traversed. Blocks by other feeds are not taken into account.
+    pub fn hops(&self) -> &HashMap<FeedId, i64> {
+        &self.hops
+    }
+
+    /// Distance of `feed` from the root. Returns `None` if the feed is not within range.
+    pub fn hops_of(&self, feed: &FeedId) -> Option<i64> {
+        self.hops.get(feed).copied()
+    }
+
+    /// Stream of changes to [Graph::hops].
+    ///
+    /// The first item contains the hops of all feeds currently in range.
+    pub fn hops_stream(&mut self) -> impl Stream<Item = HopsUpdate> {
+        let (sender, receiver) = mpsc::unbounded();
+        let initial = self.hops.iter().map(|(feed, hops)| (*feed, Some(*hops)));
+        let _ = sender.unbounded_send(initial.collect());
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    fn update_hops(&mut self) {
+        let hops = self.compute_hops();
+        let mut update = HopsUpdate::new();
+        for (feed, value) in &hops {
+            if self.hops.get(feed) != Some(value) {
+                update.insert(*feed, Some(*value));
+            }
+        }
+        for feed in self.hops.keys() {
+            if !hops.contains_key(feed) {
+                update.insert(*feed, None);
+            }
+        }
+        self.hops = hops;
+        if !update.is_empty() {
+            self.subscribers
+                .retain(|subscriber| subscriber.unbounded_send(update.clone()).is_ok());
+        }
+    }
+
+    /// Breadth-first search from the root along follow edges.
+    fn compute_hops(&self) -> HashMap<FeedId, i64> {
+        let mut hops = HashMap::new();
+        hops.insert(self.root, 0);
+        let root_edges = self.edges.get(&self.root);
+        for (target, relation) in root_edges.into_iter().flatten() {
+            if *relation == Relation::Block && *target != self.root {
+                hops.insert(*target, BLOCKED);
+            }
+        }
+
+        let mut queue = VecDeque::new();
+        queue.push_back((self.root, 0));
+        while let Some((feed, distance)) = queue.pop_front() {
+            if distance >= self.max_hops as i64 {
+                continue;
+            }
+            for (target, relation) in self.edges.get(&feed).into_iter().flatten() {
+                if *relation == Relation::Follow && !hops.contains_key(target) {
+                    hops.insert(*target, distance + 1);
+                    queue.push_back((*target, distance + 1));
+                }
+            }
+        }
+        hops
+    }
+}
+
+/// Apply all messages from `log` to `graph`. Resolves when the log stream ends.
+///
+/// The lock is only held while a message is applied so the graph can be queried concurrently.
+pub async fn ingest(graph: &std::sync::Mutex<Graph>, log: impl Stream<Item = Message>) {
+    futures::pin_mut!(log);
+    while let Some(message) = log.next().await {
+        graph.lock().unwrap().apply(&message);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::sign::KeyPair;
+    use crate::feed::{MessageId, MessageValue};
+
+    fn feed() -> FeedId {
+        FeedId::from(KeyPair::gen().public)
+    }
+
+    fn contact_message(author: FeedId, content: serde_json::Value) -> Message {
+        Message {
+            key: MessageId([0; 32]),
+            value: MessageValue {
+                previous: None,
+                author,
+                sequence: 1,
+                timestamp: 0.into(),
+                hash: "sha256".to_string(),
+                content,
+                signature: String::new(),
+            },
+            timestamp: 0.0,
+        }
+    }
+
+    #[test]
+    fn hops_radius() {
+        let [a, b, c, d] = [feed(), feed(), feed(), feed()];
+        let mut graph = Graph::new(a, 2);
+        graph.follow(a, b);
+        graph.follow(b, c);
+        graph.follow(c, d);
+        graph.follow(c, a);
+
+        let expected = [(a, 0), (b, 1), (c, 2)].iter().copied().collect();
+        assert_eq!(graph.hops(), &expected);
+    }
+
+    #[test]
+    fn apply_contact_messages() {
+        let [a, b] = [feed(), feed()];
+        let mut graph = Graph::new(a, 3);
+
+        graph.apply(&contact_message(
+            a,
+            serde_json::json!({ "type": "contact", "contact": b, "following": true }),
+        ));
+        assert!(graph.is_following(&a, &b));
+        assert_eq!(graph.hops_of(&b), Some(1));
+
+        graph.apply(&contact_message(
+            a,
+            serde_json::json!({ "type": "contact", "contact": b, "blocking": true }),
+        ));
+        assert!(graph.is_blocked(&a, &b));
+        assert_eq!(graph.hops_of(&b), Some(BLOCKED));
+
+        graph.apply(&contact_message(
+            a,
+            serde_json::json!({ "type": "contact", "contact": b, "blocking": false }),
+        ));
+        assert_eq!(graph.relation(&a, &b), None);
+        assert_eq!(graph.hops_of(&b), None);
+
+        graph.apply(&contact_message(
+            a,
+            serde_json::json!({ "type": "post", "contact": b, "following": true }),
+        ));
+        assert_eq!(graph.relation(&a, &b), None);
+    }
+
+    #[async_std::test]
+    async fn hops_stream() {
+        let [a, b, c] = [feed(), feed(), feed()];
+        let mut graph = Graph::new(a, 2);
+        let mut updates = graph.hops_stream();
+        assert_eq!(
+            updates.next().await.unwrap(),
+            [(a, Some(0))].iter().copied().collect()
+        );
+
+        graph.follow(b, c);
+        graph.follow(a, b);
+        assert_eq!(
+            updates.next().await.unwrap(),
+            [(b, Some(1)), (c, Some(2))].iter().copied().collect()
+        );
+
+        graph.set_relation(a, b, None);
+        assert_eq!(
+            updates.next().await.unwrap(),
+            [(b, None), (c, None)].iter().copied().collect()
+        );
+    }
+}
@@ -0,0 +1,435 @@
+//! Join rooms with [HTTP invites].
+//!
+//! A room hands out invites as URIs of the form
+//! `ssb:experimental?action=claim-http-invite&invite=<code>&postTo=<url>`. To use an invite the
+//! client
+//!
+//! 1. claims it by posting its feed ID and the invite code to `postTo`. The room answers with
+//!    its multiserver address.
+//! 2. connects to the room and calls `httpInviteAccept` with the invite code. Afterwards the
+//!    client is a member and can connect with the returned [Credentials].
+//!
+//! [accept] does both steps. The built-in HTTP client only supports plain `http` URLs. For
+//! `https` URLs use [Invite::claim_body] and [parse_claim_response] with an HTTP client that
+//! supports TLS and then call [accept_claimed].
+//!
+//! [HTTP invites]: https://github.com/ssb-ngi-pointer/ssb-http-invite-spec
+use async_std::net::TcpStream;
+use futures::prelude::*;
+
+use crate::crypto::sign::KeyPair;
+use crate::feed::FeedId;
+use crate::multi_address::{Address, AddressError, DialError, Dialer, MultiAddress};
+
+/// Value of the `action` query parameter of invite URIs.
+pub const CLAIM_ACTION: &str = "claim-http-invite";
+
+/// Muxrpc method the client calls to accept a claimed invite.
+pub const ACCEPT_METHOD: &str = "httpInviteAccept";
+
+#[derive(thiserror::Error, Debug)]
+pub enum HttpInviteError {
+    #[error("Invalid invite URI: {reason}")]
+    InvalidUri { reason: &'static str },
+    #[error("Cannot claim invite at {url}, only http URLs are supported")]
+    UnsupportedUrl { url: String },
+    #[error("HTTP request to {url} failed")]
+    Http {
+        url: String,
+        #[source]
+        error: std::io::Error,
+    },
+    #[error("Room responded with HTTP status {status}")]
+    HttpStatus { status: u16 },
+    #[error("Failed to decode claim response")]
+    DecodeResponse(#[source] serde_json::Error),
+    #[error("Room rejected the invite: {message}")]
+    Rejected { message: String },
+    #[error("Room address is invalid")]
+    Address(#[from] AddressError),
+    #[error("Failed to connect to room")]
+    Dial(#[from] DialError),
+    #[error("Handshake with room failed")]
+    Handshake(#[from] ssb_box_stream::Error),
+    #[error("Room did not accept the invite")]
+    Accept(#[source] crate::rpc::ssb::Error),
+}
+
+/// Invite parsed from an invite URI.
+///
+/// ```rust
+/// # use ssb::httpinvite::Invite;
+/// let invite = "ssb:experimental?action=claim-http-invite&invite=39c0ac1850ec9af14f1bb73&postTo=http%3A%2F%2Froom.example.com%2Finvite%2Fconsume"
+///     .parse::<Invite>()
+///     .unwrap();
+/// assert_eq!(invite.code, "39c0ac1850ec9af14f1bb73");
+/// assert_eq!(invite.post_to, "http://room.example.com/invite/consume");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+    pub code: String,
+    /// URL the invite is claimed at
+    pub post_to: String,
+}
+
+impl Invite {
+    /// JSON body of the claim request for the feed `id`.
+    pub fn claim_body(&self, id: &FeedId) -> serde_json::Value {
+        serde_json::json!({ "id": id, "invite": self.code })
+    }
+}
+
+impl std::str::FromStr for Invite {
+    type Err = HttpInviteError;
+
+    fn from_str(uri: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason| HttpInviteError::InvalidUri { reason };
+        let query = uri
+            .strip_prefix("ssb:experimental?")
+            .ok_or_else(|| invalid("expected ssb:experimental URI"))?;
+        let mut action = None;
+        let mut code = None;
+        let mut post_to = None;
+        for pair in query.split('&') {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_decode(value).ok_or_else(|| invalid("invalid percent encoding"))?;
+            match key {
+                "action" => action = Some(value),
+                "invite" => code = Some(value),
+                "postTo" => post_to = Some(value),
+                _ => {}
+            }
+        }
+        if action.as_deref() != Some(CLAIM_ACTION) {
+            return Err(invalid("action is not claim-http-invite"));
+        }
+        Ok(Invite {
+            code: code.ok_or_else(|| invalid("missing invite parameter"))?,
+            post_to: post_to.ok_or_else(|| invalid("missing postTo parameter"))?,
+        })
+    }
+}
+
+/// Decode `%XX` escapes and `+` in a URI query value.
+fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = value.bytes();
+    let mut decoded = Vec::with_capacity(value.len());
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let high = char::from(bytes.next()?).to_digit(16)?;
+                let low = char::from(bytes.next()?).to_digit(16)?;
+                decoded.push((high * 16 + low) as u8);
+            }
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Parse the body of the response to a claim request and return the address of the room.
+pub fn parse_claim_response(body: &[u8]) -> Result<MultiAddress, HttpInviteError> {
+    #[derive(serde::Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Response {
+        status: String,
+        error: Option<String>,
+        multiserver_address: Option<MultiAddress>,
+    }
+    let response =
+        serde_json::from_slice::<Response>(body).map_err(HttpInviteError::DecodeResponse)?;
+    match response {
+        Response {
+            status,
+            multiserver_address: Some(address),
+            ..
+        } if status == "successful" => Ok(address),
+        Response { status, error, .. } => Err(HttpInviteError::Rejected {
+            message: error.unwrap_or(status),
+        }),
+    }
+}
+
+/// Claim `invite` for `id` and return the address of the room.
+pub async fn claim(invite: &Invite, id: &FeedId) -> Result<MultiAddress, HttpInviteError> {
+    let body = serde_json::to_vec(&invite.claim_body(id)).expect("JSON value serializes");
+    let (status, response) = http_post_json(&invite.post_to, &body).await?;
+    // Rooms report errors with a JSON body, too.
+    match parse_claim_response(&response) {
+        Err(HttpInviteError::DecodeResponse(_)) if !(200..300).contains(&status) => {
+            Err(HttpInviteError::HttpStatus { status })
+        }
+        result => result,
+    }
+}
+
+/// Send a `POST` request with a JSON body to a plain `http` URL and return the status code and
+/// response body.
+async fn http_post_json(url: &str, body: &[u8]) -> Result<(u16, Vec<u8>), HttpInviteError> {
+    let unsupported = || HttpInviteError::UnsupportedUrl {
+        url: url.to_string(),
+    };
+    let http_error = |error| HttpInviteError::Http {
+        url: url.to_string(),
+        error,
+    };
+    let invalid_data = |message| {
+        http_error(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            message,
+        ))
+    };
+
+    let rest = url.strip_prefix("http://").ok_or_else(unsupported)?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() || authority.contains('@') {
+        return Err(unsupported());
+    }
+    let address = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+
+    let mut stream = TcpStream::connect(address).await.map_err(http_error)?;
+    // HTTP/1.0 responses are never chunked and end when the server closes the connection.
+    let head = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nAccept: application/json\r\nContent-Length: {}\r\n\r\n",
+        path,
+        authority,
+        body.len()
+    );
+    stream
+        .write_all(head.as_bytes())
+        .await
+        .map_err(http_error)?;
+    stream.write_all(body).await.map_err(http_error)?;
+    let mut response = Vec::new();
+    stream
+        .read_to_end(&mut response)
+        .await
+        .map_err(http_error)?;
+
+    let header_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| invalid_data("HTTP response has no body"))?;
+    let status = std::str::from_utf8(&response[..header_end])
+        .ok()
+        .and_then(|head| head.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid_data("Invalid HTTP status line"))?;
+    Ok((status, response.split_off(header_end + 4)))
+}
+
+/// Everything a member needs to connect to a room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    /// Address the invite was accepted at
+    pub address: Address,
+    pub room_id: FeedId,
+}
+
+/// Claim `invite`, connect to the room and accept the invite.
+pub async fn accept(
+    invite: &Invite,
+    keypair: &KeyPair,
+    network_key: &crate::NetworkKey,
+    dialer: &Dialer,
+) -> Result<Credentials, HttpInviteError> {
+    let room = claim(invite, &FeedId(keypair.public)).await?;
+    accept_claimed(&invite.code, &room, keypair, network_key, dialer).await
+}
+
+/// Connect to the room at `room` and accept an invite that has already been claimed.
+///
+/// Races connections to the addresses of `room` with [Dialer::connect] and uses the first one
+/// that completes the handshake.
+pub async fn accept_claimed(
+    code: &str,
+    room: &MultiAddress,
+    keypair: &KeyPair,
+    network_key: &crate::NetworkKey,
+    dialer: &Dialer,
+) -> Result<Credentials, HttpInviteError> {
+    let (address, (client, room_id)) = dialer
+        .connect(room, |address, stream| {
+            handshake(address, stream, keypair, network_key)
+        })
+        .await?;
+    accept_with(client, code).await?;
+    Ok(Credentials { address, room_id })
+}
+
+async fn handshake(
+    address: Address,
+    stream: TcpStream,
+    keypair: &KeyPair,
+    network_key: &crate::NetworkKey,
+) -> Result<(crate::rpc::ssb::Client, FeedId), HttpInviteError> {
+    let room_key = ssb_box_stream::PublicKey::from_slice(&address.shs_key()?)
+        .map_err(|_| AddressError::MissingShs)?;
+    let identity = ssb_box_stream::SecretKey::from(keypair);
+    let (sender, receiver) =
+        ssb_box_stream::Client::new(network_key, &room_key, &identity.public_key(), &identity)
+            .connect(stream)
+            .await?;
+    Ok((
+        crate::rpc::ssb::Client::new(sender, receiver),
+        FeedId::from(room_key),
+    ))
+}
+
+async fn accept_with(
+    mut client: crate::rpc::ssb::Client,
+    code: &str,
+) -> Result<(), HttpInviteError> {
+    let result = client
+        .send_async_typed::<crate::rpc::ssb::Json<serde_json::Value>>(
+            &[ACCEPT_METHOD],
+            vec![code.into()],
+        )
+        .await
+        .map_err(HttpInviteError::Accept);
+    if let Err(error) = client.close().await {
+        tracing::debug!(?error, "failed to close room connection");
+    }
+    result.map(|_| ())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_invite() {
+        let error = |uri: &str| match uri.parse::<Invite>() {
+            Err(HttpInviteError::InvalidUri { reason }) => reason,
+            result => panic!("Unexpected result {:?}", result),
+        };
+        assert_eq!(
+            error("ssb:experimental?action=join-room&invite=a&postTo=b"),
+            "action is not claim-http-invite"
+        );
+        assert_eq!(
+            error("ssb:experimental?action=claim-http-invite&invite=a"),
+            "missing postTo parameter"
+        );
+        assert_eq!(
+            error("ssb:experimental?action=claim-http-invite&invite=%zz"),
+            "invalid percent encoding"
+        );
+        assert_eq!(
+            error("https://room.example.com"),
+            "expected ssb:experimental URI"
+        );
+    }
+
+    #[test]
+    fn claim_response() {
+        let address = parse_claim_response(
+            br#"{ "status": "successful", "multiserverAddress": "net:10.0.0.1:8008~shs:AA==" }"#,
+        )
+        .unwrap();
+        assert_eq!(address.to_string(), "net:10.0.0.1:8008~shs:AA==");
+
+        let error = parse_claim_response(br#"{ "status": "failed", "error": "invite expired" }"#);
+        assert!(matches!(
+            error,
+            Err(HttpInviteError::Rejected { message }) if message == "invite expired"
+        ));
+    }
+
+    #[async_std::test]
+    async fn accept_invite() {
+        let network_key = crate::NetworkKey::test_network("httpinvite");
+        let room_identity = ssb_box_stream::SecretKey::generate();
+        let keypair = KeyPair::gen();
+
+        let (accepted_sender, mut accepted) = futures::channel::mpsc::unbounded();
+        let room_listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let room_address = Address::net_shs(
+            &as_v4(room_listener.local_addr().unwrap()),
+            room_identity.public_key().as_bytes(),
+        );
+        let server =
+            ssb_box_stream::Server::new(&network_key, &room_identity.public_key(), &room_identity);
+        async_std::task::spawn(async move {
+            let (stream, _) = room_listener.accept().await.unwrap();
+            let (sender, receiver, _) = server.accept(stream).await.unwrap();
+            let mut service = crate::rpc::base::Service::new();
+            service.add_async(ACCEPT_METHOD, move |(code,): (String,)| {
+                accepted_sender.unbounded_send(code).unwrap();
+                future::ready(crate::rpc::base::service::AsyncResponse::json_ok(&true))
+            });
+            crate::rpc::base::Endpoint::new(sender, receiver, service)
+                .join()
+                .await
+                .unwrap();
+        });
+
+        let http_listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let http_address = http_listener.local_addr().unwrap();
+        let response_address = room_address.to_string();
+        let (claim_sender, mut claims) = futures::channel::mpsc::unbounded();
+        async_std::task::spawn(async move {
+            let (mut stream, _) = http_listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while !request.ends_with(b"}") {
+                let read = stream.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            claim_sender.unbounded_send(request).unwrap();
+            let body = serde_json::json!({
+                "status": "successful",
+                "multiserverAddress": response_address,
+            })
+            .to_string();
+            let response = format!(
+                "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{}",
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let invite = Invite {
+            code: "abc".to_string(),
+            post_to: format!("http://{}/invite/consume", http_address),
+        };
+        let credentials = accept(&invite, &keypair, &network_key, &Dialer::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            credentials,
+            Credentials {
+                address: room_address,
+                room_id: FeedId::from(room_identity.public_key()),
+            }
+        );
+
+        let request = String::from_utf8(claims.next().await.unwrap()).unwrap();
+        assert!(request.starts_with("POST /invite/consume HTTP/1.0\r\n"));
+        let body = request.split("\r\n\r\n").nth(1).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(body).unwrap(),
+            invite.claim_body(&FeedId(keypair.public))
+        );
+        assert_eq!(accepted.next().await.unwrap(), "abc");
+    }
+
+    fn as_v4(address: std::net::SocketAddr) -> std::net::SocketAddrV4 {
+        match address {
+            std::net::SocketAddr::V4(address) => address,
+            std::net::SocketAddr::V6(_) => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,509 @@
+//! Manage the key pairs of several identities in a [Keystore].
+//!
+//! Besides the main identity that owns the feed of the user, applications may use separate
+//! identities, for example one per app, and keep the seeds of invites they handed out. Every
+//! [Identity] in a keystore has a unique name and a [Role].
+//!
+//! A keystore is stored encrypted with a passphrase. The encryption key is derived from the
+//! passphrase with Argon2id and the identities are encrypted with XSalsa20-Poly1305.
+//!
+//! ```rust
+//! # use ssb::identity::{Keystore, Role};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut keystore = Keystore::new();
+//! keystore.generate("me", Role::Main)?;
+//! keystore.generate("chess", Role::App("chess".to_string()))?;
+//!
+//! let sealed = keystore.seal("correct horse battery staple")?;
+//! let keystore = Keystore::open(&sealed, "correct horse battery staple")?;
+//! let server = keystore.main().unwrap().server(&ssb::SCUTTLEBUTT_NETWORK_KEY);
+//! # Ok(())
+//! # }
+//! ```
+use std::path::{Path, PathBuf};
+use zeroize::{Zeroize as _, Zeroizing};
+
+use crate::crypto::{pwhash, secretbox, sign};
+use crate::feed::FeedId;
+
+/// Version of the encrypted keystore format written by [Keystore::seal].
+const VERSION: u32 = 1;
+
+/// Lowest Argon2id limits accepted by libsodium (`crypto_pwhash_argon2id_OPSLIMIT_MIN` and
+/// `crypto_pwhash_argon2id_MEMLIMIT_MIN`).
+const OPSLIMIT_MIN: usize = 1;
+const MEMLIMIT_MIN: usize = 8192;
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeystoreError {
+    #[error("Failed to access keystore file {path}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        error: std::io::Error,
+    },
+
+    #[error("Failed to decode keystore")]
+    Json(
+        #[source]
+        #[from]
+        serde_json::Error,
+    ),
+
+    #[error("Failed to decode base64 string")]
+    Base64(
+        #[source]
+        #[from]
+        base64::DecodeError,
+    ),
+
+    #[error("Unsupported keystore version {0}")]
+    UnsupportedVersion(u32),
+
+    /// The key derivation limits stored in the keystore are below the minimum of libsodium or
+    /// above the limits for sensitive data.
+    #[error("Invalid key derivation limits (opslimit {opslimit}, memlimit {memlimit})")]
+    InvalidLimits { opslimit: usize, memlimit: usize },
+
+    /// Usually because the memory for the key derivation could not be allocated
+    #[error("Failed to derive key from passphrase")]
+    DeriveKey,
+
+    #[error("Wrong passphrase or corrupted keystore")]
+    Decrypt,
+
+    #[error("Invalid secret key for identity {0}")]
+    InvalidSecretKey(String),
+
+    #[error("Identity {0} already exists")]
+    DuplicateName(String),
+
+    #[error("Keystore already has a main identity")]
+    DuplicateMain,
+}
+
+/// What an [Identity] is used for.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Role {
+    /// Identity of the user. A keystore has at most one.
+    Main,
+    /// Identity used by the app with the given name instead of the main identity.
+    App(String),
+    /// Key pair derived from the seed of an invite code.
+    Invite,
+}
+
+/// Named key pair in a [Keystore].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    name: String,
+    role: Role,
+    key_pair: sign::KeyPair,
+}
+
+impl Identity {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn role(&self) -> &Role {
+        &self.role
+    }
+
+    pub fn key_pair(&self) -> &sign::KeyPair {
+        &self.key_pair
+    }
+
+    pub fn feed_id(&self) -> FeedId {
+        FeedId::from(self.key_pair.public)
+    }
+
+    /// Handshake client that authenticates with this identity to the server with
+    /// `server_identity_pk`.
+    pub fn client(
+        &self,
+        network_key: &crate::NetworkKey,
+        server_identity_pk: &ssb_box_stream::PublicKey,
+    ) -> ssb_box_stream::Client {
+        let identity = ssb_box_stream::SecretKey::from(&self.key_pair);
+        ssb_box_stream::Client::new(
+            network_key,
+            server_identity_pk,
+            &identity.public_key(),
+            &identity,
+        )
+    }
+
+    /// Handshake server that accepts connections for this identity.
+    pub fn server(&self, network_key: &crate::NetworkKey) -> ssb_box_stream::Server {
+        let identity = ssb_box_stream::SecretKey::from(&self.key_pair);
+        ssb_box_stream::Server::new(network_key, &identity.public_key(), &identity)
+    }
+}
+
+/// Identities with unique names. See the [module documentation][self].
+#[derive(Debug, Clone)]
+pub struct Keystore {
+    identities: Vec<Identity>,
+    limits: (pwhash::OpsLimit, pwhash::MemLimit),
+}
+
+impl Keystore {
+    /// Empty keystore that is sealed with the interactive Argon2id limits.
+    pub fn new() -> Self {
+        Self {
+            identities: Vec::new(),
+            limits: (pwhash::OPSLIMIT_INTERACTIVE, pwhash::MEMLIMIT_INTERACTIVE),
+        }
+    }
+
+    /// Use `ops` and `mem` for the key derivation when sealing. Higher limits make guessing the
+    /// passphrase more expensive but also slow down [Keystore::open].
+    pub fn with_limits(mut self, ops: pwhash::OpsLimit, mem: pwhash::MemLimit) -> Self {
+        self.limits = (ops, mem);
+        self
+    }
+
+    /// Add an identity with the key pair `key_pair`.
+    pub fn insert(
+        &mut self,
+        name: &str,
+        role: Role,
+        key_pair: sign::KeyPair,
+    ) -> Result<&Identity, KeystoreError> {
+        if self.get(name).is_some() {
+            return Err(KeystoreError::DuplicateName(name.to_string()));
+        }
+        if role == Role::Main && self.main().is_some() {
+            return Err(KeystoreError::DuplicateMain);
+        }
+        self.identities.push(Identity {
+            name: name.to_string(),
+            role,
+            key_pair,
+        });
+        Ok(self.identities.last().unwrap())
+    }
+
+    /// Add an identity with a new random key pair.
+    pub fn generate(&mut self, name: &str, role: Role) -> Result<&Identity, KeystoreError> {
+        self.insert(name, role, sign::KeyPair::gen())
+    }
+
+    /// Add the key pair of an invite with `seed`. See [crate::invite::InviteCode].
+    pub fn insert_invite(
+        &mut self,
+        name: &str,
+        seed: &sign::Seed,
+    ) -> Result<&Identity, KeystoreError> {
+        let (public, secret) = sign::keypair_from_seed(seed);
+        self.insert(name, Role::Invite, sign::KeyPair::new(public, secret))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Identity> {
+        self.identities
+            .iter()
+            .find(|identity| identity.name == name)
+    }
+
+    pub fn main(&self) -> Option<&Identity> {
+        self.identities
+            .iter()
+            .find(|identity| identity.role == Role::Main)
+    }
+
+    /// Identity used by the app `app`, if any.
+    pub fn app(&self, app: &str) -> Option<&Identity> {
+        self.identities
+            .iter()
+            .find(|identity| matches!(&identity.role, Role::App(name) if name == app))
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Identity> {
+        let index = self
+            .identities
+            .iter()
+            .position(|identity| identity.name == name)?;
+        Some(self.identities.remove(index))
+    }
+
+    /// Identities in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = &Identity> {
+        self.identities.iter()
+    }
+
+    /// Encrypt the keystore with `passphrase`.
+    pub fn seal(&self, passphrase: &str) -> Result<Vec<u8>, KeystoreError> {
+        let identities = self
+            .identities
+            .iter()
+            .map(|identity| StoredIdentity {
+                name: identity.name.clone(),
+                role: identity.role.clone(),
+                secret: base64::encode(&identity.key_pair.secret),
+            })
+            .collect::<Vec<_>>();
+        let plain_text = Zeroizing::new(serde_json::to_vec(&identities)?);
+
+        let (ops, mem) = self.limits;
+        let salt = pwhash::gen_salt();
+        let key = derive_key(passphrase, &salt, ops, mem)?;
+        let nonce = secretbox::gen_nonce();
+        let sealed = Sealed {
+            version: VERSION,
+            opslimit: ops.0,
+            memlimit: mem.0,
+            salt: base64::encode(salt),
+            nonce: base64::encode(nonce),
+            cipher_text: base64::encode(secretbox::seal(&plain_text, &nonce, &key)),
+        };
+        Ok(serde_json::to_vec(&sealed)?)
+    }
+
+    /// Decrypt a keystore created by [Keystore::seal].
+    ///
+    /// The keystore is sealed with the same limits again. Limits above
+    /// [pwhash::OPSLIMIT_SENSITIVE] and [pwhash::MEMLIMIT_SENSITIVE] are rejected so that a
+    /// tampered keystore cannot make the key derivation run for hours or exhaust the memory.
+    pub fn open(data: &[u8], passphrase: &str) -> Result<Self, KeystoreError> {
+        let sealed = serde_json::from_slice::<Sealed>(data)?;
+        if sealed.version != VERSION {
+            return Err(KeystoreError::UnsupportedVersion(sealed.version));
+        }
+        let salt = pwhash::Salt::from_slice(&base64::decode(&sealed.salt)?)
+            .ok_or(KeystoreError::Decrypt)?;
+        let nonce = secretbox::Nonce::from_slice(&base64::decode(&sealed.nonce)?)
+            .ok_or(KeystoreError::Decrypt)?;
+        if !(OPSLIMIT_MIN..=pwhash::OPSLIMIT_SENSITIVE.0).contains(&sealed.opslimit)
+            || !(MEMLIMIT_MIN..=pwhash::MEMLIMIT_SENSITIVE.0).contains(&sealed.memlimit)
+        {
+            return Err(KeystoreError::InvalidLimits {
+                opslimit: sealed.opslimit,
+                memlimit: sealed.memlimit,
+            });
+        }
+        let ops = pwhash::OpsLimit(sealed.opslimit);
+        let mem = pwhash::MemLimit(sealed.memlimit);
+        let key = derive_key(passphrase, &salt, ops, mem)?;
+        let plain_text = Zeroizing::new(
+            secretbox::open(&base64::decode(&sealed.cipher_text)?, &nonce, &key)
+                .map_err(|()| KeystoreError::Decrypt)?,
+        );
+
+        let mut keystore = Self::new().with_limits(ops, mem);
+        for stored in serde_json::from_slice::<Vec<StoredIdentity>>(&plain_text)? {
+            let secret =
+                sign::SecretKey::from_slice(&Zeroizing::new(base64::decode(&stored.secret)?))
+                    .ok_or_else(|| KeystoreError::InvalidSecretKey(stored.name.clone()))?;
+            let key_pair = sign::KeyPair::new(secret.public_key(), secret);
+            keystore.insert(&stored.name, stored.role.clone(), key_pair)?;
+        }
+        Ok(keystore)
+    }
+
+    /// [Seal][Keystore::seal] the keystore and write it to `path`.
+    ///
+    /// The data is written and synced to a temporary file first that then replaces `path`, so
+    /// `path` holds either the old or the new keystore even if the process crashes. On Unix the
+    /// file is only readable and writable by the owner.
+    pub fn save(&self, path: &Path, passphrase: &str) -> Result<(), KeystoreError> {
+        use std::io::Write as _;
+
+        let data = self.seal(passphrase)?;
+        let tmp_path = path.with_extension("tmp");
+        let io_error = |error| KeystoreError::Io {
+            path: path.to_owned(),
+            error,
+        };
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp_path).map_err(io_error)?;
+        file.write_all(&data).map_err(io_error)?;
+        file.sync_all().map_err(io_error)?;
+        drop(file);
+        std::fs::rename(&tmp_path, path).map_err(io_error)
+    }
+
+    /// Read the keystore at `path` and [open][Keystore::open] it.
+    pub fn load(path: &Path, passphrase: &str) -> Result<Self, KeystoreError> {
+        let data = std::fs::read(path).map_err(|error| KeystoreError::Io {
+            path: path.to_owned(),
+            error,
+        })?;
+        Self::open(&data, passphrase)
+    }
+}
+
+impl Default for Keystore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Sealed {
+    version: u32,
+    opslimit: usize,
+    memlimit: usize,
+    salt: String,
+    nonce: String,
+    cipher_text: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StoredIdentity {
+    name: String,
+    role: Role,
+    secret: String,
+}
+
+impl Drop for StoredIdentity {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+fn derive_key(
+    passphrase: &str,
+    salt: &pwhash::Salt,
+    ops: pwhash::OpsLimit,
+    mem: pwhash::MemLimit,
+) -> Result<secretbox::Key, KeystoreError> {
+    let mut key = secretbox::Key([0u8; secretbox::KEYBYTES]);
+    pwhash::derive_key(&mut key.0, passphrase.as_bytes(), salt, ops, mem)
+        .map_err(|()| KeystoreError::DeriveKey)?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Keystore with the lowest limits to keep the tests fast.
+    fn keystore() -> Keystore {
+        Keystore::new().with_limits(pwhash::OpsLimit(1), pwhash::MemLimit(8192))
+    }
+
+    #[test]
+    fn seal_open() {
+        let mut keystore = keystore();
+        keystore.generate("me", Role::Main).unwrap();
+        keystore
+            .generate("chess", Role::App("chess".to_string()))
+            .unwrap();
+        keystore
+            .insert_invite("invite", &sign::Seed([3u8; 32]))
+            .unwrap();
+
+        let sealed = keystore.seal("secret").unwrap();
+        let opened = Keystore::open(&sealed, "secret").unwrap();
+        assert_eq!(
+            opened.iter().collect::<Vec<_>>(),
+            keystore.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(opened.app("chess").unwrap().name(), "chess");
+        assert_eq!(
+            opened.get("invite").unwrap().key_pair().public,
+            sign::keypair_from_seed(&sign::Seed([3u8; 32])).0
+        );
+
+        assert!(matches!(
+            Keystore::open(&sealed, "wrong"),
+            Err(KeystoreError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn invalid_limits() {
+        let sealed = keystore().seal("secret").unwrap();
+        let mut value = serde_json::from_slice::<serde_json::Value>(&sealed).unwrap();
+        value["opslimit"] = (pwhash::OPSLIMIT_SENSITIVE.0 + 1).into();
+        assert!(matches!(
+            Keystore::open(&serde_json::to_vec(&value).unwrap(), "secret"),
+            Err(KeystoreError::InvalidLimits { .. })
+        ));
+
+        value["opslimit"] = 1.into();
+        value["memlimit"] = usize::MAX.into();
+        assert!(matches!(
+            Keystore::open(&serde_json::to_vec(&value).unwrap(), "secret"),
+            Err(KeystoreError::InvalidLimits { .. })
+        ));
+    }
+
+    #[test]
+    fn save_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keystore.json");
+        let mut keystore = keystore();
+        keystore.generate("me", Role::Main).unwrap();
+        keystore.save(&path, "secret").unwrap();
+        // Overwrites the existing keystore
+        keystore.generate("other", Role::Invite).unwrap();
+        keystore.save(&path, "secret").unwrap();
+
+        let loaded = Keystore::load(&path, "secret").unwrap();
+        assert_eq!(
+            loaded.iter().collect::<Vec<_>>(),
+            keystore.iter().collect::<Vec<_>>()
+        );
+        assert!(!path.with_extension("tmp").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn unique_names() {
+        let mut keystore = keystore();
+        keystore.generate("me", Role::Main).unwrap();
+        assert!(matches!(
+            keystore.generate("me", Role::Invite),
+            Err(KeystoreError::DuplicateName(_))
+        ));
+        assert!(matches!(
+            keystore.generate("other", Role::Main),
+            Err(KeystoreError::DuplicateMain)
+        ));
+        keystore.remove("me").unwrap();
+        keystore.generate("other", Role::Main).unwrap();
+    }
+
+    #[async_std::test]
+    async fn handshake() {
+        let mut keystore = keystore();
+        let server = keystore.generate("server", Role::Main).unwrap().clone();
+        let client = keystore
+            .generate("client", Role::App("app".to_string()))
+            .unwrap()
+            .clone();
+        let network_key = crate::SCUTTLEBUTT_NETWORK_KEY;
+
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_pk = ssb_box_stream::PublicKey(server.key_pair().public.0);
+        let accept = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            server.server(&network_key).accept(stream).await.unwrap()
+        };
+        let connect = async {
+            let stream = async_std::net::TcpStream::connect(addr).await.unwrap();
+            client
+                .client(&network_key, &server_pk)
+                .connect(stream)
+                .await
+                .unwrap()
+        };
+        let ((_, _, client_pk), _) = futures::join!(accept, connect);
+        assert_eq!(FeedId::from(client_pk), client.feed_id());
+    }
+}
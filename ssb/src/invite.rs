@@ -0,0 +1,369 @@
+//! Pub invites and the `invite` RPC plugin.
+//!
+//! A pub hands out invite codes of the form `<host>:<port>:<pub ID>~<seed>`. The seed is the
+//! secret of a temporary identity. The guest connects to the pub with this identity and calls
+//! `invite.use` with its feed ID. The pub then follows the guest and responds with the `contact`
+//! message. Every invite can be used a limited number of times.
+//!
+//! The [Invites] plugin serves `invite.create` to the pub itself and to local clients and
+//! `invite.use` to connections that authenticate with an invite identity. Invites are only kept in
+//! memory.
+//!
+//! ```rust
+//! # use ssb::invite::InviteCode;
+//! let code = "pub.example.com:8008:@FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=.ed25519~\
+//!             JuGVd5P6ZRmKNdm1GEGfE+kpJ/UlJcv7uhW8A4Wn0qA=";
+//! let invite = code.parse::<InviteCode>().unwrap();
+//! assert_eq!(invite.address, "pub.example.com:8008");
+//! assert_eq!(invite.to_string(), code);
+//! ```
+use futures::prelude::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::crypto::sign::{self, KeyPair, PublicKey};
+use crate::feed::{FeedId, Message};
+use crate::plugin::{Context, Plugin};
+use crate::replicate::{AddError, Replicator};
+use crate::rpc::base::service::{AsyncResponse, Error, Service};
+use crate::rpc::ssb::InviteCreateParams;
+
+/// Invite code that identifies the pub and contains the secret of the invite identity.
+#[derive(Clone, PartialEq, Eq)]
+pub struct InviteCode {
+    /// Host and port of the pub
+    pub address: String,
+    pub pub_id: FeedId,
+    pub seed: sign::Seed,
+}
+
+impl InviteCode {
+    /// Identity the guest uses to connect to the pub.
+    pub fn keypair(&self) -> KeyPair {
+        let (public, secret) = sign::keypair_from_seed(&self.seed);
+        KeyPair::new(public, secret)
+    }
+}
+
+impl std::fmt::Display for InviteCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}~{}",
+            self.address,
+            self.pub_id,
+            base64::encode(&self.seed)
+        )
+    }
+}
+
+impl std::fmt::Debug for InviteCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InviteCode")
+            .field("address", &self.address)
+            .field("pub_id", &self.pub_id)
+            .field("seed", &"<secret>")
+            .finish()
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid invite code: {reason}")]
+pub struct InviteCodeError {
+    pub reason: &'static str,
+}
+
+impl std::str::FromStr for InviteCode {
+    type Err = InviteCodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rest, seed) = s.rsplit_once('~').ok_or(InviteCodeError {
+            reason: "missing seed",
+        })?;
+        let (address, pub_id) = rest.rsplit_once(':').ok_or(InviteCodeError {
+            reason: "missing pub ID",
+        })?;
+        if address.is_empty() {
+            return Err(InviteCodeError {
+                reason: "missing address",
+            });
+        }
+        let pub_id = pub_id.parse().map_err(|_| InviteCodeError {
+            reason: "invalid pub ID",
+        })?;
+        let seed = base64::decode(seed)
+            .ok()
+            .and_then(|seed| sign::Seed::from_slice(&seed))
+            .ok_or(InviteCodeError {
+                reason: "invalid seed",
+            })?;
+        Ok(Self {
+            address: address.to_string(),
+            pub_id,
+            seed,
+        })
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RedeemError {
+    #[error("Invite does not exist or has been used up")]
+    Invalid,
+    #[error("Failed to publish contact message")]
+    Publish(#[from] AddError),
+}
+
+/// The `invite` plugin of a pub. Clones share the invites.
+#[derive(Clone)]
+pub struct Invites {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    keypair: KeyPair,
+    address: String,
+    replicator: Replicator,
+    timestamps: crate::time::Timestamps,
+    /// Remaining uses by the public key of the invite identity
+    invites: Mutex<HashMap<PublicKey, u32>>,
+    /// Held while a message is signed and added so that messages are published in order.
+    publish_lock: Mutex<()>,
+}
+
+impl std::fmt::Debug for Invites {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Invites")
+            .field("pub_id", &FeedId(self.inner.keypair.public))
+            .field("address", &self.inner.address)
+            .finish()
+    }
+}
+
+/// Arguments of `invite.use`
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct UseArgs {
+    feed: FeedId,
+}
+
+impl Invites {
+    /// Invites for the pub with identity `keypair` that is reachable at `address` (host and
+    /// port). The pub publishes the `contact` messages for redeemed invites through `replicator`.
+    pub fn new(keypair: KeyPair, address: impl ToString, replicator: Replicator) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                keypair,
+                address: address.to_string(),
+                replicator,
+                timestamps: crate::time::Timestamps::new(),
+                invites: Mutex::new(HashMap::new()),
+                publish_lock: Mutex::new(()),
+            }),
+        }
+    }
+
+    pub fn pub_id(&self) -> FeedId {
+        FeedId(self.inner.keypair.public)
+    }
+
+    /// Create an invite that can be used `uses` times.
+    pub fn create(&self, uses: u32) -> InviteCode {
+        let seed = sign::Seed::from_slice(&crate::crypto::randombytes::randombytes(32)).unwrap();
+        let code = InviteCode {
+            address: self.inner.address.clone(),
+            pub_id: self.pub_id(),
+            seed,
+        };
+        self.inner
+            .invites
+            .lock()
+            .unwrap()
+            .insert(code.keypair().public, uses);
+        code
+    }
+
+    /// Whether `key` is the identity of an invite that has uses left.
+    pub fn is_invite(&self, key: &PublicKey) -> bool {
+        self.inner.invites.lock().unwrap().contains_key(key)
+    }
+
+    /// Use the invite with identity `key` to make the pub follow `feed`. Returns the `contact`
+    /// message.
+    pub fn redeem(&self, key: &PublicKey, feed: FeedId) -> Result<Message, RedeemError> {
+        self.take_use(key)?;
+        let content = serde_json::json!({
+            "type": "contact",
+            "contact": feed,
+            "following": true,
+        });
+        match self.publish(content) {
+            Ok(message) => {
+                tracing::info!(%feed, "invite redeemed");
+                Ok(message)
+            }
+            Err(error) => {
+                *self.inner.invites.lock().unwrap().entry(*key).or_insert(0) += 1;
+                Err(error.into())
+            }
+        }
+    }
+
+    fn take_use(&self, key: &PublicKey) -> Result<(), RedeemError> {
+        let mut invites = self.inner.invites.lock().unwrap();
+        match invites.get(key).copied() {
+            None | Some(0) => return Err(RedeemError::Invalid),
+            Some(1) => invites.remove(key),
+            Some(uses) => invites.insert(*key, uses - 1),
+        };
+        Ok(())
+    }
+
+    /// Sign a message with `content` and add it to the feed of the pub.
+    fn publish(&self, content: serde_json::Value) -> Result<Message, AddError> {
+        let _publish_guard = self.inner.publish_lock.lock().unwrap();
+        let replicator = &self.inner.replicator;
+        let latest = replicator.store().latest(&self.pub_id())?;
+        let message = crate::feed::validate::sign(
+            &self.inner.keypair,
+            latest.as_ref(),
+            self.inner.timestamps.next(latest.as_ref()),
+            content,
+        );
+        replicator.add(&serde_json::to_value(&message.value).unwrap())?;
+        Ok(message)
+    }
+}
+
+impl Plugin for Invites {
+    fn name(&self) -> &str {
+        "invite"
+    }
+
+    fn init(&self, service: &mut Service, context: &Context) {
+        let peer = context.peer;
+
+        let invites = self.clone();
+        service.add_async("create", move |(params,): (InviteCreateParams,)| {
+            // Only the pub itself and local clients may create invites.
+            let response = if peer.is_none() || peer == Some(invites.inner.keypair.public) {
+                AsyncResponse::json_ok(&invites.create(params.uses).to_string())
+            } else {
+                AsyncResponse::Err(Error::new("Error", "Not allowed to create invites"))
+            };
+            future::ready(response)
+        });
+        service.describe(
+            "create",
+            "Create an invite",
+            &[("params", "Object with the number of `uses` of the invite")],
+        );
+
+        let invites = self.clone();
+        service.add_async("use", move |(args,): (UseArgs,)| {
+            let invites = invites.clone();
+            async move {
+                let result = match peer {
+                    Some(key) => invites.redeem(&key, args.feed),
+                    None => Err(RedeemError::Invalid),
+                };
+                match result {
+                    Ok(message) => AsyncResponse::json_ok(&serde_json::json!({
+                        "key": message.key,
+                        "value": message.value,
+                    })),
+                    Err(error) => AsyncResponse::Err(Error::new("Error", error)),
+                }
+            }
+        });
+        service.describe(
+            "use",
+            "Use the invite the connection authenticated with",
+            &[("args", "Object with the `feed` the pub follows")],
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::feed::MemoryFeedStore;
+    use crate::graph::Graph;
+    use crate::plugin::Plugins;
+    use crate::rpc::base::{AsyncResponse as Response, Endpoint};
+    use async_std::os::unix::net::UnixStream;
+
+    fn invites() -> Invites {
+        let keypair = KeyPair::gen();
+        let graph = Graph::new(FeedId(keypair.public), 2);
+        let replicator = Replicator::new(
+            Arc::new(MemoryFeedStore::new()),
+            Arc::new(Mutex::new(graph)),
+        );
+        Invites::new(keypair, "localhost:8008", replicator)
+    }
+
+    /// Client connected to `invites` as `peer`.
+    fn connect(invites: &Invites, peer: Option<PublicKey>) -> (Endpoint, Endpoint) {
+        let mut plugins = Plugins::new();
+        plugins.add(invites.clone()).unwrap();
+        let (client_io, server_io) = UnixStream::pair().unwrap();
+        let server = Endpoint::from_io(server_io, plugins.service(&Context { peer }));
+        (Endpoint::from_io(client_io, Service::new()), server)
+    }
+
+    async fn call(client: &mut Endpoint, method: &str, arg: serde_json::Value) -> Response {
+        client
+            .client()
+            .send_async(vec!["invite".to_string(), method.to_string()], vec![arg])
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn invite_code() {
+        let invites = invites();
+        let code = invites.create(1);
+        assert_eq!(code.to_string().parse::<InviteCode>().unwrap(), code);
+        assert_eq!(code.pub_id, invites.pub_id());
+        assert!(invites.is_invite(&code.keypair().public));
+
+        assert!("localhost:8008:@AAAA.ed25519~AAAA"
+            .parse::<InviteCode>()
+            .is_err());
+        assert_eq!(
+            "localhost".parse::<InviteCode>(),
+            Err(InviteCodeError {
+                reason: "missing seed"
+            })
+        );
+    }
+
+    #[async_std::test]
+    async fn use_invite() {
+        let invites = invites();
+        let alice = FeedId(KeyPair::gen().public);
+
+        let (mut local, _server) = connect(&invites, None);
+        let code = match call(&mut local, "create", serde_json::json!({ "uses": 1 })).await {
+            Response::Json(code) => serde_json::from_slice::<String>(&code).unwrap(),
+            response => panic!("unexpected response {:?}", response),
+        };
+        let code = code.parse::<InviteCode>().unwrap();
+
+        let (mut guest, _server) = connect(&invites, Some(code.keypair().public));
+        let created = call(&mut guest, "create", serde_json::json!({ "uses": 1 })).await;
+        assert!(matches!(created, Response::Error(_)), "{:?}", created);
+
+        let args = serde_json::json!({ "feed": alice });
+        let used = call(&mut guest, "use", args.clone()).await;
+        assert!(matches!(used, Response::Json(_)), "{:?}", used);
+        let graph = invites.inner.replicator.graph();
+        assert!(graph
+            .lock()
+            .unwrap()
+            .is_following(&invites.pub_id(), &alice));
+        assert!(!invites.is_invite(&code.keypair().public));
+
+        let used = call(&mut guest, "use", args).await;
+        assert!(matches!(used, Response::Error(_)), "{:?}", used);
+    }
+}
@@ -1,9 +1,13 @@
 //! An unfinished implementation of the [Scuttlebut protocol][protocol] in rust
 //!
 //! [protocol]: https://ssbc.github.io/scuttlebutt-protocol-guide
+//!
+//! When compiling for `wasm32` targets only the client stack is available: [rpc],
+//! [multi_address] and the `websocket` transport. The [bipf] encoding is available, too.
 
 #![warn(missing_debug_implementations, clippy::all)]
 
+#[cfg(not(target_arch = "wasm32"))]
 #[macro_use]
 extern crate prettytable;
 
@@ -11,15 +15,62 @@ extern crate prettytable;
 #[macro_use]
 mod test_utils;
 
+#[cfg(not(target_arch = "wasm32"))]
+pub mod about;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod addressbook;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod admin;
+pub mod bipf;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod blobs;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod config;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod conn;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod crypto;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod discovery;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod feed;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod graph;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod httpinvite;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod identity;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod invite;
 pub mod multi_address;
+#[cfg(all(feature = "nat", not(target_arch = "wasm32")))]
+pub mod nat;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod plugin;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod private_box;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod pub_server;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod replicate;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod room;
 pub mod rpc;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod secret_file;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod ssbc;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod store;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod time;
 pub mod utils;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod votes;
+#[cfg(target_arch = "wasm32")]
+pub mod websocket;
+
+pub use ssb_box_stream::NetworkKey;
 
-pub const SCUTTLEBUTT_NETWORK_IDENTIFIER: [u8; 32] = [
-    0xd4, 0xa1, 0xcb, 0x88, 0xa6, 0x6f, 0x02, 0xf8, 0xdb, 0x63, 0x5c, 0xe2, 0x64, 0x41, 0xcc, 0x5d,
-    0xac, 0x1b, 0x08, 0x42, 0x0c, 0xea, 0xac, 0x23, 0x08, 0x39, 0xb7, 0x55, 0x84, 0x5a, 0x9f, 0xfb,
-];
+/// Network key of the main Scuttlebutt network used in the handshake.
+pub const SCUTTLEBUTT_NETWORK_KEY: NetworkKey = NetworkKey::MAIN_NET;
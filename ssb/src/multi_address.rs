@@ -27,6 +27,42 @@
 //!
 //! assert_eq!(multi_address.to_string(), multi_address_string);
 //! ```
+//!
+//! The first protocol of an address determines the [Transport] that a [Dialer] uses to connect
+//! to the peer. Besides IP addresses the dialer supports host names that are resolved with DNS
+//! and onion addresses that are dialed through a SOCKS5 proxy.
+//!
+//! ```rust
+//! # use ssb::multi_address::{Address, Transport};
+//! let address: Address = "onion:example.onion:8008~shs:3q2+7w==".parse().unwrap();
+//! assert_eq!(
+//!     address.transport().unwrap(),
+//!     Transport::Onion { host: "example.onion".to_string(), port: 8008 }
+//! );
+//! ```
+
+#[cfg(not(target_arch = "wasm32"))]
+use futures::prelude::*;
+#[cfg(not(target_arch = "wasm32"))]
+use std::convert::TryFrom;
+
+/// Implements `Serialize` and `Deserialize` using the `Display` and `FromStr` implementations.
+macro_rules! impl_serde_via_string {
+    ($type:ty) => {
+        impl serde::Serialize for $type {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $type {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let s = String::deserialize(deserializer)?;
+                s.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MultiAddress {
@@ -63,7 +99,9 @@ impl std::fmt::Display for MultiAddress {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl_serde_via_string!(MultiAddress);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Address {
     pub protocols: Vec<Protocol>,
 }
@@ -81,6 +119,36 @@ impl Address {
             protocols: vec![Protocol::net(socket_addr_v4), Protocol::shs(key)],
         }
     }
+
+    /// Returns the transport given by the first protocol of the address.
+    pub fn transport(&self) -> Result<Transport, AddressError> {
+        let protocol = self.protocols.first().ok_or(AddressError::Empty)?;
+        Transport::from_protocol(protocol)
+    }
+
+    /// Returns the public key of the peer from the `shs` protocol.
+    pub fn shs_key(&self) -> Result<Vec<u8>, AddressError> {
+        let protocol = self
+            .protocols
+            .iter()
+            .find(|protocol| protocol.name == "shs")
+            .ok_or(AddressError::MissingShs)?;
+        protocol
+            .data
+            .first()
+            .and_then(|key| base64::decode(key).ok())
+            .ok_or_else(|| AddressError::InvalidData {
+                protocol: protocol.to_string(),
+            })
+    }
+}
+
+impl std::str::FromStr for Address {
+    type Err = peg::error::ParseError<peg::str::LineCol>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parser::address(s)
+    }
 }
 
 impl std::fmt::Display for Address {
@@ -97,7 +165,9 @@ impl std::fmt::Display for Address {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl_serde_via_string!(Address);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Protocol {
     pub name: String,
     pub data: Vec<String>,
@@ -119,6 +189,25 @@ impl Protocol {
         }
     }
 
+    /// ```rust
+    /// # use ssb::multi_address::Protocol;
+    /// let protocol = Protocol::dns("example.com", 8008);
+    /// assert_eq!(protocol.to_string(), "dns:example.com:8008");
+    /// ```
+    pub fn dns(host: &str, port: u16) -> Self {
+        Self {
+            name: "dns".to_string(),
+            data: vec![host.to_string(), port.to_string()],
+        }
+    }
+
+    pub fn onion(host: &str, port: u16) -> Self {
+        Self {
+            name: "onion".to_string(),
+            data: vec![host.to_string(), port.to_string()],
+        }
+    }
+
     pub fn shs(key: &[u8]) -> Self {
         Self {
             name: "shs".to_string(),
@@ -148,6 +237,388 @@ impl std::fmt::Display for Protocol {
     }
 }
 
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum AddressError {
+    #[error("Address has no protocols")]
+    Empty,
+    #[error("Unsupported transport protocol {name}")]
+    UnsupportedTransport { name: String },
+    #[error("Invalid data for protocol {protocol}")]
+    InvalidData { protocol: String },
+    #[error("Address has no shs protocol")]
+    MissingShs,
+}
+
+/// Network transport used to connect to a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    /// `net:<ip>:<port>`
+    Net(std::net::SocketAddr),
+    /// `dns:<host>:<port>` or `net:<host>:<port>` where the host is not an IP address. The host
+    /// is resolved when dialing.
+    Dns { host: String, port: u16 },
+    /// `onion:<host>:<port>`. Requires a SOCKS5 proxy to dial.
+    Onion { host: String, port: u16 },
+}
+
+impl Transport {
+    pub fn from_protocol(protocol: &Protocol) -> Result<Self, AddressError> {
+        let invalid = || AddressError::InvalidData {
+            protocol: protocol.to_string(),
+        };
+        let (host, port) = match protocol.data.as_slice() {
+            [host, port] => (host.clone(), port.parse::<u16>().map_err(|_| invalid())?),
+            _ => return Err(invalid()),
+        };
+        match protocol.name.as_str() {
+            "net" => match host.parse::<std::net::IpAddr>() {
+                Ok(ip) => Ok(Self::Net(std::net::SocketAddr::new(ip, port))),
+                Err(_) => Ok(Self::Dns { host, port }),
+            },
+            "dns" => Ok(Self::Dns { host, port }),
+            "onion" if host.ends_with(".onion") => Ok(Self::Onion { host, port }),
+            "onion" => Err(invalid()),
+            name => Err(AddressError::UnsupportedTransport {
+                name: name.to_string(),
+            }),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum DialError {
+    #[error(transparent)]
+    Address(#[from] AddressError),
+    #[error("Cannot dial onion address without a SOCKS5 proxy")]
+    NoProxy,
+    #[error("SOCKS5 proxy failed with reply {reply}")]
+    Proxy { reply: u8 },
+    #[error("Failed to connect")]
+    Io(#[from] std::io::Error),
+    #[error("Handshake failed")]
+    Handshake(#[source] anyhow::Error),
+    #[error("Multiaddress contains no address")]
+    NoAddress,
+}
+
+/// Default for [Dialer::with_attempt_delay], as recommended by [RFC
+/// 8305](https://tools.ietf.org/html/rfc8305#section-5).
+#[cfg(not(target_arch = "wasm32"))]
+pub const DEFAULT_ATTEMPT_DELAY: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// Opens TCP connections to [Address]es. Not available on `wasm32` targets.
+///
+/// Onion addresses are dialed through a SOCKS5 proxy, for example a Tor daemon. With
+/// [Dialer::proxy_all] every connection goes through the proxy.
+///
+/// [Dialer::connect] races connection attempts to all addresses of a [MultiAddress] and all IP
+/// addresses that host names resolve to.
+///
+/// ```no_run
+/// # async {
+/// # use ssb::multi_address::{Address, Dialer};
+/// let dialer = Dialer::new().with_socks5_proxy("127.0.0.1:9050".parse().unwrap());
+/// let address: Address = "onion:example.onion:8008~shs:3q2+7w==".parse().unwrap();
+/// let stream = dialer.dial(&address).await.unwrap();
+/// # };
+/// ```
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone, Default)]
+pub struct Dialer {
+    socks5_proxy: Option<std::net::SocketAddr>,
+    proxy_all: bool,
+    attempt_delay: Option<std::time::Duration>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Dialer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Dial onion addresses through the SOCKS5 proxy at `proxy`, for example a Tor daemon.
+    pub fn with_socks5_proxy(mut self, proxy: std::net::SocketAddr) -> Self {
+        self.socks5_proxy = Some(proxy);
+        self
+    }
+
+    /// Dial all addresses through the SOCKS5 proxy set with [Dialer::with_socks5_proxy]. Host
+    /// names are resolved by the proxy so that DNS requests do not bypass it.
+    pub fn proxy_all(mut self) -> Self {
+        self.proxy_all = true;
+        self
+    }
+
+    /// Returns `true` if the dialer supports the transport of `address`.
+    pub fn can_dial(&self, address: &Address) -> bool {
+        match address.transport() {
+            Ok(Transport::Onion { .. }) => self.socks5_proxy.is_some(),
+            Ok(_) => true,
+            Err(_) => false,
+        }
+    }
+
+    /// Time to wait for a connection attempt in [Dialer::connect] before starting the next one.
+    /// Defaults to [DEFAULT_ATTEMPT_DELAY].
+    pub fn with_attempt_delay(mut self, delay: std::time::Duration) -> Self {
+        self.attempt_delay = Some(delay);
+        self
+    }
+
+    /// Open a TCP connection using the transport of `address`.
+    pub async fn dial(&self, address: &Address) -> Result<async_std::net::TcpStream, DialError> {
+        let target = self.target(address.transport()?)?;
+        self.open(&target).await
+    }
+
+    /// Connect to one of the addresses of `multi_address` and run `handshake` on the connection.
+    ///
+    /// Host names are resolved to all their IP addresses, alternating between IPv6 and IPv4. The
+    /// connection attempts are started in order, each one after the previous attempt failed or
+    /// after the [attempt delay][Dialer::with_attempt_delay] has passed. The first attempt whose
+    /// handshake succeeds wins and all other attempts are cancelled. If all attempts fail the
+    /// error of the last one is returned.
+    ///
+    /// ```no_run
+    /// # async {
+    /// # use ssb::multi_address::{Dialer, MultiAddress};
+    /// let dialer = Dialer::new();
+    /// let multi_address: MultiAddress = "net:192.0.2.1:8008~shs:3q2+7w==;dns:example.com:8008~shs:3q2+7w=="
+    ///     .parse()
+    ///     .unwrap();
+    /// let (address, stream) = dialer
+    ///     .connect(&multi_address, |_address, stream| async move {
+    ///         Ok::<_, std::io::Error>(stream)
+    ///     })
+    ///     .await
+    ///     .unwrap();
+    /// # };
+    /// ```
+    pub async fn connect<T, E, F, Fut>(
+        &self,
+        multi_address: &MultiAddress,
+        handshake: F,
+    ) -> Result<(Address, T), DialError>
+    where
+        F: Fn(Address, async_std::net::TcpStream) -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: Into<anyhow::Error>,
+    {
+        let (targets, mut last_error) = self.resolve(multi_address).await;
+        let attempt_delay = self.attempt_delay.unwrap_or(DEFAULT_ATTEMPT_DELAY);
+        let handshake = &handshake;
+        let attempt = |(address, target): (Address, Target)| async move {
+            let stream = self.open(&target).await?;
+            let result = handshake(address.clone(), stream)
+                .await
+                .map_err(|error| DialError::Handshake(error.into()));
+            if let Err(error) = &result {
+                tracing::debug!(%address, ?error, "connection attempt failed");
+            }
+            Ok((address, result?))
+        };
+
+        let mut targets = targets.into_iter();
+        let mut attempts = futures::stream::FuturesUnordered::new();
+        let mut start_next = true;
+        loop {
+            if start_next {
+                if let Some(target) = targets.next() {
+                    attempts.push(attempt(target));
+                }
+            }
+            if attempts.is_empty() {
+                return Err(last_error.unwrap_or(DialError::NoAddress));
+            }
+            let delay = futures_timer::Delay::new(attempt_delay);
+            start_next = match future::select(attempts.next(), delay).await {
+                future::Either::Left((Some(Ok(connected)), _)) => return Ok(connected),
+                future::Either::Left((Some(Err(error)), _)) => {
+                    last_error = Some(error);
+                    true
+                }
+                future::Either::Left((None, _)) | future::Either::Right(_) => true,
+            };
+        }
+    }
+
+    fn target(&self, transport: Transport) -> Result<Target, DialError> {
+        if self.proxy_all && self.socks5_proxy.is_some() {
+            let (host, port) = match transport {
+                Transport::Net(socket_addr) => (socket_addr.ip().to_string(), socket_addr.port()),
+                Transport::Dns { host, port } | Transport::Onion { host, port } => (host, port),
+            };
+            return Ok(Target::Proxied { host, port });
+        }
+        match transport {
+            Transport::Net(socket_addr) => Ok(Target::Direct(socket_addr)),
+            Transport::Dns { host, port } => Ok(Target::Host { host, port }),
+            Transport::Onion { host, port } => {
+                if self.socks5_proxy.is_none() {
+                    return Err(DialError::NoProxy);
+                }
+                Ok(Target::Proxied { host, port })
+            }
+        }
+    }
+
+    /// Targets for all addresses in `multi_address` with host names resolved to IP addresses.
+    /// Also returns the last error for addresses that cannot be dialed.
+    async fn resolve(
+        &self,
+        multi_address: &MultiAddress,
+    ) -> (Vec<(Address, Target)>, Option<DialError>) {
+        let mut targets = Vec::new();
+        let mut last_error = None;
+        for address in &multi_address.addresses {
+            let target = address
+                .transport()
+                .map_err(DialError::from)
+                .and_then(|transport| self.target(transport));
+            match target {
+                Ok(Target::Host { host, port }) => {
+                    match async_std::net::ToSocketAddrs::to_socket_addrs(&(host.as_str(), port))
+                        .await
+                    {
+                        Ok(socket_addrs) => targets.extend(
+                            interleave_families(socket_addrs.collect())
+                                .into_iter()
+                                .map(|socket_addr| (address.clone(), Target::Direct(socket_addr))),
+                        ),
+                        Err(error) => last_error = Some(error.into()),
+                    }
+                }
+                Ok(target) => targets.push((address.clone(), target)),
+                Err(error) => last_error = Some(error),
+            }
+        }
+        (targets, last_error)
+    }
+
+    async fn open(&self, target: &Target) -> Result<async_std::net::TcpStream, DialError> {
+        let stream = match target {
+            Target::Direct(socket_addr) => async_std::net::TcpStream::connect(socket_addr).await?,
+            Target::Host { host, port } => {
+                async_std::net::TcpStream::connect((host.as_str(), *port)).await?
+            }
+            Target::Proxied { host, port } => {
+                let proxy = self.socks5_proxy.ok_or(DialError::NoProxy)?;
+                let mut stream = async_std::net::TcpStream::connect(proxy).await?;
+                socks5_connect(&mut stream, host, *port).await?;
+                stream
+            }
+        };
+        Ok(stream)
+    }
+}
+
+/// Where [Dialer] opens a TCP connection to.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Target {
+    Direct(std::net::SocketAddr),
+    /// Host name that is resolved when connecting
+    Host {
+        host: String,
+        port: u16,
+    },
+    /// Host name or IP address that the SOCKS5 proxy connects to
+    Proxied {
+        host: String,
+        port: u16,
+    },
+}
+
+/// Order `socket_addrs` so that IPv6 and IPv4 addresses alternate, starting with the family of
+/// the first address.
+#[cfg(not(target_arch = "wasm32"))]
+fn interleave_families(socket_addrs: Vec<std::net::SocketAddr>) -> Vec<std::net::SocketAddr> {
+    let first_is_ipv6 = match socket_addrs.first() {
+        Some(socket_addr) => socket_addr.is_ipv6(),
+        None => return socket_addrs,
+    };
+    let mut interleaved = Vec::with_capacity(socket_addrs.len());
+    let (first, second): (Vec<_>, Vec<_>) = socket_addrs
+        .into_iter()
+        .partition(|socket_addr| socket_addr.is_ipv6() == first_is_ipv6);
+    let mut first = first.into_iter();
+    let mut second = second.into_iter();
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Ask a SOCKS5 proxy without authentication to connect to `host` and `port`. `host` is an IP
+/// address or a domain name that the proxy resolves. See [RFC
+/// 1928](https://tools.ietf.org/html/rfc1928).
+#[cfg(not(target_arch = "wasm32"))]
+async fn socks5_connect(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    host: &str,
+    port: u16,
+) -> Result<(), DialError> {
+    const VERSION: u8 = 5;
+    const NO_AUTHENTICATION: u8 = 0;
+    const CONNECT: u8 = 1;
+    const IPV4: u8 = 1;
+    const DOMAIN_NAME: u8 = 3;
+    const IPV6: u8 = 4;
+    let protocol_error = |message| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+
+    stream.write_all(&[VERSION, 1, NO_AUTHENTICATION]).await?;
+    let mut method = [0u8; 2];
+    stream.read_exact(&mut method).await?;
+    if method != [VERSION, NO_AUTHENTICATION] {
+        return Err(protocol_error("SOCKS5 proxy requires authentication").into());
+    }
+
+    let mut request = vec![VERSION, CONNECT, 0];
+    match host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(ip)) => {
+            request.push(IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(std::net::IpAddr::V6(ip)) => {
+            request.push(IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let host_length = u8::try_from(host.len())
+                .map_err(|_| protocol_error("Host name is too long for SOCKS5"))?;
+            request.extend_from_slice(&[DOMAIN_NAME, host_length]);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(protocol_error("Invalid SOCKS5 reply").into());
+    }
+    if reply[1] != 0 {
+        return Err(DialError::Proxy { reply: reply[1] });
+    }
+    // Skip the bound address and port
+    let address_length = match reply[3] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN_NAME => {
+            let mut length = [0u8; 1];
+            stream.read_exact(&mut length).await?;
+            length[0] as usize
+        }
+        _ => return Err(protocol_error("Invalid SOCKS5 address type").into()),
+    };
+    let mut bound = vec![0u8; address_length + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
 peg::parser! {
     grammar parser() for str {
         rule name() -> String
@@ -168,7 +639,7 @@ peg::parser! {
         rule protocol() -> Protocol
             = name:name() data:data_part()* { Protocol { name, data } }
 
-        rule address() -> Address
+        pub rule address() -> Address
             = protocols:protocol() ** "~" { Address { protocols } }
 
         pub rule multi_address() -> MultiAddress
@@ -198,4 +669,197 @@ mod test {
             proptest::collection::vec(protocol, 1..4).prop_map(|protocols| Address { protocols });
         proptest::collection::vec(address, 1..4).prop_map(|addresses| MultiAddress { addresses })
     }
+
+    #[test]
+    fn transports() {
+        let transport = |s: &str| s.parse::<Address>().unwrap().transport();
+        assert_eq!(
+            transport("net:10.0.0.1:8008~shs:AA=="),
+            Ok(Transport::Net("10.0.0.1:8008".parse().unwrap()))
+        );
+        assert_eq!(
+            transport("net:pub.example.com:8008~shs:AA=="),
+            Ok(Transport::Dns {
+                host: "pub.example.com".to_string(),
+                port: 8008
+            })
+        );
+        assert_eq!(
+            transport("dns:pub.example.com:8008"),
+            Ok(Transport::Dns {
+                host: "pub.example.com".to_string(),
+                port: 8008
+            })
+        );
+        assert!(matches!(
+            transport("onion:example.com:8008"),
+            Err(AddressError::InvalidData { .. })
+        ));
+        assert!(matches!(
+            transport("ws:example.com:80"),
+            Err(AddressError::UnsupportedTransport { .. })
+        ));
+    }
+
+    #[async_std::test]
+    async fn dial_dns() {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let address = Address {
+            protocols: vec![Protocol::dns("localhost", port)],
+        };
+        let dialer = Dialer::new();
+        let (dialed, accepted) = futures::join!(dialer.dial(&address), listener.accept());
+        dialed.unwrap();
+        accepted.unwrap();
+    }
+
+    #[async_std::test]
+    async fn dial_onion() {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let dialer = Dialer::new().with_socks5_proxy(listener.local_addr().unwrap());
+        let address = Address {
+            protocols: vec![Protocol::onion("example.onion", 8008)],
+        };
+        let proxy = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            stream.write_all(&[5, 0]).await.unwrap();
+            let mut request = [0u8; 20];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request[..5], &[5, 1, 0, 3, 13]);
+            assert_eq!(&request[5..18], b"example.onion");
+            assert_eq!(&request[18..], &8008u16.to_be_bytes());
+            stream
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+        };
+        let (dialed, ()) = futures::join!(dialer.dial(&address), proxy);
+        dialed.unwrap();
+
+        let error = Dialer::new().dial(&address).await.unwrap_err();
+        assert!(matches!(error, DialError::NoProxy));
+        assert!(!Dialer::new().can_dial(&address));
+        assert!(dialer.can_dial(&address));
+    }
+
+    #[async_std::test]
+    async fn dial_proxy_all() {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let dialer = Dialer::new()
+            .with_socks5_proxy(listener.local_addr().unwrap())
+            .proxy_all();
+        let listener = &listener;
+        let proxy = |request_length: usize| async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[5, 0]).await.unwrap();
+            let mut request = vec![0u8; request_length];
+            stream.read_exact(&mut request).await.unwrap();
+            stream
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+            request
+        };
+
+        let address = "net:10.0.0.1:8008~shs:AA==".parse::<Address>().unwrap();
+        let mut expected = vec![5, 1, 0, 1, 10, 0, 0, 1];
+        expected.extend_from_slice(&8008u16.to_be_bytes());
+        let (dialed, request) = futures::join!(dialer.dial(&address), proxy(expected.len()));
+        dialed.unwrap();
+        assert_eq!(request, expected);
+
+        // Host names are resolved by the proxy
+        let address = Address {
+            protocols: vec![Protocol::dns("example.com", 8008)],
+        };
+        let mut expected = vec![5, 1, 0, 3, 11];
+        expected.extend_from_slice(b"example.com");
+        expected.extend_from_slice(&8008u16.to_be_bytes());
+        let (dialed, request) = futures::join!(dialer.dial(&address), proxy(expected.len()));
+        dialed.unwrap();
+        assert_eq!(request, expected);
+    }
+
+    #[test]
+    fn interleave() {
+        let socket_addrs = [
+            "[::1]:1",
+            "[::1]:2",
+            "[::1]:3",
+            "127.0.0.1:4",
+            "127.0.0.1:5",
+        ]
+        .iter()
+        .map(|socket_addr| socket_addr.parse().unwrap())
+        .collect::<Vec<std::net::SocketAddr>>();
+        let ports = interleave_families(socket_addrs)
+            .iter()
+            .map(|socket_addr| socket_addr.port())
+            .collect::<Vec<_>>();
+        assert_eq!(ports, vec![1, 4, 2, 5, 3]);
+    }
+
+    #[async_std::test]
+    async fn connect_race() {
+        // The handshake with the first listener never completes
+        let stalled = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let responding = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        // Nothing listens on this address
+        let closed = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let address = |socket_addr| match socket_addr {
+            std::net::SocketAddr::V4(socket_addr) => Address::net_shs(&socket_addr, &[0u8; 32]),
+            std::net::SocketAddr::V6(_) => unreachable!(),
+        };
+        let multi_address = MultiAddress {
+            addresses: vec![
+                address(closed),
+                address(stalled.local_addr().unwrap()),
+                address(responding.local_addr().unwrap()),
+            ],
+        };
+        let dialer = Dialer::new().with_attempt_delay(std::time::Duration::from_millis(10));
+        let handshake = |_address, mut stream: async_std::net::TcpStream| async move {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).await.map(|()| byte[0])
+        };
+        let respond = async {
+            let (mut stream, _) = responding.accept().await.unwrap();
+            stream.write_all(&[42]).await.unwrap();
+            stream
+        };
+        let (connected, _stalled, _responding) = futures::join!(
+            dialer.connect(&multi_address, handshake),
+            stalled.accept(),
+            respond
+        );
+        let (address, byte) = connected.unwrap();
+        assert_eq!(address, multi_address.addresses[2]);
+        assert_eq!(byte, 42);
+
+        let error = dialer
+            .connect(&MultiAddress { addresses: vec![] }, handshake)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, DialError::NoAddress));
+    }
 }
@@ -0,0 +1,268 @@
+//! Port mappings on the router so that peers outside of the local network can connect.
+//!
+//! [map_port] asks the default gateway for a TCP port mapping with [NAT-PMP][natpmp] and falls
+//! back to [UPnP IGD][upnp]. The external address of the mapping is announced by adding it to
+//! the local multi address with [PortMapping::announce], for example for
+//! [DiscoveryConfig::announce][crate::discovery::DiscoveryConfig::announce], and published with
+//! a `pub` message created by [PortMapping::pub_content].
+//!
+//! ```no_run
+//! # async_std::task::block_on(async {
+//! # let local: ssb::multi_address::MultiAddress = unimplemented!();
+//! use ssb::nat;
+//! let mapping = nat::map_port(8008, nat::DEFAULT_LIFETIME).await.unwrap();
+//! let announce = mapping.announce(&local);
+//! // Mappings expire and need to be renewed
+//! async_std::task::sleep(mapping.lifetime / 2).await;
+//! let mapping = mapping.renew().await.unwrap();
+//! # });
+//! ```
+//!
+//! Only available with the `nat` feature.
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
+use crate::feed::FeedId;
+use crate::multi_address::{Address, MultiAddress};
+
+pub mod natpmp;
+pub mod upnp;
+
+/// Lifetime of port mappings recommended by [RFC 6886](https://tools.ietf.org/html/rfc6886).
+pub const DEFAULT_LIFETIME: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// Time to wait for answers of UPnP gateways in [map_port].
+const UPNP_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(thiserror::Error, Debug)]
+pub enum NatError {
+    #[error("Default gateway is unknown")]
+    NoGateway,
+    #[error("Gateway did not respond")]
+    Timeout,
+    #[error("Gateway refused the NAT-PMP request with result code {result}")]
+    NatPmp { result: u16 },
+    #[error("No UPnP internet gateway found")]
+    NoUpnpGateway,
+    #[error("Gateway refused the UPnP request with HTTP status {status}")]
+    Upnp { status: u16 },
+    #[error("Invalid response from gateway: {reason}")]
+    InvalidResponse { reason: &'static str },
+    #[error("Failed to talk to gateway")]
+    Io(#[from] std::io::Error),
+}
+
+/// Gateway that created a [PortMapping].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Gateway {
+    NatPmp(SocketAddrV4),
+    Upnp(upnp::Gateway),
+}
+
+/// TCP port mapping created by [map_port].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortMapping {
+    pub gateway: Gateway,
+    pub internal_port: u16,
+    /// Address where peers outside of the local network reach `internal_port`
+    pub external_address: SocketAddrV4,
+    /// Time after which the gateway removes the mapping unless it is renewed
+    pub lifetime: Duration,
+}
+
+/// Request a mapping of the external port `internal_port` to `internal_port` of this host from
+/// the default gateway. The gateway may choose a different external port.
+///
+/// Tries NAT-PMP first and UPnP IGD if that fails. Returns the error of UPnP if both fail.
+pub async fn map_port(internal_port: u16, lifetime: Duration) -> Result<PortMapping, NatError> {
+    match default_gateway() {
+        Ok(gateway) => {
+            let gateway = SocketAddrV4::new(gateway, natpmp::PORT);
+            match map_with(
+                &Gateway::NatPmp(gateway),
+                internal_port,
+                internal_port,
+                lifetime,
+            )
+            .await
+            {
+                Ok(mapping) => return Ok(mapping),
+                Err(error) => tracing::debug!(%gateway, ?error, "NAT-PMP port mapping failed"),
+            }
+        }
+        Err(error) => tracing::debug!(?error, "cannot use NAT-PMP"),
+    }
+    let gateway = upnp::discover(UPNP_DISCOVERY_TIMEOUT).await?;
+    map_with(
+        &Gateway::Upnp(gateway),
+        internal_port,
+        internal_port,
+        lifetime,
+    )
+    .await
+}
+
+async fn map_with(
+    gateway: &Gateway,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: Duration,
+) -> Result<PortMapping, NatError> {
+    let (external_address, lifetime) = match gateway {
+        Gateway::NatPmp(gateway) => {
+            let ip = natpmp::external_address(*gateway).await?;
+            let (port, lifetime) =
+                natpmp::map_tcp(*gateway, internal_port, external_port, lifetime).await?;
+            (SocketAddrV4::new(ip, port), lifetime)
+        }
+        Gateway::Upnp(gateway) => {
+            gateway
+                .add_port_mapping(internal_port, external_port, lifetime)
+                .await?;
+            let ip = gateway.external_address().await?;
+            (SocketAddrV4::new(ip, external_port), lifetime)
+        }
+    };
+    Ok(PortMapping {
+        gateway: gateway.clone(),
+        internal_port,
+        external_address,
+        lifetime,
+    })
+}
+
+impl PortMapping {
+    /// Request the mapping again to extend its lifetime. Should be called before half of the
+    /// lifetime has passed. The external address may change, for example if the router was
+    /// restarted.
+    pub async fn renew(&self) -> Result<PortMapping, NatError> {
+        map_with(
+            &self.gateway,
+            self.internal_port,
+            self.external_address.port(),
+            self.lifetime,
+        )
+        .await
+    }
+
+    /// Ask the gateway to remove the mapping.
+    pub async fn remove(&self) -> Result<(), NatError> {
+        match &self.gateway {
+            Gateway::NatPmp(gateway) => {
+                natpmp::map_tcp(*gateway, self.internal_port, 0, Duration::from_secs(0)).await?;
+            }
+            Gateway::Upnp(gateway) => {
+                gateway
+                    .delete_port_mapping(self.external_address.port())
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    /// Address of the peer with public key `key` at the external address.
+    pub fn address(&self, key: &FeedId) -> Address {
+        Address::net_shs(&self.external_address, key.public_key().as_ref())
+    }
+
+    /// Add the external address to `multi_address`. The key is taken from the first address
+    /// with a `shs` protocol. Returns `multi_address` unchanged if no address has a key or if it
+    /// already contains the external address.
+    pub fn announce(&self, multi_address: &MultiAddress) -> MultiAddress {
+        let mut multi_address = multi_address.clone();
+        let key = multi_address
+            .addresses
+            .iter()
+            .find_map(|address| address.shs_key().ok());
+        if let Some(key) = key {
+            let external = Address::net_shs(&self.external_address, &key);
+            if !multi_address.addresses.contains(&external) {
+                multi_address.addresses.push(external);
+            }
+        }
+        multi_address
+    }
+
+    /// Content of a `pub` message that announces the external address for `feed`.
+    pub fn pub_content(&self, feed: &FeedId) -> serde_json::Value {
+        serde_json::json!({
+            "type": "pub",
+            "address": {
+                "host": self.external_address.ip().to_string(),
+                "port": self.external_address.port(),
+                "key": feed,
+            },
+        })
+    }
+}
+
+/// IPv4 address of the default gateway. Only implemented on Linux.
+pub fn default_gateway() -> Result<Ipv4Addr, NatError> {
+    let routes = std::fs::read_to_string("/proc/net/route").map_err(|_| NatError::NoGateway)?;
+    parse_route_table(&routes).ok_or(NatError::NoGateway)
+}
+
+/// Find the gateway of the default route in the contents of `/proc/net/route`.
+fn parse_route_table(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let mut columns = line.split_whitespace().skip(1);
+        let destination = columns.next()?;
+        let gateway = u32::from_str_radix(columns.next()?, 16).ok()?;
+        if destination != "00000000" || gateway == 0 {
+            return None;
+        }
+        // The kernel prints the address in network byte order as a native integer.
+        Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn route_table() {
+        let routes =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+            wlan0\t0002A8C0\t00000000\t0001\t0\t0\t600\t00FFFFFF\t0\t0\t0\n\
+            wlan0\t00000000\t0102A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0\n";
+        let expected = if cfg!(target_endian = "little") {
+            Ipv4Addr::new(192, 168, 2, 1)
+        } else {
+            Ipv4Addr::new(1, 2, 168, 192)
+        };
+        assert_eq!(parse_route_table(routes), Some(expected));
+        assert_eq!(parse_route_table("Iface\tDestination\tGateway\n"), None);
+    }
+
+    #[test]
+    fn announce() {
+        let key = FeedId(crate::crypto::sign::KeyPair::gen().public);
+        let local = MultiAddress {
+            addresses: vec![Address::net_shs(
+                &"192.168.2.10:8008".parse().unwrap(),
+                key.public_key().as_ref(),
+            )],
+        };
+        let mapping = PortMapping {
+            gateway: Gateway::NatPmp("192.168.2.1:5351".parse().unwrap()),
+            internal_port: 8008,
+            external_address: "203.0.113.7:8009".parse().unwrap(),
+            lifetime: DEFAULT_LIFETIME,
+        };
+        let announced = mapping.announce(&local);
+        assert_eq!(
+            announced.addresses,
+            vec![local.addresses[0].clone(), mapping.address(&key)]
+        );
+        assert_eq!(mapping.announce(&announced), announced);
+
+        assert_eq!(
+            mapping.pub_content(&key),
+            serde_json::json!({
+                "type": "pub",
+                "address": { "host": "203.0.113.7", "port": 8009, "key": key },
+            })
+        );
+    }
+}
@@ -0,0 +1,159 @@
+//! Client for the [NAT Port Mapping Protocol][RFC 6886].
+//!
+//! Requests are sent over UDP to [PORT] of the gateway and retried with a doubling timeout.
+//!
+//! [RFC 6886]: https://tools.ietf.org/html/rfc6886
+use std::convert::TryFrom;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
+use super::NatError;
+
+/// Port that gateways listen on for NAT-PMP requests.
+pub const PORT: u16 = 5351;
+
+const VERSION: u8 = 0;
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_TCP: u8 = 2;
+/// Added to the opcode of a request to get the opcode of the response.
+const OP_RESPONSE: u8 = 128;
+
+/// Timeout of the first attempt. Doubled for every retry.
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+/// RFC 6886 recommends nine attempts which take more than a minute. We give up after about
+/// four seconds instead.
+const ATTEMPTS: u32 = 4;
+
+/// Ask `gateway` for its external IPv4 address.
+pub async fn external_address(gateway: SocketAddrV4) -> Result<Ipv4Addr, NatError> {
+    let response = request(gateway, &[VERSION, OP_EXTERNAL_ADDRESS]).await?;
+    let ip = response.get(8..12).ok_or(NatError::InvalidResponse {
+        reason: "external address response is too short",
+    })?;
+    Ok(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]))
+}
+
+/// Map `external_port` of `gateway` to `internal_port` of this host for TCP. Returns the
+/// external port and lifetime that the gateway granted, which may differ from the requested
+/// ones.
+///
+/// A `lifetime` of zero removes the mapping. If `external_port` is zero the gateway chooses one.
+pub async fn map_tcp(
+    gateway: SocketAddrV4,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: Duration,
+) -> Result<(u16, Duration), NatError> {
+    let lifetime = u32::try_from(lifetime.as_secs()).unwrap_or(u32::MAX);
+    let mut packet = vec![VERSION, OP_MAP_TCP, 0, 0];
+    packet.extend_from_slice(&internal_port.to_be_bytes());
+    packet.extend_from_slice(&external_port.to_be_bytes());
+    packet.extend_from_slice(&lifetime.to_be_bytes());
+    let response = request(gateway, &packet).await?;
+    parse_mapping(&response)
+}
+
+/// Parse the external port and lifetime of a successful mapping response.
+fn parse_mapping(response: &[u8]) -> Result<(u16, Duration), NatError> {
+    if response.len() < 16 {
+        return Err(NatError::InvalidResponse {
+            reason: "mapping response is too short",
+        });
+    }
+    let external_port = u16::from_be_bytes([response[10], response[11]]);
+    let lifetime = u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+    Ok((external_port, Duration::from_secs(u64::from(lifetime))))
+}
+
+/// Send `packet` to `gateway` and return the response. Fails if the response reports an error.
+async fn request(gateway: SocketAddrV4, packet: &[u8]) -> Result<Vec<u8>, NatError> {
+    let socket = async_std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(gateway).await?;
+    let mut timeout = INITIAL_TIMEOUT;
+    for _ in 0..ATTEMPTS {
+        socket.send(packet).await?;
+        let deadline = std::time::Instant::now() + timeout;
+        // Skip responses to earlier attempts or other requests
+        loop {
+            let mut buf = [0u8; 16];
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            let size = match async_std::future::timeout(remaining, socket.recv(&mut buf)).await {
+                Ok(size) => size?,
+                Err(_) => break,
+            };
+            if let Some(response) = check_response(&buf[..size], packet[1])? {
+                return Ok(response);
+            }
+        }
+        timeout *= 2;
+    }
+    Err(NatError::Timeout)
+}
+
+/// Returns `None` if `response` does not answer a request with `opcode`.
+fn check_response(response: &[u8], opcode: u8) -> Result<Option<Vec<u8>>, NatError> {
+    if response.len() < 8 || response[0] != VERSION || response[1] != opcode + OP_RESPONSE {
+        return Ok(None);
+    }
+    match u16::from_be_bytes([response[2], response[3]]) {
+        0 => Ok(Some(response.to_vec())),
+        result => Err(NatError::NatPmp { result }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Answers one external address and one mapping request.
+    async fn gateway(socket: async_std::net::UdpSocket) {
+        let mut buf = [0u8; 16];
+        let (_, client) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..2], [VERSION, OP_EXTERNAL_ADDRESS]);
+        let response = [0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7];
+        socket.send_to(&response, client).await.unwrap();
+
+        let (size, client) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(size, 12);
+        assert_eq!(&buf[..4], [VERSION, OP_MAP_TCP, 0, 0]);
+        // Map to a different external port and shorten the lifetime
+        let mut response = vec![0, 130, 0, 0, 0, 0, 0, 1];
+        response.extend_from_slice(&buf[4..6]);
+        response.extend_from_slice(&8009u16.to_be_bytes());
+        response.extend_from_slice(&3600u32.to_be_bytes());
+        socket.send_to(&response, client).await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn map() {
+        let socket = async_std::net::UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let address = match socket.local_addr().unwrap() {
+            std::net::SocketAddr::V4(address) => address,
+            std::net::SocketAddr::V6(_) => unreachable!(),
+        };
+        let server = async_std::task::spawn(gateway(socket));
+        let ip = external_address(address).await.unwrap();
+        assert_eq!(ip, Ipv4Addr::new(203, 0, 113, 7));
+        let mapping = map_tcp(address, 8008, 8008, super::super::DEFAULT_LIFETIME)
+            .await
+            .unwrap();
+        assert_eq!(mapping, (8009, Duration::from_secs(3600)));
+        server.await;
+    }
+
+    #[test]
+    fn error_response() {
+        // Result code 2: not authorized
+        let response = [0, 130, 0, 2, 0, 0, 0, 1];
+        assert!(matches!(
+            check_response(&response, OP_MAP_TCP),
+            Err(NatError::NatPmp { result: 2 })
+        ));
+        assert!(matches!(
+            check_response(&response, OP_EXTERNAL_ADDRESS),
+            Ok(None)
+        ));
+    }
+}
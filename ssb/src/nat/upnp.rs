@@ -0,0 +1,352 @@
+//! Client for the `WANIPConnection` service of [UPnP internet gateway devices][IGD].
+//!
+//! [discover] finds the gateway with an SSDP search, fetches its device description and returns
+//! the control URL of the connection service. The actions of the service are called with SOAP
+//! requests. Only plain `http` URLs, which all gateways use, are supported.
+//!
+//! [IGD]: https://openconnectivity.org/developer/specifications/upnp-resources/upnp/internet-gateway-device-igd-v-2-0/
+use async_std::net::{TcpStream, UdpSocket};
+use futures::prelude::*;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
+use super::NatError;
+
+/// Multicast address for SSDP searches.
+const SSDP_ADDRESS: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900);
+
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+
+/// Services that can map ports. `WANPPPConnection` is used by DSL routers.
+const SERVICE_TYPES: [&str; 2] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// Description of the port mappings that shows up in the router interface.
+const MAPPING_DESCRIPTION: &str = "ssb";
+
+/// Connection service of an internet gateway device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gateway {
+    pub control_url: String,
+    pub service_type: String,
+}
+
+/// Search for an internet gateway device and return its connection service.
+///
+/// Waits at most `timeout` for gateways to answer.
+pub async fn discover(timeout: Duration) -> Result<Gateway, NatError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n",
+        SSDP_ADDRESS, SEARCH_TARGET
+    );
+    socket.send_to(search.as_bytes(), SSDP_ADDRESS).await?;
+
+    let deadline = std::time::Instant::now() + timeout;
+    loop {
+        let mut buf = [0u8; 2048];
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        let size = match async_std::future::timeout(remaining, socket.recv(&mut buf)).await {
+            Ok(size) => size?,
+            Err(_) => return Err(NatError::NoUpnpGateway),
+        };
+        let location = match std::str::from_utf8(&buf[..size])
+            .ok()
+            .and_then(|response| header(response, "location"))
+        {
+            Some(location) => location.to_string(),
+            None => continue,
+        };
+        match description(&location).await {
+            Ok(Some(gateway)) => return Ok(gateway),
+            Ok(None) => tracing::debug!(%location, "device has no connection service"),
+            Err(error) => tracing::debug!(%location, ?error, "failed to get device description"),
+        }
+    }
+}
+
+/// Fetch the device description at `location` and find the connection service.
+async fn description(location: &str) -> Result<Option<Gateway>, NatError> {
+    let (status, body) = http_request("GET", location, &[], "").await?;
+    if status != 200 {
+        return Err(NatError::Upnp { status });
+    }
+    let body = String::from_utf8_lossy(&body);
+    Ok(parse_description(&body, location))
+}
+
+/// Find the first connection service in the device description `xml` that was fetched from
+/// `location`.
+fn parse_description(xml: &str, location: &str) -> Option<Gateway> {
+    xml.split("<service>").skip(1).find_map(|service| {
+        let service_type = element(service, "serviceType")?;
+        if !SERVICE_TYPES.contains(&service_type) {
+            return None;
+        }
+        let control_url = element(service, "controlURL")?;
+        let control_url = if control_url.starts_with("http://") {
+            control_url.to_string()
+        } else {
+            let (authority, _) = split_url(location)?;
+            let separator = if control_url.starts_with('/') {
+                ""
+            } else {
+                "/"
+            };
+            format!("http://{}{}{}", authority, separator, control_url)
+        };
+        Some(Gateway {
+            control_url,
+            service_type: service_type.to_string(),
+        })
+    })
+}
+
+impl Gateway {
+    /// External IPv4 address of the gateway.
+    pub async fn external_address(&self) -> Result<Ipv4Addr, NatError> {
+        let response = self.call("GetExternalIPAddress", &[]).await?;
+        element(&response, "NewExternalIPAddress")
+            .and_then(|ip| ip.parse().ok())
+            .ok_or(NatError::InvalidResponse {
+                reason: "no external IP address",
+            })
+    }
+
+    /// Map `external_port` of the gateway to `internal_port` of this host for TCP.
+    pub async fn add_port_mapping(
+        &self,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: Duration,
+    ) -> Result<(), NatError> {
+        let internal_client = self.local_address().await?;
+        self.call(
+            "AddPortMapping",
+            &[
+                ("NewRemoteHost", ""),
+                ("NewExternalPort", &external_port.to_string()),
+                ("NewProtocol", "TCP"),
+                ("NewInternalPort", &internal_port.to_string()),
+                ("NewInternalClient", &internal_client.to_string()),
+                ("NewEnabled", "1"),
+                ("NewPortMappingDescription", MAPPING_DESCRIPTION),
+                ("NewLeaseDuration", &lifetime.as_secs().to_string()),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Remove the TCP mapping of `external_port`.
+    pub async fn delete_port_mapping(&self, external_port: u16) -> Result<(), NatError> {
+        self.call(
+            "DeletePortMapping",
+            &[
+                ("NewRemoteHost", ""),
+                ("NewExternalPort", &external_port.to_string()),
+                ("NewProtocol", "TCP"),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Address of the interface that this host uses to reach the gateway.
+    async fn local_address(&self) -> Result<std::net::IpAddr, NatError> {
+        let (authority, _) = split_url(&self.control_url).ok_or(NatError::InvalidResponse {
+            reason: "invalid control URL",
+        })?;
+        // Connecting a UDP socket does not send anything.
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.connect(authority).await?;
+        Ok(socket.local_addr()?.ip())
+    }
+
+    /// Call `action` with `arguments` and return the response body.
+    async fn call(&self, action: &str, arguments: &[(&str, &str)]) -> Result<String, NatError> {
+        let arguments = arguments
+            .iter()
+            .map(|(name, value)| format!("<{}>{}</{}>", name, value, name))
+            .collect::<String>();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\r\n\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{service}\">{arguments}</u:{action}></s:Body></s:Envelope>",
+            action = action,
+            service = self.service_type,
+            arguments = arguments
+        );
+        let soap_action = format!("\"{}#{}\"", self.service_type, action);
+        let (status, response) = http_request(
+            "POST",
+            &self.control_url,
+            &[
+                ("Content-Type", "text/xml; charset=\"utf-8\""),
+                ("SOAPAction", &soap_action),
+            ],
+            &body,
+        )
+        .await?;
+        if status != 200 {
+            return Err(NatError::Upnp { status });
+        }
+        Ok(String::from_utf8_lossy(&response).into_owned())
+    }
+}
+
+/// Value of the header `name` in an HTTP response. Header names are case insensitive.
+fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_at(line.find(':')?);
+        if key.trim().eq_ignore_ascii_case(name) {
+            Some(value[1..].trim())
+        } else {
+            None
+        }
+    })
+}
+
+/// Text of the first element `name` in `xml`. Namespace prefixes of the element are ignored.
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    loop {
+        let start = rest.find('<')? + 1;
+        rest = &rest[start..];
+        let end = rest.find('>')?;
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+        let tag_name = tag.split_whitespace().next().unwrap_or(tag);
+        let local_name = tag_name.rsplit(':').next().unwrap_or(tag_name);
+        if local_name == name {
+            let close = rest.find("</")?;
+            return Some(rest[..close].trim());
+        }
+    }
+}
+
+/// Split a plain `http` URL into authority, including the port, and path.
+fn split_url(url: &str) -> Option<(String, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return None;
+    }
+    let authority = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    Some((authority, path))
+}
+
+/// Send an HTTP/1.0 request to a plain `http` URL and return the status and response body.
+async fn http_request(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Result<(u16, Vec<u8>), NatError> {
+    let invalid = |reason| NatError::InvalidResponse { reason };
+    let (authority, path) = split_url(url).ok_or_else(|| invalid("unsupported URL"))?;
+    let mut stream = TcpStream::connect(authority.as_str()).await?;
+    let mut request = format!("{} {} HTTP/1.0\r\nHost: {}\r\n", method, path, authority);
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+    stream.write_all(request.as_bytes()).await?;
+    // HTTP/1.0 responses end when the server closes the connection.
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+
+    let header_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| invalid("HTTP response has no body"))?;
+    let status = std::str::from_utf8(&response[..header_end])
+        .ok()
+        .and_then(|head| head.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid("invalid HTTP status line"))?;
+    Ok((status, response.split_off(header_end + 4)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn description() {
+        let xml = r#"<?xml version="1.0"?>
+            <root xmlns="urn:schemas-upnp-org:device-1-0">
+              <device>
+                <serviceList>
+                  <service>
+                    <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+                    <controlURL>/ctl/L3F</controlURL>
+                  </service>
+                  <service>
+                    <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+                    <controlURL>/ctl/IPConn</controlURL>
+                  </service>
+                </serviceList>
+              </device>
+            </root>"#;
+        assert_eq!(
+            parse_description(xml, "http://192.168.2.1:5000/rootDesc.xml"),
+            Some(Gateway {
+                control_url: "http://192.168.2.1:5000/ctl/IPConn".to_string(),
+                service_type: SERVICE_TYPES[0].to_string(),
+            })
+        );
+        assert_eq!(parse_description("<root></root>", "http://host/"), None);
+    }
+
+    #[test]
+    fn ssdp_location() {
+        let response = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nLocation: http://192.168.2.1:5000/rootDesc.xml\r\n\r\n";
+        assert_eq!(
+            header(response, "LOCATION"),
+            Some("http://192.168.2.1:5000/rootDesc.xml")
+        );
+    }
+
+    #[async_std::test]
+    async fn soap() {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let gateway = Gateway {
+            control_url: format!("http://{}/ctl/IPConn", listener.local_addr().unwrap()),
+            service_type: SERVICE_TYPES[0].to_string(),
+        };
+        let server = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = String::new();
+            while !request.ends_with("</s:Envelope>") {
+                let mut buf = [0u8; 4096];
+                let size = stream.read(&mut buf).await.unwrap();
+                request.push_str(std::str::from_utf8(&buf[..size]).unwrap());
+            }
+            let body = "<?xml version=\"1.0\"?><s:Envelope><s:Body>\
+                <u:GetExternalIPAddressResponse xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">\
+                <NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>\
+                </u:GetExternalIPAddressResponse></s:Body></s:Envelope>";
+            let response = format!("HTTP/1.0 200 OK\r\nContent-Type: text/xml\r\n\r\n{}", body);
+            stream.write_all(response.as_bytes()).await.unwrap();
+            request
+        };
+        let (ip, request) = futures::join!(gateway.external_address(), server);
+        assert_eq!(ip.unwrap(), Ipv4Addr::new(203, 0, 113, 7));
+        assert!(request.starts_with("POST /ctl/IPConn HTTP/1.0\r\n"));
+        assert!(request.contains(
+            "SOAPAction: \"urn:schemas-upnp-org:service:WANIPConnection:1#GetExternalIPAddress\""
+        ));
+    }
+}
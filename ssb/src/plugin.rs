@@ -0,0 +1,217 @@
+//! Compose an SSB server from plugins.
+//!
+//! Similar to [secret-stack] plugins, a [Plugin] packages a feature (blobs, gossip, replication,
+//! …) by contributing RPC handlers to the [Service] of every connection and by optionally running
+//! a background task for the lifetime of the server. Plugins are collected in a [Plugins]
+//! registry.
+//!
+//! Since each connection owns its [Service], [Plugin::init] is called once per connection. State
+//! that is shared between connections and the background task should be kept behind an
+//! [Arc][std::sync::Arc] in the plugin.
+//!
+//! ```rust
+//! # use ssb::plugin::{Context, Plugin, Plugins};
+//! # use ssb::rpc::base::service::{AsyncResponse, Service};
+//! struct Ping;
+//!
+//! impl Plugin for Ping {
+//!     fn name(&self) -> &str {
+//!         "ping"
+//!     }
+//!
+//!     fn init(&self, service: &mut Service, _context: &Context) {
+//!         service.add_sync("ping", |_: Vec<()>| AsyncResponse::json_ok(&"pong"));
+//!     }
+//! }
+//!
+//! let mut plugins = Plugins::new();
+//! plugins.add(Ping).unwrap();
+//! let service = plugins.service(&Context::default());
+//! assert_eq!(service.manifest()["ping"]["ping"], "sync");
+//! ```
+//!
+//! [secret-stack]: https://github.com/ssb-js/secret-stack
+
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use std::sync::Arc;
+
+use crate::crypto::sign::PublicKey;
+use crate::rpc::base::Service;
+
+/// A feature of the server that provides RPC methods and an optional background task.
+pub trait Plugin: Send + Sync {
+    /// Name of the plugin. All methods added in [Plugin::init] are registered under this group.
+    fn name(&self) -> &str;
+
+    /// Add the methods of the plugin to the service of a new connection.
+    fn init(&self, service: &mut Service, context: &Context);
+
+    /// Add methods that are not grouped under the plugin name, like `createHistoryStream`.
+    ///
+    /// Called after [Plugin::init] with the root service of the connection.
+    fn init_root(&self, _service: &mut Service, _context: &Context) {}
+
+    /// Return a task that runs in the background while the server is running.
+    ///
+    /// Called once by [Plugins::run]. If the task fails the server is stopped.
+    fn task(&self) -> Option<BoxFuture<'static, anyhow::Result<()>>> {
+        None
+    }
+}
+
+/// Information about the connection passed to [Plugin::init].
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    /// Public key of the remote peer. `None` if the connection is not authenticated, for example
+    /// when the client connects over a local socket.
+    pub peer: Option<PublicKey>,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Plugin {name} is already registered")]
+pub struct DuplicatePluginError {
+    pub name: String,
+}
+
+/// Registry of [Plugin]s that make up the server.
+#[derive(Default, Clone)]
+pub struct Plugins {
+    plugins: Vec<Arc<dyn Plugin>>,
+}
+
+impl Plugins {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a plugin. Fails if a plugin with the same name is already registered.
+    pub fn add(&mut self, plugin: impl Plugin + 'static) -> Result<(), DuplicatePluginError> {
+        if self.plugins.iter().any(|p| p.name() == plugin.name()) {
+            return Err(DuplicatePluginError {
+                name: plugin.name().to_string(),
+            });
+        }
+        self.plugins.push(Arc::new(plugin));
+        Ok(())
+    }
+
+    /// Names of the registered plugins in the order they were added.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.plugins.iter().map(|plugin| plugin.name())
+    }
+
+    /// Build the service for a new connection from all registered plugins.
+    pub fn service(&self, context: &Context) -> Service {
+        let mut service = Service::new();
+        for plugin in &self.plugins {
+            let mut plugin_service = Service::new();
+            plugin.init(&mut plugin_service, context);
+            service.add_service(plugin.name(), plugin_service);
+            let mut root_service = Service::new();
+            plugin.init_root(&mut root_service, context);
+            service.merge(root_service);
+        }
+        service
+    }
+
+    /// Run the background tasks of all plugins.
+    ///
+    /// Resolves when all tasks have finished or returns the error of the first task that fails.
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let tasks = self.plugins.iter().filter_map(|plugin| {
+            let name = plugin.name().to_string();
+            plugin.task().map(move |task| {
+                task.map_err(move |error| error.context(format!("Plugin {} failed", name)))
+            })
+        });
+        futures::future::try_join_all(tasks).await?;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Plugins {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Plugins")
+            .field("plugins", &self.names().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::base::service::AsyncResponse;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Counter {
+        name: &'static str,
+        count: Arc<AtomicUsize>,
+    }
+
+    impl Plugin for Counter {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn init(&self, service: &mut Service, _context: &Context) {
+            let count = Arc::clone(&self.count);
+            service.add_sync("get", move |_: Vec<()>| {
+                AsyncResponse::json_ok(&count.load(Ordering::SeqCst))
+            });
+        }
+
+        fn task(&self) -> Option<BoxFuture<'static, anyhow::Result<()>>> {
+            let count = Arc::clone(&self.count);
+            Some(
+                async move {
+                    count.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }
+                .boxed(),
+            )
+        }
+    }
+
+    fn counter(name: &'static str) -> Counter {
+        Counter {
+            name,
+            count: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    #[async_std::test]
+    async fn service_and_tasks() {
+        let a = counter("a");
+        let count = Arc::clone(&a.count);
+        let mut plugins = Plugins::new();
+        plugins.add(a).unwrap();
+        plugins.add(counter("b")).unwrap();
+
+        let service = plugins.service(&Context::default());
+        assert_eq!(
+            service.manifest(),
+            serde_json::json!({
+                "manifest": "sync",
+                "help": "sync",
+                "a": { "get": "sync", "help": "sync" },
+                "b": { "get": "sync", "help": "sync" },
+            })
+        );
+
+        plugins.run().await.unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn duplicate_plugin() {
+        let mut plugins = Plugins::new();
+        plugins.add(counter("a")).unwrap();
+        assert_eq!(
+            plugins.add(counter("a")),
+            Err(DuplicatePluginError {
+                name: "a".to_string()
+            })
+        );
+    }
+}
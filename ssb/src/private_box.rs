@@ -0,0 +1,147 @@
+//! Encryption of private messages with [private-box].
+//!
+//! A box can be opened by up to [MAX_RECIPIENTS] feeds. It consists of a nonce, a one-time public
+//! key, the key of the body encrypted for every recipient and the encrypted body. The body key is
+//! prefixed with the number of recipients so that a recipient knows where the body starts.
+//!
+//! Private messages have the box encoded as base64 with a `.box` suffix as their content.
+//!
+//! ```rust
+//! # use ssb::crypto::sign::KeyPair;
+//! # use ssb::feed::FeedId;
+//! let alice = KeyPair::gen();
+//! let bob = KeyPair::gen();
+//! let content = serde_json::json!({ "type": "post", "text": "secret" });
+//! let boxed = ssb::private_box::box_content(&content, &[FeedId(bob.public)]).unwrap();
+//! assert_eq!(ssb::private_box::unbox_content(&boxed, &bob), Some(content));
+//! assert_eq!(ssb::private_box::unbox_content(&boxed, &alice), None);
+//! ```
+//!
+//! [private-box]: https://github.com/auditdrivencrypto/private-box
+use crate::crypto::{box_, secretbox, sign};
+use crate::feed::FeedId;
+
+/// Largest number of recipients of a box. Recipients only try this many keys when they open a
+/// box.
+pub const MAX_RECIPIENTS: usize = 7;
+
+/// Size of the body key for one recipient after it is encrypted.
+const RECIPIENT_KEY_SIZE: usize = 1 + secretbox::KEYBYTES + secretbox::MACBYTES;
+
+/// Size of the nonce and the one-time public key at the start of a box.
+const HEADER_SIZE: usize = secretbox::NONCEBYTES + box_::PUBLICKEYBYTES;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum PrivateBoxError {
+    #[error("A box needs at least one recipient")]
+    NoRecipients,
+    #[error("A box may have at most {MAX_RECIPIENTS} recipients, got {count}")]
+    TooManyRecipients { count: usize },
+    #[error("Public key of recipient {recipient} cannot be used for encryption")]
+    InvalidRecipient { recipient: FeedId },
+}
+
+/// Encrypt `plaintext` so that each of `recipients` can decrypt it.
+pub fn encrypt(plaintext: &[u8], recipients: &[FeedId]) -> Result<Vec<u8>, PrivateBoxError> {
+    if recipients.is_empty() {
+        return Err(PrivateBoxError::NoRecipients);
+    }
+    if recipients.len() > MAX_RECIPIENTS {
+        return Err(PrivateBoxError::TooManyRecipients {
+            count: recipients.len(),
+        });
+    }
+
+    let nonce = secretbox::gen_nonce();
+    let (onetime_public, onetime_secret) = box_::gen_keypair();
+    let key = secretbox::gen_key();
+    let mut recipient_key = vec![recipients.len() as u8];
+    recipient_key.extend_from_slice(key.as_ref());
+
+    let mut output = Vec::with_capacity(
+        HEADER_SIZE + recipients.len() * RECIPIENT_KEY_SIZE + plaintext.len() + secretbox::MACBYTES,
+    );
+    output.extend_from_slice(nonce.as_ref());
+    output.extend_from_slice(onetime_public.as_ref());
+    for recipient in recipients {
+        let shared_key = crate::crypto::sign_to_box_pk(recipient.public_key())
+            .and_then(|public_key| crate::crypto::share_key(&public_key, &onetime_secret))
+            .ok_or(PrivateBoxError::InvalidRecipient {
+                recipient: *recipient,
+            })?;
+        let shared_key = secretbox::Key::from_slice(shared_key.as_ref()).unwrap();
+        output.extend(secretbox::seal(&recipient_key, &nonce, &shared_key));
+    }
+    output.extend(secretbox::seal(plaintext, &nonce, &key));
+    Ok(output)
+}
+
+/// Decrypt a box with the secret key of a recipient. Returns `None` if the box is not for the
+/// recipient or is malformed.
+pub fn decrypt(ciphertext: &[u8], secret_key: &sign::SecretKey) -> Option<Vec<u8>> {
+    let nonce = secretbox::Nonce::from_slice(ciphertext.get(..secretbox::NONCEBYTES)?)?;
+    let onetime_public =
+        box_::PublicKey::from_slice(ciphertext.get(secretbox::NONCEBYTES..HEADER_SIZE)?)?;
+    let secret_key = crate::crypto::sign_to_box_sk(secret_key)?;
+    let shared_key = crate::crypto::share_key(&onetime_public, &secret_key)?;
+    let shared_key = secretbox::Key::from_slice(shared_key.as_ref())?;
+
+    let recipient_keys = ciphertext[HEADER_SIZE..].chunks_exact(RECIPIENT_KEY_SIZE);
+    let recipient_key = recipient_keys
+        .take(MAX_RECIPIENTS)
+        .find_map(|encrypted| secretbox::open(encrypted, &nonce, &shared_key).ok())?;
+    let (count, key) = recipient_key.split_first()?;
+    let key = secretbox::Key::from_slice(key)?;
+    let body = ciphertext.get(HEADER_SIZE + *count as usize * RECIPIENT_KEY_SIZE..)?;
+    secretbox::open(body, &nonce, &key).ok()
+}
+
+/// Encrypt message content for `recipients` and return it as the content of a private message.
+pub fn box_content(
+    content: &serde_json::Value,
+    recipients: &[FeedId],
+) -> Result<String, PrivateBoxError> {
+    let plaintext = serde_json::to_vec(content).expect("JSON value serializes");
+    let ciphertext = encrypt(&plaintext, recipients)?;
+    Ok(format!("{}.box", base64::encode(ciphertext)))
+}
+
+/// Decrypt the content of a private message. Returns `None` if `keypair` is not a recipient or
+/// the content is not a box of JSON.
+pub fn unbox_content(content: &str, keypair: &sign::KeyPair) -> Option<serde_json::Value> {
+    let ciphertext = base64::decode(content.strip_suffix(".box")?).ok()?;
+    let plaintext = decrypt(&ciphertext, &keypair.secret)?;
+    serde_json::from_slice(&plaintext).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recipients() {
+        let keypairs = (0..MAX_RECIPIENTS + 1)
+            .map(|_| sign::KeyPair::gen())
+            .collect::<Vec<_>>();
+        let feeds = keypairs
+            .iter()
+            .map(|keypair| FeedId(keypair.public))
+            .collect::<Vec<_>>();
+
+        let ciphertext = encrypt(b"hello", &feeds[..3]).unwrap();
+        for keypair in &keypairs[..3] {
+            assert_eq!(
+                decrypt(&ciphertext, &keypair.secret).as_deref(),
+                Some(&b"hello"[..])
+            );
+        }
+        assert_eq!(decrypt(&ciphertext, &keypairs[3].secret), None);
+        assert_eq!(decrypt(&ciphertext[..40], &keypairs[0].secret), None);
+
+        assert_eq!(encrypt(b"", &[]), Err(PrivateBoxError::NoRecipients));
+        assert_eq!(
+            encrypt(b"", &feeds),
+            Err(PrivateBoxError::TooManyRecipients { count: 8 })
+        );
+    }
+}
@@ -0,0 +1,479 @@
+//! Run an always-on pub.
+//!
+//! A pub accepts connections from its peers, hands out [invites][crate::invite], follows the
+//! feeds of the peers that redeem an invite and replicates the feeds within range of its follow
+//! graph. [run] starts a pub from a [Config]. Applications that need control over the connections
+//! use [PubServer] directly, for example to show the [connection events][PubServer::events].
+//!
+//! ```no_run
+//! # async_std::task::block_on(async {
+//! use ssb::crypto::sign::KeyPair;
+//! use ssb::pub_server::{run, Config};
+//!
+//! let mut config = Config::new(KeyPair::gen(), "/var/lib/ssb-pub");
+//! config.host = "pub.example.com:8008".to_string();
+//! run(config).await.unwrap();
+//! # });
+//! ```
+use anyhow::Context as _;
+use futures::prelude::*;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::admin::Monitor;
+use crate::conn::{
+    ConnEvent, ConnEvents, Direction, DisconnectCause, DuplicatePolicy, PeerConnections, Traffic,
+};
+use crate::crypto::sign::{KeyPair, PublicKey};
+use crate::feed::{FeedId, FeedStore};
+use crate::graph::Graph;
+use crate::invite::Invites;
+use crate::multi_address::{Dialer, MultiAddress};
+use crate::plugin::{Context, Plugins};
+use crate::replicate::Replicator;
+use crate::rpc::base::{Endpoint, Service};
+use crate::NetworkKey;
+
+/// Name of the ssb-db2 directory in the [data directory][Config::data_dir] that holds the feeds.
+pub const DB2_DIR: &str = "db2";
+
+/// Configuration for [run].
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Identity of the pub
+    pub keypair: KeyPair,
+    /// Directory for the feed store. Created if it does not exist.
+    pub data_dir: PathBuf,
+    /// Address to accept secret handshake connections on. Defaults to `0.0.0.0:8008`.
+    pub addr: String,
+    /// Host and port under which peers reach the pub. Used for invite codes. Defaults to
+    /// `localhost:8008`.
+    pub host: String,
+    /// Unix socket to accept unauthenticated local connections on, for example to create
+    /// invites with `ssbc invite create`.
+    pub socket: Option<PathBuf>,
+    /// Feeds up to this many hops from the pub are replicated. Defaults to 2.
+    pub hops: u32,
+    /// Key of the network for the secret handshake. Defaults to the main network.
+    pub network_key: NetworkKey,
+    /// What to do when a peer connects again while it is connected. Defaults to
+    /// [DuplicatePolicy::ReplaceOld] so that peers can reconnect before the old connection times
+    /// out.
+    pub duplicate_policy: DuplicatePolicy,
+}
+
+impl Config {
+    pub fn new(keypair: KeyPair, data_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            keypair,
+            data_dir: data_dir.into(),
+            addr: "0.0.0.0:8008".to_string(),
+            host: "localhost:8008".to_string(),
+            socket: None,
+            hops: 2,
+            network_key: crate::SCUTTLEBUTT_NETWORK_KEY,
+            duplicate_policy: DuplicatePolicy::ReplaceOld,
+        }
+    }
+
+    /// Take the address, network key and replication range from the ssb-server `config`.
+    pub fn from_ssb_config(
+        keypair: KeyPair,
+        data_dir: impl Into<PathBuf>,
+        config: &crate::config::Config,
+    ) -> Result<Self, crate::config::ConfigError> {
+        let addr = config.listen_addr();
+        let host = match &config.host {
+            Some(host) => format!("{}:{}", host, config.port),
+            None => format!("localhost:{}", config.port),
+        };
+        Ok(Self {
+            addr,
+            host,
+            hops: config.friends.hops,
+            network_key: config.network_key()?,
+            ..Self::new(keypair, data_dir)
+        })
+    }
+}
+
+/// Open the feed store in the data directory and serve the pub until accepting connections
+/// fails.
+pub async fn run(config: Config) -> anyhow::Result<()> {
+    std::fs::create_dir_all(&config.data_dir).with_context(|| {
+        format!(
+            "Failed to create data directory {}",
+            config.data_dir.display()
+        )
+    })?;
+    let store = crate::store::db2::Db2FeedStore::open(config.data_dir.join(DB2_DIR))
+        .context("Failed to open feed store")?;
+    let server = PubServer::new(&config, Arc::new(store)).context("Failed to load feeds")?;
+    server.listen(&config).await
+}
+
+/// Replicator, invites, plugins and open connections of a pub. Clones share the state.
+#[derive(Clone)]
+pub struct PubServer {
+    identity: ssb_box_stream::SecretKey,
+    network_key: NetworkKey,
+    replicator: Replicator,
+    invites: Invites,
+    plugins: Plugins,
+    connections: PeerConnections,
+    events: ConnEvents,
+    monitor: Monitor,
+}
+
+impl std::fmt::Debug for PubServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PubServer")
+            .field("invites", &self.invites)
+            .field("plugins", &self.plugins)
+            .field("connections", &self.connections)
+            .field("events", &self.events)
+            .field("monitor", &self.monitor)
+            .finish()
+    }
+}
+
+impl PubServer {
+    /// Pub with the identity and replication range of `config` that stores feeds in `store`.
+    ///
+    /// Builds the follow graph from the `contact` messages in `store`.
+    pub fn new(config: &Config, store: Arc<dyn FeedStore>) -> anyhow::Result<Self> {
+        let mut graph = Graph::new(FeedId(config.keypair.public), config.hops);
+        for feed in store.feeds()? {
+            for message in store.history(&feed, 1, None)? {
+                graph.apply(&message);
+            }
+        }
+        let replicator = Replicator::new(store, Arc::new(Mutex::new(graph)));
+        let invites = Invites::new(config.keypair.clone(), &config.host, replicator.clone());
+        let mut plugins = Plugins::new();
+        plugins.add(replicator.clone())?;
+        plugins.add(invites.clone())?;
+        let connections = PeerConnections::new(config.keypair.public, config.duplicate_policy);
+        Ok(Self {
+            identity: ssb_box_stream::SecretKey::from(&config.keypair),
+            network_key: config.network_key,
+            replicator,
+            invites,
+            plugins,
+            connections,
+            events: ConnEvents::new(),
+            monitor: Monitor::new(),
+        })
+    }
+
+    pub fn id(&self) -> FeedId {
+        self.invites.pub_id()
+    }
+
+    pub fn replicator(&self) -> &Replicator {
+        &self.replicator
+    }
+
+    pub fn invites(&self) -> &Invites {
+        &self.invites
+    }
+
+    /// Peers that are connected to the pub.
+    pub fn connections(&self) -> &PeerConnections {
+        &self.connections
+    }
+
+    /// Events of the TCP connections accepted by [PubServer::listen].
+    pub fn events(&self) -> &ConnEvents {
+        &self.events
+    }
+
+    /// Streams and traffic of the connections accepted by [PubServer::listen] and opened by
+    /// [PubServer::connect].
+    pub fn monitor(&self) -> &Monitor {
+        &self.monitor
+    }
+
+    /// Service for a connection to `peer`. `peer` is `None` for local connections.
+    ///
+    /// Local connections can also call the [admin][crate::admin] methods.
+    pub fn service(&self, peer: Option<PublicKey>) -> Service {
+        let mut service = self.plugins.service(&Context { peer });
+        if peer.is_none() {
+            service.add_service("admin", crate::admin::service(self));
+        }
+        service
+    }
+
+    /// Accept connections on the address and socket of `config` and run the plugins until
+    /// accepting connections fails. Connections are reported to [PubServer::events].
+    pub async fn listen(&self, config: &Config) -> anyhow::Result<()> {
+        tracing::info!(addr = %config.addr, id = %self.id(), "starting pub");
+
+        let tcp_server = self.clone();
+        let listen_tcp = crate::conn::listen_tcp(
+            config.addr.as_str(),
+            &self.network_key,
+            &self.identity,
+            self.events.clone(),
+            move |sender, receiver, peer| {
+                let server = tcp_server.clone();
+                async move {
+                    let endpoint = server.endpoint(sender, receiver, peer, Direction::Inbound);
+                    server
+                        .serve_direction(endpoint, Some(peer.0), Direction::Inbound)
+                        .await
+                }
+            },
+        )
+        .map(|result| result.context("Failed to accept connections"));
+
+        let listen_socket = async {
+            let path = match &config.socket {
+                Some(path) => path,
+                None => return future::pending::<anyhow::Result<()>>().await,
+            };
+            // Remove the socket of a previous run.
+            let _ = std::fs::remove_file(path);
+            let listener = async_std::os::unix::net::UnixListener::bind(path)
+                .await
+                .with_context(|| format!("Failed to bind {}", path.display()))?;
+            loop {
+                let (stream, _) = listener.accept().await?;
+                let server = self.clone();
+                async_std::task::spawn(async move {
+                    let endpoint = Endpoint::from_io(stream, server.service(None));
+                    if let Err(error) = server.serve(endpoint, None).await {
+                        tracing::warn!(?error, "local connection failed");
+                    }
+                });
+            }
+        };
+
+        futures::try_join!(listen_tcp, listen_socket, self.plugins.run())?;
+        Ok(())
+    }
+
+    /// Replicate with `peer` through `endpoint` and wait until the connection is closed.
+    ///
+    /// The pub replicates with `ebt.replicate` and falls back to `createHistoryStream` if the
+    /// peer does not support it. Local connections and guests that connect with an invite are
+    /// only served.
+    ///
+    /// If the peer is connected already the [duplicate policy][Config::duplicate_policy] decides
+    /// whether this connection or the open one is closed.
+    ///
+    /// Returns an error if the connection failed or was rejected as a duplicate.
+    pub async fn serve(&self, endpoint: Endpoint, peer: Option<PublicKey>) -> anyhow::Result<()> {
+        self.serve_direction(endpoint, peer, Direction::Inbound)
+            .await
+    }
+
+    /// Connect to the peer at `address`, replicate with it in the background and return its
+    /// feed ID once the handshake succeeded.
+    ///
+    /// The connection is reported to [PubServer::events] and closed like the connections
+    /// accepted by [PubServer::listen].
+    pub async fn connect(&self, address: &MultiAddress) -> anyhow::Result<FeedId> {
+        let identity = &self.identity;
+        let network_key = &self.network_key;
+        let (address, (sender, receiver, peer)) = Dialer::new()
+            .connect(address, |address, stream| async move {
+                let peer = ssb_box_stream::PublicKey::from_slice(&address.shs_key()?)?;
+                let (sender, receiver) = ssb_box_stream::Client::new(
+                    network_key,
+                    &peer,
+                    &identity.public_key(),
+                    identity,
+                )
+                .connect(stream)
+                .await?;
+                Ok::<_, anyhow::Error>((sender, receiver, FeedId::from(peer)))
+            })
+            .await?;
+        self.events.emit(ConnEvent::Connected {
+            peer,
+            addr: address.to_string(),
+        });
+        let sent = sender.bytes_written().clone();
+        let received = receiver.bytes_read().clone();
+        let endpoint = self.endpoint(sender, receiver, peer, Direction::Outbound);
+        let server = self.clone();
+        async_std::task::spawn(async move {
+            let cause = match server
+                .serve_direction(endpoint, Some(peer.0), Direction::Outbound)
+                .await
+            {
+                Ok(()) => DisconnectCause::Closed,
+                Err(error) => DisconnectCause::Error(format!("{:#}", error)),
+            };
+            server.events.emit(ConnEvent::Disconnected {
+                peer,
+                cause,
+                bytes: Traffic {
+                    sent: sent.get(),
+                    received: received.get(),
+                },
+            });
+        });
+        Ok(peer)
+    }
+
+    /// Endpoint for a secret handshake connection that is tracked by the [Monitor].
+    fn endpoint(
+        &self,
+        sender: ssb_box_stream::TcpSender,
+        receiver: ssb_box_stream::TcpReceiver,
+        peer: FeedId,
+        direction: Direction,
+    ) -> Endpoint {
+        let metrics = self
+            .monitor
+            .track(peer, direction)
+            .traffic(sender.bytes_written(), receiver.bytes_read());
+        Endpoint::builder()
+            .service(self.service(Some(peer.0)))
+            .metrics(metrics)
+            .peer(peer.into())
+            .direction(direction)
+            .build(sender, receiver)
+    }
+
+    async fn serve_direction(
+        &self,
+        endpoint: Endpoint,
+        peer: Option<PublicKey>,
+        direction: Direction,
+    ) -> anyhow::Result<()> {
+        let peer = match peer {
+            Some(peer) if !self.invites.is_invite(&peer) => peer,
+            _ => return endpoint.join().await,
+        };
+        let mut connection = match self.connections.open(peer, direction) {
+            Ok(connection) => connection,
+            Err(error) => {
+                tracing::info!(%error, "closing duplicate connection");
+                let _ = endpoint.close().await;
+                return Err(error.into());
+            }
+        };
+        let close = endpoint.close_handle();
+        let serve = self.replicate(endpoint, FeedId(peer));
+        futures::pin_mut!(serve);
+        let replaced = connection.replaced();
+        futures::pin_mut!(replaced);
+        match future::select(replaced, serve).await {
+            future::Either::Left(((), serve)) => {
+                tracing::info!(peer = %FeedId(peer), "closing replaced or disconnected connection");
+                close.close();
+                serve.await
+            }
+            future::Either::Right((result, _)) => result,
+        }
+    }
+
+    async fn replicate(&self, mut endpoint: Endpoint, peer: FeedId) -> anyhow::Result<()> {
+        tracing::info!(%peer, "peer connected");
+        let replicated = match self.replicator.replicate_ebt(endpoint.client()).await {
+            Ok(()) => Ok(()),
+            Err(error) => {
+                tracing::debug!(%peer, ?error, "EBT replication failed");
+                self.replicator.replicate_history(endpoint.client()).await
+            }
+        };
+        if let Err(error) = replicated {
+            tracing::warn!(%peer, ?error, "replication failed");
+        }
+        endpoint.join().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::feed::MemoryFeedStore;
+    use crate::replicate::Event;
+    use async_std::os::unix::net::UnixStream;
+
+    #[async_std::test]
+    async fn replace_duplicate_connection() {
+        let config = Config::new(KeyPair::gen(), "/nonexistent");
+        let server = PubServer::new(&config, Arc::new(MemoryFeedStore::new())).unwrap();
+        let peer = KeyPair::gen().public;
+        let connect = || {
+            let (client_io, server_io) = UnixStream::pair().unwrap();
+            let endpoint = Endpoint::from_io(server_io, server.service(Some(peer)));
+            let server = server.clone();
+            async_std::task::spawn(async move { server.serve(endpoint, Some(peer)).await });
+            Endpoint::from_io(client_io, Service::new())
+        };
+
+        let first = connect();
+        let _second = connect();
+        async_std::future::timeout(std::time::Duration::from_secs(5), first.join())
+            .await
+            .expect("first connection was not closed")
+            .unwrap();
+        assert_eq!(server.connections().count(&peer), 1);
+    }
+
+    #[async_std::test]
+    async fn redeem_invite_and_replicate() {
+        let config = Config::new(KeyPair::gen(), "/nonexistent");
+        let server = PubServer::new(&config, Arc::new(MemoryFeedStore::new())).unwrap();
+        let connect = |peer: PublicKey, service: Service| {
+            let (client_io, server_io) = UnixStream::pair().unwrap();
+            let endpoint = Endpoint::from_io(server_io, server.service(Some(peer)));
+            let server = server.clone();
+            async_std::task::spawn(async move { server.serve(endpoint, Some(peer)).await });
+            Endpoint::from_io(client_io, service)
+        };
+
+        let alice = KeyPair::gen();
+        let alice_id = FeedId(alice.public);
+        let code = server.invites().create(1);
+        let mut guest = connect(code.keypair().public, Service::new());
+        let response = guest
+            .client()
+            .send_async(
+                vec!["invite".to_string(), "use".to_string()],
+                vec![serde_json::json!({ "feed": alice_id })],
+            )
+            .await
+            .unwrap();
+        assert!(
+            matches!(response, crate::rpc::base::AsyncResponse::Json(_)),
+            "{:?}",
+            response
+        );
+
+        let alice_replicator = Replicator::new(
+            Arc::new(MemoryFeedStore::new()),
+            Arc::new(Mutex::new(Graph::new(alice_id, 2))),
+        );
+        let mut latest = None;
+        for _ in 0..2 {
+            let message = crate::feed::validate::sign(
+                &alice,
+                latest.as_ref(),
+                0u64,
+                serde_json::json!({ "type": "post" }),
+            );
+            alice_replicator.store().append(message.clone()).unwrap();
+            latest = Some(message);
+        }
+        let mut plugins = Plugins::new();
+        plugins.add(alice_replicator).unwrap();
+        let mut events = server.replicator().events();
+        let _alice = connect(alice.public, plugins.service(&Context::default()));
+        for expected in 1..=2 {
+            match events.next().await.unwrap() {
+                Event::Appended(message) => {
+                    assert_eq!(message.value.author, alice_id);
+                    assert_eq!(message.value.sequence, expected);
+                }
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+    }
+}
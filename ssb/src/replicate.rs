@@ -0,0 +1,1010 @@
+//! Replicate feeds with connected peers.
+//!
+//! The [Replicator] decides which feeds to replicate from the hops of the follow [Graph] and keeps
+//! them in a [FeedStore]. Messages received from peers are validated before they are appended.
+//! Appended `contact` messages are applied to the graph so that newly followed feeds are
+//! replicated, too.
+//!
+//! Two replication protocols are supported.
+//!
+//! * [Epidemic broadcast trees][ebt] with the `ebt.replicate` duplex stream. Both peers send a
+//!   vector clock that maps feed IDs to notes. A note is `-1` if the feed should not be
+//!   replicated. Otherwise it is the latest sequence number shifted by one bit to the left. The
+//!   lowest bit is set if the peer does not want to receive messages of the feed. Each peer
+//!   then sends the messages the other peer is missing and forwards new messages as they arrive.
+//! * The legacy `createHistoryStream` source that returns the messages of a single feed.
+//!
+//! Feeds that are [PARTIAL_HOPS] or more hops away are replicated partially if their
+//! [index feeds][IndexFeed] were added with [Replicator::add_index_feeds]. Instead of the whole
+//! feed only the index feeds are requested with the `getIndexFeed` source together with the
+//! messages they reference, for example the `about` and `contact` messages of the feed. The
+//! referenced messages are validated without their predecessors and kept apart from the store,
+//! see [Replicator::indexed_messages]. Partially replicated feeds are not requested with EBT.
+//!
+//! [ebt]: https://github.com/ssbc/epidemic-broadcast-trees
+
+use futures::channel::mpsc;
+use futures::prelude::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::feed::metafeed::{self, IndexFeed, Indexed};
+use crate::feed::validate::{validate, ValidationError};
+use crate::feed::{FeedId, FeedStore, Message, MessageId, StoreError};
+use crate::graph::Graph;
+use crate::plugin::{Context, Plugin};
+use crate::rpc::base::service::{Body, Error, Service, SinkClosed, StreamMessage};
+use crate::rpc::base::Client;
+
+/// Feeds with at least this many hops are replicated partially if their index feeds are known.
+pub const PARTIAL_HOPS: i64 = 2;
+
+/// Progress of the replication.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A message was validated and appended to the store.
+    Appended(Message),
+    /// A message referenced by an index feed was validated and added to the
+    /// [indexed messages][Replicator::indexed_messages].
+    Indexed(Message),
+    /// A message received from a peer failed validation and was dropped.
+    Invalid {
+        feed: FeedId,
+        error: ValidationError,
+    },
+}
+
+/// Replication state of a feed returned by [Replicator::progress].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedProgress {
+    pub feed: FeedId,
+    /// Distance of the feed in the follow graph
+    pub hops: i64,
+    /// Sequence number of the latest message in the store. `0` if the store has no messages.
+    pub local: u64,
+    /// Highest sequence number a peer announced in its EBT clock. `None` if no peer announced
+    /// the feed.
+    pub remote: Option<u64>,
+}
+
+impl FeedProgress {
+    /// Number of messages that peers have and the store is missing.
+    pub fn lag(&self) -> u64 {
+        self.remote.unwrap_or(0).saturating_sub(self.local)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AddError {
+    #[error(transparent)]
+    Validation(#[from] ValidationError),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("Message does not match the message referenced by the index feed")]
+    NotIndexed,
+}
+
+/// Replicates the feeds within range of the follow graph.
+///
+/// Cloning returns a handle to the same replicator.
+#[derive(Clone)]
+pub struct Replicator {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    store: Arc<dyn FeedStore>,
+    graph: Arc<Mutex<Graph>>,
+    /// Held while a message is validated and appended so that concurrent sessions do not append
+    /// conflicting messages.
+    append_lock: Mutex<()>,
+    subscribers: Mutex<Vec<mpsc::UnboundedSender<Event>>>,
+    /// Index feeds of partially replicated feeds by the indexed feed
+    index_feeds: Mutex<HashMap<FeedId, Vec<IndexFeed>>>,
+    /// Messages received through index feeds
+    indexed: Mutex<HashMap<MessageId, Message>>,
+    /// Highest sequence number of every feed announced by any peer
+    remote: Mutex<HashMap<FeedId, u64>>,
+}
+
+impl Replicator {
+    pub fn new(store: Arc<dyn FeedStore>, graph: Arc<Mutex<Graph>>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                store,
+                graph,
+                append_lock: Mutex::new(()),
+                subscribers: Mutex::new(Vec::new()),
+                index_feeds: Mutex::new(HashMap::new()),
+                indexed: Mutex::new(HashMap::new()),
+                remote: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    pub fn store(&self) -> &Arc<dyn FeedStore> {
+        &self.inner.store
+    }
+
+    pub fn graph(&self) -> &Arc<Mutex<Graph>> {
+        &self.inner.graph
+    }
+
+    /// Replicate `feed` through `index_feeds` instead of its whole history while it is at least
+    /// [PARTIAL_HOPS] hops away. Index feeds that do not index `feed` are ignored. Replaces the
+    /// index feeds that were added for `feed` before.
+    ///
+    /// The index feeds are usually found with
+    /// [MetafeedTree::index_feeds][metafeed::MetafeedTree::index_feeds].
+    pub fn add_index_feeds(&self, feed: FeedId, index_feeds: Vec<IndexFeed>) {
+        let index_feeds = index_feeds
+            .into_iter()
+            .filter(|index_feed| index_feed.query.author == feed)
+            .collect::<Vec<_>>();
+        let mut all_index_feeds = self.inner.index_feeds.lock().unwrap();
+        if index_feeds.is_empty() {
+            all_index_feeds.remove(&feed);
+        } else {
+            all_index_feeds.insert(feed, index_feeds);
+        }
+    }
+
+    /// Index feeds to replicate instead of `feed` if the feed is replicated partially.
+    fn partial(&self, feed: &FeedId, hops: i64) -> Option<Vec<IndexFeed>> {
+        if hops < PARTIAL_HOPS {
+            return None;
+        }
+        self.inner.index_feeds.lock().unwrap().get(feed).cloned()
+    }
+
+    /// Message with `id` that was received through an index feed.
+    pub fn indexed_message(&self, id: &MessageId) -> Option<Message> {
+        self.inner.indexed.lock().unwrap().get(id).cloned()
+    }
+
+    /// Messages of `author` that were received through index feeds ordered by sequence number.
+    pub fn indexed_messages(&self, author: &FeedId) -> Vec<Message> {
+        let mut messages = self
+            .inner
+            .indexed
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|message| message.value.author == *author)
+            .cloned()
+            .collect::<Vec<_>>();
+        messages.sort_by_key(|message| message.value.sequence);
+        messages
+    }
+
+    /// Replication state of every feed in range of the follow graph, ordered by hops and feed.
+    ///
+    /// The remote sequence numbers are taken from the EBT clocks of all peers that replicated
+    /// since the replicator was created.
+    pub fn progress(&self) -> Result<Vec<FeedProgress>, StoreError> {
+        let mut feeds = self
+            .inner
+            .graph
+            .lock()
+            .unwrap()
+            .hops()
+            .iter()
+            .filter(|(_, hops)| **hops >= 0)
+            .map(|(feed, hops)| (*feed, *hops))
+            .collect::<Vec<_>>();
+        feeds.sort_by_key(|(feed, hops)| (*hops, *feed));
+        let remote = self.inner.remote.lock().unwrap().clone();
+        feeds
+            .into_iter()
+            .map(|(feed, hops)| {
+                let local = self
+                    .inner
+                    .store
+                    .latest(&feed)?
+                    .map_or(0, |message| message.value.sequence);
+                Ok(FeedProgress {
+                    feed,
+                    hops,
+                    local,
+                    remote: remote.get(&feed).copied(),
+                })
+            })
+            .collect()
+    }
+
+    /// Stream of replication [Event]s that happen after this method is called.
+    pub fn events(&self) -> impl Stream<Item = Event> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.inner.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    fn emit(&self, event: Event) {
+        self.inner
+            .subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+
+    /// Validate a message value and append it to the store.
+    ///
+    /// Returns `Ok(None)` if the message is already stored.
+    pub fn add(&self, value: &serde_json::Value) -> Result<Option<Message>, AddError> {
+        let author = value
+            .get("author")
+            .and_then(|author| serde_json::from_value::<FeedId>(author.clone()).ok())
+            .ok_or_else(|| ValidationError::Decode {
+                message: "missing or invalid author".to_string(),
+            })?;
+        let sequence = value.get("sequence").and_then(serde_json::Value::as_u64);
+
+        let append_guard = self.inner.append_lock.lock().unwrap();
+        let latest = self.inner.store.latest(&author)?;
+        if let (Some(latest), Some(sequence)) = (&latest, sequence) {
+            if sequence <= latest.value.sequence {
+                return Ok(None);
+            }
+        }
+        let message = match validate(latest.as_ref(), value) {
+            Ok(message) => message,
+            Err(error) => {
+                drop(append_guard);
+                self.emit(Event::Invalid {
+                    feed: author,
+                    error: error.clone(),
+                });
+                return Err(error.into());
+            }
+        };
+        self.inner.store.append(message.clone())?;
+        drop(append_guard);
+
+        self.inner.graph.lock().unwrap().apply(&message);
+        self.emit(Event::Appended(message.clone()));
+        Ok(Some(message))
+    }
+
+    /// Validate a message of `index_feed` and the message it references and add both.
+    ///
+    /// The referenced message must match the query of the index feed. It is validated first so
+    /// that the index feed does not advance past a message that was not received.
+    fn add_index_message(
+        &self,
+        index_feed: &IndexFeed,
+        item: &IndexFeedItem,
+    ) -> Result<(), AddError> {
+        let indexed = crate::feed::validate::verify(&item.indexed.value)?;
+        let reference = item
+            .msg
+            .get("content")
+            .and_then(|content| content.get("indexed"))
+            .and_then(|indexed| indexed.get("key"))
+            .and_then(serde_json::Value::as_str);
+        if reference != Some(indexed.key.to_string().as_str())
+            || !index_feed.query.matches(&indexed.value)
+        {
+            return Err(AddError::NotIndexed);
+        }
+        let message = match self.add(&item.msg)? {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+        if metafeed::indexed(&message).map(|reference| reference.sequence)
+            != Some(indexed.value.sequence)
+        {
+            return Err(AddError::NotIndexed);
+        }
+
+        let previous = self
+            .inner
+            .indexed
+            .lock()
+            .unwrap()
+            .insert(indexed.key, indexed.clone());
+        if previous.is_none() {
+            self.inner.graph.lock().unwrap().apply(&indexed);
+            self.emit(Event::Indexed(indexed));
+        }
+        Ok(())
+    }
+
+    /// Note for `feed` in our vector clock given the hops of the feed.
+    fn note(&self, feed: &FeedId, hops: Option<i64>) -> Result<i64, StoreError> {
+        match hops {
+            Some(hops) if hops >= 0 && self.partial(feed, hops).is_none() => {
+                let sequence = self
+                    .inner
+                    .store
+                    .latest(feed)?
+                    .map_or(0, |latest| latest.value.sequence);
+                Ok((sequence as i64) << 1)
+            }
+            _ => Ok(-1),
+        }
+    }
+
+    /// Replicate with the peer connected through `client` using `ebt.replicate`.
+    ///
+    /// Runs until the peer closes the stream.
+    pub async fn replicate_ebt(&self, client: &mut Client) -> anyhow::Result<()> {
+        let (source, mut sink) = client
+            .start_duplex(
+                vec!["ebt".to_string(), "replicate".to_string()],
+                vec![serde_json::json!({ "version": 3, "format": "classic" })],
+            )
+            .await?;
+        let incoming = source
+            .take_while(|body| future::ready(body.is_ok()))
+            .filter_map(|body| future::ready(body.ok().and_then(|body| body.decode_json().ok())));
+        let (outgoing, mut outgoing_receiver) = mpsc::unbounded();
+
+        let session = self.clone().ebt_session(incoming, outgoing);
+        let forward = async move {
+            while let Some(value) = outgoing_receiver.next().await {
+                sink.send(Body::json(&value)).await?;
+            }
+            sink.close().await
+        };
+        futures::try_join!(session.map_err(anyhow::Error::from), forward)?;
+        Ok(())
+    }
+
+    /// Run the replication protocol that is common to both peers of an EBT stream.
+    ///
+    /// Ends when `incoming` ends.
+    async fn ebt_session(
+        self,
+        incoming: impl Stream<Item = serde_json::Value>,
+        outgoing: mpsc::UnboundedSender<serde_json::Value>,
+    ) -> Result<(), StoreError> {
+        let send = |value: serde_json::Value| {
+            // The peer may have closed the stream already.
+            let _ = outgoing.unbounded_send(value);
+        };
+        let mut events = self.events().fuse();
+        // The first item contains all feeds in range and is sent as our initial clock.
+        let mut hops_updates = self.inner.graph.lock().unwrap().hops_stream().fuse();
+        let incoming = incoming.fuse();
+        futures::pin_mut!(incoming);
+        // Latest sequence number the peer has for every feed it wants to receive.
+        let mut peer_has = HashMap::<FeedId, u64>::new();
+
+        loop {
+            futures::select! {
+                update = hops_updates.next() => {
+                    let update = match update {
+                        Some(update) => update,
+                        None => continue,
+                    };
+                    let mut clock = serde_json::Map::new();
+                    for (feed, hops) in update {
+                        clock.insert(feed.to_string(), self.note(&feed, hops)?.into());
+                    }
+                    if !clock.is_empty() {
+                        send(serde_json::Value::Object(clock));
+                    }
+                }
+                value = incoming.next() => {
+                    let value = match value {
+                        Some(value) => value,
+                        None => return Ok(()),
+                    };
+                    if value.get("signature").is_some() {
+                        match self.add(&value) {
+                            Ok(Some(message)) => {
+                                if let Some(sequence) = peer_has.get_mut(&message.value.author) {
+                                    *sequence = (*sequence).max(message.value.sequence);
+                                }
+                            }
+                            Ok(None)
+                            | Err(AddError::Validation(_))
+                            | Err(AddError::NotIndexed) => {}
+                            Err(AddError::Store(error)) => return Err(error),
+                        }
+                        continue;
+                    }
+                    let clock = match serde_json::from_value::<HashMap<FeedId, i64>>(value) {
+                        Ok(clock) => clock,
+                        Err(error) => {
+                            tracing::warn!(?error, "invalid EBT clock");
+                            continue;
+                        }
+                    };
+                    for (feed, note) in clock {
+                        if note >= 0 {
+                            let mut remote = self.inner.remote.lock().unwrap();
+                            let announced = remote.entry(feed).or_default();
+                            *announced = (*announced).max((note >> 1) as u64);
+                        }
+                        if note < 0 || note & 1 == 1 {
+                            peer_has.remove(&feed);
+                            continue;
+                        }
+                        let mut sequence = (note >> 1) as u64;
+                        let history = self.inner.store.history(&feed, sequence + 1, None)?;
+                        // The peer cannot validate deleted messages and anything after them.
+                        for message in history.into_iter().take_while(|message| !message.value.is_deleted()) {
+                            sequence = message.value.sequence;
+                            send(serde_json::to_value(&message.value).unwrap());
+                        }
+                        peer_has.insert(feed, sequence);
+                    }
+                }
+                event = events.next() => {
+                    if let Some(Event::Appended(message)) = event {
+                        if let Some(sequence) = peer_has.get_mut(&message.value.author) {
+                            if message.value.sequence == *sequence + 1 {
+                                *sequence = message.value.sequence;
+                                send(serde_json::to_value(&message.value).unwrap());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replicate all feeds in range from the peer connected through `client` using
+    /// `createHistoryStream`.
+    ///
+    /// Requests the messages the store is missing for every feed and returns once all requests
+    /// have completed. A feed is skipped once the peer sends an invalid message for it.
+    /// Partially replicated feeds are requested with `getIndexFeed`.
+    pub async fn replicate_history(&self, client: &mut Client) -> anyhow::Result<()> {
+        let feeds = self
+            .inner
+            .graph
+            .lock()
+            .unwrap()
+            .hops()
+            .iter()
+            .filter(|(_, hops)| **hops >= 0)
+            .map(|(feed, hops)| (*feed, *hops))
+            .collect::<Vec<_>>();
+        for (feed, hops) in feeds {
+            match self.partial(&feed, hops) {
+                Some(index_feeds) => {
+                    for index_feed in index_feeds {
+                        self.replicate_index_feed(client, &index_feed).await?;
+                    }
+                }
+                None => self.replicate_feed(client, feed).await?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Request the messages of `feed` the store is missing with `createHistoryStream`.
+    async fn replicate_feed(&self, client: &mut Client, feed: FeedId) -> anyhow::Result<()> {
+        let sequence = self
+            .inner
+            .store
+            .latest(&feed)?
+            .map_or(0, |latest| latest.value.sequence);
+        let mut history = client
+            .start_source(
+                vec!["createHistoryStream".to_string()],
+                vec![serde_json::json!({
+                    "id": feed,
+                    "seq": sequence + 1,
+                    "keys": false,
+                    "live": false,
+                })],
+            )
+            .await?;
+        while let Some(body) = history.next().await {
+            let value = match body {
+                Ok(body) => body.decode_json::<serde_json::Value>()?,
+                Err(Error { name, message }) => {
+                    tracing::warn!(%feed, %name, %message, "createHistoryStream failed");
+                    break;
+                }
+            };
+            match self.add(&value) {
+                Ok(_) => {}
+                Err(AddError::Store(error)) => return Err(error.into()),
+                Err(error) => {
+                    tracing::warn!(%feed, ?error, "received invalid message");
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Request the messages of `index_feed` the store is missing together with the messages they
+    /// reference with `getIndexFeed`.
+    async fn replicate_index_feed(
+        &self,
+        client: &mut Client,
+        index_feed: &IndexFeed,
+    ) -> anyhow::Result<()> {
+        let feed = index_feed.feed;
+        let sequence = self
+            .inner
+            .store
+            .latest(&feed)?
+            .map_or(0, |latest| latest.value.sequence);
+        let mut items = client
+            .start_source(
+                vec!["getIndexFeed".to_string()],
+                vec![serde_json::json!({ "id": feed, "seq": sequence + 1 })],
+            )
+            .await?;
+        while let Some(body) = items.next().await {
+            let item = match body {
+                Ok(body) => body.decode_json::<IndexFeedItem>()?,
+                Err(Error { name, message }) => {
+                    tracing::warn!(%feed, %name, %message, "getIndexFeed failed");
+                    break;
+                }
+            };
+            match self.add_index_message(index_feed, &item) {
+                Ok(()) => {}
+                Err(AddError::Store(error)) => return Err(error.into()),
+                Err(error) => {
+                    tracing::warn!(%feed, ?error, "received invalid index message");
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Items for a `getIndexFeed` request.
+    ///
+    /// The referenced messages are looked up in the store using the author of the query of the
+    /// index feed, or in the indexed messages if the indexed feed is replicated partially. The
+    /// response ends before the first message whose referenced message is not found.
+    fn index_feed_stream(
+        &self,
+        args: IndexFeedArgs,
+    ) -> impl Stream<Item = Result<Body, Error>> + Send {
+        let items = self
+            .index_feed_items(&args)
+            .map_err(|error| Error::new("Error", error));
+        let items = match items {
+            Ok(items) => items,
+            Err(error) => return stream::once(future::ready(Err(error))).left_stream(),
+        };
+        stream::iter(items)
+            .map(|item| Ok(Body::json(&item)))
+            .right_stream()
+    }
+
+    fn index_feed_items(&self, args: &IndexFeedArgs) -> Result<Vec<IndexFeedItem>, StoreError> {
+        let author = self
+            .inner
+            .index_feeds
+            .lock()
+            .unwrap()
+            .values()
+            .flatten()
+            .find(|index_feed| index_feed.feed == args.id)
+            .map(|index_feed| index_feed.query.author);
+        let mut items = Vec::new();
+        for message in self
+            .inner
+            .store
+            .history(&args.id, args.seq.max(1), args.limit)?
+        {
+            let indexed = match metafeed::indexed(&message) {
+                Some(indexed) => self.find_indexed(author.as_ref(), indexed)?,
+                None => None,
+            };
+            let indexed = match indexed {
+                Some(indexed) => indexed,
+                None => break,
+            };
+            items.push(IndexFeedItem {
+                msg: serde_json::to_value(&message.value).unwrap(),
+                indexed: IndexedMessage {
+                    key: indexed.key,
+                    value: serde_json::to_value(&indexed.value).unwrap(),
+                },
+            });
+        }
+        Ok(items)
+    }
+
+    fn find_indexed(
+        &self,
+        author: Option<&FeedId>,
+        indexed: Indexed,
+    ) -> Result<Option<Message>, StoreError> {
+        if let Some(message) = self.indexed_message(&indexed.key) {
+            return Ok(Some(message));
+        }
+        let message = match author {
+            Some(author) => self.inner.store.get(author, indexed.sequence)?,
+            None => None,
+        };
+        Ok(message.filter(|message| message.key == indexed.key && !message.value.is_deleted()))
+    }
+
+    /// Messages for a `createHistoryStream` request.
+    fn history_stream(
+        &self,
+        args: HistoryStreamArgs,
+    ) -> impl Stream<Item = Result<Body, Error>> + Send {
+        let HistoryStreamArgs {
+            id,
+            seq,
+            limit,
+            keys,
+            live,
+        } = args;
+        // Subscribe before reading the history so that no message is missed.
+        let events = if live {
+            self.events().left_stream()
+        } else {
+            stream::empty().right_stream()
+        };
+        let mut history = match self.inner.store.history(&id, seq.max(1), limit) {
+            Ok(history) => history,
+            Err(error) => {
+                let error = Error::new("Error", error);
+                return stream::once(future::ready(Err(error))).left_stream();
+            }
+        };
+        // Deleted content is not served. The history ends before the first deleted message
+        // because the peer cannot validate the messages that follow it.
+        let deleted = history
+            .iter()
+            .position(|message| message.value.is_deleted());
+        if let Some(position) = deleted {
+            history.truncate(position);
+        }
+        let mut latest = history
+            .last()
+            .map_or(seq.max(1) - 1, |message| message.value.sequence);
+        let live_messages = events.filter_map(move |event| {
+            future::ready(match event {
+                Event::Appended(message)
+                    if deleted.is_none()
+                        && message.value.author == id
+                        && message.value.sequence == latest + 1 =>
+                {
+                    latest = message.value.sequence;
+                    Some(message)
+                }
+                _ => None,
+            })
+        });
+        stream::iter(history)
+            .chain(live_messages)
+            .take(limit.unwrap_or(usize::MAX))
+            .map(move |message| {
+                Ok(if keys {
+                    Body::json(&message)
+                } else {
+                    Body::json(&message.value)
+                })
+            })
+            .right_stream()
+    }
+}
+
+impl std::fmt::Debug for Replicator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Replicator")
+            .field("store", &"Arc<dyn FeedStore>")
+            .field("graph", &self.inner.graph)
+            .finish()
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct HistoryStreamArgs {
+    id: FeedId,
+    #[serde(default)]
+    seq: u64,
+    limit: Option<usize>,
+    #[serde(default = "default_true")]
+    keys: bool,
+    #[serde(default)]
+    live: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct IndexFeedArgs {
+    id: FeedId,
+    #[serde(default)]
+    seq: u64,
+    limit: Option<usize>,
+}
+
+/// Item of a `getIndexFeed` response.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct IndexFeedItem {
+    /// Value of the message of the index feed
+    msg: serde_json::Value,
+    indexed: IndexedMessage,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct IndexedMessage {
+    key: MessageId,
+    value: serde_json::Value,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Plugin for Replicator {
+    fn name(&self) -> &str {
+        "ebt"
+    }
+
+    fn init(&self, service: &mut Service, _context: &Context) {
+        let replicator = self.clone();
+        service.add_duplex("replicate", move |_: Vec<serde_json::Value>| {
+            let (incoming_sink, incoming) = mpsc::unbounded();
+            let incoming = incoming
+                .take_while(|message| future::ready(matches!(message, StreamMessage::Data(_))))
+                .filter_map(|message| {
+                    future::ready(match message {
+                        StreamMessage::Data(body) => body.decode_json().ok(),
+                        _ => None,
+                    })
+                });
+            let (outgoing, outgoing_receiver) = mpsc::unbounded();
+            let session = replicator.clone().ebt_session(incoming, outgoing);
+            async_std::task::spawn(async move {
+                if let Err(error) = session.await {
+                    tracing::warn!(?error, "EBT session failed");
+                }
+            });
+            (
+                outgoing_receiver.map(|value: serde_json::Value| Ok(Body::json(&value))),
+                incoming_sink.sink_map_err(|_| SinkClosed),
+            )
+        });
+    }
+
+    fn init_root(&self, service: &mut Service, _context: &Context) {
+        let replicator = self.clone();
+        service.add_source(
+            "createHistoryStream",
+            move |(args,): (HistoryStreamArgs,)| replicator.history_stream(args),
+        );
+        let replicator = self.clone();
+        service.add_source("getIndexFeed", move |(args,): (IndexFeedArgs,)| {
+            replicator.index_feed_stream(args)
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::sign::KeyPair;
+    use crate::feed::validate::sign;
+    use crate::feed::MemoryFeedStore;
+    use crate::plugin::Plugins;
+    use crate::rpc::base::Endpoint;
+
+    fn replicator(root: FeedId, follows: &[FeedId]) -> Replicator {
+        let mut graph = Graph::new(root, 2);
+        for feed in follows {
+            graph.follow(root, *feed);
+        }
+        Replicator::new(
+            Arc::new(MemoryFeedStore::new()),
+            Arc::new(Mutex::new(graph)),
+        )
+    }
+
+    fn publish(replicator: &Replicator, keypair: &KeyPair, count: usize) {
+        for _ in 0..count {
+            let latest = replicator.store().latest(&FeedId(keypair.public)).unwrap();
+            let message = sign(
+                keypair,
+                latest.as_ref(),
+                0u64,
+                serde_json::json!({ "type": "post" }),
+            );
+            replicator
+                .add(&serde_json::to_value(&message.value).unwrap())
+                .unwrap();
+        }
+    }
+
+    fn connect(a: &Replicator, b: &Replicator) -> (Endpoint, Endpoint) {
+        let service = |replicator: &Replicator| {
+            let mut plugins = Plugins::new();
+            plugins.add(replicator.clone()).unwrap();
+            plugins.service(&Context::default())
+        };
+        let (a_to_b_sender, a_to_b_receiver) = mpsc::channel(10);
+        let (b_to_a_sender, b_to_a_receiver) = mpsc::channel(10);
+        let endpoint_a = Endpoint::new(
+            a_to_b_sender,
+            b_to_a_receiver.map(Ok::<_, std::io::Error>),
+            service(a),
+        );
+        let endpoint_b = Endpoint::new(
+            b_to_a_sender,
+            a_to_b_receiver.map(Ok::<_, std::io::Error>),
+            service(b),
+        );
+        (endpoint_a, endpoint_b)
+    }
+
+    #[async_std::test]
+    async fn replicate_history() {
+        let alice = KeyPair::gen();
+        let alice_id = FeedId(alice.public);
+        let bob_id = FeedId(KeyPair::gen().public);
+        let a = replicator(alice_id, &[]);
+        let b = replicator(bob_id, &[alice_id]);
+        publish(&a, &alice, 3);
+
+        let (_endpoint_a, mut endpoint_b) = connect(&a, &b);
+        let events = b.events();
+        b.replicate_history(endpoint_b.client()).await.unwrap();
+
+        assert_eq!(b.store().history(&alice_id, 1, None).unwrap().len(), 3);
+        let appended = events
+            .take(3)
+            .map(|event| match event {
+                Event::Appended(message) => message.value.sequence,
+                event => panic!("unexpected event {:?}", event),
+            })
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(appended, vec![1, 2, 3]);
+    }
+
+    #[async_std::test]
+    async fn replicate_history_deleted() {
+        let alice = KeyPair::gen();
+        let alice_id = FeedId(alice.public);
+        let bob_id = FeedId(KeyPair::gen().public);
+        let a = replicator(alice_id, &[]);
+        let b = replicator(bob_id, &[alice_id]);
+        publish(&a, &alice, 3);
+        let deleted = a.store().get(&alice_id, 2).unwrap().unwrap();
+        assert!(a.store().delete_content(&deleted.key).unwrap());
+
+        let (_endpoint_a, mut endpoint_b) = connect(&a, &b);
+        b.replicate_history(endpoint_b.client()).await.unwrap();
+        let history = b.store().history(&alice_id, 1, None).unwrap();
+        assert_eq!(history.len(), 1);
+    }
+
+    #[async_std::test]
+    async fn replicate_partial() {
+        let alice = KeyPair::gen();
+        let alice_id = FeedId(alice.public);
+        let index = KeyPair::gen();
+        let index_feed = IndexFeed {
+            feed: FeedId(index.public),
+            query: metafeed::IndexQuery {
+                author: alice_id,
+                content_type: "about".to_string(),
+                private: false,
+            },
+        };
+        let bob_id = FeedId(KeyPair::gen().public);
+        let carol_id = FeedId(KeyPair::gen().public);
+        let a = replicator(alice_id, &[]);
+        let b = replicator(bob_id, &[carol_id]);
+        b.graph().lock().unwrap().follow(carol_id, alice_id);
+        a.add_index_feeds(alice_id, vec![index_feed.clone()]);
+        b.add_index_feeds(alice_id, vec![index_feed.clone()]);
+
+        let mut latest = None::<Message>;
+        let mut latest_index = None::<Message>;
+        for content_type in &["post", "about", "post", "about"] {
+            let message = sign(
+                &alice,
+                latest.as_ref(),
+                0u64,
+                serde_json::json!({ "type": content_type }),
+            );
+            a.store().append(message.clone()).unwrap();
+            if *content_type == "about" {
+                let index_message = sign(
+                    &index,
+                    latest_index.as_ref(),
+                    0u64,
+                    metafeed::index_content(&message),
+                );
+                a.store().append(index_message.clone()).unwrap();
+                latest_index = Some(index_message);
+            }
+            latest = Some(message);
+        }
+
+        let (_endpoint_a, mut endpoint_b) = connect(&a, &b);
+        b.replicate_history(endpoint_b.client()).await.unwrap();
+
+        assert!(b.store().history(&alice_id, 1, None).unwrap().is_empty());
+        assert_eq!(
+            b.store().history(&index_feed.feed, 1, None).unwrap().len(),
+            2
+        );
+        let indexed = b.indexed_messages(&alice_id);
+        assert_eq!(
+            indexed
+                .iter()
+                .map(|message| message.value.sequence)
+                .collect::<Vec<_>>(),
+            vec![2, 4]
+        );
+        assert_eq!(
+            b.indexed_message(&latest.unwrap().key),
+            indexed.get(1).cloned()
+        );
+    }
+
+    #[async_std::test]
+    async fn replicate_ebt_live() {
+        let alice = KeyPair::gen();
+        let alice_id = FeedId(alice.public);
+        let bob_id = FeedId(KeyPair::gen().public);
+        let a = replicator(alice_id, &[]);
+        let b = replicator(bob_id, &[alice_id]);
+        publish(&a, &alice, 2);
+
+        let (_endpoint_a, mut endpoint_b) = connect(&a, &b);
+        let mut events = b.events();
+        let replicate = b.replicate_ebt(endpoint_b.client());
+        let check = async {
+            for expected in 1..=3 {
+                match events.next().await.unwrap() {
+                    Event::Appended(message) => assert_eq!(message.value.sequence, expected),
+                    event => panic!("unexpected event {:?}", event),
+                }
+                if expected == 2 {
+                    publish(&a, &alice, 1);
+                }
+            }
+        };
+        let check = async_std::future::timeout(std::time::Duration::from_secs(5), check);
+        futures::pin_mut!(replicate, check);
+        match future::select(replicate, check).await {
+            future::Either::Left((result, _)) => panic!("replicate finished: {:?}", result),
+            future::Either::Right((result, _)) => result.unwrap(),
+        }
+        assert_eq!(b.store().history(&alice_id, 1, None).unwrap().len(), 3);
+        let progress = b.progress().unwrap();
+        assert_eq!(progress[1].feed, alice_id);
+        assert_eq!(progress[1].local, 3);
+        // Alice announced the second message in her initial clock.
+        assert_eq!(progress[1].remote, Some(2));
+    }
+
+    #[test]
+    fn add_invalid() {
+        let alice = KeyPair::gen();
+        let alice_id = FeedId(alice.public);
+        let replicator = replicator(alice_id, &[]);
+        let first = sign(&alice, None, 0u64, serde_json::json!({ "type": "post" }));
+        let second = sign(
+            &alice,
+            Some(&first),
+            0u64,
+            serde_json::json!({ "type": "post" }),
+        );
+
+        let error = replicator
+            .add(&serde_json::to_value(&second.value).unwrap())
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            AddError::Validation(ValidationError::Sequence {
+                expected: 1,
+                actual: 2
+            })
+        ));
+
+        let first_value = serde_json::to_value(&first.value).unwrap();
+        assert!(replicator.add(&first_value).unwrap().is_some());
+        assert!(replicator.add(&first_value).unwrap().is_none());
+    }
+}
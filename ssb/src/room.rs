@@ -0,0 +1,527 @@
+//! Room server that relays connections between its attendants.
+//!
+//! The [Room] plugin implements the server side of [rooms]. Peers that are connected to the room
+//! and registered with [Room::attend] are attendants. An attendant opens a tunnel to another
+//! attendant by calling the `tunnel.connect` duplex with the `portal` (the room) and the `target`
+//! of the tunnel. The room calls `tunnel.connect` on the connection of the target with the
+//! `origin` of the tunnel added and splices both streams. The peers then run a secret handshake
+//! through the tunnel.
+//!
+//! Messages are passed through the tunnel one at a time, see [proxy::forward_duplex], so a slow
+//! peer slows down the other peer instead of filling up the room.
+//!
+//! The room also serves `room.metadata`, `room.attendants`, `tunnel.isRoom` and `tunnel.ping`.
+//! A room is open by default. A room created with [Room::with_members] only accepts its members as
+//! attendants and only they may open tunnels and list the attendants.
+//!
+//! ```no_run
+//! # async_std::task::block_on(async {
+//! # let io: async_std::net::TcpStream = unimplemented!();
+//! # let peer: ssb::feed::FeedId = unimplemented!();
+//! use ssb::plugin::{Context, Plugins};
+//! use ssb::room::Room;
+//!
+//! let room = Room::new("my room");
+//! let mut plugins = Plugins::new();
+//! plugins.add(room.clone()).unwrap();
+//!
+//! // For every connection
+//! let service = plugins.service(&Context { peer: Some(peer.0) });
+//! let mut endpoint = ssb::rpc::base::Endpoint::from_io(io, service);
+//! let _attendance = room.attend(peer, endpoint.client().sender());
+//! endpoint.join().await.unwrap();
+//! # });
+//! ```
+//!
+//! [rooms]: https://ssb-ngi-pointer.github.io/rooms2/
+use futures::channel::mpsc;
+use futures::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use crate::feed::FeedId;
+use crate::plugin::{Context, Plugin};
+use crate::rpc::base::service::{AsyncResponse, Body, Error, Service, SinkClosed};
+use crate::rpc::base::{proxy, RequestSender, StreamMessage};
+use crate::rpc::ssb::{AttendantsEvent, RoomMetadata};
+
+/// Features announced in `room.metadata`.
+const FEATURES: &[&str] = &["tunnel", "room1"];
+
+/// Room plugin. Clones share the attendants and members.
+#[derive(Debug, Clone)]
+pub struct Room {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    name: String,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Members of the room. Everybody may attend if `None`.
+    members: Option<HashSet<FeedId>>,
+    attendants: HashMap<FeedId, Attendant>,
+    /// Subscribers of `room.attendants`.
+    subscribers: Vec<mpsc::UnboundedSender<AttendantsEvent>>,
+    next_connection: u64,
+}
+
+#[derive(Debug)]
+struct Attendant {
+    /// Identifies the connection of the attendant. If a peer attends on a second connection the
+    /// first one is replaced.
+    connection: u64,
+    sender: RequestSender,
+}
+
+impl State {
+    fn is_member(&self, peer: &FeedId) -> bool {
+        match &self.members {
+            Some(members) => members.contains(peer),
+            None => true,
+        }
+    }
+
+    fn notify(&mut self, event: AttendantsEvent) {
+        self.subscribers
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+/// Error returned by [Room::attend].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("{peer} is not a member of the room")]
+pub struct NotMemberError {
+    pub peer: FeedId,
+}
+
+/// Arguments of `tunnel.connect` sent by the origin of a tunnel.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct ConnectArgs {
+    portal: FeedId,
+    target: FeedId,
+}
+
+/// Arguments of `tunnel.connect` that the room sends to the target of a tunnel.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct TargetConnectArgs {
+    origin: FeedId,
+    portal: FeedId,
+    target: FeedId,
+}
+
+impl Room {
+    /// Open room called `name`.
+    pub fn new(name: impl ToString) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                name: name.to_string(),
+                state: Mutex::new(State::default()),
+            }),
+        }
+    }
+
+    /// Room called `name` that only admits `members`.
+    pub fn with_members(name: impl ToString, members: impl IntoIterator<Item = FeedId>) -> Self {
+        let room = Self::new(name);
+        room.inner.state.lock().unwrap().members = Some(members.into_iter().collect());
+        room
+    }
+
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    /// Admit `peer` to a room created with [Room::with_members].
+    pub fn add_member(&self, peer: FeedId) {
+        if let Some(members) = &mut self.inner.state.lock().unwrap().members {
+            members.insert(peer);
+        }
+    }
+
+    /// Remove `peer` from the members of a room created with [Room::with_members]. The peer
+    /// stops attending the room but its connection and open tunnels are not closed.
+    pub fn remove_member(&self, peer: &FeedId) {
+        let mut state = self.inner.state.lock().unwrap();
+        let state = &mut *state;
+        if let Some(members) = &mut state.members {
+            members.remove(peer);
+            if state.attendants.remove(peer).is_some() {
+                state.notify(AttendantsEvent::Left {
+                    id: peer.to_string(),
+                });
+            }
+        }
+    }
+
+    pub fn is_member(&self, peer: &FeedId) -> bool {
+        self.inner.state.lock().unwrap().is_member(peer)
+    }
+
+    /// Current attendants of the room.
+    pub fn attendants(&self) -> Vec<FeedId> {
+        let state = self.inner.state.lock().unwrap();
+        let mut attendants = state.attendants.keys().copied().collect::<Vec<_>>();
+        attendants.sort();
+        attendants
+    }
+
+    /// Register the connection to `peer` so that other attendants can open tunnels to it.
+    /// `sender` belongs to the endpoint of the connection.
+    ///
+    /// The peer attends the room until the returned [Attendance] is dropped, usually when the
+    /// connection is closed. Fails if the peer is not a member of the room.
+    pub fn attend(
+        &self,
+        peer: FeedId,
+        sender: RequestSender,
+    ) -> Result<Attendance, NotMemberError> {
+        let mut state = self.inner.state.lock().unwrap();
+        if !state.is_member(&peer) {
+            return Err(NotMemberError { peer });
+        }
+        let connection = state.next_connection;
+        state.next_connection += 1;
+        let previous = state
+            .attendants
+            .insert(peer, Attendant { connection, sender });
+        if previous.is_none() {
+            state.notify(AttendantsEvent::Joined {
+                id: peer.to_string(),
+            });
+        }
+        tracing::debug!(%peer, "peer attends room");
+        Ok(Attendance {
+            room: self.clone(),
+            peer,
+            connection,
+        })
+    }
+
+    fn metadata(&self, peer: Option<FeedId>) -> RoomMetadata {
+        RoomMetadata {
+            name: self.inner.name.clone(),
+            membership: matches!(peer, Some(peer) if self.is_member(&peer)),
+            features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+        }
+    }
+
+    /// Stream of the attendants for `room.attendants`. Starts with the current state.
+    fn subscribe(&self) -> impl Stream<Item = AttendantsEvent> {
+        let (sender, receiver) = mpsc::unbounded();
+        let mut state = self.inner.state.lock().unwrap();
+        let mut ids = state
+            .attendants
+            .keys()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>();
+        ids.sort();
+        state.subscribers.push(sender);
+        stream::once(future::ready(AttendantsEvent::State { ids })).chain(receiver)
+    }
+
+    /// Splice the `tunnel.connect` stream of `origin` with a `tunnel.connect` stream to the
+    /// target.
+    fn connect(&self, origin: Option<FeedId>, args: ConnectArgs) -> Result<Tunnel, Error> {
+        let state = self.inner.state.lock().unwrap();
+        let origin = match origin {
+            Some(origin) if state.is_member(&origin) => origin,
+            _ => return Err(room_error("Only members of the room may open tunnels")),
+        };
+        if args.target == origin {
+            return Err(room_error("Cannot open a tunnel to yourself"));
+        }
+        let target = state
+            .attendants
+            .get(&args.target)
+            .ok_or_else(|| room_error(format!("{} is not attending the room", args.target)))?;
+        tracing::debug!(%origin, target = %args.target, "opening tunnel");
+        let target_args = TargetConnectArgs {
+            origin,
+            portal: args.portal,
+            target: args.target,
+        };
+        Ok(proxy::forward_duplex(
+            target.sender.clone(),
+            vec!["tunnel".to_string(), "connect".to_string()],
+            vec![serde_json::to_value(target_args).unwrap()],
+        ))
+    }
+}
+
+/// Source and sink of a `tunnel.connect` stream.
+type Tunnel = (
+    stream::BoxStream<'static, Result<Body, Error>>,
+    std::pin::Pin<Box<dyn Sink<StreamMessage, Error = SinkClosed> + Send>>,
+);
+
+impl Plugin for Room {
+    fn name(&self) -> &str {
+        "room"
+    }
+
+    fn init(&self, service: &mut Service, context: &Context) {
+        let peer = context.peer.map(FeedId);
+
+        let room = self.clone();
+        service.add_sync("metadata", move |_: Vec<()>| {
+            AsyncResponse::json_ok(&room.metadata(peer))
+        });
+        service.describe(
+            "metadata",
+            "Name and features of the room and whether the peer is a member",
+            &[],
+        );
+
+        let room = self.clone();
+        service.add_source("attendants", move |_: Vec<()>| {
+            match peer.filter(|peer| room.is_member(peer)) {
+                Some(_) => room
+                    .subscribe()
+                    .map(|event| Ok(Body::json(&event)))
+                    .left_stream(),
+                None => stream::once(future::ready(Err(room_error(
+                    "Only members of the room may list the attendants",
+                ))))
+                .right_stream(),
+            }
+        });
+        service.describe("attendants", "Live stream of the attendants", &[]);
+    }
+
+    fn init_root(&self, service: &mut Service, context: &Context) {
+        let peer = context.peer.map(FeedId);
+        let mut tunnel = Service::new();
+
+        let room = self.clone();
+        tunnel.add_sync("isRoom", move |_: Vec<()>| {
+            AsyncResponse::json_ok(&room.metadata(peer))
+        });
+        tunnel.describe("isRoom", "Metadata of the room", &[]);
+
+        tunnel.add_sync("ping", |_: Vec<()>| {
+            AsyncResponse::json_ok(&(crate::time::now() as u64))
+        });
+        tunnel.describe("ping", "Current time of the room in milliseconds", &[]);
+
+        let room = self.clone();
+        tunnel.add_duplex("connect", move |(args,): (ConnectArgs,)| {
+            room.connect(peer, args).unwrap_or_else(|error| {
+                let sink = futures::sink::drain().sink_map_err(|infallible| match infallible {});
+                (
+                    stream::once(future::ready(Err(error))).boxed(),
+                    Box::pin(sink),
+                )
+            })
+        });
+        tunnel.describe(
+            "connect",
+            "Open a tunnel to an attendant",
+            &[(
+                "args",
+                "Object with the `portal` and the `target` of the tunnel",
+            )],
+        );
+
+        service.add_service("tunnel", tunnel);
+    }
+}
+
+/// Returned by [Room::attend]. The peer leaves the room when this is dropped.
+#[derive(Debug)]
+pub struct Attendance {
+    room: Room,
+    peer: FeedId,
+    connection: u64,
+}
+
+impl Drop for Attendance {
+    fn drop(&mut self) {
+        let mut state = self.room.inner.state.lock().unwrap();
+        let attends = matches!(
+            state.attendants.get(&self.peer),
+            Some(attendant) if attendant.connection == self.connection
+        );
+        if attends {
+            state.attendants.remove(&self.peer);
+            state.notify(AttendantsEvent::Left {
+                id: self.peer.to_string(),
+            });
+            tracing::debug!(peer = %self.peer, "peer left room");
+        }
+    }
+}
+
+fn room_error(message: impl ToString) -> Error {
+    Error::new("Error", message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::sign::KeyPair;
+    use crate::plugin::Plugins;
+    use crate::rpc::base::{AsyncResponse as Response, Endpoint};
+    use async_std::os::unix::net::UnixStream;
+
+    struct Peer {
+        id: FeedId,
+        client: Endpoint,
+        server: Endpoint,
+        attendance: Option<Attendance>,
+    }
+
+    fn connect(room: &Room) -> Peer {
+        connect_as(room, FeedId(KeyPair::gen().public))
+    }
+
+    /// Connect peer `id` to `room` and let it attend if it is a member. The peer echoes the
+    /// messages of tunnels that are opened to it after sending the origin of the tunnel.
+    fn connect_as(room: &Room, id: FeedId) -> Peer {
+        let mut plugins = Plugins::new();
+        plugins.add(room.clone()).unwrap();
+        let (client_io, server_io) = UnixStream::pair().unwrap();
+        let mut server =
+            Endpoint::from_io(server_io, plugins.service(&Context { peer: Some(id.0) }));
+        let mut tunnel = Service::new();
+        tunnel.add_duplex("connect", |(args,): (TargetConnectArgs,)| {
+            let (sender, receiver) = mpsc::unbounded();
+            let _ = sender.unbounded_send(Ok(Body::json(&args.origin)));
+            let sink = futures::sink::unfold(
+                Some(sender),
+                |sender: Option<mpsc::UnboundedSender<_>>, message| async move {
+                    match (sender, message) {
+                        (Some(sender), StreamMessage::Data(body)) => {
+                            let _ = sender.unbounded_send(Ok(body));
+                            Ok::<_, SinkClosed>(Some(sender))
+                        }
+                        _ => Ok(None),
+                    }
+                },
+            );
+            (receiver, sink)
+        });
+        let mut service = Service::new();
+        service.add_service("tunnel", tunnel);
+        let client = Endpoint::from_io(client_io, service);
+        let attendance = room.attend(id, server.client().sender()).ok();
+        Peer {
+            id,
+            client,
+            server,
+            attendance,
+        }
+    }
+
+    fn tunnel_connect() -> Vec<String> {
+        vec!["tunnel".to_string(), "connect".to_string()]
+    }
+
+    #[async_std::test]
+    async fn tunnel() {
+        let room = Room::new("room");
+        let portal = FeedId(KeyPair::gen().public);
+        let mut alice = connect(&room);
+        let bob = connect(&room);
+
+        let args = serde_json::json!({ "portal": portal, "target": bob.id });
+        let (mut source, mut sink) = alice
+            .client
+            .client()
+            .start_duplex(tunnel_connect(), vec![args])
+            .await
+            .unwrap();
+        let origin = source.next().await.unwrap().unwrap();
+        assert_eq!(origin.decode_json::<FeedId>().unwrap(), alice.id);
+        for value in 1..=3u32 {
+            sink.send(Body::json(&value)).await.unwrap();
+            let echoed = source.next().await.unwrap().unwrap();
+            assert_eq!(echoed.decode_json::<u32>().unwrap(), value);
+        }
+        sink.close().await.unwrap();
+        assert!(source.next().await.is_none());
+
+        let missing = FeedId(KeyPair::gen().public);
+        let args = serde_json::json!({ "portal": portal, "target": missing });
+        let (mut source, _sink) = alice
+            .client
+            .client()
+            .start_duplex(tunnel_connect(), vec![args])
+            .await
+            .unwrap();
+        let error = source.next().await.unwrap().unwrap_err();
+        assert!(error.message.contains("not attending"), "{:?}", error);
+    }
+
+    #[async_std::test]
+    async fn membership() {
+        let alice = FeedId(KeyPair::gen().public);
+        let room = Room::with_members("room", vec![alice]);
+        let mut alice = connect_as(&room, alice);
+        let mut mallory = connect(&room);
+        assert!(mallory.attendance.is_none());
+        assert_eq!(room.attendants(), vec![alice.id]);
+
+        let metadata = mallory
+            .client
+            .client()
+            .send_async(vec!["room".to_string(), "metadata".to_string()], vec![])
+            .await
+            .unwrap();
+        let expected = RoomMetadata {
+            name: "room".to_string(),
+            membership: false,
+            features: vec!["tunnel".to_string(), "room1".to_string()],
+        };
+        assert_eq!(
+            metadata,
+            Response::Json(serde_json::to_vec(&expected).unwrap())
+        );
+
+        let args = serde_json::json!({ "portal": alice.id, "target": alice.id });
+        let (mut source, _sink) = mallory
+            .client
+            .client()
+            .start_duplex(tunnel_connect(), vec![args])
+            .await
+            .unwrap();
+        let error = source.next().await.unwrap().unwrap_err();
+        assert!(error.message.contains("Only members"), "{:?}", error);
+
+        let mut attendants = alice
+            .client
+            .client()
+            .start_source(vec!["room".to_string(), "attendants".to_string()], vec![])
+            .await
+            .unwrap()
+            .map(|event| event.unwrap().decode_json::<AttendantsEvent>().unwrap());
+        assert_eq!(
+            attendants.next().await,
+            Some(AttendantsEvent::State {
+                ids: vec![alice.id.to_string()]
+            })
+        );
+        room.add_member(mallory.id);
+        let mallory_attendance = room.attend(mallory.id, mallory.server.client().sender());
+        assert_eq!(
+            attendants.next().await,
+            Some(AttendantsEvent::Joined {
+                id: mallory.id.to_string()
+            })
+        );
+        room.remove_member(&mallory.id);
+        assert_eq!(
+            attendants.next().await,
+            Some(AttendantsEvent::Left {
+                id: mallory.id.to_string()
+            })
+        );
+        // The attendance was already ended by removing the member.
+        drop(mallory_attendance);
+        assert_eq!(room.attendants(), vec![alice.id]);
+    }
+}
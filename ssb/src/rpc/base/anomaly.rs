@@ -0,0 +1,202 @@
+//! Diagnostics for responses that do not match a request and for data that violates the
+//! protocol.
+use futures::prelude::*;
+use std::sync::{Arc, Mutex};
+
+use super::packet::{Response, MAX_REJECTED_BYTES};
+use super::packet_stream::NextPacketError;
+
+/// How a [Client][super::Client] handles a response it cannot match to a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownResponsePolicy {
+    /// Drop the response.
+    Ignore,
+    /// Drop the response and log a warning.
+    #[default]
+    Log,
+    /// Stop processing responses. [Client::join][super::Client::join] and
+    /// [Endpoint::join][super::Endpoint::join] return a [ProtocolError].
+    FailConnection,
+}
+
+/// Unexpected response from the peer. Reported by
+/// [Client::anomalies][super::Client::anomalies] regardless of the [UnknownResponsePolicy].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolAnomaly {
+    /// Response to an `async` or `sync` request that is not pending. The request may have been
+    /// answered already.
+    UnknownRequest(Response),
+    /// Stream message for a stream that is not open.
+    UnknownStream(Response),
+    /// Data on a flow controlled stream after the peer used up its credit. Always fails the
+    /// connection regardless of the [UnknownResponsePolicy].
+    CreditExceeded(Response),
+}
+
+impl ProtocolAnomaly {
+    pub fn response(&self) -> &Response {
+        match self {
+            Self::UnknownRequest(response)
+            | Self::UnknownStream(response)
+            | Self::CreditExceeded(response) => response,
+        }
+    }
+}
+
+impl std::fmt::Display for ProtocolAnomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownRequest(response) => {
+                write!(
+                    f,
+                    "Response for unknown request {}",
+                    response_number(response)
+                )
+            }
+            Self::UnknownStream(response) => {
+                write!(
+                    f,
+                    "Response for unknown stream {}",
+                    response_number(response)
+                )
+            }
+            Self::CreditExceeded(response) => {
+                write!(
+                    f,
+                    "Data on stream {} exceeds the credit",
+                    response_number(response)
+                )
+            }
+        }
+    }
+}
+
+fn response_number(response: &Response) -> u32 {
+    match response {
+        Response::AsyncOk { number, .. }
+        | Response::AsyncErr { number, .. }
+        | Response::Stream { number, .. } => *number,
+    }
+}
+
+/// Error returned when a [ProtocolAnomaly] fails the connection.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Protocol violation by peer: {anomaly}")]
+pub struct ProtocolError {
+    pub anomaly: ProtocolAnomaly,
+}
+
+/// Policy and anomaly subscribers shared between a client and its response reader task.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Diagnostics {
+    inner: Arc<Mutex<DiagnosticsInner>>,
+}
+
+#[derive(Debug, Default)]
+struct DiagnosticsInner {
+    policy: UnknownResponsePolicy,
+    subscribers: Vec<futures::channel::mpsc::UnboundedSender<ProtocolAnomaly>>,
+}
+
+impl Diagnostics {
+    pub(super) fn set_policy(&self, policy: UnknownResponsePolicy) {
+        self.inner.lock().unwrap().policy = policy;
+    }
+
+    pub(super) fn subscribe(&self) -> impl Stream<Item = ProtocolAnomaly> {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        self.inner.lock().unwrap().subscribers.push(sender);
+        receiver
+    }
+
+    /// Notify subscribers and apply the policy. Returns an error if the connection should fail.
+    pub(super) fn report(&self, anomaly: ProtocolAnomaly) -> Result<(), ProtocolError> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .subscribers
+            .retain(|subscriber| subscriber.unbounded_send(anomaly.clone()).is_ok());
+        match inner.policy {
+            UnknownResponsePolicy::Ignore => Ok(()),
+            UnknownResponsePolicy::Log => {
+                tracing::warn!(response = ?anomaly.response(), "{}", anomaly);
+                Ok(())
+            }
+            UnknownResponsePolicy::FailConnection => Err(ProtocolError { anomaly }),
+        }
+    }
+}
+
+/// Data from the peer that cannot be decoded.
+///
+/// The endpoint stops reading from the connection, logs the violation once with the peer, the
+/// direction of the connection and the offending bytes, and reports it to
+/// [EndpointMetrics::protocol_violation][super::EndpointMetrics::protocol_violation].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolViolation {
+    pub kind: ViolationKind,
+    /// Description of the error including its causes
+    pub message: String,
+    /// Start of the offending packet, at most [MAX_REJECTED_BYTES] bytes. Empty if the
+    /// connection ended in the middle of a packet.
+    pub bytes: Vec<u8>,
+}
+
+/// What is wrong with the data of a [ProtocolViolation].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// The packet header cannot be parsed.
+    InvalidHeader,
+    /// The body is longer than [EndpointConfig::max_body_len][super::EndpointConfig::max_body_len].
+    OversizedPacket,
+    /// The body cannot be decompressed or decoded.
+    InvalidBody,
+    /// The connection ended in the middle of a packet.
+    Truncated,
+}
+
+impl ProtocolViolation {
+    /// Violation for an error returned while reading packets. `rejected` is the start of the
+    /// offending packet. Returns `None` for errors that are not caused by the data, like IO errors
+    /// and idle timeouts.
+    pub(super) fn from_error(error: &NextPacketError, rejected: &[u8]) -> Option<Self> {
+        let kind = match error {
+            NextPacketError::InvalidHeader(_) => ViolationKind::InvalidHeader,
+            NextPacketError::BodyTooLarge { .. } => ViolationKind::OversizedPacket,
+            NextPacketError::PacketParse(_) => ViolationKind::InvalidBody,
+            NextPacketError::UnexpectedEndOfStream => ViolationKind::Truncated,
+            NextPacketError::Source(_) | NextPacketError::IdleTimeout(_) => return None,
+        };
+        let bytes = &rejected[..rejected.len().min(MAX_REJECTED_BYTES)];
+        Some(Self {
+            kind,
+            message: error_chain(error),
+            bytes: bytes.to_vec(),
+        })
+    }
+
+    /// The offending bytes as space separated hex pairs, for example `01 ff 00`.
+    pub fn hexdump(&self) -> String {
+        self.bytes
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl std::fmt::Display for ProtocolViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.message)
+    }
+}
+
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
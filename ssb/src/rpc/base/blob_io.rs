@@ -0,0 +1,323 @@
+//! Byte pipes over streams whose messages are [Body::Blob] chunks, for example `blobs.get` and
+//! `blobs.add`.
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::client::{BoxStreamSource, StreamSink};
+use super::packet::Body;
+use super::Error;
+
+/// Largest chunk that [BlobWriter] sends in one message. Larger writes are split.
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+/// [AsyncRead] that concatenates the [Body::Blob] chunks of a source.
+///
+/// Reading ends when the peer ends the stream. An error from the peer or a body that is not a
+/// blob fails the read.
+///
+/// ```no_run
+/// # use futures::prelude::*;
+/// # use ssb::rpc::base::{BlobReader, Client};
+/// # async fn example(client: &mut Client, args: serde_json::Value) -> anyhow::Result<()> {
+/// let source = client
+///     .start_source(vec!["blobs".to_string(), "get".to_string()], vec![args])
+///     .await?;
+/// let mut data = Vec::new();
+/// BlobReader::new(source).read_to_end(&mut data).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct BlobReader {
+    source: BoxStreamSource,
+    /// Chunk that has not been read completely
+    chunk: Vec<u8>,
+    /// Number of bytes of `chunk` that have been read
+    position: usize,
+}
+
+impl BlobReader {
+    pub fn new(source: BoxStreamSource) -> Self {
+        Self {
+            source,
+            chunk: Vec::new(),
+            position: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> BoxStreamSource {
+        self.source
+    }
+}
+
+impl std::fmt::Debug for BlobReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlobReader")
+            .field("buffered", &(self.chunk.len() - self.position))
+            .finish()
+    }
+}
+
+impl AsyncRead for BlobReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        while self.position == self.chunk.len() {
+            match futures::ready!(self.source.poll_next_unpin(cx)) {
+                None => return Poll::Ready(Ok(0)),
+                Some(Ok(Body::Blob(chunk))) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                Some(Ok(body)) => {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Expected blob chunk, got {:?}", body),
+                    )))
+                }
+                Some(Err(Error { name, message })) => {
+                    return Poll::Ready(Err(std::io::Error::other(format!(
+                        "{}: {}",
+                        name, message
+                    ))))
+                }
+            }
+        }
+        let len = std::cmp::min(buf.len(), self.chunk.len() - self.position);
+        let position = self.position;
+        buf[..len].copy_from_slice(&self.chunk[position..position + len]);
+        self.position += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+/// [AsyncWrite] that sends the written bytes as [Body::Blob] chunks. Created by
+/// [StreamSink::into_async_write].
+///
+/// Writes return as soon as the chunk is queued. Flushing waits until all chunks have been
+/// written to the connection. Closing the writer ends the stream. Dropping it without closing
+/// leaves the stream open, like dropping a [StreamSink].
+pub struct BlobWriter {
+    state: WriterState,
+}
+
+type Operation = BoxFuture<'static, (Option<StreamSink>, anyhow::Result<()>)>;
+
+enum WriterState {
+    Idle(StreamSink),
+    Sending(Operation),
+    Flushing(Operation),
+    Closing(Operation),
+    Closed,
+}
+
+impl BlobWriter {
+    pub(super) fn new(sink: StreamSink) -> Self {
+        Self {
+            state: WriterState::Idle(sink),
+        }
+    }
+
+    /// Wait until the pending operation is done.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let operation = match &mut self.state {
+            WriterState::Sending(operation)
+            | WriterState::Flushing(operation)
+            | WriterState::Closing(operation) => operation,
+            WriterState::Idle(_) | WriterState::Closed => return Poll::Ready(Ok(())),
+        };
+        let (sink, result) = futures::ready!(operation.poll_unpin(cx));
+        self.state = match sink {
+            Some(sink) => WriterState::Idle(sink),
+            None => WriterState::Closed,
+        };
+        Poll::Ready(result.map_err(std::io::Error::other))
+    }
+
+    /// Start an operation with the sink of an idle writer.
+    fn start<F, Fut>(
+        &mut self,
+        state: fn(Operation) -> WriterState,
+        operation: F,
+    ) -> std::io::Result<()>
+    where
+        F: FnOnce(StreamSink) -> Fut,
+        Fut: Future<Output = (Option<StreamSink>, anyhow::Result<()>)> + Send + 'static,
+    {
+        match std::mem::replace(&mut self.state, WriterState::Closed) {
+            WriterState::Idle(sink) => {
+                self.state = state(operation(sink).boxed());
+                Ok(())
+            }
+            WriterState::Closed => Err(std::io::ErrorKind::NotConnected.into()),
+            _ => unreachable!("writer is busy"),
+        }
+    }
+}
+
+impl std::fmt::Debug for BlobWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = match &self.state {
+            WriterState::Idle(_) => "idle",
+            WriterState::Sending(_) => "sending",
+            WriterState::Flushing(_) => "flushing",
+            WriterState::Closing(_) => "closing",
+            WriterState::Closed => "closed",
+        };
+        f.debug_struct("BlobWriter").field("state", &state).finish()
+    }
+}
+
+impl AsyncWrite for BlobWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        futures::ready!(self.poll_pending(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let chunk = buf[..std::cmp::min(buf.len(), MAX_CHUNK_SIZE)].to_vec();
+        let len = chunk.len();
+        self.start(WriterState::Sending, |mut sink| async move {
+            let result = sink.send(Body::Blob(chunk)).await;
+            (Some(sink), result)
+        })?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if !matches!(self.state, WriterState::Flushing(_)) {
+            futures::ready!(self.poll_pending(cx))?;
+            if let WriterState::Closed = self.state {
+                return Poll::Ready(Ok(()));
+            }
+            self.start(WriterState::Flushing, |mut sink| async move {
+                let result = sink.flush().await;
+                (Some(sink), result)
+            })?;
+        }
+        self.poll_pending(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        if !matches!(self.state, WriterState::Closing(_)) {
+            futures::ready!(self.poll_pending(cx))?;
+            if let WriterState::Closed = self.state {
+                return Poll::Ready(Ok(()));
+            }
+            self.start(WriterState::Closing, |sink| async move {
+                let mut flush = sink.dup();
+                let result = match sink.close().await {
+                    Ok(()) => flush.flush().await,
+                    Err(error) => Err(error),
+                };
+                (None, result)
+            })?;
+        }
+        self.poll_pending(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::base::{Endpoint, Service, SinkError, StreamMessage};
+    use futures::channel::mpsc;
+
+    fn endpoints(service: Service) -> (Endpoint, Endpoint) {
+        let (sender_a, receiver_a) = mpsc::channel(10);
+        let (sender_b, receiver_b) = mpsc::channel(10);
+        let client = Endpoint::new_client(sender_a, receiver_b.map(Ok::<_, std::io::Error>));
+        let server = Endpoint::new(sender_b, receiver_a.map(Ok::<_, std::io::Error>), service);
+        (client, server)
+    }
+
+    #[async_std::test]
+    async fn read() {
+        let mut service = Service::new();
+        service.add_source("get", |_: Vec<()>| {
+            stream::iter(vec![
+                Ok(Body::Blob(b"hello".to_vec())),
+                Ok(Body::Blob(vec![])),
+                Ok(Body::Blob(b" world".to_vec())),
+            ])
+        });
+        service.add_source("fail", |_: Vec<()>| {
+            stream::iter(vec![
+                Ok(Body::Blob(b"hello".to_vec())),
+                Err(Error::new("NotFound", "gone")),
+            ])
+        });
+        let (mut client, _server) = endpoints(service);
+
+        let source = client
+            .client()
+            .start_source(vec!["get".to_string()], vec![])
+            .await
+            .unwrap();
+        let mut reader = BlobReader::new(source);
+        let mut start = [0u8; 3];
+        reader.read_exact(&mut start).await.unwrap();
+        assert_eq!(&start, b"hel");
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"lo world");
+
+        let source = client
+            .client()
+            .start_source(vec!["fail".to_string()], vec![])
+            .await
+            .unwrap();
+        let mut data = Vec::new();
+        let error = BlobReader::new(source)
+            .read_to_end(&mut data)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "NotFound: gone");
+        assert_eq!(data, b"hello");
+    }
+
+    #[async_std::test]
+    async fn write() {
+        let (chunk_sender, chunks) = mpsc::unbounded::<StreamMessage>();
+        let chunk_sender = std::sync::Mutex::new(Some(chunk_sender));
+        let mut service = Service::new();
+        service.add_sink("add", move |_: Vec<()>| {
+            let sender = chunk_sender.lock().unwrap().take().unwrap();
+            sender.sink_map_err(|_| SinkError::Done)
+        });
+        let (mut client, _server) = endpoints(service);
+
+        let (_response, sink) = client
+            .client()
+            .start_sink(vec!["add".to_string()], vec![])
+            .await
+            .unwrap();
+        let data = (0..MAX_CHUNK_SIZE * 2 + 10)
+            .map(|index| index as u8)
+            .collect::<Vec<_>>();
+        let mut writer = sink.into_async_write();
+        writer.write_all(&data).await.unwrap();
+        writer.close().await.unwrap();
+        writer.close().await.unwrap();
+        assert!(writer.write(b"more").await.is_err());
+
+        let messages = chunks.collect::<Vec<_>>().await;
+        assert_eq!(messages.last(), Some(&StreamMessage::End));
+        let chunks = messages[..messages.len() - 1]
+            .iter()
+            .map(|message| match message {
+                StreamMessage::Data(Body::Blob(chunk)) => chunk.clone(),
+                message => panic!("Unexpected message {:?}", message),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= MAX_CHUNK_SIZE));
+        assert_eq!(chunks.concat(), data);
+    }
+}
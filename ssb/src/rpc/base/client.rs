@@ -1,33 +1,93 @@
 use chashmap::CHashMap;
 use futures::prelude::*;
+use std::collections::HashSet;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use super::anomaly::{Diagnostics, ProtocolAnomaly, ProtocolError, UnknownResponsePolicy};
+use super::blob_io::BlobWriter;
 use super::error::Error;
-use super::packet::{Body, Request, Response};
+use super::flow_control::{FlowControl, ReceiveWindow, SendCredit};
+use super::packet::{Body, Request, RequestType, Response};
+use super::request_number::{RequestNumbers, RequestNumbersExhausted};
+use super::stream_channel::StreamSender;
 use super::stream_message::StreamMessage;
 use super::stream_request::{StreamRequest, StreamRequestType};
+use super::writer::FlushHandle;
+use crate::utils::task::{spawn, JoinHandle};
 
 /// Client for an application agnostic RPC protocol described in the [Scuttlebutt
 /// Protocol Guide][ssb-prot].
 ///
 /// [ssb-prot]: https://ssbc.github.io/scuttlebutt-protocol-guide/#rpc-protocol
 pub struct Client {
-    request_sink: BoxRequestSink,
-    next_request_number: u32,
-    pending_async_requests: Arc<CHashMap<u32, futures::channel::oneshot::Sender<AsyncResponse>>>,
-    streams: Arc<CHashMap<u32, futures::channel::mpsc::UnboundedSender<Result<Body, Error>>>>,
-    packet_reader_handle: async_std::task::JoinHandle<()>,
+    sender: RequestSender,
+    packet_reader_handle: JoinHandle<Result<(), ProtocolError>>,
+    diagnostics: Diagnostics,
 }
 
 impl std::fmt::Debug for Client {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Client")
+            .field("sender", &self.sender)
+            .field("packet_reader_task", &self.packet_reader_handle)
+            .field("diagnostics", &self.diagnostics)
+            .finish()
+    }
+}
+
+/// Sends requests over the connection of a [Client]. Returned by [Client::sender].
+///
+/// In contrast to [Client] the sender can be cloned and used by several tasks at the same time.
+/// The responses are still read by the [Client], so requests fail with
+/// [AsyncRequestError::Closed] once its connection is closed.
+pub struct RequestSender {
+    request_sink: BoxRequestSink,
+    request_numbers: Arc<Mutex<RequestNumbers>>,
+    pending_async_requests: Arc<CHashMap<u32, futures::channel::oneshot::Sender<AsyncResponse>>>,
+    /// `async` requests that were abandoned after they were sent, for example because they timed
+    /// out. Their late responses are dropped.
+    abandoned_requests: Arc<Mutex<HashSet<u32>>>,
+    streams: Arc<CHashMap<u32, OpenStream>>,
+    /// Set when no more responses are received.
+    closed: Arc<AtomicBool>,
+    /// Capacity of the buffer for messages received on a stream. Unbounded if `None`.
+    stream_buffer: Option<usize>,
+    flow_control: FlowControl,
+    /// Set if the client belongs to an [Endpoint][super::Endpoint] that batches writes.
+    flush_handle: Option<FlushHandle>,
+}
+
+impl Clone for RequestSender {
+    fn clone(&self) -> Self {
+        Self {
+            request_sink: self.request_sink.dup(),
+            request_numbers: Arc::clone(&self.request_numbers),
+            pending_async_requests: Arc::clone(&self.pending_async_requests),
+            abandoned_requests: Arc::clone(&self.abandoned_requests),
+            streams: Arc::clone(&self.streams),
+            closed: Arc::clone(&self.closed),
+            stream_buffer: self.stream_buffer,
+            flow_control: self.flow_control.clone(),
+            flush_handle: self.flush_handle.clone(),
+        }
+    }
+}
+
+impl std::fmt::Debug for RequestSender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RequestSender")
             .field("sink", &"Pin<Box<dyn Sink>>")
-            .field("next_request_number", &self.next_request_number)
+            .field("request_numbers", &self.request_numbers)
             .field("pending_async_requests", &self.pending_async_requests)
+            .field("abandoned_requests", &self.abandoned_requests)
             .field("streams", &"Arc<CHashMap<_, _>>")
-            .field("packet_reader_task", &self.packet_reader_handle)
+            .field("closed", &self.closed)
+            .field("stream_buffer", &self.stream_buffer)
+            .field("flow_control", &self.flow_control)
+            .field("flush_handle", &self.flush_handle)
             .finish()
     }
 }
@@ -42,92 +102,257 @@ impl Client {
         RequestSink::Error: std::error::Error + Send + Sync + 'static,
         ResponseStream: Stream<Item = Response> + Send + Unpin + 'static,
     {
+        Self::with_options(
+            request_sink,
+            response_stream,
+            None,
+            FlowControl::default(),
+            None,
+        )
+    }
+
+    /// Create a client that buffers at most `stream_buffer` messages for every stream. If the
+    /// buffer of a stream is full no responses are processed until the stream is read. Streams
+    /// that are flow controlled by `flow_control` are not limited by the buffer.
+    ///
+    /// [Client::flush] uses `flush_handle` if it is given.
+    pub(super) fn with_options<RequestSink, ResponseStream>(
+        request_sink: RequestSink,
+        response_stream: ResponseStream,
+        stream_buffer: Option<usize>,
+        flow_control: FlowControl,
+        flush_handle: Option<FlushHandle>,
+    ) -> Self
+    where
+        RequestSink: Sink<Request> + Send + Clone + Unpin + 'static,
+        RequestSink::Error: std::error::Error + Send + Sync + 'static,
+        ResponseStream: Stream<Item = Response> + Send + Unpin + 'static,
+    {
+        let request_sink: BoxRequestSink = Box::pin(request_sink.sink_map_err(anyhow::Error::from));
+        let request_numbers = Arc::new(Mutex::new(RequestNumbers::new()));
+        let request_numbers2 = Arc::clone(&request_numbers);
+        let request_sink2 = request_sink.dup();
         let pending_async_requests = Arc::new(CHashMap::new());
         let streams = Arc::new(CHashMap::new());
         let streams2 = Arc::clone(&streams);
         let pending_async_requests2 = Arc::clone(&pending_async_requests);
-        let packet_reader_task = async_std::task::spawn(async move {
-            Self::consume_responses(response_stream, &pending_async_requests2, &streams2).await
+        let abandoned_requests = Arc::new(Mutex::new(HashSet::new()));
+        let abandoned_requests2 = Arc::clone(&abandoned_requests);
+        let diagnostics = Diagnostics::default();
+        let diagnostics2 = diagnostics.clone();
+        let closed = Arc::new(AtomicBool::new(false));
+        let closed2 = Arc::clone(&closed);
+        let flow_control2 = flow_control.clone();
+        let packet_reader_task = spawn("rpc client packet_reader", async move {
+            let result = Self::consume_responses(
+                response_stream,
+                request_sink2,
+                &request_numbers2,
+                &pending_async_requests2,
+                &abandoned_requests2,
+                &streams2,
+                &diagnostics2,
+            )
+            .await;
+            // Fail pending requests and end open streams. No more responses will arrive.
+            closed2.store(true, Ordering::SeqCst);
+            pending_async_requests2.clear();
+            streams2.clear();
+            flow_control2.close();
+            result
         });
         Self {
-            request_sink: Box::pin(request_sink.sink_map_err(anyhow::Error::from)),
-            next_request_number: 1,
-            pending_async_requests,
-            streams,
+            sender: RequestSender {
+                request_sink,
+                request_numbers,
+                pending_async_requests,
+                abandoned_requests,
+                streams,
+                closed,
+                stream_buffer,
+                flow_control,
+                flush_handle,
+            },
             packet_reader_handle: packet_reader_task,
+            diagnostics,
+        }
+    }
+
+    /// Returns a handle that sends requests over the connection of this client. See
+    /// [RequestSender].
+    pub fn sender(&self) -> RequestSender {
+        self.sender.clone()
+    }
+
+    /// Write all requests that have been sent so far to the connection.
+    ///
+    /// An [Endpoint][super::Endpoint] with a [write_delay][super::EndpointConfig::write_delay]
+    /// waits for more packets before it writes to the connection. Call this after sending
+    /// latency-sensitive stream messages. Async requests are delayed by at most the write delay.
+    pub async fn flush(&mut self) -> anyhow::Result<()> {
+        self.sender.flush().await
+    }
+
+    /// Send the end message for all streams that we have not ended yet.
+    ///
+    /// Used to close the connection gracefully. The sources of the streams still receive the
+    /// messages the peer sends until it ends the streams.
+    pub async fn end_streams(&mut self) -> anyhow::Result<()> {
+        let sender = &mut self.sender;
+        let numbers = sender.request_numbers.lock().unwrap().local_open();
+        for number in numbers {
+            // Numbers that are not used by streams belong to pending async requests.
+            if sender.pending_async_requests.contains_key(&number) {
+                continue;
+            }
+            if let Some(mut stream) = sender.streams.get_mut(&number) {
+                // We end the stream now and must not end it again when the peer ends it.
+                stream.end_with_remote = false;
+            }
+            sender
+                .request_sink
+                .send(StreamMessage::End.into_request(number))
+                .await?;
+            sender.request_numbers.lock().unwrap().end_local(number);
         }
+        Ok(())
     }
 
-    pub async fn join(self) {
+    /// Wait until the connection is closed.
+    ///
+    /// Returns an error if a response violated the protocol and the [UnknownResponsePolicy] is
+    /// [UnknownResponsePolicy::FailConnection].
+    pub async fn join(self) -> Result<(), ProtocolError> {
         self.packet_reader_handle.await
     }
 
-    #[tracing::instrument(skip(response_stream, pending_async_requests, streams))]
+    /// Set how responses that do not match a pending request or an open stream are handled.
+    /// The default is [UnknownResponsePolicy::Log].
+    pub fn set_unknown_response_policy(&self, policy: UnknownResponsePolicy) {
+        self.diagnostics.set_policy(policy);
+    }
+
+    /// Returns a stream of all responses that do not match a pending request or an open stream
+    /// from now on.
+    pub fn anomalies(&self) -> impl Stream<Item = ProtocolAnomaly> {
+        self.diagnostics.subscribe()
+    }
+
+    #[tracing::instrument(skip(
+        response_stream,
+        request_sink,
+        request_numbers,
+        pending_async_requests,
+        abandoned_requests,
+        streams,
+        diagnostics
+    ))]
     async fn consume_responses<Stream_>(
         response_stream: Stream_,
+        mut request_sink: BoxRequestSink,
+        request_numbers: &Mutex<RequestNumbers>,
         pending_async_requests: &CHashMap<u32, futures::channel::oneshot::Sender<AsyncResponse>>,
-        streams: &CHashMap<u32, futures::channel::mpsc::UnboundedSender<Result<Body, Error>>>,
-    ) -> ()
+        abandoned_requests: &Mutex<HashSet<u32>>,
+        streams: &CHashMap<u32, OpenStream>,
+        diagnostics: &Diagnostics,
+    ) -> Result<(), ProtocolError>
     where
         Stream_: Stream<Item = Response> + Send + Unpin + 'static,
     {
         let mut response_stream = response_stream;
         while let Some(response) = response_stream.next().await {
             tracing::trace!(?response, "received response");
-            match response {
+            let anomaly = match response {
                 Response::AsyncOk { number, body } => {
-                    pending_async_requests.alter(number, |opt_respond| {
-                        if let Some(respond) = opt_respond {
-                            // TODO handle error
-                            respond.send(AsyncResponse::from(body)).unwrap();
-                        } else {
-                            tracing::error!(number, ?body, "no matching response");
+                    match pending_async_requests.remove(&number) {
+                        Some(respond) => {
+                            request_numbers.lock().unwrap().release(number);
+                            // We don’t care if the caller stopped waiting for the response.
+                            let _ = respond.send(AsyncResponse::from(body));
+                            None
                         }
-                        None
-                    })
+                        None if abandoned_requests.lock().unwrap().remove(&number) => None,
+                        None => Some(ProtocolAnomaly::UnknownRequest(Response::AsyncOk {
+                            number,
+                            body,
+                        })),
+                    }
                 }
                 Response::AsyncErr {
                     number,
                     name,
                     message,
-                } => {
-                    pending_async_requests.alter(number, |opt_respond| {
-                        if let Some(respond) = opt_respond {
-                            // TODO handle error
-                            respond
-                                .send(AsyncResponse::Error(Error { name, message }))
-                                .unwrap();
-                        } else {
-                            todo!("no response listener for error")
-                        }
+                } => match pending_async_requests.remove(&number) {
+                    Some(respond) => {
+                        request_numbers.lock().unwrap().release(number);
+                        let _ = respond.send(AsyncResponse::Error(Error { name, message }));
                         None
-                    })
-                }
-                Response::Stream { number, message } => match message {
-                    StreamMessage::Data(body) => {
-                        if let Some(stream) = streams.get_mut(&number) {
-                            // We don’t care if the client user drops the source.
-                            let _ = stream.unbounded_send(Ok(body));
-                        } else {
-                            tracing::warn!(stream_id = ?number, "received response for unknown stream");
-                        }
                     }
-                    StreamMessage::Error(error) => {
-                        if let Some(stream) = streams.remove(&number) {
+                    None if abandoned_requests.lock().unwrap().remove(&number) => None,
+                    None => Some(ProtocolAnomaly::UnknownRequest(Response::AsyncErr {
+                        number,
+                        name,
+                        message,
+                    })),
+                },
+                Response::Stream { number, message } => {
+                    let stream = match message {
+                        StreamMessage::Data(_) => streams.get(&number).map(|stream| stream.clone()),
+                        StreamMessage::Error(_) | StreamMessage::End => streams.remove(&number),
+                    };
+                    match (stream, message) {
+                        (Some(mut stream), message) => {
+                            let is_end = message.is_end();
                             // We don’t care if the client user drops the source.
-                            let _ = stream.unbounded_send(Err(error));
-                        } else {
-                            tracing::warn!(stream_id = ?number, "received response for unknown stream");
+                            match message {
+                                StreamMessage::Data(body) => {
+                                    let within_window =
+                                        stream.window.as_ref().is_none_or(ReceiveWindow::receive);
+                                    if !within_window {
+                                        return Err(ProtocolError {
+                                            anomaly: ProtocolAnomaly::CreditExceeded(
+                                                StreamMessage::Data(body).into_response(number),
+                                            ),
+                                        });
+                                    }
+                                    let _ = stream.sender.send(Ok(body)).await;
+                                }
+                                StreamMessage::Error(error) => {
+                                    let _ = stream.sender.send(Err(error)).await;
+                                }
+                                StreamMessage::End => {}
+                            }
+                            if is_end {
+                                let end_local = {
+                                    let mut numbers = request_numbers.lock().unwrap();
+                                    numbers.end_remote(number);
+                                    stream.end_with_remote && numbers.is_local_open(number)
+                                };
+                                // Sources may have been ended early through the sink returned by
+                                // `start_stream`.
+                                if end_local {
+                                    // The peer waits for our end message before it releases
+                                    // the number.
+                                    let _ = request_sink
+                                        .send(StreamMessage::End.into_request(number))
+                                        .await;
+                                    request_numbers.lock().unwrap().end_local(number);
+                                }
+                            }
+                            None
                         }
+                        (None, message) => Some(ProtocolAnomaly::UnknownStream(Response::Stream {
+                            number,
+                            message,
+                        })),
                     }
-                    StreamMessage::End => {
-                        if streams.remove(&number).is_none() {
-                            tracing::warn!(stream_id = ?number, "received response for unknown stream");
-                        }
-                    }
-                },
+                }
+            };
+            if let Some(anomaly) = anomaly {
+                diagnostics.report(anomaly)?;
             }
         }
+        Ok(())
     }
 
     /// Send a `async` type request to the server and return the response.
@@ -136,23 +361,205 @@ impl Client {
         method: Vec<String>,
         args: Vec<serde_json::Value>,
     ) -> Result<AsyncResponse, AsyncRequestError> {
-        let request_number = self.next_request_number;
-        self.next_request_number += 1;
+        self.sender.send_async(method, args).await
+    }
+
+    /// Like [Client::send_async] but fails with [AsyncRequestError::Timeout] if the response
+    /// does not arrive within `timeout`. See [RequestSender::send_async_with].
+    pub async fn send_async_with(
+        &mut self,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+        timeout: Duration,
+    ) -> Result<AsyncResponse, AsyncRequestError> {
+        self.sender.send_async_with(method, args, timeout).await
+    }
+
+    /// Send a `sync` type request to the server and return the response.
+    ///
+    /// Sync requests only differ from `async` requests in the request type that is sent to the
+    /// server. The server answers both with a single response.
+    pub async fn send_sync(
+        &mut self,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+    ) -> Result<AsyncResponse, AsyncRequestError> {
+        self.sender.send_sync(method, args).await
+    }
+
+    /// Send a request to the server to start a duplex stream.
+    pub async fn start_duplex(
+        &mut self,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+    ) -> anyhow::Result<(BoxStreamSource, StreamSink)> {
+        self.sender.start_duplex(method, args).await
+    }
+
+    /// Like [Client::start_duplex] but fails the source if the server does not send a message
+    /// within `inactivity_timeout`. See [RequestSender::start_source_with].
+    pub async fn start_duplex_with(
+        &mut self,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+        inactivity_timeout: Duration,
+    ) -> anyhow::Result<(BoxStreamSource, StreamSink)> {
+        self.sender
+            .start_duplex_with(method, args, inactivity_timeout)
+            .await
+    }
+
+    /// Send a request to the server to start a source stream and return the stream of
+    /// messages the server sends.
+    pub async fn start_source(
+        &mut self,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+    ) -> anyhow::Result<BoxStreamSource> {
+        self.sender.start_source(method, args).await
+    }
+
+    /// Like [Client::start_source] but fails the source if the server does not send a message
+    /// within `inactivity_timeout`. See [RequestSender::start_source_with].
+    pub async fn start_source_with(
+        &mut self,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+        inactivity_timeout: Duration,
+    ) -> anyhow::Result<BoxStreamSource> {
+        self.sender
+            .start_source_with(method, args, inactivity_timeout)
+            .await
+    }
+
+    /// Send a request to the server to start a sink stream.
+    ///
+    /// The returned source does not yield any data. It ends when the server ends the stream and
+    /// yields an error if the server fails the stream.
+    pub async fn start_sink(
+        &mut self,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+    ) -> anyhow::Result<(BoxStreamSource, StreamSink)> {
+        self.sender.start_sink(method, args).await
+    }
+}
+
+impl RequestSender {
+    /// Write all requests that have been sent so far to the connection. See [Client::flush].
+    pub async fn flush(&mut self) -> anyhow::Result<()> {
+        match &self.flush_handle {
+            Some(flush_handle) => flush_handle.flush().await,
+            None => self.request_sink.flush().await,
+        }
+    }
+
+    /// Send a `async` type request to the server and return the response.
+    pub async fn send_async(
+        &mut self,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+    ) -> Result<AsyncResponse, AsyncRequestError> {
+        self.send_request(RequestType::Async, method, args).await
+    }
+
+    /// Like [RequestSender::send_async] but fails with [AsyncRequestError::Timeout] if the
+    /// response does not arrive within `timeout`, including the time it takes to send the request.
+    ///
+    /// On timeout the request is forgotten and its number is released. A late response is
+    /// dropped without a [ProtocolAnomaly].
+    pub async fn send_async_with(
+        &mut self,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+        timeout: Duration,
+    ) -> Result<AsyncResponse, AsyncRequestError> {
+        let response = self.send_request(RequestType::Async, method, args);
+        futures::pin_mut!(response);
+        match future::select(response, futures_timer::Delay::new(timeout)).await {
+            future::Either::Left((response, _)) => response,
+            future::Either::Right(_) => Err(AsyncRequestError::Timeout(timeout)),
+        }
+    }
+
+    /// Send a `sync` type request to the server and return the response.
+    ///
+    /// Sync requests only differ from `async` requests in the request type that is sent to the
+    /// server. The server answers both with a single response.
+    pub async fn send_sync(
+        &mut self,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+    ) -> Result<AsyncResponse, AsyncRequestError> {
+        self.send_request(RequestType::Sync, method, args).await
+    }
+
+    /// Send the request and wait for the response. If the returned future is dropped before the
+    /// response arrives the request is abandoned, see [PendingRequest].
+    async fn send_request(
+        &mut self,
+        type_: RequestType,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+    ) -> Result<AsyncResponse, AsyncRequestError> {
+        let request_number = self.allocate_request_number()?;
+        let mut pending = PendingRequest {
+            number: request_number,
+            sent: false,
+            completed: false,
+            pending_async_requests: Arc::clone(&self.pending_async_requests),
+            abandoned_requests: Arc::clone(&self.abandoned_requests),
+            request_numbers: Arc::clone(&self.request_numbers),
+        };
 
         let request = Request::Async {
             number: request_number,
             method,
+            type_,
             args,
         };
         let (sender, receiver) = futures::channel::oneshot::channel();
         self.pending_async_requests.insert(request_number, sender);
-        self.request_sink
-            .send(request)
-            .await
-            .map_err(|error| AsyncRequestError::Send { error })?;
-        Ok(receiver
-            .await
-            .expect("Response channel dropped. Possible reuse of request number"))
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(AsyncRequestError::Closed);
+        }
+        // The request may be queued even if the send does not complete.
+        pending.sent = true;
+        if let Err(error) = self.request_sink.send(request).await {
+            pending.sent = false;
+            return Err(AsyncRequestError::Send { error });
+        }
+        let response = receiver.await.map_err(|_| AsyncRequestError::Closed);
+        // The response consumer has released the number, or the connection is closed.
+        pending.completed = true;
+        response
+    }
+
+    /// Allocate a request number for a new stream and register `stream` under it. The stream is
+    /// unregistered when the returned registration is dropped before it is committed.
+    fn register_stream(
+        &self,
+        stream: OpenStream,
+    ) -> Result<StreamRegistration, RequestNumbersExhausted> {
+        let number = self.allocate_request_number()?;
+        self.streams.insert(number, stream);
+        Ok(StreamRegistration {
+            number,
+            streams: Arc::clone(&self.streams),
+            request_numbers: Arc::clone(&self.request_numbers),
+            committed: false,
+        })
+    }
+
+    fn allocate_request_number(&self) -> Result<u32, RequestNumbersExhausted> {
+        let number = self.request_numbers.lock().unwrap().allocate()?;
+        // The number has wrapped around. A response for the abandoned request is now ambiguous.
+        self.abandoned_requests.lock().unwrap().remove(&number);
+        Ok(number)
+    }
+
+    fn release_request_number(&self, number: u32) {
+        self.request_numbers.lock().unwrap().release(number)
     }
 
     /// Send a request to the server to start a duplex stream.
@@ -165,40 +572,215 @@ impl Client {
             .await
     }
 
-    async fn start_stream(
+    /// Like [RequestSender::start_duplex] but fails the source if the server does not send a
+    /// message within `inactivity_timeout`. See [RequestSender::start_source_with].
+    pub async fn start_duplex_with(
         &mut self,
-        type_: StreamRequestType,
         method: Vec<String>,
         args: Vec<serde_json::Value>,
+        inactivity_timeout: Duration,
     ) -> anyhow::Result<(BoxStreamSource, StreamSink)> {
-        let request_number = self.next_request_number;
-        self.next_request_number += 1;
+        let (source, sink) = self
+            .start_stream(StreamRequestType::Duplex, method, args)
+            .await?;
+        let source = with_inactivity_timeout(source, inactivity_timeout, sink.dup());
+        Ok((source, sink))
+    }
 
-        self.request_sink
-            .send(
-                StreamRequest {
-                    name: method,
-                    type_,
-                    args,
-                }
-                .into_request(request_number),
-            )
+    /// Send a request to the server to start a source stream and return the stream of
+    /// messages the server sends.
+    pub async fn start_source(
+        &mut self,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+    ) -> anyhow::Result<BoxStreamSource> {
+        let (source, _sink) = self
+            .start_stream(StreamRequestType::Source, method, args)
             .await?;
+        Ok(source)
+    }
 
-        let (received_messages_sender, received_messages_receiver) =
-            futures::channel::mpsc::unbounded();
-        self.streams
-            .insert(request_number, received_messages_sender);
+    /// Like [RequestSender::start_source] but fails the source if the server does not send a
+    /// message within `inactivity_timeout`.
+    ///
+    /// On timeout the source yields a [TIMEOUT_ERROR_NAME] error and ends. We send the end
+    /// message for the stream to the server so that it stops the stream.
+    pub async fn start_source_with(
+        &mut self,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+        inactivity_timeout: Duration,
+    ) -> anyhow::Result<BoxStreamSource> {
+        let (source, sink) = self
+            .start_stream(StreamRequestType::Source, method, args)
+            .await?;
+        Ok(with_inactivity_timeout(source, inactivity_timeout, sink))
+    }
+
+    /// Send a request to the server to start a sink stream.
+    ///
+    /// The returned source does not yield any data. It ends when the server ends the stream and
+    /// yields an error if the server fails the stream.
+    pub async fn start_sink(
+        &mut self,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+    ) -> anyhow::Result<(BoxStreamSource, StreamSink)> {
+        self.start_stream(StreamRequestType::Sink, method, args)
+            .await
+    }
+
+    pub(super) async fn start_stream(
+        &mut self,
+        type_: StreamRequestType,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+    ) -> anyhow::Result<(BoxStreamSource, StreamSink)> {
+        // Flow controlled streams are limited by their window and must not block other streams.
+        let windows = self.flow_control.windows();
+        let stream_buffer = match windows {
+            Some(_) => None,
+            None => self.stream_buffer,
+        };
+        // Register the stream with the response consumer before the request leaves. Otherwise
+        // responses that arrive immediately would be dropped as unknown.
+        let (received_messages_sender, mut received_messages_receiver) =
+            super::stream_channel::channel(stream_buffer);
+        let registration = self.register_stream(OpenStream {
+            sender: received_messages_sender,
+            end_with_remote: type_ == StreamRequestType::Source,
+            window: None,
+        })?;
+        let flow_control = windows.and_then(|(window, peer_window)| {
+            let (credit, receive_window) = self
+                .flow_control
+                .open_stream(registration.number as i32, peer_window)?;
+            Some((window, credit, receive_window))
+        });
+        let credit = match flow_control {
+            Some((window, credit, receive_window)) => {
+                if let Some(mut stream) = self.streams.get_mut(&registration.number) {
+                    stream.window = Some(receive_window.clone());
+                }
+                received_messages_receiver = received_messages_receiver
+                    .inspect(move |item| {
+                        if item.is_ok() {
+                            receive_window.consume();
+                        }
+                    })
+                    .boxed();
+                Some((window, credit))
+            }
+            None => None,
+        };
+        // The consumer marks the client as closed before it ends all registered streams. If it
+        // has not done so yet, it will end this stream too.
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(AsyncRequestError::Closed.into());
+        }
+
+        let request = super::flow_control::stream_request(
+            StreamRequest {
+                name: method,
+                type_,
+                args,
+            },
+            registration.number,
+            credit.as_ref().map(|(window, _)| *window),
+        );
+        // Dropping the registration unregisters the stream if the request cannot be queued or
+        // the caller stops waiting before it is queued. Once the request is queued the peer may
+        // answer it, so the stream must stay registered.
+        self.request_sink.feed(request).await?;
+        let request_number = registration.commit();
+        if let Err(error) = self.request_sink.flush().await {
+            self.streams.remove(&request_number);
+            self.release_request_number(request_number);
+            return Err(error);
+        }
         let stream_sink = StreamSink {
             request_sink: self.request_sink.dup(),
+            flush_handle: self.flush_handle.clone(),
+            request_numbers: Arc::clone(&self.request_numbers),
             id: request_number,
+            ended: Arc::new(AtomicBool::new(false)),
+            credit: credit.map(|(_, credit)| credit),
         };
-        Ok((Box::pin(received_messages_receiver), stream_sink))
+        Ok((received_messages_receiver, stream_sink))
     }
 }
 
 pub type BoxStreamSource = futures::stream::BoxStream<'static, Result<Body, Error>>;
 
+/// `async` request that has been registered with the response consumer. Dropping it before it
+/// is completed forgets the request and releases its number. If the request may have been sent
+/// its late response is dropped.
+struct PendingRequest {
+    number: u32,
+    sent: bool,
+    /// The response arrived or the connection is closed. Nothing needs to be cleaned up.
+    completed: bool,
+    pending_async_requests: Arc<CHashMap<u32, futures::channel::oneshot::Sender<AsyncResponse>>>,
+    abandoned_requests: Arc<Mutex<HashSet<u32>>>,
+    request_numbers: Arc<Mutex<RequestNumbers>>,
+}
+
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        if self.completed {
+            return;
+        }
+        // The response may have arrived in the meantime. Then the number is already released.
+        if self.pending_async_requests.remove(&self.number).is_none() {
+            return;
+        }
+        if self.sent {
+            self.abandoned_requests.lock().unwrap().insert(self.number);
+        }
+        self.request_numbers.lock().unwrap().release(self.number);
+    }
+}
+
+/// Stream that is registered with the response consumer but whose request may not have been sent
+/// yet. Created by [RequestSender::register_stream].
+///
+/// Dropping the registration before [StreamRegistration::commit] removes the stream and releases
+/// its request number.
+struct StreamRegistration {
+    number: u32,
+    streams: Arc<CHashMap<u32, OpenStream>>,
+    request_numbers: Arc<Mutex<RequestNumbers>>,
+    committed: bool,
+}
+
+impl StreamRegistration {
+    /// Keep the stream after its request was sent and return the request number.
+    fn commit(mut self) -> u32 {
+        self.committed = true;
+        self.number
+    }
+}
+
+impl Drop for StreamRegistration {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.streams.remove(&self.number);
+            self.request_numbers.lock().unwrap().release(self.number);
+        }
+    }
+}
+
+/// Stream that receives messages from the peer.
+#[derive(Clone)]
+struct OpenStream {
+    sender: StreamSender<Result<Body, Error>>,
+    /// Send the end message as soon as the peer ends the stream. Used for `source` streams where
+    /// the user does not get a [StreamSink].
+    end_with_remote: bool,
+    /// Set if the stream is flow controlled.
+    window: Option<ReceiveWindow>,
+}
+
 type BoxRequestSink = Pin<Box<dyn ClonableRequestSink>>;
 
 trait ClonableRequestSink
@@ -218,6 +800,41 @@ where
     }
 }
 
+/// Name of the error that a source started with [RequestSender::start_source_with] or
+/// [RequestSender::start_duplex_with] yields when the server is inactive for too long.
+pub const TIMEOUT_ERROR_NAME: &str = "TimeoutError";
+
+/// Yield the items of `source` until no item arrives within `timeout`. Then end the stream
+/// through `sink`, yield a [TIMEOUT_ERROR_NAME] error and end.
+fn with_inactivity_timeout(
+    source: BoxStreamSource,
+    timeout: Duration,
+    sink: StreamSink,
+) -> BoxStreamSource {
+    futures::stream::unfold(Some((source, sink)), move |state| async move {
+        let (mut source, mut sink) = state?;
+        let next = match future::select(source.next(), futures_timer::Delay::new(timeout)).await {
+            future::Either::Left((next, _)) => Some(next),
+            future::Either::Right(_) => None,
+        };
+        match next {
+            Some(Some(item)) => Some((item, Some((source, sink)))),
+            Some(None) => None,
+            None => {
+                // We don’t care if the connection is already gone.
+                let _ = sink.send_end(StreamMessage::End).await;
+                let _ = sink.flush().await;
+                let error = Error::new(
+                    TIMEOUT_ERROR_NAME,
+                    format!("No message received within {:?}", timeout),
+                );
+                Some((Err(error), None))
+            }
+        }
+    })
+    .boxed()
+}
+
 /// Send messages for a specific stream to the peer.
 ///
 /// The sink must be explicitly closed by calling [StreamSink::close] or [StreamSink::error] to
@@ -225,7 +842,13 @@ where
 /// [StreamSink].
 pub struct StreamSink {
     request_sink: BoxRequestSink,
+    flush_handle: Option<FlushHandle>,
+    request_numbers: Arc<Mutex<RequestNumbers>>,
     id: u32,
+    /// Set once the end message was sent. Shared with the inactivity timeout of the source.
+    ended: Arc<AtomicBool>,
+    /// Set if the stream is flow controlled.
+    credit: Option<SendCredit>,
 }
 
 impl std::fmt::Debug for StreamSink {
@@ -241,22 +864,60 @@ impl StreamSink {
         self.send_message(StreamMessage::Data(data)).await
     }
 
+    /// Write the messages that have been sent so far to the connection. See [Client::flush].
+    pub async fn flush(&mut self) -> anyhow::Result<()> {
+        match &self.flush_handle {
+            Some(flush_handle) => flush_handle.flush().await,
+            None => self.request_sink.flush().await,
+        }
+    }
+
     pub async fn close(mut self) -> anyhow::Result<()> {
-        self.send_message(StreamMessage::End).await
+        self.send_end(StreamMessage::End).await
     }
 
     pub async fn error(mut self, error: Error) -> anyhow::Result<()> {
-        self.send_message(StreamMessage::Error(error)).await
+        self.send_end(StreamMessage::Error(error)).await
+    }
+
+    async fn send_end(&mut self, stream_message: StreamMessage) -> anyhow::Result<()> {
+        // The stream may have been ended by an inactivity timeout.
+        if self.ended.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let result = self.send_message(stream_message).await;
+        self.request_numbers.lock().unwrap().end_local(self.id);
+        result
+    }
+
+    /// Send the bytes written to the returned writer as [Body::Blob] chunks. Closing the writer
+    /// ends the stream. Use it for methods like `blobs.add` that take a byte stream.
+    pub fn into_async_write(self) -> BlobWriter {
+        BlobWriter::new(self)
+    }
+
+    pub(super) fn dup(&self) -> Self {
+        Self {
+            request_sink: self.request_sink.dup(),
+            flush_handle: self.flush_handle.clone(),
+            request_numbers: Arc::clone(&self.request_numbers),
+            id: self.id,
+            ended: Arc::clone(&self.ended),
+            credit: self.credit.clone(),
+        }
     }
 
     async fn send_message(&mut self, stream_message: StreamMessage) -> anyhow::Result<()> {
+        if let (StreamMessage::Data(_), Some(credit)) = (&stream_message, &self.credit) {
+            credit.acquire().await;
+        }
         self.request_sink
             .send(stream_message.into_request(self.id))
             .await
     }
 }
 
-/// Response returned by [Client::send_async].
+/// Response returned by [Client::send_async] and [Client::send_sync].
 #[derive(Clone, PartialEq, Eq)]
 pub enum AsyncResponse {
     Json(Vec<u8>),
@@ -265,6 +926,27 @@ pub enum AsyncResponse {
     Error(Error),
 }
 
+impl AsyncResponse {
+    /// Returns a reader for the data of a [AsyncResponse::Json] response.
+    ///
+    /// Use it to pass the data to [serde_json::from_reader] or to copy it to a writer without
+    /// decoding it first. Returns `None` for other responses.
+    ///
+    /// ```
+    /// # use ssb::rpc::base::AsyncResponse;
+    /// let response = AsyncResponse::Json(b"[1,2]".to_vec());
+    /// let reader = response.json_reader().unwrap();
+    /// let value = serde_json::from_reader::<_, Vec<u32>>(reader).unwrap();
+    /// assert_eq!(value, [1, 2]);
+    /// ```
+    pub fn json_reader(&self) -> Option<impl std::io::Read + '_> {
+        match self {
+            Self::Json(data) => Some(data.as_slice()),
+            _ => None,
+        }
+    }
+}
+
 impl std::fmt::Debug for AsyncResponse {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -294,7 +976,7 @@ impl From<Body> for AsyncResponse {
 }
 
 #[derive(Debug, thiserror::Error)]
-/// Error returned by [Client::send_async].
+/// Error returned by [Client::send_async] and [Client::send_sync].
 pub enum AsyncRequestError {
     /// Failed to send the request to the server
     #[error("Failed to send request")]
@@ -303,4 +985,277 @@ pub enum AsyncRequestError {
         #[source]
         error: anyhow::Error,
     },
+    /// The connection was closed before the response arrived
+    #[error("Connection closed before the response arrived")]
+    Closed,
+    /// Too many requests are in progress
+    #[error(transparent)]
+    Exhausted(#[from] RequestNumbersExhausted),
+    /// The response did not arrive in time. Returned by [Client::send_async_with].
+    #[error("No response within {0:?}")]
+    Timeout(Duration),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn client() -> (
+        Client,
+        futures::channel::mpsc::Receiver<Request>,
+        futures::channel::mpsc::Sender<Response>,
+    ) {
+        let (request_sender, request_receiver) = futures::channel::mpsc::channel(10);
+        let (response_sender, response_receiver) = futures::channel::mpsc::channel(10);
+        (
+            Client::new(request_sender, response_receiver),
+            request_receiver,
+            response_sender,
+        )
+    }
+
+    /// A stream whose start is cancelled before the request is queued is unregistered. A stream
+    /// whose request was queued stays registered.
+    #[async_std::test]
+    async fn cancelled_stream_start() {
+        let (request_sender, mut request_receiver) = futures::channel::mpsc::channel(1);
+        let (_response_sender, response_receiver) = futures::channel::mpsc::channel::<Response>(10);
+        let mut client = Client::new(request_sender, response_receiver);
+        let _source = client
+            .start_source(vec!["first".to_string()], vec![])
+            .await
+            .unwrap();
+        // The request is queued but the channel is full afterwards
+        let start = client.start_source(vec!["second".to_string()], vec![]);
+        assert!(start.now_or_never().is_none());
+        assert_eq!(client.sender.streams.len(), 2);
+        // The request cannot be queued
+        let start = client.start_source(vec!["third".to_string()], vec![]);
+        assert!(start.now_or_never().is_none());
+        assert_eq!(client.sender.streams.len(), 2);
+
+        let number = |request: Request| match request {
+            Request::Stream { number, .. } => number,
+            request => panic!("unexpected request {:?}", request),
+        };
+        assert_eq!(number(request_receiver.next().await.unwrap()), 1);
+        assert_eq!(number(request_receiver.next().await.unwrap()), 2);
+        // The number of the cancelled stream was released
+        assert!(!client
+            .sender
+            .request_numbers
+            .lock()
+            .unwrap()
+            .is_local_open(3));
+        let _source = client
+            .start_source(vec!["fourth".to_string()], vec![])
+            .await
+            .unwrap();
+        assert_eq!(number(request_receiver.next().await.unwrap()), 4);
+    }
+
+    fn unknown_error() -> Response {
+        Response::AsyncErr {
+            number: 7,
+            name: "ERROR".to_string(),
+            message: "unknown".to_string(),
+        }
+    }
+
+    #[async_std::test]
+    async fn unknown_response_anomaly() {
+        let (client, _requests, mut responses) = client();
+        let mut anomalies = client.anomalies();
+        let stream_end = Response::Stream {
+            number: 8,
+            message: StreamMessage::End,
+        };
+        responses.send(unknown_error()).await.unwrap();
+        responses.send(stream_end.clone()).await.unwrap();
+        drop(responses);
+
+        assert_eq!(
+            anomalies.next().await,
+            Some(ProtocolAnomaly::UnknownRequest(unknown_error()))
+        );
+        assert_eq!(
+            anomalies.next().await,
+            Some(ProtocolAnomaly::UnknownStream(stream_end))
+        );
+        client.join().await.unwrap();
+        assert_eq!(anomalies.next().await, None);
+    }
+
+    #[async_std::test]
+    async fn unknown_response_fail_connection() {
+        let (client, _requests, mut responses) = client();
+        client.set_unknown_response_policy(UnknownResponsePolicy::FailConnection);
+        responses.send(unknown_error()).await.unwrap();
+        assert_eq!(
+            client.join().await,
+            Err(ProtocolError {
+                anomaly: ProtocolAnomaly::UnknownRequest(unknown_error())
+            })
+        );
+    }
+
+    #[async_std::test]
+    async fn pending_request_connection_closed() {
+        let (mut client, _requests, responses) = client();
+        drop(responses);
+        let result = client.send_async(vec!["foo".to_string()], vec![]).await;
+        assert!(matches!(result, Err(AsyncRequestError::Closed)));
+    }
+
+    #[async_std::test]
+    async fn source_end() {
+        let (mut client, mut requests, mut responses) = client();
+        let mut source = client
+            .start_source(vec!["foo".to_string()], vec![])
+            .await
+            .unwrap();
+        let request = requests.next().await.unwrap();
+        assert!(matches!(request, Request::Stream { number: 1, .. }));
+
+        responses
+            .send(StreamMessage::End.into_response(1))
+            .await
+            .unwrap();
+        assert!(source.next().await.is_none());
+        // The client ends its side of the stream so that the peer can release the number.
+        assert_eq!(
+            requests.next().await,
+            Some(StreamMessage::End.into_request(1))
+        );
+    }
+
+    #[async_std::test]
+    async fn request_numbers_not_reused_while_active() {
+        let (mut client, mut requests, _responses) = client();
+        let (_source, sink) = client
+            .start_duplex(vec!["foo".to_string()], vec![])
+            .await
+            .unwrap();
+        sink.close().await.unwrap();
+        let _source = client
+            .start_source(vec!["foo".to_string()], vec![])
+            .await
+            .unwrap();
+        let numbers = requests
+            .by_ref()
+            .take(3)
+            .map(|request| match request {
+                Request::Stream { number, .. } => number,
+                Request::Async { number, .. } => number,
+            })
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(numbers, vec![1, 1, 2]);
+    }
+
+    #[async_std::test]
+    async fn async_request_releases_state() {
+        let (mut client, mut requests, mut responses) = client();
+        let strong_counts = |client: &Client| {
+            (
+                Arc::strong_count(&client.sender.pending_async_requests),
+                Arc::strong_count(&client.sender.abandoned_requests),
+                Arc::strong_count(&client.sender.request_numbers),
+            )
+        };
+        let before = strong_counts(&client);
+        let response = client.send_async(vec!["foo".to_string()], vec![]);
+        let respond = async {
+            assert!(matches!(
+                requests.next().await,
+                Some(Request::Async { number: 1, .. })
+            ));
+            responses
+                .send(Response::AsyncOk {
+                    number: 1,
+                    body: Body::Json(b"true".to_vec()),
+                })
+                .await
+                .unwrap();
+        };
+        let (response, ()) = future::join(response, respond).await;
+        assert_eq!(response.unwrap(), AsyncResponse::Json(b"true".to_vec()));
+        assert_eq!(strong_counts(&client), before);
+    }
+
+    #[async_std::test]
+    async fn async_request_timeout() {
+        let (mut client, mut requests, mut responses) = client();
+        let mut anomalies = client.anomalies();
+        let result = client
+            .send_async_with(vec!["foo".to_string()], vec![], Duration::from_millis(10))
+            .await;
+        assert!(matches!(result, Err(AsyncRequestError::Timeout(_))));
+        assert!(matches!(
+            requests.next().await,
+            Some(Request::Async { number: 1, .. })
+        ));
+        assert!(client.sender.pending_async_requests.is_empty());
+        assert!(client
+            .sender
+            .request_numbers
+            .lock()
+            .unwrap()
+            .local_open()
+            .is_empty());
+
+        // The late response is dropped without an anomaly.
+        responses
+            .send(Response::AsyncOk {
+                number: 1,
+                body: Body::Json(b"true".to_vec()),
+            })
+            .await
+            .unwrap();
+        drop(responses);
+        client.join().await.unwrap();
+        assert_eq!(anomalies.next().await, None);
+    }
+
+    #[async_std::test]
+    async fn source_inactivity_timeout() {
+        let (mut client, mut requests, mut responses) = client();
+        let mut anomalies = client.anomalies();
+        let mut source = client
+            .start_source_with(vec!["foo".to_string()], vec![], Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert!(matches!(
+            requests.next().await,
+            Some(Request::Stream { number: 1, .. })
+        ));
+
+        let body = Body::Json(b"1".to_vec());
+        responses
+            .send(StreamMessage::Data(body.clone()).into_response(1))
+            .await
+            .unwrap();
+        assert_eq!(source.next().await, Some(Ok(body)));
+
+        match source.next().await {
+            Some(Err(error)) => assert_eq!(error.name, TIMEOUT_ERROR_NAME),
+            item => panic!("unexpected item {:?}", item),
+        }
+        assert!(source.next().await.is_none());
+        assert_eq!(
+            requests.next().await,
+            Some(StreamMessage::End.into_request(1))
+        );
+
+        // The peer acknowledges the end without a second end message from us.
+        responses
+            .send(StreamMessage::End.into_response(1))
+            .await
+            .unwrap();
+        drop(responses);
+        client.join().await.unwrap();
+        assert_eq!(anomalies.next().await, None);
+        // The client has not sent a second end message.
+        assert!(requests.next().await.is_none());
+    }
 }
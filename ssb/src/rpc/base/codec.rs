@@ -0,0 +1,112 @@
+//! Packet-level access to RPC connections.
+//!
+//! [Endpoint][super::Endpoint] takes care of request numbers, streams and services. Tools
+//! that only need to look at or forward packets, like proxies, sniffers or protocol bridges,
+//! can use [PacketStream] and [PacketSink] on the raw connection instead.
+//!
+//! ```
+//! # use futures::prelude::*;
+//! # use ssb::rpc::base::codec::*;
+//! # async_std::task::block_on(async {
+//! let (sender, receiver) = futures::channel::mpsc::unbounded::<Vec<u8>>();
+//! let mut sink = PacketSink::new(sender);
+//! let packet = Packet::Request(Request::Async {
+//!     number: 1,
+//!     method: vec!["whoami".to_string()],
+//!     type_: RequestType::Async,
+//!     args: vec![],
+//! });
+//! sink.send(packet.clone()).await.unwrap();
+//! sink.close().await.unwrap();
+//!
+//! let packets = PacketStream::new(receiver.map(Ok::<_, std::io::Error>))
+//!     .try_collect::<Vec<_>>()
+//!     .await
+//!     .unwrap();
+//! assert_eq!(packets, vec![packet]);
+//! # });
+//! ```
+use futures::prelude::*;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+#[doc(inline)]
+pub use super::packet::{
+    Body, DecodeError, Packet, PacketDecoder, PacketParseError, Request, RequestType, Response,
+};
+#[doc(inline)]
+pub use super::packet_stream::{NextPacketError, PacketStream};
+#[doc(inline)]
+pub use ssb_packet::{BodyType, Header, HeaderFlags, HeaderParseError};
+
+#[pin_project::pin_project]
+#[derive(Debug)]
+/// [Sink] that encodes [Packet]s and writes them to an underlying [Sink] of bytes.
+///
+/// Every packet is written as a separate item. Closing the sink sends the goodbye header before
+/// the underlying sink is closed.
+pub struct PacketSink<Sink> {
+    #[pin]
+    sink: Sink,
+    goodbye_sent: bool,
+}
+
+impl<Sink> PacketSink<Sink> {
+    pub fn new(sink: Sink) -> Self {
+        Self {
+            sink,
+            goodbye_sent: false,
+        }
+    }
+
+    /// Returns the underlying sink.
+    pub fn into_inner(self) -> Sink {
+        self.sink
+    }
+}
+
+impl<Sink_: Sink<Vec<u8>>> Sink<Packet> for PacketSink<Sink_> {
+    type Error = Sink_::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().sink.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, packet: Packet) -> Result<(), Self::Error> {
+        self.project().sink.start_send(packet.build())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().sink.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let mut this = self.project();
+        if !*this.goodbye_sent {
+            futures::ready!(this.sink.as_mut().poll_ready(cx))?;
+            this.sink.as_mut().start_send(vec![0u8; Header::SIZE])?;
+            *this.goodbye_sent = true;
+        }
+        this.sink.poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn goodbye_on_close() {
+        let (sender, receiver) = futures::channel::mpsc::unbounded::<Vec<u8>>();
+        let mut sink = PacketSink::new(sender);
+        let packet = Packet::Response(Response::AsyncOk {
+            number: 1,
+            body: Body::String("foo".to_string()),
+        });
+        sink.send(packet.clone()).await.unwrap();
+        sink.close().await.unwrap();
+
+        let writes = receiver.collect::<Vec<_>>().await;
+        assert_eq!(writes, vec![packet.build(), vec![0u8; Header::SIZE]]);
+    }
+}
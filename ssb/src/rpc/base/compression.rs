@@ -0,0 +1,203 @@
+//! Compression of stream data between peers that both support it.
+//!
+//! Compression is off by default because other muxrpc implementations do not understand
+//! compressed packets. An [Endpoint][super::Endpoint] built with
+//! [EndpointBuilder::compression][super::EndpointBuilder::compression] serves the
+//! `compression.enable` method. [Endpoint::enable_compression][super::Endpoint::enable_compression]
+//! asks the peer to enable one of the configured algorithms. Once both peers agreed, the bodies of
+//! stream data packets are compressed if that makes them smaller. Compressed packets have
+//! [HeaderFlags::is_compressed][super::packet::HeaderFlags::is_compressed] set.
+//!
+//! The algorithms are provided by the application by implementing [Compression]. The `deflate`
+//! feature provides [Deflate].
+//!
+//! # Protocol
+//!
+//! The peer that enables compression sends a `sync` request `compression.enable` with the name
+//! of the algorithm as the only argument. Before it sends the request it accepts compressed
+//! packets. The other peer answers `true` if it supports the algorithm and from then on accepts
+//! and sends compressed packets. Once the response arrives the first peer starts sending
+//! compressed packets, too. An answer other than `true` leaves compression disabled.
+use std::sync::{Arc, Mutex};
+
+use super::service::{AsyncResponse, Service};
+
+/// Compression algorithm for the bodies of stream data packets.
+pub trait Compression: Send + Sync + 'static {
+    /// Identifies the algorithm to the peer, for example `zstd`.
+    fn name(&self) -> &str;
+
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+
+    /// Errors if `data` was not produced by [Compression::compress].
+    ///
+    /// `data` comes from the peer and may inflate to much more than `max_len` bytes.
+    /// Implementations must stop after `max_len + 1` bytes. Longer bodies are rejected.
+    fn decompress(&self, data: &[u8], max_len: usize) -> std::io::Result<Vec<u8>>;
+}
+
+/// DEFLATE ([RFC 1951](https://tools.ietf.org/html/rfc1951)) without zlib or gzip framing,
+/// named `deflate`. The default compression level is 6.
+#[cfg(feature = "deflate")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Deflate {
+    level: flate2::Compression,
+}
+
+#[cfg(feature = "deflate")]
+impl Deflate {
+    /// Compression level from 0 (no compression) to 9 (best compression).
+    pub fn new(level: u32) -> Self {
+        Self {
+            level: flate2::Compression::new(level),
+        }
+    }
+}
+
+#[cfg(feature = "deflate")]
+impl Compression for Deflate {
+    fn name(&self) -> &str {
+        "deflate"
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        use std::io::Write as _;
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), self.level);
+        encoder
+            .write_all(data)
+            .and_then(|()| encoder.finish())
+            .expect("writing to a Vec does not fail")
+    }
+
+    fn decompress(&self, data: &[u8], max_len: usize) -> std::io::Result<Vec<u8>> {
+        use std::io::Read as _;
+        let mut decompressed = Vec::new();
+        flate2::read::DeflateDecoder::new(data)
+            .take((max_len as u64).saturating_add(1))
+            .read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    }
+}
+
+/// Bodies shorter than this are not worth compressing.
+const MIN_COMPRESSED_LEN: usize = 64;
+
+/// Configured algorithms and the algorithms agreed on with the peer for an endpoint. Clones
+/// share the state.
+#[derive(Clone, Default)]
+pub(super) struct CompressionState {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    algorithms: Vec<Arc<dyn Compression>>,
+    send: Option<Arc<dyn Compression>>,
+    receive: Option<Arc<dyn Compression>>,
+}
+
+impl std::fmt::Debug for CompressionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        let name = |algorithm: &Option<Arc<dyn Compression>>| {
+            algorithm
+                .as_ref()
+                .map(|algorithm| algorithm.name().to_string())
+        };
+        f.debug_struct("CompressionState")
+            .field(
+                "algorithms",
+                &inner
+                    .algorithms
+                    .iter()
+                    .map(|algorithm| algorithm.name())
+                    .collect::<Vec<_>>(),
+            )
+            .field("send", &name(&inner.send))
+            .field("receive", &name(&inner.receive))
+            .finish()
+    }
+}
+
+impl CompressionState {
+    pub(super) fn new(algorithms: Vec<Arc<dyn Compression>>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                algorithms,
+                send: None,
+                receive: None,
+            })),
+        }
+    }
+
+    pub(super) fn algorithms(&self) -> Vec<Arc<dyn Compression>> {
+        self.inner.lock().unwrap().algorithms.clone()
+    }
+
+    /// Name of the algorithm used for sending. `None` if compression is not enabled.
+    pub(super) fn name(&self) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .send
+            .as_ref()
+            .map(|algorithm| algorithm.name().to_string())
+    }
+
+    pub(super) fn set_send(&self, algorithm: Option<Arc<dyn Compression>>) {
+        self.inner.lock().unwrap().send = algorithm;
+    }
+
+    /// Accept packets compressed with `algorithm` and return the algorithm that was accepted
+    /// before.
+    pub(super) fn set_receive(
+        &self,
+        algorithm: Option<Arc<dyn Compression>>,
+    ) -> Option<Arc<dyn Compression>> {
+        std::mem::replace(&mut self.inner.lock().unwrap().receive, algorithm)
+    }
+
+    /// Compresses `data` if compression is enabled and the result is smaller.
+    pub(super) fn compress(&self, data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < MIN_COMPRESSED_LEN {
+            return None;
+        }
+        let algorithm = self.inner.lock().unwrap().send.clone()?;
+        let compressed = algorithm.compress(data);
+        if compressed.len() < data.len() {
+            Some(compressed)
+        } else {
+            None
+        }
+    }
+
+    /// Returns at most `max_len + 1` bytes. See [Compression::decompress].
+    pub(super) fn decompress(&self, data: &[u8], max_len: usize) -> Option<Vec<u8>> {
+        let algorithm = self.inner.lock().unwrap().receive.clone()?;
+        match algorithm.decompress(data, max_len) {
+            Ok(data) => Some(data),
+            Err(error) => {
+                tracing::debug!(%error, algorithm = algorithm.name(), "failed to decompress");
+                None
+            }
+        }
+    }
+
+    /// Service with the `enable` method that the peer calls to enable compression.
+    pub(super) fn service(&self) -> Service {
+        let state = self.clone();
+        let mut service = Service::new();
+        service.add_sync("enable", move |(name,): (String,)| {
+            let algorithm = state
+                .algorithms()
+                .into_iter()
+                .find(|algorithm| algorithm.name() == name);
+            let enabled = algorithm.is_some();
+            if enabled {
+                state.set_receive(algorithm.clone());
+                state.set_send(algorithm);
+            }
+            AsyncResponse::json_ok(&enabled)
+        });
+        service
+    }
+}
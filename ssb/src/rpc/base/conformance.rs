@@ -0,0 +1,171 @@
+//! Packets as they appear on the wire, following the examples of the [RPC section of the
+//! Scuttlebutt Protocol Guide][guide].
+//!
+//! Every example is checked in both directions: the wire data must decode to the packet and the
+//! packet must encode to exactly the wire data.
+//!
+//! [guide]: https://ssbc.github.io/scuttlebutt-protocol-guide/#rpc-protocol
+use super::packet::{Packet, PacketDecoder, Request, RequestType, Response};
+use super::{Body, Error, StreamMessage};
+
+const FEED_ID: &str = "@FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=.ed25519";
+
+struct Example {
+    name: &'static str,
+    header: [u8; 9],
+    body: Vec<u8>,
+    packet: Packet,
+}
+
+impl Example {
+    fn wire(&self) -> Vec<u8> {
+        [&self.header[..], &self.body].concat()
+    }
+}
+
+fn examples() -> Vec<Example> {
+    let whoami_response = format!(r#"{{"id":"{}"}}"#, FEED_ID);
+    let history_request = format!(
+        r#"{{"name":["createHistoryStream"],"type":"source","args":[{{"id":"{}"}}]}}"#,
+        FEED_ID
+    );
+    let error = r#"{"name":"Error","message":"no such method"}"#;
+    vec![
+        Example {
+            name: "async request",
+            header: [0x02, 0, 0, 0, 0x2c, 0, 0, 0, 1],
+            body: br#"{"name":["whoami"],"type":"async","args":[]}"#.to_vec(),
+            packet: Packet::Request(Request::Async {
+                number: 1,
+                method: vec!["whoami".to_string()],
+                type_: RequestType::Async,
+                args: vec![],
+            }),
+        },
+        Example {
+            name: "async response",
+            header: [0x02, 0, 0, 0, 0x3e, 0xff, 0xff, 0xff, 0xff],
+            body: whoami_response.as_bytes().to_vec(),
+            packet: Packet::Response(Response::AsyncOk {
+                number: 1,
+                body: Body::Json(whoami_response.as_bytes().to_vec()),
+            }),
+        },
+        Example {
+            name: "async error response",
+            header: [0x06, 0, 0, 0, 0x2b, 0xff, 0xff, 0xff, 0xfe],
+            body: error.as_bytes().to_vec(),
+            packet: Packet::Response(Response::AsyncErr {
+                number: 2,
+                name: "Error".to_string(),
+                message: "no such method".to_string(),
+            }),
+        },
+        Example {
+            name: "source request",
+            header: [0x0a, 0, 0, 0, 0x78, 0, 0, 0, 3],
+            body: history_request.as_bytes().to_vec(),
+            packet: Packet::Request(Request::Stream {
+                number: 3,
+                message: StreamMessage::Data(Body::Json(history_request.as_bytes().to_vec())),
+            }),
+        },
+        Example {
+            name: "stream JSON data",
+            header: [0x0a, 0, 0, 0, 0x09, 0xff, 0xff, 0xff, 0xfd],
+            body: br#"{"seq":1}"#.to_vec(),
+            packet: Packet::Response(Response::Stream {
+                number: 3,
+                message: StreamMessage::Data(Body::Json(br#"{"seq":1}"#.to_vec())),
+            }),
+        },
+        Example {
+            name: "stream binary data",
+            header: [0x08, 0, 0, 0, 4, 0xff, 0xff, 0xff, 0xfd],
+            body: vec![0, 1, 2, 0xff],
+            packet: Packet::Response(Response::Stream {
+                number: 3,
+                message: StreamMessage::Data(Body::Blob(vec![0, 1, 2, 0xff])),
+            }),
+        },
+        Example {
+            name: "stream string data",
+            header: [0x09, 0, 0, 0, 7, 0xff, 0xff, 0xff, 0xfd],
+            body: "grüße".as_bytes().to_vec(),
+            packet: Packet::Response(Response::Stream {
+                number: 3,
+                message: StreamMessage::Data(Body::String("grüße".to_string())),
+            }),
+        },
+        Example {
+            name: "stream end from responder",
+            header: [0x0e, 0, 0, 0, 4, 0xff, 0xff, 0xff, 0xfd],
+            body: b"true".to_vec(),
+            packet: Packet::Response(Response::Stream {
+                number: 3,
+                message: StreamMessage::End,
+            }),
+        },
+        Example {
+            name: "stream end from requester",
+            header: [0x0e, 0, 0, 0, 4, 0, 0, 0, 3],
+            body: b"true".to_vec(),
+            packet: Packet::Request(Request::Stream {
+                number: 3,
+                message: StreamMessage::End,
+            }),
+        },
+        Example {
+            name: "stream error",
+            header: [0x0e, 0, 0, 0, 0x2b, 0xff, 0xff, 0xff, 0xfc],
+            body: error.as_bytes().to_vec(),
+            packet: Packet::Response(Response::Stream {
+                number: 4,
+                message: StreamMessage::Error(Error::new("Error", "no such method")),
+            }),
+        },
+    ]
+}
+
+#[test]
+fn decode_examples() {
+    for example in examples() {
+        let mut decoder = PacketDecoder::new();
+        let packet = decoder.put(&example.wire()[..]);
+        assert!(
+            matches!(&packet, Some(Ok(Some(packet))) if packet == &example.packet),
+            "{}: {:?}",
+            example.name,
+            packet
+        );
+        assert!(decoder.is_empty(), "{}", example.name);
+    }
+}
+
+#[test]
+fn encode_examples() {
+    for example in examples() {
+        let wire = example.wire();
+        assert_eq!(example.packet.build(), wire, "{}", example.name);
+    }
+}
+
+#[test]
+fn decode_connection() {
+    let examples = examples();
+    let mut data = examples
+        .iter()
+        .flat_map(|example| example.wire())
+        .collect::<Vec<_>>();
+    // Goodbye
+    data.extend_from_slice(&[0u8; 9]);
+
+    let mut decoder = PacketDecoder::new();
+    let mut data = &data[..];
+    for example in &examples {
+        let packet = decoder.put(&mut data).unwrap().unwrap();
+        assert_eq!(packet.as_ref(), Some(&example.packet), "{}", example.name);
+    }
+    assert!(decoder.put(&mut data).unwrap().unwrap().is_none());
+    assert!(data.is_empty());
+}
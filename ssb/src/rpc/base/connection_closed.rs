@@ -0,0 +1,72 @@
+use futures::future::Shared;
+use futures::prelude::*;
+use std::sync::{Arc, Mutex};
+
+/// Tells the tasks of an [Endpoint][super::Endpoint] that the connection is gone.
+///
+/// The packet reader closes the connection when the peer ends it or reading fails. Request
+/// handlers are cancelled and the packet writer stops because nothing they send can reach the
+/// peer anymore.
+#[derive(Debug, Clone)]
+pub(super) struct ConnectionClosed {
+    sender: Arc<Mutex<Option<futures::channel::oneshot::Sender<()>>>>,
+    receiver: Shared<futures::channel::oneshot::Receiver<()>>,
+}
+
+impl ConnectionClosed {
+    pub(super) fn new() -> Self {
+        let (sender, receiver) = futures::channel::oneshot::channel();
+        Self {
+            sender: Arc::new(Mutex::new(Some(sender))),
+            receiver: receiver.shared(),
+        }
+    }
+
+    /// Mark the connection as closed. Does nothing if it is closed already.
+    pub(super) fn close(&self) {
+        if let Some(sender) = self.sender.lock().unwrap().take() {
+            tracing::debug!("connection closed");
+            let _ = sender.send(());
+        }
+    }
+
+    pub(super) fn is_closed(&self) -> bool {
+        self.sender.lock().unwrap().is_none()
+    }
+
+    /// Resolves once the connection is closed. Never resolves if all handles are dropped without
+    /// closing the connection.
+    pub(super) fn wait(&self) -> impl Future<Output = ()> + Send + Unpin + 'static {
+        self.receiver.clone().then(|result| match result {
+            Ok(()) => future::ready(()).left_future(),
+            Err(futures::channel::oneshot::Canceled) => future::pending().right_future(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn close() {
+        let closed = ConnectionClosed::new();
+        let wait = closed.wait();
+        assert!(!closed.is_closed());
+        assert!(closed.wait().now_or_never().is_none());
+
+        closed.clone().close();
+        assert!(closed.is_closed());
+        wait.await;
+        closed.wait().await;
+        closed.close();
+    }
+
+    #[test]
+    fn drop_without_close() {
+        let closed = ConnectionClosed::new();
+        let wait = closed.wait();
+        drop(closed);
+        assert!(wait.now_or_never().is_none());
+    }
+}
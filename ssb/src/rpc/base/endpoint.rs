@@ -1,20 +1,361 @@
 use anyhow::Context as _;
 use futures::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use super::client::Client;
-use super::packet::{Packet, Request, Response};
+use super::anomaly::ProtocolViolation;
+use super::client::{AsyncResponse, Client, RequestSender};
+use super::compression::{Compression, CompressionState};
+use super::connection_closed::ConnectionClosed;
+use super::flow_control::FlowControl;
+use super::packet::{BodyEncoding, Packet, Request, Response};
 use super::packet_stream::{NextPacketError, PacketStream};
+use super::server::{HandlerPanics, ServerConfig};
+use super::writer::{flush_channel, multiplex, send_packets, WriteConfig};
 use super::Service;
+use crate::utils::task::{spawn, JoinHandle};
 
+/// Buffer sizes and limits for an [Endpoint].
+///
+/// Larger buffers improve throughput for bulk transfers like replication at the cost of memory.
+///
+/// ```
+/// # use ssb::rpc::base::EndpointConfig;
+/// let config = EndpointConfig {
+///     request_buffer: 64,
+///     ..EndpointConfig::default()
+/// };
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointConfig {
+    /// Number of outgoing and incoming requests that are buffered between the connection and the
+    /// client or server.
+    pub request_buffer: usize,
+    /// Number of outgoing and incoming responses that are buffered between the connection and the
+    /// client or server.
+    pub response_buffer: usize,
+    /// Number of messages that are buffered for every stream until the application consumes
+    /// them. Unbounded if `None`.
+    ///
+    /// If flow control is enabled (see [Endpoint::enable_flow_control]) this is the window of
+    /// every stream: the peer stops sending on a stream once the buffer is full and the other
+    /// streams are not affected. Otherwise, if the buffer of a stream started by the client is
+    /// full no other messages are read from the connection. A stream served by the endpoint
+    /// whose buffer overflows fails with a `STREAM_BUFFER_FULL` error so that a handler that
+    /// does not consume its messages does not block other requests.
+    pub stream_buffer: Option<usize>,
+    /// Maximum number of packets that are combined into one write to the connection.
+    pub write_batch: usize,
+    /// Packets are written as soon as the combined packets exceed this number of bytes.
+    pub write_buffer_size: usize,
+    /// Time to wait for more packets before writing to the connection. If `None` all packets
+    /// that are ready are written immediately.
+    ///
+    /// Use [Client::flush] to write packets without waiting.
+    pub write_delay: Option<Duration>,
+    /// Encoding of outgoing JSON bodies. Only use a different encoding than
+    /// [BodyEncoding::Json] if the peer is known to support it.
+    pub body_encoding: BodyEncoding,
+    /// Close the connection if the peer sends nothing for this long. Peers keep idle
+    /// connections alive by sending requests periodically, see [EndpointConfig::keep_alive]. No
+    /// timeout if `None`.
+    pub idle_timeout: Option<Duration>,
+    /// Send a `manifest` request if nothing was sent to the peer for this long. The request and
+    /// its response keep the [idle timeouts][EndpointConfig::idle_timeout] of both peers from
+    /// expiring, so the interval should be shorter than either of them. No keep-alive if `None`.
+    pub keep_alive: Option<Duration>,
+    /// Maximum number of requests from the peer that are handled at the same time. Requests that
+    /// exceed the limit are answered with a `TOO_MANY_REQUESTS` error. Unlimited if `None`.
+    pub max_concurrent_requests: Option<usize>,
+    /// Measure the round trip time to the peer at this interval by timing a `manifest` request.
+    /// Any response counts, even an error. See [Endpoint::rtt] and
+    /// [EndpointMetrics::rtt_measured]. No probes if `None`. Not supported on `wasm32`.
+    pub rtt_probe_interval: Option<Duration>,
+    /// Close the connection if the peer announces a packet body longer than this number of bytes.
+    /// The body is not buffered. Reported as a [ProtocolViolation]. Unlimited if `None`.
+    pub max_body_len: Option<u32>,
+}
+
+impl Default for EndpointConfig {
+    fn default() -> Self {
+        Self {
+            request_buffer: 10,
+            response_buffer: 10,
+            stream_buffer: None,
+            write_batch: 16,
+            write_buffer_size: 16 * 1024,
+            write_delay: None,
+            body_encoding: BodyEncoding::Json,
+            idle_timeout: None,
+            keep_alive: None,
+            max_concurrent_requests: None,
+            rtt_probe_interval: None,
+            max_body_len: None,
+        }
+    }
+}
+
+/// Receives events of an [Endpoint], for example to collect metrics.
+///
+/// All methods do nothing by default. They are called from the tasks of the endpoint and should
+/// return quickly.
+pub trait EndpointMetrics: Send + Sync + 'static {
+    /// Called for every packet received from the peer.
+    fn packet_received(&self, _packet: &Packet) {}
+
+    /// Called for every packet before it is written to the connection.
+    fn packet_sent(&self, _packet: &Packet) {}
+
+    /// Called with the round trip time of every probe, see [EndpointConfig::rtt_probe_interval].
+    fn rtt_measured(&self, _rtt: Duration) {}
+
+    /// Called once if the peer sends data that cannot be decoded, right before the connection is
+    /// closed. The violation is also logged.
+    fn protocol_violation(&self, _violation: &ProtocolViolation) {}
+}
+
+/// Builder for an [Endpoint] created with [Endpoint::builder].
+///
+/// ```
+/// # use ssb::rpc::base::{Endpoint, Service};
+/// # use futures::prelude::*;
+/// fn endpoint(
+///     send: futures::channel::mpsc::Sender<Vec<u8>>,
+///     receive: futures::channel::mpsc::Receiver<Vec<u8>>,
+/// ) -> Endpoint {
+///     let mut service = Service::new();
+///     service.add_sync("whoami", |_: Vec<()>| todo!());
+///     Endpoint::builder()
+///         .service(service)
+///         .request_buffer(64)
+///         .max_concurrent_requests(100)
+///         .idle_timeout(std::time::Duration::from_secs(60))
+///         .build(send, receive.map(Ok::<_, std::io::Error>))
+/// }
+/// ```
+#[derive(Default)]
+pub struct EndpointBuilder {
+    service: Service,
+    config: EndpointConfig,
+    metrics: Option<Arc<dyn EndpointMetrics>>,
+    peer: Option<ssb_box_stream::PublicKey>,
+    direction: Option<&'static str>,
+    compression: Vec<Arc<dyn Compression>>,
+}
+
+impl EndpointBuilder {
+    /// Service that handles the requests of the peer. Without a service every request is
+    /// answered with a “method not found” error.
+    pub fn service(mut self, service: Service) -> Self {
+        self.service = service;
+        self
+    }
+
+    /// Replace all options with `config`.
+    pub fn config(mut self, config: EndpointConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// See [EndpointConfig::request_buffer].
+    pub fn request_buffer(mut self, size: usize) -> Self {
+        self.config.request_buffer = size;
+        self
+    }
+
+    /// See [EndpointConfig::response_buffer].
+    pub fn response_buffer(mut self, size: usize) -> Self {
+        self.config.response_buffer = size;
+        self
+    }
+
+    /// See [EndpointConfig::stream_buffer].
+    pub fn stream_buffer(mut self, size: Option<usize>) -> Self {
+        self.config.stream_buffer = size;
+        self
+    }
+
+    /// See [EndpointConfig::idle_timeout].
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// See [EndpointConfig::keep_alive].
+    pub fn keep_alive(mut self, interval: Duration) -> Self {
+        self.config.keep_alive = Some(interval);
+        self
+    }
+
+    /// See [EndpointConfig::max_concurrent_requests].
+    pub fn max_concurrent_requests(mut self, limit: usize) -> Self {
+        self.config.max_concurrent_requests = Some(limit);
+        self
+    }
+
+    /// See [EndpointConfig::rtt_probe_interval].
+    pub fn rtt_probe_interval(mut self, interval: Duration) -> Self {
+        self.config.rtt_probe_interval = Some(interval);
+        self
+    }
+
+    /// See [EndpointConfig::max_body_len].
+    pub fn max_body_len(mut self, len: u32) -> Self {
+        self.config.max_body_len = Some(len);
+        self
+    }
+
+    /// Report packets sent and received to `metrics`.
+    pub fn metrics(mut self, metrics: impl EndpointMetrics) -> Self {
+        self.metrics = Some(Arc::new(metrics));
+        self
+    }
+
+    /// Support compressing stream data with `algorithm`. Algorithms that are added first are
+    /// preferred by [Endpoint::enable_compression]. See [compression][super::compression].
+    pub fn compression(mut self, algorithm: impl Compression) -> Self {
+        self.compression.push(Arc::new(algorithm));
+        self
+    }
+
+    /// Identity of the peer, usually established by the handshake. Available from
+    /// [Endpoint::peer].
+    pub fn peer(mut self, peer: ssb_box_stream::PublicKey) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+
+    /// Who dialed the connection. Only used to label logs of protocol violations.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn direction(mut self, direction: crate::conn::Direction) -> Self {
+        self.direction = Some(direction.as_str());
+        self
+    }
+
+    /// Create an endpoint that sends packets to `send` and receives packets from `receive`.
+    pub fn build<Sink_, TryStream_>(self, send: Sink_, receive: TryStream_) -> Endpoint
+    where
+        Sink_: Sink<Vec<u8>> + Send + Unpin + 'static,
+        Sink_::Error: std::error::Error + Send + Sync + 'static,
+        TryStream_: TryStream<Ok = Vec<u8>> + Send + Unpin + 'static,
+        TryStream_::Error: std::error::Error + Send + Sync + 'static,
+    {
+        Endpoint::start(send, receive, self)
+    }
+
+    /// Create an endpoint that sends and receives packets over `io`.
+    pub fn build_io<Io>(self, io: Io) -> Endpoint
+    where
+        Io: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (read, write) = io.split();
+        self.build(write.into_sink(), crate::utils::read_to_stream(read))
+    }
+}
+
+impl std::fmt::Debug for EndpointBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EndpointBuilder")
+            .field("service", &self.service)
+            .field("config", &self.config)
+            .field("metrics", &self.metrics.is_some())
+            .field("peer", &self.peer)
+            .field("direction", &self.direction)
+            .field(
+                "compression",
+                &self
+                    .compression
+                    .iter()
+                    .map(|algorithm| algorithm.name())
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+/// RPC connection with a peer.
+///
+/// Dropping the endpoint stops its tasks without saying goodbye. Pending requests of the
+/// [Client] and of its [RequestSender]s fail with [AsyncRequestError::Closed] and the streams of
+/// the client end. Use [Endpoint::close] to close the connection gracefully.
+///
+/// [AsyncRequestError::Closed]: super::AsyncRequestError::Closed
 #[derive(Debug)]
 pub struct Endpoint {
     client: Client,
-    server_task: async_std::task::JoinHandle<anyhow::Result<()>>,
-    packet_reader_task: async_std::task::JoinHandle<Result<(), NextPacketError>>,
-    packet_sender_task: async_std::task::JoinHandle<anyhow::Result<()>>,
+    peer: Option<ssb_box_stream::PublicKey>,
+    handler_panics: HandlerPanics,
+    server_task: JoinHandle<anyhow::Result<()>>,
+    packet_reader_task: JoinHandle<Result<(), NextPacketError>>,
+    packet_sender_task: JoinHandle<anyhow::Result<()>>,
+    goodbye_sender: GoodbyeSender,
+    rtt: Arc<Mutex<Option<Duration>>>,
+    compression: CompressionState,
+    flow_control: FlowControl,
+    stop_tasks: StopTasks,
+}
+
+/// Stops the tasks of an [Endpoint] when the endpoint is dropped.
+#[derive(Debug)]
+struct StopTasks {
+    closed: ConnectionClosed,
+    abort_handles: Vec<future::AbortHandle>,
+}
+
+impl Drop for StopTasks {
+    fn drop(&mut self) {
+        self.closed.close();
+        for abort_handle in &self.abort_handles {
+            abort_handle.abort();
+        }
+    }
+}
+
+/// Like [spawn] but adds a handle to stop the task to `abort_handles`. The task returns
+/// `aborted` if it is stopped.
+fn spawn_abortable<F>(
+    name: &str,
+    future: F,
+    aborted: F::Output,
+    abort_handles: &mut Vec<future::AbortHandle>,
+) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (future, abort_handle) = future::abortable(future);
+    abort_handles.push(abort_handle);
+    spawn(name, future.map(|result| result.unwrap_or(aborted)))
+}
+
+type GoodbyeSender = Arc<Mutex<Option<futures::channel::oneshot::Sender<()>>>>;
+
+/// Closes the connection of an [Endpoint] from another task. Returned by
+/// [Endpoint::close_handle].
+#[derive(Debug, Clone)]
+pub struct CloseHandle {
+    goodbye_sender: GoodbyeSender,
+}
+
+impl CloseHandle {
+    /// Send the goodbye header and close the connection once all packets have been written.
+    ///
+    /// In contrast to [Endpoint::close] the streams are not ended first. Does nothing if the
+    /// endpoint was closed already.
+    pub fn close(&self) {
+        if let Some(goodbye_sender) = self.goodbye_sender.lock().unwrap().take() {
+            let _ = goodbye_sender.send(());
+        }
+    }
 }
 
 impl Endpoint {
+    /// Configure an endpoint step by step. See [EndpointBuilder].
+    pub fn builder() -> EndpointBuilder {
+        EndpointBuilder::default()
+    }
+
     pub fn new<Sink_, TryStream_>(send: Sink_, receive: TryStream_, service: Service) -> Self
     where
         Sink_: Sink<Vec<u8>> + Send + Unpin + 'static,
@@ -22,52 +363,222 @@ impl Endpoint {
         TryStream_: TryStream<Ok = Vec<u8>> + Send + Unpin + 'static,
         TryStream_::Error: std::error::Error + Send + Sync + 'static,
     {
-        let (in_requests_sender, in_requests_receiver) = futures::channel::mpsc::channel(10);
-        let (out_requests_sender, out_requests_receiver) = futures::channel::mpsc::channel(10);
-        let (in_responses_sender, in_responses_receiver) = futures::channel::mpsc::channel(10);
-        let (out_responses_sender, out_responses_receiver) = futures::channel::mpsc::channel(10);
-        let client = Client::new(out_requests_sender, in_responses_receiver);
-
-        let server_task = async_std::task::Builder::new()
-            .name("rpc endpoint server".to_string())
-            .spawn(async move {
-                super::server::run(service, in_requests_receiver, out_responses_sender)
-                    .await
-                    .context("Server errored")
-            })
-            .unwrap();
+        Self::with_config(send, receive, service, EndpointConfig::default())
+    }
+
+    /// Like [Endpoint::new] but with the buffer sizes from `config`.
+    pub fn with_config<Sink_, TryStream_>(
+        send: Sink_,
+        receive: TryStream_,
+        service: Service,
+        config: EndpointConfig,
+    ) -> Self
+    where
+        Sink_: Sink<Vec<u8>> + Send + Unpin + 'static,
+        Sink_::Error: std::error::Error + Send + Sync + 'static,
+        TryStream_: TryStream<Ok = Vec<u8>> + Send + Unpin + 'static,
+        TryStream_::Error: std::error::Error + Send + Sync + 'static,
+    {
+        Self::builder()
+            .service(service)
+            .config(config)
+            .build(send, receive)
+    }
 
-        let packet_reader_task = async_std::task::Builder::new()
-            .name("rpc endpoint packet_reader".to_string())
-            .spawn(dispatch_incoming_packet(
+    fn start<Sink_, TryStream_>(send: Sink_, receive: TryStream_, builder: EndpointBuilder) -> Self
+    where
+        Sink_: Sink<Vec<u8>> + Send + Unpin + 'static,
+        Sink_::Error: std::error::Error + Send + Sync + 'static,
+        TryStream_: TryStream<Ok = Vec<u8>> + Send + Unpin + 'static,
+        TryStream_::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let EndpointBuilder {
+            mut service,
+            config,
+            metrics,
+            peer,
+            direction,
+            compression,
+        } = builder;
+        let EndpointConfig {
+            request_buffer,
+            response_buffer,
+            stream_buffer,
+            write_batch,
+            write_buffer_size,
+            write_delay,
+            body_encoding,
+            idle_timeout,
+            keep_alive,
+            max_concurrent_requests,
+            rtt_probe_interval,
+            max_body_len,
+        } = config;
+        let (in_requests_sender, in_requests_receiver) =
+            futures::channel::mpsc::channel(request_buffer);
+        let (out_requests_sender, out_requests_receiver) =
+            futures::channel::mpsc::channel(request_buffer);
+        let (in_responses_sender, in_responses_receiver) =
+            futures::channel::mpsc::channel(response_buffer);
+        let (out_responses_sender, out_responses_receiver) =
+            futures::channel::mpsc::channel(response_buffer);
+        let (flush_handle, flush_requests) = flush_channel();
+        let closed = ConnectionClosed::new();
+        let (goodbye_sender, goodbye_receiver) = futures::channel::oneshot::channel();
+        // Only say goodbye if the endpoint is closed explicitly, not when it is dropped.
+        let goodbye = goodbye_receiver.then(|result| match result {
+            Ok(()) => future::ready(()).left_future(),
+            Err(futures::channel::oneshot::Canceled) => future::pending().right_future(),
+        });
+        let flow_control = FlowControl::new(stream_buffer);
+        if stream_buffer.is_some() {
+            service.add_service(super::flow_control::SERVICE_NAME, flow_control.service());
+        }
+        let client = Client::with_options(
+            out_requests_sender,
+            in_responses_receiver,
+            stream_buffer,
+            flow_control.clone(),
+            Some(flush_handle),
+        );
+
+        let compression_configured = !compression.is_empty();
+        let compression = CompressionState::new(compression);
+        if compression_configured {
+            service.add_service("compression", compression.service());
+        }
+
+        let handler_panics = HandlerPanics::default();
+        let server_handler_panics = handler_panics.clone();
+        let server_closed = closed.clone();
+        let server_flow_control = flow_control.clone();
+        let mut abort_handles = Vec::new();
+        let server_task = spawn_abortable(
+            "rpc endpoint server",
+            async move {
+                super::server::run(
+                    service,
+                    server_handler_panics,
+                    ServerConfig {
+                        flow_control: server_flow_control,
+                        stream_buffer,
+                        max_concurrent_requests,
+                    },
+                    in_requests_receiver,
+                    out_responses_sender,
+                    server_closed,
+                )
+                .await
+                .context("Server errored")
+            },
+            Ok(()),
+            &mut abort_handles,
+        );
+
+        let packet_reader_task = spawn_abortable(
+            "rpc endpoint packet_reader",
+            dispatch_incoming_packet(
                 receive,
                 in_requests_sender,
                 in_responses_sender,
-            ))
-            .unwrap();
+                closed.clone(),
+                ReadConfig {
+                    idle_timeout,
+                    max_body_len,
+                    body_encoding,
+                    metrics: metrics.clone(),
+                    compression: compression_configured.then(|| compression.clone()),
+                    peer,
+                    direction,
+                },
+            ),
+            Ok(()),
+            &mut abort_handles,
+        );
 
-        let packet_sender_task = async_std::task::Builder::new()
-            .name("rpc endpoint packet_sender".to_string())
-            .spawn(async move {
-                futures::stream::select(
-                    out_requests_receiver.map(Packet::Request),
-                    out_responses_receiver.map(Packet::Response),
-                )
-                .map(|packet| Ok(packet.build()))
-                .forward(send)
-                .await
-                .context("Failed to send packet")
-            })
-            .unwrap();
+        let rtt = Arc::new(Mutex::new(None));
+        if let Some(interval) = rtt_probe_interval {
+            #[cfg(target_arch = "wasm32")]
+            {
+                let _ = interval;
+                tracing::warn!("RTT probes are not supported on wasm32");
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            spawn(
+                "rpc endpoint rtt_probe",
+                probe_rtt(
+                    client.sender(),
+                    interval,
+                    Arc::clone(&rtt),
+                    metrics.clone(),
+                    closed.clone(),
+                ),
+            );
+        }
+
+        let sent = Arc::new(AtomicBool::new(false));
+        if let Some(interval) = keep_alive {
+            spawn(
+                "rpc endpoint keep_alive",
+                send_keep_alive(client.sender(), interval, Arc::clone(&sent), closed.clone()),
+            );
+        }
+
+        let stop_tasks_closed = closed.clone();
+        let packet_sender_task = spawn_abortable(
+            "rpc endpoint packet_sender",
+            send_packets(
+                multiplex(out_requests_receiver, out_responses_receiver).inspect(
+                    move |sequenced| {
+                        sent.store(true, Ordering::Relaxed);
+                        if let Some(metrics) = &metrics {
+                            metrics.packet_sent(&sequenced.packet);
+                        }
+                    },
+                ),
+                flush_requests,
+                goodbye,
+                closed,
+                send,
+                WriteConfig {
+                    batch: write_batch,
+                    buffer_size: write_buffer_size,
+                    delay: write_delay,
+                    body_encoding,
+                    compression: compression_configured.then(|| compression.clone()),
+                },
+            ),
+            Ok(()),
+            &mut abort_handles,
+        );
 
         Self {
             client,
+            peer,
+            handler_panics,
             server_task,
             packet_reader_task,
             packet_sender_task,
+            goodbye_sender: Arc::new(Mutex::new(Some(goodbye_sender))),
+            rtt,
+            compression,
+            flow_control,
+            stop_tasks: StopTasks {
+                closed: stop_tasks_closed,
+                abort_handles,
+            },
         }
     }
 
+    /// Create an endpoint that sends and receives packets over `io`, for example a TCP
+    /// connection.
+    pub fn from_io<Io>(io: Io, service: Service) -> Self
+    where
+        Io: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        Self::builder().service(service).build_io(io)
+    }
+
     /// Create an endpoint without a server.
     ///
     /// Any request send to the endpoint will respond with a “method not found” error.
@@ -85,46 +596,997 @@ impl Endpoint {
         &mut self.client
     }
 
+    /// Identity of the peer if it was provided with [EndpointBuilder::peer].
+    pub fn peer(&self) -> Option<&ssb_box_stream::PublicKey> {
+        self.peer.as_ref()
+    }
+
+    /// Number of requests and streams that failed because a [Service] handler panicked.
+    ///
+    /// The peer receives a `HANDLER_PANIC` error for each of them while the endpoint keeps
+    /// serving other requests.
+    pub fn handler_panics(&self) -> u64 {
+        self.handler_panics.count()
+    }
+
+    /// Smoothed round trip time to the peer. `None` until the first probe returns or if
+    /// [EndpointConfig::rtt_probe_interval] is not set.
+    pub fn rtt(&self) -> Option<Duration> {
+        *self.rtt.lock().unwrap()
+    }
+
+    /// Ask the peer to enable compression with one of the algorithms added with
+    /// [EndpointBuilder::compression], trying them in order. Returns the name of the algorithm
+    /// that is used or `None` if the peer supports none of them.
+    ///
+    /// Only the request fails if the peer does not support compression at all.
+    pub async fn enable_compression(&mut self) -> anyhow::Result<Option<String>> {
+        let method = vec!["compression".to_string(), "enable".to_string()];
+        for algorithm in self.compression.algorithms() {
+            let name = algorithm.name().to_string();
+            // The peer may send compressed data before we receive its response.
+            let previous = self.compression.set_receive(Some(Arc::clone(&algorithm)));
+            let response = self
+                .client
+                .send_sync(method.clone(), vec![name.clone().into()])
+                .await;
+            match response {
+                Ok(AsyncResponse::Json(data))
+                    if serde_json::from_slice::<bool>(&data).unwrap_or(false) =>
+                {
+                    self.compression.set_send(Some(algorithm));
+                    return Ok(Some(name));
+                }
+                Ok(AsyncResponse::Error(_)) => {
+                    self.compression.set_receive(previous);
+                    return Ok(None);
+                }
+                Ok(_) => {
+                    self.compression.set_receive(previous);
+                }
+                Err(error) => {
+                    self.compression.set_receive(previous);
+                    return Err(error.into());
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Name of the algorithm that compresses stream data sent to the peer. `None` if compression
+    /// is not enabled.
+    pub fn compression(&self) -> Option<String> {
+        self.compression.name()
+    }
+
+    /// Ask the peer to enable flow control for the streams that are started from now on.
+    /// Returns `false` if either peer does not support flow control, for example because its
+    /// [EndpointConfig::stream_buffer] is unbounded. See [flow_control][super::flow_control].
+    pub async fn enable_flow_control(&mut self) -> anyhow::Result<bool> {
+        self.flow_control.enable(self.client.sender()).await
+    }
+
+    /// Returns `true` if the streams started from now on are flow controlled.
+    pub fn flow_control(&self) -> bool {
+        self.flow_control.is_enabled()
+    }
+
+    /// Handle to close the connection while the endpoint is used elsewhere, for example while
+    /// waiting for [Endpoint::join].
+    pub fn close_handle(&self) -> CloseHandle {
+        CloseHandle {
+            goodbye_sender: Arc::clone(&self.goodbye_sender),
+        }
+    }
+
+    /// Close the connection gracefully.
+    ///
+    /// Sends the end message for all streams the client has not ended yet, sends the goodbye
+    /// header and closes the connection once all packets have been written. Responses of the
+    /// server that are not ready yet are dropped.
+    pub async fn close(mut self) -> anyhow::Result<()> {
+        self.client.end_streams().await?;
+        self.close_handle().close();
+        self.packet_sender_task.await
+    }
+
+    /// Wait until the connection is closed and all tasks of the endpoint have finished.
+    ///
+    /// When the peer closes the connection request handlers that have not finished are cancelled.
+    /// This is a normal shutdown and returns `Ok` even if streams were still active. Errors
+    /// reading or writing packets while the connection is open are returned.
     pub async fn join(self) -> anyhow::Result<()> {
         let Endpoint {
+            client,
+            peer: _,
+            handler_panics: _,
+            flow_control: _,
             packet_reader_task,
             packet_sender_task,
             server_task,
-            ..
+            goodbye_sender: _,
+            rtt: _,
+            compression: _,
+            // Stops the tasks if the returned future is dropped
+            stop_tasks: _stop_tasks,
         } = self;
         futures::try_join!(
             packet_reader_task.map(|result| result.context("Failed to read incoming packet")),
             packet_sender_task,
-            server_task
+            server_task,
+            client.join().map_err(anyhow::Error::from)
         )?;
         Ok(())
     }
 }
 
+/// Options for reading packets in [dispatch_incoming_packet].
+struct ReadConfig {
+    idle_timeout: Option<Duration>,
+    max_body_len: Option<u32>,
+    /// CBOR bodies are rejected unless this is [BodyEncoding::Cbor]
+    body_encoding: BodyEncoding,
+    metrics: Option<Arc<dyn EndpointMetrics>>,
+    /// Decompress stream data. `None` if compression is not configured.
+    compression: Option<CompressionState>,
+    /// Peer and direction of the connection to label protocol violations
+    peer: Option<ssb_box_stream::PublicKey>,
+    direction: Option<&'static str>,
+}
+
 /// Parse packets from `stream` and send them to the appropriate channel.
 ///
-/// Errors once reading a packet errors.
+/// Errors once reading a packet errors or the peer sends nothing for `idle_timeout`. Closes the
+/// connection when it returns.
 async fn dispatch_incoming_packet<Stream_>(
+    stream: Stream_,
+    request_sender: futures::channel::mpsc::Sender<Request>,
+    response_sender: futures::channel::mpsc::Sender<Response>,
+    closed: ConnectionClosed,
+    config: ReadConfig,
+) -> Result<(), NextPacketError>
+where
+    Stream_: TryStream<Ok = Vec<u8>> + Unpin,
+    Stream_::Error: std::error::Error + Send + Sync + 'static,
+{
+    let result = read_packets(stream, request_sender, response_sender, config).await;
+    closed.close();
+    result
+}
+
+/// Time a `manifest` request every `interval` until the connection is closed or a request cannot
+/// be sent.
+#[cfg(not(target_arch = "wasm32"))]
+async fn probe_rtt(
+    mut sender: RequestSender,
+    interval: Duration,
+    rtt: Arc<Mutex<Option<Duration>>>,
+    metrics: Option<Arc<dyn EndpointMetrics>>,
+    closed: ConnectionClosed,
+) {
+    let probes = async move {
+        loop {
+            let start = std::time::Instant::now();
+            if let Err(error) = sender.send_sync(vec!["manifest".to_string()], vec![]).await {
+                tracing::debug!(%error, "stopping rtt probes");
+                return;
+            }
+            let sample = start.elapsed();
+            {
+                let mut rtt = rtt.lock().unwrap();
+                *rtt = Some(smooth_rtt(*rtt, sample));
+            }
+            if let Some(metrics) = &metrics {
+                metrics.rtt_measured(sample);
+            }
+            futures_timer::Delay::new(interval).await;
+        }
+    };
+    futures::pin_mut!(probes);
+    future::select(probes, closed.wait()).await;
+}
+
+/// Send a `manifest` request every `interval` unless other packets were sent in the meantime
+/// until the connection is closed or a request cannot be sent. `sent` is set whenever a packet is
+/// sent.
+async fn send_keep_alive(
+    mut sender: RequestSender,
+    interval: Duration,
+    sent: Arc<AtomicBool>,
+    closed: ConnectionClosed,
+) {
+    let pings = async move {
+        loop {
+            futures_timer::Delay::new(interval).await;
+            if sent.swap(false, Ordering::Relaxed) {
+                continue;
+            }
+            // Any response keeps the connection alive, even an error.
+            if let Err(error) = sender.send_sync(vec!["manifest".to_string()], vec![]).await {
+                tracing::debug!(%error, "stopping keep-alive");
+                return;
+            }
+            sent.store(false, Ordering::Relaxed);
+        }
+    };
+    futures::pin_mut!(pings);
+    future::select(pings, closed.wait()).await;
+}
+
+/// Exponentially weighted moving average of round trip times like the smoothed RTT of TCP
+/// (RFC 6298).
+pub(crate) fn smooth_rtt(previous: Option<Duration>, sample: Duration) -> Duration {
+    match previous {
+        Some(previous) => (previous * 7 + sample) / 8,
+        None => sample,
+    }
+}
+
+async fn read_packets<Stream_>(
     stream: Stream_,
     mut request_sender: futures::channel::mpsc::Sender<Request>,
     mut response_sender: futures::channel::mpsc::Sender<Response>,
+    config: ReadConfig,
 ) -> Result<(), NextPacketError>
 where
     Stream_: TryStream<Ok = Vec<u8>> + Unpin,
     Stream_::Error: std::error::Error + Send + Sync + 'static,
 {
-    let mut packet_stream = PacketStream::new(stream);
+    let ReadConfig {
+        idle_timeout,
+        max_body_len,
+        body_encoding,
+        metrics,
+        compression,
+        peer,
+        direction,
+    } = config;
+    let mut packet_stream = match compression {
+        Some(compression) => PacketStream::with_decompress(
+            stream,
+            Box::new(move |data, max_len| compression.decompress(data, max_len)),
+        ),
+        None => PacketStream::new(stream),
+    };
+    packet_stream.set_max_body_len(max_body_len);
+    packet_stream.set_body_encoding(body_encoding);
     loop {
-        let next_item = packet_stream.try_next().await?;
+        let next_item = match idle_timeout {
+            Some(timeout) => {
+                let delay = futures_timer::Delay::new(timeout);
+                match future::select(packet_stream.try_next(), delay).await {
+                    future::Either::Left((next_item, _)) => next_item,
+                    future::Either::Right(((), _)) => {
+                        return Err(NextPacketError::IdleTimeout(timeout))
+                    }
+                }
+            }
+            None => packet_stream.try_next().await,
+        };
+        let next_item = match next_item {
+            Ok(next_item) => next_item,
+            Err(error) => {
+                if let Some(violation) =
+                    ProtocolViolation::from_error(&error, packet_stream.rejected())
+                {
+                    report_violation(&violation, peer.as_ref(), direction, metrics.as_deref());
+                }
+                return Err(error);
+            }
+        };
         if let Some(packet) = next_item {
-            match packet {
+            if let Some(metrics) = &metrics {
+                metrics.packet_received(&packet);
+            }
+            let result = match packet {
                 Packet::Request(request) => request_sender.send(request).await,
                 Packet::Response(response) => response_sender.send(response).await,
+            };
+            if result.is_err() {
+                // The server or the client stopped. The task that stopped reports the reason.
+                tracing::debug!("packet receiver closed");
+                return Ok(());
             }
-            .expect("Failed to forward packet")
         } else {
             tracing::debug!("end of endpoint stream");
             return Ok(());
         }
     }
 }
+
+/// Log `violation` and report it to `metrics`.
+fn report_violation(
+    violation: &ProtocolViolation,
+    peer: Option<&ssb_box_stream::PublicKey>,
+    direction: Option<&'static str>,
+    metrics: Option<&dyn EndpointMetrics>,
+) {
+    tracing::warn!(
+        peer = %peer.map_or_else(|| "unknown".to_string(), |peer| peer.to_string()),
+        direction = direction.unwrap_or("unknown"),
+        kind = ?violation.kind,
+        bytes = %violation.hexdump(),
+        "protocol violation: {}",
+        violation.message,
+    );
+    if let Some(metrics) = metrics {
+        metrics.protocol_violation(violation);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::base::packet::RequestType;
+    use crate::rpc::base::service::{AsyncResponse, SinkClosed};
+    use crate::rpc::base::stream_request::{StreamRequest, StreamRequestType};
+    use crate::rpc::base::{Body, StreamMessage, TypedSource};
+    use futures::channel::mpsc;
+
+    #[async_std::test]
+    async fn small_buffers() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let config = EndpointConfig {
+            request_buffer: 0,
+            response_buffer: 0,
+            stream_buffer: Some(0),
+            write_batch: 1,
+            ..EndpointConfig::default()
+        };
+        let mut service = Service::new();
+        service.add_source("count", |(n,): (u32,)| {
+            futures::stream::iter(0..n).map(|i| Ok(Body::json(&i)))
+        });
+        service.add_async("echo", |(value,): (u32,)| async move {
+            AsyncResponse::json_ok(&value)
+        });
+
+        let (a_to_b_sender, a_to_b_receiver) = mpsc::channel(0);
+        let (b_to_a_sender, b_to_a_receiver) = mpsc::channel(0);
+        let mut endpoint_a = Endpoint::with_config(
+            a_to_b_sender,
+            b_to_a_receiver.map(Ok::<_, std::io::Error>),
+            Service::new(),
+            config.clone(),
+        );
+        let _endpoint_b = Endpoint::with_config(
+            b_to_a_sender,
+            a_to_b_receiver.map(Ok::<_, std::io::Error>),
+            service,
+            config,
+        );
+
+        let client = endpoint_a.client();
+        let source = client
+            .start_source(vec!["count".to_string()], vec![serde_json::json!(100)])
+            .await
+            .unwrap();
+        let items = TypedSource::<u32>::new(source)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(items, (0..100).collect::<Vec<_>>());
+
+        let response = client
+            .send_async(vec!["echo".to_string()], vec![serde_json::json!(42)])
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            crate::rpc::base::AsyncResponse::Json(b"42".to_vec())
+        );
+    }
+
+    #[cfg(feature = "cbor")]
+    #[async_std::test]
+    async fn cbor_bodies() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let config = EndpointConfig {
+            body_encoding: BodyEncoding::Cbor,
+            ..EndpointConfig::default()
+        };
+        let mut service = Service::new();
+        service.add_async("echo", |(value,): (serde_json::Value,)| async move {
+            AsyncResponse::json_ok(&value)
+        });
+
+        let (a_to_b_sender, a_to_b_receiver) = mpsc::channel(0);
+        let (b_to_a_sender, b_to_a_receiver) = mpsc::channel(0);
+        let mut endpoint_a = Endpoint::with_config(
+            a_to_b_sender,
+            b_to_a_receiver.map(Ok::<_, std::io::Error>),
+            Service::new(),
+            config.clone(),
+        );
+        let _endpoint_b = Endpoint::with_config(
+            b_to_a_sender,
+            a_to_b_receiver.map(Ok::<_, std::io::Error>),
+            service,
+            config,
+        );
+
+        let value = serde_json::json!({ "a": [1, "two", null] });
+        let response = endpoint_a
+            .client()
+            .send_async(vec!["echo".to_string()], vec![value.clone()])
+            .await
+            .unwrap();
+        let reader = response.json_reader().unwrap();
+        assert_eq!(
+            serde_json::from_reader::<_, serde_json::Value>(reader).unwrap(),
+            value
+        );
+    }
+
+    #[async_std::test]
+    async fn close() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        // The source never yields an item. We only observe when the server drops it.
+        let (mut live_sender, live_receiver) = futures::channel::oneshot::channel();
+        let live_receiver = std::sync::Mutex::new(Some(live_receiver));
+        let mut service = Service::new();
+        service.add_source("live", move |_: Vec<()>| {
+            let receiver = live_receiver.lock().unwrap().take().unwrap();
+            crate::utils::OneshotStream::new(receiver)
+        });
+
+        let (a_to_b_sender, a_to_b_receiver) = mpsc::channel(10);
+        let (b_to_a_sender, b_to_a_receiver) = mpsc::channel(10);
+        let mut endpoint_a = Endpoint::new(
+            a_to_b_sender,
+            b_to_a_receiver.map(Ok::<_, std::io::Error>),
+            Service::new(),
+        );
+        let endpoint_b = Endpoint::new(
+            b_to_a_sender,
+            a_to_b_receiver.map(Ok::<_, std::io::Error>),
+            service,
+        );
+
+        let _source = endpoint_a
+            .client()
+            .start_source(vec!["live".to_string()], vec![])
+            .await
+            .unwrap();
+        endpoint_a.close().await.unwrap();
+
+        // The server ends the stream and stops reading after the goodbye.
+        live_sender.cancellation().await;
+        endpoint_b.packet_reader_task.await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn drop_stops_tasks() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let (peer_sender, peer_receiver) = mpsc::channel::<Vec<u8>>(10);
+        let (endpoint_sender, mut endpoint_receiver) = mpsc::channel(10);
+        let mut endpoint =
+            Endpoint::new_client(endpoint_sender, peer_receiver.map(Ok::<_, std::io::Error>));
+        let mut sender = endpoint.client().sender();
+        let source = sender
+            .start_source(vec!["live".to_string()], vec![])
+            .await
+            .unwrap();
+        let request = async_std::task::spawn(async move {
+            sender.send_async(vec!["hang".to_string()], vec![]).await
+        });
+        // Both requests were written and the peer never answers
+        endpoint_receiver.next().await.unwrap();
+        endpoint_receiver.next().await.unwrap();
+
+        drop(endpoint);
+        let result = async_std::future::timeout(Duration::from_secs(5), request)
+            .await
+            .expect("request still pending");
+        assert!(matches!(
+            result,
+            Err(crate::rpc::base::AsyncRequestError::Closed)
+        ));
+        async_std::future::timeout(Duration::from_secs(5), source.collect::<Vec<_>>())
+            .await
+            .expect("stream did not end");
+        assert!(peer_sender.is_closed());
+    }
+
+    #[async_std::test]
+    async fn peer_disconnects() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        // The source never yields an item. We only observe when the server drops it.
+        let (mut live_sender, live_receiver) = futures::channel::oneshot::channel::<Body>();
+        let live_receiver = std::sync::Mutex::new(Some(live_receiver));
+        let mut service = Service::new();
+        service.add_source("live", move |_: Vec<()>| {
+            let receiver = live_receiver.lock().unwrap().take().unwrap();
+            crate::utils::OneshotStream::new(receiver).map(Ok)
+        });
+        service.add_async("hang", |_: Vec<()>| future::pending::<AsyncResponse>());
+
+        let (mut peer_sender, peer_receiver) = mpsc::channel(10);
+        let (endpoint_sender, endpoint_receiver) = mpsc::channel(10);
+        let endpoint = Endpoint::new(
+            endpoint_sender,
+            peer_receiver.map(Ok::<_, std::io::Error>),
+            service,
+        );
+
+        let requests = vec![
+            Request::Async {
+                number: 1,
+                method: vec!["hang".to_string()],
+                type_: RequestType::Async,
+                args: vec![],
+            },
+            StreamRequest {
+                name: vec!["live".to_string()],
+                type_: StreamRequestType::Source,
+                args: vec![],
+            }
+            .into_request(2),
+        ];
+        for request in requests {
+            peer_sender
+                .send(Packet::Request(request).build())
+                .await
+                .unwrap();
+        }
+        drop(peer_sender);
+        drop(endpoint_receiver);
+
+        // Handlers are cancelled and the endpoint shuts down without an error.
+        live_sender.cancellation().await;
+        async_std::future::timeout(std::time::Duration::from_secs(5), endpoint.join())
+            .await
+            .expect("endpoint did not shut down")
+            .unwrap();
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct PacketCounter {
+        received: Arc<std::sync::atomic::AtomicUsize>,
+        sent: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl EndpointMetrics for PacketCounter {
+        fn packet_received(&self, _packet: &Packet) {
+            self.received
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+
+        fn packet_sent(&self, _packet: &Packet) {
+            self.sent.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[async_std::test]
+    async fn builder() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let mut service = Service::new();
+        service.add_async("echo", |(value,): (u32,)| async move {
+            AsyncResponse::json_ok(&value)
+        });
+        let peer = ssb_box_stream::PublicKey([7; 32]);
+        let counter = PacketCounter::default();
+        let (sender_a, receiver_a) = mpsc::channel(10);
+        let (sender_b, receiver_b) = mpsc::channel(10);
+        let mut endpoint_a =
+            Endpoint::new_client(sender_a, receiver_b.map(Ok::<_, std::io::Error>));
+        let endpoint_b = Endpoint::builder()
+            .service(service)
+            .peer(peer)
+            .metrics(counter.clone())
+            .max_concurrent_requests(1)
+            .build(sender_b, receiver_a.map(Ok::<_, std::io::Error>));
+
+        assert_eq!(endpoint_b.peer(), Some(&peer));
+        assert_eq!(endpoint_a.peer(), None);
+        let response = endpoint_a
+            .client()
+            .send_async(vec!["echo".to_string()], vec![serde_json::json!(42)])
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            crate::rpc::base::AsyncResponse::Json(b"42".to_vec())
+        );
+        assert_eq!(
+            counter.received.load(std::sync::atomic::Ordering::SeqCst),
+            1
+        );
+        assert_eq!(counter.sent.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[derive(Debug, Clone)]
+    struct RttSamples(mpsc::UnboundedSender<Duration>);
+
+    impl EndpointMetrics for RttSamples {
+        fn rtt_measured(&self, rtt: Duration) {
+            let _ = self.0.unbounded_send(rtt);
+        }
+    }
+
+    #[async_std::test]
+    async fn rtt_probe() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let (samples_sender, samples) = mpsc::unbounded();
+        let (sender_a, receiver_a) = mpsc::channel(10);
+        let (sender_b, receiver_b) = mpsc::channel(10);
+        let endpoint_a = Endpoint::builder()
+            .rtt_probe_interval(Duration::from_millis(10))
+            .metrics(RttSamples(samples_sender))
+            .build(sender_a, receiver_b.map(Ok::<_, std::io::Error>));
+        let endpoint_b = Endpoint::new_client(sender_b, receiver_a.map(Ok::<_, std::io::Error>));
+        assert_eq!(endpoint_b.rtt(), None);
+
+        // The peer answers `manifest` with an error which counts as well
+        let samples =
+            async_std::future::timeout(Duration::from_secs(5), samples.take(3).collect::<Vec<_>>())
+                .await
+                .expect("no rtt samples");
+        assert_eq!(samples.len(), 3);
+        assert!(endpoint_a.rtt().is_some());
+
+        // Probes do not keep the connection open
+        endpoint_b.close().await.unwrap();
+        async_std::future::timeout(Duration::from_secs(5), endpoint_a.join())
+            .await
+            .expect("endpoint did not shut down")
+            .unwrap();
+    }
+
+    /// Run-length encoding as `(count, byte)` pairs.
+    struct RunLength;
+
+    impl Compression for RunLength {
+        fn name(&self) -> &str {
+            "rle"
+        }
+
+        fn compress(&self, data: &[u8]) -> Vec<u8> {
+            let mut compressed = Vec::new();
+            for byte in data {
+                match compressed.as_mut_slice() {
+                    [.., count, last] if last == byte && *count < u8::MAX => *count += 1,
+                    _ => compressed.extend_from_slice(&[1, *byte]),
+                }
+            }
+            compressed
+        }
+
+        fn decompress(&self, data: &[u8], max_len: usize) -> std::io::Result<Vec<u8>> {
+            let pairs = data.chunks_exact(2);
+            if !pairs.remainder().is_empty() {
+                return Err(std::io::ErrorKind::InvalidData.into());
+            }
+            Ok(pairs
+                .flat_map(|pair| std::iter::repeat_n(pair[1], pair[0] as usize))
+                .take(max_len.saturating_add(1))
+                .collect())
+        }
+    }
+
+    #[async_std::test]
+    async fn compression() {
+        compressed_source(RunLength, RunLength).await;
+    }
+
+    #[cfg(feature = "deflate")]
+    #[async_std::test]
+    async fn deflate_compression() {
+        use super::super::compression::Deflate;
+        compressed_source(Deflate::default(), Deflate::new(9)).await;
+    }
+
+    /// Enable compression between two endpoints and check that a source is received unchanged
+    /// while fewer bytes are sent.
+    async fn compressed_source(algorithm_a: impl Compression, algorithm_b: impl Compression) {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let name = algorithm_a.name().to_string();
+        let mut service = Service::new();
+        service.add_source("repeat", |(n,): (usize,)| {
+            futures::stream::iter(0..3).map(move |_| Ok(Body::json(&"a".repeat(n))))
+        });
+        let (sender_a, receiver_a) = mpsc::channel::<Vec<u8>>(10);
+        let (sender_b, receiver_b) = mpsc::channel::<Vec<u8>>(10);
+        // Record the bytes that the server sends
+        let (sent_sender, sent) = mpsc::unbounded();
+        let sender_a = sender_a.with(move |data: Vec<u8>| {
+            let _ = sent_sender.unbounded_send(data.len());
+            future::ok::<_, mpsc::SendError>(data)
+        });
+        let _endpoint_a = Endpoint::builder()
+            .service(service)
+            .compression(algorithm_a)
+            .build(Box::pin(sender_a), receiver_b.map(Ok::<_, std::io::Error>));
+        let mut endpoint_b = Endpoint::builder()
+            .compression(algorithm_b)
+            .build(sender_b, receiver_a.map(Ok::<_, std::io::Error>));
+
+        assert_eq!(endpoint_b.compression(), None);
+        let enabled = endpoint_b.enable_compression().await.unwrap();
+        assert_eq!(enabled, Some(name.clone()));
+        assert_eq!(endpoint_b.compression(), Some(name));
+
+        let items = endpoint_b
+            .client()
+            .start_source(vec!["repeat".to_string()], vec![1000.into()])
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(items, vec![Body::json(&"a".repeat(1000)); 3]);
+        drop(endpoint_b);
+        let sent = sent.collect::<Vec<_>>().await.into_iter().sum::<usize>();
+        assert!(sent < 1000, "sent {} bytes", sent);
+    }
+
+    #[async_std::test]
+    async fn compression_not_supported() {
+        let (sender_a, receiver_a) = mpsc::channel(10);
+        let (sender_b, receiver_b) = mpsc::channel(10);
+        let _endpoint_a = Endpoint::new_client(sender_a, receiver_b.map(Ok::<_, std::io::Error>));
+        let mut endpoint_b = Endpoint::builder()
+            .compression(RunLength)
+            .build(sender_b, receiver_a.map(Ok::<_, std::io::Error>));
+        assert_eq!(endpoint_b.enable_compression().await.unwrap(), None);
+        assert_eq!(endpoint_b.compression(), None);
+    }
+
+    #[async_std::test]
+    async fn idle_timeout() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let (_peer_sender, peer_receiver) = mpsc::channel::<Vec<u8>>(10);
+        let (endpoint_sender, _endpoint_receiver) = mpsc::channel(10);
+        let endpoint = Endpoint::builder()
+            .idle_timeout(Duration::from_millis(20))
+            .build(endpoint_sender, peer_receiver.map(Ok::<_, std::io::Error>));
+
+        let error = async_std::future::timeout(Duration::from_secs(5), endpoint.join())
+            .await
+            .expect("endpoint did not time out")
+            .unwrap_err();
+        assert!(
+            matches!(
+                error.downcast_ref::<NextPacketError>(),
+                Some(NextPacketError::IdleTimeout(_))
+            ),
+            "{:?}",
+            error
+        );
+    }
+
+    #[async_std::test]
+    async fn keep_alive() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let mut service = Service::new();
+        service.add_async("echo", |(value,): (u32,)| async move {
+            AsyncResponse::json_ok(&value)
+        });
+        let (sender_a, receiver_a) = mpsc::channel(10);
+        let (sender_b, receiver_b) = mpsc::channel(10);
+        let mut endpoint_a = Endpoint::builder()
+            .idle_timeout(Duration::from_millis(100))
+            .keep_alive(Duration::from_millis(20))
+            .build(sender_a, receiver_b.map(Ok::<_, std::io::Error>));
+        let endpoint_b = Endpoint::builder()
+            .service(service)
+            .idle_timeout(Duration::from_millis(100))
+            .build(sender_b, receiver_a.map(Ok::<_, std::io::Error>));
+
+        // Both peers would have timed out several times without the keep-alive of one of them
+        async_std::task::sleep(Duration::from_millis(500)).await;
+        let response = endpoint_a
+            .client()
+            .send_async(vec!["echo".to_string()], vec![serde_json::json!(42)])
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            crate::rpc::base::AsyncResponse::Json(b"42".to_vec())
+        );
+
+        endpoint_a.close().await.unwrap();
+        async_std::future::timeout(Duration::from_secs(5), endpoint_b.join())
+            .await
+            .expect("endpoint did not shut down")
+            .unwrap();
+    }
+
+    #[async_std::test]
+    async fn stream_multiplexing() {
+        let _ = tracing_subscriber::fmt::try_init();
+        const STREAMS: u32 = 300;
+        const MESSAGES: u32 = 20;
+
+        let mut service = Service::new();
+        service.add_duplex("echo", |_: Vec<()>| {
+            let (sender, receiver) = mpsc::unbounded();
+            let sink = futures::sink::unfold(
+                Some(sender),
+                |sender: Option<mpsc::UnboundedSender<_>>, message| async move {
+                    match (sender, message) {
+                        (Some(sender), StreamMessage::Data(body)) => {
+                            let _ = sender.unbounded_send(Ok(body));
+                            Ok::<_, SinkClosed>(Some(sender))
+                        }
+                        // Dropping the sender ends the source.
+                        _ => Ok(None),
+                    }
+                },
+            );
+            (receiver, sink)
+        });
+        let (sender_a, receiver_a) = mpsc::channel(10);
+        let (sender_b, receiver_b) = mpsc::channel(10);
+        let mut endpoint_a =
+            Endpoint::new_client(sender_a, receiver_b.map(Ok::<_, std::io::Error>));
+        // Without flow control a stream fails if its buffer overflows, so every stream can buffer
+        // all its messages.
+        let _endpoint_b = Endpoint::builder()
+            .service(service)
+            .stream_buffer(Some(MESSAGES as usize))
+            .build(sender_b, receiver_a.map(Ok::<_, std::io::Error>));
+
+        // Messages of all streams are interleaved on the connection. Each stream must receive
+        // its own messages in order.
+        let streams = (0..STREAMS).map(|stream| {
+            let mut sender = endpoint_a.client().sender();
+            async move {
+                let (source, mut sink) = sender
+                    .start_duplex(vec!["echo".to_string()], vec![])
+                    .await
+                    .unwrap();
+                let receive = source
+                    .map(|body| body.unwrap().decode_json::<(u32, u32)>().unwrap())
+                    .collect::<Vec<_>>();
+                let send = async move {
+                    for index in 0..MESSAGES {
+                        sink.send(Body::json(&(stream, index))).await.unwrap();
+                        async_std::task::yield_now().await;
+                    }
+                    sink.close().await.unwrap();
+                };
+                let (received, ()) = future::join(receive, send).await;
+                let expected = (0..MESSAGES)
+                    .map(|index| (stream, index))
+                    .collect::<Vec<_>>();
+                assert_eq!(received, expected);
+            }
+        });
+        async_std::future::timeout(Duration::from_secs(30), future::join_all(streams))
+            .await
+            .expect("streams did not finish");
+    }
+
+    #[async_std::test]
+    async fn stalled_sink() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let mut service = Service::new();
+        service.add_sink("stalled", |_: Vec<()>| {
+            futures::sink::drain::<StreamMessage>()
+                .sink_map_err(|infallible| match infallible {})
+                .with(|_: StreamMessage| future::pending())
+        });
+        service.add_sync("ping", |_: Vec<()>| AsyncResponse::json_ok(&"pong"));
+        let (sender_a, receiver_a) = mpsc::channel(10);
+        let (sender_b, receiver_b) = mpsc::channel(10);
+        let mut endpoint_a =
+            Endpoint::new_client(sender_a, receiver_b.map(Ok::<_, std::io::Error>));
+        let _endpoint_b = Endpoint::builder()
+            .service(service)
+            .stream_buffer(Some(1))
+            .build(sender_b, receiver_a.map(Ok::<_, std::io::Error>));
+
+        let client = endpoint_a.client();
+        let (mut source, mut sink) = client
+            .start_sink(vec!["stalled".to_string()], vec![])
+            .await
+            .unwrap();
+        // The handler never consumes these messages. The stream fails once its buffer overflows.
+        // Sending may fail after that.
+        for index in 0..100 {
+            let _ = sink.send(Body::json(&index)).await;
+        }
+        let error = async_std::future::timeout(Duration::from_secs(10), source.next())
+            .await
+            .expect("stalled stream did not fail")
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(error.name, "STREAM_BUFFER_FULL");
+        let response = async_std::future::timeout(
+            Duration::from_secs(10),
+            client.send_sync(vec!["ping".to_string()], vec![]),
+        )
+        .await
+        .expect("stalled sink blocked the request")
+        .unwrap();
+        assert_eq!(
+            response,
+            crate::rpc::base::AsyncResponse::Json(b"\"pong\"".to_vec())
+        );
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct ViolationRecorder(Arc<Mutex<Vec<ProtocolViolation>>>);
+
+    impl EndpointMetrics for ViolationRecorder {
+        fn protocol_violation(&self, violation: &ProtocolViolation) {
+            self.0.lock().unwrap().push(violation.clone());
+        }
+    }
+
+    #[async_std::test]
+    async fn protocol_violation() {
+        use crate::rpc::base::ViolationKind;
+        let _ = tracing_subscriber::fmt::try_init();
+
+        // Request number zero is invalid with every feature set.
+        let invalid_header = vec![2, 0, 0, 0, 0, 0, 0, 0, 0];
+        let oversized = vec![2, 0, 0, 0x10, 0, 0, 0, 0, 1];
+        // CBOR body on a connection that uses JSON
+        let cbor = vec![3, 0, 0, 0, 1, 0, 0, 0, 1, 0xf5];
+        for (data, kind) in [
+            (invalid_header, ViolationKind::InvalidHeader),
+            (oversized, ViolationKind::OversizedPacket),
+            (cbor, ViolationKind::InvalidBody),
+            (
+                vec![2, 0, 0, 0, 10, 0, 0, 0, 1, b'{'],
+                ViolationKind::Truncated,
+            ),
+        ] {
+            let recorder = ViolationRecorder::default();
+            let (mut peer_sender, peer_receiver) = mpsc::channel(10);
+            let (endpoint_sender, _endpoint_receiver) = mpsc::channel(10);
+            let endpoint = Endpoint::builder()
+                .metrics(recorder.clone())
+                .peer(ssb_box_stream::PublicKey([7; 32]))
+                .max_body_len(1024)
+                .build(endpoint_sender, peer_receiver.map(Ok::<_, std::io::Error>));
+            peer_sender.send(data.clone()).await.unwrap();
+            drop(peer_sender);
+
+            endpoint.join().await.unwrap_err();
+            let violations = recorder.0.lock().unwrap();
+            assert_eq!(violations.len(), 1);
+            assert_eq!(violations[0].kind, kind);
+            if kind == ViolationKind::Truncated {
+                assert_eq!(violations[0].bytes, Vec::<u8>::new());
+            } else {
+                assert_eq!(violations[0].bytes, data);
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn from_io() {
+        let mut service = Service::new();
+        service.add_async("echo", |(value,): (u32,)| async move {
+            AsyncResponse::json_ok(&value)
+        });
+        let (io_a, io_b) = async_std::os::unix::net::UnixStream::pair().unwrap();
+        let mut endpoint_a = Endpoint::from_io(io_a, Service::new());
+        let _endpoint_b = Endpoint::from_io(io_b, service);
+
+        let response = endpoint_a
+            .client()
+            .send_async(vec!["echo".to_string()], vec![serde_json::json!(42)])
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            crate::rpc::base::AsyncResponse::Json(b"42".to_vec())
+        );
+    }
+}
@@ -0,0 +1,538 @@
+//! Per-stream flow control between peers that both support it.
+//!
+//! Without flow control the peer sends stream messages as fast as the connection allows. A
+//! stream whose messages are not consumed then either buffers them without limit or, with a
+//! bounded [EndpointConfig::stream_buffer][super::EndpointConfig::stream_buffer], stops the
+//! endpoint from reading the connection, which also stalls all other streams.
+//!
+//! With flow control every stream has a window of [EndpointConfig::stream_buffer] messages. The
+//! peer only sends as many messages as we have granted it credit for and we grant more credit as
+//! the application consumes messages. A stream that is not read, for example a paused
+//! [PausableSource][super::PausableSource], stops its sender once the window is used up while
+//! the other streams keep going.
+//!
+//! Flow control is off by default because other muxrpc implementations do not know about it. An
+//! [Endpoint][super::Endpoint] with a bounded stream buffer serves the `flowControl.open` method.
+//! [Endpoint::enable_flow_control][super::Endpoint::enable_flow_control] asks the peer to enable
+//! flow control. Only streams that are started once both peers agreed are flow controlled.
+//!
+//! # Protocol
+//!
+//! The peer that enables flow control starts a `duplex` stream `flowControl.open` with its
+//! window as the only argument. The other peer sends its own window as the first message. Both
+//! peers then use the stream to send credit as `[number, credit]` JSON arrays. `number` is the
+//! request number of the stream as it appears in the packets the peer sending the credit sends
+//! on that stream. That is, it is positive for streams started by that peer and negative
+//! otherwise. `credit` is the number of additional data messages the other peer may send.
+//!
+//! A stream is flow controlled if the body of the request that starts it has a `credit` field.
+//! Its value is the number of messages the server may send before it receives more credit. The
+//! client may send as many messages as the window of the server before it receives more credit.
+//! Requests with a `credit` field are only sent once the peer has agreed to flow control.
+//!
+//! Sending more messages than granted is a protocol violation that closes the connection.
+use futures::prelude::*;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+
+use super::client::RequestSender;
+use super::packet::{Body, Request};
+use super::service::{Service, SinkClosed};
+use super::stream_message::StreamMessage;
+use super::stream_request::StreamRequest;
+use crate::utils::task::spawn;
+
+/// Name of the service that serves the `open` method.
+pub(super) const SERVICE_NAME: &str = "flowControl";
+
+/// Credit granted to the peer and received from it for all flow controlled streams of an
+/// endpoint. Clones share the state.
+#[derive(Clone, Default)]
+pub(super) struct FlowControl {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Number of messages we buffer for a stream. Flow control is not supported if `None`.
+    window: Option<u32>,
+    /// Window of the peer. Set once the peer has agreed to flow control.
+    peer_window: Option<u32>,
+    /// Sends credit to the peer over the `flowControl.open` stream.
+    grants: Option<futures::channel::mpsc::UnboundedSender<(i32, u32)>>,
+    /// Credit for sending on flow controlled streams, keyed by the number we send on the stream.
+    credit: HashMap<i32, Weak<CreditCell>>,
+    /// Set when the connection is closed.
+    closed: bool,
+}
+
+impl std::fmt::Debug for FlowControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        f.debug_struct("FlowControl")
+            .field("window", &inner.window)
+            .field("peer_window", &inner.peer_window)
+            .field("streams", &inner.credit.len())
+            .finish()
+    }
+}
+
+impl FlowControl {
+    /// Flow control with a window of `window` messages per stream. Flow control is not
+    /// supported if `window` is `None`.
+    pub(super) fn new(window: Option<usize>) -> Self {
+        // A window of zero would never allow a message.
+        let window = window.map(|window| window.clamp(1, u32::MAX as usize) as u32);
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                window,
+                ..Inner::default()
+            })),
+        }
+    }
+
+    /// Returns `true` once both peers agreed to flow control.
+    pub(super) fn is_enabled(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.peer_window.is_some() && inner.grants.is_some()
+    }
+
+    /// Ask the peer to enable flow control through `sender`. Returns `false` if the peer does
+    /// not support it.
+    pub(super) async fn enable(&self, mut sender: RequestSender) -> anyhow::Result<bool> {
+        if self.is_enabled() {
+            return Ok(true);
+        }
+        let window = match self.inner.lock().unwrap().window {
+            Some(window) => window,
+            None => return Ok(false),
+        };
+        // The peer may start flow controlled streams as soon as it received the request. We must
+        // be able to grant credit for them.
+        let (grants_sender, grants) = futures::channel::mpsc::unbounded();
+        self.inner.lock().unwrap().grants = Some(grants_sender);
+        let disable = || self.inner.lock().unwrap().grants = None;
+        let method = vec![SERVICE_NAME.to_string(), "open".to_string()];
+        let (mut source, mut sink) = match sender
+            .start_duplex(method, vec![serde_json::json!(window)])
+            .await
+        {
+            Ok(stream) => stream,
+            Err(error) => {
+                disable();
+                return Err(error);
+            }
+        };
+        let peer_window = match source.next().await {
+            Some(Ok(body)) => body.decode_json::<u32>().ok(),
+            _ => None,
+        };
+        let peer_window = match peer_window {
+            Some(peer_window) => peer_window,
+            None => {
+                disable();
+                // We don’t care if the peer already ended the stream.
+                let _ = sink.close().await;
+                return Ok(false);
+            }
+        };
+        self.inner.lock().unwrap().peer_window = Some(peer_window);
+
+        spawn("rpc flow control send credit", async move {
+            let mut grants = grants;
+            while let Some(grant) = grants.next().await {
+                if sink.send(Body::json(&grant)).await.is_err() {
+                    break;
+                }
+                // Credit is urgent because the peer may be waiting for it.
+                let _ = sink.flush().await;
+            }
+        });
+        let state = self.clone();
+        spawn("rpc flow control receive credit", async move {
+            while let Some(Ok(body)) = source.next().await {
+                state.receive_grant(&body);
+            }
+        });
+        Ok(true)
+    }
+
+    /// Service with the `open` method that the peer calls to enable flow control.
+    pub(super) fn service(&self) -> Service {
+        let state = self.clone();
+        let mut service = Service::new();
+        service.add_duplex("open", move |(peer_window,): (u32,)| {
+            let (grants_sender, grants) = futures::channel::mpsc::unbounded();
+            let window = {
+                let mut inner = state.inner.lock().unwrap();
+                inner.grants = Some(grants_sender);
+                inner.peer_window = Some(peer_window);
+                inner.window
+            };
+            let source = stream::once(future::ready(Ok(Body::json(&window))))
+                .chain(grants.map(|grant| Ok(Body::json(&grant))));
+            let state = state.clone();
+            let sink = sink::unfold((), move |(), message: StreamMessage| {
+                let result = match message {
+                    StreamMessage::Data(body) => {
+                        state.receive_grant(&body);
+                        Ok(())
+                    }
+                    StreamMessage::Error(_) | StreamMessage::End => Err(SinkClosed),
+                };
+                future::ready(result)
+            });
+            (source, sink)
+        });
+        service
+    }
+
+    /// Window of both peers if flow control is enabled.
+    pub(super) fn windows(&self) -> Option<(u32, u32)> {
+        let inner = self.inner.lock().unwrap();
+        inner.grants.as_ref()?;
+        Some((inner.window?, inner.peer_window?))
+    }
+
+    /// Start flow control for the stream that we send on with `number`. We may send `credit`
+    /// messages before the peer grants more.
+    ///
+    /// Returns `None` if we don’t support flow control.
+    pub(super) fn open_stream(
+        &self,
+        number: i32,
+        credit: u32,
+    ) -> Option<(SendCredit, ReceiveWindow)> {
+        let mut inner = self.inner.lock().unwrap();
+        let window = inner.window?;
+        inner.grants.as_ref()?;
+        inner.credit.retain(|_, cell| cell.strong_count() > 0);
+        let cell = Arc::new(CreditCell {
+            state: Mutex::new(CreditState {
+                available: credit,
+                closed: inner.closed,
+                waker: None,
+            }),
+        });
+        inner.credit.insert(number, Arc::downgrade(&cell));
+        let receive_window = ReceiveWindow {
+            inner: Arc::new(WindowInner {
+                number,
+                window,
+                outstanding: AtomicU32::new(window),
+                consumed: AtomicU32::new(0),
+                flow_control: self.clone(),
+            }),
+        };
+        Some((SendCredit { cell }, receive_window))
+    }
+
+    /// Stop waiting for credit because the connection is closed.
+    pub(super) fn close(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.closed = true;
+        inner.grants = None;
+        for cell in inner.credit.drain().filter_map(|(_, cell)| cell.upgrade()) {
+            cell.close();
+        }
+    }
+
+    fn receive_grant(&self, body: &Body) {
+        let (number, credit) = match body.decode_json::<(i32, u32)>() {
+            Ok(grant) => grant,
+            Err(error) => {
+                tracing::debug!(?error, "invalid credit from peer");
+                return;
+            }
+        };
+        // The peer names the stream by the number it sends on it.
+        let number = number.wrapping_neg();
+        let cell = self
+            .inner
+            .lock()
+            .unwrap()
+            .credit
+            .get(&number)
+            .and_then(Weak::upgrade);
+        // The stream may have ended already.
+        if let Some(cell) = cell {
+            cell.add(credit);
+        }
+    }
+
+    fn grant(&self, number: i32, credit: u32) {
+        if let Some(grants) = &self.inner.lock().unwrap().grants {
+            let _ = grants.unbounded_send((number, credit));
+        }
+    }
+}
+
+/// Add a `credit` field to the body of the request that starts a flow controlled stream.
+pub(super) fn stream_request(request: StreamRequest, number: u32, credit: Option<u32>) -> Request {
+    let credit = match credit {
+        Some(credit) => credit,
+        None => return request.into_request(number),
+    };
+    let mut body = serde_json::to_value(&request).expect("stream requests are valid JSON");
+    body["credit"] = serde_json::json!(credit);
+    StreamMessage::Data(Body::json(&body)).into_request(number)
+}
+
+/// Credit the peer granted in the request that starts a stream. `None` if the stream is not
+/// flow controlled.
+pub(super) fn requested_credit(body: &Body) -> Option<u32> {
+    #[derive(serde::Deserialize)]
+    struct Credit {
+        credit: Option<u32>,
+    }
+    body.decode_json::<Credit>().ok()?.credit
+}
+
+/// Number of messages we may send on a stream. Clones share the credit.
+#[derive(Debug, Clone)]
+pub(super) struct SendCredit {
+    cell: Arc<CreditCell>,
+}
+
+impl SendCredit {
+    /// Wait until we may send a message and use up one unit of credit. Resolves immediately
+    /// once the connection is closed.
+    pub(super) fn acquire(&self) -> impl Future<Output = ()> + Send + '_ {
+        future::poll_fn(move |cx| self.cell.poll_acquire(cx))
+    }
+}
+
+#[derive(Debug)]
+struct CreditCell {
+    state: Mutex<CreditState>,
+}
+
+#[derive(Debug)]
+struct CreditState {
+    available: u32,
+    closed: bool,
+    /// Task waiting for credit
+    waker: Option<Waker>,
+}
+
+impl CreditCell {
+    fn poll_acquire(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            Poll::Ready(())
+        } else if state.available > 0 {
+            state.available -= 1;
+            Poll::Ready(())
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    fn add(&self, credit: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.available = state.available.saturating_add(credit);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Messages the peer may still send on a stream we receive from. Clones share the window.
+#[derive(Clone)]
+pub(super) struct ReceiveWindow {
+    inner: Arc<WindowInner>,
+}
+
+struct WindowInner {
+    /// Number we send on the stream
+    number: i32,
+    window: u32,
+    /// Credit the peer has not used yet
+    outstanding: AtomicU32,
+    /// Messages consumed since we last granted credit
+    consumed: AtomicU32,
+    flow_control: FlowControl,
+}
+
+impl std::fmt::Debug for ReceiveWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReceiveWindow")
+            .field("number", &self.inner.number)
+            .field("window", &self.inner.window)
+            .field("outstanding", &self.inner.outstanding)
+            .finish()
+    }
+}
+
+impl ReceiveWindow {
+    /// Record a data message from the peer. Returns `false` if the peer had no credit left.
+    pub(super) fn receive(&self) -> bool {
+        self.inner
+            .outstanding
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |outstanding| {
+                outstanding.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Record that the application consumed a data message. Grants the peer more credit once
+    /// half of the window has been consumed.
+    pub(super) fn consume(&self) {
+        let threshold = std::cmp::max(self.inner.window / 2, 1);
+        let consumed = self.inner.consumed.fetch_add(1, Ordering::SeqCst) + 1;
+        if consumed < threshold {
+            return;
+        }
+        let credit = self.inner.consumed.swap(0, Ordering::SeqCst);
+        if credit > 0 {
+            self.inner.outstanding.fetch_add(credit, Ordering::SeqCst);
+            self.inner.flow_control.grant(self.inner.number, credit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::base::{Endpoint, RequestSender};
+    use futures::channel::mpsc;
+
+    /// Service with a `duplex` method `echo` that returns every message.
+    fn echo_service() -> Service {
+        let mut service = Service::new();
+        service.add_duplex("echo", |_: Vec<()>| {
+            let (sender, receiver) = mpsc::unbounded();
+            let sink = sink::unfold(
+                Some(sender),
+                |sender: Option<mpsc::UnboundedSender<_>>, message| async move {
+                    match (sender, message) {
+                        (Some(sender), StreamMessage::Data(body)) => {
+                            let _ = sender.unbounded_send(Ok(body));
+                            Ok::<_, SinkClosed>(Some(sender))
+                        }
+                        // Dropping the sender ends the source.
+                        _ => Ok(None),
+                    }
+                },
+            );
+            (receiver, sink)
+        });
+        service
+    }
+
+    fn endpoints(
+        stream_buffer_a: Option<usize>,
+        stream_buffer_b: Option<usize>,
+    ) -> (Endpoint, Endpoint) {
+        let (sender_a, receiver_a) = mpsc::channel(10);
+        let (sender_b, receiver_b) = mpsc::channel(10);
+        let endpoint_a = Endpoint::builder()
+            .service(echo_service())
+            .stream_buffer(stream_buffer_a)
+            .build(sender_a, receiver_b.map(Ok::<_, std::io::Error>));
+        let endpoint_b = Endpoint::builder()
+            .service(echo_service())
+            .stream_buffer(stream_buffer_b)
+            .build(sender_b, receiver_a.map(Ok::<_, std::io::Error>));
+        (endpoint_a, endpoint_b)
+    }
+
+    #[async_std::test]
+    async fn enable() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let (mut endpoint_a, endpoint_b) = endpoints(Some(4), Some(8));
+        assert!(!endpoint_a.flow_control());
+        assert!(endpoint_a.enable_flow_control().await.unwrap());
+        assert!(endpoint_a.flow_control());
+        assert!(endpoint_b.flow_control());
+    }
+
+    /// Send `messages` messages through the `echo` method and check that all of them are
+    /// returned.
+    async fn echo(mut sender: RequestSender, messages: u32) {
+        let (source, mut sink) = sender
+            .start_duplex(vec!["echo".to_string()], vec![])
+            .await
+            .unwrap();
+        let receive = source
+            .map(|body| body.unwrap().decode_json::<u32>().unwrap())
+            .collect::<Vec<_>>();
+        let send = async move {
+            for index in 0..messages {
+                sink.send(Body::json(&index)).await.unwrap();
+            }
+            sink.close().await.unwrap();
+        };
+        let (received, ()) = future::join(receive, send).await;
+        assert_eq!(received, (0..messages).collect::<Vec<_>>());
+    }
+
+    #[async_std::test]
+    async fn duplex_streams() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let (mut endpoint_a, mut endpoint_b) = endpoints(Some(2), Some(3));
+        assert!(endpoint_a.enable_flow_control().await.unwrap());
+
+        // Streams started by both peers wait for credit in both directions.
+        let sender_a = endpoint_a.client().sender();
+        let sender_b = endpoint_b.client().sender();
+        let streams = (0..10).flat_map(|_| {
+            vec![
+                echo(sender_a.clone(), 50).boxed(),
+                echo(sender_b.clone(), 50).boxed(),
+            ]
+        });
+        async_std::future::timeout(
+            std::time::Duration::from_secs(30),
+            future::join_all(streams),
+        )
+        .await
+        .expect("streams did not finish");
+    }
+
+    #[async_std::test]
+    async fn not_supported() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let (mut endpoint_a, _endpoint_b) = endpoints(Some(4), None);
+        assert!(!endpoint_a.enable_flow_control().await.unwrap());
+        assert!(!endpoint_a.flow_control());
+
+        let (mut endpoint_a, _endpoint_b) = endpoints(None, Some(4));
+        assert!(!endpoint_a.enable_flow_control().await.unwrap());
+    }
+
+    #[test]
+    fn receive_window() {
+        let flow_control = FlowControl::new(Some(4));
+        let (grants_sender, mut grants) = mpsc::unbounded();
+        flow_control.inner.lock().unwrap().grants = Some(grants_sender);
+        let (_credit, window) = flow_control.open_stream(-3, 0).unwrap();
+
+        for _ in 0..4 {
+            assert!(window.receive());
+        }
+        assert!(!window.receive());
+
+        window.consume();
+        assert!(grants.try_recv().is_err());
+        window.consume();
+        assert_eq!(grants.try_recv().unwrap(), (-3, 2));
+        assert!(window.receive());
+        assert!(window.receive());
+        assert!(!window.receive());
+    }
+}
@@ -0,0 +1,213 @@
+//! Sans-IO core of an RPC endpoint.
+//!
+//! [EndpointMachine] turns bytes received from a peer into [Packet]s and packets into bytes to
+//! send without owning a connection or spawning tasks. [Endpoint][super::Endpoint] reads packets
+//! with it and custom event loops can use it to embed the protocol.
+
+use super::packet::{BodyEncoding, DecodeError, Decompress, Header, Packet, PacketDecoder};
+
+/// State machine for the wire protocol of an RPC connection.
+///
+/// ```
+/// # use ssb::rpc::base::machine::EndpointMachine;
+/// # use ssb::rpc::base::packet::*;
+/// let mut alice = EndpointMachine::new();
+/// let mut bob = EndpointMachine::new();
+///
+/// let request = Packet::Request(Request::Async {
+///     number: 1,
+///     method: vec!["whoami".to_string()],
+///     type_: RequestType::Async,
+///     args: vec![],
+/// });
+/// alice.send(request.clone());
+/// alice.close();
+///
+/// while let Some(data) = alice.poll_transmit() {
+///     bob.receive(&data);
+/// }
+/// assert_eq!(bob.poll_packet().unwrap().unwrap(), request);
+/// assert!(bob.poll_packet().is_none());
+/// assert!(bob.is_remote_closed());
+/// ```
+#[derive(Debug, Default)]
+pub struct EndpointMachine {
+    decoder: PacketDecoder,
+    /// Received bytes that have not been decoded yet.
+    input: bytes::BytesMut,
+    /// Bytes that need to be sent to the peer.
+    output: Vec<u8>,
+    /// `true` if the peer sent the goodbye header or an invalid packet. No packets are decoded
+    /// after that.
+    input_closed: bool,
+    remote_closed: bool,
+    closed: bool,
+}
+
+impl EndpointMachine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Machine that decodes compressed packets with `decompress`. See
+    /// [PacketDecoder::with_decompress].
+    pub fn with_decompress(decompress: Decompress) -> Self {
+        Self {
+            decoder: PacketDecoder::with_decompress(decompress),
+            ..Self::default()
+        }
+    }
+
+    /// Fail when the peer sends a packet with a body longer than `max` bytes. See
+    /// [PacketDecoder::set_max_body_len].
+    pub fn set_max_body_len(&mut self, max: Option<u32>) {
+        self.decoder.set_max_body_len(max);
+    }
+
+    /// Accept CBOR bodies from the peer. See [PacketDecoder::set_body_encoding].
+    pub fn set_body_encoding(&mut self, encoding: BodyEncoding) {
+        self.decoder.set_body_encoding(encoding);
+    }
+
+    /// Start of the packet that made [EndpointMachine::poll_packet] fail. See
+    /// [PacketDecoder::rejected].
+    pub fn rejected(&self) -> &[u8] {
+        self.decoder.rejected()
+    }
+
+    /// Add bytes received from the peer. Packets are decoded by [EndpointMachine::poll_packet].
+    ///
+    /// Data received after the peer said goodbye is ignored.
+    pub fn receive(&mut self, data: &[u8]) {
+        if !self.input_closed {
+            self.input.extend_from_slice(data);
+        }
+    }
+
+    /// Returns the next packet decoded from the received data.
+    ///
+    /// Returns `None` if more data is needed or the peer has closed the connection. Once an
+    /// error is returned no further packets are decoded.
+    pub fn poll_packet(&mut self) -> Option<Result<Packet, DecodeError>> {
+        if self.input_closed {
+            return None;
+        }
+        let result = self.decoder.put(&mut self.input)?;
+        match result {
+            Ok(Some(packet)) => Some(Ok(packet)),
+            Ok(None) => {
+                self.close_input();
+                self.remote_closed = true;
+                None
+            }
+            Err(error) => {
+                self.close_input();
+                Some(Err(error))
+            }
+        }
+    }
+
+    /// Returns `true` if the peer sent the goodbye header.
+    pub fn is_remote_closed(&self) -> bool {
+        self.remote_closed
+    }
+
+    /// Returns `true` if the received data ends in the middle of a packet.
+    ///
+    /// If the connection ends while this is `true` the peer did not finish sending a packet.
+    pub fn has_partial_packet(&self) -> bool {
+        !self.input_closed && (!self.decoder.is_empty() || !self.input.is_empty())
+    }
+
+    /// Queue `packet` to be sent to the peer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the endpoint was closed with [EndpointMachine::close].
+    pub fn send(&mut self, packet: Packet) {
+        assert!(!self.closed, "Packet sent after endpoint was closed");
+        self.output.extend(packet.build());
+    }
+
+    /// Queue the goodbye header that tells the peer that no more packets are sent.
+    pub fn close(&mut self) {
+        if !self.closed {
+            self.output.extend_from_slice(&[0u8; Header::SIZE]);
+            self.closed = true;
+        }
+    }
+
+    /// Returns the bytes that need to be sent to the peer or `None` if there are none.
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        if self.output.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.output))
+        }
+    }
+
+    fn close_input(&mut self) {
+        self.input_closed = true;
+        self.input = bytes::BytesMut::new();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::base::packet::{Body, Response};
+    use crate::test_utils::*;
+
+    #[proptest]
+    fn receive_in_chunks(
+        #[strategy(proptest::collection::vec(any::<Packet>(), 0..10))] packets: Vec<Packet>,
+        #[strategy(1..100usize)] chunk_size: usize,
+    ) {
+        let mut sender = EndpointMachine::new();
+        for packet in &packets {
+            sender.send(packet.clone());
+        }
+        sender.close();
+        let data = sender.poll_transmit().unwrap_or_default();
+        prop_assert!(sender.poll_transmit().is_none());
+
+        let mut receiver = EndpointMachine::new();
+        let mut received = Vec::new();
+        for chunk in data.chunks(chunk_size) {
+            receiver.receive(chunk);
+            while let Some(packet) = receiver.poll_packet() {
+                received.push(packet.unwrap());
+            }
+        }
+        prop_assert_eq!(received, packets);
+        prop_assert!(receiver.is_remote_closed());
+        prop_assert!(!receiver.has_partial_packet());
+    }
+
+    #[test]
+    fn partial_packet() {
+        let packet = Packet::Response(Response::AsyncOk {
+            number: 1,
+            body: Body::String("hello".to_string()),
+        })
+        .build();
+        let mut machine = EndpointMachine::new();
+        machine.receive(&packet[..Header::SIZE + 1]);
+        assert!(machine.poll_packet().is_none());
+        assert!(machine.has_partial_packet());
+        assert!(!machine.is_remote_closed());
+    }
+
+    #[test]
+    fn invalid_header() {
+        let mut machine = EndpointMachine::new();
+        // Request number zero
+        machine.receive(&[2, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(matches!(
+            machine.poll_packet(),
+            Some(Err(DecodeError::InvalidHeader(_)))
+        ));
+        assert!(machine.poll_packet().is_none());
+        assert!(!machine.has_partial_packet());
+    }
+}
@@ -5,32 +5,80 @@
 //!
 //! [ssb-prot]: https://ssbc.github.io/scuttlebutt-protocol-guide/#rpc-protocol
 //! [ssbc-muxrpc]: https://github.com/ssbc/muxrpc
+//!
+//! # Message order
+//!
+//! Messages of a stream are delivered in the order they were sent, in both directions. Messages
+//! of different streams and responses to different requests may interleave in any order.
+//!
+//! The order follows from the connection delivering bytes in order and from every stream being
+//! passed through first-in-first-out queues. Debug builds tag every outgoing stream packet with
+//! its position on the stream before the packets of all streams are merged and batched. The
+//! writer decodes what it writes and panics if the packets of a stream are out of order. The
+//! wire format has no sequence numbers, so the order of received packets is not checked.
+mod anomaly;
+mod blob_io;
 mod client;
+pub mod codec;
+pub mod compression;
+#[cfg(test)]
+mod conformance;
+mod connection_closed;
 mod endpoint;
-mod header;
-mod packet;
+pub mod flow_control;
+pub mod machine;
+pub mod packet;
 mod packet_stream;
+mod pausable_source;
+pub mod proxy;
+mod request_number;
+pub mod schema;
 mod server;
+#[cfg(all(not(target_arch = "wasm32"), any(test, feature = "simulation")))]
+pub mod simulation;
+mod stream_channel;
 mod stream_request;
 #[cfg(any(test, feature = "test-server"))]
 pub mod test_server;
+mod typed_source;
+mod writer;
+
+#[doc(inline)]
+pub use anomaly::{
+    ProtocolAnomaly, ProtocolError, ProtocolViolation, UnknownResponsePolicy, ViolationKind,
+};
+
+#[doc(inline)]
+pub use client::{
+    AsyncRequestError, AsyncResponse, BoxStreamSource, Client, RequestSender, TIMEOUT_ERROR_NAME,
+};
 
 #[doc(inline)]
-pub use client::{AsyncRequestError, AsyncResponse, Client};
+pub use request_number::RequestNumbersExhausted;
 
 #[doc(inline)]
-pub use packet::Body;
+pub use packet::{Body, BodyEncoding};
 
+pub(crate) use endpoint::smooth_rtt;
 #[doc(inline)]
-pub use endpoint::Endpoint;
+pub use endpoint::{CloseHandle, Endpoint, EndpointBuilder, EndpointConfig, EndpointMetrics};
 
-mod service;
+pub mod service;
 #[doc(inline)]
 pub use service::{Service, SinkError};
 
 mod stream_message;
 #[doc(inline)]
-pub use stream_message::StreamMessage;
+pub use stream_message::{StreamError, StreamMessage};
+
+#[doc(inline)]
+pub use typed_source::TypedSource;
+
+#[doc(inline)]
+pub use pausable_source::{PausableSource, SourceHandle};
+
+#[doc(inline)]
+pub use blob_io::{BlobReader, BlobWriter, MAX_CHUNK_SIZE};
 
 mod error;
 #[doc(inline)]
@@ -1,8 +1,15 @@
-use super::header::BodyType;
+//! Encoding and decoding of RPC packets.
+//!
+//! [Endpoint][super::Endpoint] takes care of this for connections. The types are exposed for
+//! tools that inspect or generate packets directly.
+//!
+//! The codec does not perform IO. [PacketDecoder] is fed bytes from any source and
+//! [Packet::build] returns the bytes to send. Headers and the framing of packets are handled by
+//! the `#![no_std]` crate [ssb_packet]. This module interprets the bodies.
 
 use super::error::Error;
-pub use super::header::{Header, HeaderFlags, HeaderParseError};
 use super::stream_message::StreamMessage;
+pub use ssb_packet::{BodyType, Header, HeaderFlags, HeaderParseError, MAX_REJECTED_BYTES};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(test, derive(proptest_derive::Arbitrary))]
@@ -25,6 +32,7 @@ pub enum Request {
             )
         )]
         method: Vec<String>,
+        type_: RequestType,
         #[cfg_attr(test, proptest(value = "vec![]"))]
         args: Vec<serde_json::Value>,
     },
@@ -35,6 +43,26 @@ pub enum Request {
     },
 }
 
+/// Type of a request that is answered with a single response.
+///
+/// On the wire `sync` and `async` requests are identical except for the `type` field of the
+/// request body. The response to both is a single packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(test, derive(proptest_derive::Arbitrary))]
+#[serde(rename_all = "lowercase")]
+pub enum RequestType {
+    Async,
+    Sync,
+}
+
+impl Default for RequestType {
+    /// Peers may omit the `type` field for non-stream requests in which case the request is
+    /// treated as `async`.
+    fn default() -> Self {
+        RequestType::Async
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 #[cfg_attr(test, proptest(no_params))]
@@ -58,6 +86,7 @@ pub enum Response {
 }
 
 #[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum PacketParseError {
     #[error("Failed to decode JSON request body")]
     RequestBody {
@@ -81,21 +110,63 @@ pub enum PacketParseError {
         actual: BodyType,
         expected: BodyType,
     },
+    #[error("Failed to decompress body")]
+    Decompress,
+    /// The peer sent a body with [BodyType::Cbor] but the connection does not use
+    /// [BodyEncoding::Cbor].
+    #[error("Received CBOR body on a connection that does not use CBOR")]
+    CborNotNegotiated,
+    #[cfg(feature = "cbor")]
+    #[error("Invalid CBOR body")]
+    CborBody {
+        #[source]
+        error: serde_cbor::Error,
+    },
+}
+
+/// Encoding of JSON bodies on the wire.
+///
+/// Peers always accept JSON. [BodyEncoding::Cbor] requires the `cbor` feature and must only be
+/// used if the peer is known to accept CBOR bodies, for example because both sides are configured
+/// to use it. Received CBOR bodies are only accepted if the connection uses [BodyEncoding::Cbor]
+/// and are converted to [Body::Json]. Otherwise they fail with
+/// [PacketParseError::CborNotNegotiated].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum BodyEncoding {
+    #[default]
+    Json,
+    /// Send JSON bodies as CBOR with body type [BodyType::Cbor]. Bodies that are not valid JSON
+    /// are sent unchanged.
+    #[cfg(feature = "cbor")]
+    Cbor,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
 #[cfg_attr(test, derive(proptest_derive::Arbitrary))]
 struct RequestBody {
     name: Vec<String>,
+    #[serde(rename = "type", default)]
+    type_: RequestType,
     // TODO generate json values
     #[cfg_attr(test, proptest(value = "vec![]"))]
     args: Vec<serde_json::Value>,
 }
 
 impl Packet {
+    /// Parse a packet from a connection that uses [BodyEncoding::Json]. CBOR bodies are rejected.
     pub fn parse(header: Header, body: Vec<u8>) -> Result<Self, PacketParseError> {
+        Self::parse_with_encoding(header, body, BodyEncoding::Json)
+    }
+
+    /// Like [Packet::parse] but accepts CBOR bodies if `encoding` is [BodyEncoding::Cbor].
+    pub fn parse_with_encoding(
+        header: Header,
+        body: Vec<u8>,
+        encoding: BodyEncoding,
+    ) -> Result<Self, PacketParseError> {
         let request_number = header.request_number;
-        let body = Body::parse(header.body_type, body)?;
+        let body = Body::parse(header.body_type, body, encoding)?;
         #[allow(clippy::collapsible_if)]
         let packet = if request_number > 0 {
             let number = request_number as u32;
@@ -107,7 +178,7 @@ impl Packet {
                 // always be set to `false` since `true` for async requests is
                 // unspecified.
                 let json = body.into_json()?;
-                let RequestBody { name, args } =
+                let RequestBody { name, type_, args } =
                     serde_json::from_slice(&json).map_err(|error| {
                         PacketParseError::RequestBody {
                             body: String::from_utf8_lossy(&json).into_owned(),
@@ -117,12 +188,14 @@ impl Packet {
                 Request::Async {
                     number: header.request_number as u32,
                     method: name,
+                    type_,
                     args,
                 }
             };
             Packet::Request(request)
         } else {
-            let number = -request_number as u32;
+            // `i32::MIN` cannot be negated. We use the absolute value as an unsigned number.
+            let number = request_number.unsigned_abs();
             let response = if header.flags.is_stream {
                 let message = parse_stream_message(&header.flags, body)?;
                 Response::Stream { number, message }
@@ -148,12 +221,17 @@ impl Packet {
                 Request::Async {
                     number,
                     method,
+                    type_,
                     args,
                 } => RawPacket {
                     request_number: number as i32,
                     is_stream: false,
                     is_end_or_error: false,
-                    body: Body::json(&RequestBody { name: method, args }),
+                    body: Body::json(&RequestBody {
+                        name: method,
+                        type_,
+                        args,
+                    }),
                 },
                 Request::Stream { number, message } => {
                     RawPacket::from_stream_message(number as i32, message)
@@ -161,7 +239,7 @@ impl Packet {
             },
             Packet::Response(response) => match response {
                 Response::AsyncOk { number, body } => RawPacket {
-                    request_number: -(number as i32),
+                    request_number: (number as i32).wrapping_neg(),
                     is_stream: false,
                     is_end_or_error: false,
                     body,
@@ -171,20 +249,56 @@ impl Packet {
                     name,
                     message,
                 } => RawPacket {
-                    request_number: -(number as i32),
+                    request_number: (number as i32).wrapping_neg(),
                     is_stream: false,
                     is_end_or_error: true,
                     body: Body::json(&Error { name, message }),
                 },
                 Response::Stream { number, message } => {
-                    RawPacket::from_stream_message(-(number as i32), message)
+                    RawPacket::from_stream_message((number as i32).wrapping_neg(), message)
                 }
             },
         }
     }
 
     pub fn build(self) -> Vec<u8> {
-        self.build_raw().build()
+        self.build_with_encoding(BodyEncoding::Json)
+    }
+
+    /// Like [Packet::build] but sends JSON bodies with the given encoding.
+    pub fn build_with_encoding(self, encoding: BodyEncoding) -> Vec<u8> {
+        self.build_raw().build(encoding)
+    }
+
+    /// Like [Packet::build_with_encoding] but replaces the body of stream data with the result of
+    /// `compress` and sets [HeaderFlags::is_compressed]. If `compress` returns `None` the body is
+    /// sent as is.
+    pub fn build_compressed(
+        self,
+        encoding: BodyEncoding,
+        compress: &dyn Fn(&[u8]) -> Option<Vec<u8>>,
+    ) -> Vec<u8> {
+        let is_data = matches!(
+            self,
+            Packet::Request(Request::Stream {
+                message: StreamMessage::Data(_),
+                ..
+            }) | Packet::Response(Response::Stream {
+                message: StreamMessage::Data(_),
+                ..
+            })
+        );
+        let (mut header, body_data) = self.build_raw().header_and_body(encoding);
+        let compressed = if is_data { compress(&body_data) } else { None };
+        let body_data = match compressed {
+            Some(compressed) => {
+                header.flags.is_compressed = true;
+                header.body_len = compressed.len() as u32;
+                compressed
+            }
+            None => body_data,
+        };
+        join_header_and_body(header, body_data)
     }
 }
 
@@ -198,7 +312,11 @@ pub enum Body {
 }
 
 impl Body {
-    fn parse(body_type: BodyType, data: Vec<u8>) -> Result<Self, PacketParseError> {
+    fn parse(
+        body_type: BodyType,
+        data: Vec<u8>,
+        encoding: BodyEncoding,
+    ) -> Result<Self, PacketParseError> {
         Ok(match body_type {
             BodyType::Binary => Body::Blob(data),
             BodyType::Utf8String => {
@@ -207,9 +325,23 @@ impl Body {
                 Body::String(string)
             }
             BodyType::Json => Body::Json(data),
+            BodyType::Cbor => Body::parse_cbor(&data, encoding)?,
         })
     }
 
+    #[cfg(feature = "cbor")]
+    fn parse_cbor(data: &[u8], encoding: BodyEncoding) -> Result<Self, PacketParseError> {
+        if encoding != BodyEncoding::Cbor {
+            return Err(PacketParseError::CborNotNegotiated);
+        }
+        Body::from_cbor(data).map_err(|error| PacketParseError::CborBody { error })
+    }
+
+    #[cfg(not(feature = "cbor"))]
+    fn parse_cbor(_data: &[u8], _encoding: BodyEncoding) -> Result<Self, PacketParseError> {
+        Err(PacketParseError::CborNotNegotiated)
+    }
+
     pub fn json(value: &impl serde::Serialize) -> Self {
         // TODO error
         Self::Json(serde_json::to_vec(value).unwrap())
@@ -231,8 +363,18 @@ impl Body {
 
     /// Deserializes a JSON body into the type `T`.
     ///
+    /// `T` may borrow strings and bytes from the body. This avoids copying them out of large
+    /// responses.
+    ///
     /// Errors when the body does not contain JSON data or the JSON value cannot be decoded as `T`.
-    pub fn decode_json<T: serde::de::DeserializeOwned>(&self) -> Result<T, BodyDecodeError> {
+    ///
+    /// ```
+    /// # use ssb::rpc::base::Body;
+    /// let body = Body::json(&["a", "b"]);
+    /// let strings = body.decode_json::<Vec<&str>>().unwrap();
+    /// assert_eq!(strings, ["a", "b"]);
+    /// ```
+    pub fn decode_json<'a, T: serde::Deserialize<'a>>(&'a self) -> Result<T, BodyDecodeError> {
         match self {
             Body::Blob(_) => Err(BodyDecodeError::InvalidBodyType {
                 actual: BodyType::Binary,
@@ -240,15 +382,41 @@ impl Body {
             Body::String(_) => Err(BodyDecodeError::InvalidBodyType {
                 actual: BodyType::Utf8String,
             }),
-            Body::Json(data) => Ok(serde_json::from_slice(&data)?),
+            Body::Json(data) => Ok(serde_json::from_slice(data)?),
         }
     }
 
-    fn build(self) -> (BodyType, Vec<u8>) {
-        match self {
-            Self::Blob(data) => (BodyType::Binary, data),
-            Self::String(string) => (BodyType::Utf8String, Vec::from(string)),
-            Self::Json(data) => (BodyType::Json, data),
+    /// Creates a JSON body from a JSON value encoded as CBOR.
+    #[cfg(feature = "cbor")]
+    pub fn from_cbor(data: &[u8]) -> Result<Self, serde_cbor::Error> {
+        let value = serde_cbor::from_slice::<serde_json::Value>(data)?;
+        Ok(Self::json(&value))
+    }
+
+    /// Encodes the value of a JSON body as CBOR.
+    ///
+    /// ```
+    /// # use ssb::rpc::base::Body;
+    /// let body = Body::json(&serde_json::json!({ "a": [1, true] }));
+    /// let cbor = body.to_cbor().unwrap();
+    /// assert_eq!(Body::from_cbor(&cbor).unwrap(), body);
+    /// ```
+    #[cfg(feature = "cbor")]
+    pub fn to_cbor(&self) -> Result<Vec<u8>, BodyDecodeError> {
+        let value = self.decode_json::<serde_json::Value>()?;
+        Ok(serde_cbor::to_vec(&value).expect("JSON values can be encoded as CBOR"))
+    }
+
+    fn build(self, encoding: BodyEncoding) -> (BodyType, Vec<u8>) {
+        match (self, encoding) {
+            (Self::Blob(data), _) => (BodyType::Binary, data),
+            (Self::String(string), _) => (BodyType::Utf8String, Vec::from(string)),
+            #[cfg(feature = "cbor")]
+            (body @ Self::Json(_), BodyEncoding::Cbor) => match body.to_cbor() {
+                Ok(data) => (BodyType::Cbor, data),
+                Err(_) => body.build(BodyEncoding::Json),
+            },
+            (Self::Json(data), _) => (BodyType::Json, data),
         }
     }
 }
@@ -279,6 +447,181 @@ pub enum BodyDecodeError {
     ),
 }
 
+/// Error returned by [PacketDecoder::put].
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+    #[error("Failed to parse packet header")]
+    InvalidHeader(
+        #[source]
+        #[from]
+        HeaderParseError,
+    ),
+    #[error("Failed to parse packet")]
+    PacketParse(
+        #[source]
+        #[from]
+        PacketParseError,
+    ),
+    #[error("Packet body of {len} bytes exceeds the limit of {max} bytes")]
+    BodyTooLarge { len: u32, max: u32 },
+}
+
+impl From<ssb_packet::FrameError> for DecodeError {
+    fn from(error: ssb_packet::FrameError) -> Self {
+        match error {
+            ssb_packet::FrameError::InvalidHeader(error) => DecodeError::InvalidHeader(error),
+            ssb_packet::FrameError::BodyTooLarge { len, max } => {
+                DecodeError::BodyTooLarge { len, max }
+            }
+        }
+    }
+}
+
+/// Decoder that is fed bytes until it produces a [Packet].
+///
+/// Call [PacketDecoder::put] repeatedly until a [Packet] or an error is returned.
+///
+/// ```
+/// # use ssb::rpc::base::packet::*;
+/// let request = Packet::Request(Request::Async {
+///     number: 1,
+///     method: vec!["whoami".to_string()],
+///     type_: RequestType::Async,
+///     args: vec![],
+/// });
+/// let data = request.clone().build();
+/// let (first, second) = data.split_at(5);
+///
+/// let mut decoder = PacketDecoder::new();
+/// assert!(decoder.put(first).is_none());
+/// assert_eq!(decoder.put(second).unwrap().unwrap(), Some(request));
+/// ```
+#[derive(Default)]
+pub struct PacketDecoder {
+    frames: ssb_packet::FrameDecoder,
+    decompress: Option<Decompress>,
+    body_encoding: BodyEncoding,
+    max_body_len: Option<u32>,
+}
+
+/// Restores bodies of packets with [HeaderFlags::is_compressed] set. Returns `None` if the body
+/// cannot be decompressed.
+///
+/// The second argument is the maximum body length. Implementations should stop after one more
+/// byte than that so that bodies which inflate past the limit are detected without inflating
+/// them completely.
+pub type Decompress = Box<dyn Fn(&[u8], usize) -> Option<Vec<u8>> + Send>;
+
+impl std::fmt::Debug for PacketDecoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PacketDecoder")
+            .field("frames", &self.frames)
+            .field("decompress", &self.decompress.is_some())
+            .field("body_encoding", &self.body_encoding)
+            .field("max_body_len", &self.max_body_len)
+            .finish()
+    }
+}
+
+impl PacketDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decoder that accepts compressed packets. Without `decompress` they fail with
+    /// [PacketParseError::Decompress].
+    pub fn with_decompress(decompress: Decompress) -> Self {
+        Self {
+            decompress: Some(decompress),
+            ..Self::default()
+        }
+    }
+
+    /// Fail with [DecodeError::BodyTooLarge] when a header announces a body that is longer than
+    /// `max` bytes or when a compressed body is longer than `max` bytes after decompression.
+    /// Bodies of any length are accepted if `max` is `None`.
+    pub fn set_max_body_len(&mut self, max: Option<u32>) {
+        self.frames.set_max_body_len(max);
+        self.max_body_len = max;
+    }
+
+    /// Accept CBOR bodies if `encoding` is [BodyEncoding::Cbor]. Defaults to
+    /// [BodyEncoding::Json] which rejects them with [PacketParseError::CborNotNegotiated].
+    pub fn set_body_encoding(&mut self, encoding: BodyEncoding) {
+        self.body_encoding = encoding;
+    }
+
+    /// Start of the packet that made [PacketDecoder::put] fail, for example to log it. Contains
+    /// the header and at most [MAX_REJECTED_BYTES] bytes in total. Empty if no error occurred.
+    pub fn rejected(&self) -> &[u8] {
+        self.frames.rejected()
+    }
+
+    /// Consume bytes from `data` until a packet is complete.
+    ///
+    /// Returns `None` if all of `data` was consumed without completing a packet. Returns
+    /// `Some(Ok(None))` if the goodbye header was read. Remaining bytes are left in `data`.
+    pub fn put(
+        &mut self,
+        mut data: impl bytes::Buf,
+    ) -> Option<Result<Option<Packet>, DecodeError>> {
+        loop {
+            let chunk = data.chunk();
+            let mut remaining = chunk;
+            let result = self.frames.put(&mut remaining);
+            let consumed = chunk.len() - remaining.len();
+            data.advance(consumed);
+            match result {
+                Some(Ok(Some(frame))) => return Some(self.parse(frame).map(Some)),
+                Some(Ok(None)) => return Some(Ok(None)),
+                Some(Err(error)) => return Some(Err(error.into())),
+                None if !data.has_remaining() => return None,
+                None => {}
+            }
+        }
+    }
+
+    fn parse(&mut self, frame: ssb_packet::Frame) -> Result<Packet, DecodeError> {
+        let ssb_packet::Frame { header, body } = frame;
+        let body = if header.flags.is_compressed {
+            let max_len = self.max_body_len.map_or(usize::MAX, |max| max as usize);
+            match self
+                .decompress
+                .as_ref()
+                .and_then(|decompress| decompress(&body, max_len))
+            {
+                Some(decompressed) if decompressed.len() > max_len => {
+                    self.frames.reject(&header, &body);
+                    return Err(DecodeError::BodyTooLarge {
+                        len: decompressed.len() as u32,
+                        max: max_len as u32,
+                    });
+                }
+                Some(body) => body,
+                None => {
+                    self.frames.reject(&header, &body);
+                    return Err(DecodeError::PacketParse(PacketParseError::Decompress));
+                }
+            }
+        } else {
+            body
+        };
+        // Keep the start of the body on the stack because parsing consumes it.
+        let mut body_start = [0u8; MAX_REJECTED_BYTES - Header::SIZE];
+        let body_start_len = std::cmp::min(body.len(), body_start.len());
+        body_start[..body_start_len].copy_from_slice(&body[..body_start_len]);
+        Packet::parse_with_encoding(header, body, self.body_encoding).map_err(|error| {
+            self.frames.reject(&header, &body_start[..body_start_len]);
+            DecodeError::PacketParse(error)
+        })
+    }
+
+    /// Returns `true` if the decoder has not consumed any bytes of the next packet.
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct RawPacket {
     request_number: i32,
@@ -288,14 +631,14 @@ struct RawPacket {
 }
 
 impl RawPacket {
-    fn header_and_body(self) -> (Header, Vec<u8>) {
+    fn header_and_body(self, encoding: BodyEncoding) -> (Header, Vec<u8>) {
         let Self {
             request_number,
             is_stream,
             is_end_or_error,
             body,
         } = self;
-        let (body_type, body_data) = body.build();
+        let (body_type, body_data) = body.build(encoding);
         let header = Header {
             request_number,
             body_len: body_data.len() as u32,
@@ -303,16 +646,15 @@ impl RawPacket {
             flags: HeaderFlags {
                 is_stream,
                 is_end_or_error,
+                is_compressed: false,
             },
         };
         (header, body_data)
     }
 
-    fn build(self) -> Vec<u8> {
-        let (header, mut body_data) = self.header_and_body();
-        let mut data = header.build().to_vec();
-        data.append(&mut body_data);
-        data
+    fn build(self, encoding: BodyEncoding) -> Vec<u8> {
+        let (header, body_data) = self.header_and_body(encoding);
+        join_header_and_body(header, body_data)
     }
 
     fn from_stream_message(request_number: i32, stream_message: StreamMessage) -> Self {
@@ -325,6 +667,10 @@ impl RawPacket {
     }
 }
 
+fn join_header_and_body(header: Header, body: Vec<u8>) -> Vec<u8> {
+    ssb_packet::Frame { header, body }.build()
+}
+
 fn stream_message_into_body(stream_message: StreamMessage) -> Body {
     match stream_message {
         StreamMessage::Data(body) => body,
@@ -368,8 +714,184 @@ mod test {
 
     #[proptest]
     fn packet_build_parse(packet: Packet) {
-        let (header, body) = packet.clone().build_raw().header_and_body();
+        let (header, body) = packet
+            .clone()
+            .build_raw()
+            .header_and_body(BodyEncoding::Json);
         let packet2 = Packet::parse(header, body)?;
         prop_assert_eq!(packet, packet2);
     }
+
+    #[proptest]
+    fn packet_build_compressed(packet: Packet) {
+        let reverse = |data: &[u8]| Some(data.iter().rev().copied().collect::<Vec<_>>());
+        let data = packet
+            .clone()
+            .build_compressed(BodyEncoding::Json, &reverse);
+        let mut decoder =
+            PacketDecoder::with_decompress(Box::new(move |data, _max_len| reverse(data)));
+        let packet2 = decoder.put(data.as_slice()).unwrap()?;
+        prop_assert_eq!(Some(packet), packet2);
+    }
+
+    #[cfg(feature = "deflate")]
+    #[test]
+    fn decompressed_body_too_large() {
+        use crate::rpc::base::compression::{Compression as _, Deflate};
+
+        let packet = Packet::Request(Request::Stream {
+            number: 1,
+            message: StreamMessage::Data(Body::Blob(vec![0; 100_000])),
+        });
+        let data = packet.build_compressed(BodyEncoding::Json, &|data| {
+            Some(Deflate::default().compress(data))
+        });
+        assert!(data.len() < 1000, "compressed to {} bytes", data.len());
+
+        let mut decoder = PacketDecoder::with_decompress(Box::new(|data, max_len| {
+            Deflate::default().decompress(data, max_len).ok()
+        }));
+        decoder.set_max_body_len(Some(1000));
+        assert!(matches!(
+            decoder.put(data.as_slice()),
+            Some(Err(DecodeError::BodyTooLarge {
+                len: 1001,
+                max: 1000
+            }))
+        ));
+        assert_eq!(decoder.rejected(), &data[..MAX_REJECTED_BYTES]);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[proptest]
+    fn packet_build_parse_cbor(packet: Packet) {
+        let (header, body) = packet
+            .clone()
+            .build_raw()
+            .header_and_body(BodyEncoding::Cbor);
+        let packet2 = Packet::parse_with_encoding(header, body, BodyEncoding::Cbor)?;
+        prop_assert_eq!(packet, packet2);
+    }
+
+    #[test]
+    fn cbor_not_negotiated() {
+        // CBOR encoding of `true`
+        let body = vec![0xf5];
+        let header = Header {
+            flags: HeaderFlags {
+                is_stream: false,
+                is_end_or_error: false,
+                is_compressed: false,
+            },
+            body_type: BodyType::Cbor,
+            body_len: body.len() as u32,
+            request_number: -1,
+        };
+        let data = join_header_and_body(header, body);
+        let mut decoder = PacketDecoder::new();
+        let result = decoder.put(data.as_slice()).unwrap();
+        assert!(matches!(
+            result,
+            Err(DecodeError::PacketParse(
+                PacketParseError::CborNotNegotiated
+            ))
+        ));
+        assert_eq!(decoder.rejected(), &data[..]);
+    }
+
+    #[test]
+    fn parse_min_request_number() {
+        let body = b"{}".to_vec();
+        let header = Header {
+            flags: HeaderFlags {
+                is_stream: false,
+                is_end_or_error: false,
+                is_compressed: false,
+            },
+            body_type: BodyType::Json,
+            body_len: body.len() as u32,
+            request_number: i32::MIN,
+        };
+        let packet = Packet::parse(header, body.clone()).unwrap();
+        assert_eq!(
+            packet,
+            Packet::Response(Response::AsyncOk {
+                number: 1 << 31,
+                body: Body::Json(body),
+            })
+        );
+        assert_eq!(
+            packet.build_raw().header_and_body(BodyEncoding::Json).0,
+            header
+        );
+    }
+
+    #[test]
+    fn parse_request_type() {
+        let header = |body_len: usize| Header {
+            flags: HeaderFlags {
+                is_stream: false,
+                is_end_or_error: false,
+                is_compressed: false,
+            },
+            body_type: BodyType::Json,
+            body_len: body_len as u32,
+            request_number: 1,
+        };
+
+        for (body, expected_type) in [
+            (&br#"{"name":["whoami"],"args":[]}"#[..], RequestType::Async),
+            (
+                br#"{"name":["whoami"],"args":[],"type":"async"}"#,
+                RequestType::Async,
+            ),
+            (
+                br#"{"name":["whoami"],"args":[],"type":"sync"}"#,
+                RequestType::Sync,
+            ),
+        ] {
+            let packet = Packet::parse(header(body.len()), body.to_vec()).unwrap();
+            assert_eq!(
+                packet,
+                Packet::Request(Request::Async {
+                    number: 1,
+                    method: vec!["whoami".to_string()],
+                    type_: expected_type,
+                    args: vec![],
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn decoder_rejected() {
+        let header = |body_len: usize| Header {
+            flags: HeaderFlags {
+                is_stream: false,
+                is_end_or_error: false,
+                is_compressed: false,
+            },
+            body_type: BodyType::Json,
+            body_len: body_len as u32,
+            request_number: 1,
+        };
+
+        let body = vec![b'x'; 100];
+        let data = join_header_and_body(header(body.len()), body);
+        let mut decoder = PacketDecoder::new();
+        assert!(matches!(
+            decoder.put(data.as_slice()),
+            Some(Err(DecodeError::PacketParse(_)))
+        ));
+        assert_eq!(decoder.rejected(), &data[..MAX_REJECTED_BYTES]);
+
+        let data = header(101).build();
+        let mut decoder = PacketDecoder::new();
+        decoder.set_max_body_len(Some(100));
+        assert!(matches!(
+            decoder.put(&data[..]),
+            Some(Err(DecodeError::BodyTooLarge { len: 101, max: 100 }))
+        ));
+        assert_eq!(decoder.rejected(), &data[..]);
+    }
 }
@@ -4,8 +4,10 @@ use futures::prelude::*;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use super::packet::{Header, HeaderParseError, Packet, PacketParseError};
-use crate::utils::ReadBuffer;
+use super::machine::EndpointMachine;
+use super::packet::{
+    BodyEncoding, DecodeError, Decompress, HeaderParseError, Packet, PacketParseError,
+};
 
 #[derive(Debug, thiserror::Error)]
 /// Error receiving an RPC [Packet].
@@ -24,28 +26,75 @@ pub enum NextPacketError {
         #[from]
         PacketParseError,
     ),
+    #[error("Packet body of {len} bytes exceeds the limit of {max} bytes")]
+    BodyTooLarge { len: u32, max: u32 },
     #[error("Unexpected end of stream while parsing packet")]
     UnexpectedEndOfStream,
+    #[error("Peer sent nothing for {0:?}")]
+    IdleTimeout(std::time::Duration),
+}
+
+impl From<DecodeError> for NextPacketError {
+    fn from(error: DecodeError) -> Self {
+        match error {
+            DecodeError::InvalidHeader(error) => Self::InvalidHeader(error),
+            DecodeError::PacketParse(error) => Self::PacketParse(error),
+            DecodeError::BodyTooLarge { len, max } => Self::BodyTooLarge { len, max },
+        }
+    }
 }
 
 #[pin_project::pin_project]
 #[derive(Debug)]
 /// [Stream] of [Packet]s parsed from underlying [Stream] of bytes.
+///
+/// Decoding is done by [EndpointMachine]. The stream ends when the remote sends the goodbye
+/// header or after the first decoding error.
 pub struct PacketStream<Stream> {
     #[pin]
     stream: Stream,
-    reader: PacketReader,
-    buffer: bytes::Bytes,
+    machine: EndpointMachine,
+    done: bool,
 }
 
 impl<Stream> PacketStream<Stream> {
     pub fn new(stream: Stream) -> Self {
         Self {
             stream,
-            reader: PacketReader::new(),
-            buffer: bytes::Bytes::new(),
+            machine: EndpointMachine::new(),
+            done: false,
         }
     }
+
+    /// Stream that accepts compressed packets. See [PacketDecoder::with_decompress].
+    ///
+    /// [PacketDecoder::with_decompress]: super::packet::PacketDecoder::with_decompress
+    pub fn with_decompress(stream: Stream, decompress: Decompress) -> Self {
+        Self {
+            stream,
+            machine: EndpointMachine::with_decompress(decompress),
+            done: false,
+        }
+    }
+
+    /// Fail with [NextPacketError::BodyTooLarge] when the peer sends a packet with a body longer
+    /// than `max` bytes.
+    pub fn set_max_body_len(&mut self, max: Option<u32>) {
+        self.machine.set_max_body_len(max);
+    }
+
+    /// Accept CBOR bodies if `encoding` is [BodyEncoding::Cbor]. Otherwise they fail with
+    /// [NextPacketError::PacketParse].
+    pub fn set_body_encoding(&mut self, encoding: BodyEncoding) {
+        self.machine.set_body_encoding(encoding);
+    }
+
+    /// Start of the packet that could not be decoded after [NextPacketError::InvalidHeader],
+    /// [NextPacketError::PacketParse] or [NextPacketError::BodyTooLarge]. See
+    /// [EndpointMachine::rejected].
+    pub fn rejected(&self) -> &[u8] {
+        self.machine.rejected()
+    }
 }
 
 impl<Stream_> Stream for PacketStream<Stream_>
@@ -57,106 +106,45 @@ where
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         loop {
-            let mut this = self.as_mut().project();
-
-            if this.buffer.is_empty() {
-                match futures::ready!(this.stream.try_poll_next(cx)) {
-                    Some(Ok(data)) => *this.buffer = bytes::Bytes::from(data),
-                    Some(Err(err)) => {
-                        return Poll::Ready(Some(Err(NextPacketError::Source(Box::new(err)))))
-                    }
-                    None => {
-                        if this.reader.is_empty() {
-                            return Poll::Ready(None);
-                        } else {
-                            return Poll::Ready(Some(Err(NextPacketError::UnexpectedEndOfStream)));
-                        }
-                    }
-                };
+            let this = self.as_mut().project();
+            if *this.done {
+                return Poll::Ready(None);
             }
 
-            if let Some(packet_result) = this.reader.put(&mut this.buffer) {
-                return Poll::Ready(packet_result.transpose());
-            }
-        }
-    }
-}
-
-/// Buffer that is fed bytes until it produces [Packet].
-///
-/// Call [PacketReader::put] repeatedly until a [Packet] or an error is returned.
-#[derive(Debug)]
-enum PacketReader {
-    ReadingHeader { buffer: ReadBuffer },
-    ReadingBody { header: Header, buffer: ReadBuffer },
-}
-
-impl PacketReader {
-    fn new() -> Self {
-        Self::ReadingHeader {
-            buffer: ReadBuffer::new(Header::SIZE),
-        }
-    }
-
-    fn put(
-        &mut self,
-        mut data: impl bytes::Buf,
-    ) -> Option<Result<Option<Packet>, NextPacketError>> {
-        loop {
-            if !data.has_remaining() {
-                return None;
-            }
-
-            match self {
-                Self::ReadingHeader { buffer } => {
-                    use std::convert::TryInto as _;
-                    let header_data = buffer.put(&mut data)?;
-                    // .try_into() is guaranteed to not fail since the buffer
-                    // holds exactly Header::SIZE bytes.
-                    let header_data = header_data.as_slice().try_into().unwrap();
-                    let header = match Header::parse(header_data) {
-                        Ok(Some(header)) => header,
-                        Ok(None) => {
-                            return Some(Ok(None));
-                        }
-                        Err(err) => return Some(Err(NextPacketError::InvalidHeader(err))),
-                    };
-                    if header.body_len == 0 {
-                        *self = Self::new();
-                        return match Packet::parse(header, Vec::new()) {
-                            Ok(packet) => Some(Ok(Some(packet))),
-                            Err(err) => Some(Err(NextPacketError::PacketParse(err))),
-                        };
-                    }
-
-                    *self = Self::ReadingBody {
-                        header,
-                        buffer: ReadBuffer::new(header.body_len as usize),
-                    };
+            match this.machine.poll_packet() {
+                Some(Ok(packet)) => return Poll::Ready(Some(Ok(packet))),
+                Some(Err(error)) => {
+                    *this.done = true;
+                    return Poll::Ready(Some(Err(error.into())));
                 }
-                Self::ReadingBody { header, buffer } => {
-                    let body_data = buffer.put(&mut data)?;
-                    let packet_result = match Packet::parse(*header, body_data) {
-                        Ok(packet) => Ok(Some(packet)),
-                        Err(err) => Err(NextPacketError::PacketParse(err)),
-                    };
-                    *self = Self::new();
-                    return Some(packet_result);
+                None if this.machine.is_remote_closed() => {
+                    *this.done = true;
+                    return Poll::Ready(None);
                 }
+                None => {}
             }
-        }
-    }
 
-    fn is_empty(&self) -> bool {
-        match self {
-            PacketReader::ReadingHeader { buffer } => buffer.is_empty(),
-            PacketReader::ReadingBody { .. } => false,
+            match futures::ready!(this.stream.try_poll_next(cx)) {
+                Some(Ok(data)) => this.machine.receive(&data),
+                Some(Err(err)) => {
+                    return Poll::Ready(Some(Err(NextPacketError::Source(Box::new(err)))))
+                }
+                None => {
+                    *this.done = true;
+                    if this.machine.has_partial_packet() {
+                        return Poll::Ready(Some(Err(NextPacketError::UnexpectedEndOfStream)));
+                    } else {
+                        return Poll::Ready(None);
+                    }
+                }
+            };
         }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use super::super::packet::Header;
     use super::*;
     use crate::test_utils::*;
 
@@ -0,0 +1,191 @@
+use futures::prelude::*;
+use futures::task::AtomicWaker;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use super::client::BoxStreamSource;
+use super::packet::Body;
+use super::Error;
+
+/// Source that can be paused and resumed through a [SourceHandle] without ending the stream.
+///
+/// While the source is paused it yields nothing and the messages the peer sends stay in the
+/// buffer of the stream. If flow control is enabled (see [Endpoint::enable_flow_control]) the
+/// peer stops sending on the stream once [EndpointConfig::stream_buffer] messages are buffered.
+/// The other streams on the connection are not affected.
+///
+/// Without flow control a bounded buffer makes the endpoint stop reading from the connection
+/// once it is full, which delays all other streams until the source is resumed. With an
+/// unbounded buffer the messages accumulate until the source is resumed.
+///
+/// ```no_run
+/// # use futures::prelude::*;
+/// # use ssb::rpc::base::{Client, PausableSource};
+/// # async fn example(client: &mut Client) -> anyhow::Result<()> {
+/// let source = client
+///     .start_source(vec!["live".to_string()], vec![])
+///     .await?;
+/// let mut source = PausableSource::new(source);
+/// let handle = source.handle();
+/// handle.pause();
+/// // …
+/// handle.resume();
+/// let first = source.next().await;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [EndpointConfig::stream_buffer]: super::EndpointConfig::stream_buffer
+/// [Endpoint::enable_flow_control]: super::Endpoint::enable_flow_control
+pub struct PausableSource {
+    source: BoxStreamSource,
+    handle: SourceHandle,
+}
+
+impl PausableSource {
+    pub fn new(source: BoxStreamSource) -> Self {
+        Self {
+            source,
+            handle: SourceHandle::default(),
+        }
+    }
+
+    /// Handle to pause and resume this source from another task.
+    pub fn handle(&self) -> SourceHandle {
+        self.handle.clone()
+    }
+
+    pub fn into_inner(self) -> BoxStreamSource {
+        self.source
+    }
+}
+
+impl std::fmt::Debug for PausableSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PausableSource")
+            .field("paused", &self.handle.is_paused())
+            .finish()
+    }
+}
+
+impl Stream for PausableSource {
+    type Item = Result<Body, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.handle.is_paused() {
+            self.handle.state.waker.register(cx.waker());
+            // Don’t miss a resume that happened before the waker was registered.
+            if self.handle.is_paused() {
+                return Poll::Pending;
+            }
+        }
+        self.source.poll_next_unpin(cx)
+    }
+}
+
+/// Pauses and resumes a [PausableSource]. Clones control the same source.
+#[derive(Debug, Clone, Default)]
+pub struct SourceHandle {
+    state: Arc<PauseState>,
+}
+
+#[derive(Debug, Default)]
+struct PauseState {
+    paused: AtomicBool,
+    /// Task that polled the source while it was paused
+    waker: AtomicWaker,
+}
+
+impl SourceHandle {
+    /// Stop yielding messages until [SourceHandle::resume] is called.
+    pub fn pause(&self) {
+        self.state.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Continue yielding messages, starting with the ones buffered while the source was paused.
+    pub fn resume(&self) {
+        self.state.paused.store(false, Ordering::SeqCst);
+        self.state.waker.wake();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.paused.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::base::service::AsyncResponse;
+    use crate::rpc::base::{Endpoint, Service};
+    use futures::channel::mpsc;
+
+    async fn next(source: &mut PausableSource) -> u32 {
+        let body = source.next().await.unwrap().unwrap();
+        body.decode_json().unwrap()
+    }
+
+    #[async_std::test]
+    async fn pause_resume() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        // Receives the index of every message the server produces.
+        let (produced_sender, mut produced) = mpsc::unbounded();
+        let mut service = Service::new();
+        service.add_source("count", move |_: Vec<()>| {
+            let produced_sender = produced_sender.clone();
+            stream::iter(0u32..).map(move |index| {
+                let _ = produced_sender.unbounded_send(index);
+                Ok(Body::json(&index))
+            })
+        });
+        service.add_sync("ping", |_: Vec<()>| AsyncResponse::json_ok(&"pong"));
+        let (sender_a, receiver_a) = mpsc::channel(10);
+        let (sender_b, receiver_b) = mpsc::channel(10);
+        let mut client = Endpoint::builder()
+            .stream_buffer(Some(2))
+            .build(sender_a, receiver_b.map(Ok::<_, std::io::Error>));
+        let _server = Endpoint::builder()
+            .service(service)
+            .stream_buffer(Some(2))
+            .build(sender_b, receiver_a.map(Ok::<_, std::io::Error>));
+        assert!(client.enable_flow_control().await.unwrap());
+
+        let source = client
+            .client()
+            .start_source(vec!["count".to_string()], vec![])
+            .await
+            .unwrap();
+        let mut source = PausableSource::new(source);
+        let handle = source.handle();
+        assert_eq!(next(&mut source).await, 0);
+
+        handle.pause();
+        let mut paused_next = Box::pin(next(&mut source));
+        assert!(futures::poll!(paused_next.as_mut()).is_pending());
+        // The server sends messages 1 and 2 to fill the window of two messages. It produces
+        // message 3 and waits for credit.
+        while produced.next().await != Some(3) {}
+
+        // The connection is still usable while the paused stream is stalled.
+        let response = client
+            .client()
+            .send_sync(vec!["ping".to_string()], vec![])
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            crate::rpc::base::AsyncResponse::Json(b"\"pong\"".to_vec())
+        );
+        assert!(produced.try_recv().is_err());
+        assert!(futures::poll!(paused_next.as_mut()).is_pending());
+
+        handle.resume();
+        assert_eq!(paused_next.await, 1);
+        for index in 2..10 {
+            assert_eq!(next(&mut source).await, index);
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//! Relay requests between two endpoints, for example to build a tunnel relay or to inspect the
+//! traffic between two peers.
+//!
+//! [service] returns a [Service] that forwards every request it receives through a
+//! [RequestSender] to another endpoint and relays the responses back. Each forwarded request gets
+//! a request number of the other connection, so the numbers of both connections are independent.
+//! Streams keep their type and are ended or failed on the other connection when the peer ends or
+//! fails them.
+//!
+//! Stream messages are passed on one at a time. A message is only taken from one connection after
+//! the previous message was handed to the other connection, so the stream buffers and write
+//! limits of the [EndpointConfig] apply in both directions and a slow peer slows down the other
+//! peer instead of filling up the proxy.
+//!
+//! [relay] connects two peers with each other so that both can send requests to the other one.
+//!
+//! ```no_run
+//! # async_std::task::block_on(async {
+//! # let a: async_std::net::TcpStream = unimplemented!();
+//! # let b: async_std::net::TcpStream = unimplemented!();
+//! use ssb::rpc::base::{proxy, EndpointConfig};
+//! let (a, b) = proxy::relay(a, b, EndpointConfig::default());
+//! futures::try_join!(a.join(), b.join()).unwrap();
+//! # });
+//! ```
+use futures::channel::{mpsc, oneshot};
+use futures::future::{BoxFuture, Shared};
+use futures::prelude::*;
+use std::sync::{Arc, Mutex};
+
+use super::client::{self, BoxStreamSource, RequestSender, StreamSink};
+use super::service::{AsyncResponse, BoxEndpointSink, BoxEndpointStream, Fallback, SinkClosed};
+use super::stream_request::StreamRequestType;
+use super::{Endpoint, EndpointConfig, Error, Service, StreamMessage};
+use crate::utils::task::spawn;
+
+/// Name of the error that is returned to the peer if a request cannot be forwarded.
+pub const PROXY_ERROR: &str = "PROXY_ERROR";
+
+/// Service that forwards all requests to the endpoint that `target` belongs to.
+pub fn service(target: RequestSender) -> Service {
+    deferred_service(ready_target(target))
+}
+
+/// Endpoints for the connections `a` and `b` that forward all requests received on one
+/// connection to the other one. Both endpoints use `config`.
+///
+/// The endpoints are independent. Use [Endpoint::join] or [Endpoint::close] on both of them.
+/// Requests fail with a [PROXY_ERROR] once the other connection is closed.
+pub fn relay<A, B>(a: A, b: B, config: EndpointConfig) -> (Endpoint, Endpoint)
+where
+    A: AsyncRead + AsyncWrite + Send + 'static,
+    B: AsyncRead + AsyncWrite + Send + 'static,
+{
+    // Each service needs the sender of the other endpoint which does not exist yet.
+    let (a_target_sender, a_target) = oneshot::channel();
+    let (b_target_sender, b_target) = oneshot::channel();
+    let mut a = Endpoint::builder()
+        .service(deferred_service(b_target.map(Result::ok).boxed().shared()))
+        .config(config.clone())
+        .build_io(a);
+    let mut b = Endpoint::builder()
+        .service(deferred_service(a_target.map(Result::ok).boxed().shared()))
+        .config(config)
+        .build_io(b);
+    let _ = a_target_sender.send(Arc::new(Mutex::new(a.client().sender())));
+    let _ = b_target_sender.send(Arc::new(Mutex::new(b.client().sender())));
+    (a, b)
+}
+
+/// Resolves to the sender requests are forwarded to. The sender is wrapped because [Shared]
+/// requires a `Sync` output.
+type Target = Shared<BoxFuture<'static, Option<Arc<Mutex<RequestSender>>>>>;
+
+fn ready_target(sender: RequestSender) -> Target {
+    future::ready(Some(Arc::new(Mutex::new(sender))))
+        .boxed()
+        .shared()
+}
+
+async fn target_sender(target: Target) -> Option<RequestSender> {
+    let sender = target.await?;
+    let sender = sender.lock().unwrap().clone();
+    Some(sender)
+}
+
+/// Service that forwards all requests to the sender `target` resolves to. Requests fail if it
+/// resolves to `None`.
+fn deferred_service(target: Target) -> Service {
+    let mut service = Service::new();
+    service.set_fallback(Forward { target });
+    service
+}
+
+struct Forward {
+    target: Target,
+}
+
+impl Fallback for Forward {
+    fn handle_async(
+        &self,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+    ) -> BoxFuture<'static, AsyncResponse> {
+        let target = self.target.clone();
+        async move {
+            let mut sender = match target_sender(target).await {
+                Some(sender) => sender,
+                None => return AsyncResponse::Err(not_connected_error()),
+            };
+            // Servers answer `sync` and `async` requests the same way.
+            match sender.send_async(method, args).await {
+                Ok(client::AsyncResponse::Json(data)) => AsyncResponse::Ok(super::Body::Json(data)),
+                Ok(client::AsyncResponse::Blob(data)) => AsyncResponse::Ok(super::Body::Blob(data)),
+                Ok(client::AsyncResponse::String(data)) => {
+                    AsyncResponse::Ok(super::Body::String(data))
+                }
+                Ok(client::AsyncResponse::Error(error)) => AsyncResponse::Err(error),
+                Err(error) => AsyncResponse::Err(Error::new(PROXY_ERROR, error)),
+            }
+        }
+        .boxed()
+    }
+
+    fn handle_stream(
+        &self,
+        type_: StreamRequestType,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+    ) -> (BoxEndpointStream, BoxEndpointSink) {
+        forward_stream(self.target.clone(), type_, method, args)
+    }
+}
+
+/// Start the duplex stream `method` through `target` and relay the messages between it and the
+/// returned source and sink.
+///
+/// Use this in a [Service::add_duplex] handler to splice a stream of the peer with a stream on
+/// another connection. Messages are passed on one at a time, like with [service].
+pub fn forward_duplex(
+    target: RequestSender,
+    method: Vec<String>,
+    args: Vec<serde_json::Value>,
+) -> (BoxEndpointStream, BoxEndpointSink) {
+    forward_stream(
+        ready_target(target),
+        StreamRequestType::Duplex,
+        method,
+        args,
+    )
+}
+
+fn forward_stream(
+    target: Target,
+    type_: StreamRequestType,
+    method: Vec<String>,
+    args: Vec<serde_json::Value>,
+) -> (BoxEndpointStream, BoxEndpointSink) {
+    let (source_sender, source) = oneshot::channel::<Result<BoxStreamSource, Error>>();
+    // Without buffer the peer's next message is only accepted once the previous one was
+    // sent on the other connection.
+    let (messages_sender, messages) = mpsc::channel::<StreamMessage>(0);
+    spawn("rpc proxy stream", async move {
+        let started = match target_sender(target).await {
+            Some(mut sender) => sender
+                .start_stream(type_, method, args)
+                .await
+                .map_err(|error| Error::new(PROXY_ERROR, error)),
+            None => Err(not_connected_error()),
+        };
+        match started {
+            Ok((source, sink)) => {
+                let _ = source_sender.send(Ok(source));
+                forward_messages(messages, sink).await;
+            }
+            Err(error) => {
+                let _ = source_sender.send(Err(error));
+            }
+        }
+    });
+    let source = source
+        .map(|source| match source {
+            Ok(Ok(source)) => source,
+            Ok(Err(error)) => stream::once(future::ready(Err(error))).boxed(),
+            Err(oneshot::Canceled) => stream::empty().boxed(),
+        })
+        .flatten_stream();
+    (
+        source.boxed(),
+        Box::pin(messages_sender.sink_map_err(|_| SinkClosed)),
+    )
+}
+
+/// Send the stream messages of the peer to the other connection until the peer ends the stream.
+async fn forward_messages(mut messages: mpsc::Receiver<StreamMessage>, mut sink: StreamSink) {
+    let result = loop {
+        match messages.next().await {
+            Some(StreamMessage::Data(body)) => {
+                if let Err(error) = sink.send(body).await {
+                    break Err(error);
+                }
+            }
+            Some(StreamMessage::Error(error)) => break sink.error(error).await,
+            // The stream is dropped without an end message if the peer's connection closes.
+            Some(StreamMessage::End) | None => break sink.close().await,
+        }
+    };
+    if let Err(error) = result {
+        tracing::debug!(?error, "failed to forward stream message");
+    }
+}
+
+fn not_connected_error() -> Error {
+    Error::new(PROXY_ERROR, "Proxy is not connected")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_std::os::unix::net::UnixStream;
+
+    fn echo_service() -> Service {
+        let mut service = Service::new();
+        service.add_async("echo", |(value,): (serde_json::Value,)| {
+            future::ready(AsyncResponse::json_ok(&value))
+        });
+        service.add_source("count", |(count,): (u32,)| {
+            stream::iter(0..count).map(|index| Ok(super::super::Body::json(&index)))
+        });
+        service.add_duplex("double", |_: Vec<()>| {
+            let (sender, receiver) = mpsc::unbounded();
+            let sink = futures::sink::unfold(
+                Some(sender),
+                |sender: Option<mpsc::UnboundedSender<_>>, message| async move {
+                    match (sender, message) {
+                        (Some(sender), StreamMessage::Data(body)) => {
+                            let value = body.decode_json::<u32>().unwrap();
+                            let _ =
+                                sender.unbounded_send(Ok(super::super::Body::json(&(value * 2))));
+                            Ok::<_, SinkClosed>(Some(sender))
+                        }
+                        // Dropping the sender ends the source.
+                        _ => Ok(None),
+                    }
+                },
+            );
+            (receiver, sink)
+        });
+        service
+    }
+
+    #[async_std::test]
+    async fn relay_requests() {
+        let (server_io, proxy_server_io) = UnixStream::pair().unwrap();
+        let (client_io, proxy_client_io) = UnixStream::pair().unwrap();
+        let _server = Endpoint::from_io(server_io, echo_service());
+        let mut client = Endpoint::from_io(client_io, Service::new());
+        let (_proxy_client, _proxy_server) =
+            relay(proxy_client_io, proxy_server_io, EndpointConfig::default());
+        let client = client.client();
+
+        let response = client
+            .send_async(vec!["echo".to_string()], vec![serde_json::json!("hello")])
+            .await
+            .unwrap();
+        assert_eq!(response, client::AsyncResponse::Json(b"\"hello\"".to_vec()));
+
+        let missing = client
+            .send_async(vec!["missing".to_string()], vec![])
+            .await
+            .unwrap();
+        assert!(
+            matches!(missing, client::AsyncResponse::Error(Error { name, .. }) if name == "METHOD_NOT_FOUND")
+        );
+
+        let counted = client
+            .start_source(vec!["count".to_string()], vec![serde_json::json!(3)])
+            .await
+            .unwrap()
+            .map(|body| body.unwrap().decode_json::<u32>().unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(counted, vec![0, 1, 2]);
+
+        let (mut source, mut sink) = client
+            .start_duplex(vec!["double".to_string()], vec![])
+            .await
+            .unwrap();
+        for value in 1..=3u32 {
+            sink.send(super::super::Body::json(&value)).await.unwrap();
+            let doubled = source.next().await.unwrap().unwrap();
+            assert_eq!(doubled.decode_json::<u32>().unwrap(), value * 2);
+        }
+        sink.close().await.unwrap();
+        assert!(source.next().await.is_none());
+    }
+}
@@ -0,0 +1,140 @@
+//! Allocation of request numbers for outgoing requests.
+use std::collections::HashMap;
+
+use super::stream_message::StreamState;
+
+/// Largest request number that can be encoded in a packet header.
+pub(super) const MAX_REQUEST_NUMBER: u32 = i32::MAX as u32;
+
+/// Error returned when a new request is started while all request numbers are in use.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("All request numbers are in use")]
+pub struct RequestNumbersExhausted;
+
+/// Hands out request numbers and keeps track of the requests that use them.
+///
+/// Numbers are allocated in increasing order and wrap around after [MAX_REQUEST_NUMBER]. A number
+/// is only reused once its request is complete, that is the response to an `async` request has
+/// arrived or both directions of a stream have ended.
+#[derive(Debug)]
+pub(super) struct RequestNumbers {
+    next: u32,
+    max: u32,
+    active: HashMap<u32, StreamState>,
+}
+
+impl RequestNumbers {
+    pub(super) fn new() -> Self {
+        Self::with_max(MAX_REQUEST_NUMBER)
+    }
+
+    fn with_max(max: u32) -> Self {
+        Self {
+            next: 1,
+            max,
+            active: HashMap::new(),
+        }
+    }
+
+    pub(super) fn allocate(&mut self) -> Result<u32, RequestNumbersExhausted> {
+        if self.active.len() >= self.max as usize {
+            return Err(RequestNumbersExhausted);
+        }
+        loop {
+            let number = self.next;
+            self.next = if number >= self.max { 1 } else { number + 1 };
+            if let std::collections::hash_map::Entry::Vacant(entry) = self.active.entry(number) {
+                entry.insert(StreamState::Open);
+                return Ok(number);
+            }
+        }
+    }
+
+    /// Release the number of an `async` request or of a stream that ended abnormally.
+    pub(super) fn release(&mut self, number: u32) {
+        self.active.remove(&number);
+    }
+
+    /// Record that we sent the end message for stream `number`.
+    pub(super) fn end_local(&mut self, number: u32) {
+        self.update(number, StreamState::end_local)
+    }
+
+    /// Record that the peer sent the end message for stream `number`.
+    pub(super) fn end_remote(&mut self, number: u32) {
+        self.update(number, StreamState::end_remote)
+    }
+
+    /// Returns `true` if we have not sent the end message for the active request `number`.
+    pub(super) fn is_local_open(&self, number: u32) -> bool {
+        matches!(self.active.get(&number), Some(state) if state.is_local_open())
+    }
+
+    /// Numbers of the requests for which we have not sent the end message yet.
+    pub(super) fn local_open(&self) -> Vec<u32> {
+        self.active
+            .iter()
+            .filter(|(_, state)| state.is_local_open())
+            .map(|(number, _)| *number)
+            .collect()
+    }
+
+    fn update(&mut self, number: u32, f: impl FnOnce(StreamState) -> StreamState) {
+        if let Some(state) = self.active.get_mut(&number) {
+            *state = f(*state);
+            if state.is_closed() {
+                self.active.remove(&number);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn is_active(&self, number: u32) -> bool {
+        self.active.contains_key(&number)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn wrap_around() {
+        let mut numbers = RequestNumbers::with_max(3);
+        assert_eq!(numbers.allocate(), Ok(1));
+        assert_eq!(numbers.allocate(), Ok(2));
+        numbers.release(1);
+        assert_eq!(numbers.allocate(), Ok(3));
+        // 2 is still in use
+        assert_eq!(numbers.allocate(), Ok(1));
+        assert_eq!(numbers.allocate(), Err(RequestNumbersExhausted));
+        numbers.release(3);
+        assert_eq!(numbers.allocate(), Ok(3));
+    }
+
+    #[test]
+    fn stream_released_when_both_ended() {
+        let mut numbers = RequestNumbers::new();
+        let number = numbers.allocate().unwrap();
+        numbers.end_remote(number);
+        assert!(numbers.is_active(number));
+        numbers.end_remote(number);
+        assert!(numbers.is_active(number));
+        numbers.end_local(number);
+        assert!(!numbers.is_active(number));
+
+        let number = numbers.allocate().unwrap();
+        numbers.end_local(number);
+        assert!(numbers.is_active(number));
+        numbers.end_remote(number);
+        assert!(!numbers.is_active(number));
+    }
+
+    #[test]
+    fn max_request_number() {
+        let mut numbers = RequestNumbers::new();
+        numbers.next = MAX_REQUEST_NUMBER;
+        assert_eq!(numbers.allocate(), Ok(MAX_REQUEST_NUMBER));
+        assert_eq!(numbers.allocate(), Ok(1));
+    }
+}
@@ -0,0 +1,271 @@
+//! Describe and validate the arguments of RPC methods.
+//!
+//! An [ArgsSchema] is registered with [Service::set_schema][super::Service::set_schema]. Requests
+//! with arguments that do not match the schema are rejected with an `ArgumentError` that names
+//! every offending argument and field.
+//!
+//! ```rust
+//! # use ssb::rpc::base::schema::{ArgSchema, ArgType, ArgsSchema};
+//! let schema = ArgsSchema::new()
+//!     .arg(ArgSchema::new("id", ArgType::String).description("Feed ID"))
+//!     .arg(
+//!         ArgSchema::new("opts", ArgType::Object)
+//!             .optional()
+//!             .field(ArgSchema::new("limit", ArgType::Number).optional()),
+//!     );
+//!
+//! let args = vec![serde_json::json!(1), serde_json::json!({ "limit": "10" })];
+//! let error = schema.validate(&args).unwrap_err();
+//! assert_eq!(error.name, "ArgumentError");
+//! assert_eq!(
+//!     error.message,
+//!     "Invalid arguments: `id`: expected string, found number; `opts.limit`: expected number, found string"
+//! );
+//! ```
+
+use super::Error;
+
+/// Schema for the positional arguments of a method.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArgsSchema {
+    pub args: Vec<ArgSchema>,
+}
+
+impl ArgsSchema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a positional argument to the schema.
+    pub fn arg(mut self, arg: ArgSchema) -> Self {
+        self.args.push(arg);
+        self
+    }
+
+    /// Check `args` against the schema.
+    ///
+    /// Returns an `ArgumentError` listing all violations if the arguments are invalid. Surplus
+    /// arguments are rejected.
+    pub fn validate(&self, args: &[serde_json::Value]) -> Result<(), Error> {
+        let mut violations = Vec::new();
+        for (index, arg_schema) in self.args.iter().enumerate() {
+            arg_schema.validate(args.get(index), &arg_schema.name, &mut violations);
+        }
+        if args.len() > self.args.len() {
+            violations.push(format!(
+                "expected at most {} arguments, got {}",
+                self.args.len(),
+                args.len()
+            ));
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(argument_error(format!(
+                "Invalid arguments: {}",
+                violations.join("; ")
+            )))
+        }
+    }
+}
+
+/// Schema for a single argument or for a field of an object argument.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArgSchema {
+    pub name: String,
+    pub type_: ArgType,
+    pub optional: bool,
+    pub description: Option<String>,
+    /// Schemas for the fields of an [ArgType::Object] value. Fields not listed are not checked.
+    pub fields: Vec<ArgSchema>,
+}
+
+impl ArgSchema {
+    pub fn new(name: impl ToString, type_: ArgType) -> Self {
+        Self {
+            name: name.to_string(),
+            type_,
+            optional: false,
+            description: None,
+            fields: Vec::new(),
+        }
+    }
+
+    /// Mark the argument as optional. `null` and missing values are accepted.
+    pub fn optional(mut self) -> Self {
+        self.optional = true;
+        self
+    }
+
+    pub fn description(mut self, description: impl ToString) -> Self {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    /// Add a schema for a field of an object argument.
+    pub fn field(mut self, field: ArgSchema) -> Self {
+        self.fields.push(field);
+        self
+    }
+
+    fn validate(
+        &self,
+        value: Option<&serde_json::Value>,
+        path: &str,
+        violations: &mut Vec<String>,
+    ) {
+        let value = match value {
+            None | Some(serde_json::Value::Null) => {
+                if !self.optional {
+                    violations.push(format!("`{}`: missing required value", path));
+                }
+                return;
+            }
+            Some(value) => value,
+        };
+
+        if !self.type_.matches(value) {
+            violations.push(format!(
+                "`{}`: expected {}, found {}",
+                path,
+                self.type_.name(),
+                ArgType::of(value).name()
+            ));
+            return;
+        }
+
+        if let serde_json::Value::Object(object) = value {
+            for field in &self.fields {
+                field.validate(
+                    object.get(&field.name),
+                    &format!("{}.{}", path, field.name),
+                    violations,
+                );
+            }
+        }
+    }
+}
+
+/// JSON type of an argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgType {
+    /// Matches any value
+    Any,
+    Boolean,
+    Number,
+    String,
+    Array,
+    Object,
+}
+
+impl ArgType {
+    /// Returns the type name as used in `help` responses.
+    pub fn name(self) -> &'static str {
+        match self {
+            ArgType::Any => "any",
+            ArgType::Boolean => "boolean",
+            ArgType::Number => "number",
+            ArgType::String => "string",
+            ArgType::Array => "array",
+            ArgType::Object => "object",
+        }
+    }
+
+    fn matches(self, value: &serde_json::Value) -> bool {
+        self == ArgType::Any || self == Self::of(value)
+    }
+
+    fn of(value: &serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => ArgType::Any,
+            serde_json::Value::Bool(_) => ArgType::Boolean,
+            serde_json::Value::Number(_) => ArgType::Number,
+            serde_json::Value::String(_) => ArgType::String,
+            serde_json::Value::Array(_) => ArgType::Array,
+            serde_json::Value::Object(_) => ArgType::Object,
+        }
+    }
+}
+
+/// Deserialize method arguments as `Args`.
+///
+/// In contrast to plain [serde_json::from_value] the error names the argument or field that
+/// failed to deserialize.
+pub(super) fn deserialize_args<Args: serde::de::DeserializeOwned>(
+    args: Vec<serde_json::Value>,
+) -> Result<Args, Error> {
+    serde_path_to_error::deserialize(serde_json::Value::Array(args)).map_err(|error| {
+        argument_error(format!(
+            "Failed to deserialize argument `{}`: {}",
+            error.path(),
+            error.inner()
+        ))
+    })
+}
+
+fn argument_error(message: String) -> Error {
+    Error {
+        name: "ArgumentError".to_string(),
+        message,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validate_ok() {
+        let schema = ArgsSchema::new()
+            .arg(ArgSchema::new("id", ArgType::String))
+            .arg(ArgSchema::new("opts", ArgType::Object).optional());
+
+        assert_eq!(schema.validate(&[serde_json::json!("@feed")]), Ok(()));
+        assert_eq!(
+            schema.validate(&[serde_json::json!("@feed"), serde_json::Value::Null]),
+            Ok(())
+        );
+        assert_eq!(
+            schema.validate(&[serde_json::json!("@feed"), serde_json::json!({})]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn validate_missing_and_surplus() {
+        let schema = ArgsSchema::new().arg(ArgSchema::new("id", ArgType::String));
+
+        assert_eq!(
+            schema.validate(&[]).unwrap_err().message,
+            "Invalid arguments: `id`: missing required value"
+        );
+        assert_eq!(
+            schema
+                .validate(&[serde_json::json!("a"), serde_json::json!("b")])
+                .unwrap_err()
+                .message,
+            "Invalid arguments: expected at most 1 arguments, got 2"
+        );
+    }
+
+    #[test]
+    fn deserialize_args_path() {
+        #[derive(Debug, serde::Deserialize)]
+        struct Opts {
+            #[allow(dead_code)]
+            limit: u32,
+        }
+
+        let error =
+            deserialize_args::<(Opts,)>(vec![serde_json::json!({ "limit": "x" })]).unwrap_err();
+        assert_eq!(error.name, "ArgumentError");
+        assert!(
+            error
+                .message
+                .starts_with("Failed to deserialize argument `[0].limit`: invalid type"),
+            "{}",
+            error.message
+        );
+    }
+}
@@ -1,46 +1,178 @@
 use anyhow::Context;
 use futures::prelude::*;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
+use super::connection_closed::ConnectionClosed;
+use super::flow_control::{FlowControl, ReceiveWindow, SendCredit};
 use super::packet::{Request, Response};
-use super::service::{BoxEndpointSink, BoxEndpointStream, Error, Service, StreamMessage};
+use super::service::{
+    error_endpoint, AsyncResponse, BoxEndpointSink, BoxEndpointStream, Error, Service,
+    StreamMessage,
+};
+use super::stream_channel::StreamSender;
+use super::stream_message::StreamState;
 use super::stream_request::StreamRequest;
+use crate::utils::task::spawn;
+
+/// Options of [run] that the endpoint takes from its configuration.
+pub(super) struct ServerConfig {
+    pub(super) flow_control: FlowControl,
+    /// See [RequestDispatcher::stream_buffer].
+    pub(super) stream_buffer: Option<usize>,
+    /// See [RequestDispatcher::max_concurrent_requests].
+    pub(super) max_concurrent_requests: Option<usize>,
+}
 
 pub async fn run(
     service: Service,
+    handler_panics: HandlerPanics,
+    config: ServerConfig,
     request_stream: impl Stream<Item = Request> + Unpin + 'static + Send,
     response_sender: futures::channel::mpsc::Sender<Response>,
+    closed: ConnectionClosed,
 ) -> anyhow::Result<()> {
-    let mut request_stream = request_stream;
+    let ServerConfig {
+        flow_control,
+        stream_buffer,
+        max_concurrent_requests,
+    } = config;
+    let mut request_stream = request_stream.fuse();
+    let (local_end_sender, mut local_end_receiver) = futures::channel::mpsc::unbounded();
     let mut request_dispatcher = RequestDispatcher {
-        service,
+        service: Arc::new(service),
+        handler_panics,
+        flow_control,
+        stream_buffer,
+        max_concurrent_requests,
         response_sender,
+        closed,
+        local_end_sender,
+        pending_async: std::collections::HashSet::new(),
         streams: std::collections::HashMap::new(),
     };
-    while let Some(request) = request_stream.next().await {
-        request_dispatcher.handle_request(request)?;
+    loop {
+        futures::select_biased! {
+            number = local_end_receiver.select_next_some() => {
+                request_dispatcher.end_local(number);
+            }
+            request = request_stream.next() => match request {
+                Some(request) => request_dispatcher.handle_request(request).await?,
+                None => break,
+            },
+        }
     }
     Ok(())
 }
 
+/// Counts panics in service handlers.
+///
+/// A panic only fails the request or stream it occurred in. The peer receives a
+/// `HANDLER_PANIC` error for it and the endpoint keeps running.
+#[derive(Debug, Clone, Default)]
+pub(super) struct HandlerPanics {
+    count: Arc<AtomicU64>,
+}
+
+impl HandlerPanics {
+    pub(super) fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Record a panic of the handler for request `number` and return the error to send to the
+    /// peer.
+    fn report(&self, number: u32, payload: Box<dyn std::any::Any + Send>) -> Error {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let reason = if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "unknown reason".to_string()
+        };
+        tracing::error!(request = number, %reason, "request handler panicked");
+        Error {
+            name: "HANDLER_PANIC".to_string(),
+            message: format!("Handler for request {} panicked: {}", number, reason),
+        }
+    }
+}
+
 struct RequestDispatcher {
-    service: Service,
+    /// Handlers are called by the task of each request so that slow handlers don’t delay
+    /// messages for other requests. Handlers run concurrently, so the handlers for two requests
+    /// that arrive back to back may be called in either order.
+    service: Arc<Service>,
+    handler_panics: HandlerPanics,
+    flow_control: FlowControl,
+    /// Number of messages from the peer that are buffered for every stream. If the handler does
+    /// not consume them fast enough and the buffer overflows the stream fails with
+    /// [stream_buffer_full_error]. Unbounded if `None`.
+    stream_buffer: Option<usize>,
+    /// Requests that arrive while this many requests are active are rejected. Unlimited if `None`.
+    max_concurrent_requests: Option<usize>,
     response_sender: futures::channel::mpsc::Sender<Response>,
+    /// Cancels request handlers once the connection is gone.
+    closed: ConnectionClosed,
+    /// Receives the number of a request once its response or the end message of its source has
+    /// been sent.
+    local_end_sender: futures::channel::mpsc::UnboundedSender<u32>,
+    /// `async` requests that have not been answered yet.
+    pending_async: std::collections::HashSet<u32>,
+    /// Streams where at least one direction is still open.
     streams: std::collections::HashMap<u32, StreamHandle>,
 }
 
 impl RequestDispatcher {
-    fn handle_request(&mut self, msg: Request) -> anyhow::Result<()> {
+    async fn handle_request(&mut self, msg: Request) -> anyhow::Result<()> {
         tracing::trace!(?msg, "handle request");
         match msg {
             Request::Async {
                 number,
                 method,
+                type_: _,
                 args,
             } => {
-                let response_fut = self.service.handle_async(method, args);
+                if self.is_active(number) {
+                    self.reject(
+                        AsyncResponse::Err(number_in_use_error(number)).into_response(number),
+                    );
+                    return Ok(());
+                }
+                if let Some(error) = self.check_limit() {
+                    self.reject(AsyncResponse::Err(error).into_response(number));
+                    return Ok(());
+                }
+                self.pending_async.insert(number);
+                let service = Arc::clone(&self.service);
+                let handler_panics = self.handler_panics.clone();
                 let mut response_sender = self.response_sender.clone();
-                async_std::task::spawn(async move {
-                    let response = response_fut.await;
+                let local_end_sender = self.local_end_sender.clone();
+                let closed = self.closed.wait();
+                spawn("rpc server async response", async move {
+                    // We don’t distinguish between `sync` and `async` requests. How a request is
+                    // handled only depends on the method registered with the service.
+                    let response_fut = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        service.handle_async(method, args)
+                    }));
+                    let response = match response_fut {
+                        Ok(response_fut) => AssertUnwindSafe(response_fut).catch_unwind().boxed(),
+                        Err(payload) => future::ready(Err(payload)).boxed(),
+                    };
+                    let response = match future::select(response, closed).await {
+                        future::Either::Left((response, _)) => response,
+                        future::Either::Right(((), _)) => {
+                            tracing::debug!(number, "cancelled async request");
+                            return;
+                        }
+                    }
+                    .unwrap_or_else(|payload| {
+                        AsyncResponse::Err(handler_panics.report(number, payload))
+                    });
+                    // Notify the dispatcher before the peer can see the response and reuse the
+                    // number.
+                    let _ = local_end_sender.unbounded_send(number);
                     let result = response_sender.send(response.into_response(number)).await;
                     if let Err(error) = result {
                         tracing::warn!(response_id = ?number, ?error, "Failed to send response");
@@ -50,95 +182,283 @@ impl RequestDispatcher {
             Request::Stream { number, message } => match message {
                 StreamMessage::Data(body) => {
                     if let Some(stream) = self.streams.get_mut(&number) {
-                        stream.incoming(StreamMessage::Data(body));
+                        if stream.state.is_remote_open() {
+                            let within_window =
+                                stream.window.as_ref().is_none_or(ReceiveWindow::receive);
+                            if !within_window {
+                                anyhow::bail!("Data on stream {} exceeds the credit", number);
+                            }
+                            stream.incoming(number, StreamMessage::Data(body));
+                        } else {
+                            // The peer ended the stream and reuses the number before we did.
+                            self.reject(
+                                StreamMessage::Error(number_in_use_error(number))
+                                    .into_response(number),
+                            );
+                        }
+                    } else if self.pending_async.contains(&number) {
+                        self.reject(
+                            StreamMessage::Error(number_in_use_error(number)).into_response(number),
+                        );
                     } else {
                         let StreamRequest { name, type_, args } = body
                             .decode_json()
                             .context("Failed to parse stream request")?;
                         tracing::debug!(name = ?name.join("."), ?type_, "stream request");
-                        let (source, sink) = self.service.handle_stream(name, args);
-                        let stream_handle =
-                            StreamHandle::new(number, self.response_sender.clone(), source, sink);
+                        let flow_control =
+                            super::flow_control::requested_credit(&body).and_then(|credit| {
+                                self.flow_control
+                                    .open_stream((number as i32).wrapping_neg(), credit)
+                            });
+                        // A rejected stream is still tracked so that further messages from the
+                        // peer are consumed until it ends the stream.
+                        let limit_error = self.check_limit();
+                        let service = Arc::clone(&self.service);
+                        let endpoint = move || match limit_error {
+                            Some(error) => error_endpoint(error),
+                            None => service.handle_stream(type_, name, args),
+                        };
+                        let stream_handle = StreamHandle::new(number, self, flow_control, endpoint);
                         self.streams.insert(number, stream_handle);
                     }
                 }
                 StreamMessage::Error(_) | StreamMessage::End => {
-                    if let Some(mut stream) = self.streams.remove(&number) {
-                        stream.incoming(message);
+                    if let Some(stream) = self.streams.get_mut(&number) {
+                        if stream.state.is_remote_open() {
+                            stream.incoming(number, message);
+                            stream.state = stream.state.end_remote();
+                            self.release_if_closed(number);
+                        } else {
+                            tracing::debug!(number, "repeated end message from peer");
+                        }
                     } else {
-                        let mut response_sender = self.response_sender.clone();
-                        async_std::task::spawn(async move {
-                            // We don’t care if the connection has been dropped
-                            let _ = response_sender
-                                .send(
-                                    StreamMessage::Error(Error {
-                                        name: "STREAM_DOES_NOT_EXIST".to_string(),
-                                        message: format!(
-                                            "Stream with ID {:?} does not exist",
-                                            number
-                                        ),
-                                    })
-                                    .into_response(number),
-                                )
-                                .await;
-                        });
+                        self.reject(
+                            StreamMessage::Error(Error {
+                                name: "STREAM_DOES_NOT_EXIST".to_string(),
+                                message: format!("Stream with ID {:?} does not exist", number),
+                            })
+                            .into_response(number),
+                        );
                     }
                 }
             },
         }
         Ok(())
     }
+
+    /// Send an error response for a request that is not handled.
+    fn reject(&self, response: Response) {
+        tracing::debug!(?response, "rejecting request");
+        let mut response_sender = self.response_sender.clone();
+        spawn("rpc server reject", async move {
+            // We don’t care if the connection has been dropped
+            let _ = response_sender.send(response).await;
+        });
+    }
+
+    /// Returns the error for a new request if [RequestDispatcher::max_concurrent_requests] are
+    /// already active.
+    fn check_limit(&self) -> Option<Error> {
+        let limit = self.max_concurrent_requests?;
+        if self.pending_async.len() + self.streams.len() < limit {
+            return None;
+        }
+        tracing::debug!(limit, "too many concurrent requests");
+        Some(Error {
+            name: "TOO_MANY_REQUESTS".to_string(),
+            message: format!("Only {} requests may be active at the same time", limit),
+        })
+    }
+
+    /// Returns true if the peer uses `number` for a request that has not completed.
+    fn is_active(&self, number: u32) -> bool {
+        self.pending_async.contains(&number) || self.streams.contains_key(&number)
+    }
+
+    /// Record that the response to request `number` or the end message of the stream’s source
+    /// has been sent.
+    fn end_local(&mut self, number: u32) {
+        if self.pending_async.remove(&number) {
+            return;
+        }
+        if let Some(stream) = self.streams.get_mut(&number) {
+            stream.state = stream.state.end_local();
+            self.release_if_closed(number);
+        }
+    }
+
+    /// Forget the stream if both directions have ended so that the peer may reuse its number.
+    fn release_if_closed(&mut self, number: u32) {
+        if let Some(stream) = self.streams.get(&number) {
+            if stream.state.is_closed() {
+                self.streams.remove(&number);
+            }
+        }
+    }
+}
+
+fn number_in_use_error(number: u32) -> Error {
+    Error {
+        name: "REQUEST_NUMBER_IN_USE".to_string(),
+        message: format!("Request number {} is used by an active request", number),
+    }
+}
+
+fn stream_buffer_full_error(number: u32) -> Error {
+    Error {
+        name: "STREAM_BUFFER_FULL".to_string(),
+        message: format!(
+            "Stream {} received more messages than the handler consumed",
+            number
+        ),
+    }
 }
 
 /// Handle for the dipsatcher to communicate with the stream created by [Service].
 struct StreamHandle {
-    incoming_sender: futures::channel::mpsc::UnboundedSender<StreamMessage>,
+    incoming_sender: StreamSender<StreamMessage>,
+    /// Fails the stream. The source task sends the error to the peer and ends our side.
+    fail_sender: futures::channel::mpsc::UnboundedSender<Error>,
+    state: StreamState,
+    /// Set if the stream is flow controlled.
+    window: Option<ReceiveWindow>,
 }
 
 impl StreamHandle {
+    /// Spawn the tasks of the stream. `endpoint` creates the handler’s source and sink. It is
+    /// called by the source task. Messages from the peer are buffered until the sink exists.
+    ///
+    /// If the stream is flow controlled the source waits for credit before it sends a message
+    /// and the peer is granted credit as the sink consumes messages.
     fn new(
         stream_id: u32,
-        response_sink: futures::channel::mpsc::Sender<Response>,
-        source: BoxEndpointStream,
-        sink: BoxEndpointSink,
+        dispatcher: &RequestDispatcher,
+        flow_control: Option<(SendCredit, ReceiveWindow)>,
+        endpoint: impl FnOnce() -> (BoxEndpointStream, BoxEndpointSink) + Send + 'static,
     ) -> Self {
+        let response_sink = dispatcher.response_sender.clone();
+        let local_end_sender = dispatcher.local_end_sender.clone();
+        let handler_panics = dispatcher.handler_panics.clone();
+        let closed = &dispatcher.closed;
+        // The dispatcher never waits for the sink. A full buffer fails the stream instead.
         let (incoming_sender, incoming_receiver) =
-            futures::channel::mpsc::unbounded::<StreamMessage>();
+            super::stream_channel::channel::<StreamMessage>(dispatcher.stream_buffer);
+        // Notifies the source task when the sink panicked or the buffer overflowed so that the
+        // peer receives the error.
+        let (fail_sender, fail_receiver) = futures::channel::mpsc::unbounded::<Error>();
+        let sink_fail_sender = fail_sender.clone();
+
+        let (credit, window) = flow_control.unzip();
+        let sink_window = window.clone();
+        let source_handler_panics = handler_panics.clone();
+        let mut source_closed = closed.wait().fuse();
+        let sink_closed = closed.wait();
+        spawn("rpc server stream source", async move {
+            let (source, sink) = std::panic::catch_unwind(AssertUnwindSafe(endpoint))
+                .unwrap_or_else(|payload| {
+                    error_endpoint(source_handler_panics.report(stream_id, payload))
+                });
+
+            spawn("rpc server stream sink", async move {
+                let incoming = incoming_receiver.inspect(move |message| {
+                    if let (StreamMessage::Data(_), Some(window)) = (message, &sink_window) {
+                        window.consume();
+                    }
+                });
+                let forward = AssertUnwindSafe(incoming.map(Ok).forward(sink)).catch_unwind();
+                match future::select(forward, sink_closed).await {
+                    future::Either::Left((Err(payload), _)) => {
+                        let _ = sink_fail_sender
+                            .unbounded_send(handler_panics.report(stream_id, payload));
+                    }
+                    future::Either::Left((Ok(_), _)) => {}
+                    future::Either::Right(((), _)) => {
+                        tracing::debug!(stream_id, "cancelled stream sink");
+                    }
+                }
+            });
 
-        async_std::task::spawn(async move {
-            let mut source = source;
+            let mut source = AssertUnwindSafe(source).catch_unwind().fuse();
+            let mut fail_receiver = fail_receiver;
             let mut response_sink = response_sink;
             loop {
-                let item = source.next().await;
-                let message = match item {
-                    None => StreamMessage::End,
-                    Some(Ok(body)) => StreamMessage::Data(body),
-                    Some(Err(error)) => StreamMessage::Error(error),
+                let message = futures::select_biased! {
+                    () = source_closed => {
+                        tracing::debug!(stream_id, "cancelled stream source");
+                        break;
+                    },
+                    error = fail_receiver.select_next_some() => StreamMessage::Error(error),
+                    item = source.next() => match item {
+                        None => StreamMessage::End,
+                        Some(Ok(Ok(body))) => StreamMessage::Data(body),
+                        Some(Ok(Err(error))) => StreamMessage::Error(error),
+                        Some(Err(payload)) => {
+                            StreamMessage::Error(source_handler_panics.report(stream_id, payload))
+                        }
+                    },
                 };
+                if let (StreamMessage::Data(_), Some(credit)) = (&message, &credit) {
+                    futures::select_biased! {
+                        () = source_closed => {
+                            tracing::debug!(stream_id, "cancelled stream source");
+                            break;
+                        },
+                        () = credit.acquire().fuse() => {},
+                    }
+                }
                 let message_is_end = message.is_end();
+                if message_is_end {
+                    // Notify the dispatcher before the peer can see the end message and reuse
+                    // the number. The dispatcher is gone if the connection has been closed.
+                    let _ = local_end_sender.unbounded_send(stream_id);
+                }
                 let result = response_sink.send(message.into_response(stream_id)).await;
                 if result.is_err() || message_is_end {
                     break;
                 }
             }
+            // Release the handler’s resources before the response channel closes.
+            drop(source);
         });
 
-        async_std::task::spawn(async move {
-            let _ = incoming_receiver.map(Ok).forward(sink).await;
-        });
-
-        Self { incoming_sender }
+        Self {
+            incoming_sender,
+            fail_sender,
+            state: StreamState::Open,
+            window,
+        }
     }
 
-    fn incoming(&mut self, stream_message: StreamMessage) {
-        let _ = self.incoming_sender.unbounded_send(stream_message);
+    /// Pass a message from the peer to the sink task of the stream without waiting for the sink.
+    ///
+    /// If the buffer is full the stream fails with [stream_buffer_full_error] and the handler’s
+    /// sink is closed. Further messages are dropped.
+    fn incoming(&mut self, stream_id: u32, stream_message: StreamMessage) {
+        let is_end = stream_message.is_end();
+        match self.incoming_sender.try_send(stream_message) {
+            Err(error) if error.is_full() && !is_end => {
+                tracing::debug!(stream_id, "stream buffer full");
+                let _ = self
+                    .fail_sender
+                    .unbounded_send(stream_buffer_full_error(stream_id));
+                self.incoming_sender.close_channel();
+            }
+            // The sink task is gone if the handler’s sink failed or the buffer overflowed
+            // before. We drop the message in that case.
+            _ => {}
+        }
+        if is_end {
+            // Let the sink finish even though the stream stays registered until our side ends.
+            self.incoming_sender.close_channel();
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::rpc::base::packet::Body;
+    use crate::rpc::base::packet::{Body, RequestType};
+    use crate::rpc::base::service::SinkClosed;
     use crate::rpc::base::stream_request::{StreamRequest, StreamRequestType};
 
     #[async_std::test]
@@ -203,9 +523,9 @@ mod test {
 
         let mut service = Service::new();
         let (source_sender, source) = futures::channel::mpsc::unbounded();
-        let source_cell = std::cell::RefCell::new(Some(source));
+        let source_cell = std::sync::Mutex::new(Some(source));
         service.add_source("source", move |_: Vec<()>| {
-            source_cell.borrow_mut().take().unwrap()
+            source_cell.lock().unwrap().take().unwrap()
         });
 
         let mut test_dispatcher = TestDispatcher::new(service);
@@ -366,6 +686,129 @@ mod test {
         );
     }
 
+    #[async_std::test]
+    async fn sync_request() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let mut service = Service::new();
+        service.add_sync("syncEcho", |(value,): (String,)| {
+            super::super::service::AsyncResponse::Ok(Body::String(value))
+        });
+
+        let mut test_dispatcher = TestDispatcher::new(service);
+
+        for (number, type_) in [(1, RequestType::Sync), (2, RequestType::Async)] {
+            test_dispatcher
+                .send(Request::Async {
+                    number,
+                    method: vec!["syncEcho".to_string()],
+                    type_,
+                    args: vec![serde_json::json!("hello")],
+                })
+                .await;
+            let response = test_dispatcher.recv().await.unwrap();
+            assert_eq!(
+                response,
+                Response::AsyncOk {
+                    number,
+                    body: Body::String("hello".to_string())
+                }
+            );
+        }
+
+        let responses = test_dispatcher.end().await;
+        assert_eq!(responses, vec![]);
+    }
+
+    #[async_std::test]
+    async fn manifest_request() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let mut service = Service::new();
+        service.add_source("source", |_: Vec<()>| futures::stream::pending());
+
+        let mut test_dispatcher = TestDispatcher::new(service);
+        test_dispatcher
+            .send(Request::Async {
+                number: 1,
+                method: vec!["manifest".to_string()],
+                type_: RequestType::Sync,
+                args: vec![],
+            })
+            .await;
+        let response = test_dispatcher.recv().await.unwrap();
+        let body = match response {
+            Response::AsyncOk { number: 1, body } => body,
+            response => panic!("Unexpected response {:?}", response),
+        };
+        assert_eq!(
+            body.decode_json::<serde_json::Value>().unwrap(),
+            serde_json::json!({
+                "manifest": "sync",
+                "help": "sync",
+                "source": "source",
+            })
+        );
+    }
+
+    #[async_std::test]
+    async fn method_not_found() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        // Client-only endpoints have an empty service and answer every request with an error.
+        let mut test_dispatcher = TestDispatcher::new(Service::new());
+        let error =
+            crate::rpc::base::Error::new("METHOD_NOT_FOUND", "Method \"missing\" not found");
+
+        for (number, type_) in [(1, RequestType::Sync), (2, RequestType::Async)] {
+            test_dispatcher
+                .send(Request::Async {
+                    number,
+                    method: vec!["missing".to_string()],
+                    type_,
+                    args: vec![],
+                })
+                .await;
+            let response = test_dispatcher.recv().await.unwrap();
+            assert_eq!(
+                response,
+                Response::AsyncErr {
+                    number,
+                    name: error.name.clone(),
+                    message: error.message.clone(),
+                }
+            );
+        }
+
+        for (number, type_) in [
+            (3, StreamRequestType::Source),
+            (4, StreamRequestType::Sink),
+            (5, StreamRequestType::Duplex),
+        ] {
+            test_dispatcher
+                .send(
+                    StreamRequest {
+                        name: vec!["missing".to_string()],
+                        type_,
+                        args: vec![],
+                    }
+                    .into_request(number),
+                )
+                .await;
+            let response = test_dispatcher.recv().await.unwrap();
+            assert_eq!(
+                response,
+                StreamMessage::Error(error.clone()).into_response(number)
+            );
+            test_dispatcher
+                .send(StreamMessage::End.into_request(number))
+                .await;
+        }
+
+        let responses = test_dispatcher.end().await;
+        assert_eq!(responses, vec![]);
+    }
+
     #[async_std::test]
     async fn connection_closed() {
         let _ = tracing_subscriber::fmt::try_init();
@@ -402,23 +845,507 @@ mod test {
         test_dispatcher.end().await;
     }
 
+    #[async_std::test]
+    async fn async_handler_panic() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let mut service = Service::new();
+        service.add_sync("syncPanic", |_: Vec<()>| -> AsyncResponse {
+            panic!("sync failed")
+        });
+        service.add_async("asyncPanic", |_: Vec<()>| async { panic!("async failed") });
+        service.add_sync("ok", |_: Vec<()>| AsyncResponse::json_ok(&true));
+
+        let mut test_dispatcher = TestDispatcher::new(service);
+
+        for (number, method, reason) in [
+            (1, "syncPanic", "sync failed"),
+            (2, "asyncPanic", "async failed"),
+        ] {
+            test_dispatcher
+                .send(Request::Async {
+                    number,
+                    method: vec![method.to_string()],
+                    type_: RequestType::Async,
+                    args: vec![],
+                })
+                .await;
+            let response = test_dispatcher.recv().await.unwrap();
+            assert_eq!(
+                response,
+                Response::AsyncErr {
+                    number,
+                    name: "HANDLER_PANIC".to_string(),
+                    message: format!("Handler for request {} panicked: {}", number, reason),
+                }
+            );
+        }
+
+        test_dispatcher
+            .send(Request::Async {
+                number: 3,
+                method: vec!["ok".to_string()],
+                type_: RequestType::Async,
+                args: vec![],
+            })
+            .await;
+        let response = test_dispatcher.recv().await.unwrap();
+        assert_eq!(
+            response,
+            Response::AsyncOk {
+                number: 3,
+                body: Body::json(&true)
+            }
+        );
+        assert_eq!(test_dispatcher.handler_panics.count(), 2);
+        test_dispatcher.end().await;
+    }
+
+    #[async_std::test]
+    async fn source_panic() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let mut service = Service::new();
+        service.add_source("source", |_: Vec<()>| {
+            futures::stream::poll_fn(|_| -> std::task::Poll<Option<Result<Body, Error>>> {
+                panic!("source failed")
+            })
+        });
+
+        let mut test_dispatcher = TestDispatcher::new(service);
+
+        test_dispatcher
+            .send(
+                StreamRequest {
+                    name: vec!["source".to_string()],
+                    type_: StreamRequestType::Source,
+                    args: vec![],
+                }
+                .into_request(1),
+            )
+            .await;
+        let response = test_dispatcher.recv().await.unwrap();
+        assert_eq!(
+            response,
+            StreamMessage::Error(Error {
+                name: "HANDLER_PANIC".to_string(),
+                message: "Handler for request 1 panicked: source failed".to_string(),
+            })
+            .into_response(1)
+        );
+        assert_eq!(test_dispatcher.handler_panics.count(), 1);
+        test_dispatcher
+            .send(StreamMessage::End.into_request(1))
+            .await;
+        let responses = test_dispatcher.end().await;
+        assert_eq!(responses, vec![]);
+    }
+
+    #[async_std::test]
+    async fn sink_panic() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let mut service = Service::new();
+        service.add_sink("sink", |_: Vec<()>| {
+            futures::sink::drain::<StreamMessage>()
+                .sink_map_err(|infallible| match infallible {})
+                .with(
+                    |_| -> futures::future::Ready<
+                        Result<StreamMessage, super::super::service::SinkError>,
+                    > { panic!("sink failed") },
+                )
+        });
+
+        let mut test_dispatcher = TestDispatcher::new(service);
+
+        test_dispatcher
+            .send(
+                StreamRequest {
+                    name: vec!["sink".to_string()],
+                    type_: StreamRequestType::Sink,
+                    args: vec![],
+                }
+                .into_request(1),
+            )
+            .await;
+        test_dispatcher
+            .send(StreamMessage::Data(Body::String("".to_string())).into_request(1))
+            .await;
+        let response = test_dispatcher.recv().await.unwrap();
+        assert_eq!(
+            response,
+            StreamMessage::Error(Error {
+                name: "HANDLER_PANIC".to_string(),
+                message: "Handler for request 1 panicked: sink failed".to_string(),
+            })
+            .into_response(1)
+        );
+        assert_eq!(test_dispatcher.handler_panics.count(), 1);
+        test_dispatcher.end().await;
+    }
+
+    #[async_std::test]
+    async fn stream_buffer_full() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let mut service = Service::new();
+        service.add_sink("stalled", |_: Vec<()>| {
+            futures::sink::drain::<StreamMessage>()
+                .sink_map_err(|infallible| match infallible {})
+                .with(|_: StreamMessage| future::pending())
+        });
+
+        let mut test_dispatcher = TestDispatcher::with_options(service, Some(1), None);
+
+        test_dispatcher
+            .send(
+                StreamRequest {
+                    name: vec!["stalled".to_string()],
+                    type_: StreamRequestType::Sink,
+                    args: vec![],
+                }
+                .into_request(1),
+            )
+            .await;
+        // The handler never consumes these messages.
+        for _ in 0..10 {
+            test_dispatcher
+                .send(StreamMessage::Data(Body::String("".to_string())).into_request(1))
+                .await;
+        }
+        let response = test_dispatcher.recv().await.unwrap();
+        assert_eq!(
+            response,
+            StreamMessage::Error(stream_buffer_full_error(1)).into_response(1)
+        );
+
+        // Messages after the overflow are dropped and the peer may end the stream.
+        test_dispatcher
+            .send(StreamMessage::Data(Body::String("".to_string())).into_request(1))
+            .await;
+        test_dispatcher
+            .send(StreamMessage::End.into_request(1))
+            .await;
+        assert_eq!(test_dispatcher.end().await, vec![]);
+    }
+
+    #[async_std::test]
+    async fn duplex_remote_end_first() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let (source_sender, source) = futures::channel::mpsc::unbounded();
+        let (sink, sink_receiver) = futures::channel::mpsc::unbounded::<StreamMessage>();
+        let endpoint = std::sync::Mutex::new(Some((source, sink)));
+        let mut service = Service::new();
+        service.add_duplex("duplex", move |_: Vec<()>| {
+            let (source, sink) = endpoint.lock().unwrap().take().unwrap();
+            (source, sink.sink_map_err(|_| SinkClosed))
+        });
+        service.add_source("source", |_: Vec<()>| futures::stream::empty());
+
+        let mut test_dispatcher = TestDispatcher::new(service);
+
+        test_dispatcher
+            .send(
+                StreamRequest {
+                    name: vec!["duplex".to_string()],
+                    type_: StreamRequestType::Duplex,
+                    args: vec![],
+                }
+                .into_request(1),
+            )
+            .await;
+        test_dispatcher
+            .send(StreamMessage::End.into_request(1))
+            .await;
+        // Repeated end messages are ignored
+        test_dispatcher
+            .send(StreamMessage::End.into_request(1))
+            .await;
+        assert_eq!(
+            sink_receiver.collect::<Vec<_>>().await,
+            vec![StreamMessage::End]
+        );
+
+        // Our half of the stream is still open
+        source_sender
+            .unbounded_send(Ok(Body::String("foo".to_string())))
+            .unwrap();
+        let response = test_dispatcher.recv().await.unwrap();
+        assert_eq!(
+            response,
+            StreamMessage::Data(Body::String("foo".to_string())).into_response(1)
+        );
+        drop(source_sender);
+        let response = test_dispatcher.recv().await.unwrap();
+        assert_eq!(response, StreamMessage::End.into_response(1));
+
+        // The number is released once both directions have ended.
+        test_dispatcher
+            .send(
+                StreamRequest {
+                    name: vec!["source".to_string()],
+                    type_: StreamRequestType::Source,
+                    args: vec![],
+                }
+                .into_request(1),
+            )
+            .await;
+        let response = test_dispatcher.recv().await.unwrap();
+        assert_eq!(response, StreamMessage::End.into_response(1));
+        test_dispatcher
+            .send(StreamMessage::End.into_request(1))
+            .await;
+
+        let responses = test_dispatcher.end().await;
+        assert_eq!(responses, vec![]);
+    }
+
+    #[async_std::test]
+    async fn duplex_local_end_first() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let (sink, sink_receiver) = futures::channel::mpsc::unbounded::<StreamMessage>();
+        let sink_cell = std::sync::Mutex::new(Some(sink));
+        let mut service = Service::new();
+        service.add_duplex("duplex", move |_: Vec<()>| {
+            let sink = sink_cell.lock().unwrap().take().unwrap();
+            (futures::stream::empty(), sink.sink_map_err(|_| SinkClosed))
+        });
+
+        let mut test_dispatcher = TestDispatcher::new(service);
+
+        test_dispatcher
+            .send(
+                StreamRequest {
+                    name: vec!["duplex".to_string()],
+                    type_: StreamRequestType::Duplex,
+                    args: vec![],
+                }
+                .into_request(1),
+            )
+            .await;
+        let response = test_dispatcher.recv().await.unwrap();
+        assert_eq!(response, StreamMessage::End.into_response(1));
+
+        // The peer may still send data
+        test_dispatcher
+            .send(StreamMessage::Data(Body::String("foo".to_string())).into_request(1))
+            .await;
+        test_dispatcher
+            .send(StreamMessage::End.into_request(1))
+            .await;
+        assert_eq!(
+            sink_receiver.collect::<Vec<_>>().await,
+            vec![
+                StreamMessage::Data(Body::String("foo".to_string())),
+                StreamMessage::End
+            ]
+        );
+
+        // The stream has been released
+        test_dispatcher
+            .send(StreamMessage::End.into_request(1))
+            .await;
+        let responses = test_dispatcher.end().await;
+        assert_eq!(
+            responses,
+            vec![StreamMessage::Error(Error {
+                name: "STREAM_DOES_NOT_EXIST".to_string(),
+                message: "Stream with ID 1 does not exist".to_string()
+            })
+            .into_response(1)]
+        );
+    }
+
+    #[async_std::test]
+    async fn request_number_in_use() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let mut service = Service::new();
+        service.add_async("pending", |_: Vec<()>| {
+            futures::future::pending::<AsyncResponse>()
+        });
+
+        let mut test_dispatcher = TestDispatcher::new(service);
+
+        let request = Request::Async {
+            number: 1,
+            method: vec!["pending".to_string()],
+            type_: RequestType::Async,
+            args: vec![],
+        };
+        test_dispatcher.send(request.clone()).await;
+        test_dispatcher.send(request).await;
+        let response = test_dispatcher.recv().await.unwrap();
+        assert_eq!(
+            response,
+            Response::AsyncErr {
+                number: 1,
+                name: "REQUEST_NUMBER_IN_USE".to_string(),
+                message: "Request number 1 is used by an active request".to_string(),
+            }
+        );
+
+        test_dispatcher
+            .send(
+                StreamRequest {
+                    name: vec!["pending".to_string()],
+                    type_: StreamRequestType::Source,
+                    args: vec![],
+                }
+                .into_request(1),
+            )
+            .await;
+        let response = test_dispatcher.recv().await.unwrap();
+        assert_eq!(
+            response,
+            StreamMessage::Error(Error {
+                name: "REQUEST_NUMBER_IN_USE".to_string(),
+                message: "Request number 1 is used by an active request".to_string(),
+            })
+            .into_response(1)
+        );
+        test_dispatcher.close_connection();
+        test_dispatcher.end().await;
+    }
+
+    #[async_std::test]
+    async fn request_number_reused_after_response() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let mut service = Service::new();
+        service.add_sync("ok", |_: Vec<()>| AsyncResponse::json_ok(&true));
+
+        let mut test_dispatcher = TestDispatcher::new(service);
+
+        for _ in 0..3 {
+            test_dispatcher
+                .send(Request::Async {
+                    number: 1,
+                    method: vec!["ok".to_string()],
+                    type_: RequestType::Async,
+                    args: vec![],
+                })
+                .await;
+            let response = test_dispatcher.recv().await.unwrap();
+            assert_eq!(
+                response,
+                Response::AsyncOk {
+                    number: 1,
+                    body: Body::json(&true)
+                }
+            );
+        }
+        let responses = test_dispatcher.end().await;
+        assert_eq!(responses, vec![]);
+    }
+
+    #[async_std::test]
+    async fn max_concurrent_requests() {
+        let _ = tracing_subscriber::fmt::try_init();
+
+        let mut service = Service::new();
+        service.add_sync("ok", |_: Vec<()>| AsyncResponse::json_ok(&true));
+        service.add_async("pending", |_: Vec<()>| {
+            futures::future::pending::<AsyncResponse>()
+        });
+        service.add_source("source", |_: Vec<()>| futures::stream::pending());
+
+        let mut test_dispatcher = TestDispatcher::with_limit(service, Some(1));
+        let async_request = |number, method: &str| Request::Async {
+            number,
+            method: vec![method.to_string()],
+            type_: RequestType::Async,
+            args: vec![],
+        };
+        let too_many_requests = Error {
+            name: "TOO_MANY_REQUESTS".to_string(),
+            message: "Only 1 requests may be active at the same time".to_string(),
+        };
+
+        // Completed requests don’t count against the limit.
+        for number in 1..=2 {
+            test_dispatcher.send(async_request(number, "ok")).await;
+            let response = test_dispatcher.recv().await.unwrap();
+            assert_eq!(
+                response,
+                Response::AsyncOk {
+                    number,
+                    body: Body::json(&true)
+                }
+            );
+        }
+
+        test_dispatcher.send(async_request(3, "pending")).await;
+        test_dispatcher.send(async_request(4, "ok")).await;
+        let response = test_dispatcher.recv().await.unwrap();
+        assert_eq!(
+            response,
+            AsyncResponse::Err(too_many_requests.clone()).into_response(4)
+        );
+
+        test_dispatcher
+            .send(
+                StreamRequest {
+                    name: vec!["source".to_string()],
+                    type_: StreamRequestType::Source,
+                    args: vec![],
+                }
+                .into_request(5),
+            )
+            .await;
+        let response = test_dispatcher.recv().await.unwrap();
+        assert_eq!(
+            response,
+            StreamMessage::Error(too_many_requests).into_response(5)
+        );
+        test_dispatcher.close_connection();
+        test_dispatcher.end().await;
+    }
+
     struct TestDispatcher {
         request_sender: futures::channel::mpsc::Sender<Request>,
         response_receiver: futures::channel::mpsc::Receiver<Response>,
+        handler_panics: HandlerPanics,
         run_handle: async_std::task::JoinHandle<Result<(), anyhow::Error>>,
     }
 
     impl TestDispatcher {
         fn new(service: Service) -> Self {
+            Self::with_options(service, None, None)
+        }
+
+        fn with_limit(service: Service, max_concurrent_requests: Option<usize>) -> Self {
+            Self::with_options(service, None, max_concurrent_requests)
+        }
+
+        fn with_options(
+            service: Service,
+            stream_buffer: Option<usize>,
+            max_concurrent_requests: Option<usize>,
+        ) -> Self {
             let (request_sender, request_receiver) = futures::channel::mpsc::channel(10);
             let (response_sender, response_receiver) = futures::channel::mpsc::channel(10);
 
-            let run_handle =
-                async_std::task::spawn(run(service, request_receiver, response_sender));
+            let handler_panics = HandlerPanics::default();
+            let run_handle = async_std::task::spawn(run(
+                service,
+                handler_panics.clone(),
+                ServerConfig {
+                    flow_control: FlowControl::default(),
+                    stream_buffer,
+                    max_concurrent_requests,
+                },
+                request_receiver,
+                response_sender,
+                ConnectionClosed::new(),
+            ));
 
             Self {
                 request_sender,
                 response_receiver,
+                handler_panics,
                 run_handle,
             }
         }
@@ -440,6 +1367,7 @@ mod test {
             let TestDispatcher {
                 request_sender,
                 response_receiver,
+                handler_panics: _,
                 run_handle,
             } = self;
             drop(request_sender);
@@ -5,6 +5,8 @@ use std::collections::HashMap;
 use std::{pin::Pin, task::Poll};
 
 use super::packet::Response;
+use super::schema::{deserialize_args, ArgType, ArgsSchema};
+use super::stream_request::StreamRequestType;
 
 pub use super::packet::Body;
 pub use super::{Error, StreamMessage};
@@ -43,10 +45,43 @@ pub enum SinkError {
 #[derive(Debug)]
 pub struct SinkClosed;
 
+/// Methods served by an [Endpoint][super::Endpoint].
+///
+/// Handlers are shared by all requests and may be called from several tasks at the same time,
+/// so they must be `Sync`. The handlers for two requests that arrive back to back may be called
+/// in either order.
 #[derive(Default)]
 pub struct Service {
     async_handlers: HashMap<Vec<String>, Handler<BoxFuture<'static, AsyncResponse>>>,
-    stream_handlers: HashMap<Vec<String>, Handler<(BoxEndpointStream, BoxEndpointSink)>>,
+    sync_handlers: HashMap<Vec<String>, Handler<AsyncResponse>>,
+    stream_handlers: HashMap<Vec<String>, (StreamRequestType, StreamHandler)>,
+    schemas: HashMap<Vec<String>, ArgsSchema>,
+    descriptions: HashMap<Vec<String>, MethodDescription>,
+    fallback: Option<Box<dyn Fallback>>,
+}
+
+/// Handles requests for methods that are not registered with a [Service]. See
+/// [Service::set_fallback].
+pub(super) trait Fallback: Send + Sync {
+    fn handle_async(
+        &self,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+    ) -> BoxFuture<'static, AsyncResponse>;
+
+    fn handle_stream(
+        &self,
+        type_: StreamRequestType,
+        method: Vec<String>,
+        args: Vec<serde_json::Value>,
+    ) -> (BoxEndpointStream, BoxEndpointSink);
+}
+
+/// Documentation for a method provided with [Service::describe].
+#[derive(Debug, Clone, Default)]
+struct MethodDescription {
+    description: String,
+    args: HashMap<String, String>,
 }
 
 impl Service {
@@ -56,22 +91,36 @@ impl Service {
     pub fn add_async<Args, Fut>(
         &mut self,
         method: impl ToString,
-        f: impl Fn(Args) -> Fut + Send + 'static,
+        f: impl Fn(Args) -> Fut + Send + Sync + 'static,
     ) where
         Args: serde::de::DeserializeOwned,
         Fut: Future<Output = AsyncResponse> + Send + 'static,
     {
         self.async_handlers.insert(
             vec![method.to_string()],
-            Box::new(move |args| {
-                let args = serde_json::Value::Array(args);
-                match serde_json::from_value::<Args>(args) {
-                    Ok(args) => f(args).boxed(),
-                    Err(error) => futures::future::ready(AsyncResponse::Err(
-                        deserialize_arguments_error(error),
-                    ))
-                    .boxed(),
-                }
+            Box::new(move |args| match deserialize_args::<Args>(args) {
+                Ok(args) => f(args).boxed(),
+                Err(error) => futures::future::ready(AsyncResponse::Err(error)).boxed(),
+            }),
+        );
+    }
+
+    /// Add a handler for a `sync` method.
+    ///
+    /// In contrast to [Service::add_async] the handler computes the response immediately. Sync
+    /// methods are answered regardless of whether the peer sends a `sync` or `async` request.
+    pub fn add_sync<Args>(
+        &mut self,
+        method: impl ToString,
+        f: impl Fn(Args) -> AsyncResponse + Send + Sync + 'static,
+    ) where
+        Args: serde::de::DeserializeOwned,
+    {
+        self.sync_handlers.insert(
+            vec![method.to_string()],
+            Box::new(move |args| match deserialize_args::<Args>(args) {
+                Ok(args) => f(args),
+                Err(error) => AsyncResponse::Err(error),
             }),
         );
     }
@@ -79,47 +128,52 @@ impl Service {
     pub fn add_source<Args, Source>(
         &mut self,
         method: impl ToString,
-        f: impl Fn(Args) -> Source + Send + 'static,
+        f: impl Fn(Args) -> Source + Send + Sync + 'static,
     ) where
         Args: serde::de::DeserializeOwned,
         Source: Stream<Item = Result<Body, Error>> + Send + 'static,
     {
         self.stream_handlers.insert(
             vec![method.to_string()],
-            Box::new(move |args| {
-                let args = serde_json::Value::Array(args);
-                match serde_json::from_value::<Args>(args) {
+            (
+                StreamRequestType::Source,
+                Box::new(move |args| match deserialize_args::<Args>(args) {
                     Ok(args) => stream_to_endpoint(f(args)),
-                    Err(error) => error_endpoint(deserialize_arguments_error(error)),
-                }
-            }),
+                    Err(error) => error_endpoint(error),
+                }),
+            ),
         );
     }
 
+    /// Add a handler for a `sink` method.
+    ///
+    /// The sink receives the data the peer sends and the [StreamMessage::End] message when the
+    /// peer ends the stream. The sink ends the stream by failing with [SinkError::Done] or
+    /// [SinkError::Error]. If the sink accepts the end message the stream ends without an error.
     pub fn add_sink<Args, Sink_>(
         &mut self,
         method: impl ToString,
-        f: impl Fn(Args) -> Sink_ + Send + 'static,
+        f: impl Fn(Args) -> Sink_ + Send + Sync + 'static,
     ) where
         Args: serde::de::DeserializeOwned,
         Sink_: Sink<StreamMessage, Error = SinkError> + Send + 'static,
     {
         self.stream_handlers.insert(
             vec![method.to_string()],
-            Box::new(move |args| {
-                let args = serde_json::Value::Array(args);
-                match serde_json::from_value::<Args>(args) {
+            (
+                StreamRequestType::Sink,
+                Box::new(move |args| match deserialize_args::<Args>(args) {
                     Ok(args) => sink_to_endpoint(f(args)),
-                    Err(error) => error_endpoint(deserialize_arguments_error(error)),
-                }
-            }),
+                    Err(error) => error_endpoint(error),
+                }),
+            ),
         );
     }
 
     pub fn add_duplex<Args, Source, Sink_>(
         &mut self,
         method: impl ToString,
-        f: impl Fn(Args) -> (Source, Sink_) + Send + 'static,
+        f: impl Fn(Args) -> (Source, Sink_) + Send + Sync + 'static,
     ) where
         Args: serde::de::DeserializeOwned,
         Source: Stream<Item = Result<Body, Error>> + Send + 'static,
@@ -128,37 +182,250 @@ impl Service {
         let method2 = method.to_string();
         self.stream_handlers.insert(
             vec![method.to_string()],
-            Box::new(move |args| {
-                let args = serde_json::Value::Array(args);
-                match serde_json::from_value::<Args>(args) {
-                    Ok(args) => {
-                        let (source, sink) = f(args);
-                        (source.boxed(), Box::pin(sink))
-                    }
-                    Err(error) => {
-                        tracing::warn!(method = ?method2, ?error, "failed to deserialize arguments");
-                        error_endpoint(deserialize_arguments_error(error))
+            (
+                StreamRequestType::Duplex,
+                Box::new(move |args| {
+                    match deserialize_args::<Args>(args) {
+                        Ok(args) => {
+                            let (source, sink) = f(args);
+                            (source.boxed(), Box::pin(sink))
+                        }
+                        Err(error) => {
+                            tracing::warn!(method = ?method2, ?error, "failed to deserialize arguments");
+                            error_endpoint(error)
+                        }
                     }
-                }
-            }),
+                }),
+            ),
+        );
+    }
+
+    /// Validate the arguments for `method` against `schema` before they are passed to the
+    /// handler.
+    ///
+    /// If the arguments don’t match the schema the peer receives an `ArgumentError` and the
+    /// handler is not called. The schema may be set before or after the handler is added.
+    pub fn set_schema(&mut self, method: impl ToString, schema: ArgsSchema) {
+        self.schemas.insert(vec![method.to_string()], schema);
+    }
+
+    /// Document `method` for the `help` method of the service.
+    ///
+    /// `args` maps argument names to their descriptions. If a schema was registered for the method
+    /// with [Service::set_schema], the argument types are taken from the schema.
+    ///
+    /// Every service and group added with [Service::add_service] answers `help` requests unless a
+    /// `help` method was registered explicitly. The response lists all methods of the group.
+    ///
+    /// ```rust
+    /// # use ssb::rpc::base::Service;
+    /// let mut service = Service::new();
+    /// service.add_sync("whoami", |_: Vec<()>| unimplemented!());
+    /// service.describe("whoami", "Print main identity", &[]);
+    ///
+    /// assert_eq!(
+    ///     service.help(&[]),
+    ///     Some(serde_json::json!({
+    ///         "description": "",
+    ///         "commands": {
+    ///             "whoami": {
+    ///                 "description": "Print main identity",
+    ///                 "type": "sync",
+    ///                 "args": {},
+    ///             },
+    ///         },
+    ///     }))
+    /// );
+    /// ```
+    pub fn describe(
+        &mut self,
+        method: impl ToString,
+        description: impl ToString,
+        args: &[(&str, &str)],
+    ) {
+        self.descriptions.insert(
+            vec![method.to_string()],
+            MethodDescription {
+                description: description.to_string(),
+                args: args
+                    .iter()
+                    .map(|(name, description)| (name.to_string(), description.to_string()))
+                    .collect(),
+            },
         );
     }
 
+    /// Pass requests for methods that are not registered to `fallback`. This includes the
+    /// `manifest` and `help` methods. Fallbacks of services added with [Service::add_service] or
+    /// [Service::merge] are dropped.
+    pub(super) fn set_fallback(&mut self, fallback: impl Fallback + 'static) {
+        self.fallback = Some(Box::new(fallback));
+    }
+
     pub fn add_service(&mut self, group: impl ToString, service: Self) {
+        let group = group.to_string();
+        self.extend(service, |mut method| {
+            method.insert(0, group.clone());
+            method
+        });
+    }
+
+    /// Add all methods of `service` to this service without a group prefix.
+    ///
+    /// Methods of `service` replace methods with the same name.
+    pub fn merge(&mut self, service: Self) {
+        self.extend(service, |method| method);
+    }
+
+    fn extend(&mut self, service: Self, rename: impl Fn(Vec<String>) -> Vec<String>) {
         let Self {
             async_handlers,
+            sync_handlers,
             stream_handlers,
+            schemas,
+            descriptions,
+            fallback: _,
         } = service;
         self.async_handlers
-            .extend(async_handlers.into_iter().map(|(mut k, v)| {
-                k.insert(0, group.to_string());
-                (k, v)
-            }));
+            .extend(async_handlers.into_iter().map(|(k, v)| (rename(k), v)));
+        self.sync_handlers
+            .extend(sync_handlers.into_iter().map(|(k, v)| (rename(k), v)));
         self.stream_handlers
-            .extend(stream_handlers.into_iter().map(|(mut k, v)| {
-                k.insert(0, group.to_string());
-                (k, v)
-            }));
+            .extend(stream_handlers.into_iter().map(|(k, v)| (rename(k), v)));
+        self.schemas
+            .extend(schemas.into_iter().map(|(k, v)| (rename(k), v)));
+        self.descriptions
+            .extend(descriptions.into_iter().map(|(k, v)| (rename(k), v)));
+    }
+
+    /// Returns the manifest describing all registered methods and their types.
+    ///
+    /// The manifest is a nested JSON object where each key is a method name or a group name added
+    /// with [Service::add_service]. Method values are one of `"async"`, `"sync"`, `"source"`,
+    /// `"sink"` or `"duplex"`.
+    ///
+    /// ```rust
+    /// # use ssb::rpc::base::Service;
+    /// let mut group = Service::new();
+    /// group.add_sync("get", |_: Vec<()>| unimplemented!());
+    ///
+    /// let mut service = Service::new();
+    /// service.add_source("stream", |_: Vec<()>| futures::stream::empty());
+    /// service.add_service("group", group);
+    ///
+    /// assert_eq!(
+    ///     service.manifest(),
+    ///     serde_json::json!({
+    ///         "manifest": "sync",
+    ///         "help": "sync",
+    ///         "stream": "source",
+    ///         "group": { "get": "sync", "help": "sync" },
+    ///     })
+    /// );
+    /// ```
+    ///
+    /// If no `manifest` method has been registered the service answers requests for `manifest` with
+    /// this value.
+    pub fn manifest(&self) -> serde_json::Value {
+        let mut manifest = serde_json::Map::new();
+        if !self.has_method(&[MANIFEST_METHOD.to_string()]) {
+            manifest.insert(MANIFEST_METHOD.to_string(), "sync".into());
+        }
+        for (method, type_) in self.methods() {
+            manifest_insert(&mut manifest, method, type_.into());
+        }
+        manifest_insert_help(&mut manifest);
+        serde_json::Value::Object(manifest)
+    }
+
+    /// Returns the `help` response for the methods in `group`.
+    ///
+    /// `group` is the path of a service added with [Service::add_service]. The root service is
+    /// represented by an empty path. Returns `None` if no such group exists.
+    ///
+    /// See [Service::describe] for details.
+    pub fn help(&self, group: &[String]) -> Option<serde_json::Value> {
+        if !self.has_group(group) {
+            return None;
+        }
+
+        let mut commands = serde_json::Map::new();
+        for (method, type_) in self.methods() {
+            let name = match method.split_last() {
+                Some((name, method_group)) if method_group == group => name,
+                _ => continue,
+            };
+            let description = self.descriptions.get(method);
+            let mut args = serde_json::Map::new();
+            if let Some(schema) = self.schemas.get(method) {
+                for arg in &schema.args {
+                    let description = description
+                        .and_then(|description| description.args.get(&arg.name))
+                        .or(arg.description.as_ref());
+                    args.insert(
+                        arg.name.clone(),
+                        serde_json::json!({
+                            "description": description,
+                            "type": arg.type_.name(),
+                            "optional": arg.optional,
+                        }),
+                    );
+                }
+            }
+            if let Some(description) = description {
+                for (arg_name, arg_description) in &description.args {
+                    args.entry(arg_name.clone()).or_insert_with(|| {
+                        serde_json::json!({
+                            "description": arg_description,
+                            "type": ArgType::Any.name(),
+                        })
+                    });
+                }
+            }
+            commands.insert(
+                name.clone(),
+                serde_json::json!({
+                    "description": description
+                        .map(|description| description.description.as_str())
+                        .unwrap_or(""),
+                    "type": type_,
+                    "args": args,
+                }),
+            );
+        }
+
+        Some(serde_json::json!({
+            "description": "",
+            "commands": commands,
+        }))
+    }
+
+    /// Returns all registered methods with their type.
+    fn methods(&self) -> impl Iterator<Item = (&Vec<String>, &'static str)> {
+        self.async_handlers
+            .keys()
+            .map(|method| (method, "async"))
+            .chain(self.sync_handlers.keys().map(|method| (method, "sync")))
+            .chain(
+                self.stream_handlers
+                    .iter()
+                    .map(|(method, (type_, _))| (method, type_.as_str())),
+            )
+    }
+
+    /// Returns true if `group` is the root service or a service added with
+    /// [Service::add_service].
+    fn has_group(&self, group: &[String]) -> bool {
+        group.is_empty()
+            || self
+                .methods()
+                .any(|(method, _)| method.len() > group.len() && method.starts_with(group))
+    }
+
+    fn has_method(&self, method: &[String]) -> bool {
+        self.async_handlers.contains_key(method)
+            || self.sync_handlers.contains_key(method)
+            || self.stream_handlers.contains_key(method)
     }
 
     pub(super) fn handle_async(
@@ -166,8 +433,28 @@ impl Service {
         method: Vec<String>,
         args: Vec<serde_json::Value>,
     ) -> BoxFuture<'static, AsyncResponse> {
+        if let Err(error) = self.validate_args(&method, &args) {
+            return futures::future::ready(AsyncResponse::Err(error)).boxed();
+        }
+        if let Some(handler) = self.sync_handlers.get(&method) {
+            return futures::future::ready(handler(args)).boxed();
+        }
+        if let (false, Some(fallback)) = (self.async_handlers.contains_key(&method), &self.fallback)
+        {
+            return fallback.handle_async(method, args);
+        }
         match self.async_handlers.get(&method) {
             Some(handler) => handler(args),
+            None if method == [MANIFEST_METHOD] => {
+                futures::future::ready(AsyncResponse::json_ok(&self.manifest())).boxed()
+            }
+            None if method.last().map(String::as_str) == Some(HELP_METHOD) => {
+                let response = match self.help(&method[..method.len() - 1]) {
+                    Some(help) => AsyncResponse::json_ok(&help),
+                    None => AsyncResponse::Err(method_not_found_error(&method)),
+                };
+                futures::future::ready(response).boxed()
+            }
             None => {
                 tracing::warn!(method = ?method.join(","), "missing async method");
                 futures::future::ready(AsyncResponse::Err(method_not_found_error(&method))).boxed()
@@ -177,12 +464,17 @@ impl Service {
 
     pub(super) fn handle_stream(
         &self,
+        type_: StreamRequestType,
         method: Vec<String>,
         args: Vec<serde_json::Value>,
     ) -> (BoxEndpointStream, BoxEndpointSink) {
-        match self.stream_handlers.get(&method) {
-            Some(handler) => handler(args),
-            None => {
+        if let Err(error) = self.validate_args(&method, &args) {
+            return error_endpoint(error);
+        }
+        match (self.stream_handlers.get(&method), &self.fallback) {
+            (Some((_, handler)), _) => handler(args),
+            (None, Some(fallback)) => fallback.handle_stream(type_, method, args),
+            (None, None) => {
                 tracing::warn!(method = ?method.join("."), "missing stream method");
                 error_endpoint(method_not_found_error(&method))
             }
@@ -190,6 +482,18 @@ impl Service {
     }
 }
 
+impl Service {
+    fn validate_args(&self, method: &[String], args: &[serde_json::Value]) -> Result<(), Error> {
+        match self.schemas.get(method) {
+            Some(schema) => schema.validate(args).map_err(|error| {
+                tracing::debug!(method = ?method.join("."), ?error, "invalid arguments");
+                error
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
 impl std::fmt::Debug for Service {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Service")
@@ -197,10 +501,15 @@ impl std::fmt::Debug for Service {
                 "async_handlers",
                 &self.async_handlers.keys().collect::<Vec<_>>(),
             )
+            .field(
+                "sync_handlers",
+                &self.sync_handlers.keys().collect::<Vec<_>>(),
+            )
             .field(
                 "stream_handlers",
                 &self.stream_handlers.keys().collect::<Vec<_>>(),
             )
+            .field("fallback", &self.fallback.is_some())
             .finish()
     }
 }
@@ -209,9 +518,52 @@ pub(super) type BoxEndpointStream = BoxStream<'static, Result<Body, Error>>;
 
 pub(super) type BoxEndpointSink = Pin<Box<dyn Sink<StreamMessage, Error = SinkClosed> + Send>>;
 
-type Handler<T> = Box<dyn Fn(Vec<serde_json::Value>) -> T + Send + 'static>;
+type Handler<T> = Box<dyn Fn(Vec<serde_json::Value>) -> T + Send + Sync + 'static>;
+
+type StreamHandler = Handler<(BoxEndpointStream, BoxEndpointSink)>;
+
+/// Name of the method that serves [Service::manifest].
+const MANIFEST_METHOD: &str = "manifest";
+
+/// Name of the method that serves [Service::help] for the root service and every group.
+const HELP_METHOD: &str = "help";
+
+/// Add the `help` method to `manifest` and all nested groups unless a method with that name
+/// exists.
+fn manifest_insert_help(manifest: &mut serde_json::Map<String, serde_json::Value>) {
+    for value in manifest.values_mut() {
+        if let serde_json::Value::Object(group_manifest) = value {
+            manifest_insert_help(group_manifest);
+        }
+    }
+    manifest
+        .entry(HELP_METHOD)
+        .or_insert_with(|| serde_json::Value::from("sync"));
+}
+
+/// Insert `type_` into the nested `manifest` object at the path given by `method`.
+fn manifest_insert(
+    manifest: &mut serde_json::Map<String, serde_json::Value>,
+    method: &[String],
+    type_: serde_json::Value,
+) {
+    match method {
+        [] => {}
+        [name] => {
+            manifest.insert(name.clone(), type_);
+        }
+        [group, rest @ ..] => {
+            let group_manifest = manifest
+                .entry(group.clone())
+                .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+            if let serde_json::Value::Object(group_manifest) = group_manifest {
+                manifest_insert(group_manifest, rest, type_)
+            }
+        }
+    }
+}
 
-fn error_endpoint(error: Error) -> (BoxEndpointStream, BoxEndpointSink) {
+pub(super) fn error_endpoint(error: Error) -> (BoxEndpointStream, BoxEndpointSink) {
     let sink = futures::sink::drain().sink_map_err(|infallible| match infallible {});
     let source = futures::stream::once(async move { Err(error) });
     (source.boxed(), Box::pin(sink))
@@ -223,23 +575,29 @@ fn sink_to_endpoint(
     let (response_sender, response_receiver) =
         futures::channel::oneshot::channel::<Result<Body, Error>>();
     let source = crate::utils::OneshotStream::new(response_receiver);
-    let duplex_sink = sink
-        .with::<_, _, _, SinkError>(|stream_message| {
-            futures::future::ready({
-                match stream_message {
-                    StreamMessage::Data(_) => Ok(stream_message),
-                    StreamMessage::Error(err) => Err(SinkError::Error(err)),
-                    StreamMessage::End => Err(SinkError::Done),
-                }
-            })
-        })
-        .sink_map_err(|err| {
+    let duplex_sink = futures::sink::unfold(
+        (Box::pin(sink), response_sender),
+        |(mut sink, response_sender), stream_message| async move {
+            let err = match stream_message {
+                StreamMessage::Data(_) => match sink.send(stream_message).await {
+                    Ok(()) => return Ok((sink, response_sender)),
+                    Err(err) => err,
+                },
+                StreamMessage::Error(err) => SinkError::Error(err),
+                // The handler receives the end so that it can finish its work and respond with
+                // an error.
+                StreamMessage::End => match sink.send(stream_message).await {
+                    Ok(()) => SinkError::Done,
+                    Err(err) => err,
+                },
+            };
             match err {
                 SinkError::Done => drop(response_sender),
                 SinkError::Error(err) => response_sender.send(Err(err)).unwrap(),
             }
-            SinkClosed
-        });
+            Err(SinkClosed)
+        },
+    );
     (source.boxed(), Box::pin(duplex_sink))
 }
 
@@ -286,9 +644,51 @@ fn method_not_found_error(method: &[String]) -> Error {
     Error { name, message }
 }
 
-fn deserialize_arguments_error(error: serde_json::Error) -> Error {
-    Error {
-        name: "ArgumentError".to_string(),
-        message: format!("Failed to deserialize arguments {}", error),
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::base::schema::ArgSchema;
+
+    #[async_std::test]
+    async fn help_response() {
+        let mut group = Service::new();
+        group.add_async("get", |_: Vec<serde_json::Value>| async {
+            unimplemented!()
+        });
+        group.set_schema(
+            "get",
+            ArgsSchema::new()
+                .arg(ArgSchema::new("id", ArgType::String).description("Message ID"))
+                .arg(ArgSchema::new("opts", ArgType::Object).optional()),
+        );
+        group.describe("get", "Get a message", &[("opts", "Options")]);
+        group.add_source("stream", |_: Vec<()>| futures::stream::empty());
+
+        let mut service = Service::new();
+        service.add_service("group", group);
+
+        let response = service
+            .handle_async(vec!["group".to_string(), "help".to_string()], vec![])
+            .await;
+        let body = match response {
+            AsyncResponse::Ok(body) => body,
+            AsyncResponse::Err(error) => panic!("{:?}", error),
+        };
+        let help = body.decode_json::<crate::rpc::ssb::Help>().unwrap();
+
+        let get = &help.methods["get"];
+        assert_eq!(get.description, "Get a message");
+        assert_eq!(get.type_, "async");
+        assert_eq!(get.args["id"].description.as_deref(), Some("Message ID"));
+        assert_eq!(get.args["id"].type_, "string");
+        assert!(!get.args["id"].optional);
+        assert_eq!(get.args["opts"].description.as_deref(), Some("Options"));
+        assert!(get.args["opts"].optional);
+
+        let stream = &help.methods["stream"];
+        assert_eq!(stream.description, "");
+        assert_eq!(stream.type_, "source");
+
+        assert!(service.help(&["missing".to_string()]).is_none());
     }
 }
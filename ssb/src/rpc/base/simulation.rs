@@ -0,0 +1,644 @@
+//! Deterministic simulation of RPC connections for tests.
+//!
+//! [Simulation] runs [Endpoint]s on a single-threaded executor and connects them with virtual
+//! links. A link splits the data it carries into chunks at random boundaries, delays each chunk
+//! and may drop the connection. All randomness comes from a seeded generator and time is
+//! virtual, so an interleaving that makes a test fail is reproduced by running the simulation
+//! with the same seed.
+//!
+//! ```
+//! use ssb::rpc::base::service::AsyncResponse;
+//! use ssb::rpc::base::simulation::{LinkConfig, Simulation};
+//! use ssb::rpc::base::Service;
+//!
+//! let mut simulation = Simulation::new(7);
+//! let mut service = Service::new();
+//! service.add_async("ping", |_: Vec<()>| async { AsyncResponse::json_ok(&"pong") });
+//! let (mut client, _server) = simulation.connect(Service::new(), service, LinkConfig::default());
+//!
+//! let response = simulation
+//!     .block_on(client.client().send_async(vec!["ping".to_string()], vec![]))
+//!     .unwrap()
+//!     .unwrap();
+//! assert_eq!(response, ssb::rpc::base::AsyncResponse::Json(b"\"pong\"".to_vec()));
+//! ```
+//!
+//! Only tasks spawned by the endpoints run on the simulated executor. Handlers that use timers
+//! or spawn tasks on a runtime make the simulation non-deterministic.
+use futures::prelude::*;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::ops::RangeInclusive;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use super::{Endpoint, Service};
+use crate::utils::task::local::{set_spawner, SpawnerGuard};
+
+/// Behavior of a virtual link.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkConfig {
+    /// Number of ticks it takes a chunk to arrive. Chunks never overtake earlier chunks sent in
+    /// the same direction.
+    pub latency: RangeInclusive<u64>,
+    /// Written data is split into chunks of at most this many bytes.
+    pub max_chunk_size: usize,
+    /// Drop the connection this many ticks after the link was created. Data in flight is lost
+    /// and both sides receive [LinkError::Dropped].
+    pub drop_after: Option<u64>,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            latency: 0..=10,
+            max_chunk_size: 64,
+            drop_after: None,
+        }
+    }
+}
+
+/// Error returned by [LinkSink] and [LinkStream].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    #[error("Link was dropped")]
+    Dropped,
+    #[error("Link was closed")]
+    Closed,
+}
+
+/// Event recorded by the simulation. Two runs with the same seed record the same events.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// A chunk of `len` bytes arrived at the receiving end of `pipe`.
+    Delivered { time: u64, pipe: usize, len: usize },
+    /// The sending end of `pipe` was closed and all data has arrived.
+    Closed { time: u64, pipe: usize },
+    /// The connection that consists of `pipes` was dropped.
+    Dropped { time: u64, pipes: [usize; 2] },
+}
+
+/// Returned by [Simulation::block_on] if neither the future nor any task can make progress and
+/// no data is in flight.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("Simulation stalled at time {time}")]
+pub struct Stalled {
+    pub time: u64,
+}
+
+/// Single-threaded executor with a virtual network. See the [module documentation][self].
+///
+/// While the simulation exists, all RPC tasks spawned on the current thread run on the
+/// simulation’s executor.
+pub struct Simulation {
+    pool: futures::executor::LocalPool,
+    network: Arc<Mutex<Network>>,
+    _spawner_guard: SpawnerGuard,
+}
+
+impl std::fmt::Debug for Simulation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Simulation")
+            .field("network", &self.network)
+            .finish()
+    }
+}
+
+impl Simulation {
+    pub fn new(seed: u64) -> Self {
+        let pool = futures::executor::LocalPool::new();
+        let spawner_guard = set_spawner(pool.spawner());
+        Self {
+            pool,
+            network: Arc::new(Mutex::new(Network::new(seed))),
+            _spawner_guard: spawner_guard,
+        }
+    }
+
+    /// Current virtual time in ticks.
+    pub fn now(&self) -> u64 {
+        self.network.lock().unwrap().now
+    }
+
+    /// Events recorded so far.
+    pub fn trace(&self) -> Vec<TraceEvent> {
+        self.network.lock().unwrap().trace.clone()
+    }
+
+    /// Create a virtual connection and return both of its ends.
+    pub fn link(&mut self, config: LinkConfig) -> ((LinkSink, LinkStream), (LinkSink, LinkStream)) {
+        let (a_to_b, b_to_a) = self.network.lock().unwrap().add_link(config);
+        let end = |send, receive| {
+            (
+                LinkSink {
+                    network: Arc::clone(&self.network),
+                    pipe: send,
+                },
+                LinkStream {
+                    network: Arc::clone(&self.network),
+                    pipe: receive,
+                },
+            )
+        };
+        (end(a_to_b, b_to_a), end(b_to_a, a_to_b))
+    }
+
+    /// Create two endpoints that are connected with a virtual link.
+    pub fn connect(
+        &mut self,
+        service_a: Service,
+        service_b: Service,
+        config: LinkConfig,
+    ) -> (Endpoint, Endpoint) {
+        let ((send_a, receive_a), (send_b, receive_b)) = self.link(config);
+        (
+            Endpoint::new(send_a, receive_a, service_a),
+            Endpoint::new(send_b, receive_b, service_b),
+        )
+    }
+
+    /// Run the simulation until `future` completes.
+    ///
+    /// Tasks run until none of them can make progress. Then virtual time advances to the next
+    /// event on the network. Returns [Stalled] if there is no such event.
+    pub fn block_on<F: Future>(&mut self, future: F) -> Result<F::Output, Stalled> {
+        futures::pin_mut!(future);
+        let woken = Arc::new(AtomicBool::new(true));
+        let waker = futures::task::waker(Arc::new(FlagWaker(Arc::clone(&woken))));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if woken.swap(false, Ordering::SeqCst) {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return Ok(output);
+                }
+            }
+            self.pool.run_until_stalled();
+            if woken.load(Ordering::SeqCst) {
+                continue;
+            }
+            let mut network = self.network.lock().unwrap();
+            if !network.advance() {
+                return Err(Stalled { time: network.now });
+            }
+        }
+    }
+
+    /// Run all tasks and deliver all data in flight.
+    pub fn run_until_stalled(&mut self) {
+        let _ = self.block_on(future::pending::<()>());
+    }
+}
+
+struct FlagWaker(Arc<AtomicBool>);
+
+impl futures::task::ArcWake for FlagWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Sending end of a virtual link.
+#[derive(Debug)]
+pub struct LinkSink {
+    network: Arc<Mutex<Network>>,
+    pipe: usize,
+}
+
+impl Sink<Vec<u8>> for LinkSink {
+    type Error = LinkError;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(self.network.lock().unwrap().pipes[self.pipe].check_sendable())
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        self.network.lock().unwrap().send(self.pipe, item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(self.network.lock().unwrap().pipes[self.pipe].check_sendable())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.network.lock().unwrap().close(self.pipe);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Receiving end of a virtual link.
+///
+/// The stream ends when the sending end is closed and returns [LinkError::Dropped] once when
+/// the connection is dropped.
+#[derive(Debug)]
+pub struct LinkStream {
+    network: Arc<Mutex<Network>>,
+    pipe: usize,
+}
+
+impl Stream for LinkStream {
+    type Item = Result<Vec<u8>, LinkError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut network = self.network.lock().unwrap();
+        let pipe = &mut network.pipes[self.pipe];
+        if let Some(chunk) = pipe.inbox.pop_front() {
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+        match pipe.state {
+            PipeState::Open | PipeState::Closing => {
+                pipe.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            PipeState::Closed => Poll::Ready(None),
+            PipeState::Dropped { reported: false } => {
+                pipe.state = PipeState::Dropped { reported: true };
+                Poll::Ready(Some(Err(LinkError::Dropped)))
+            }
+            PipeState::Dropped { reported: true } => Poll::Ready(None),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Network {
+    now: u64,
+    rng: SplitMix64,
+    /// Used to order events that happen at the same time.
+    next_sequence: u64,
+    in_flight: BinaryHeap<Reverse<Scheduled>>,
+    pipes: Vec<Pipe>,
+    trace: Vec<TraceEvent>,
+}
+
+impl Network {
+    fn new(seed: u64) -> Self {
+        Self {
+            now: 0,
+            rng: SplitMix64(seed),
+            next_sequence: 0,
+            in_flight: BinaryHeap::new(),
+            pipes: Vec::new(),
+            trace: Vec::new(),
+        }
+    }
+
+    fn add_link(&mut self, config: LinkConfig) -> (usize, usize) {
+        assert!(config.max_chunk_size > 0, "max_chunk_size must not be zero");
+        let a_to_b = self.pipes.len();
+        let b_to_a = a_to_b + 1;
+        if let Some(drop_after) = config.drop_after {
+            self.schedule(self.now + drop_after, Event::Drop([a_to_b, b_to_a]));
+        }
+        self.pipes.push(Pipe::new(config.clone()));
+        self.pipes.push(Pipe::new(config));
+        (a_to_b, b_to_a)
+    }
+
+    fn send(&mut self, pipe: usize, mut data: Vec<u8>) -> Result<(), LinkError> {
+        self.pipes[pipe].check_sendable()?;
+        while !data.is_empty() {
+            let max_chunk_size = self.pipes[pipe].config.max_chunk_size as u64;
+            let len = self.rng.in_range(1..=max_chunk_size) as usize;
+            let rest = data.split_off(std::cmp::min(len, data.len()));
+            let time = self.arrival_time(pipe);
+            self.schedule(time, Event::Data(pipe, data));
+            data = rest;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, pipe: usize) {
+        if self.pipes[pipe].state == PipeState::Open {
+            self.pipes[pipe].state = PipeState::Closing;
+            let time = self.arrival_time(pipe);
+            self.schedule(time, Event::Close(pipe));
+        }
+    }
+
+    /// Returns the time at which the next chunk sent through `pipe` arrives.
+    fn arrival_time(&mut self, pipe: usize) -> u64 {
+        let latency = self.rng.in_range(self.pipes[pipe].config.latency.clone());
+        let time = std::cmp::max(self.now + latency, self.pipes[pipe].last_arrival);
+        self.pipes[pipe].last_arrival = time;
+        time
+    }
+
+    fn schedule(&mut self, time: u64, event: Event) {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.in_flight.push(Reverse(Scheduled {
+            time,
+            sequence,
+            event,
+        }));
+    }
+
+    /// Advance the time to the next scheduled event and process it. Returns `false` if there
+    /// are no events.
+    fn advance(&mut self) -> bool {
+        let Reverse(Scheduled { time, event, .. }) = match self.in_flight.pop() {
+            Some(scheduled) => scheduled,
+            None => return false,
+        };
+        self.now = time;
+        match event {
+            Event::Data(pipe, chunk) => {
+                self.trace.push(TraceEvent::Delivered {
+                    time,
+                    pipe,
+                    len: chunk.len(),
+                });
+                self.pipes[pipe].inbox.push_back(chunk);
+                self.pipes[pipe].wake();
+            }
+            Event::Close(pipe) => {
+                self.trace.push(TraceEvent::Closed { time, pipe });
+                self.pipes[pipe].state = PipeState::Closed;
+                self.pipes[pipe].wake();
+            }
+            Event::Drop(pipes) => {
+                self.trace.push(TraceEvent::Dropped { time, pipes });
+                self.in_flight
+                    .retain(|Reverse(scheduled)| match scheduled.event {
+                        Event::Data(pipe, _) | Event::Close(pipe) => !pipes.contains(&pipe),
+                        Event::Drop(_) => true,
+                    });
+                for pipe in pipes {
+                    self.pipes[pipe].state = PipeState::Dropped { reported: false };
+                    self.pipes[pipe].wake();
+                }
+            }
+        }
+        true
+    }
+}
+
+/// One direction of a link.
+#[derive(Debug)]
+struct Pipe {
+    config: LinkConfig,
+    state: PipeState,
+    /// Chunks that have arrived but have not been read.
+    inbox: VecDeque<Vec<u8>>,
+    waker: Option<Waker>,
+    /// Arrival time of the last chunk that was sent.
+    last_arrival: u64,
+}
+
+impl Pipe {
+    fn new(config: LinkConfig) -> Self {
+        Self {
+            config,
+            state: PipeState::Open,
+            inbox: VecDeque::new(),
+            waker: None,
+            last_arrival: 0,
+        }
+    }
+
+    fn check_sendable(&self) -> Result<(), LinkError> {
+        match self.state {
+            PipeState::Open => Ok(()),
+            PipeState::Closing | PipeState::Closed => Err(LinkError::Closed),
+            PipeState::Dropped { .. } => Err(LinkError::Dropped),
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PipeState {
+    Open,
+    /// The sender closed the pipe but data is still in flight.
+    Closing,
+    Closed,
+    Dropped {
+        /// `true` if the receiver has seen the error.
+        reported: bool,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Scheduled {
+    time: u64,
+    sequence: u64,
+    event: Event,
+}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.time, self.sequence).cmp(&(other.time, other.sequence))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Event {
+    Data(usize, Vec<u8>),
+    Close(usize),
+    Drop([usize; 2]),
+}
+
+/// Small deterministic random number generator. See
+/// <https://prng.di.unimi.it/splitmix64.c>.
+#[derive(Debug)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn in_range(&mut self, range: RangeInclusive<u64>) -> u64 {
+        let (start, end) = range.into_inner();
+        assert!(start <= end, "Empty range");
+        match (end - start).checked_add(1) {
+            Some(len) => start + self.next() % len,
+            None => self.next(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::base::service::AsyncResponse;
+    use crate::rpc::base::Body;
+    use crate::test_utils::*;
+
+    fn echo_service() -> Service {
+        let mut service = Service::new();
+        service.add_async("echo", |(value,): (serde_json::Value,)| async move {
+            AsyncResponse::json_ok(&value)
+        });
+        service.add_source("count", |(n,): (u32,)| {
+            futures::stream::iter(0..n).map(|i| Ok(Body::json(&i)))
+        });
+        service
+    }
+
+    fn run_requests(seed: u64, config: LinkConfig) -> Simulation {
+        let mut simulation = Simulation::new(seed);
+        let (mut a, _b) = simulation.connect(Service::new(), echo_service(), config);
+        simulation
+            .block_on(async {
+                for i in 0..5 {
+                    let response = a
+                        .client()
+                        .send_async(vec!["echo".to_string()], vec![serde_json::json!(i)])
+                        .await
+                        .unwrap();
+                    assert_eq!(
+                        response,
+                        crate::rpc::base::AsyncResponse::Json(i.to_string().into_bytes())
+                    );
+                }
+                let source = a
+                    .client()
+                    .start_source(vec!["count".to_string()], vec![serde_json::json!(20)])
+                    .await
+                    .unwrap();
+                let items = source.try_collect::<Vec<_>>().await.unwrap();
+                assert_eq!(items.len(), 20);
+            })
+            .unwrap();
+        simulation
+    }
+
+    #[proptest]
+    fn requests(
+        seed: u64,
+        #[strategy(1..100usize)] max_chunk_size: usize,
+        #[strategy(0..20u64)] max_latency: u64,
+    ) {
+        run_requests(
+            seed,
+            LinkConfig {
+                latency: 0..=max_latency,
+                max_chunk_size,
+                drop_after: None,
+            },
+        );
+    }
+
+    /// Responses to a stream request may arrive before `start_source` returns. They must not be
+    /// treated as responses for an unknown stream.
+    #[proptest]
+    fn immediate_stream_responses(seed: u64) {
+        let mut simulation = Simulation::new(seed);
+        let (mut a, _b) = simulation.connect(
+            Service::new(),
+            echo_service(),
+            LinkConfig {
+                latency: 0..=1,
+                ..LinkConfig::default()
+            },
+        );
+        a.client()
+            .set_unknown_response_policy(crate::rpc::base::UnknownResponsePolicy::FailConnection);
+        let mut anomalies = a.client().anomalies();
+        let mut sender = a.client().sender();
+        let counts = simulation
+            .block_on(async {
+                let sources = future::try_join_all((1..=5).map(|n| {
+                    let mut sender = sender.clone();
+                    async move {
+                        sender
+                            .start_source(vec!["count".to_string()], vec![serde_json::json!(n)])
+                            .await
+                    }
+                }))
+                .await
+                .unwrap();
+                // Start another stream while the responses of the others arrive
+                let last = sender
+                    .start_source(vec!["count".to_string()], vec![serde_json::json!(3)])
+                    .await
+                    .unwrap();
+                future::try_join_all(
+                    sources
+                        .into_iter()
+                        .chain(std::iter::once(last))
+                        .map(|source| source.map_ok(|_| 1).try_fold(0, |n, i| future::ok(n + i))),
+                )
+                .await
+                .unwrap()
+            })
+            .unwrap();
+        assert_eq!(counts, vec![1, 2, 3, 4, 5, 3]);
+        assert!(anomalies.next().now_or_never().is_none());
+    }
+
+    #[test]
+    fn deterministic() {
+        let config = LinkConfig {
+            max_chunk_size: 7,
+            ..LinkConfig::default()
+        };
+        let trace = run_requests(1, config.clone()).trace();
+        assert!(!trace.is_empty());
+        assert_eq!(run_requests(1, config.clone()).trace(), trace);
+        assert_ne!(run_requests(2, config).trace(), trace);
+    }
+
+    #[test]
+    fn drop_link() {
+        let mut simulation = Simulation::new(0);
+        let (a, _b) = simulation.connect(
+            Service::new(),
+            echo_service(),
+            LinkConfig {
+                drop_after: Some(5),
+                ..LinkConfig::default()
+            },
+        );
+        let result = simulation.block_on(a.join()).unwrap();
+        assert!(result.is_err());
+        assert_eq!(simulation.now(), 5);
+        assert_eq!(
+            simulation.trace(),
+            vec![TraceEvent::Dropped {
+                time: 5,
+                pipes: [0, 1]
+            }]
+        );
+    }
+
+    #[test]
+    fn close_link() {
+        let mut simulation = Simulation::new(0);
+        let ((mut sink, _), (_, stream)) = simulation.link(LinkConfig::default());
+        let received = simulation
+            .block_on(async move {
+                sink.send(b"hello world".to_vec()).await.unwrap();
+                sink.close().await.unwrap();
+                assert_eq!(sink.send(vec![1]).await, Err(LinkError::Closed));
+                stream.try_concat().await.unwrap()
+            })
+            .unwrap();
+        assert_eq!(received, b"hello world");
+    }
+
+    #[test]
+    fn stalled() {
+        let mut simulation = Simulation::new(0);
+        let ((_sink, _), (_, mut stream)) = simulation.link(LinkConfig::default());
+        assert_eq!(simulation.block_on(stream.next()), Err(Stalled { time: 0 }));
+    }
+}
@@ -0,0 +1,108 @@
+//! Channel that buffers the messages the peer sent on a single stream until they are consumed.
+use futures::prelude::*;
+use futures::stream::BoxStream;
+
+/// Create a channel that holds up to `buffer` messages or an unbounded number of messages if
+/// `buffer` is `None`.
+///
+/// If the channel is bounded [StreamSender::send] waits until there is space for the message and
+/// [StreamSender::try_send] fails.
+pub(super) fn channel<T: Send + 'static>(
+    buffer: Option<usize>,
+) -> (StreamSender<T>, BoxStream<'static, T>) {
+    match buffer {
+        Some(buffer) => {
+            let (sender, receiver) = futures::channel::mpsc::channel(buffer);
+            (StreamSender::Bounded(sender), receiver.boxed())
+        }
+        None => {
+            let (sender, receiver) = futures::channel::mpsc::unbounded();
+            (StreamSender::Unbounded(sender), receiver.boxed())
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(super) enum StreamSender<T> {
+    Bounded(futures::channel::mpsc::Sender<T>),
+    Unbounded(futures::channel::mpsc::UnboundedSender<T>),
+}
+
+impl<T> Clone for StreamSender<T> {
+    fn clone(&self) -> Self {
+        match self {
+            StreamSender::Bounded(sender) => StreamSender::Bounded(sender.clone()),
+            StreamSender::Unbounded(sender) => StreamSender::Unbounded(sender.clone()),
+        }
+    }
+}
+
+impl<T> StreamSender<T> {
+    pub(super) async fn send(&mut self, item: T) -> Result<(), futures::channel::mpsc::SendError> {
+        match self {
+            StreamSender::Bounded(sender) => sender.send(item).await,
+            StreamSender::Unbounded(sender) => sender.unbounded_send(item).map_err(|error| {
+                // The channel is never full so it must be disconnected.
+                error.into_send_error()
+            }),
+        }
+    }
+
+    /// Send `item` without waiting. Fails if a bounded channel is full or the receiver is gone.
+    pub(super) fn try_send(
+        &mut self,
+        item: T,
+    ) -> Result<(), futures::channel::mpsc::TrySendError<T>> {
+        match self {
+            StreamSender::Bounded(sender) => sender.try_send(item),
+            StreamSender::Unbounded(sender) => sender.unbounded_send(item),
+        }
+    }
+
+    /// Close the channel. The receiver gets all messages that have been sent and then ends.
+    pub(super) fn close_channel(&mut self) {
+        match self {
+            StreamSender::Bounded(sender) => sender.close_channel(),
+            StreamSender::Unbounded(sender) => sender.close_channel(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn bounded() {
+        let (mut sender, mut receiver) = channel::<u32>(Some(1));
+        sender.send(1).await.unwrap();
+        let mut send = Box::pin(sender.send(2));
+        // The channel is full
+        assert!(send.as_mut().now_or_never().is_none());
+        assert_eq!(receiver.next().await, Some(1));
+        send.await.unwrap();
+        sender.close_channel();
+        assert_eq!(receiver.collect::<Vec<_>>().await, vec![2]);
+    }
+
+    #[async_std::test]
+    async fn bounded_try_send() {
+        let (mut sender, mut receiver) = channel::<u32>(Some(0));
+        sender.try_send(1).unwrap();
+        assert!(sender.try_send(2).unwrap_err().is_full());
+        assert_eq!(receiver.next().await, Some(1));
+        sender.try_send(3).unwrap();
+        drop(receiver);
+        assert!(sender.try_send(4).unwrap_err().is_disconnected());
+    }
+
+    #[async_std::test]
+    async fn unbounded() {
+        let (mut sender, receiver) = channel::<u32>(None);
+        for i in 0..100 {
+            sender.send(i).await.unwrap();
+        }
+        drop(sender);
+        assert_eq!(receiver.collect::<Vec<_>>().await.len(), 100);
+    }
+}
@@ -1,5 +1,5 @@
 use super::error::Error;
-use super::packet::{Body, Request, Response};
+use super::packet::{Body, BodyDecodeError, Request, Response};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(test, derive(proptest_derive::Arbitrary))]
@@ -34,4 +34,118 @@ impl StreamMessage {
             StreamMessage::End => true,
         }
     }
+
+    /// Deserializes the JSON body of a [StreamMessage::Data] message into `T`.
+    ///
+    /// Fails with [StreamError::Remote] for [StreamMessage::Error] and with [StreamError::Ended]
+    /// for [StreamMessage::End].
+    pub fn decode<T: serde::de::DeserializeOwned>(&self) -> Result<T, StreamError> {
+        match self {
+            StreamMessage::Data(body) => Ok(body.decode_json()?),
+            StreamMessage::Error(error) => Err(StreamError::Remote(error.clone())),
+            StreamMessage::End => Err(StreamError::Ended),
+        }
+    }
+}
+
+/// Error returned by [StreamMessage::decode] and [TypedSource][super::TypedSource].
+#[derive(Debug, thiserror::Error)]
+pub enum StreamError {
+    /// The peer ended the stream with an error.
+    #[error("Stream failed ({}): {}", .0.name, .0.message)]
+    Remote(Error),
+    /// The peer ended the stream.
+    #[error("Stream ended")]
+    Ended,
+    #[error("Failed to decode stream message")]
+    Decode(
+        #[from]
+        #[source]
+        BodyDecodeError,
+    ),
+}
+
+/// Tracks which directions of a stream have ended.
+///
+/// Either side may end its direction with [StreamMessage::End] or [StreamMessage::Error] while
+/// the other side keeps sending. The request number of the stream may only be reused once both
+/// directions have ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(super) enum StreamState {
+    #[default]
+    Open,
+    /// We sent the end message. The peer may still send messages.
+    LocalEnded,
+    /// The peer sent the end message. We may still send messages.
+    RemoteEnded,
+    /// Both directions have ended.
+    Closed,
+}
+
+impl StreamState {
+    /// Returns the state after we sent the end message.
+    pub(super) fn end_local(self) -> Self {
+        match self {
+            StreamState::Open | StreamState::LocalEnded => StreamState::LocalEnded,
+            StreamState::RemoteEnded | StreamState::Closed => StreamState::Closed,
+        }
+    }
+
+    /// Returns the state after the peer sent the end message.
+    pub(super) fn end_remote(self) -> Self {
+        match self {
+            StreamState::Open | StreamState::RemoteEnded => StreamState::RemoteEnded,
+            StreamState::LocalEnded | StreamState::Closed => StreamState::Closed,
+        }
+    }
+
+    /// Returns true if we may still send messages for the stream.
+    pub(super) fn is_local_open(self) -> bool {
+        matches!(self, StreamState::Open | StreamState::RemoteEnded)
+    }
+
+    /// Returns true if the peer may still send messages for the stream.
+    pub(super) fn is_remote_open(self) -> bool {
+        matches!(self, StreamState::Open | StreamState::LocalEnded)
+    }
+
+    pub(super) fn is_closed(self) -> bool {
+        self == StreamState::Closed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stream_state() {
+        let state = StreamState::default();
+        assert!(state.is_remote_open());
+        assert_eq!(state.end_local(), StreamState::LocalEnded);
+        assert!(state.end_local().is_remote_open());
+        assert_eq!(state.end_remote(), StreamState::RemoteEnded);
+        assert!(!state.end_remote().is_remote_open());
+        assert!(state.end_local().end_remote().is_closed());
+        assert!(state.end_remote().end_local().is_closed());
+        assert_eq!(state.end_remote().end_remote(), StreamState::RemoteEnded);
+    }
+
+    #[test]
+    fn decode() {
+        let message = StreamMessage::Data(Body::json(&[1, 2]));
+        assert_eq!(message.decode::<Vec<u32>>().unwrap(), vec![1, 2]);
+        assert!(matches!(
+            message.decode::<String>(),
+            Err(StreamError::Decode(_))
+        ));
+        assert!(matches!(
+            StreamMessage::Error(Error::new("Error", "failed")).decode::<u32>(),
+            Err(StreamError::Remote(_))
+        ));
+        assert!(matches!(
+            StreamMessage::End.decode::<u32>(),
+            Err(StreamError::Ended)
+        ));
+    }
 }
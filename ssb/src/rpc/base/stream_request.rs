@@ -26,17 +26,23 @@ pub enum StreamRequestType {
     Duplex,
 }
 
-impl serde::Serialize for StreamRequestType {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
+impl StreamRequestType {
+    /// Returns the name of the type as used on the wire and in manifests.
+    pub fn as_str(&self) -> &'static str {
         match self {
             Self::Source => "source",
             Self::Sink => "sink",
             Self::Duplex => "duplex",
         }
-        .serialize(serializer)
+    }
+}
+
+impl serde::Serialize for StreamRequestType {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.as_str().serialize(serializer)
     }
 }
 
@@ -1,13 +1,47 @@
+//! Service for testing muxrpc implementations against each other.
+//!
+//! [compat_service] implements the same methods as `muxrpc-test-suite/api.js`. The JavaScript
+//! test suite runs against it when the Rust server is started with the `muxrpc-compat-server`
+//! binary and `EXTERNAL_SERVER` is set to its port. Other implementations can use the binary in
+//! the same way.
+//!
+//! ```bash
+//! cargo run --features test-server --bin muxrpc-compat-server -- --listen 127.0.0.1:19423
+//! ```
 use anyhow::Context;
 use futures::prelude::*;
+use std::time::Duration;
 
 use super::endpoint::Endpoint;
 use super::service::{AsyncResponse, Body, Service, SinkError};
-use super::{Error, StreamMessage};
+use super::{Error, StreamError, StreamMessage};
 
-fn test_service() -> Service {
+/// Upper bound for the sizes requested from `asyncLarge` and `sourceLarge`.
+const MAX_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
+
+/// Create a [Service] that provides the following methods. Errors passed as arguments are
+/// objects with `name` and `message`.
+///
+/// * `syncEcho(value)` (sync) responds with `value`.
+/// * `asyncEcho(value)` responds with `value`.
+/// * `asyncError(error)` responds with `error`.
+/// * `asyncLarge(size)` responds with a string of `size` bytes.
+/// * `sourceEcho(values)` streams `values`.
+/// * `sourceError(values, error)` streams `values` and ends the stream with `error`.
+/// * `sourceInifite()` streams `0` every millisecond until the client ends the stream.
+/// * `sourceSlow(values, delay)` streams `values` waiting `delay` milliseconds before each value.
+/// * `sourceLarge(count, size)` streams `count` strings of `size` bytes.
+/// * `sinkExpect(values)` consumes the stream and fails if it did not receive exactly `values`.
+/// * `sinkAbort(n)` consumes `n` values and ends the stream.
+/// * `sinkAbortError(n, error)` consumes `n` values and ends the stream with `error`.
+/// * `duplexAdd(summand)` responds to every number with the number plus `summand`.
+pub fn compat_service() -> Service {
     let mut service = Service::new();
 
+    service.add_sync("syncEcho", |(x,): (serde_json::Value,)| {
+        AsyncResponse::json_ok(&x)
+    });
+
     service.add_async("asyncEcho", |(x,): (serde_json::Value,)| async move {
         AsyncResponse::json_ok(&x)
     });
@@ -19,19 +53,28 @@ fn test_service() -> Service {
         })
     });
 
+    service.add_async("asyncLarge", |(size,): (usize,)| async move {
+        match large_payload(size) {
+            Ok(payload) => AsyncResponse::json_ok(&payload),
+            Err(error) => AsyncResponse::Err(error),
+        }
+    });
+
     service.add_source("sourceEcho", |(values,): (Vec<serde_json::Value>,)| {
         futures::stream::iter(values).map(|value| Ok(Body::json(&value)))
     });
 
     service.add_source(
         "sourceError",
-        |(_, error): (serde_json::Value, EchoError)| {
-            futures::stream::once(async move {
-                Err(Error {
-                    name: error.name,
-                    message: error.message,
-                })
-            })
+        |(values, error): (Vec<serde_json::Value>, EchoError)| {
+            futures::stream::iter(values)
+                .map(|value| Ok(Body::json(&value)))
+                .chain(futures::stream::once(async move {
+                    Err(Error {
+                        name: error.name,
+                        message: error.message,
+                    })
+                }))
         },
     );
 
@@ -42,6 +85,22 @@ fn test_service() -> Service {
         })
     });
 
+    service.add_source(
+        "sourceSlow",
+        |(values, delay): (Vec<serde_json::Value>, u64)| {
+            futures::stream::iter(values).then(move |value| async move {
+                async_std::task::sleep(Duration::from_millis(delay)).await;
+                Ok(Body::json(&value))
+            })
+        },
+    );
+
+    service.add_source("sourceLarge", |(count, size): (usize, usize)| {
+        let payload = large_payload(size).map(|payload| Body::json(&payload));
+        let count = if payload.is_ok() { count } else { 1 };
+        futures::stream::repeat(payload).take(count)
+    });
+
     service.add_sink("sinkExpect", |(values,): (Vec<serde_json::Value>,)| {
         let mut collected = Vec::<serde_json::Value>::new();
         futures::sink::drain()
@@ -68,6 +127,25 @@ fn test_service() -> Service {
             })
     });
 
+    service.add_sink("sinkAbort", |(n,): (u32,)| {
+        let mut remaining_items = n;
+        futures::sink::drain()
+            .sink_map_err(|infallible| match infallible {})
+            .with(move |stream_message: StreamMessage| {
+                futures::future::ready(match stream_message {
+                    StreamMessage::Data(_) => {
+                        remaining_items = remaining_items.saturating_sub(1);
+                        if remaining_items == 0 {
+                            Err(SinkError::Done)
+                        } else {
+                            Ok(())
+                        }
+                    }
+                    _ => Err(SinkError::Done),
+                })
+            })
+    });
+
     service.add_sink("sinkAbortError", |(n, error): (u32, EchoError)| {
         let mut remaining_items = n;
         futures::sink::drain()
@@ -99,23 +177,24 @@ fn test_service() -> Service {
         // This should never panic. `incoming` is only dropped after we stop accepting inputs on `sink`.
         let sink = incoming_sink.sink_map_err(|err| panic!("{}", err));
 
-        let source = incoming.scan(false, move |closed, stream_message| {
+        let source = incoming.scan(false, move |closed, stream_message: StreamMessage| {
             if *closed {
                 return futures::future::ready(None);
             }
-            let result = match stream_message {
-                StreamMessage::Data(body) => {
-                    let value = body.decode_json::<u64>().unwrap();
-                    Some(Ok(Body::json(&(value + summand))))
-                }
-                StreamMessage::Error(err) => {
+            let result = match stream_message.decode::<u64>() {
+                Ok(value) => Some(Ok(Body::json(&(value + summand)))),
+                Err(StreamError::Remote(err)) => {
                     *closed = true;
                     Some(Err(err))
                 }
-                StreamMessage::End => {
+                Err(StreamError::Ended) => {
                     *closed = true;
                     None
                 }
+                Err(err @ StreamError::Decode(_)) => {
+                    *closed = true;
+                    Some(Err(Error::new("DecodeError", err)))
+                }
             };
             futures::future::ready(result)
         });
@@ -131,6 +210,17 @@ struct EchoError {
     message: String,
 }
 
+fn large_payload(size: usize) -> Result<String, Error> {
+    if size > MAX_PAYLOAD_SIZE {
+        return Err(Error::new(
+            "PayloadTooLarge",
+            format!("Payloads are limited to {} bytes", MAX_PAYLOAD_SIZE),
+        ));
+    }
+    Ok("x".repeat(size))
+}
+
+/// Serve [compat_service] to every client that connects to `bind_addr`.
 pub async fn run(bind_addr: impl async_std::net::ToSocketAddrs) -> anyhow::Result<()> {
     let listener = async_std::net::TcpListener::bind(bind_addr).await?;
     listener
@@ -148,12 +238,137 @@ pub async fn run(bind_addr: impl async_std::net::ToSocketAddrs) -> anyhow::Resul
 
 async fn handle_incoming(stream: async_std::net::TcpStream) -> anyhow::Result<()> {
     tracing::info!(addr = ?stream.peer_addr().unwrap(), "connected to client");
-    let (read, write) = stream.split();
-    let endpoint = Endpoint::new(
-        write.into_sink(),
-        crate::utils::read_to_stream(read),
-        test_service(),
-    );
+    let endpoint = Endpoint::from_io(stream, compat_service());
     endpoint.join().await.context("Endpoint::join failed")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::base::TypedSource;
+    use serde_json::json;
+
+    fn connect() -> (Endpoint, Endpoint) {
+        let (sender_a, receiver_a) = futures::channel::mpsc::channel(10);
+        let (sender_b, receiver_b) = futures::channel::mpsc::channel(10);
+        let client = Endpoint::new_client(sender_a, receiver_b.map(Ok::<_, std::io::Error>));
+        let server = Endpoint::new(
+            sender_b,
+            receiver_a.map(Ok::<_, std::io::Error>),
+            compat_service(),
+        );
+        (client, server)
+    }
+
+    #[async_std::test]
+    async fn sync_echo() {
+        let (mut client, _server) = connect();
+        let response = client
+            .client()
+            .send_sync(vec!["syncEcho".to_string()], vec![json!("foo")])
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            crate::rpc::base::AsyncResponse::Json(br#""foo""#.to_vec())
+        );
+    }
+
+    #[async_std::test]
+    async fn async_large() {
+        let (mut client, _server) = connect();
+        let response = client
+            .client()
+            .send_async(vec!["asyncLarge".to_string()], vec![json!(100_000)])
+            .await
+            .unwrap();
+        let payload =
+            serde_json::from_reader::<_, String>(response.json_reader().unwrap()).unwrap();
+        assert_eq!(payload.len(), 100_000);
+
+        let response = client
+            .client()
+            .send_async(
+                vec!["asyncLarge".to_string()],
+                vec![json!(MAX_PAYLOAD_SIZE + 1)],
+            )
+            .await
+            .unwrap();
+        assert!(
+            matches!(&response, crate::rpc::base::AsyncResponse::Error(error) if error.name == "PayloadTooLarge"),
+            "{:?}",
+            response
+        );
+    }
+
+    #[async_std::test]
+    async fn source_error() {
+        let (mut client, _server) = connect();
+        let source = client
+            .client()
+            .start_source(
+                vec!["sourceError".to_string()],
+                vec![json!([1, 2]), json!({"name": "NAME", "message": "MSG"})],
+            )
+            .await
+            .unwrap();
+        let items = source.collect::<Vec<_>>().await;
+        assert_eq!(
+            items,
+            vec![
+                Ok(Body::json(&1)),
+                Ok(Body::json(&2)),
+                Err(Error::new("NAME", "MSG"))
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn source_slow_and_large() {
+        let (mut client, _server) = connect();
+        let source = client
+            .client()
+            .start_source(
+                vec!["sourceSlow".to_string()],
+                vec![json!([1, 2, 3]), json!(1)],
+            )
+            .await
+            .unwrap();
+        let values = TypedSource::<u32>::new(source)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(values, vec![1, 2, 3]);
+
+        let source = client
+            .client()
+            .start_source(
+                vec!["sourceLarge".to_string()],
+                vec![json!(3), json!(70_000)],
+            )
+            .await
+            .unwrap();
+        let values = TypedSource::<String>::new(source)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(values.len(), 3);
+        assert!(values.iter().all(|value| value.len() == 70_000));
+    }
+
+    #[async_std::test]
+    async fn sink_abort() {
+        let (mut client, _server) = connect();
+        let (source, mut sink) = client
+            .client()
+            .start_sink(vec!["sinkAbort".to_string()], vec![json!(2)])
+            .await
+            .unwrap();
+        sink.send(Body::json(&1)).await.unwrap();
+        sink.send(Body::json(&2)).await.unwrap();
+        let items = source.collect::<Vec<_>>().await;
+        assert_eq!(items, vec![]);
+        sink.close().await.unwrap();
+    }
+}
@@ -0,0 +1,84 @@
+use futures::prelude::*;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::client::BoxStreamSource;
+use super::stream_message::StreamError;
+
+/// Source that deserializes the JSON data the peer sends on a stream into `T`.
+///
+/// Errors the peer sends are returned as [StreamError::Remote]. Data that cannot be decoded as
+/// `T` results in a [StreamError::Decode] item. The source continues after decoding errors.
+///
+/// ```no_run
+/// # use futures::prelude::*;
+/// # use ssb::rpc::base::{Client, TypedSource};
+/// # async fn example(client: &mut Client) -> anyhow::Result<()> {
+/// let source = client
+///     .start_source(vec!["count".to_string()], vec![serde_json::json!(10)])
+///     .await?;
+/// let numbers = TypedSource::<u32>::new(source).try_collect::<Vec<_>>().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TypedSource<T> {
+    source: BoxStreamSource,
+    _item: PhantomData<fn() -> T>,
+}
+
+impl<T> TypedSource<T> {
+    pub fn new(source: BoxStreamSource) -> Self {
+        Self {
+            source,
+            _item: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> BoxStreamSource {
+        self.source
+    }
+}
+
+impl<T> std::fmt::Debug for TypedSource<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TypedSource")
+            .field("item", &std::any::type_name::<T>())
+            .finish()
+    }
+}
+
+impl<T: serde::de::DeserializeOwned> Stream for TypedSource<T> {
+    type Item = Result<T, StreamError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = futures::ready!(self.source.poll_next_unpin(cx));
+        Poll::Ready(item.map(|result| match result {
+            Ok(body) => Ok(body.decode_json()?),
+            Err(error) => Err(StreamError::Remote(error)),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::base::{Body, Error};
+
+    #[async_std::test]
+    async fn decode_items() {
+        let source = futures::stream::iter(vec![
+            Ok(Body::json(&1u32)),
+            Ok(Body::String("two".to_string())),
+            Ok(Body::json(&3u32)),
+            Err(Error::new("Error", "failed")),
+        ])
+        .boxed();
+        let items = TypedSource::<u32>::new(source).collect::<Vec<_>>().await;
+        assert!(matches!(items[0], Ok(1)));
+        assert!(matches!(items[1], Err(StreamError::Decode(_))));
+        assert!(matches!(items[2], Ok(3)));
+        assert!(matches!(&items[3], Err(StreamError::Remote(error)) if error.message == "failed"));
+        assert_eq!(items.len(), 4);
+    }
+}
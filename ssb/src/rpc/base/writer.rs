@@ -0,0 +1,598 @@
+//! Writes outgoing packets of an [Endpoint][super::Endpoint] to the connection.
+//!
+//! Packets that are ready at the same time are combined into a single write. This avoids a
+//! system call and an encrypted box per RPC message when many messages are sent.
+//!
+//! In debug builds and tests every stream packet is tagged with its position on the stream by
+//! [multiplex] before the packets of all streams are merged. Every write is decoded again and
+//! the writer panics if the packets of a stream are not written in the order of their tags.
+use anyhow::Context as _;
+use futures::prelude::*;
+#[cfg(any(test, debug_assertions))]
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::compression::CompressionState;
+use super::connection_closed::ConnectionClosed;
+use super::packet::{BodyEncoding, Header, Packet, Request, Response};
+
+/// Limits for combining packets into a single write.
+#[derive(Debug, Clone)]
+pub(super) struct WriteConfig {
+    /// Maximum number of packets in one write
+    pub batch: usize,
+    /// A write is started once the buffered packets exceed this number of bytes.
+    pub buffer_size: usize,
+    /// Time to wait for more packets before writing. If `None` the packets that are ready are
+    /// written immediately.
+    pub delay: Option<Duration>,
+    pub body_encoding: BodyEncoding,
+    /// Compress stream data once compression is enabled. `None` if it is not configured.
+    pub compression: Option<CompressionState>,
+}
+
+/// Packet that is queued for [send_packets].
+#[derive(Debug)]
+pub(super) struct Sequenced {
+    pub(super) packet: Packet,
+    /// Position of the packet among the packets of its stream in one direction. `None` for
+    /// packets that are not part of a stream.
+    #[cfg(any(test, debug_assertions))]
+    sequence: Option<u64>,
+}
+
+/// Merge outgoing requests and responses into the packets for [send_packets].
+///
+/// Requests and responses take turns when both are ready. The order within `requests` and within
+/// `responses` is kept. Messages that we send on one stream all go through one of them, so they
+/// are written in order while the messages of different streams interleave. Stream packets are
+/// tagged with their position before they are merged so that [send_packets] can check this.
+pub(super) fn multiplex(
+    requests: impl Stream<Item = Request>,
+    responses: impl Stream<Item = Response>,
+) -> impl Stream<Item = Sequenced> {
+    futures::stream::select(
+        sequence(requests.map(Packet::Request)),
+        sequence(responses.map(Packet::Response)),
+    )
+}
+
+/// Tag the stream packets in `packets` with their position on their stream. All packets that
+/// are sent on a stream must go through the same call.
+pub(super) fn sequence(packets: impl Stream<Item = Packet>) -> impl Stream<Item = Sequenced> {
+    #[cfg(any(test, debug_assertions))]
+    let mut next_sequences = HashMap::<i32, u64>::new();
+    packets.map(move |packet| {
+        #[cfg(any(test, debug_assertions))]
+        let sequence = stream_position(&packet).map(|(request_number, is_end)| {
+            let next = next_sequences.entry(request_number).or_default();
+            let sequence = *next;
+            *next += 1;
+            if is_end {
+                // The peer may reuse the number for a new stream.
+                next_sequences.remove(&request_number);
+            }
+            sequence
+        });
+        Sequenced {
+            packet,
+            #[cfg(any(test, debug_assertions))]
+            sequence,
+        }
+    })
+}
+
+/// Request number of a stream packet as it appears in the header and whether it ends the stream.
+#[cfg(any(test, debug_assertions))]
+fn stream_position(packet: &Packet) -> Option<(i32, bool)> {
+    match packet {
+        Packet::Request(Request::Stream { number, message }) => {
+            Some((*number as i32, message.is_end()))
+        }
+        Packet::Response(Response::Stream { number, message }) => {
+            Some(((*number as i32).wrapping_neg(), message.is_end()))
+        }
+        _ => None,
+    }
+}
+
+type FlushRequest = futures::channel::oneshot::Sender<()>;
+
+/// Create a handle to request an immediate write and the stream of requests to pass to
+/// [send_packets].
+pub(super) fn flush_channel() -> (FlushHandle, impl Stream<Item = FlushRequest>) {
+    let (sender, receiver) = futures::channel::mpsc::unbounded();
+    (FlushHandle(sender), receiver)
+}
+
+/// Asks [send_packets] to write all queued packets without waiting for
+/// [WriteConfig::delay].
+#[derive(Debug, Clone)]
+pub(super) struct FlushHandle(futures::channel::mpsc::UnboundedSender<FlushRequest>);
+
+impl FlushHandle {
+    /// Returns once all packets that were queued before the call have been written and the
+    /// connection has been flushed.
+    pub(super) async fn flush(&self) -> anyhow::Result<()> {
+        let (sender, receiver) = futures::channel::oneshot::channel();
+        self.0
+            .unbounded_send(sender)
+            .map_err(|_| anyhow::anyhow!("Connection closed"))?;
+        receiver
+            .await
+            .map_err(|_| anyhow::anyhow!("Connection closed"))
+    }
+}
+
+/// Write `packets` to `sink` and flush it after every write. Closes `sink` once `packets`
+/// ends.
+///
+/// When `goodbye` resolves the packets that are ready are written followed by the goodbye header
+/// and `sink` is closed. Packets that are sent afterwards are dropped.
+///
+/// When the connection is closed the packets that are ready are written and `sink` is closed.
+/// Write errors after the connection has been closed are ignored since the peer is gone. Other
+/// write errors close the connection.
+pub(super) async fn send_packets<Sink_>(
+    packets: impl Stream<Item = Sequenced> + Unpin,
+    flush_requests: impl Stream<Item = FlushRequest> + Unpin,
+    goodbye: impl Future<Output = ()> + Unpin,
+    closed: ConnectionClosed,
+    sink: Sink_,
+    config: WriteConfig,
+) -> anyhow::Result<()>
+where
+    Sink_: Sink<Vec<u8>> + Unpin,
+    Sink_::Error: std::error::Error + Send + Sync + 'static,
+{
+    let result = write_packets(
+        packets,
+        flush_requests,
+        goodbye,
+        closed.wait(),
+        sink,
+        config,
+    )
+    .await;
+    match result {
+        Err(error) if closed.is_closed() => {
+            tracing::debug!(?error, "failed to write to closed connection");
+            Ok(())
+        }
+        Err(error) => {
+            closed.close();
+            Err(error)
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+async fn write_packets<Sink_>(
+    packets: impl Stream<Item = Sequenced> + Unpin,
+    flush_requests: impl Stream<Item = FlushRequest> + Unpin,
+    goodbye: impl Future<Output = ()> + Unpin,
+    closed: impl Future<Output = ()> + Unpin,
+    mut sink: Sink_,
+    config: WriteConfig,
+) -> anyhow::Result<()>
+where
+    Sink_: Sink<Vec<u8>> + Unpin,
+    Sink_::Error: std::error::Error + Send + Sync + 'static,
+{
+    let mut packets = packets.fuse();
+    let mut flush_requests = flush_requests.fuse();
+    let mut goodbye = goodbye.fuse();
+    let mut closed = closed.fuse();
+    let mut batch = Batch::default();
+    let mut ended = false;
+    let mut say_goodbye = false;
+    while !ended {
+        let mut flushed = Vec::new();
+        futures::select_biased! {
+            flush_request = flush_requests.select_next_some() => flushed.push(flush_request),
+            packet = packets.next() => match packet {
+                Some(packet) => batch.push(packet, &config),
+                None => ended = true,
+            },
+            () = goodbye => {
+                ended = true;
+                say_goodbye = true;
+            },
+            () = closed => ended = true,
+        }
+
+        let mut delay = match config.delay {
+            Some(delay) if flushed.is_empty() => Some(futures_timer::Delay::new(delay).fuse()),
+            _ => None,
+        };
+        while !ended && !batch.is_full(&config) {
+            if let Some(next) = packets.next().now_or_never() {
+                match next {
+                    Some(packet) => batch.push(packet, &config),
+                    None => ended = true,
+                }
+                continue;
+            }
+            // No packet is ready. Wait for more packets until the delay has passed.
+            let mut delay_future = match delay.as_mut() {
+                Some(delay_future) => delay_future,
+                None => break,
+            };
+            let mut flush_now = false;
+            futures::select_biased! {
+                flush_request = flush_requests.select_next_some() => {
+                    flushed.push(flush_request);
+                    flush_now = true;
+                }
+                packet = packets.next() => match packet {
+                    Some(packet) => batch.push(packet, &config),
+                    None => ended = true,
+                },
+                () = delay_future => break,
+            }
+            if flush_now {
+                // Write the packets that are ready without waiting any longer.
+                delay = None;
+            }
+        }
+
+        batch
+            .write(&mut sink)
+            .await
+            .context("Failed to send packet")?;
+        for flush_request in flushed {
+            let _ = flush_request.send(());
+        }
+    }
+    if say_goodbye {
+        sink.send(vec![0u8; Header::SIZE])
+            .await
+            .context("Failed to send goodbye")?;
+    }
+    sink.close().await.context("Failed to send packet")?;
+    Ok(())
+}
+
+/// Encoded packets that are written together.
+#[derive(Debug, Default)]
+struct Batch {
+    data: Vec<u8>,
+    packets: usize,
+    /// [Sequenced::sequence] of the packets in `data`
+    #[cfg(any(test, debug_assertions))]
+    sequences: Vec<Option<u64>>,
+    /// Sequence of the next packet that is written on every open stream
+    #[cfg(any(test, debug_assertions))]
+    next_sequences: HashMap<i32, u64>,
+}
+
+impl Batch {
+    fn push(&mut self, sequenced: Sequenced, config: &WriteConfig) {
+        #[cfg(any(test, debug_assertions))]
+        self.sequences.push(sequenced.sequence);
+        let packet = sequenced.packet;
+        let data = match &config.compression {
+            Some(compression) => {
+                packet.build_compressed(config.body_encoding, &|body| compression.compress(body))
+            }
+            None => packet.build_with_encoding(config.body_encoding),
+        };
+        self.data.extend_from_slice(&data);
+        self.packets += 1;
+    }
+
+    fn is_full(&self, config: &WriteConfig) -> bool {
+        self.packets >= config.batch || self.data.len() >= config.buffer_size
+    }
+
+    async fn write<Sink_: Sink<Vec<u8>> + Unpin>(
+        &mut self,
+        sink: &mut Sink_,
+    ) -> Result<(), Sink_::Error> {
+        if self.packets == 0 {
+            return Ok(());
+        }
+        #[cfg(any(test, debug_assertions))]
+        self.check_order();
+        self.packets = 0;
+        sink.send(std::mem::take(&mut self.data)).await
+    }
+
+    /// Decode the headers of the packets in the batch and panic if the packets of a stream are
+    /// not in the order they were tagged with by [sequence].
+    #[cfg(any(test, debug_assertions))]
+    fn check_order(&mut self) {
+        let mut frames = ssb_packet::FrameDecoder::new();
+        let mut data = self.data.as_slice();
+        for sequence in self.sequences.drain(..) {
+            let header = match frames.put(&mut data) {
+                Some(Ok(Some(frame))) => frame.header,
+                result => panic!("batch contains an invalid packet: {:?}", result),
+            };
+            let sequence = match sequence {
+                Some(sequence) => sequence,
+                None => continue,
+            };
+            let next = self
+                .next_sequences
+                .entry(header.request_number)
+                .or_default();
+            assert_eq!(
+                sequence, *next,
+                "stream message written out of order on stream {}",
+                header.request_number
+            );
+            *next += 1;
+            if header.flags.is_end_or_error {
+                self.next_sequences.remove(&header.request_number);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rpc::base::packet::{Body, PacketDecoder, RequestType};
+    use crate::rpc::base::StreamMessage;
+
+    fn packet(number: u32) -> Packet {
+        Packet::Request(Request::Async {
+            number,
+            method: vec!["foo".to_string()],
+            type_: RequestType::Async,
+            args: vec![],
+        })
+    }
+
+    fn config() -> WriteConfig {
+        WriteConfig {
+            batch: 2,
+            buffer_size: usize::MAX,
+            delay: None,
+            body_encoding: BodyEncoding::Json,
+            compression: None,
+        }
+    }
+
+    #[async_std::test]
+    async fn batch_ready_packets() {
+        let packets = futures::stream::iter((1..=5).map(packet));
+        let mut sink = RecordingSink::default();
+        send_packets(
+            sequence(packets),
+            futures::stream::pending(),
+            future::pending(),
+            ConnectionClosed::new(),
+            &mut sink,
+            config(),
+        )
+        .await
+        .unwrap();
+        let expected = (1..=5).flat_map(|n| packet(n).build()).collect::<Vec<_>>();
+        assert_eq!(sink.writes.len(), 3);
+        assert_eq!(sink.writes.concat(), expected);
+        assert_eq!(sink.flushes, 3);
+        assert!(sink.closed);
+    }
+
+    #[async_std::test]
+    async fn buffer_size() {
+        let packets = futures::stream::iter((1..=3).map(packet));
+        let mut sink = RecordingSink::default();
+        let config = WriteConfig {
+            batch: usize::MAX,
+            buffer_size: 1,
+            delay: None,
+            body_encoding: BodyEncoding::Json,
+            compression: None,
+        };
+        send_packets(
+            sequence(packets),
+            futures::stream::pending(),
+            future::pending(),
+            ConnectionClosed::new(),
+            &mut sink,
+            config,
+        )
+        .await
+        .unwrap();
+        assert_eq!(sink.writes.len(), 3);
+    }
+
+    #[async_std::test]
+    async fn delay() {
+        let (mut packet_sender, packets) = futures::channel::mpsc::unbounded();
+        let (flush_handle, flush_requests) = flush_channel();
+        let config = WriteConfig {
+            batch: usize::MAX,
+            buffer_size: usize::MAX,
+            delay: Some(Duration::from_secs(60)),
+            body_encoding: BodyEncoding::Json,
+            compression: None,
+        };
+        let (sink, writes) = futures::channel::mpsc::unbounded::<Vec<u8>>();
+        let task = async_std::task::spawn(send_packets(
+            sequence(packets),
+            flush_requests,
+            future::pending(),
+            ConnectionClosed::new(),
+            sink.sink_map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe)),
+            config,
+        ));
+
+        packet_sender.send(packet(1)).await.unwrap();
+        packet_sender.send(packet(2)).await.unwrap();
+        // Without the flush request the writer would wait for a minute.
+        flush_handle.flush().await.unwrap();
+        packet_sender.send(packet(3)).await.unwrap();
+        drop(packet_sender);
+        task.await.unwrap();
+
+        let writes = writes.collect::<Vec<_>>().await;
+        assert_eq!(writes.len(), 2);
+        assert_eq!(writes[0], [packet(1).build(), packet(2).build()].concat());
+        assert_eq!(writes[1], packet(3).build());
+    }
+
+    #[async_std::test]
+    async fn goodbye() {
+        let (mut packet_sender, packets) = futures::channel::mpsc::unbounded();
+        let (goodbye_sender, goodbye) = futures::channel::oneshot::channel();
+        let mut sink = RecordingSink::default();
+        packet_sender.send(packet(1)).await.unwrap();
+        goodbye_sender.send(()).unwrap();
+        send_packets(
+            sequence(packets),
+            futures::stream::pending(),
+            goodbye.map(|_| ()),
+            ConnectionClosed::new(),
+            &mut sink,
+            config(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            sink.writes.concat(),
+            [packet(1).build(), vec![0u8; Header::SIZE]].concat()
+        );
+        assert!(sink.closed);
+    }
+
+    #[async_std::test]
+    async fn stream_order() {
+        const STREAMS: u32 = 20;
+        const MESSAGES: u32 = 50;
+        let (request_sender, requests) = futures::channel::mpsc::channel(4);
+        let (response_sender, responses) = futures::channel::mpsc::channel(4);
+        let mut senders = Vec::new();
+        for number in 1..=STREAMS {
+            let mut request_sender = request_sender.clone();
+            let mut response_sender = response_sender.clone();
+            senders.push(async_std::task::spawn(async move {
+                for index in 0..MESSAGES {
+                    let message = StreamMessage::Data(Body::json(&index));
+                    request_sender
+                        .send(message.clone().into_request(number))
+                        .await
+                        .unwrap();
+                    response_sender
+                        .send(message.into_response(number))
+                        .await
+                        .unwrap();
+                }
+            }));
+        }
+        drop((request_sender, response_sender));
+
+        let mut sink = RecordingSink::default();
+        let config = WriteConfig {
+            batch: 7,
+            buffer_size: 256,
+            delay: None,
+            body_encoding: BodyEncoding::Json,
+            compression: None,
+        };
+        send_packets(
+            multiplex(requests, responses),
+            futures::stream::pending(),
+            future::pending(),
+            ConnectionClosed::new(),
+            &mut sink,
+            config,
+        )
+        .await
+        .unwrap();
+        future::join_all(senders).await;
+
+        // Messages of each stream and direction arrive in order, streams may interleave.
+        let mut received = std::collections::BTreeMap::<_, Vec<u32>>::new();
+        let data = sink.writes.concat();
+        let mut data = data.as_slice();
+        let mut decoder = PacketDecoder::new();
+        while let Some(packet) = decoder.put(&mut data) {
+            let (key, message) = match packet.unwrap().unwrap() {
+                Packet::Request(Request::Stream { number, message }) => ((true, number), message),
+                Packet::Response(Response::Stream { number, message }) => {
+                    ((false, number), message)
+                }
+                packet => panic!("Unexpected packet {:?}", packet),
+            };
+            let index = match message {
+                StreamMessage::Data(body) => body.decode_json::<u32>().unwrap(),
+                message => panic!("Unexpected message {:?}", message),
+            };
+            received.entry(key).or_default().push(index);
+        }
+        assert_eq!(received.len(), 2 * STREAMS as usize);
+        for indexes in received.values() {
+            assert_eq!(*indexes, (0..MESSAGES).collect::<Vec<_>>());
+        }
+    }
+
+    #[async_std::test]
+    #[should_panic(expected = "stream message written out of order on stream -1")]
+    async fn out_of_order() {
+        let messages = (0..3).map(|index| StreamMessage::Data(Body::json(&index)).into_response(1));
+        let mut packets = sequence(futures::stream::iter(messages).map(Packet::Response))
+            .collect::<Vec<_>>()
+            .await;
+        // Swap the second and third message of the stream.
+        packets.swap(1, 2);
+        send_packets(
+            futures::stream::iter(packets),
+            futures::stream::pending(),
+            future::pending(),
+            ConnectionClosed::new(),
+            &mut RecordingSink::default(),
+            config(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordingSink {
+        buffered: Vec<Vec<u8>>,
+        writes: Vec<Vec<u8>>,
+        flushes: usize,
+        closed: bool,
+    }
+
+    impl Sink<Vec<u8>> for RecordingSink {
+        type Error = std::io::Error;
+
+        fn poll_ready(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), Self::Error>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn start_send(
+            mut self: std::pin::Pin<&mut Self>,
+            item: Vec<u8>,
+        ) -> Result<(), Self::Error> {
+            self.buffered.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), Self::Error>> {
+            let buffered = std::mem::take(&mut self.buffered);
+            self.writes.extend(buffered);
+            self.flushes += 1;
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), Self::Error>> {
+            self.closed = true;
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Typed content of common message types for [Client::publish][super::Client::publish].
+
+/// Content of a message. Serializes to an object with the `type` field set to the lowercase
+/// variant name.
+///
+/// ```rust
+/// # use ssb::rpc::ssb::{Content, Post};
+/// let content = Content::Post(Post::new("hello"));
+/// assert_eq!(
+///     serde_json::to_value(&content).unwrap(),
+///     serde_json::json!({ "type": "post", "text": "hello" }),
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Content {
+    Post(Post),
+    Contact(Contact),
+    About(About),
+    Vote(Vote),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Post {
+    pub text: String,
+    /// First message of the thread the post replies to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<String>,
+    /// Latest messages of the thread the author has seen.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub branch: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    /// Feeds, messages and blobs the text refers to. Usually objects with a `link` field.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<serde_json::Value>,
+}
+
+impl Post {
+    pub fn new(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            ..Self::default()
+        }
+    }
+}
+
+/// Follow, unfollow, block or unblock a feed.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Contact {
+    pub contact: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub following: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blocking: Option<bool>,
+}
+
+/// Describe a feed. Fields that are `None` are not changed.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct About {
+    pub about: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Blob ID of the image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Vote {
+    pub vote: VoteValue,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct VoteValue {
+    /// Message that is voted on
+    pub link: String,
+    /// `1` to like the message and `0` to withdraw the like.
+    pub value: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expression: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serde() {
+        let content = Content::Vote(Vote {
+            vote: VoteValue {
+                link: "%a.sha256".to_string(),
+                value: 1,
+                expression: Some("Like".to_string()),
+            },
+        });
+        let json = serde_json::json!({
+            "type": "vote",
+            "vote": { "link": "%a.sha256", "value": 1, "expression": "Like" },
+        });
+        assert_eq!(serde_json::to_value(&content).unwrap(), json);
+        assert_eq!(serde_json::from_value::<Content>(json).unwrap(), content);
+
+        let content = serde_json::from_value::<Content>(serde_json::json!({
+            "type": "post",
+            "text": "hi",
+            "root": "%root.sha256",
+        }))
+        .unwrap();
+        assert_eq!(
+            content,
+            Content::Post(Post {
+                root: Some("%root.sha256".to_string()),
+                ..Post::new("hi")
+            })
+        );
+    }
+}
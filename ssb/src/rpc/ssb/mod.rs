@@ -2,6 +2,28 @@
 use futures::prelude::*;
 use std::collections::HashMap;
 
+use crate::rpc::base::{AsyncResponse, StreamError, TypedSource};
+
+mod content;
+pub use content::{About, Contact, Content, Post, Vote, VoteValue};
+
+mod retry;
+pub use retry::{retry, Attempt, ErrorClass, Retried, RetryError, RetryPolicy, IDEMPOTENT_METHODS};
+
+mod room;
+#[cfg(not(target_arch = "wasm32"))]
+pub use room::alias_registration_signature;
+pub use room::{
+    alias_url, AliasResolution, AliasResolutionError, AttendantsEvent, BoxAttendantsStream,
+    RoomClient, RoomMetadata,
+};
+
+mod thread;
+pub use thread::{build_thread, ThreadMessage, ThreadNode};
+
+mod user_stream;
+pub use user_stream::{merge_live, UserStreamMessage, UserStreamOptions};
+
 #[derive(Debug)]
 pub struct Client {
     endpoint: crate::rpc::base::Endpoint,
@@ -23,11 +45,37 @@ impl Client {
         }
     }
 
+    /// Create a new client that talks to a server over `io`.
+    pub fn from_io(io: impl AsyncRead + AsyncWrite + Send + 'static) -> Self {
+        Client {
+            endpoint: crate::rpc::base::Endpoint::from_io(io, crate::rpc::base::Service::new()),
+        }
+    }
+
     /// Get the underlying application agnostic client.
     pub fn base(&mut self) -> &mut crate::rpc::base::Client {
         self.endpoint.client()
     }
 
+    /// Typed methods of the `room` module for clients connected to a room.
+    pub fn room(&mut self) -> RoomClient<'_> {
+        RoomClient::new(self)
+    }
+
+    /// Typed methods of the `admin` module for local clients of a
+    /// [PubServer][crate::pub_server::PubServer].
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn admin(&mut self) -> crate::admin::AdminClient<'_> {
+        crate::admin::AdminClient::new(self)
+    }
+
+    /// End all open streams, say goodbye to the server and close the connection.
+    ///
+    /// See [crate::rpc::base::Endpoint::close].
+    pub async fn close(self) -> anyhow::Result<()> {
+        self.endpoint.close().await
+    }
+
     /// Get all registered RPC methods .
     pub async fn manifest(&mut self) -> Result<Manifest, Error> {
         let rpc_manifest = self
@@ -53,65 +101,231 @@ impl Client {
         Ok(help)
     }
 
-    pub async fn publish(&mut self, content: MessageContent) -> Result<serde_json::Value, Error> {
-        self.send_async_json(&["publish"], vec![serde_json::to_value(content).unwrap()])
-            .await
+    /// Publish a message with `content` to the feed of the server.
+    ///
+    /// `content` is usually a [Content] but may be any value that serializes to an object with a
+    /// `type` field or to the string of an encrypted message.
+    pub async fn publish<C: serde::Serialize + ?Sized>(
+        &mut self,
+        content: &C,
+    ) -> Result<PublishedMessage, Error> {
+        let content = serde_json::to_value(content).map_err(Error::EncodeContent)?;
+        self.send_async_json(&["publish"], vec![content]).await
+    }
+
+    /// Encrypt `content` for `recipients` with [private-box][crate::private_box] and publish it.
+    ///
+    /// The recipients are added to the `recps` field of the content unless it is already set.
+    /// The author can only read the message if their feed is one of the recipients.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn publish_private<C: serde::Serialize + ?Sized>(
+        &mut self,
+        content: &C,
+        recipients: &[crate::feed::FeedId],
+    ) -> Result<PublishedMessage, Error> {
+        let mut content = serde_json::to_value(content).map_err(Error::EncodeContent)?;
+        if let serde_json::Value::Object(fields) = &mut content {
+            fields
+                .entry("recps")
+                .or_insert_with(|| serde_json::to_value(recipients).unwrap());
+        }
+        let content = crate::private_box::box_content(&content, recipients)?;
+        self.publish(&content).await
     }
 
     /// Create an invitation
     pub async fn invite_create(&mut self, params: InviteCreateParams) -> Result<String, Error> {
-        let response = self
+        self.send_async_typed(
+            &["invite", "create"],
+            vec![serde_json::to_value(params).unwrap()],
+        )
+        .await
+    }
+
+    /// Stream the messages of `feed` with `createUserStream`.
+    ///
+    /// The sync marker that servers send between old and live messages is skipped.
+    pub async fn create_user_stream(
+        &mut self,
+        feed: &str,
+        options: UserStreamOptions,
+    ) -> Result<BoxUserStream, Error> {
+        let mut args = serde_json::to_value(options).unwrap();
+        args["id"] = feed.into();
+        args["keys"] = true.into();
+        let source = self
             .endpoint
             .client()
-            .send_async(
-                vec!["invite".to_string(), "create".to_string()],
-                vec![serde_json::to_value(params).unwrap()],
+            .start_source(vec!["createUserStream".to_string()], vec![args])
+            .await
+            .map_err(Error::StartStream)?;
+        let messages =
+            TypedSource::<user_stream::UserStreamItem>::new(source).filter_map(|item| async move {
+                match item {
+                    Ok(user_stream::UserStreamItem::Sync { .. }) => None,
+                    Ok(user_stream::UserStreamItem::Message(message)) => Some(Ok(message)),
+                    Err(error) => Some(Err(error)),
+                }
+            });
+        Ok(messages.boxed())
+    }
+
+    /// Stream the messages of `feed` after sequence number `gt` and keep streaming new messages.
+    ///
+    /// Starts a live stream before it requests the existing messages and joins both with
+    /// [merge_live]. Messages are returned in order without duplicates.
+    pub async fn create_user_stream_live(
+        &mut self,
+        feed: &str,
+        gt: Option<u64>,
+    ) -> Result<BoxUserStream, Error> {
+        let live = self
+            .create_user_stream(
+                feed,
+                UserStreamOptions {
+                    gt,
+                    live: true,
+                    old: Some(false),
+                    ..UserStreamOptions::default()
+                },
+            )
+            .await?;
+        let history = self
+            .create_user_stream(
+                feed,
+                UserStreamOptions {
+                    gt,
+                    ..UserStreamOptions::default()
+                },
             )
             .await?;
+        Ok(merge_live(history, live).boxed())
+    }
 
-        match response {
-            crate::rpc::base::AsyncResponse::Json(_) => {
-                Err(Error::InvalidResponseType { type_: "json" })
-            }
-            crate::rpc::base::AsyncResponse::String(content) => Ok(content),
-            crate::rpc::base::AsyncResponse::Blob(_) => {
-                Err(Error::InvalidResponseType { type_: "blob" })
+    /// Fetch the message `msg_id` and all messages that reply to it.
+    ///
+    /// Replies are found with a `links` query for messages that have `msg_id` as their `root`.
+    /// See [build_thread] for how they are arranged.
+    pub async fn get_thread(&mut self, msg_id: &str) -> Result<ThreadNode, Error> {
+        let value = self
+            .send_async_json::<serde_json::Value>(&["get"], vec![msg_id.into()])
+            .await?;
+        let root = ThreadMessage::from_value(msg_id.to_string(), value)?;
+
+        let query = serde_json::json!({
+            "dest": msg_id,
+            "rel": "root",
+            "keys": true,
+            "values": true,
+        });
+        let links = self
+            .endpoint
+            .client()
+            .start_source(vec!["links".to_string()], vec![query])
+            .await
+            .map_err(Error::StartStream)?;
+        let mut links = TypedSource::<Link>::new(links);
+        let mut replies = Vec::new();
+        while let Some(link) = links.try_next().await? {
+            if let Some(value) = link.value {
+                replies.push(ThreadMessage::from_value(link.key, value)?);
             }
-            crate::rpc::base::AsyncResponse::Error(error) => Err(Error::Rpc {
-                name: error.name,
-                message: error.message,
-            }),
         }
+        Ok(build_thread(root, replies))
     }
 
-    /// Send an `async` type request and expect a response with `T` serialized as.
-    async fn send_async_json<T: serde::de::DeserializeOwned>(
+    /// Send an `async` type request and convert the response to `T`.
+    ///
+    /// Fails with [Error::InvalidResponseType] if the response body type does not match `T`.
+    pub async fn send_async_typed<T: FromAsyncResponse>(
         &mut self,
         method: &[&str],
         args: Vec<serde_json::Value>,
     ) -> Result<T, Error> {
         let method = method.iter().map(|s| String::from(*s)).collect();
         let response = self.endpoint.client().send_async(method, args).await?;
+        T::from_async_response(response)
+    }
+
+    /// Send an `async` type request and expect a response with `T` serialized as.
+    async fn send_async_json<T: serde::de::DeserializeOwned>(
+        &mut self,
+        method: &[&str],
+        args: Vec<serde_json::Value>,
+    ) -> Result<T, Error> {
+        let Json(value) = self.send_async_typed(method, args).await?;
+        Ok(value)
+    }
+}
+
+/// Message returned by [Client::publish].
+pub type PublishedMessage = UserStreamMessage;
+
+/// Messages returned by [Client::create_user_stream].
+pub type BoxUserStream =
+    futures::stream::BoxStream<'static, Result<UserStreamMessage, StreamError>>;
+
+/// Conversion from the response to an `async` request. Used by [Client::send_async_typed].
+///
+/// Implemented for
+/// * [Json] and [serde_json::Value] for JSON responses,
+/// * [String] for string responses, and
+/// * `Vec<u8>` for binary responses.
+pub trait FromAsyncResponse: Sized {
+    fn from_async_response(response: AsyncResponse) -> Result<Self, Error>;
+}
+
+/// Response that is deserialized from JSON.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Json<T>(pub T);
 
+impl<T: serde::de::DeserializeOwned> FromAsyncResponse for Json<T> {
+    fn from_async_response(response: AsyncResponse) -> Result<Self, Error> {
         match response {
-            crate::rpc::base::AsyncResponse::Json(data) => {
-                let value = serde_json::from_slice::<T>(&data)?;
-                Ok(value)
-            }
-            crate::rpc::base::AsyncResponse::String(_) => {
-                Err(Error::InvalidResponseType { type_: "string" })
-            }
-            crate::rpc::base::AsyncResponse::Blob(_) => {
-                Err(Error::InvalidResponseType { type_: "blob" })
-            }
-            crate::rpc::base::AsyncResponse::Error(error) => Err(Error::Rpc {
-                name: error.name,
-                message: error.message,
-            }),
+            AsyncResponse::Json(data) => Ok(Json(serde_json::from_slice(&data)?)),
+            response => Err(unexpected_response(response)),
+        }
+    }
+}
+
+impl FromAsyncResponse for serde_json::Value {
+    fn from_async_response(response: AsyncResponse) -> Result<Self, Error> {
+        let Json(value) = Json::from_async_response(response)?;
+        Ok(value)
+    }
+}
+
+impl FromAsyncResponse for String {
+    fn from_async_response(response: AsyncResponse) -> Result<Self, Error> {
+        match response {
+            AsyncResponse::String(content) => Ok(content),
+            response => Err(unexpected_response(response)),
+        }
+    }
+}
+
+impl FromAsyncResponse for Vec<u8> {
+    fn from_async_response(response: AsyncResponse) -> Result<Self, Error> {
+        match response {
+            AsyncResponse::Blob(data) => Ok(data),
+            response => Err(unexpected_response(response)),
         }
     }
 }
 
+/// Error for a response that does not have the expected type.
+fn unexpected_response(response: AsyncResponse) -> Error {
+    match response {
+        AsyncResponse::Json(_) => Error::InvalidResponseType { type_: "json" },
+        AsyncResponse::String(_) => Error::InvalidResponseType { type_: "string" },
+        AsyncResponse::Blob(_) => Error::InvalidResponseType { type_: "blob" },
+        AsyncResponse::Error(error) => Error::Rpc {
+            name: error.name,
+            message: error.message,
+        },
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
@@ -126,20 +340,64 @@ pub enum Error {
     InvalidResponseType { type_: &'static str },
     #[error("RPC error response ({name}): {message}")]
     Rpc { name: String, message: String },
+    #[error("Failed to start stream")]
+    StartStream(#[source] anyhow::Error),
+    #[error(transparent)]
+    Stream(#[from] StreamError),
+    #[error("Failed to encode message content")]
+    EncodeContent(#[source] serde_json::Error),
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error(transparent)]
+    PrivateBox(#[from] crate::private_box::PrivateBoxError),
 }
 
-#[derive(Debug)]
+/// Item of a `links` stream with `keys` and `values` set.
+#[derive(serde::Deserialize, Debug)]
+struct Link {
+    key: String,
+    value: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Manifest {
     pub methods: Vec<ManifestMethod>,
     pub modules: HashMap<String, Manifest>,
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ManifestMethod {
     pub name: String,
     pub type_: String,
 }
 
+impl Manifest {
+    /// Returns the type of the method at `path` if the manifest includes it.
+    ///
+    /// ```rust
+    /// # use ssb::rpc::ssb::{Manifest, ManifestMethod};
+    /// let mut manifest = Manifest::default();
+    /// manifest.methods.push(ManifestMethod {
+    ///     name: "whoami".to_string(),
+    ///     type_: "sync".to_string(),
+    /// });
+    /// assert_eq!(manifest.method_type(&["whoami"]), Some("sync"));
+    /// assert_eq!(manifest.method_type(&["blobs", "get"]), None);
+    /// ```
+    pub fn method_type(&self, path: &[&str]) -> Option<&str> {
+        match path {
+            [] => None,
+            [name] => self
+                .methods
+                .iter()
+                .find(|method| method.name == *name)
+                .map(|method| method.type_.as_str()),
+            [module, rest @ ..] => self.modules.get(*module)?.method_type(rest),
+        }
+    }
+}
+
 impl From<RpcManifest> for Manifest {
     fn from(m: RpcManifest) -> Self {
         let mut methods = Vec::new();
@@ -171,6 +429,7 @@ enum RpcManifestEntry {
 }
 
 #[derive(serde::Deserialize, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Help {
     pub description: String,
     #[serde(rename = "commands")]
@@ -178,6 +437,7 @@ pub struct Help {
 }
 
 #[derive(serde::Deserialize, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct HelpMethod {
     pub description: String,
     #[serde(rename = "type")]
@@ -188,6 +448,7 @@ pub struct HelpMethod {
 }
 
 #[derive(serde::Deserialize, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct HelpMethodArg {
     pub description: Option<String>,
     #[serde(rename = "type")]
@@ -197,16 +458,164 @@ pub struct HelpMethodArg {
     pub default: Option<serde_json::Value>,
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
-pub struct MessageContent {
-    #[serde(rename = "type")]
-    pub type_: String,
-    pub text: String,
-}
-
 /// Parameters for [Client::invite_create].
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct InviteCreateParams {
     /// Number of times this invite can be used
     pub uses: u32,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn from_async_response() {
+        let Json(value) =
+            Json::<Vec<u32>>::from_async_response(AsyncResponse::Json(b"[1,2]".to_vec())).unwrap();
+        assert_eq!(value, vec![1, 2]);
+
+        let value = String::from_async_response(AsyncResponse::String("foo".to_string()));
+        assert_eq!(value.unwrap(), "foo");
+
+        let value = Vec::<u8>::from_async_response(AsyncResponse::Blob(vec![1, 2]));
+        assert_eq!(value.unwrap(), vec![1, 2]);
+    }
+
+    pub(super) fn connect(
+        service: crate::rpc::base::Service,
+    ) -> (Client, crate::rpc::base::Endpoint) {
+        let (client_sender, server_receiver) = futures::channel::mpsc::channel(10);
+        let (server_sender, client_receiver) = futures::channel::mpsc::channel(10);
+        let server = crate::rpc::base::Endpoint::new(
+            server_sender,
+            server_receiver.map(Ok::<_, std::io::Error>),
+            service,
+        );
+        let client = Client::new(client_sender, client_receiver.map(Ok::<_, std::io::Error>));
+        (client, server)
+    }
+
+    #[async_std::test]
+    async fn get_thread() {
+        let value = |sequence: u64, content: serde_json::Value| {
+            serde_json::json!({
+                "author": "@author",
+                "sequence": sequence,
+                "timestamp": sequence,
+                "content": content,
+            })
+        };
+        let root = value(1, serde_json::json!({ "type": "post", "text": "root" }));
+        let links = vec![
+            serde_json::json!({
+                "key": "%b",
+                "value": value(3, serde_json::json!({ "root": "%root", "branch": "%a" })),
+            }),
+            serde_json::json!({
+                "key": "%a",
+                "value": value(2, serde_json::json!({ "root": "%root", "branch": "%root" })),
+            }),
+        ];
+        let mut service = crate::rpc::base::Service::new();
+        service.add_async("get", move |(id,): (String,)| {
+            assert_eq!(id, "%root");
+            future::ready(crate::rpc::base::service::AsyncResponse::json_ok(&root))
+        });
+        service.add_source("links", move |(query,): (serde_json::Value,)| {
+            assert_eq!(query["dest"], "%root");
+            assert_eq!(query["rel"], "root");
+            futures::stream::iter(links.clone()).map(|link| Ok(crate::rpc::base::Body::json(&link)))
+        });
+        let (mut client, _server) = connect(service);
+
+        let thread = client.get_thread("%root").await.unwrap();
+        assert_eq!(thread.message.content["text"], "root");
+        assert_eq!(thread.replies.len(), 1);
+        assert_eq!(thread.replies[0].message.key, "%a");
+        assert_eq!(thread.replies[0].replies[0].message.key, "%b");
+    }
+
+    #[async_std::test]
+    async fn publish() {
+        let mut service = crate::rpc::base::Service::new();
+        service.add_async("publish", |(content,): (serde_json::Value,)| {
+            let message = serde_json::json!({
+                "key": "%a.sha256",
+                "value": { "sequence": 5, "content": content },
+                "timestamp": 10,
+            });
+            future::ready(crate::rpc::base::service::AsyncResponse::json_ok(&message))
+        });
+        let (mut client, _server) = connect(service);
+
+        let message = client
+            .publish(&Content::Post(Post::new("hello")))
+            .await
+            .unwrap();
+        assert_eq!(message.key, "%a.sha256");
+        assert_eq!(message.sequence, 5);
+        assert_eq!(message.value["content"]["text"], "hello");
+
+        let keypair = crate::crypto::sign::KeyPair::gen();
+        let recipient = crate::feed::FeedId(keypair.public);
+        let message = client
+            .publish_private(&Content::Post(Post::new("secret")), &[recipient])
+            .await
+            .unwrap();
+        let content = message.value["content"].as_str().unwrap();
+        let content = crate::private_box::unbox_content(content, &keypair).unwrap();
+        assert_eq!(content["text"], "secret");
+        assert_eq!(content["recps"], serde_json::json!([recipient]));
+    }
+
+    #[async_std::test]
+    async fn create_user_stream_live() {
+        let mut service = crate::rpc::base::Service::new();
+        service.add_source("createUserStream", |(options,): (serde_json::Value,)| {
+            assert_eq!(options["id"], "@feed");
+            assert_eq!(options["keys"], true);
+            assert_eq!(options["gt"], 1);
+            let message = |sequence: u64| {
+                serde_json::json!({
+                    "key": format!("%{}.sha256", sequence),
+                    "value": { "sequence": sequence },
+                    "timestamp": 0,
+                })
+            };
+            let items = if options["live"] == true {
+                vec![message(3), message(4)]
+            } else {
+                vec![message(2), message(3), serde_json::json!({ "sync": true })]
+            };
+            futures::stream::iter(items).map(|item| Ok(crate::rpc::base::Body::json(&item)))
+        });
+        let (mut client, _server) = connect(service);
+
+        let sequences = client
+            .create_user_stream_live("@feed", Some(1))
+            .await
+            .unwrap()
+            .map_ok(|message| message.sequence)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(sequences, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn from_async_response_invalid_type() {
+        let error = String::from_async_response(AsyncResponse::Blob(vec![])).unwrap_err();
+        assert!(matches!(
+            error,
+            Error::InvalidResponseType { type_: "blob" }
+        ));
+
+        let error = Vec::<u8>::from_async_response(AsyncResponse::Error(crate::rpc::base::Error {
+            name: "Error".to_string(),
+            message: "failed".to_string(),
+        }))
+        .unwrap_err();
+        assert!(matches!(error, Error::Rpc { .. }));
+    }
+}
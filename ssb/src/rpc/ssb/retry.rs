@@ -0,0 +1,310 @@
+//! Retry requests that failed because of a flaky connection.
+//!
+//! [retry] repeats a call with exponential backoff as long as the error is
+//! [retryable][ErrorClass::is_retryable] and the method is idempotent according to the
+//! [RetryPolicy]. Methods that change state on the server, like `publish`, are only tried once
+//! because we cannot tell whether a request that timed out was applied.
+//!
+//! ```rust
+//! # use ssb::rpc::ssb::{retry, Client, RetryPolicy};
+//! # use futures::prelude::*;
+//! # async fn f(client: &mut Client) -> anyhow::Result<()> {
+//! let policy = RetryPolicy::new(3);
+//! let manifest = retry(&policy, &["manifest"], client, |client| {
+//!     client.manifest().boxed()
+//! })
+//! .await?;
+//! println!("{} failed attempts", manifest.attempts.len());
+//! # Ok(())
+//! # }
+//! ```
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use std::collections::HashSet;
+use std::time::Duration;
+
+use super::Error;
+use crate::rpc::base::{AsyncRequestError, StreamError, TIMEOUT_ERROR_NAME};
+
+/// Methods that [RetryPolicy::new] considers idempotent.
+pub const IDEMPOTENT_METHODS: &[&str] = &[
+    "whoami",
+    "manifest",
+    "help",
+    "get",
+    "getLatest",
+    "latestSequence",
+    "createHistoryStream",
+    "createUserStream",
+    "blobs.has",
+    "blobs.size",
+    "blobs.get",
+    "room.metadata",
+    "room.listAliases",
+];
+
+/// Kind of failure of a call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The request could not be sent or the connection was closed before the response arrived.
+    Transport,
+    /// The response did not arrive in time.
+    Timeout,
+    /// The server answered with an error.
+    Rpc,
+    /// The response could not be decoded or the request could not be encoded.
+    Other,
+}
+
+impl ErrorClass {
+    pub fn of(error: &Error) -> Self {
+        match error {
+            Error::Base(AsyncRequestError::Timeout(_)) => Self::Timeout,
+            Error::Base(_) | Error::StartStream(_) | Error::Stream(StreamError::Ended) => {
+                Self::Transport
+            }
+            Error::Stream(StreamError::Remote(error)) if error.name == TIMEOUT_ERROR_NAME => {
+                Self::Timeout
+            }
+            Error::Rpc { .. } | Error::Stream(StreamError::Remote(_)) => Self::Rpc,
+            _ => Self::Other,
+        }
+    }
+
+    /// Returns `true` if trying again may succeed. The server answers an RPC error again with
+    /// the same error and decoding errors are not caused by the connection.
+    pub fn is_retryable(self) -> bool {
+        matches!(self, Self::Transport | Self::Timeout)
+    }
+}
+
+/// When and how often [retry] tries a call again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Largest number of times a call is made, including the first.
+    pub max_attempts: u32,
+    /// Time to wait after the first failure. The delay doubles with every failure.
+    pub min_delay: Duration,
+    /// Upper limit for the time to wait after a failure
+    pub max_delay: Duration,
+    /// An attempt fails with [AsyncRequestError::Timeout] if it takes longer. Unlimited if
+    /// `None`.
+    pub attempt_timeout: Option<Duration>,
+    /// Methods that are retried, joined with `.`, for example `blobs.has`.
+    pub idempotent: HashSet<String>,
+}
+
+impl RetryPolicy {
+    /// Policy that makes up to `max_attempts` calls of the [IDEMPOTENT_METHODS].
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            min_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(10),
+            attempt_timeout: None,
+            idempotent: IDEMPOTENT_METHODS.iter().map(|m| m.to_string()).collect(),
+        }
+    }
+
+    pub fn delay(mut self, min: Duration, max: Duration) -> Self {
+        self.min_delay = min;
+        self.max_delay = max;
+        self
+    }
+
+    pub fn attempt_timeout(mut self, timeout: Duration) -> Self {
+        self.attempt_timeout = Some(timeout);
+        self
+    }
+
+    /// Also retry `method`.
+    pub fn idempotent(mut self, method: &[&str]) -> Self {
+        self.idempotent.insert(method.join("."));
+        self
+    }
+
+    pub fn is_idempotent(&self, method: &[&str]) -> bool {
+        self.idempotent.contains(&method.join("."))
+    }
+
+    /// Time to wait after the call failed `failures` times in a row.
+    pub fn backoff(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return Duration::ZERO;
+        }
+        let factor = 2u32.saturating_pow(failures - 1);
+        self.min_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+}
+
+/// Failed call made by [retry].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attempt {
+    pub class: ErrorClass,
+    /// Description of the error, including its sources
+    pub error: String,
+    /// Time we waited before the next attempt. `None` if the call was not tried again.
+    pub delay: Option<Duration>,
+}
+
+/// Value returned by a call that succeeded after the failed [attempts][Retried::attempts].
+#[derive(Debug)]
+pub struct Retried<T> {
+    pub value: T,
+    pub attempts: Vec<Attempt>,
+}
+
+/// Error of the last attempt of a call that was given up.
+#[derive(Debug, thiserror::Error)]
+#[error("Request failed after {} attempts", .attempts.len())]
+pub struct RetryError {
+    /// All failed attempts, including the last one
+    pub attempts: Vec<Attempt>,
+    #[source]
+    pub error: Error,
+}
+
+/// Call `call` with `state` until it succeeds or the `policy` gives up.
+///
+/// `method` is only used to decide whether the call is idempotent. If `state` is a connection
+/// that was closed, retrying is pointless, so `state` may reconnect when `call` is invoked.
+pub async fn retry<S, T, F>(
+    policy: &RetryPolicy,
+    method: &[&str],
+    state: &mut S,
+    mut call: F,
+) -> Result<Retried<T>, RetryError>
+where
+    F: for<'a> FnMut(&'a mut S) -> BoxFuture<'a, Result<T, Error>>,
+{
+    let idempotent = policy.is_idempotent(method);
+    let mut attempts = Vec::new();
+    loop {
+        let result = match policy.attempt_timeout {
+            Some(timeout) => {
+                match future::select(call(state), futures_timer::Delay::new(timeout)).await {
+                    future::Either::Left((result, _)) => result,
+                    future::Either::Right(_) => Err(AsyncRequestError::Timeout(timeout).into()),
+                }
+            }
+            None => call(state).await,
+        };
+        let error = match result {
+            Ok(value) => return Ok(Retried { value, attempts }),
+            Err(error) => error,
+        };
+        let class = ErrorClass::of(&error);
+        let failures = attempts.len() as u32 + 1;
+        let delay = if idempotent && class.is_retryable() && failures < policy.max_attempts {
+            Some(policy.backoff(failures))
+        } else {
+            None
+        };
+        tracing::debug!(?method, ?class, ?delay, "attempt failed: {:#}", error);
+        attempts.push(Attempt {
+            class,
+            error: format!("{:#}", error),
+            delay,
+        });
+        match delay {
+            Some(delay) => futures_timer::Delay::new(delay).await,
+            None => return Err(RetryError { attempts, error }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy::new(3).delay(Duration::from_millis(1), Duration::from_millis(2))
+    }
+
+    /// Fails with the last of `errors` and succeeds once there are none left.
+    fn fail_with(
+        errors: &mut Vec<Error>,
+    ) -> impl Future<Output = Result<usize, Error>> + Send + 'static {
+        let result = match errors.pop() {
+            Some(error) => Err(error),
+            None => Ok(0),
+        };
+        future::ready(result)
+    }
+
+    #[async_std::test]
+    async fn retry_transport_errors() {
+        let mut errors = vec![
+            AsyncRequestError::Closed.into(),
+            AsyncRequestError::Timeout(Duration::from_secs(1)).into(),
+        ];
+        let result = retry(&policy(), &["whoami"], &mut errors, |errors| {
+            fail_with(errors).boxed()
+        })
+        .await
+        .unwrap();
+        let classes = result.attempts.iter().map(|a| a.class).collect::<Vec<_>>();
+        assert_eq!(classes, [ErrorClass::Timeout, ErrorClass::Transport]);
+        assert_eq!(result.attempts[0].delay, Some(Duration::from_millis(1)));
+        assert_eq!(result.attempts[1].delay, Some(Duration::from_millis(2)));
+
+        // Gives up after `max_attempts`
+        let mut errors = (0..3).map(|_| AsyncRequestError::Closed.into()).collect();
+        let error = retry(&policy(), &["whoami"], &mut errors, |errors| {
+            fail_with(errors).boxed()
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.attempts.len(), 3);
+        assert_eq!(error.attempts[2].delay, None);
+    }
+
+    #[async_std::test]
+    async fn no_retry() {
+        // RPC errors
+        let mut errors = vec![Error::Rpc {
+            name: "Error".to_string(),
+            message: "not found".to_string(),
+        }];
+        let error = retry(&policy(), &["get"], &mut errors, |errors| {
+            fail_with(errors).boxed()
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.attempts.len(), 1);
+        assert_eq!(error.attempts[0].class, ErrorClass::Rpc);
+
+        // Methods that are not idempotent
+        let mut errors = vec![AsyncRequestError::Timeout(Duration::from_secs(1)).into()];
+        let error = retry(&policy(), &["publish"], &mut errors, |errors| {
+            fail_with(errors).boxed()
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.attempts.len(), 1);
+        assert!(matches!(
+            error.error,
+            Error::Base(AsyncRequestError::Timeout(_))
+        ));
+    }
+
+    #[async_std::test]
+    async fn attempt_timeout() {
+        let policy = policy().attempt_timeout(Duration::from_millis(10));
+        let mut calls = 0;
+        let result = retry(&policy, &["whoami"], &mut calls, |calls| {
+            *calls += 1;
+            if *calls == 1 {
+                future::pending().boxed()
+            } else {
+                future::ok(*calls).boxed()
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(result.value, 2);
+        assert_eq!(result.attempts[0].class, ErrorClass::Timeout);
+    }
+}
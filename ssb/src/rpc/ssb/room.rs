@@ -0,0 +1,312 @@
+//! Client for [rooms] through [RoomClient].
+//!
+//! A room relays connections between its members. Members may register aliases with the room so
+//! that others can find them at `https://<alias>.<room host>`.
+//!
+//! [rooms]: https://ssb-ngi-pointer.github.io/rooms2/
+use futures::prelude::*;
+use std::collections::BTreeSet;
+
+use super::{Client, Error};
+use crate::rpc::base::{StreamError, TypedSource};
+
+/// Typed methods of the `room` RPC module. Created by [Client::room].
+#[derive(Debug)]
+pub struct RoomClient<'a> {
+    client: &'a mut Client,
+}
+
+impl<'a> RoomClient<'a> {
+    pub(super) fn new(client: &'a mut Client) -> Self {
+        Self { client }
+    }
+
+    /// Name and features of the room and whether the client is a member.
+    pub async fn metadata(&mut self) -> Result<RoomMetadata, Error> {
+        self.client
+            .send_async_json(&["room", "metadata"], vec![])
+            .await
+    }
+
+    /// Live stream of the peers that are connected to the room.
+    ///
+    /// The first event is [AttendantsEvent::State] with all current attendants.
+    pub async fn attendants(&mut self) -> Result<BoxAttendantsStream, Error> {
+        let source = self
+            .client
+            .endpoint
+            .client()
+            .start_source(vec!["room".to_string(), "attendants".to_string()], vec![])
+            .await
+            .map_err(Error::StartStream)?;
+        Ok(TypedSource::<AttendantsEvent>::new(source).boxed())
+    }
+
+    /// Register `alias` for the client with the room.
+    ///
+    /// `signature` is created with [alias_registration_signature]. Returns the URL of the alias
+    /// if the room sends it. Older rooms only confirm the registration.
+    pub async fn register_alias(
+        &mut self,
+        alias: &str,
+        signature: &str,
+    ) -> Result<Option<String>, Error> {
+        let response = self
+            .client
+            .send_async_json::<serde_json::Value>(
+                &["room", "registerAlias"],
+                vec![alias.into(), signature.into()],
+            )
+            .await?;
+        Ok(match response {
+            serde_json::Value::String(url) => Some(url),
+            _ => None,
+        })
+    }
+
+    /// Remove the registration of `alias`.
+    pub async fn revoke_alias(&mut self, alias: &str) -> Result<(), Error> {
+        self.client
+            .send_async_json::<serde_json::Value>(&["room", "revokeAlias"], vec![alias.into()])
+            .await?;
+        Ok(())
+    }
+}
+
+/// Response to [RoomClient::metadata].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RoomMetadata {
+    pub name: String,
+    /// Whether the client is a member of the room
+    pub membership: bool,
+    /// Features the room supports, for example `tunnel` or `alias`.
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+/// Event of [RoomClient::attendants]. Attendants are identified by their feed ID.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum AttendantsEvent {
+    State { ids: Vec<String> },
+    Joined { id: String },
+    Left { id: String },
+}
+
+impl AttendantsEvent {
+    /// Update `attendants` with the event.
+    pub fn apply(&self, attendants: &mut BTreeSet<String>) {
+        match self {
+            AttendantsEvent::State { ids } => *attendants = ids.iter().cloned().collect(),
+            AttendantsEvent::Joined { id } => {
+                attendants.insert(id.clone());
+            }
+            AttendantsEvent::Left { id } => {
+                attendants.remove(id);
+            }
+        }
+    }
+}
+
+/// Events returned by [RoomClient::attendants].
+pub type BoxAttendantsStream =
+    futures::stream::BoxStream<'static, Result<AttendantsEvent, StreamError>>;
+
+/// URL where `alias` is resolved by the room at `room_host`.
+///
+/// ```rust
+/// # use ssb::rpc::ssb::alias_url;
+/// assert_eq!(alias_url("alice", "room.example.com"), "https://alice.room.example.com");
+/// ```
+pub fn alias_url(alias: &str, room_host: &str) -> String {
+    format!("https://{}.{}", alias, room_host)
+}
+
+/// Information about an alias that the room returns for [alias_url] with the query
+/// `?encoding=json`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AliasResolution {
+    /// Address of the room
+    pub multiserver_address: String,
+    pub room_id: String,
+    pub user_id: String,
+    pub alias: String,
+    /// Signature of the user for the registration
+    pub signature: String,
+}
+
+impl AliasResolution {
+    /// Parse the JSON response of the room. Fails if the room reports an error.
+    pub fn from_json(json: &[u8]) -> Result<Self, AliasResolutionError> {
+        #[derive(serde::Deserialize)]
+        struct Response {
+            status: String,
+            error: Option<String>,
+            #[serde(flatten)]
+            resolution: serde_json::Value,
+        }
+        let response = serde_json::from_slice::<Response>(json)?;
+        if response.status != "successful" {
+            return Err(AliasResolutionError::Room {
+                message: response.error.unwrap_or(response.status),
+            });
+        }
+        Ok(serde_json::from_value(response.resolution)?)
+    }
+
+    /// Check that the user signed the registration of the alias with this room.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn verify(&self) -> bool {
+        let (room_id, user_id) = match (self.room_id.parse(), self.user_id.parse()) {
+            (Ok(room_id), Ok(user_id)) => (room_id, user_id),
+            _ => return false,
+        };
+        let signature = self
+            .signature
+            .strip_suffix(".sig.ed25519")
+            .and_then(|signature| base64::decode(signature).ok())
+            .and_then(|signature| crate::crypto::sign::Signature::from_slice(&signature));
+        match signature {
+            Some(signature) => crate::crypto::sign::verify_detached(
+                &signature,
+                alias_registration_data(&room_id, &user_id, &self.alias).as_bytes(),
+                crate::feed::FeedId::public_key(&user_id),
+            ),
+            None => false,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AliasResolutionError {
+    #[error("Failed to decode alias resolution")]
+    Decode(#[from] serde_json::Error),
+    #[error("Room failed to resolve alias: {message}")]
+    Room { message: String },
+}
+
+/// Data that a user signs to register `alias` with `room`.
+#[cfg(not(target_arch = "wasm32"))]
+fn alias_registration_data(
+    room: &crate::feed::FeedId,
+    user: &crate::feed::FeedId,
+    alias: &str,
+) -> String {
+    format!("=room-alias-registration:{}:{}:{}", room, user, alias)
+}
+
+/// Signature for [RoomClient::register_alias].
+#[cfg(not(target_arch = "wasm32"))]
+pub fn alias_registration_signature(
+    room: &crate::feed::FeedId,
+    user: &crate::crypto::sign::KeyPair,
+    alias: &str,
+) -> String {
+    let data = alias_registration_data(room, &crate::feed::FeedId(user.public), alias);
+    let signature = crate::crypto::sign::sign_detached(data.as_bytes(), &user.secret);
+    format!("{}.sig.ed25519", base64::encode(signature))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::sign::KeyPair;
+    use crate::feed::FeedId;
+
+    #[test]
+    fn attendants() {
+        let mut attendants = BTreeSet::new();
+        let events = serde_json::json!([
+            { "type": "state", "ids": ["@a", "@b"] },
+            { "type": "joined", "id": "@c" },
+            { "type": "left", "id": "@a" },
+        ]);
+        for event in serde_json::from_value::<Vec<AttendantsEvent>>(events).unwrap() {
+            event.apply(&mut attendants);
+        }
+        assert_eq!(
+            attendants.into_iter().collect::<Vec<_>>(),
+            vec!["@b".to_string(), "@c".to_string()]
+        );
+    }
+
+    #[test]
+    fn alias_resolution() {
+        let room = FeedId(KeyPair::gen().public);
+        let user = KeyPair::gen();
+        let signature = alias_registration_signature(&room, &user, "alice");
+        let json = serde_json::json!({
+            "status": "successful",
+            "multiserverAddress": "net:room.example.com:8008~shs:abc",
+            "roomId": room,
+            "userId": FeedId(user.public),
+            "alias": "alice",
+            "signature": signature,
+        });
+        let mut resolution = AliasResolution::from_json(json.to_string().as_bytes()).unwrap();
+        assert!(resolution.verify());
+        resolution.alias = "bob".to_string();
+        assert!(!resolution.verify());
+
+        let error =
+            AliasResolution::from_json(br#"{ "status": "failed", "error": "alias not found" }"#);
+        assert!(matches!(
+            error,
+            Err(AliasResolutionError::Room { message }) if message == "alias not found"
+        ));
+    }
+
+    #[async_std::test]
+    async fn room_client() {
+        let mut service = crate::rpc::base::Service::new();
+        service.add_async("metadata", |_: Vec<()>| {
+            let metadata = serde_json::json!({
+                "name": "room",
+                "membership": true,
+                "features": ["tunnel", "alias"],
+            });
+            future::ready(crate::rpc::base::service::AsyncResponse::json_ok(&metadata))
+        });
+        service.add_async("registerAlias", |(alias, _signature): (String, String)| {
+            let url = format!("https://{}.room.example.com", alias);
+            future::ready(crate::rpc::base::service::AsyncResponse::json_ok(&url))
+        });
+        service.add_source("attendants", |_: Vec<()>| {
+            let events = vec![
+                serde_json::json!({ "type": "state", "ids": ["@a"] }),
+                serde_json::json!({ "type": "joined", "id": "@b" }),
+            ];
+            futures::stream::iter(events).map(|event| Ok(crate::rpc::base::Body::json(&event)))
+        });
+        let mut root = crate::rpc::base::Service::new();
+        root.add_service("room", service);
+        let (mut client, _server) = super::super::test::connect(root);
+
+        let metadata = client.room().metadata().await.unwrap();
+        assert!(metadata.membership);
+        assert_eq!(metadata.features, vec!["tunnel", "alias"]);
+        let url = client.room().register_alias("alice", "sig").await.unwrap();
+        assert_eq!(url.as_deref(), Some("https://alice.room.example.com"));
+
+        let events = client
+            .room()
+            .attendants()
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            events,
+            vec![
+                AttendantsEvent::State {
+                    ids: vec!["@a".to_string()]
+                },
+                AttendantsEvent::Joined {
+                    id: "@b".to_string()
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! Types for [Client::get_thread][super::Client::get_thread].
+use std::collections::HashMap;
+
+/// Message of a thread with the fields of its value that are needed to display it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadMessage {
+    pub key: String,
+    pub author: String,
+    pub sequence: u64,
+    /// Time in milliseconds since the Unix epoch claimed by the author.
+    pub timestamp: f64,
+    pub content: serde_json::Value,
+}
+
+#[derive(serde::Deserialize)]
+struct RawMessageValue {
+    author: String,
+    sequence: u64,
+    timestamp: f64,
+    content: serde_json::Value,
+}
+
+impl ThreadMessage {
+    /// Create the message from its ID and the message value as returned by `get`.
+    pub fn from_value(key: String, value: serde_json::Value) -> Result<Self, serde_json::Error> {
+        let RawMessageValue {
+            author,
+            sequence,
+            timestamp,
+            content,
+        } = serde_json::from_value(value)?;
+        Ok(Self {
+            key,
+            author,
+            sequence,
+            timestamp,
+            content,
+        })
+    }
+
+    /// Returns the messages in the `branch` field of the content. The field is either a single
+    /// message ID or an array of them.
+    pub fn branches(&self) -> Vec<&str> {
+        match self.content.get("branch") {
+            Some(serde_json::Value::String(branch)) => vec![branch.as_str()],
+            Some(serde_json::Value::Array(branches)) => branches
+                .iter()
+                .filter_map(|branch| branch.as_str())
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+/// Message of a thread and the messages that reply to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadNode {
+    pub message: ThreadMessage,
+    /// Replies ordered by their timestamp.
+    pub replies: Vec<ThreadNode>,
+}
+
+/// Arrange `replies` to `root` as a tree.
+///
+/// A reply is nested under the last message of its `branch` field that belongs to the thread and
+/// was published before the reply. Other replies are direct replies to `root`. Duplicates and
+/// the root itself are ignored.
+pub fn build_thread(root: ThreadMessage, mut replies: Vec<ThreadMessage>) -> ThreadNode {
+    replies.retain(|reply| reply.key != root.key);
+    replies.sort_by(|a, b| {
+        a.timestamp
+            .partial_cmp(&b.timestamp)
+            .unwrap_or(std::cmp::Ordering::Equal)
+            .then_with(|| a.key.cmp(&b.key))
+    });
+    replies.dedup_by(|a, b| a.key == b.key);
+
+    // Index 0 is the root. A reply only refers to messages with a smaller index so the tree has
+    // no cycles.
+    let mut indices = HashMap::new();
+    indices.insert(root.key.clone(), 0);
+    let mut children = vec![Vec::new(); replies.len() + 1];
+    for (index, reply) in replies.iter().enumerate() {
+        let index = index + 1;
+        let parent = reply
+            .branches()
+            .into_iter()
+            .rev()
+            .find_map(|branch| indices.get(branch).copied())
+            .unwrap_or(0);
+        children[parent].push(index);
+        indices.insert(reply.key.clone(), index);
+    }
+
+    let mut messages = std::iter::once(root)
+        .chain(replies)
+        .map(Some)
+        .collect::<Vec<_>>();
+    build_node(0, &mut messages, &children)
+}
+
+fn build_node(
+    index: usize,
+    messages: &mut [Option<ThreadMessage>],
+    children: &[Vec<usize>],
+) -> ThreadNode {
+    let message = messages[index].take().unwrap();
+    let replies = children[index]
+        .iter()
+        .map(|child| build_node(*child, messages, children))
+        .collect();
+    ThreadNode { message, replies }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(key: &str, timestamp: f64, branch: serde_json::Value) -> ThreadMessage {
+        ThreadMessage {
+            key: key.to_string(),
+            author: "@author".to_string(),
+            sequence: 1,
+            timestamp,
+            content: serde_json::json!({ "type": "post", "root": "root", "branch": branch }),
+        }
+    }
+
+    fn keys(node: &ThreadNode) -> serde_json::Value {
+        let replies = node.replies.iter().map(keys).collect::<Vec<_>>();
+        serde_json::json!([node.message.key, replies])
+    }
+
+    #[test]
+    fn nested_replies() {
+        let root = message("root", 0.0, serde_json::Value::Null);
+        let replies = vec![
+            message("c", 3.0, serde_json::json!(["root", "a"])),
+            message("a", 1.0, serde_json::json!("root")),
+            message("b", 2.0, serde_json::json!("root")),
+            message("a", 1.0, serde_json::json!("root")),
+            message("d", 4.0, serde_json::json!("unknown")),
+        ];
+        let thread = build_thread(root, replies);
+        assert_eq!(
+            keys(&thread),
+            serde_json::json!(["root", [["a", [["c", []]]], ["b", []], ["d", []]]])
+        );
+    }
+
+    #[test]
+    fn branch_cycle() {
+        let root = message("root", 0.0, serde_json::Value::Null);
+        let replies = vec![
+            message("a", 1.0, serde_json::json!("b")),
+            message("b", 2.0, serde_json::json!("a")),
+        ];
+        let thread = build_thread(root, replies);
+        assert_eq!(
+            keys(&thread),
+            serde_json::json!(["root", [["a", [["b", []]]]]])
+        );
+    }
+
+    #[test]
+    fn from_value() {
+        let message = ThreadMessage::from_value(
+            "%a.sha256".to_string(),
+            serde_json::json!({
+                "previous": null,
+                "author": "@author",
+                "sequence": 3,
+                "timestamp": 1000,
+                "hash": "sha256",
+                "content": { "type": "post", "text": "hi" },
+                "signature": "sig",
+            }),
+        )
+        .unwrap();
+        assert_eq!(message.sequence, 3);
+        assert_eq!(message.timestamp, 1000.0);
+        assert_eq!(message.content["text"], "hi");
+    }
+}
@@ -0,0 +1,146 @@
+//! Types for [Client::create_user_stream][super::Client::create_user_stream].
+use futures::prelude::*;
+
+/// Options for `createUserStream`. Sequence bounds are unset by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct UserStreamOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gt: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gte: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lt: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lte: Option<u64>,
+    /// Keep the stream open and send new messages as they arrive.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub live: bool,
+    /// Send messages that already exist. The server defaults to `true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old: Option<bool>,
+    /// Send messages in descending order of their sequence numbers.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub reverse: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+}
+
+/// Message of a feed as sent by `createUserStream` with `keys: true` and returned by `publish`.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(try_from = "RawUserStreamMessage")]
+pub struct UserStreamMessage {
+    pub key: String,
+    /// Sequence number of the message taken from `value`.
+    pub sequence: u64,
+    pub value: serde_json::Value,
+    /// Time in milliseconds since the Unix epoch when the server received the message.
+    pub timestamp: f64,
+}
+
+#[derive(serde::Deserialize)]
+struct RawUserStreamMessage {
+    key: String,
+    value: serde_json::Value,
+    #[serde(default)]
+    timestamp: f64,
+}
+
+impl std::convert::TryFrom<RawUserStreamMessage> for UserStreamMessage {
+    type Error = &'static str;
+
+    fn try_from(raw: RawUserStreamMessage) -> Result<Self, Self::Error> {
+        let sequence = raw
+            .value
+            .get("sequence")
+            .and_then(serde_json::Value::as_u64)
+            .ok_or("message value has no sequence number")?;
+        Ok(Self {
+            key: raw.key,
+            sequence,
+            value: raw.value,
+            timestamp: raw.timestamp,
+        })
+    }
+}
+
+/// Item of a `createUserStream` source. Servers send a sync marker after the old messages when
+/// both `old` and `live` are set.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+pub(super) enum UserStreamItem {
+    Sync {
+        #[serde(rename = "sync")]
+        _sync: bool,
+    },
+    Message(UserStreamMessage),
+}
+
+/// Returns the messages of `history` followed by the messages of `live` that `history` did not
+/// contain.
+///
+/// `live` must be started before `history` so that no message is missed. Messages of `live` that
+/// have a sequence number not greater than the last message of `history` are dropped. Errors are
+/// passed through.
+pub fn merge_live<E>(
+    history: impl Stream<Item = Result<UserStreamMessage, E>>,
+    live: impl Stream<Item = Result<UserStreamMessage, E>>,
+) -> impl Stream<Item = Result<UserStreamMessage, E>> {
+    let mut last_sequence = None;
+    history.chain(live).filter(move |item| {
+        let keep = match item {
+            Ok(message) => match last_sequence {
+                Some(last) if message.sequence <= last => false,
+                _ => {
+                    last_sequence = Some(message.sequence);
+                    true
+                }
+            },
+            Err(_) => true,
+        };
+        future::ready(keep)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(sequence: u64) -> Result<UserStreamMessage, ()> {
+        Ok(UserStreamMessage {
+            key: format!("%{}.sha256", sequence),
+            sequence,
+            value: serde_json::json!({ "sequence": sequence }),
+            timestamp: 0.0,
+        })
+    }
+
+    #[async_std::test]
+    async fn merge_overlap() {
+        let history = futures::stream::iter(vec![message(1), message(2), message(3)]);
+        let live = futures::stream::iter(vec![message(2), message(3), message(4), Err(())]);
+        let sequences = merge_live(history, live)
+            .map(|item| item.map(|message| message.sequence))
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(sequences, vec![Ok(1), Ok(2), Ok(3), Ok(4), Err(())]);
+    }
+
+    #[test]
+    fn decode_item() {
+        let item = serde_json::from_value::<UserStreamItem>(serde_json::json!({ "sync": true }));
+        assert!(matches!(item, Ok(UserStreamItem::Sync { .. })));
+
+        let item = serde_json::from_value::<UserStreamItem>(serde_json::json!({
+            "key": "%a.sha256",
+            "value": { "sequence": 4 },
+            "timestamp": 10.0,
+        }));
+        assert!(matches!(item, Ok(UserStreamItem::Message(message)) if message.sequence == 4));
+
+        let item = serde_json::from_value::<UserStreamItem>(serde_json::json!({
+            "key": "%a.sha256",
+            "value": {},
+        }));
+        assert!(item.is_err());
+    }
+}
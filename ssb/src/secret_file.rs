@@ -3,6 +3,7 @@ use std::{
     fs, io,
     path::{Path, PathBuf},
 };
+use zeroize::Zeroizing;
 
 use crate::crypto;
 
@@ -47,10 +48,10 @@ pub enum LoadError {
 
 /// Load secret key from an SSB "secret" file
 pub fn load(path: &Path) -> Result<crypto::sign::SecretKey, LoadError> {
-    let data = fs::read_to_string(path).map_err(|error| LoadError::ReadIo {
+    let data = Zeroizing::new(fs::read_to_string(path).map_err(|error| LoadError::ReadIo {
         path: path.to_owned(),
         error,
-    })?;
+    })?);
     parse(&data)
 }
 
@@ -67,14 +68,15 @@ fn parse(data: &str) -> Result<crypto::sign::SecretKey, LoadError> {
         private: String,
     }
 
-    let data = strip_comments(data);
+    let data = Zeroizing::new(strip_comments(data));
     let Secret { private } = serde_json::from_str(&data)?;
+    let private = Zeroizing::new(private);
     let key_data = match private.split('.').collect::<Vec<&str>>().as_slice() {
         [key_data, scheme] if *scheme == "ed25519" => *key_data,
         _ => return Err(LoadError::UnknownKeyScheme),
     };
 
-    let key_data = base64::decode(key_data)?;
+    let key_data = Zeroizing::new(base64::decode(key_data)?);
     if key_data.len() != crypto::sign::SECRETKEYBYTES {
         return Err(LoadError::InvalidSecretKeyLength(key_data.len()));
     }
@@ -32,6 +32,10 @@ struct Options {
     /// Path of Unix socket to connect to the server
     #[structopt(long, default_value(Options::socket_default()))]
     socket: std::path::PathBuf,
+
+    /// Path of the ssb-server config file. Defaults to `~/.ssb/config`
+    #[structopt(long)]
+    config: Option<std::path::PathBuf>,
 }
 
 impl Options {
@@ -42,12 +46,16 @@ impl Options {
                 "Failed to connect to {}",
                 self.socket.to_string_lossy()
             ))?;
-        let (read, write) = stream.split();
-        let receive = crate::utils::read_to_stream(read);
-        let send = write.into_sink::<Vec<u8>>();
+        Ok(crate::rpc::ssb::Client::from_io(stream))
+    }
 
-        let client = crate::rpc::ssb::Client::new(send, receive);
-        Ok(client)
+    /// Load the config file. Settings may be overridden with `ssb_` environment variables.
+    fn config(&self) -> anyhow::Result<crate::config::Config> {
+        match &self.config {
+            Some(path) => crate::config::load(path),
+            None => crate::config::load_default(),
+        }
+        .context("Failed to load config")
     }
 
     // We have to return `&str` instead of `String`. Otherwise we can’t use it the default value
@@ -63,9 +71,18 @@ impl Options {
 enum Command {
     Call(Call),
     Manifest(Manifest),
+    HasMethod(HasMethod),
     Help(Help),
     PublishPost(PublishPost),
     Invite(Invite),
+    Status(Status),
+    Connect(Connect),
+    Disconnect(Disconnect),
+    Server(Server),
+    Pub(Pub),
+    Feed(Feed),
+    Blob(Blob),
+    VerifyLog(VerifyLog),
 }
 
 impl Command {
@@ -73,9 +90,18 @@ impl Command {
         match self {
             Self::Call(x) => x.run(options).await,
             Self::Manifest(x) => x.run(options).await,
+            Self::HasMethod(x) => x.run(options).await,
             Self::Help(x) => x.run(options).await,
             Self::PublishPost(x) => x.run(options).await,
             Self::Invite(x) => x.run(options).await,
+            Self::Status(x) => x.run(options).await,
+            Self::Connect(x) => x.run(options).await,
+            Self::Disconnect(x) => x.run(options).await,
+            Self::Server(x) => x.run(options).await,
+            Self::Pub(x) => x.run(options).await,
+            Self::Feed(x) => x.run(options).await,
+            Self::Blob(x) => x.run(options).await,
+            Self::VerifyLog(x) => x.run(),
         }
     }
 }
@@ -117,12 +143,24 @@ impl Call {
 
 #[derive(StructOpt)]
 /// Prints RPC methods the server supports
-struct Manifest {}
+struct Manifest {
+    /// Print the manifest as returned by the server as JSON
+    #[structopt(long)]
+    json: bool,
+}
 
 impl Manifest {
     async fn run(&self, options: Options) -> anyhow::Result<()> {
         let mut client = options.client().await?;
 
+        if self.json {
+            let manifest = client
+                .send_async_typed::<serde_json::Value>(&["manifest"], vec![])
+                .await?;
+            println!("{}", serde_json::to_string_pretty(&manifest).unwrap());
+            return Ok(());
+        }
+
         let manifest = client.manifest().await?;
 
         let mut table = new_table();
@@ -159,6 +197,31 @@ impl Manifest {
     }
 }
 
+/// Check whether the server provides an RPC method
+///
+/// Prints the type of the method and exits with a non-zero status if the server does not list the
+/// method in its manifest.
+#[derive(StructOpt)]
+struct HasMethod {
+    /// Method path delimited with a dot (.)
+    method: String,
+}
+
+impl HasMethod {
+    async fn run(&self, options: Options) -> anyhow::Result<()> {
+        let mut client = options.client().await?;
+        let manifest = client.manifest().await?;
+        let path = self.method.split('.').collect::<Vec<_>>();
+        match manifest.method_type(&path) {
+            Some(type_) => {
+                println!("{}", type_);
+                Ok(())
+            }
+            None => anyhow::bail!("Server does not provide method `{}`", self.method),
+        }
+    }
+}
+
 /// Print help for an RPC method
 #[derive(StructOpt)]
 struct Help {
@@ -204,17 +267,21 @@ impl Help {
 struct PublishPost {
     /// Text content of the post
     text: String,
+    /// Encrypt the post for this feed. May be given multiple times.
+    #[structopt(long = "recipient")]
+    recipients: Vec<crate::feed::FeedId>,
 }
 
 impl PublishPost {
     async fn run(&self, options: Options) -> anyhow::Result<()> {
         let mut client = options.client().await?;
-        let message = client
-            .publish(crate::rpc::ssb::MessageContent {
-                type_: "post".to_string(),
-                text: self.text.clone(),
-            })
-            .await?;
+        let content = crate::rpc::ssb::Content::Post(crate::rpc::ssb::Post::new(self.text.clone()));
+        let message = if self.recipients.is_empty() {
+            client.publish(&content).await?
+        } else {
+            client.publish_private(&content, &self.recipients).await?
+        };
+        let message = serde_json::json!({ "key": message.key, "value": message.value });
         println!("{}", serde_json::to_string_pretty(&message).unwrap());
         Ok(())
     }
@@ -253,6 +320,648 @@ impl InviteCreate {
     }
 }
 
+/// Show the connections and the replication progress of a pub
+///
+/// Requires a local connection to a server that provides the `admin` methods, like `ssbc pub`.
+/// With `--watch` the status is refreshed until the command is interrupted and only feeds that
+/// lag behind the peers are listed.
+#[derive(StructOpt)]
+struct Status {
+    /// Refresh the status periodically
+    #[structopt(long)]
+    watch: bool,
+
+    /// Seconds between refreshes with `--watch`
+    #[structopt(long, default_value = "2")]
+    interval: u64,
+}
+
+impl Status {
+    async fn run(&self, options: Options) -> anyhow::Result<()> {
+        let mut client = options.client().await?;
+        let result = if self.watch {
+            until_interrupted(self.watch(&mut client)).await
+        } else {
+            self.render(&mut client)
+                .await
+                .map(|status| print!("{}", status))
+        };
+        client.close().await?;
+        result
+    }
+
+    async fn watch(&self, client: &mut crate::rpc::ssb::Client) -> anyhow::Result<()> {
+        let interval = std::time::Duration::from_secs(self.interval.max(1));
+        loop {
+            let status = self.render(client).await?;
+            // Clear the terminal and move the cursor to the top left corner.
+            print!("\x1b[2J\x1b[H{}", status);
+            std::io::Write::flush(&mut std::io::stdout())?;
+            async_std::task::sleep(interval).await;
+        }
+    }
+
+    async fn render(&self, client: &mut crate::rpc::ssb::Client) -> anyhow::Result<String> {
+        let connections = client
+            .admin()
+            .connections()
+            .await
+            .context("Failed to get connections")?;
+        let progress = client
+            .admin()
+            .replication()
+            .await
+            .context("Failed to get replication progress")?;
+
+        let mut table = new_table();
+        table.set_titles(prettytable::row![
+            b => "PEER", "STATE", "DIRECTION", "UP", "DOWN", "STREAMS"
+        ]);
+        for connection in &connections {
+            let replicating = connection.streams.iter().any(|stream| {
+                stream.method == ["ebt", "replicate"] || stream.method == ["createHistoryStream"]
+            });
+            let direction = match connection.direction {
+                crate::conn::Direction::Inbound => "inbound",
+                crate::conn::Direction::Outbound => "outbound",
+            };
+            table.add_row(prettytable::row![
+                connection.peer,
+                if replicating { "replicating" } else { "connected" },
+                direction,
+                r -> format_bytes(connection.sent),
+                r -> format_bytes(connection.received),
+                r -> connection.streams.len()
+            ]);
+        }
+
+        let lagging = progress.iter().filter(|feed| feed.lag() > 0).count();
+        let mut feeds = new_table();
+        feeds.set_titles(prettytable::row![b => "FEED", "HOPS", "LOCAL", "REMOTE", "LAG"]);
+        for feed in &progress {
+            if self.watch && feed.lag() == 0 {
+                continue;
+            }
+            feeds.add_row(prettytable::row![
+                feed.feed,
+                r -> feed.hops,
+                r -> feed.local,
+                r -> feed.remote.map_or_else(|| "-".to_string(), |remote| remote.to_string()),
+                r -> feed.lag()
+            ]);
+        }
+
+        Ok(format!(
+            "{} connections, {} feeds replicated, {} behind by {} messages\n\n{}\n{}",
+            connections.len(),
+            progress.len(),
+            lagging,
+            progress.iter().map(|feed| feed.lag()).sum::<u64>(),
+            table,
+            feeds
+        ))
+    }
+}
+
+/// Format a number of bytes with a binary unit, for example `1.5 KiB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Ask a pub to connect to a peer and replicate with it
+#[derive(StructOpt)]
+struct Connect {
+    /// Multiaddress of the peer, for example `net:192.0.2.1:8008~shs:<key>`
+    address: crate::multi_address::MultiAddress,
+}
+
+impl Connect {
+    async fn run(&self, options: Options) -> anyhow::Result<()> {
+        let mut client = options.client().await?;
+        let peer = client
+            .admin()
+            .connect(&self.address)
+            .await
+            .context(format!("Failed to connect to {}", self.address))?;
+        client.close().await?;
+        println!("{}", peer);
+        Ok(())
+    }
+}
+
+/// Ask a pub to close all connections to a peer
+#[derive(StructOpt)]
+struct Disconnect {
+    /// Feed ID of the peer
+    peer: crate::feed::FeedId,
+}
+
+impl Disconnect {
+    async fn run(&self, options: Options) -> anyhow::Result<()> {
+        let mut client = options.client().await?;
+        let count = client.admin().disconnect(&self.peer).await?;
+        client.close().await?;
+        if count == 0 {
+            anyhow::bail!("Not connected to {}", self.peer);
+        }
+        Ok(())
+    }
+}
+
+/// Print the messages of a feed
+///
+/// Requests the messages with `createHistoryStream` and validates them. Invalid messages are
+/// reported on stderr and make the command fail.
+#[derive(StructOpt)]
+struct Feed {
+    /// ID of the feed, for example `@FCX/tsDLpubCPKKfIrw4gc+SQkHcaD17s7GI6i/ziWY=.ed25519`
+    id: crate::feed::FeedId,
+
+    /// Sequence number of the first message to print
+    #[structopt(long, default_value = "1")]
+    seq: u64,
+
+    /// Maximum number of messages to print
+    #[structopt(long)]
+    limit: Option<usize>,
+
+    /// Keep printing new messages as they are published
+    #[structopt(long)]
+    live: bool,
+}
+
+impl Feed {
+    async fn run(&self, options: Options) -> anyhow::Result<()> {
+        let mut client = options.client().await?;
+        let result = until_interrupted(self.print(&mut client)).await;
+        client.close().await?;
+        result
+    }
+
+    async fn print(&self, client: &mut crate::rpc::ssb::Client) -> anyhow::Result<()> {
+        let history = client
+            .base()
+            .start_source(
+                vec!["createHistoryStream".to_string()],
+                vec![serde_json::json!({
+                    "id": self.id,
+                    "seq": self.seq,
+                    "limit": self.limit,
+                    "keys": false,
+                    "live": self.live,
+                })],
+            )
+            .await?;
+
+        let mut history = crate::rpc::base::TypedSource::<serde_json::Value>::new(history);
+        let mut previous = None;
+        let mut invalid = 0;
+        while let Some(value) = history.next().await {
+            let value = value.context("Failed to receive message")?;
+            println!("{}", serde_json::to_string_pretty(&value).unwrap());
+            // Without the previous message we can only check the message itself and its
+            // signature.
+            let result = match &previous {
+                Some(previous) => crate::feed::validate::validate(Some(previous), &value),
+                None if self.seq <= 1 && invalid == 0 => {
+                    crate::feed::validate::validate(None, &value)
+                }
+                None => crate::feed::validate::verify(&value),
+            };
+            match result {
+                Ok(message) => previous = Some(message),
+                Err(error) => {
+                    invalid += 1;
+                    let sequence = value.get("sequence").unwrap_or(&serde_json::Value::Null);
+                    eprintln!("!!! INVALID MESSAGE {}: {}", sequence, error);
+                    // Later messages cannot be chained to an invalid message.
+                    previous = None;
+                }
+            }
+        }
+        if invalid > 0 {
+            anyhow::bail!("Received {} invalid messages", invalid);
+        }
+        Ok(())
+    }
+}
+
+/// Download and upload blobs
+#[derive(StructOpt)]
+enum Blob {
+    Get(BlobGet),
+    Add(BlobAdd),
+}
+
+impl Blob {
+    async fn run(&self, options: Options) -> anyhow::Result<()> {
+        match self {
+            Blob::Get(x) => x.run(options).await,
+            Blob::Add(x) => x.run(options).await,
+        }
+    }
+}
+
+/// Download a blob and write it to stdout
+///
+/// Fails if the received data does not match the hash of the blob.
+#[derive(StructOpt)]
+struct BlobGet {
+    /// ID of the blob, for example `&LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=.sha256`
+    id: crate::blobs::BlobId,
+
+    /// Write the blob to this file instead of stdout
+    #[structopt(long)]
+    out: Option<std::path::PathBuf>,
+
+    /// Maximum size of the blob in bytes
+    #[structopt(long)]
+    max: Option<u64>,
+}
+
+impl BlobGet {
+    async fn run(&self, options: Options) -> anyhow::Result<()> {
+        let mut client = options.client().await?;
+        let result = until_interrupted(self.get(&mut client)).await;
+        client.close().await?;
+        result
+    }
+
+    async fn get(&self, client: &mut crate::rpc::ssb::Client) -> anyhow::Result<()> {
+        let data = crate::blobs::client::get(client.base(), &self.id, self.max)
+            .await
+            .context(format!("Failed to get blob {}", self.id))?;
+        match &self.out {
+            Some(path) => async_std::fs::write(path, data)
+                .await
+                .context(format!("Failed to write {}", path.to_string_lossy()))?,
+            None => {
+                let mut stdout = async_std::io::stdout();
+                stdout.write_all(&data).await?;
+                stdout.flush().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Upload a file as a blob and print its ID
+#[derive(StructOpt)]
+struct BlobAdd {
+    /// Path of the file to upload
+    file: std::path::PathBuf,
+}
+
+impl BlobAdd {
+    async fn run(&self, options: Options) -> anyhow::Result<()> {
+        let file = async_std::fs::File::open(&self.file)
+            .await
+            .context(format!("Failed to open {}", self.file.to_string_lossy()))?;
+        let chunks = futures::stream::try_unfold(file, |mut file| async move {
+            let mut chunk = vec![0u8; 64 * 1024];
+            let n = file.read(&mut chunk).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            chunk.truncate(n);
+            Ok::<_, std::io::Error>(Some((chunk, file)))
+        });
+        let mut client = options.client().await?;
+        let id = crate::blobs::client::add(client.base(), chunks).await?;
+        println!("{}", id);
+        Ok(())
+    }
+}
+
+/// Check the messages of all feeds in a local database
+///
+/// Validates the sequence numbers, hash chains and signatures of every feed and prints the number
+/// of valid messages and the first invalid message per feed. Exits with a non-zero status if a
+/// feed is invalid unless the invalid messages were removed with `--truncate`.
+#[derive(StructOpt)]
+struct VerifyLog {
+    /// ssb-db2 directory. Defaults to `~/.ssb/db2`
+    #[structopt(long)]
+    db2: Option<std::path::PathBuf>,
+
+    /// Remove the messages of each feed starting with the first invalid message
+    #[structopt(long)]
+    truncate: bool,
+}
+
+impl VerifyLog {
+    fn run(&self) -> anyhow::Result<()> {
+        let dir = match &self.db2 {
+            Some(dir) => dir.clone(),
+            None => dirs::home_dir()
+                .context("Failed to find home directory")?
+                .join(".ssb")
+                .join("db2"),
+        };
+        let store =
+            crate::store::db2::Db2FeedStore::open(dir).context("Failed to open ssb-db2 log")?;
+        let reports = if self.truncate {
+            crate::store::repair(&store)
+        } else {
+            crate::store::verify(&store)
+        }
+        .context("Failed to read feeds")?;
+
+        let mut table = new_table();
+        table.set_titles(prettytable::row![b => "FEED", "VALID", "STATUS"]);
+        let mut invalid = 0;
+        for report in &reports {
+            let status = match &report.invalid {
+                None => "ok".to_string(),
+                Some(message) => {
+                    if !report.truncated {
+                        invalid += 1;
+                    }
+                    format!(
+                        "{} at {}: {}",
+                        if report.truncated {
+                            "truncated"
+                        } else {
+                            "invalid"
+                        },
+                        message.sequence,
+                        message.error
+                    )
+                }
+            };
+            table.add_row(prettytable::row![report.feed, r -> report.valid, status]);
+        }
+        table.printstd();
+
+        if invalid > 0 {
+            anyhow::bail!("{} of {} feeds are invalid", invalid, reports.len());
+        }
+        Ok(())
+    }
+}
+
+/// Run a minimal server for testing clients
+///
+/// Accepts secret handshake connections on a TCP socket and serves `manifest`, `help` and `echo`
+/// and the methods of the enabled plugins.
+#[derive(StructOpt)]
+struct Server {
+    /// Address to listen on. Defaults to the address from the config
+    #[structopt(long)]
+    addr: Option<String>,
+
+    /// Path of the secret file with the server identity. Defaults to `~/.ssb/secret`
+    #[structopt(long)]
+    secret: Option<std::path::PathBuf>,
+
+    /// Serve the `blobs` plugin with blobs stored in this directory
+    #[structopt(long)]
+    blobs: Option<std::path::PathBuf>,
+
+    /// Run a room with this name that relays tunnels between the connected peers
+    #[structopt(long)]
+    room: Option<String>,
+
+    /// Only admit these feeds to the room. The room is open if no member is given
+    #[structopt(long = "room-member", requires = "room")]
+    room_members: Vec<crate::feed::FeedId>,
+}
+
+impl Server {
+    async fn run(&self, options: Options) -> anyhow::Result<()> {
+        let config = options.config()?;
+        let network_key = config.network_key()?;
+        let addr = self.addr.clone().unwrap_or_else(|| config.listen_addr());
+        let secret_key = match &self.secret {
+            Some(path) => crate::secret_file::load(path),
+            None => crate::secret_file::load_default(),
+        }
+        .context("Failed to load server identity")?;
+        let identity = ssb_box_stream::SecretKey(secret_key.0);
+
+        let mut plugins = crate::plugin::Plugins::new();
+        if let Some(blobs) = &self.blobs {
+            plugins.add(crate::blobs::Blobs::new(crate::blobs::BlobStore::new(
+                blobs,
+            )))?;
+        }
+        let room = self.room.as_ref().map(|name| {
+            if self.room_members.is_empty() {
+                crate::room::Room::new(name)
+            } else {
+                crate::room::Room::with_members(name, self.room_members.iter().copied())
+            }
+        });
+        if let Some(room) = &room {
+            plugins.add(room.clone())?;
+        }
+
+        tracing::info!(%addr, identity = %identity.public_key(), "starting server");
+        let connections = crate::conn::PeerConnections::new(
+            crate::crypto::sign::PublicKey(identity.public_key().0),
+            crate::conn::DuplicatePolicy::ReplaceOld,
+        );
+        let events = crate::conn::ConnEvents::new();
+        let log_events = log_conn_events(events.subscribe());
+        let handler_plugins = plugins.clone();
+        let listen = crate::conn::listen_tcp(
+            addr.as_str(),
+            &network_key,
+            &identity,
+            events,
+            move |sender, receiver, peer| {
+                let context = crate::plugin::Context { peer: Some(peer.0) };
+                let service = server_service(&handler_plugins, &context);
+                let room = room.clone();
+                let connection = connections.open(peer.0, crate::conn::Direction::Inbound);
+                async move {
+                    let mut endpoint = crate::rpc::base::Endpoint::new(sender, receiver, service);
+                    let mut connection = match connection {
+                        Ok(connection) => connection,
+                        Err(error) => {
+                            let _ = endpoint.close().await;
+                            return Err(error.into());
+                        }
+                    };
+                    let close = endpoint.close_handle();
+                    let _attendance = room.and_then(|room| {
+                        room.attend(peer, endpoint.client().sender())
+                            .map_err(|error| tracing::info!(%error, "peer does not attend room"))
+                            .ok()
+                    });
+                    let join = endpoint.join();
+                    let replaced = connection.replaced();
+                    futures::pin_mut!(join, replaced);
+                    match future::select(replaced, join).await {
+                        future::Either::Left(((), join)) => {
+                            tracing::info!(%peer, "closing replaced connection");
+                            close.close();
+                            join.await
+                        }
+                        future::Either::Right((joined, _)) => joined,
+                    }
+                }
+            },
+        )
+        .map(|result| result.context("Failed to accept connections"));
+        futures::try_join!(listen, plugins.run(), log_events)?;
+        Ok(())
+    }
+}
+
+/// Log every connection event. Never returns.
+async fn log_conn_events(
+    mut events: futures::channel::mpsc::UnboundedReceiver<crate::conn::ConnEvent>,
+) -> anyhow::Result<()> {
+    use crate::conn::ConnEvent;
+    while let Some(event) = events.next().await {
+        match event {
+            ConnEvent::Connecting { addr } => tracing::debug!(%addr, "incoming connection"),
+            ConnEvent::HandshakeFailed { addr, reason } => {
+                tracing::info!(%addr, %reason, "handshake failed")
+            }
+            ConnEvent::Connected { peer, addr } => tracing::info!(%peer, %addr, "client connected"),
+            ConnEvent::Disconnected { peer, cause, bytes } => tracing::info!(
+                %peer,
+                ?cause,
+                sent = bytes.sent,
+                received = bytes.received,
+                "client disconnected"
+            ),
+        }
+    }
+    future::pending().await
+}
+
+/// Run a pub that replicates the feeds of the peers that use its invites
+///
+/// Accepts secret handshake connections on a TCP socket and local connections on the socket given
+/// with `--socket`, for example to create invites with `ssbc invite create`.
+#[derive(StructOpt)]
+struct Pub {
+    /// Address to listen on. Defaults to the address from the config
+    #[structopt(long)]
+    addr: Option<String>,
+
+    /// Host and port under which peers reach the pub. Used in invite codes. Defaults to the host
+    /// and port from the config
+    #[structopt(long)]
+    host: Option<String>,
+
+    /// Path of the secret file with the pub identity. Defaults to `~/.ssb/secret`
+    #[structopt(long)]
+    secret: Option<std::path::PathBuf>,
+
+    /// Directory for the feeds of the pub. Defaults to `~/.ssb/pub`
+    #[structopt(long)]
+    data_dir: Option<std::path::PathBuf>,
+
+    /// Replicate feeds up to this many hops from the pub. Defaults to `friends.hops` from the
+    /// config
+    #[structopt(long)]
+    hops: Option<u32>,
+}
+
+impl Pub {
+    async fn run(&self, options: Options) -> anyhow::Result<()> {
+        let secret_key = match &self.secret {
+            Some(path) => crate::secret_file::load(path),
+            None => crate::secret_file::load_default(),
+        }
+        .context("Failed to load pub identity")?;
+        let data_dir = match &self.data_dir {
+            Some(data_dir) => data_dir.clone(),
+            None => dirs::home_dir()
+                .context("Failed to find home directory")?
+                .join(".ssb")
+                .join("pub"),
+        };
+        let keypair = crate::crypto::sign::KeyPair::from(&ssb_box_stream::SecretKey(secret_key.0));
+        let mut config =
+            crate::pub_server::Config::from_ssb_config(keypair, data_dir, &options.config()?)?;
+        if let Some(addr) = &self.addr {
+            config.addr = addr.clone();
+        }
+        if let Some(host) = &self.host {
+            config.host = host.clone();
+        }
+        if let Some(hops) = self.hops {
+            config.hops = hops;
+        }
+        config.socket = Some(options.socket);
+        crate::pub_server::run(config).await
+    }
+}
+
+/// Service for a connection to `ssbc server`.
+fn server_service(
+    plugins: &crate::plugin::Plugins,
+    context: &crate::plugin::Context,
+) -> crate::rpc::base::Service {
+    let mut service = plugins.service(context);
+    service.add_async("echo", |(value,): (serde_json::Value,)| async move {
+        crate::rpc::base::service::AsyncResponse::json_ok(&value)
+    });
+    service.describe(
+        "echo",
+        "Respond with the argument",
+        &[("value", "Any JSON value")],
+    );
+    service
+}
+
+/// Run `command` until it finishes or the user presses ctrl-c.
+///
+/// Interrupting the command is not an error. The caller is expected to close the connection
+/// afterwards so that the server can clean up the streams of the command.
+async fn until_interrupted(
+    command: impl Future<Output = anyhow::Result<()>>,
+) -> anyhow::Result<()> {
+    let interrupted = ctrl_c();
+    futures::pin_mut!(command, interrupted);
+    match future::select(command, interrupted).await {
+        future::Either::Left((result, _)) => result,
+        future::Either::Right((result, _)) => {
+            result?;
+            tracing::info!("interrupted");
+            Ok(())
+        }
+    }
+}
+
+/// Resolves when the process receives `SIGINT`.
+///
+/// While the future is alive `SIGINT` does not terminate the process.
+async fn ctrl_c() -> anyhow::Result<()> {
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGINT])
+        .context("Failed to register signal handler")?;
+    let _close_on_drop = CloseSignals(signals.handle());
+    async_std::task::spawn_blocking(move || {
+        signals.forever().next();
+    })
+    .await;
+    Ok(())
+}
+
+/// Stops the iterator of the signals and unregisters the handler when dropped.
+struct CloseSignals(signal_hook::iterator::Handle);
+
+impl Drop for CloseSignals {
+    fn drop(&mut self) {
+        self.0.close()
+    }
+}
+
 fn new_table() -> prettytable::Table {
     let mut table = prettytable::Table::new();
     let format = prettytable::format::FormatBuilder::new()
@@ -0,0 +1,779 @@
+//! [FeedStore] backed by the log of an [ssb-db2] database as used by Manyverse.
+//!
+//! ssb-db2 keeps all messages in `db2/log.bipf`, an [async-append-only-log]. The log consists of
+//! blocks of 64 KiB. Each block holds records that start with the length of their data as a
+//! 16-bit little-endian integer followed by the data. A length of zero marks the end of the
+//! records in a block. Deleted records keep their length but their data is zeroed. The data of a
+//! record is the message encoded with [BIPF][crate::bipf].
+//!
+//! [Db2FeedStore] builds its own index of the log in memory when it is opened. It finds the
+//! records with the `seq.index` of [jitdb] in `db2/jit`, see [SeqIndex], and only scans the
+//! blocks for records that were appended after the index was saved. The log is scanned
+//! completely if the index is missing or damaged. The other jitdb indexes and the LevelDB indexes
+//! in `db2/indexes` are not read. Messages appended through the store are picked up by ssb-db2,
+//! which brings its indexes up to date with the log when it starts.
+//!
+//! The store takes an exclusive [flock(2)] lock on the log while it is open. ssb-db2 does not
+//! take this lock, so the store must not be opened while ssb-db2 runs. To detect this, appends
+//! and truncations are refused with [Db2Error::Modified] if another writer changed the end of the
+//! log since it was indexed. A failed append restores the previous end of the log.
+//!
+//! Only messages in the classic format are exposed. Other records are skipped.
+//!
+//! ```no_run
+//! # use ssb::feed::FeedStore;
+//! # use ssb::store::db2::Db2FeedStore;
+//! let home_dir = dirs::home_dir().unwrap();
+//! let store = Db2FeedStore::open(home_dir.join(".ssb").join("db2")).unwrap();
+//! for feed in store.feeds().unwrap() {
+//!     let latest = store.latest(&feed).unwrap().unwrap();
+//!     println!("{} {}", feed, latest.value.sequence);
+//! }
+//! ```
+//!
+//! [ssb-db2]: https://github.com/ssbc/ssb-db2
+//! [async-append-only-log]: https://github.com/ssbc/async-append-only-log
+//! [jitdb]: https://github.com/ssbc/jitdb
+//! [flock(2)]: https://man7.org/linux/man-pages/man2/flock.2.html
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::bipf;
+use crate::feed::{FeedId, FeedStore, Message, StoreError};
+
+/// Size of a block of the log in bytes.
+pub const BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Size of the length prefix of a record and of the end-of-block marker.
+const LENGTH_SIZE: u64 = 2;
+
+/// Largest record that fits into a block together with its length and the end-of-block marker.
+pub const MAX_RECORD_SIZE: usize = (BLOCK_SIZE - 2 * LENGTH_SIZE) as usize;
+
+/// Size of the version, offset, count and checksum fields that precede the data of a jitdb
+/// index file.
+const INDEX_HEADER_SIZE: usize = 16;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Db2Error {
+    #[error("Failed to access the log {path:?}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        error: std::io::Error,
+    },
+    #[error("Record at offset {offset} is truncated")]
+    Truncated { offset: u64 },
+    #[error("Failed to decode record at offset {offset}")]
+    Decode {
+        offset: u64,
+        #[source]
+        error: bipf::DecodeError,
+    },
+    /// The log was changed by another process after it was indexed.
+    #[error("Record at offset {offset} is not a message")]
+    NotAMessage { offset: u64 },
+    #[error("Message has {size} bytes but records may only have {MAX_RECORD_SIZE} bytes")]
+    RecordTooLarge { size: usize },
+    #[error("The log {path:?} is locked by another store")]
+    Locked { path: PathBuf },
+    /// Another writer, for example a running ssb-db2, changed the log after it was indexed.
+    #[error("The log {path:?} was modified by another writer")]
+    Modified { path: PathBuf },
+    #[error("Invalid jitdb index {path:?}: {reason}")]
+    InvalidIndex { path: PathBuf, reason: &'static str },
+}
+
+impl From<Db2Error> for StoreError {
+    fn from(error: Db2Error) -> Self {
+        StoreError::Backend {
+            error: Box::new(error),
+        }
+    }
+}
+
+/// [FeedStore] that reads and appends to `log.bipf` of an ssb-db2 database.
+#[derive(Debug)]
+pub struct Db2FeedStore {
+    path: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// The log. The store holds an exclusive lock on it.
+    file: File,
+    /// Offsets of the records of each feed by sequence number.
+    feeds: HashMap<FeedId, BTreeMap<u64, u64>>,
+    /// Offset where the next record is written.
+    end: u64,
+    /// Length of the log after it was indexed or written by the store.
+    len: u64,
+}
+
+/// The `seq.index` of [jitdb]: the log offset of every record in the order of the log.
+///
+/// jitdb saves indexes as a header of four 32-bit little-endian integers followed by the data.
+/// The header holds the version of the index, the offset of the latest indexed record, the
+/// number of entries and the CRC-32 checksum of the data, or zero if the checksum is not set.
+/// The data of `seq.index` is an array of 32-bit little-endian offsets that may be longer than
+/// the number of entries.
+///
+/// [jitdb]: https://github.com/ssbc/jitdb
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeqIndex {
+    pub version: u32,
+    /// Offset of the latest indexed record
+    pub offset: u32,
+    /// Offsets of the records
+    pub offsets: Vec<u32>,
+}
+
+impl SeqIndex {
+    /// Read the index saved at `path`, usually `db2/jit/seq.index`. Returns `None` if the file
+    /// does not exist.
+    pub fn read(path: impl AsRef<Path>) -> Result<Option<Self>, Db2Error> {
+        let path = path.as_ref();
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => {
+                return Err(Db2Error::Io {
+                    path: path.to_path_buf(),
+                    error,
+                })
+            }
+        };
+        Self::parse(&data)
+            .map(Some)
+            .map_err(|reason| Db2Error::InvalidIndex {
+                path: path.to_path_buf(),
+                reason,
+            })
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, &'static str> {
+        if data.len() < INDEX_HEADER_SIZE {
+            return Err("file is shorter than the header");
+        }
+        let field = |index: usize| {
+            let start = index * 4;
+            u32::from_le_bytes([
+                data[start],
+                data[start + 1],
+                data[start + 2],
+                data[start + 3],
+            ])
+        };
+        let (version, offset, count, checksum) = (field(0), field(1), field(2), field(3));
+        let body = &data[INDEX_HEADER_SIZE..];
+        if (body.len() as u64) < u64::from(count) * 4 {
+            return Err("file is shorter than the entries");
+        }
+        if checksum != 0 && crc32fast::hash(body) != checksum {
+            return Err("checksum mismatch");
+        }
+        let offsets = body
+            .chunks_exact(4)
+            .take(count as usize)
+            .map(|entry| u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]))
+            .collect();
+        Ok(Self {
+            version,
+            offset,
+            offsets,
+        })
+    }
+
+    /// Encode the index with a checksum like jitdb saves it.
+    pub fn build(&self) -> Vec<u8> {
+        let body = self
+            .offsets
+            .iter()
+            .flat_map(|offset| offset.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut data = Vec::with_capacity(INDEX_HEADER_SIZE + body.len());
+        data.extend(self.version.to_le_bytes());
+        data.extend(self.offset.to_le_bytes());
+        data.extend((self.offsets.len() as u32).to_le_bytes());
+        data.extend(crc32fast::hash(&body).to_le_bytes());
+        data.extend(body);
+        data
+    }
+}
+
+impl Db2FeedStore {
+    /// Open the log in the ssb-db2 directory `dir`, usually `~/.ssb/db2`. The directory and the
+    /// log are created if they do not exist.
+    ///
+    /// All records of the log are read to build the index of the store.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, Db2Error> {
+        let dir = dir.as_ref();
+        let path = dir.join("log.bipf");
+        let io_error = |error| Db2Error::Io {
+            path: path.clone(),
+            error,
+        };
+        std::fs::create_dir_all(dir).map_err(io_error)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(io_error)?;
+        match nix::fcntl::flock(
+            file.as_raw_fd(),
+            nix::fcntl::FlockArg::LockExclusiveNonblock,
+        ) {
+            Ok(()) => {}
+            Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => {
+                return Err(Db2Error::Locked { path })
+            }
+            Err(error) => return Err(io_error(std::io::Error::other(error))),
+        }
+        let len = file.metadata().map_err(io_error)?.len();
+        let mut inner = Inner {
+            file,
+            feeds: HashMap::new(),
+            end: 0,
+            len,
+        };
+
+        let index_path = dir.join("jit").join("seq.index");
+        let indexed = match SeqIndex::read(&index_path) {
+            Ok(Some(index)) => match inner.load_seq_index(&index) {
+                Ok(indexed) => indexed,
+                Err(error) => {
+                    tracing::warn!(?index_path, ?error, "ignoring jitdb index");
+                    inner.feeds.clear();
+                    None
+                }
+            },
+            Ok(None) => None,
+            Err(error) => {
+                tracing::warn!(?index_path, ?error, "ignoring jitdb index");
+                None
+            }
+        };
+        inner.scan(indexed).map_err(|error| match error {
+            ScanError::Io(error) => io_error(error),
+            ScanError::Db2(error) => error,
+        })?;
+
+        Ok(Self {
+            path,
+            inner: Mutex::new(inner),
+        })
+    }
+
+    /// Path of the log file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn io_error(&self, error: std::io::Error) -> Db2Error {
+        Db2Error::Io {
+            path: self.path.clone(),
+            error,
+        }
+    }
+
+    /// Fail with [Db2Error::Modified] if another writer changed the log.
+    fn check_unmodified(&self, inner: &mut Inner) -> Result<(), StoreError> {
+        if inner
+            .is_unmodified()
+            .map_err(|error| self.io_error(error))?
+        {
+            Ok(())
+        } else {
+            Err(Db2Error::Modified {
+                path: self.path.clone(),
+            }
+            .into())
+        }
+    }
+
+    fn read_message(&self, inner: &mut Inner, offset: u64) -> Result<Message, StoreError> {
+        let mut length = [0u8; LENGTH_SIZE as usize];
+        inner
+            .file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| inner.file.read_exact(&mut length))
+            .map_err(|error| self.io_error(error))?;
+        let mut data = vec![0u8; u16::from_le_bytes(length) as usize];
+        inner
+            .file
+            .read_exact(&mut data)
+            .map_err(|error| self.io_error(error))?;
+        let record = Record {
+            offset,
+            data: &data,
+        };
+        Ok(record.message()?.ok_or(Db2Error::NotAMessage { offset })?)
+    }
+}
+
+impl Inner {
+    fn index(&mut self, message: &Message, offset: u64) {
+        self.feeds
+            .entry(message.value.author)
+            .or_default()
+            .insert(message.value.sequence, offset);
+    }
+
+    /// Index the records listed by `index`. Returns the offset of the last listed record.
+    fn load_seq_index(&mut self, index: &SeqIndex) -> Result<Option<u64>, ScanError> {
+        let mut previous = None;
+        for offset in index.offsets.iter().map(|offset| u64::from(*offset)) {
+            if previous.is_some_and(|previous| offset <= previous) {
+                return Err(Db2Error::Truncated { offset }.into());
+            }
+            let data = self.read_record(offset)?;
+            let record = Record {
+                offset,
+                data: &data,
+            };
+            if let Some(message) = record.message()? {
+                self.index(&message, offset);
+            }
+            previous = Some(offset);
+        }
+        Ok(previous)
+    }
+
+    /// Read the data of the record at `offset`. Fails if the record does not fit into its block.
+    fn read_record(&mut self, offset: u64) -> Result<Vec<u8>, ScanError> {
+        let mut length = [0u8; LENGTH_SIZE as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut length)?;
+        let length = u64::from(u16::from_le_bytes(length));
+        let block_end = (offset / BLOCK_SIZE + 1) * BLOCK_SIZE;
+        if length == 0 || offset + LENGTH_SIZE + length > block_end.min(self.len) {
+            return Err(Db2Error::Truncated { offset }.into());
+        }
+        let mut data = vec![0u8; length as usize];
+        self.file.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    /// Index the records of the log that follow the record at `indexed`, or all records if
+    /// `indexed` is `None`, and find the end of the log.
+    fn scan(&mut self, indexed: Option<u64>) -> Result<(), ScanError> {
+        let mut block_offset = indexed.map_or(0, |offset| offset / BLOCK_SIZE * BLOCK_SIZE);
+        self.file.seek(SeekFrom::Start(block_offset))?;
+        let mut block = vec![0u8; BLOCK_SIZE as usize];
+        loop {
+            let size = read_block(&mut self.file, &mut block)?;
+            if size == 0 {
+                break;
+            }
+            let block = &block[..size];
+            let mut position = 0;
+            while let Some(record) = next_record(block, block_offset, &mut position)? {
+                if indexed.is_some_and(|indexed| record.offset <= indexed) {
+                    continue;
+                }
+                if let Some(message) = record.message()? {
+                    self.index(&message, record.offset);
+                }
+            }
+            self.end = block_offset + position as u64;
+            block_offset += BLOCK_SIZE;
+        }
+        Ok(())
+    }
+
+    /// Returns `false` if the log does not end where the store expects it.
+    fn is_unmodified(&mut self) -> std::io::Result<bool> {
+        if self.file.metadata()?.len() != self.len {
+            return Ok(false);
+        }
+        if self.end + LENGTH_SIZE > self.len {
+            return Ok(true);
+        }
+        // Records appended by others replace the end-of-block marker.
+        let mut marker = [0u8; LENGTH_SIZE as usize];
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.read_exact(&mut marker)?;
+        Ok(marker == [0, 0])
+    }
+}
+
+#[derive(Debug)]
+enum ScanError {
+    Io(std::io::Error),
+    Db2(Db2Error),
+}
+
+impl From<std::io::Error> for ScanError {
+    fn from(error: std::io::Error) -> Self {
+        ScanError::Io(error)
+    }
+}
+
+impl From<Db2Error> for ScanError {
+    fn from(error: Db2Error) -> Self {
+        ScanError::Db2(error)
+    }
+}
+
+/// Fill `block` from the current position of `file`. Returns the number of bytes read, which is
+/// less than the block size only at the end of the file.
+fn read_block(file: &mut File, block: &mut [u8]) -> std::io::Result<usize> {
+    let mut size = 0;
+    while size < block.len() {
+        match file.read(&mut block[size..])? {
+            0 => break,
+            read => size += read,
+        }
+    }
+    Ok(size)
+}
+
+struct Record<'a> {
+    offset: u64,
+    data: &'a [u8],
+}
+
+impl Record<'_> {
+    /// Decode the message of the record. Returns `None` for deleted records and records that are
+    /// not messages in the classic format.
+    fn message(&self) -> Result<Option<Message>, Db2Error> {
+        if self.data.iter().all(|byte| *byte == 0) {
+            return Ok(None);
+        }
+        let value = bipf::decode(self.data).map_err(|error| Db2Error::Decode {
+            offset: self.offset,
+            error,
+        })?;
+        Ok(bipf::from_value(value).ok())
+    }
+}
+
+/// Returns the record of `block` at `position` and advances `position` past it. Returns `None`
+/// at the end of the records of the block.
+fn next_record<'a>(
+    block: &'a [u8],
+    block_offset: u64,
+    position: &mut usize,
+) -> Result<Option<Record<'a>>, Db2Error> {
+    let offset = block_offset + *position as u64;
+    let length = match block.get(*position..*position + LENGTH_SIZE as usize) {
+        Some(&[0, 0]) | None => return Ok(None),
+        Some(length) => u16::from_le_bytes([length[0], length[1]]) as usize,
+    };
+    let start = *position + LENGTH_SIZE as usize;
+    let data = block
+        .get(start..start + length)
+        .ok_or(Db2Error::Truncated { offset })?;
+    *position = start + length;
+    Ok(Some(Record { offset, data }))
+}
+
+impl FeedStore for Db2FeedStore {
+    fn latest(&self, feed: &FeedId) -> Result<Option<Message>, StoreError> {
+        let mut inner = self.inner.lock().unwrap();
+        let offset = inner
+            .feeds
+            .get(feed)
+            .and_then(|offsets| offsets.values().next_back().copied());
+        offset
+            .map(|offset| self.read_message(&mut inner, offset))
+            .transpose()
+    }
+
+    fn get(&self, feed: &FeedId, sequence: u64) -> Result<Option<Message>, StoreError> {
+        let mut inner = self.inner.lock().unwrap();
+        let offset = inner
+            .feeds
+            .get(feed)
+            .and_then(|offsets| offsets.get(&sequence).copied());
+        offset
+            .map(|offset| self.read_message(&mut inner, offset))
+            .transpose()
+    }
+
+    fn history(
+        &self,
+        feed: &FeedId,
+        from: u64,
+        limit: Option<usize>,
+    ) -> Result<Vec<Message>, StoreError> {
+        let mut inner = self.inner.lock().unwrap();
+        let offsets = inner.feeds.get(feed).map_or_else(Vec::new, |offsets| {
+            offsets
+                .range(from..)
+                .map(|(_, offset)| *offset)
+                .take(limit.unwrap_or(usize::MAX))
+                .collect()
+        });
+        offsets
+            .into_iter()
+            .map(|offset| self.read_message(&mut inner, offset))
+            .collect()
+    }
+
+    fn append(&self, message: Message) -> Result<(), StoreError> {
+        let mut inner = self.inner.lock().unwrap();
+        let feed = message.value.author;
+        let expected = inner
+            .feeds
+            .get(&feed)
+            .and_then(|offsets| offsets.keys().next_back())
+            .map_or(1, |sequence| sequence + 1);
+        if message.value.sequence != expected {
+            return Err(StoreError::Sequence {
+                feed,
+                expected,
+                actual: message.value.sequence,
+            });
+        }
+
+        let data = bipf::to_vec(&message).expect("Message serializes to BIPF");
+        if data.len() > MAX_RECORD_SIZE {
+            return Err(Db2Error::RecordTooLarge { size: data.len() }.into());
+        }
+        self.check_unmodified(&mut inner)?;
+        // A record must fit into the block together with the end-of-block marker. Otherwise it
+        // starts the next block.
+        let mut offset = inner.end;
+        let block_end = (offset / BLOCK_SIZE + 1) * BLOCK_SIZE;
+        if offset + LENGTH_SIZE + data.len() as u64 + LENGTH_SIZE > block_end {
+            offset = block_end;
+        }
+        let mut record = (data.len() as u16).to_le_bytes().to_vec();
+        record.extend(data);
+        let next_block_end = (offset / BLOCK_SIZE + 1) * BLOCK_SIZE;
+        let previous_len = inner.len;
+        // Blocks are always complete. The zeros that follow the last record mark the end of the
+        // block.
+        let len = next_block_end.max(previous_len);
+        let file = &mut inner.file;
+        let result = file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| file.write_all(&record))
+            .and_then(|_| file.set_len(len))
+            .and_then(|_| file.sync_data());
+        if let Err(error) = result {
+            // The record may have been written partially into the zeros of the last block.
+            let written = previous_len.saturating_sub(offset).min(record.len() as u64);
+            let restored = file
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| file.write_all(&vec![0u8; written as usize]))
+                .and_then(|_| file.set_len(previous_len))
+                .and_then(|_| file.sync_data());
+            if let Err(error) = restored {
+                tracing::warn!(path = ?self.path, ?error, "failed to restore log");
+            }
+            return Err(self.io_error(error).into());
+        }
+
+        inner.len = len;
+        inner.end = offset + record.len() as u64;
+        inner.index(&message, offset);
+        Ok(())
+    }
+
+    fn feeds(&self) -> Result<Vec<FeedId>, StoreError> {
+        Ok(self.inner.lock().unwrap().feeds.keys().copied().collect())
+    }
+
+    /// Deletes the records like ssb-db2 does by overwriting their data with zeros. The latest
+    /// message is deleted first so that the feed stays complete if deleting fails.
+    fn truncate(&self, feed: &FeedId, from: u64) -> Result<(), StoreError> {
+        let mut inner = self.inner.lock().unwrap();
+        let offsets = match inner.feeds.get(feed) {
+            Some(offsets) => offsets
+                .range(from..)
+                .map(|(sequence, offset)| (*sequence, *offset))
+                .collect::<Vec<_>>(),
+            None => return Ok(()),
+        };
+        self.check_unmodified(&mut inner)?;
+        for (sequence, offset) in offsets.into_iter().rev() {
+            let file = &mut inner.file;
+            let mut length = [0u8; LENGTH_SIZE as usize];
+            file.seek(SeekFrom::Start(offset))
+                .and_then(|_| file.read_exact(&mut length))
+                .and_then(|_| file.write_all(&vec![0u8; u16::from_le_bytes(length) as usize]))
+                .map_err(|error| self.io_error(error))?;
+            let offsets = inner.feeds.get_mut(feed).expect("Feed is indexed");
+            offsets.remove(&sequence);
+            if offsets.is_empty() {
+                inner.feeds.remove(feed);
+            }
+        }
+        inner
+            .file
+            .sync_data()
+            .map_err(|error| self.io_error(error))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::sign::KeyPair;
+    use crate::test_utils::check_persistent_store;
+
+    fn post(keypair: &KeyPair, previous: Option<&Message>, text: String) -> Message {
+        crate::feed::validate::sign(
+            keypair,
+            previous,
+            1_638_000_000_000u64,
+            serde_json::json!({ "type": "post", "text": text }),
+        )
+    }
+
+    #[test]
+    fn append_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let keypair = KeyPair::gen();
+
+        // Large messages so that the log spans several blocks.
+        let mut messages = Vec::<Message>::new();
+        for _ in 0..20 {
+            let message = post(&keypair, messages.last(), "x".repeat(7000));
+            messages.push(message);
+        }
+        check_persistent_store(|| Db2FeedStore::open(dir.path()).unwrap(), &messages);
+
+        let store = Db2FeedStore::open(dir.path()).unwrap();
+        let length = std::fs::metadata(store.path()).unwrap().len();
+        assert_eq!(length % BLOCK_SIZE, 0);
+        assert!(length > BLOCK_SIZE);
+    }
+
+    #[test]
+    fn skip_deleted_and_foreign_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let keypair = KeyPair::gen();
+        let first = post(&keypair, None, "first".to_string());
+        let second = post(&keypair, Some(&first), "second".to_string());
+
+        let mut log = Vec::new();
+        let mut push_record = |data: Vec<u8>| {
+            log.extend((data.len() as u16).to_le_bytes());
+            log.extend(data);
+        };
+        let encode = |message: &Message| bipf::to_vec(message).unwrap();
+        push_record(encode(&first));
+        // Deleted record
+        push_record(vec![0; 40]);
+        // Message of another feed format
+        push_record(bipf::encode(&bipf::from_json(&serde_json::json!({
+            "key": "ssb:message/buttwoo-v1/abc",
+            "value": { "author": "ssb:feed/buttwoo-v1/abc" },
+        }))));
+        push_record(encode(&second));
+        log.resize(BLOCK_SIZE as usize, 0);
+        std::fs::write(dir.path().join("log.bipf"), &log).unwrap();
+
+        let store = Db2FeedStore::open(dir.path()).unwrap();
+        let feed = FeedId(keypair.public);
+        assert_eq!(store.history(&feed, 1, None).unwrap(), vec![first, second]);
+    }
+
+    #[test]
+    fn jitdb_seq_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let keypair = KeyPair::gen();
+        let feed = FeedId(keypair.public);
+        let first = post(&keypair, None, "first".to_string());
+        let second = post(&keypair, Some(&first), "second".to_string());
+        let third = post(&keypair, Some(&second), "third".to_string());
+
+        // The second record follows the end-of-block marker so that only the index finds it.
+        let mut log = Vec::new();
+        let push_record = |log: &mut Vec<u8>, message: &Message| {
+            let offset = log.len() as u32;
+            let data = bipf::to_vec(message).unwrap();
+            log.extend((data.len() as u16).to_le_bytes());
+            log.extend(data);
+            offset
+        };
+        let first_offset = push_record(&mut log, &first);
+        log.extend([0, 0]);
+        let second_offset = push_record(&mut log, &second);
+        log.resize(BLOCK_SIZE as usize, 0);
+        push_record(&mut log, &third);
+        log.resize(2 * BLOCK_SIZE as usize, 0);
+        std::fs::write(dir.path().join("log.bipf"), &log).unwrap();
+
+        let index = SeqIndex {
+            version: 1,
+            offset: second_offset,
+            offsets: vec![first_offset, second_offset],
+        };
+        let index_path = dir.path().join("jit").join("seq.index");
+        std::fs::create_dir(dir.path().join("jit")).unwrap();
+        std::fs::write(&index_path, index.build()).unwrap();
+        assert_eq!(SeqIndex::read(&index_path).unwrap(), Some(index));
+
+        let store = Db2FeedStore::open(dir.path()).unwrap();
+        assert_eq!(
+            store.history(&feed, 1, None).unwrap(),
+            vec![first.clone(), second, third.clone()]
+        );
+        drop(store);
+
+        // A damaged index is ignored and the log is scanned.
+        let mut data = std::fs::read(&index_path).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        std::fs::write(&index_path, data).unwrap();
+        assert!(matches!(
+            SeqIndex::read(&index_path),
+            Err(Db2Error::InvalidIndex { .. })
+        ));
+        let store = Db2FeedStore::open(dir.path()).unwrap();
+        assert_eq!(store.history(&feed, 1, None).unwrap(), vec![first, third]);
+    }
+
+    #[test]
+    fn exclusive_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Db2FeedStore::open(dir.path()).unwrap();
+        assert!(matches!(
+            Db2FeedStore::open(dir.path()),
+            Err(Db2Error::Locked { .. })
+        ));
+        drop(store);
+        Db2FeedStore::open(dir.path()).unwrap();
+    }
+
+    #[test]
+    fn refuse_writes_after_modification() {
+        let dir = tempfile::tempdir().unwrap();
+        let keypair = KeyPair::gen();
+        let feed = FeedId(keypair.public);
+        let first = post(&keypair, None, "first".to_string());
+        let second = post(&keypair, Some(&first), "second".to_string());
+        let store = Db2FeedStore::open(dir.path()).unwrap();
+        let end = LENGTH_SIZE + bipf::to_vec(&first).unwrap().len() as u64;
+        store.append(first).unwrap();
+
+        // Another writer appends a record to the block.
+        let data = bipf::to_vec(&second).unwrap();
+        let mut record = (data.len() as u16).to_le_bytes().to_vec();
+        record.extend(data);
+        let mut file = OpenOptions::new().write(true).open(store.path()).unwrap();
+        file.seek(SeekFrom::Start(end)).unwrap();
+        file.write_all(&record).unwrap();
+        drop(file);
+
+        let is_modified = |result: Result<(), StoreError>| match result {
+            Err(StoreError::Backend { error }) => {
+                matches!(error.downcast_ref(), Some(Db2Error::Modified { .. }))
+            }
+            _ => false,
+        };
+        assert!(is_modified(store.append(second)));
+        assert!(is_modified(store.truncate(&feed, 1)));
+    }
+}
@@ -0,0 +1,428 @@
+use futures::channel::mpsc;
+use futures::prelude::*;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::blobs::BlobId;
+use crate::feed::{FeedId, FeedStore, Message, MessageId, StoreError};
+
+/// [FeedStore] that maintains indexes of the messages by type, by author and by the IDs they
+/// link to. The indexes are updated when messages are appended and can be queried with
+/// [IndexedFeedStore::query].
+///
+/// ```rust
+/// # use ssb::feed::{FeedId, FeedStore, MemoryFeedStore};
+/// # use ssb::store::IndexedFeedStore;
+/// # use ssb::crypto::sign::KeyPair;
+/// let store = IndexedFeedStore::new(MemoryFeedStore::new()).unwrap();
+/// let keypair = KeyPair::gen();
+/// let message = ssb::feed::validate::sign(
+///     &keypair,
+///     None,
+///     0u64,
+///     serde_json::json!({ "type": "post", "text": "hello" }),
+/// );
+/// store.append(message.clone()).unwrap();
+///
+/// let posts = store
+///     .query()
+///     .message_type("post")
+///     .author(FeedId(keypair.public))
+///     .collect()
+///     .unwrap();
+/// assert_eq!(posts, vec![message]);
+/// ```
+#[derive(Debug)]
+pub struct IndexedFeedStore<S> {
+    store: S,
+    indexes: Mutex<Indexes>,
+}
+
+/// Messages are identified by their position in the order they were indexed. Every index lists
+/// positions in ascending order.
+#[derive(Debug, Default)]
+struct Indexes {
+    messages: Vec<(FeedId, u64)>,
+    by_type: HashMap<String, Vec<usize>>,
+    by_author: HashMap<FeedId, Vec<usize>>,
+    by_link: HashMap<String, Vec<usize>>,
+    subscribers: Vec<mpsc::UnboundedSender<Message>>,
+}
+
+impl Indexes {
+    /// Index all messages of `store`.
+    fn build(store: &impl FeedStore) -> Result<Self, StoreError> {
+        let mut indexes = Indexes::default();
+        for feed in store.feeds()? {
+            for message in store.history(&feed, 1, None)? {
+                indexes.add(&message);
+            }
+        }
+        Ok(indexes)
+    }
+
+    /// Index all messages of `store` again. Subscribers are kept.
+    fn rebuild(&mut self, store: &impl FeedStore) -> Result<(), StoreError> {
+        let subscribers = std::mem::take(&mut self.subscribers);
+        *self = Indexes::build(store)?;
+        self.subscribers = subscribers;
+        Ok(())
+    }
+
+    fn add(&mut self, message: &Message) {
+        let position = self.messages.len();
+        self.messages
+            .push((message.value.author, message.value.sequence));
+        if let Some(content_type) = message.value.content_type() {
+            self.by_type
+                .entry(content_type.to_string())
+                .or_default()
+                .push(position);
+        }
+        self.by_author
+            .entry(message.value.author)
+            .or_default()
+            .push(position);
+        for link in links(&message.value.content) {
+            let positions = self.by_link.entry(link).or_default();
+            // A message may mention an ID more than once.
+            if positions.last() != Some(&position) {
+                positions.push(position);
+            }
+        }
+        self.subscribers
+            .retain(|subscriber| subscriber.unbounded_send(message.clone()).is_ok());
+    }
+}
+
+/// IDs of feeds, messages and blobs in `content` in their canonical form.
+fn links(content: &serde_json::Value) -> Vec<String> {
+    let mut links = Vec::new();
+    let mut values = vec![content];
+    while let Some(value) = values.pop() {
+        match value {
+            serde_json::Value::String(string) => links.extend(parse_link(string)),
+            serde_json::Value::Array(items) => values.extend(items),
+            serde_json::Value::Object(fields) => values.extend(fields.values()),
+            _ => {}
+        }
+    }
+    links
+}
+
+fn parse_link(string: &str) -> Option<String> {
+    match string.chars().next()? {
+        '@' => string.parse::<FeedId>().ok().map(|id| id.to_string()),
+        '%' => string.parse::<MessageId>().ok().map(|id| id.to_string()),
+        '&' => string.parse::<BlobId>().ok().map(|id| id.to_string()),
+        _ => None,
+    }
+}
+
+impl<S: FeedStore> IndexedFeedStore<S> {
+    /// Wrap `store` and index the messages it already contains.
+    pub fn new(store: S) -> Result<Self, StoreError> {
+        let indexes = Indexes::build(&store)?;
+        Ok(Self {
+            store,
+            indexes: Mutex::new(indexes),
+        })
+    }
+
+    /// Returns the wrapped store. Messages must not be appended to it directly because they
+    /// would not be indexed.
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// Start a query that matches all messages. Use the methods of [Query] to restrict it.
+    pub fn query(&self) -> Query<'_, S> {
+        Query {
+            store: self,
+            message_type: None,
+            author: None,
+            link: None,
+            live: false,
+        }
+    }
+}
+
+impl<S: FeedStore> FeedStore for IndexedFeedStore<S> {
+    fn latest(&self, feed: &FeedId) -> Result<Option<Message>, StoreError> {
+        self.store.latest(feed)
+    }
+
+    fn get(&self, feed: &FeedId, sequence: u64) -> Result<Option<Message>, StoreError> {
+        self.store.get(feed, sequence)
+    }
+
+    fn history(
+        &self,
+        feed: &FeedId,
+        from: u64,
+        limit: Option<usize>,
+    ) -> Result<Vec<Message>, StoreError> {
+        self.store.history(feed, from, limit)
+    }
+
+    fn append(&self, message: Message) -> Result<(), StoreError> {
+        // Hold the lock so that messages are indexed in the order they are stored.
+        let mut indexes = self.indexes.lock().unwrap();
+        self.store.append(message.clone())?;
+        indexes.add(&message);
+        Ok(())
+    }
+
+    fn feeds(&self) -> Result<Vec<FeedId>, StoreError> {
+        self.store.feeds()
+    }
+
+    /// Truncates the wrapped store and rebuilds the indexes. Live queries are kept.
+    fn truncate(&self, feed: &FeedId, from: u64) -> Result<(), StoreError> {
+        let mut indexes = self.indexes.lock().unwrap();
+        self.store.truncate(feed, from)?;
+        indexes.rebuild(&self.store)
+    }
+
+    /// Deletes the content in the wrapped store and rebuilds the indexes so that the message is
+    /// not found by its content anymore.
+    fn delete_content(&self, id: &MessageId) -> Result<bool, StoreError> {
+        let mut indexes = self.indexes.lock().unwrap();
+        if !self.store.delete_content(id)? {
+            return Ok(false);
+        }
+        indexes.rebuild(&self.store)?;
+        Ok(true)
+    }
+}
+
+/// Query of an [IndexedFeedStore]. Messages must match all given conditions.
+///
+/// Results are ordered by the time messages were indexed. Messages that were stored before the
+/// [IndexedFeedStore] was created come first, grouped by feed.
+#[derive(Debug)]
+pub struct Query<'a, S> {
+    store: &'a IndexedFeedStore<S>,
+    message_type: Option<String>,
+    author: Option<FeedId>,
+    link: Option<String>,
+    live: bool,
+}
+
+impl<'a, S: FeedStore> Query<'a, S> {
+    /// Only match messages with the given content type.
+    pub fn message_type(mut self, message_type: impl Into<String>) -> Self {
+        self.message_type = Some(message_type.into());
+        self
+    }
+
+    /// Only match messages published by `author`.
+    pub fn author(mut self, author: FeedId) -> Self {
+        self.author = Some(author);
+        self
+    }
+
+    /// Only match messages whose content mentions the feed, message or blob ID `link`.
+    pub fn link(mut self, link: impl std::fmt::Display) -> Self {
+        let link = link.to_string();
+        self.link = Some(parse_link(&link).unwrap_or(link));
+        self
+    }
+
+    /// Keep the [Query::stream] open and send messages that match as they are appended.
+    pub fn live(mut self) -> Self {
+        self.live = true;
+        self
+    }
+
+    /// Returns the stored messages that match. [Query::live] is ignored.
+    pub fn collect(self) -> Result<Vec<Message>, StoreError> {
+        let positions = {
+            let indexes = self.store.indexes.lock().unwrap();
+            self.positions(&indexes)
+        };
+        self.fetch(positions)
+    }
+
+    /// Stream of the stored messages that match. If the query is [live][Query::live] the stream
+    /// continues with new messages that match.
+    pub fn stream(self) -> Result<impl Stream<Item = Message> + Send + 'static, StoreError> {
+        let (positions, live) = {
+            let mut indexes = self.store.indexes.lock().unwrap();
+            let positions = self.positions(&indexes);
+            let live = if self.live {
+                let (sender, receiver) = mpsc::unbounded();
+                indexes.subscribers.push(sender);
+                Some(receiver)
+            } else {
+                None
+            };
+            (positions, live)
+        };
+        let old = self.fetch(positions)?;
+        let filter = Filter {
+            message_type: self.message_type,
+            author: self.author,
+            link: self.link,
+        };
+        let live = stream::iter(live)
+            .flatten()
+            .filter(move |message| future::ready(filter.matches(message)));
+        Ok(stream::iter(old).chain(live))
+    }
+
+    /// Positions of the indexed messages that match. Candidates are taken from the smallest
+    /// index that applies and then checked against the other indexes.
+    fn positions(&self, indexes: &Indexes) -> Vec<usize> {
+        let empty = Vec::new();
+        let mut candidates = Vec::new();
+        if let Some(message_type) = &self.message_type {
+            candidates.push(indexes.by_type.get(message_type).unwrap_or(&empty));
+        }
+        if let Some(author) = &self.author {
+            candidates.push(indexes.by_author.get(author).unwrap_or(&empty));
+        }
+        if let Some(link) = &self.link {
+            candidates.push(indexes.by_link.get(link).unwrap_or(&empty));
+        }
+        candidates.sort_by_key(|positions| positions.len());
+        match candidates.split_first() {
+            Some((smallest, others)) => smallest
+                .iter()
+                .copied()
+                .filter(|position| {
+                    others
+                        .iter()
+                        .all(|positions| positions.binary_search(position).is_ok())
+                })
+                .collect(),
+            None => (0..indexes.messages.len()).collect(),
+        }
+    }
+
+    fn fetch(&self, positions: Vec<usize>) -> Result<Vec<Message>, StoreError> {
+        let keys = {
+            let indexes = self.store.indexes.lock().unwrap();
+            positions
+                .into_iter()
+                .map(|position| indexes.messages[position])
+                .collect::<Vec<_>>()
+        };
+        let mut messages = Vec::with_capacity(keys.len());
+        for (feed, sequence) in keys {
+            messages.extend(self.store.store.get(&feed, sequence)?);
+        }
+        Ok(messages)
+    }
+}
+
+/// Conditions of a [Query] checked against new messages.
+struct Filter {
+    message_type: Option<String>,
+    author: Option<FeedId>,
+    link: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, message: &Message) -> bool {
+        if let Some(message_type) = &self.message_type {
+            if message.value.content_type() != Some(message_type.as_str()) {
+                return false;
+            }
+        }
+        if let Some(author) = &self.author {
+            if &message.value.author != author {
+                return false;
+            }
+        }
+        if let Some(link) = &self.link {
+            if !links(&message.value.content).contains(link) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::sign::KeyPair;
+    use crate::feed::MemoryFeedStore;
+
+    fn publish(store: &impl FeedStore, keypair: &KeyPair, content: serde_json::Value) -> Message {
+        let previous = store.latest(&FeedId(keypair.public)).unwrap();
+        let message = crate::feed::validate::sign(keypair, previous.as_ref(), 0u64, content);
+        store.append(message.clone()).unwrap();
+        message
+    }
+
+    #[test]
+    fn query_indexes() {
+        let alice = KeyPair::gen();
+        let bob = KeyPair::gen();
+        let alice_id = FeedId(alice.public);
+        let inner = MemoryFeedStore::new();
+        let first = publish(&inner, &alice, serde_json::json!({ "type": "post" }));
+
+        let store = IndexedFeedStore::new(inner).unwrap();
+        let contact = publish(
+            &store,
+            &bob,
+            serde_json::json!({ "type": "contact", "contact": alice_id, "following": true }),
+        );
+        let reply = publish(
+            &store,
+            &bob,
+            serde_json::json!({ "type": "post", "root": first.key, "mentions": [first.key] }),
+        );
+
+        assert_eq!(store.query().collect().unwrap().len(), 3);
+        assert_eq!(
+            store.query().message_type("post").collect().unwrap(),
+            vec![first.clone(), reply.clone()]
+        );
+        assert_eq!(
+            store.query().author(alice_id).collect().unwrap(),
+            vec![first.clone()]
+        );
+        assert_eq!(
+            store.query().link(alice_id).collect().unwrap(),
+            vec![contact]
+        );
+        assert_eq!(
+            store
+                .query()
+                .message_type("post")
+                .link(first.key)
+                .author(FeedId(bob.public))
+                .collect()
+                .unwrap(),
+            vec![reply]
+        );
+        assert!(store
+            .query()
+            .message_type("vote")
+            .collect()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[async_std::test]
+    async fn live_query() {
+        let alice = KeyPair::gen();
+        let store = IndexedFeedStore::new(MemoryFeedStore::new()).unwrap();
+        let first = publish(&store, &alice, serde_json::json!({ "type": "post" }));
+
+        let mut posts = store.query().message_type("post").live().stream().unwrap();
+        let mut all = store.query().stream().unwrap();
+        publish(&store, &alice, serde_json::json!({ "type": "vote" }));
+        let second = publish(&store, &alice, serde_json::json!({ "type": "post" }));
+
+        assert_eq!(posts.next().await, Some(first.clone()));
+        assert_eq!(posts.next().await, Some(second));
+        assert_eq!(posts.next().now_or_never(), None);
+        assert_eq!(all.next().await, Some(first));
+        assert_eq!(all.next().await, None);
+    }
+}
@@ -0,0 +1,234 @@
+//! Storage backends for feeds and checks of their integrity.
+//!
+//! [db2::Db2FeedStore] is a [FeedStore] that reads and appends to the log of an ssb-db2 database.
+//! [IndexedFeedStore] wraps any store and indexes messages by type, author and links for
+//! [Query]s.
+//!
+//! [verify] walks every feed of a store and validates each message against the previous one:
+//! the sequence numbers must be continuous, every message must link to the key of its
+//! predecessor and carry a valid signature of the feed author, and the stored key must match the
+//! message. [repair] additionally truncates each feed at its first invalid message so that the
+//! feed can be replicated again from there.
+//!
+//! Messages whose content was deleted with [FeedStore::delete_content] cannot be validated
+//! anymore. For them only the sequence number and the link to the predecessor are checked.
+//!
+//! ```rust
+//! # use ssb::feed::MemoryFeedStore;
+//! let store = MemoryFeedStore::new();
+//! for report in ssb::store::verify(&store).unwrap() {
+//!     if let Some(invalid) = &report.invalid {
+//!         println!("{}: message {} is invalid: {}", report.feed, invalid.sequence, invalid.error);
+//!     }
+//! }
+//! ```
+pub mod db2;
+mod index;
+pub use index::{IndexedFeedStore, Query};
+
+use crate::feed::validate::ValidationError;
+use crate::feed::{FeedId, FeedStore, Message, MessageId, StoreError};
+
+/// Number of messages read from the store at once.
+const BATCH_SIZE: usize = 1000;
+
+/// Result of checking one feed.
+#[derive(Debug)]
+pub struct FeedReport {
+    pub feed: FeedId,
+    /// Number of valid messages at the start of the feed
+    pub valid: u64,
+    /// First invalid message. All following messages are ignored.
+    pub invalid: Option<InvalidMessage>,
+    /// `true` if the feed was truncated at the invalid message by [repair].
+    pub truncated: bool,
+}
+
+impl FeedReport {
+    pub fn is_valid(&self) -> bool {
+        self.invalid.is_none()
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidMessage {
+    pub sequence: u64,
+    pub error: MessageError,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum MessageError {
+    #[error(transparent)]
+    Validation(#[from] ValidationError),
+    #[error("Message was published by {author}")]
+    Author { author: FeedId },
+    #[error("Stored key {stored} does not match the message key {actual}")]
+    Key {
+        stored: MessageId,
+        actual: MessageId,
+    },
+}
+
+/// Check all feeds of `store`. Reports are ordered by feed ID.
+pub fn verify(store: &dyn FeedStore) -> Result<Vec<FeedReport>, StoreError> {
+    check(store, false)
+}
+
+/// Check all feeds of `store` like [verify] and truncate every feed at its first invalid
+/// message.
+pub fn repair(store: &dyn FeedStore) -> Result<Vec<FeedReport>, StoreError> {
+    check(store, true)
+}
+
+fn check(store: &dyn FeedStore, truncate: bool) -> Result<Vec<FeedReport>, StoreError> {
+    let mut feeds = store.feeds()?;
+    feeds.sort();
+    feeds
+        .into_iter()
+        .map(|feed| {
+            let mut report = verify_feed(store, feed)?;
+            if let (Some(invalid), true) = (&report.invalid, truncate) {
+                store.truncate(&feed, invalid.sequence)?;
+                report.truncated = true;
+            }
+            Ok(report)
+        })
+        .collect()
+}
+
+fn verify_feed(store: &dyn FeedStore, feed: FeedId) -> Result<FeedReport, StoreError> {
+    let mut report = FeedReport {
+        feed,
+        valid: 0,
+        invalid: None,
+        truncated: false,
+    };
+    let mut previous = None::<Message>;
+    loop {
+        let messages = store.history(&feed, report.valid + 1, Some(BATCH_SIZE))?;
+        if messages.is_empty() {
+            return Ok(report);
+        }
+        for message in messages {
+            if let Err(error) = verify_message(feed, previous.as_ref(), &message) {
+                report.invalid = Some(InvalidMessage {
+                    sequence: report.valid + 1,
+                    error,
+                });
+                return Ok(report);
+            }
+            report.valid += 1;
+            previous = Some(message);
+        }
+    }
+}
+
+fn verify_message(
+    feed: FeedId,
+    previous: Option<&Message>,
+    message: &Message,
+) -> Result<(), MessageError> {
+    if message.value.author != feed {
+        return Err(MessageError::Author {
+            author: message.value.author,
+        });
+    }
+    if message.value.is_deleted() {
+        return verify_tombstone(previous, message).map_err(MessageError::from);
+    }
+    let value = serde_json::to_value(&message.value).expect("Message value serializes to JSON");
+    let validated = crate::feed::validate::validate(previous, &value)?;
+    if validated.key != message.key {
+        return Err(MessageError::Key {
+            stored: message.key,
+            actual: validated.key,
+        });
+    }
+    Ok(())
+}
+
+/// Check that the deleted `message` follows `previous`. The signature cannot be checked without
+/// the content, so the stored key is trusted.
+fn verify_tombstone(previous: Option<&Message>, message: &Message) -> Result<(), ValidationError> {
+    let expected = previous.map_or(1, |previous| previous.value.sequence + 1);
+    if message.value.sequence != expected {
+        return Err(ValidationError::Sequence {
+            expected,
+            actual: message.value.sequence,
+        });
+    }
+    if message.value.previous != previous.map(|previous| previous.key) {
+        return Err(ValidationError::Previous);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::sign::KeyPair;
+    use crate::feed::MemoryFeedStore;
+    use crate::test_utils::posts;
+
+    #[test]
+    fn verify_and_repair() {
+        let store = MemoryFeedStore::new();
+        let valid = KeyPair::gen();
+        for message in posts(&valid, 3) {
+            store.append(message).unwrap();
+        }
+        let damaged = KeyPair::gen();
+        let mut messages = posts(&damaged, 4);
+        messages[2].value.content = serde_json::json!({ "type": "post", "text": "changed" });
+        for message in messages {
+            store.append(message).unwrap();
+        }
+
+        let reports = verify(&store).unwrap();
+        assert_eq!(reports.len(), 2);
+        let report = |feed: FeedId| reports.iter().find(|report| report.feed == feed).unwrap();
+        let valid_report = report(FeedId(valid.public));
+        assert!(valid_report.is_valid());
+        assert_eq!(valid_report.valid, 3);
+        let damaged_report = report(FeedId(damaged.public));
+        assert_eq!(damaged_report.valid, 2);
+        assert!(matches!(
+            damaged_report.invalid,
+            Some(InvalidMessage {
+                sequence: 3,
+                error: MessageError::Validation(ValidationError::Signature),
+            })
+        ));
+        assert!(!damaged_report.truncated);
+
+        let reports = repair(&store).unwrap();
+        assert!(reports.iter().any(|report| report.truncated));
+        assert_eq!(
+            store
+                .latest(&FeedId(damaged.public))
+                .unwrap()
+                .unwrap()
+                .value
+                .sequence,
+            2
+        );
+        assert!(verify(&store)
+            .unwrap()
+            .iter()
+            .all(|report| report.is_valid()));
+    }
+
+    #[test]
+    fn verify_deleted() {
+        let store = MemoryFeedStore::new();
+        let keypair = KeyPair::gen();
+        let messages = posts(&keypair, 3);
+        for message in &messages {
+            store.append(message.clone()).unwrap();
+        }
+        assert!(store.delete_content(&messages[1].key).unwrap());
+        let reports = verify(&store).unwrap();
+        assert!(reports[0].is_valid());
+        assert_eq!(reports[0].valid, 3);
+    }
+}
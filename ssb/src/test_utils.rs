@@ -13,3 +13,74 @@ macro_rules! prop_reject {
         return ::core::result::Result::Err(::proptest::test_runner::TestCaseError::reject($msg));
     };
 }
+
+/// Signed posts of `keypair` with the sequence numbers 1 to `count`.
+pub fn posts(keypair: &crate::crypto::sign::KeyPair, count: usize) -> Vec<crate::feed::Message> {
+    let mut messages = Vec::<crate::feed::Message>::new();
+    for index in 0..count {
+        let message = crate::feed::validate::sign(
+            keypair,
+            messages.last(),
+            1_638_000_000_000u64,
+            serde_json::json!({ "type": "post", "text": format!("post {}", index) }),
+        );
+        messages.push(message);
+    }
+    messages
+}
+
+/// Check that a persistent [FeedStore][crate::feed::FeedStore] keeps appended messages and
+/// truncations. `open` opens the store again after the previous store was dropped. `messages` are
+/// at least four consecutive messages of one feed starting with sequence number 1.
+pub fn check_persistent_store<S: crate::feed::FeedStore>(
+    open: impl Fn() -> S,
+    messages: &[crate::feed::Message],
+) {
+    use crate::feed::StoreError;
+
+    let feed = messages[0].value.author;
+    let count = messages.len();
+    let store = open();
+    for message in &messages[..count - 1] {
+        store.append(message.clone()).unwrap();
+    }
+    let expected_sequence = count as u64;
+    assert!(matches!(
+        store.append(messages[2].clone()),
+        Err(StoreError::Sequence { expected, .. }) if expected == expected_sequence
+    ));
+    drop(store);
+
+    let store = open();
+    assert_eq!(store.feeds().unwrap(), vec![feed]);
+    assert_eq!(
+        store.latest(&feed).unwrap().as_ref(),
+        Some(&messages[count - 2])
+    );
+    assert_eq!(store.get(&feed, 2).unwrap().as_ref(), Some(&messages[1]));
+    assert_eq!(store.get(&feed, count as u64).unwrap(), None);
+    assert_eq!(
+        store.history(&feed, 1, None).unwrap(),
+        messages[..count - 1]
+    );
+    assert_eq!(store.history(&feed, 2, Some(2)).unwrap(), messages[1..3]);
+    // The message still has a valid signature after it was stored.
+    let stored = store.latest(&feed).unwrap().unwrap();
+    crate::feed::validate::validate(
+        Some(&messages[count - 3]),
+        &serde_json::to_value(&stored.value).unwrap(),
+    )
+    .unwrap();
+    store.append(messages[count - 1].clone()).unwrap();
+    assert_eq!(store.latest(&feed).unwrap().as_ref(), messages.last());
+
+    store.truncate(&feed, 3).unwrap();
+    drop(store);
+
+    let store = open();
+    assert_eq!(store.history(&feed, 1, None).unwrap(), messages[..2]);
+    store.append(messages[2].clone()).unwrap();
+    store.truncate(&feed, 1).unwrap();
+    assert_eq!(store.feeds().unwrap(), vec![]);
+    assert_eq!(store.latest(&feed).unwrap(), None);
+}
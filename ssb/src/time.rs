@@ -0,0 +1,167 @@
+//! Timestamps for messages published to the local feed.
+//!
+//! Message timestamps of a feed must increase even if the wall clock jumps backwards, for example
+//! when it is corrected by NTP. [Timestamps] hands out such timestamps like
+//! [`monotonic-timestamp`][monotonic-timestamp] does for `ssb-db`.
+//!
+//! ```
+//! # use ssb::crypto::sign::KeyPair;
+//! # use ssb::feed::validate::sign;
+//! # use ssb::time::Timestamps;
+//! let keypair = KeyPair::gen();
+//! let timestamps = Timestamps::new();
+//! let first = sign(&keypair, None, timestamps.next(None), serde_json::json!({"type": "post"}));
+//! let second = sign(
+//!     &keypair,
+//!     Some(&first),
+//!     timestamps.next(Some(&first)),
+//!     serde_json::json!({"type": "post"}),
+//! );
+//! assert!(second.value.timestamp.as_f64() > first.value.timestamp.as_f64());
+//! ```
+//!
+//! [monotonic-timestamp]: https://github.com/dominictarr/monotonic-timestamp
+use std::sync::Mutex;
+
+use crate::feed::Message;
+
+/// Smallest step in milliseconds between two timestamps if the clock did not advance.
+const STEP: f64 = 0.001;
+
+/// Source of the current time. Tests provide their own implementation to simulate clock jumps.
+pub trait Clock: Send + Sync + std::fmt::Debug {
+    /// Current time in milliseconds since the Unix epoch.
+    fn now(&self) -> f64;
+}
+
+/// The system’s wall clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> f64 {
+        now()
+    }
+}
+
+/// Current system time in milliseconds since the Unix epoch.
+pub fn now() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0.0, |duration| duration.as_millis() as f64)
+}
+
+/// Issues strictly increasing timestamps for new messages.
+#[derive(Debug)]
+pub struct Timestamps {
+    clock: Box<dyn Clock>,
+    /// Last timestamp that was issued.
+    last: Mutex<f64>,
+}
+
+impl Timestamps {
+    /// Create timestamps from the [SystemClock].
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+
+    pub fn with_clock(clock: impl Clock + 'static) -> Self {
+        Self {
+            clock: Box::new(clock),
+            last: Mutex::new(f64::NEG_INFINITY),
+        }
+    }
+
+    /// Returns the timestamp for the message that follows `previous` in the feed.
+    ///
+    /// The timestamp is the current time unless it is not greater than the timestamp of
+    /// `previous` or the last timestamp issued. Then it is slightly greater than the larger of
+    /// the two.
+    pub fn next(&self, previous: Option<&Message>) -> serde_json::Number {
+        let mut last = self.last.lock().unwrap();
+        let previous = previous
+            .and_then(|previous| previous.value.timestamp.as_f64())
+            .unwrap_or(f64::NEG_INFINITY);
+        let lower_bound = last.max(previous);
+        let now = self.clock.now();
+        let timestamp = if now > lower_bound {
+            now
+        } else {
+            lower_bound + STEP
+        };
+        *last = timestamp;
+        to_number(timestamp)
+    }
+}
+
+impl Default for Timestamps {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Encode whole milliseconds as JSON integers like JavaScript does.
+fn to_number(timestamp: f64) -> serde_json::Number {
+    if timestamp.fract() == 0.0 && timestamp >= 0.0 && timestamp < u64::MAX as f64 {
+        serde_json::Number::from(timestamp as u64)
+    } else {
+        serde_json::Number::from_f64(timestamp).unwrap_or_else(|| 0.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    #[derive(Debug, Clone, Default)]
+    struct TestClock(Arc<Mutex<f64>>);
+
+    impl TestClock {
+        fn set(&self, now: f64) {
+            *self.0.lock().unwrap() = now;
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now(&self) -> f64 {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn message(timestamp: u64) -> Message {
+        let keypair = crate::crypto::sign::KeyPair::gen();
+        crate::feed::validate::sign(
+            &keypair,
+            None,
+            timestamp,
+            serde_json::json!({ "type": "post" }),
+        )
+    }
+
+    #[test]
+    fn clock_jumps_backwards() {
+        let clock = TestClock::default();
+        let timestamps = Timestamps::with_clock(clock.clone());
+
+        clock.set(1000.0);
+        assert_eq!(timestamps.next(None), 1000.into());
+        assert_eq!(timestamps.next(None).as_f64(), Some(1000.001));
+        clock.set(500.0);
+        assert_eq!(timestamps.next(None).as_f64(), Some(1000.002));
+        clock.set(2000.0);
+        assert_eq!(timestamps.next(None), 2000.into());
+    }
+
+    #[test]
+    fn after_previous_message() {
+        let clock = TestClock::default();
+        let timestamps = Timestamps::with_clock(clock.clone());
+        let previous = message(5000);
+
+        clock.set(1000.0);
+        assert_eq!(timestamps.next(Some(&previous)).as_f64(), Some(5000.001));
+        clock.set(6000.0);
+        assert_eq!(timestamps.next(Some(&previous)), 6000.into());
+    }
+}
@@ -8,6 +8,8 @@ mod oneshot;
 #[doc(inline)]
 pub use oneshot::{OneshotClosed, OneshotSink, OneshotStream};
 
+pub mod task;
+
 /// Convert [AsyncRead] into a [Stream]. Polling the resulting stream will poll
 /// the reader for 4096 bytes and return a [Vec] of all the bytes that were read.
 pub fn read_to_stream(
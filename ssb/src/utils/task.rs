@@ -0,0 +1,153 @@
+//! Spawn tasks on the native executor or, on `wasm32` targets, on the JavaScript event loop.
+use futures::prelude::*;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Handle to wait for the output of a task created with [spawn].
+///
+/// Dropping the handle detaches the task.
+#[derive(Debug)]
+pub struct JoinHandle<T> {
+    inner: Inner<T>,
+}
+
+#[derive(Debug)]
+enum Inner<T> {
+    #[cfg(not(target_arch = "wasm32"))]
+    AsyncStd(async_std::task::JoinHandle<T>),
+    /// Output of a task that was spawned on an executor without join handles.
+    #[cfg(any(target_arch = "wasm32", test, feature = "simulation"))]
+    Channel(futures::channel::oneshot::Receiver<T>),
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match &mut self.inner {
+            #[cfg(not(target_arch = "wasm32"))]
+            Inner::AsyncStd(handle) => Pin::new(handle).poll(cx),
+            #[cfg(any(target_arch = "wasm32", test, feature = "simulation"))]
+            Inner::Channel(receiver) => Pin::new(receiver)
+                .poll(cx)
+                .map(|result| result.expect("Task panicked")),
+        }
+    }
+}
+
+/// Run `future` in the background. `name` identifies the task in logs and panic messages
+/// where the executor supports it.
+///
+/// Uses `async_std` on native targets and `wasm_bindgen_futures::spawn_local` on `wasm32`
+/// targets.
+pub fn spawn<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(all(not(target_arch = "wasm32"), any(test, feature = "simulation")))]
+    {
+        use futures::task::LocalSpawnExt as _;
+        if let Some(spawner) = local::spawner() {
+            let (task, handle) = channel_task(future);
+            spawner
+                .spawn_local(task)
+                .expect("Failed to spawn task on local executor");
+            return handle;
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    let handle = JoinHandle {
+        inner: Inner::AsyncStd(
+            async_std::task::Builder::new()
+                .name(name.to_string())
+                .spawn(future)
+                .expect("Failed to spawn task"),
+        ),
+    };
+
+    #[cfg(target_arch = "wasm32")]
+    let handle = {
+        let _ = name;
+        let (task, handle) = channel_task(future);
+        wasm_bindgen_futures::spawn_local(task);
+        handle
+    };
+
+    handle
+}
+
+/// Returns a task that runs `future` and sends its output to the returned handle.
+#[cfg(any(target_arch = "wasm32", test, feature = "simulation"))]
+fn channel_task<F>(future: F) -> (impl Future<Output = ()>, JoinHandle<F::Output>)
+where
+    F: Future + 'static,
+{
+    let (sender, receiver) = futures::channel::oneshot::channel();
+    let task = async move {
+        let _ = sender.send(future.await);
+    };
+    let handle = JoinHandle {
+        inner: Inner::Channel(receiver),
+    };
+    (task, handle)
+}
+
+/// Override the executor that [spawn] uses on the current thread.
+#[cfg(all(not(target_arch = "wasm32"), any(test, feature = "simulation")))]
+pub(crate) mod local {
+    use futures::executor::LocalSpawner;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static SPAWNER: RefCell<Option<LocalSpawner>> = const { RefCell::new(None) };
+    }
+
+    pub(super) fn spawner() -> Option<LocalSpawner> {
+        SPAWNER.with(|spawner| spawner.borrow().clone())
+    }
+
+    /// Spawn all tasks created on the current thread with `spawner` until the guard is
+    /// dropped.
+    pub(crate) fn set_spawner(spawner: LocalSpawner) -> SpawnerGuard {
+        let previous = SPAWNER.with(|current| current.borrow_mut().replace(spawner));
+        SpawnerGuard { previous }
+    }
+
+    /// Restores the previous executor when dropped.
+    #[derive(Debug)]
+    pub(crate) struct SpawnerGuard {
+        previous: Option<LocalSpawner>,
+    }
+
+    impl Drop for SpawnerGuard {
+        fn drop(&mut self) {
+            let previous = self.previous.take();
+            SPAWNER.with(|current| *current.borrow_mut() = previous);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[async_std::test]
+    async fn join() {
+        let (sender, receiver) = futures::channel::oneshot::channel::<u32>();
+        let handle = spawn("test", async move { receiver.await.unwrap() + 1 });
+        sender.send(41).unwrap();
+        assert_eq!(handle.await, 42);
+    }
+
+    #[test]
+    fn local_spawner() {
+        let mut pool = futures::executor::LocalPool::new();
+        let handle = {
+            let _guard = local::set_spawner(pool.spawner());
+            spawn("test", async { 42 })
+        };
+        assert_eq!(pool.run_until(handle), 42);
+    }
+}
@@ -0,0 +1,174 @@
+//! Likes of messages computed from `vote` messages.
+//!
+//! A `vote` message with a positive value likes the message it links to. A later vote of the
+//! same author for the same message with a value of zero or less withdraws the like.
+//!
+//! ```rust
+//! # use ssb::votes::Votes;
+//! # use ssb::feed::{FeedId, MessageId};
+//! # use ssb::crypto::sign::KeyPair;
+//! # let alice = FeedId::from(KeyPair::gen().public);
+//! # let post = MessageId([0; 32]);
+//! let mut votes = Votes::new();
+//! votes.vote(alice, post, 1);
+//! assert_eq!(votes.summary(&post).count(), 1);
+//! assert!(votes.summary(&post).voters.contains(&alice));
+//! ```
+
+use futures::channel::mpsc;
+use futures::prelude::*;
+use std::collections::{BTreeSet, HashMap};
+
+use crate::feed::{FeedId, Message, MessageId};
+
+/// Feeds that like a message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VoteSummary {
+    pub voters: BTreeSet<FeedId>,
+}
+
+impl VoteSummary {
+    /// Number of likes.
+    pub fn count(&self) -> usize {
+        self.voters.len()
+    }
+}
+
+/// Votes of a message after a `vote` message changed them.
+pub type VoteUpdate = (MessageId, VoteSummary);
+
+#[derive(Debug, Default)]
+pub struct Votes {
+    messages: HashMap<MessageId, VoteSummary>,
+    subscribers: Vec<mpsc::UnboundedSender<VoteUpdate>>,
+}
+
+#[derive(serde::Deserialize)]
+struct VoteContent {
+    vote: Vote,
+}
+
+#[derive(serde::Deserialize)]
+struct Vote {
+    link: MessageId,
+    value: i64,
+}
+
+impl Votes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the votes with a message. Messages that are not `vote` messages for a message or
+    /// that are malformed are ignored.
+    ///
+    /// Messages must be applied in the order they were published by their author.
+    pub fn apply(&mut self, message: &Message) {
+        if message.value.content_type() != Some("vote") {
+            return;
+        }
+        let VoteContent { vote } =
+            match serde_json::from_value::<VoteContent>(message.value.content.clone()) {
+                Ok(content) => content,
+                Err(_) => return,
+            };
+        self.vote(message.value.author, vote.link, vote.value);
+    }
+
+    /// Record the vote of `author` for `message`. A positive `value` likes the message, other
+    /// values withdraw a like.
+    pub fn vote(&mut self, author: FeedId, message: MessageId, value: i64) {
+        let summary = self.messages.entry(message).or_default();
+        let changed = if value > 0 {
+            summary.voters.insert(author)
+        } else {
+            summary.voters.remove(&author)
+        };
+        if changed {
+            let update = (message, summary.clone());
+            self.subscribers
+                .retain(|subscriber| subscriber.unbounded_send(update.clone()).is_ok());
+        }
+    }
+
+    /// Returns the likes of `message`.
+    pub fn summary(&self, message: &MessageId) -> VoteSummary {
+        self.messages.get(message).cloned().unwrap_or_default()
+    }
+
+    /// Stream of vote summaries that changed after the call.
+    pub fn updates(&mut self) -> impl Stream<Item = VoteUpdate> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+}
+
+/// Apply all messages from `log` to `votes`. Resolves when the log stream ends.
+///
+/// The lock is only held while a message is applied so votes can be queried concurrently.
+pub async fn ingest(votes: &std::sync::Mutex<Votes>, log: impl Stream<Item = Message>) {
+    futures::pin_mut!(log);
+    while let Some(message) = log.next().await {
+        votes.lock().unwrap().apply(&message);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::sign::KeyPair;
+
+    fn vote_message(
+        keypair: &KeyPair,
+        previous: Option<&Message>,
+        link: MessageId,
+        value: i64,
+    ) -> Message {
+        crate::feed::validate::sign(
+            keypair,
+            previous,
+            0u64,
+            serde_json::json!({
+                "type": "vote",
+                "vote": { "link": link, "value": value, "expression": "Like" },
+            }),
+        )
+    }
+
+    #[async_std::test]
+    async fn ingest_votes() {
+        let alice = KeyPair::gen();
+        let bob = KeyPair::gen();
+        let post = MessageId([1; 32]);
+        let like_alice = vote_message(&alice, None, post, 1);
+        let unlike_alice = vote_message(&alice, Some(&like_alice), post, 0);
+        let like_bob = vote_message(&bob, None, post, 1);
+        let post_message = crate::feed::validate::sign(
+            &bob,
+            Some(&like_bob),
+            0u64,
+            serde_json::json!({ "type": "post", "vote": { "link": post, "value": 1 } }),
+        );
+
+        let votes = std::sync::Mutex::new(Votes::new());
+        let mut updates = votes.lock().unwrap().updates();
+        ingest(
+            &votes,
+            futures::stream::iter(vec![like_alice, like_bob, post_message, unlike_alice]),
+        )
+        .await;
+
+        let summary = votes.lock().unwrap().summary(&post);
+        assert_eq!(summary.count(), 1);
+        assert!(summary.voters.contains(&FeedId(bob.public)));
+        let counts = std::iter::from_fn(|| updates.next().now_or_never().flatten())
+            .map(|(_, summary)| summary.count())
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![1, 2, 1]);
+        assert_eq!(
+            votes.lock().unwrap().summary(&MessageId([2; 32])),
+            VoteSummary::default()
+        );
+    }
+}
@@ -0,0 +1,77 @@
+//! WebSocket transport for browsers.
+//!
+//! Pubs and rooms that accept WebSocket connections can be reached from browser applications
+//! with [connect]. The returned [WebSocket] implements [AsyncRead] and [AsyncWrite] so it can be
+//! used with the handshake and box stream.
+//!
+//! ```no_run
+//! # async fn run(client: ssb_box_stream::Client) -> Result<(), Box<dyn std::error::Error>> {
+//! use futures::prelude::*;
+//!
+//! let socket = ssb::websocket::connect("wss://pub.example.com").await?;
+//! let (encrypt, decrypt) = client.connect(socket).await?;
+//! let mut client = ssb::rpc::ssb::Client::new(encrypt, decrypt);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Only available on `wasm32` targets.
+use futures::prelude::*;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Error returned by [connect].
+#[derive(thiserror::Error, Debug)]
+#[error("Failed to open WebSocket connection")]
+pub struct ConnectError(#[source] ws_stream_wasm::WsErr);
+
+/// Open a WebSocket connection to `url`.
+pub async fn connect(url: &str) -> Result<WebSocket, ConnectError> {
+    let (_meta, stream) = ws_stream_wasm::WsMeta::connect(url, None)
+        .await
+        .map_err(ConnectError)?;
+    Ok(WebSocket {
+        io: Box::pin(stream.into_io()),
+    })
+}
+
+/// Binary WebSocket connection that implements [AsyncRead] and [AsyncWrite].
+///
+/// Every write is sent as one WebSocket message. Closing the writer closes the connection.
+pub struct WebSocket {
+    io: Pin<Box<async_io_stream::IoStream<ws_stream_wasm::WsStreamIo, Vec<u8>>>>,
+}
+
+impl std::fmt::Debug for WebSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebSocket").finish()
+    }
+}
+
+impl AsyncRead for WebSocket {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.io.as_mut().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for WebSocket {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.io.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.io.as_mut().poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.io.as_mut().poll_close(cx)
+    }
+}
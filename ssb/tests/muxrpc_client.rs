@@ -86,8 +86,7 @@ async fn duplex_add() {
         send.close().await.unwrap();
     });
 
-    let outputs = receive
-        .map_ok(|body| body.decode_json::<u32>().unwrap())
+    let outputs = ssb::rpc::base::TypedSource::<u32>::new(receive)
         .try_collect::<Vec<_>>()
         .await
         .unwrap();
@@ -99,10 +98,8 @@ const SERVER_ADDR: &str = "127.0.0.1:19423";
 // Create a client that connects to a server at [SERVER_ADDR].
 async fn connect_client() -> Result<ssb::rpc::base::Endpoint, std::io::Error> {
     let connection = async_std::net::TcpStream::connect(SERVER_ADDR).await?;
-    let (read, write) = connection.split();
-    let stream = ssb::utils::read_to_stream(read);
-    Ok(ssb::rpc::base::Endpoint::new_client(
-        write.into_sink(),
-        stream,
+    Ok(ssb::rpc::base::Endpoint::from_io(
+        connection,
+        ssb::rpc::base::Service::new(),
     ))
 }